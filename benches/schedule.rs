@@ -0,0 +1,70 @@
+//! Schedule building for generative songs: eight tracks that each repeat a
+//! pattern hundreds or thousands of times.
+//!
+//! ```bash
+//! cargo bench --bench schedule
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::hint::black_box;
+use std::path::{Path, PathBuf};
+
+use clidaw::note::Pattern;
+use clidaw::parser;
+use clidaw::scheduler::{self, ScheduleIter};
+use clidaw::song::{self, Song};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+const TRACKS: usize = 8;
+
+const PATTERN: &str = "a s:0.5 d:0.5 [adg] _ | f g [sfh]:2 | h:0.25 j:0.25 k:0.5 - l _ |\n";
+
+/// A song of `TRACKS` tracks, each repeating the pattern `times` times at
+/// a different transposition, written to `dir` and loaded back
+fn generative_song(dir: &Path, times: u32) -> (Song, HashMap<PathBuf, Pattern>) {
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join("pattern.notes"), PATTERN).unwrap();
+    let mut text = String::from("tempo: 120\n");
+    for idx in 0..TRACKS {
+        text.push_str(&format!(
+            "\ninstrument: lead.instr\npattern.notes * {} transpose +{}\n",
+            times,
+            idx % 3
+        ));
+    }
+    let path = dir.join(format!("generative-{}.song", times));
+    fs::write(&path, text).unwrap();
+    let song = song::load(&path).unwrap();
+    let patterns = song
+        .tracks
+        .iter()
+        .flat_map(|track| &track.sequence)
+        .map(|segment| {
+            let text = fs::read_to_string(&segment.notes_path).unwrap();
+            let pattern = parser::parse_pattern(&text).unwrap();
+            (segment.notes_path.clone(), pattern)
+        })
+        .collect();
+    (song, patterns)
+}
+
+fn bench_schedule(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("clidaw-bench-{}", std::process::id()));
+    let mut group = c.benchmark_group("schedule");
+    group.sample_size(20);
+    for times in [20, 200, 2000] {
+        let (song, patterns) = generative_song(&dir, times);
+        group.bench_with_input(BenchmarkId::new("compile", times), &times, |b, _| {
+            b.iter(|| scheduler::compile(black_box(&song), black_box(&patterns)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("stream", times), &times, |b, _| {
+            b.iter(|| ScheduleIter::new(black_box(&song), black_box(&patterns)).unwrap().count())
+        });
+    }
+    group.finish();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, bench_schedule);
+criterion_main!(benches);
@@ -0,0 +1,843 @@
+//! Where the engine's audio goes. An `AudioBackend` opens an output stream
+//! that calls a render callback for each buffer; the engine doesn't know
+//! which backend it is talking to.
+//!
+//! `CpalBackend` plays on a sound device. `NullBackend` renders only when
+//! something pulls from it, so tests can run the engine without a device,
+//! and `FileBackend` pulls as fast as it can for offline rendering. A
+//! wasm32 build could add one for WebAudio the same way.
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::ClidawError;
+
+/// Sample rate and channel count of an open stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// What a backend knows about the buffer it wants rendered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferInfo {
+    /// Frames in the whole buffer (a backend may render it in pieces)
+    pub frames: usize,
+    /// Time until the buffer's first sample plays, if the backend knows
+    pub output_latency: Option<Duration>,
+}
+
+/// Renders interleaved f32 samples into a buffer; called on the audio thread
+pub type RenderCallback = Box<dyn FnMut(&mut [f32], &BufferInfo) + Send>;
+
+/// An audio output the engine can play through
+pub trait AudioBackend {
+    /// Open a stream with at least `channels` channels if it can (fewer if
+    /// not, for the caller to report). `build` makes the render callback
+    /// for the format chosen; a backend trying several formats in turn
+    /// calls it for each.
+    fn open(
+        &mut self,
+        channels: u16,
+        build: &mut dyn FnMut(StreamFormat) -> RenderCallback,
+    ) -> Result<StreamFormat, ClidawError>;
+
+    /// The open stream's format (None before `open`)
+    fn format(&self) -> Option<StreamFormat>;
+
+    /// Start calling the render callback
+    fn start(&mut self) -> Result<(), ClidawError>;
+
+    /// Stop the stream and drop the render callback
+    fn stop(&mut self);
+}
+
+/// An output device as listed by `clidaw devices`
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub is_default: bool,
+}
+
+fn device_name(device: &cpal::Device) -> String {
+    device
+        .description()
+        .map(|d| d.name().to_string())
+        .unwrap_or_else(|_| "(unknown)".to_string())
+}
+
+fn output_devices(host: &cpal::Host) -> Result<Vec<cpal::Device>, ClidawError> {
+    Ok(host
+        .output_devices()
+        .map_err(|e| ClidawError::Audio(format!("failed to list output devices: {}", e)))?
+        .collect())
+}
+
+/// List the host's output devices with their default configuration
+pub fn list_output_devices() -> Result<Vec<DeviceInfo>, ClidawError> {
+    let host = cpal::default_host();
+    let default_id = host.default_output_device().and_then(|d| d.id().ok());
+    let mut infos = Vec::new();
+    for (index, device) in output_devices(&host)?.iter().enumerate() {
+        let (sample_rate, channels) = device
+            .default_output_config()
+            .map(|c| (c.sample_rate(), c.channels()))
+            .unwrap_or((0, 0));
+        infos.push(DeviceInfo {
+            index,
+            name: device_name(device),
+            sample_rate,
+            channels,
+            is_default: default_id.is_some() && device.id().ok() == default_id,
+        });
+    }
+    Ok(infos)
+}
+
+/// Resolve `--device` against the device names: an index, or a
+/// case-insensitive substring of exactly one name (an exact name always wins).
+fn match_device(names: &[String], spec: &str) -> Result<usize, String> {
+    let candidates = || {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| format!("{}: {}", i, n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if let Ok(idx) = spec.parse::<usize>() {
+        if idx < names.len() {
+            return Ok(idx);
+        }
+        return Err(format!(
+            "device index {} out of range; available devices: {}",
+            idx,
+            candidates()
+        ));
+    }
+    if let Some(idx) = names.iter().position(|n| n.eq_ignore_ascii_case(spec)) {
+        return Ok(idx);
+    }
+    let needle = spec.to_lowercase();
+    let matches: Vec<usize> = names
+        .iter()
+        .enumerate()
+        .filter(|(_, n)| n.to_lowercase().contains(&needle))
+        .map(|(i, _)| i)
+        .collect();
+    match matches.as_slice() {
+        [idx] => Ok(*idx),
+        [] => Err(format!(
+            "no output device matching '{}'; available devices: {}",
+            spec,
+            candidates()
+        )),
+        _ => Err(format!(
+            "device '{}' is ambiguous; matching devices: {}",
+            spec,
+            matches
+                .iter()
+                .map(|&i| format!("{}: {}", i, names[i]))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Sample rates to prefer, in order, when the device supports several
+const PREFERRED_RATES: [u32; 2] = [48000, 44100];
+
+/// Sample formats the engine can write, best first
+const SAMPLE_FORMATS: [cpal::SampleFormat; 3] =
+    [cpal::SampleFormat::F32, cpal::SampleFormat::I16, cpal::SampleFormat::U16];
+
+/// Frames rendered at a time for integer formats, which are converted
+/// from a buffer of this size allocated before the stream starts
+const CONVERT_FRAMES: usize = 1024;
+
+/// Which output device to open and how (None = let clidaw choose)
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Index or name from `clidaw devices` (None = the default device)
+    pub device: Option<String>,
+    /// Sample rate in Hz
+    pub sample_rate: Option<u32>,
+    /// Frames per audio callback; smaller buffers lower the latency
+    pub buffer_size: Option<u32>,
+    /// Frames per callback when `buffer_size` isn't given, kept within the
+    /// device's range without a warning (None = the device's default)
+    pub default_buffer_size: Option<u32>,
+}
+
+/// The stream config for the device's `default` config and supported
+/// `ranges`, plus a warning for each request that couldn't be met.
+/// `channels` is the fewest the stream needs (more than 2 when tracks are
+/// routed to their own outputs).
+///
+/// Without a requested rate, an f32 config at 48k or 44.1k (stereo, or
+/// exactly `channels`, preferred) if the device supports one, then the same
+/// in i16 or u16, else its default config. A requested rate the device
+/// doesn't support falls back to that choice; a buffer size outside the
+/// device's range is clamped to it. If no config has enough channels the
+/// result has fewer, for the caller to report.
+fn choose_config(
+    default: cpal::SupportedStreamConfig,
+    ranges: &[cpal::SupportedStreamConfigRange],
+    options: &OutputOptions,
+    channels: u16,
+) -> (cpal::StreamConfig, cpal::SampleFormat, Vec<String>) {
+    let best = |rates: &[u32]| ranked_configs(ranges, rates, channels).into_iter().next();
+    let automatic = || {
+        if default.sample_format() == cpal::SampleFormat::F32
+            && PREFERRED_RATES.contains(&default.sample_rate())
+            && default.channels() >= channels
+        {
+            return default.clone();
+        }
+        best(&PREFERRED_RATES).unwrap_or_else(|| default.clone())
+    };
+
+    let mut warnings = Vec::new();
+    let supported = match options.sample_rate {
+        None => automatic(),
+        Some(rate) => match best(&[rate]) {
+            Some(config) => config,
+            None if default.sample_rate() == rate && default.channels() >= channels => {
+                default.clone()
+            }
+            None => {
+                let config = automatic();
+                warnings.push(format!(
+                    "the device doesn't support a sample rate of {} Hz; using {} Hz",
+                    rate,
+                    config.sample_rate()
+                ));
+                config
+            }
+        },
+    };
+
+    let mut config = supported.config();
+    if options.buffer_size.is_none()
+        && let Some(frames) = options.default_buffer_size
+    {
+        let frames = match *supported.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } => frames.clamp(min, max),
+            cpal::SupportedBufferSize::Unknown => frames,
+        };
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    if let Some(frames) = options.buffer_size {
+        let frames = match *supported.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } if !(min..=max).contains(&frames) => {
+                let clamped = frames.clamp(min, max);
+                warnings.push(format!(
+                    "the device takes buffers of {} to {} frames; using {} instead of {}",
+                    min, max, clamped, frames
+                ));
+                clamped
+            }
+            _ => frames,
+        };
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    (config, supported.sample_format(), warnings)
+}
+
+/// Every config in `ranges` the engine can write with at least `channels`
+/// channels, at one of `rates` where the range allows, best first: f32
+/// before i16 before u16, then stereo (or exactly the channels needed),
+/// then earlier rates
+fn ranked_configs(
+    ranges: &[cpal::SupportedStreamConfigRange],
+    rates: &[u32],
+    channels: u16,
+) -> Vec<cpal::SupportedStreamConfig> {
+    let wanted = channels.max(2);
+    let mut ranked = Vec::new();
+    for range in ranges.iter().filter(|r| r.channels() >= channels) {
+        let Some(format) = SAMPLE_FORMATS.iter().position(|&f| f == range.sample_format()) else {
+            continue;
+        };
+        for (rank, &rate) in rates.iter().enumerate() {
+            if (range.min_sample_rate()..=range.max_sample_rate()).contains(&rate) {
+                let score = format * 100 + usize::from(range.channels() != wanted) * 10 + rank;
+                ranked.push((score, range.with_sample_rate(rate)));
+            }
+        }
+    }
+    // Stable, so equally good configs keep the device's order
+    ranked.sort_by_key(|(score, _)| *score);
+    ranked.into_iter().map(|(_, config)| config).collect()
+}
+
+/// Plays on a sound device through cpal
+pub struct CpalBackend {
+    output: OutputOptions,
+    /// The open stream and what it was opened with
+    stream: Option<cpal::Stream>,
+    format: Option<StreamFormat>,
+    /// e.g. `default, 48000 Hz, f32, 2 channels`, printed on start
+    description: String,
+}
+
+impl CpalBackend {
+    /// A backend for the device and settings in `output`. Requested
+    /// settings the device can't use are replaced with a printed warning.
+    pub fn new(output: OutputOptions) -> Self {
+        Self {
+            output,
+            stream: None,
+            format: None,
+            description: String::new(),
+        }
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn open(
+        &mut self,
+        channels: u16,
+        build: &mut dyn FnMut(StreamFormat) -> RenderCallback,
+    ) -> Result<StreamFormat, ClidawError> {
+        let host = cpal::default_host();
+        let device = match self.output.device.as_deref() {
+            None => host
+                .default_output_device()
+                .ok_or_else(|| ClidawError::Audio("no output audio device available".to_string()))?,
+            Some(spec) => {
+                let mut devices = output_devices(&host)?;
+                let names: Vec<String> = devices.iter().map(device_name).collect();
+                let idx = match_device(&names, spec).map_err(ClidawError::Audio)?;
+                devices.swap_remove(idx)
+            }
+        };
+
+        let default = device.default_output_config().map_err(|e| {
+            ClidawError::Audio(format!("failed to get default output config: {}", e))
+        })?;
+        let ranges: Vec<_> = device
+            .supported_output_configs()
+            .map(|ranges| ranges.collect())
+            .unwrap_or_default();
+        let (config, format, warnings) = choose_config(default, &ranges, &self.output, channels);
+        for warning in warnings {
+            eprintln!("warning: {}", warning);
+        }
+
+        // If the chosen config won't open, try it with the device's own
+        // buffer size, then the device's other configs in turn
+        let mut candidates = vec![(config.clone(), format)];
+        if config.buffer_size != cpal::BufferSize::Default {
+            let config = cpal::StreamConfig {
+                buffer_size: cpal::BufferSize::Default,
+                ..config
+            };
+            candidates.push((config, format));
+        }
+        for supported in ranked_configs(&ranges, &PREFERRED_RATES, channels) {
+            let candidate = (supported.config(), supported.sample_format());
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+        let mut opened = None;
+        let mut first_error = None;
+        for (config, format) in candidates {
+            let render = build(StreamFormat {
+                sample_rate: config.sample_rate,
+                channels: config.channels,
+            });
+            match build_stream(&device, &config, format, render) {
+                Ok(stream) => {
+                    opened = Some((stream, config, format));
+                    break;
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        let Some((stream, config, format)) = opened else {
+            let e = first_error.map_or("no usable config".to_string(), |e| e.to_string());
+            return Err(ClidawError::Audio(format!("failed to build output stream: {}", e)));
+        };
+        if let Some(e) = first_error {
+            eprintln!("warning: the device's preferred output config failed ({}); fell back", e);
+        }
+        let buffer = match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => format!(", {}-frame buffers", frames),
+            cpal::BufferSize::Default => String::new(),
+        };
+        self.description = format!(
+            "{}, {} Hz, {}, {} channel{}{}",
+            device_name(&device),
+            config.sample_rate,
+            format,
+            config.channels,
+            if config.channels == 1 { "" } else { "s" },
+            buffer
+        );
+        let opened = StreamFormat {
+            sample_rate: config.sample_rate,
+            channels: config.channels,
+        };
+        self.stream = Some(stream);
+        self.format = Some(opened);
+        Ok(opened)
+    }
+
+    fn format(&self) -> Option<StreamFormat> {
+        self.format
+    }
+
+    fn start(&mut self) -> Result<(), ClidawError> {
+        let Some(stream) = &self.stream else {
+            return Err(ClidawError::Audio("the output stream isn't open".to_string()));
+        };
+        println!("Audio output: {}", self.description);
+        stream
+            .play()
+            .map_err(|e| ClidawError::Audio(format!("failed to play stream: {}", e)))
+    }
+
+    fn stop(&mut self) {
+        self.stream = None;
+    }
+}
+
+/// Renders each buffer for an integer sample format into f32 first, a
+/// scratch buffer's worth at a time
+struct Converter {
+    render: RenderCallback,
+    /// Allocated before the stream starts
+    scratch: Vec<f32>,
+}
+
+impl Converter {
+    fn new(render: RenderCallback, channels: usize) -> Self {
+        Self {
+            render,
+            scratch: vec![0.0; CONVERT_FRAMES * channels],
+        }
+    }
+
+    fn fill<T: cpal::FromSample<f32>>(&mut self, data: &mut [T], info: &BufferInfo) {
+        for out in data.chunks_mut(self.scratch.len()) {
+            let rendered = &mut self.scratch[..out.len()];
+            (self.render)(rendered, info);
+            for (sample, &value) in out.iter_mut().zip(rendered.iter()) {
+                *sample = T::from_sample_(value);
+            }
+        }
+    }
+}
+
+/// The size and timing of a buffer of `samples` cpal asked for
+fn buffer_info(samples: usize, channels: usize, info: &cpal::OutputCallbackInfo) -> BufferInfo {
+    let timestamp = info.timestamp();
+    BufferInfo {
+        frames: samples / channels.max(1),
+        output_latency: timestamp.playback.duration_since(&timestamp.callback),
+    }
+}
+
+fn stream_error(err: cpal::StreamError) {
+    eprintln!("audio stream error: {}", err);
+}
+
+/// Open an output stream writing `format` samples, with `render` rendering
+fn build_stream(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    format: cpal::SampleFormat,
+    mut render: RenderCallback,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    type Info = cpal::OutputCallbackInfo;
+    let channels = config.channels as usize;
+    match format {
+        cpal::SampleFormat::F32 => device.build_output_stream(
+            config,
+            move |data: &mut [f32], info: &Info| {
+                render(data, &buffer_info(data.len(), channels, info));
+            },
+            stream_error,
+            None,
+        ),
+        cpal::SampleFormat::I16 => {
+            let mut converter = Converter::new(render, channels);
+            device.build_output_stream(
+                config,
+                move |data: &mut [i16], info: &Info| {
+                    converter.fill(data, &buffer_info(data.len(), channels, info));
+                },
+                stream_error,
+                None,
+            )
+        }
+        cpal::SampleFormat::U16 => {
+            let mut converter = Converter::new(render, channels);
+            device.build_output_stream(
+                config,
+                move |data: &mut [u16], info: &Info| {
+                    converter.fill(data, &buffer_info(data.len(), channels, info));
+                },
+                stream_error,
+                None,
+            )
+        }
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+/// The render callback a pulled backend holds, and whether it has started
+#[derive(Default)]
+struct Slot {
+    render: Option<RenderCallback>,
+    running: bool,
+}
+
+/// Renders only when its `Pull` asks, so a test harness decides when (and
+/// how much) audio the engine makes
+pub struct NullBackend {
+    format: StreamFormat,
+    opened: bool,
+    slot: Arc<Mutex<Slot>>,
+}
+
+/// The harness's end of a `NullBackend`
+#[derive(Clone)]
+pub struct Pull {
+    format: StreamFormat,
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl NullBackend {
+    /// A backend that opens at exactly `sample_rate` and `channels`, and
+    /// the `Pull` that renders from it
+    pub fn new(sample_rate: u32, channels: u16) -> (Self, Pull) {
+        let format = StreamFormat {
+            sample_rate,
+            channels,
+        };
+        let slot = Arc::new(Mutex::new(Slot::default()));
+        let pull = Pull {
+            format,
+            slot: Arc::clone(&slot),
+        };
+        let backend = Self {
+            format,
+            opened: false,
+            slot,
+        };
+        (backend, pull)
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn open(
+        &mut self,
+        _channels: u16,
+        build: &mut dyn FnMut(StreamFormat) -> RenderCallback,
+    ) -> Result<StreamFormat, ClidawError> {
+        self.slot.lock().unwrap().render = Some(build(self.format));
+        self.opened = true;
+        Ok(self.format)
+    }
+
+    fn format(&self) -> Option<StreamFormat> {
+        self.opened.then_some(self.format)
+    }
+
+    fn start(&mut self) -> Result<(), ClidawError> {
+        self.slot.lock().unwrap().running = true;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        *self.slot.lock().unwrap() = Slot::default();
+    }
+}
+
+impl Pull {
+    /// Fill `out` (interleaved) from the render callback, or with silence
+    /// while the stream isn't running
+    pub fn render(&self, out: &mut [f32]) {
+        let mut slot = self.slot.lock().unwrap();
+        match &mut *slot {
+            Slot {
+                render: Some(render),
+                running: true,
+            } => {
+                let info = BufferInfo {
+                    frames: out.len() / self.format.channels.max(1) as usize,
+                    output_latency: None,
+                };
+                render(out, &info);
+            }
+            _ => out.fill(0.0),
+        }
+    }
+}
+
+/// Renders as fast as it is asked to, into memory, for offline rendering
+/// to write to a file
+pub struct FileBackend {
+    inner: NullBackend,
+}
+
+/// The offline renderer's end of a `FileBackend`: everything rendered so far
+pub struct Tape {
+    pull: Pull,
+    samples: Vec<f32>,
+}
+
+impl FileBackend {
+    pub fn new(sample_rate: u32, channels: u16) -> (Self, Tape) {
+        let (inner, pull) = NullBackend::new(sample_rate, channels);
+        let tape = Tape {
+            pull,
+            samples: Vec::new(),
+        };
+        (Self { inner }, tape)
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn open(
+        &mut self,
+        channels: u16,
+        build: &mut dyn FnMut(StreamFormat) -> RenderCallback,
+    ) -> Result<StreamFormat, ClidawError> {
+        self.inner.open(channels, build)
+    }
+
+    fn format(&self) -> Option<StreamFormat> {
+        self.inner.format()
+    }
+
+    fn start(&mut self) -> Result<(), ClidawError> {
+        self.inner.start()
+    }
+
+    fn stop(&mut self) {
+        self.inner.stop();
+    }
+}
+
+impl Tape {
+    /// Render on until the tape is `frame` frames long (nothing if it
+    /// already is). Commands sent to the engine before this land at its
+    /// current end, exactly.
+    pub fn render_to(&mut self, frame: usize) {
+        let start = self.samples.len();
+        let end = frame * self.pull.format.channels as usize;
+        if end > start {
+            self.samples.resize(end, 0.0);
+            self.pull.render(&mut self.samples[start..]);
+        }
+    }
+
+    /// The interleaved samples rendered
+    pub fn into_samples(self) -> Vec<f32> {
+        self.samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{LiveCommand, Mix, Patch, Synth};
+
+    #[test]
+    fn test_choose_config_honours_requests_or_falls_back() {
+        use cpal::{BufferSize, SampleFormat, SupportedBufferSize, SupportedStreamConfig};
+        use cpal::SupportedStreamConfigRange as Range;
+        let buffers = SupportedBufferSize::Range { min: 64, max: 4096 };
+        let default = SupportedStreamConfig::new(2, 44100, buffers, SampleFormat::I16);
+        let ranges = [
+            Range::new(2, 44100, 96000, buffers, SampleFormat::F32),
+            Range::new(1, 8000, 192000, buffers, SampleFormat::F32),
+        ];
+        let choose = |sample_rate, buffer_size| {
+            let options = OutputOptions {
+                sample_rate,
+                buffer_size,
+                ..OutputOptions::default()
+            };
+            let (config, _, warnings) = choose_config(default.clone(), &ranges, &options, 1);
+            (config.sample_rate, config.channels, config.buffer_size, warnings.len())
+        };
+        let channels_for = |needed| {
+            let ranges = [
+                Range::new(2, 44100, 48000, buffers, SampleFormat::F32),
+                Range::new(8, 44100, 48000, buffers, SampleFormat::F32),
+                Range::new(4, 44100, 48000, buffers, SampleFormat::F32),
+            ];
+            let (config, ..) =
+                choose_config(default.clone(), &ranges, &OutputOptions::default(), needed);
+            config.channels
+        };
+
+        // Nothing requested: 48k stereo, the device's buffer size
+        assert_eq!(choose(None, None), (48000, 2, BufferSize::Default, 0));
+        // Stereo when it can, mono when only mono goes that high
+        assert_eq!(choose(Some(96000), Some(256)), (96000, 2, BufferSize::Fixed(256), 0));
+        assert_eq!(choose(Some(192000), None), (192000, 1, BufferSize::Default, 0));
+        // Unsupported: fall back, and say so
+        assert_eq!(choose(Some(384000), None), (48000, 2, BufferSize::Default, 1));
+        assert_eq!(choose(None, Some(16)), (48000, 2, BufferSize::Fixed(64), 1));
+        // A default size (live mode's) fits the device quietly; a request wins
+        let preferred = |buffer_size, default_buffer_size| {
+            let options = OutputOptions {
+                buffer_size,
+                default_buffer_size,
+                ..OutputOptions::default()
+            };
+            let (config, _, warnings) = choose_config(default.clone(), &ranges, &options, 1);
+            (config.buffer_size, warnings.len())
+        };
+        assert_eq!(preferred(None, Some(16)), (BufferSize::Fixed(64), 0));
+        assert_eq!(preferred(None, Some(256)), (BufferSize::Fixed(256), 0));
+        assert_eq!(preferred(Some(1024), Some(256)), (BufferSize::Fixed(1024), 0));
+        // Routed tracks need enough channels; an exact match beats more
+        assert_eq!(channels_for(2), 2);
+        assert_eq!(channels_for(4), 4);
+        assert_eq!(channels_for(5), 8);
+        // Too many: the caller reports it
+        assert_eq!(channels_for(9), 2);
+    }
+
+    #[test]
+    fn test_integer_formats_are_chosen_when_f32_is_missing() {
+        use cpal::{SampleFormat, SupportedBufferSize, SupportedStreamConfig};
+        use cpal::SupportedStreamConfigRange as Range;
+        let buffers = SupportedBufferSize::Unknown;
+        let default = SupportedStreamConfig::new(2, 44100, buffers, SampleFormat::I24);
+        let chosen = |ranges: &[Range]| {
+            let (config, format, _) =
+                choose_config(default.clone(), ranges, &OutputOptions::default(), 1);
+            (config.sample_rate, format)
+        };
+        let u16_only = [Range::new(2, 48000, 48000, buffers, SampleFormat::U16)];
+        assert_eq!(chosen(&u16_only), (48000, SampleFormat::U16));
+        // f32 first, then i16, whatever order the device lists them in
+        let mixed = [
+            Range::new(2, 44100, 48000, buffers, SampleFormat::U16),
+            Range::new(2, 44100, 48000, buffers, SampleFormat::I16),
+            Range::new(2, 44100, 44100, buffers, SampleFormat::F32),
+        ];
+        assert_eq!(chosen(&mixed), (44100, SampleFormat::F32));
+        assert_eq!(chosen(&mixed[..2]), (48000, SampleFormat::I16));
+        // Formats the engine can't write are never offered
+        let formats: Vec<_> = ranked_configs(&mixed, &PREFERRED_RATES, 2)
+            .iter()
+            .map(|c| c.sample_format())
+            .collect();
+        assert_eq!(formats[0], SampleFormat::F32);
+        let i24 = [Range::new(2, 48000, 48000, buffers, SampleFormat::I24)];
+        assert!(ranked_configs(&i24, &[48000], 2).is_empty());
+    }
+
+    #[test]
+    fn test_integer_output_matches_f32_render() {
+        let synth = || {
+            let mut synth = Synth::new(&[Patch::default()], &Mix::default(), 48_000.0, 2);
+            synth.process_command(LiveCommand::NoteOn {
+                track: 0,
+                key: 'a',
+                freq: 440.0,
+                velocity: 1.0,
+            });
+            synth
+        };
+        // More than one scratch buffer's worth, so the chunks must line up
+        let mut expected = vec![0.0_f32; 2 * (CONVERT_FRAMES * 2 + 100)];
+        synth().render(&mut expected);
+        let whole = BufferInfo {
+            frames: expected.len() / 2,
+            output_latency: None,
+        };
+
+        let converter = || {
+            let mut synth = synth();
+            let frames = whole.frames;
+            let render: RenderCallback = Box::new(move |out: &mut [f32], info: &BufferInfo| {
+                // Every piece is told the size of the whole buffer
+                assert_eq!(info.frames, frames);
+                synth.render(out);
+            });
+            Converter::new(render, 2)
+        };
+        let mut ints = vec![0_i16; expected.len()];
+        converter().fill(&mut ints, &whole);
+        let mut unsigned = vec![0_u16; expected.len()];
+        converter().fill(&mut unsigned, &whole);
+        for ((&f, &i), &u) in expected.iter().zip(&ints).zip(&unsigned) {
+            assert!((f - i as f32 / 32768.0).abs() < 1e-3, "{} vs {}", f, i);
+            assert!((f - (u as f32 - 32768.0) / 32768.0).abs() < 1e-3, "{} vs {}", f, u);
+        }
+        assert!(ints.iter().any(|&i| i.abs() > 1000));
+    }
+
+    #[test]
+    fn test_pulled_backends_render_only_when_running() {
+        let (mut backend, pull) = NullBackend::new(44_100, 2);
+        assert_eq!(backend.format(), None);
+        let mut out = [1.0_f32; 8];
+        pull.render(&mut out);
+        assert_eq!(out, [0.0; 8]);
+
+        let format = backend
+            .open(2, &mut |format| {
+                assert_eq!(format.sample_rate, 44_100);
+                Box::new(|out: &mut [f32], info: &BufferInfo| {
+                    assert_eq!(info.frames, out.len() / 2);
+                    out.fill(0.5);
+                })
+            })
+            .unwrap();
+        assert_eq!(backend.format(), Some(format));
+        // Opened but not started: still silent
+        pull.render(&mut out);
+        assert_eq!(out, [0.0; 8]);
+        backend.start().unwrap();
+        pull.render(&mut out);
+        assert_eq!(out, [0.5; 8]);
+        backend.stop();
+        pull.render(&mut out);
+        assert_eq!(out, [0.0; 8]);
+
+        // A file backend's tape grows to each frame asked for, never back
+        let (mut backend, mut tape) = FileBackend::new(48_000, 2);
+        backend
+            .open(2, &mut |_| Box::new(|out: &mut [f32], _: &BufferInfo| out.fill(0.25)))
+            .unwrap();
+        backend.start().unwrap();
+        tape.render_to(3);
+        tape.render_to(2);
+        tape.render_to(5);
+        assert_eq!(tape.into_samples(), vec![0.25; 10]);
+    }
+
+    #[test]
+    fn test_match_device() {
+        let names: Vec<String> = ["default", "HDA Intel PCH", "USB Audio", "USB Audio Pro"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(match_device(&names, "1"), Ok(1));
+        assert_eq!(match_device(&names, "intel"), Ok(1));
+        // Exact (case-insensitive) name beats substring ambiguity
+        assert_eq!(match_device(&names, "usb audio"), Ok(2));
+        let err = match_device(&names, "usb").unwrap_err();
+        assert!(err.contains("2: USB Audio, 3: USB Audio Pro"), "{}", err);
+        assert!(match_device(&names, "9").is_err());
+        assert!(match_device(&names, "hdmi").unwrap_err().contains("0: default"));
+    }
+}
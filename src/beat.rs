@@ -0,0 +1,188 @@
+//! Exact positions and lengths in beats.
+//!
+//! A `Beat` is a whole number of ticks, `TICKS_PER_BEAT` to the beat, so
+//! note lengths add up without the drift of `f64`: a pattern repeated a
+//! thousand times still ends exactly on a bar line, and events that should
+//! coincide compare equal. The tick count divides evenly into 64ths, into
+//! decimal lengths down to hundredths, and into tuplets of 3, 5, 6, 7, 9,
+//! 11 and 13 (among others); anything finer rounds to the nearest tick.
+//! Beats become `f64` only where they turn into seconds or samples.
+
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Rem, Sub, SubAssign};
+
+use serde::{Deserialize, Serialize};
+
+/// Ticks in one beat: 2^6 · 3^2 · 5^2 · 7 · 11 · 13
+pub const TICKS_PER_BEAT: i64 = 14_414_400;
+
+/// A beat position or length, counted in ticks
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Beat(i64);
+
+impl Beat {
+    pub const ZERO: Beat = Beat(0);
+    pub const ONE: Beat = Beat(TICKS_PER_BEAT);
+
+    /// `beats` whole beats
+    pub const fn whole(beats: i64) -> Beat {
+        Beat(beats * TICKS_PER_BEAT)
+    }
+
+    /// `num / den` beats, to the nearest tick
+    pub fn ratio(num: i64, den: i64) -> Beat {
+        Beat(round_div(num as i128 * TICKS_PER_BEAT as i128, den as i128))
+    }
+
+    /// The nearest tick to `beats` (for lengths typed as decimals, or
+    /// computed from seconds)
+    pub fn from_f64(beats: f64) -> Beat {
+        Beat((beats * TICKS_PER_BEAT as f64).round() as i64)
+    }
+
+    pub fn as_f64(self) -> f64 {
+        self.0 as f64 / TICKS_PER_BEAT as f64
+    }
+
+    /// This length scaled by `factor`, to the nearest tick
+    pub fn scale(self, factor: f64) -> Beat {
+        Beat((self.0 as f64 * factor).round() as i64)
+    }
+
+    /// The nearest multiple of `grid` (halfway rounds later); unchanged for
+    /// a grid of zero or less
+    pub fn round_to(self, grid: Beat) -> Beat {
+        if grid.0 <= 0 {
+            return self;
+        }
+        Beat((self.0 + grid.0 / 2).div_euclid(grid.0) * grid.0)
+    }
+}
+
+/// `num / den` rounded to the nearest integer, halves away from zero
+fn round_div(num: i128, den: i128) -> i64 {
+    let (num, den) = if den < 0 { (-num, -den) } else { (num, den) };
+    let rounded = if num >= 0 {
+        (num + den / 2) / den
+    } else {
+        (num - den / 2) / den
+    };
+    rounded as i64
+}
+
+impl fmt::Display for Beat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.as_f64(), f)
+    }
+}
+
+impl Add for Beat {
+    type Output = Beat;
+
+    fn add(self, other: Beat) -> Beat {
+        Beat(self.0 + other.0)
+    }
+}
+
+impl Sub for Beat {
+    type Output = Beat;
+
+    fn sub(self, other: Beat) -> Beat {
+        Beat(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Beat {
+    fn add_assign(&mut self, other: Beat) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Beat {
+    fn sub_assign(&mut self, other: Beat) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Beat {
+    type Output = Beat;
+
+    fn neg(self) -> Beat {
+        Beat(-self.0)
+    }
+}
+
+impl Mul<u32> for Beat {
+    type Output = Beat;
+
+    fn mul(self, times: u32) -> Beat {
+        Beat(self.0 * times as i64)
+    }
+}
+
+impl Mul<u64> for Beat {
+    type Output = Beat;
+
+    fn mul(self, times: u64) -> Beat {
+        Beat(self.0 * times as i64)
+    }
+}
+
+/// Division into `parts` equal shares, to the nearest tick
+impl Div<u32> for Beat {
+    type Output = Beat;
+
+    fn div(self, parts: u32) -> Beat {
+        Beat(round_div(self.0 as i128, parts as i128))
+    }
+}
+
+/// The remainder after whole `period`s, never negative
+impl Rem for Beat {
+    type Output = Beat;
+
+    fn rem(self, period: Beat) -> Beat {
+        Beat(self.0.rem_euclid(period.0))
+    }
+}
+
+impl Sum for Beat {
+    fn sum<I: Iterator<Item = Beat>>(iter: I) -> Beat {
+        iter.fold(Beat::ZERO, Add::add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tuplets_and_decimals_are_exact() {
+        let third = Beat::ratio(1, 3);
+        assert_eq!(third * 3u32, Beat::ONE);
+        assert_eq!(Beat::ratio(2, 7) * 7u32, Beat::whole(2));
+        assert_eq!(Beat::from_f64(0.1) * 10u32, Beat::ONE);
+        assert_eq!(Beat::from_f64(1.0 / 3.0), third);
+        // 0.1 added up a thousand times drifts as f64, but not in ticks
+        let float: f64 = (0..1000).map(|_| 0.1).sum();
+        assert_ne!(float, 100.0);
+        let exact: Beat = (0..1000).map(|_| Beat::from_f64(0.1)).sum();
+        assert_eq!(exact, Beat::whole(100));
+        assert_eq!(exact.to_string(), "100");
+    }
+
+    #[test]
+    fn test_rounding() {
+        let grid = Beat::ratio(1, 4);
+        assert_eq!(Beat::ratio(3, 8).round_to(grid), Beat::ratio(1, 2));
+        assert_eq!(Beat::ratio(1, 3).round_to(grid), grid);
+        assert_eq!((-Beat::ratio(1, 8)).round_to(grid), Beat::ZERO);
+        assert_eq!(Beat::whole(2) / 3, Beat::ratio(2, 3));
+        assert_eq!(Beat::ratio(-1, 3) * 3u32, -Beat::ONE);
+        assert_eq!(Beat::ONE.scale(0.5), Beat::ratio(1, 2));
+    }
+}
@@ -0,0 +1,221 @@
+//! `play --cache`: a song's compiled schedule, kept in `.clidaw-cache/`
+//! next to the song and reused while none of the files it was built from
+//! have changed.
+//!
+//! The cache holds the notes before any command-line transform (humanize,
+//! quantize, fades, the seek range, the metronome) and in beats, so those
+//! and `--tempo` can change freely between runs. What does shape the notes
+//! is kept with them and must match: the tracks `--solo` and `--mute`
+//! picked, strict parsing, and a hash of every `.song`, `.notes` and
+//! included file read. Anything else wrong with a cache file (missing,
+//! unreadable, from another version) just means building the song again.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::beat::Beat;
+use crate::error::ClidawError;
+use crate::meter::MeterMap;
+use crate::render::write_atomic;
+use crate::scheduler::{Compiled, ScheduledEvent};
+
+/// Directory next to the song that holds its cache file
+pub const CACHE_DIR: &str = ".clidaw-cache";
+
+/// Bumped whenever the cache file's layout or the schedule it holds changes
+const VERSION: u32 = 4;
+
+/// What, besides its files, a cached schedule was built with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Params {
+    pub solo: Vec<String>,
+    pub mute: Vec<String>,
+    pub strict: bool,
+}
+
+/// The layout of a cache file
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    params: Params,
+    /// Every file read to build the schedule, with the hash of its contents
+    files: Vec<(PathBuf, String)>,
+    events: Vec<ScheduledEvent>,
+    /// The meter's opening signature and each change after it
+    signature: (u8, u8),
+    meter_changes: Vec<(f64, (u8, u8))>,
+    length: Beat,
+}
+
+/// A schedule found in the cache, and the files it was built from
+pub struct Hit {
+    pub compiled: Compiled,
+    pub files: Vec<PathBuf>,
+}
+
+/// The cache file for `song_path`
+pub fn path_for(song_path: &Path) -> PathBuf {
+    let dir = song_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut name = song_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".json");
+    dir.join(CACHE_DIR).join(name)
+}
+
+/// 64-bit FNV-1a of `bytes`, as hex. Unlike std's hasher it never changes
+/// between Rust releases, so old cache files stay readable.
+fn hash(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xCBF2_9CE4_8422_2325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Hash of `path`'s current contents (None if it can't be read)
+fn hash_file(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|bytes| hash(&bytes))
+}
+
+/// The cached schedule for `song_path`, if there is one built with
+/// `params` from files that haven't changed since
+pub fn load(song_path: &Path, params: &Params) -> Option<Hit> {
+    let text = fs::read_to_string(path_for(song_path)).ok()?;
+    let cached: CacheFile = serde_json::from_str(&text).ok()?;
+    if cached.version != VERSION || cached.params != *params {
+        return None;
+    }
+    if cached.files.iter().any(|(file, hash)| hash_file(file).as_ref() != Some(hash)) {
+        return None;
+    }
+    let mut meter = MeterMap::new(cached.signature);
+    for (beat, signature) in cached.meter_changes {
+        meter.change(beat, signature);
+    }
+    Some(Hit {
+        compiled: Compiled {
+            events: cached.events,
+            meter,
+            length: cached.length,
+            clamped: Vec::new(),
+        },
+        files: cached.files.into_iter().map(|(file, _)| file).collect(),
+    })
+}
+
+/// Cache `compiled` for `song_path`, built with `params` from `files`
+pub fn store(
+    song_path: &Path,
+    params: &Params,
+    files: &[PathBuf],
+    compiled: &Compiled,
+) -> Result<(), ClidawError> {
+    let files = files
+        .iter()
+        .map(|file| {
+            let bytes = fs::read(file).map_err(|e| ClidawError::io(file, e))?;
+            Ok((file.clone(), hash(&bytes)))
+        })
+        .collect::<Result<_, ClidawError>>()?;
+    let cached = CacheFile {
+        version: VERSION,
+        params: params.clone(),
+        files,
+        events: compiled.events.clone(),
+        signature: compiled.meter.signature_at(0.0),
+        meter_changes: compiled.meter.changes().collect(),
+        length: compiled.length,
+    };
+    let path = path_for(song_path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| ClidawError::io(dir, e))?;
+    }
+    let json = serde_json::to_string(&cached).expect("schedule serialization cannot fail");
+    write_atomic(&path, json.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::Drum;
+    use crate::synth::LiveCommand;
+    use crate::test_util::temp_dir;
+
+    fn compiled() -> Compiled {
+        let mut meter = MeterMap::new((4, 4));
+        meter.change(8.0, (7, 8));
+        let event = |beat, command| ScheduledEvent {
+            beat: Beat::from_f64(beat),
+            command,
+        };
+        Compiled {
+            events: vec![
+                event(0.0, LiveCommand::NoteOn {
+                    track: 0,
+                    key: '\u{E000}',
+                    freq: 440.0,
+                    velocity: 0.8,
+                }),
+                event(0.5, LiveCommand::DrumHit {
+                    track: 1,
+                    drum: Drum::Snare,
+                    velocity: 1.0,
+                }),
+                event(1.0, LiveCommand::NoteOff {
+                    track: 0,
+                    key: '\u{E000}',
+                }),
+            ],
+            meter,
+            length: Beat::whole(15),
+            clamped: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_until_a_file_changes() {
+        let dir = temp_dir("round-trip");
+        let song = dir.join("tune.song");
+        let notes = dir.join("verse.notes");
+        fs::write(&song, "instrument: a.instr\nverse.notes\n").unwrap();
+        fs::write(&notes, "a s d f\n").unwrap();
+        let params = Params {
+            solo: Vec::new(),
+            mute: vec!["1".to_string()],
+            strict: false,
+        };
+        assert!(load(&song, &params).is_none());
+
+        store(&song, &params, &[song.clone(), notes.clone()], &compiled()).unwrap();
+        assert!(dir.join(CACHE_DIR).join("tune.song.json").exists());
+        let hit = load(&song, &params).unwrap();
+        assert_eq!(hit.compiled, compiled());
+        assert_eq!(hit.files, vec![song.clone(), notes.clone()]);
+
+        // Other options, or an edited file, build the song again
+        let strict = Params {
+            strict: true,
+            ..params.clone()
+        };
+        assert!(load(&song, &strict).is_none());
+        fs::write(&notes, "a s d g\n").unwrap();
+        assert!(load(&song, &params).is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_unreadable_cache_is_a_miss() {
+        let dir = temp_dir("garbage");
+        let song = dir.join("tune.song");
+        fs::create_dir_all(dir.join(CACHE_DIR)).unwrap();
+        fs::write(path_for(&song), "{ not json").unwrap();
+        let params = Params {
+            solo: Vec::new(),
+            mute: Vec::new(),
+            strict: false,
+        };
+        assert!(load(&song, &params).is_none());
+        assert_eq!(hash(b""), "cbf29ce484222325");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,646 @@
+//! Song validation: load a `.song` and every file it references, collecting
+//! all problems instead of stopping at the first one.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::beat::Beat;
+use crate::error::ClidawError;
+use crate::note::{Event, NoteEvent, Pattern, event_duration};
+use crate::song::InstrumentSource;
+use crate::{parser, scheduler, song};
+
+/// How serious a reported problem is. Only errors make `check` fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One problem found while checking a song, with file (and line and column
+/// when known).
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(
+                f,
+                "{}: {}:{}:{}: {}",
+                label,
+                self.file.display(),
+                line,
+                column,
+                self.message
+            ),
+            (Some(line), None) => {
+                write!(f, "{}: {}:{}: {}", label, self.file.display(), line, self.message)
+            }
+            (None, _) => write!(f, "{}: {}: {}", label, self.file.display(), self.message),
+        }
+    }
+}
+
+/// One track's total length, for the summary `check` prints
+#[derive(Debug, Clone)]
+pub struct TrackLength {
+    pub name: String,
+    /// None if one of its patterns failed to parse
+    pub beats: Option<f64>,
+}
+
+/// Collected diagnostics for one song.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub diagnostics: Vec<Diagnostic>,
+    pub track_lengths: Vec<TrackLength>,
+    /// The song's `align:` policy, reported alongside the track lengths
+    pub align: song::Align,
+}
+
+impl Report {
+    fn error(&mut self, file: &Path, line: Option<usize>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            file: file.to_path_buf(),
+            line,
+            column: None,
+            message,
+        });
+    }
+
+    /// An error from loading `file`. Parse errors keep their position, and
+    /// each of an instrument file's problems is reported on its own line;
+    /// the file is left out of other messages since the diagnostic names it.
+    fn load_error(&mut self, file: &Path, line: Option<usize>, error: ClidawError) {
+        if let ClidawError::Instrument { name, problems } = &error
+            && Path::new(name) == file
+        {
+            for problem in problems {
+                self.error(file, problem.line.or(line), problem.message.clone());
+            }
+            return;
+        }
+        let (line, column, message) = match error {
+            ClidawError::Parse { line, col, msg, .. } => (Some(line), col, msg),
+            ClidawError::Io { source, .. } => (line, None, format!("reading file: {}", source)),
+            ClidawError::Song { msg, .. } => (line, None, msg),
+            other => (line, None, other.to_string()),
+        };
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            file: file.to_path_buf(),
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn warning(&mut self, file: &Path, line: Option<usize>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            file: file.to_path_buf(),
+            line,
+            column: None,
+            message,
+        });
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .count()
+    }
+}
+
+/// Optional, stricter checks
+#[derive(Debug, Clone, Default)]
+pub struct CheckOptions {
+    /// Bars between bar lines must add up to the time signature (see `check_bars`)
+    pub strict_bars: bool,
+    /// Instrument warnings count as errors
+    pub strict: bool,
+}
+
+/// Check a `.song` file: every instrument and pattern must exist and parse,
+/// ADSR values must be in range, note octaves must be 0-8, drum lines should
+/// only be played by drum kits, and track lengths should agree to within one bar.
+pub fn check_song(song_path: &Path, options: &CheckOptions) -> Report {
+    let mut report = Report::default();
+
+    let mut song = match song::load(song_path) {
+        Ok(s) => s,
+        Err(e) => {
+            report.load_error(song_path, None, e);
+            return report;
+        }
+    };
+
+    for message in song.loud_gains(false) {
+        report.warning(song_path, None, message);
+    }
+
+    // Whether each track's instrument is a drum kit (None if it failed to load)
+    let mut is_drum_track = Vec::with_capacity(song.tracks.len());
+    for track in &song.tracks {
+        // Problems point at the .instr file, or the song line of an inline definition
+        let (file, line) = match &track.instrument {
+            InstrumentSource::File(path) => (path.as_path(), None),
+            InstrumentSource::Inline { line, .. } => (song_path, Some(*line)),
+            InstrumentSource::Preset(_) => (song_path, None),
+        };
+        match track.instrument.load() {
+            Ok(instr) => {
+                for problem in instr.validate() {
+                    report.error(file, problem.line.or(line), problem.message);
+                }
+                for warning in instr.warnings() {
+                    if options.strict {
+                        report.error(file, warning.line.or(line), warning.message);
+                    } else {
+                        report.warning(file, warning.line.or(line), warning.message);
+                    }
+                }
+                is_drum_track.push(Some(instr.kit.is_some()));
+            }
+            Err(e) => {
+                report.load_error(file, line, e);
+                is_drum_track.push(None);
+            }
+        }
+    }
+
+    // Under `missing: skip` a missing pattern only warns; its segment is
+    // left out of the patterns and counts its `length`
+    let mut skipped = Vec::new();
+    if song.missing == song::Missing::Skip {
+        for skip in song.skip_missing() {
+            report.warning(&skip.notes_path, None, skip.to_string());
+            skipped.push(skip.notes_path);
+        }
+    }
+
+    // Parse each pattern once, even if several tracks use it
+    let mut patterns: HashMap<PathBuf, Option<Pattern>> = HashMap::new();
+    for track in &song.tracks {
+        for seg in &track.sequence {
+            if patterns.contains_key(&seg.notes_path) || skipped.contains(&seg.notes_path) {
+                continue;
+            }
+            let pattern = check_pattern_file(&seg.notes_path, options, &mut report);
+            patterns.insert(seg.notes_path.clone(), pattern);
+        }
+    }
+
+    for (track, is_drum) in song.tracks.iter().zip(&is_drum_track) {
+        if *is_drum != Some(false) {
+            continue;
+        }
+        for seg in &track.sequence {
+            if let Some(Some(p)) = patterns.get(&seg.notes_path)
+                && p.events.iter().any(|e| matches!(e, Event::Drums(..)))
+            {
+                report.warning(
+                    &seg.notes_path,
+                    None,
+                    format!(
+                        "drum lines are silent on track {} ({}); its instrument is not 'type: drum'",
+                        track.name,
+                        track.instrument
+                    ),
+                );
+            }
+        }
+    }
+
+    check_track_lengths(song_path, &song, &patterns, &mut report);
+
+    report
+}
+
+/// Parse one `.notes` file in strict mode, reporting read/parse errors
+/// (including unknown characters) and out-of-range octaves.
+fn check_pattern_file(path: &Path, options: &CheckOptions, report: &mut Report) -> Option<Pattern> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            report.load_error(path, None, ClidawError::io(path, e));
+            return None;
+        }
+    };
+    let strict = parser::ParseOptions::for_file(path, true);
+    let pattern = match parser::parse_pattern_with(&content, strict) {
+        Ok(p) => p,
+        Err(e) => {
+            report.load_error(path, None, e);
+            return None;
+        }
+    };
+
+    let mut bar = 1;
+    for event in &pattern.events {
+        let notes: Vec<&NoteEvent> = match event {
+            Event::Note(n, _) => vec![n],
+            Event::Chord(notes, _) => notes.iter().collect(),
+            Event::Alt(alt) => alt.choices.iter().flatten().flat_map(Event::notes).collect(),
+            Event::Drums(..) | Event::Rest(_) | Event::Tie(_) => Vec::new(),
+            Event::BarLine => {
+                bar += 1;
+                Vec::new()
+            }
+        };
+        for n in notes.iter().filter(|n| n.octave > 8) {
+            report.error(
+                path,
+                None,
+                format!("bar {}: note {} is above octave 8", bar, n.label()),
+            );
+        }
+    }
+
+    if options.strict_bars {
+        check_bars(path, &pattern, report);
+    }
+
+    Some(pattern)
+}
+
+/// Check that each bar (the events between bar lines) adds up to the time
+/// signature's beats per bar. A short first bar is allowed as a pickup with
+/// a warning; patterns without bar lines aren't checked.
+pub fn check_bars(path: &Path, pattern: &Pattern, report: &mut Report) {
+    if !pattern.events.iter().any(|e| matches!(e, Event::BarLine)) {
+        return;
+    }
+
+    // Start and total beats of each non-empty bar
+    let mut bars = Vec::new();
+    let mut start = Beat::ZERO;
+    let mut total = Beat::ZERO;
+    let mut has_events = false;
+    for event in &pattern.events {
+        if matches!(event, Event::BarLine) {
+            if has_events {
+                bars.push((start, total));
+            }
+            start += total;
+            total = Beat::ZERO;
+            has_events = false;
+        } else {
+            total += event_duration(event);
+            has_events = true;
+        }
+    }
+    if has_events {
+        bars.push((start, total));
+    }
+
+    for (idx, &(start, beats)) in bars.iter().enumerate() {
+        // Each bar is held to the signature in effect where it starts
+        let signature = pattern.meter.signature_at(start.as_f64());
+        let expected = Beat::whole(signature.0 as i64);
+        if beats == expected {
+            continue;
+        }
+        let message = format!(
+            "bar {} has {} beat{}, expected {} for {}/{} time",
+            idx + 1,
+            beats,
+            if beats == Beat::ONE { "" } else { "s" },
+            expected,
+            signature.0,
+            signature.1
+        );
+        if idx == 0 && beats < expected {
+            report.warning(path, None, format!("{} (treated as a pickup)", message));
+        } else {
+            report.error(path, None, message);
+        }
+    }
+}
+
+/// Record every track's length, and warn when tracks end more than one bar
+/// apart under `align: pad` (loop and truncate make them end together).
+/// Tracks with unparsable patterns have no length since it is unknown.
+fn check_track_lengths(
+    song_path: &Path,
+    song: &song::Song,
+    patterns: &HashMap<PathBuf, Option<Pattern>>,
+    report: &mut Report,
+) {
+    report.align = song.align;
+    if song.arrangement.is_some() {
+        check_sections(song_path, song, patterns, report);
+        return;
+    }
+    let mut lengths: Vec<(usize, f64)> = Vec::new();
+    for (idx, track) in song.tracks.iter().enumerate() {
+        let offset = Beat::from_f64(track.offset);
+        let beats = track.sequence.iter().try_fold(offset, |total, seg| {
+            match (patterns.get(&seg.notes_path), seg.length) {
+                (Some(Some(p)), _) => Some(total + p.length_beats() * seg.times),
+                (None, Some(beats)) => Some(total + Beat::from_f64(beats) * seg.times),
+                _ => None,
+            }
+        });
+        let beats = beats.map(Beat::as_f64);
+        report.track_lengths.push(TrackLength {
+            name: track.name.clone(),
+            beats,
+        });
+        if let Some(beats) = beats {
+            lengths.push((idx, beats));
+        }
+    }
+
+    if song.align != song::Align::Pad {
+        return;
+    }
+    let beats_per_bar = song.time_signature.0 as f64;
+    let Some(&(_, longest)) = lengths.iter().max_by(|a, b| a.1.total_cmp(&b.1)) else {
+        return;
+    };
+    for &(idx, len) in &lengths {
+        if longest - len > beats_per_bar {
+            report.warning(
+                song_path,
+                None,
+                format!(
+                    "track {} ({}) is {} beats long, {} beats shorter than the longest track (see 'align:')",
+                    idx,
+                    song.tracks[idx].name,
+                    len,
+                    longest - len
+                ),
+            );
+        }
+    }
+}
+
+/// Tracks of a song with sections all last the whole arrangement; under
+/// `section_align: error` a section whose parts differ is an error. Nothing
+/// is known if a pattern didn't parse.
+fn check_sections(
+    song_path: &Path,
+    song: &song::Song,
+    patterns: &HashMap<PathBuf, Option<Pattern>>,
+    report: &mut Report,
+) {
+    let parsed: Option<HashMap<PathBuf, Pattern>> = patterns
+        .iter()
+        .map(|(path, pattern)| Some((path.clone(), pattern.clone()?)))
+        .collect();
+    let total = match parsed.map(|parsed| scheduler::slot_starts(song, &parsed)) {
+        Some(Ok(starts)) => starts.last().map(|end| end.as_f64()),
+        Some(Err(e)) => {
+            report.error(song_path, None, e.to_string());
+            None
+        }
+        None => None,
+    };
+    for track in &song.tracks {
+        report.track_lengths.push(TrackLength {
+            name: track.name.clone(),
+            beats: total,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn test_reports_all_problems() {
+        let dir = temp_dir("all");
+        fs::write(dir.join("bad.instr"), "attack: -1\nsustain: 2\n").unwrap();
+        fs::write(dir.join("high.notes"), "octave: 8\na k\n").unwrap();
+        fs::write(
+            dir.join("test.song"),
+            "instrument: bad.instr\nhigh.notes\nmissing.notes\n\ninstrument: missing.instr\nhigh.notes\n",
+        )
+        .unwrap();
+
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        let messages: Vec<String> = report.diagnostics.iter().map(|d| d.to_string()).collect();
+
+        assert!(messages.iter().any(|m| m.contains("attack")), "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("sustain")), "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("missing.instr")), "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("missing.notes")), "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("C9")), "{:?}", messages);
+        assert_eq!(report.warning_count(), 0);
+        assert_eq!(report.error_count(), 5);
+    }
+
+    #[test]
+    fn test_unknown_characters_are_errors() {
+        let dir = temp_dir("strict");
+        fs::write(dir.join("typo.notes"), "a s\na q s\n").unwrap();
+        fs::write(dir.join("lead.instr"), "attack: 0.01\n").unwrap();
+        fs::write(dir.join("test.song"), "instrument: lead.instr\ntypo.notes\n").unwrap();
+
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        let messages: Vec<String> = report.diagnostics.iter().map(|d| d.to_string()).collect();
+        let expected = format!(
+            "error: {}:2:3: unknown character 'q'",
+            dir.join("typo.notes").display()
+        );
+        assert_eq!(messages, vec![expected]);
+    }
+
+    #[test]
+    fn test_warns_on_track_length_mismatch() {
+        let dir = temp_dir("lengths");
+        fs::write(dir.join("one.instr"), "attack: 0.01\n").unwrap();
+        fs::write(dir.join("bar.notes"), "beats: 4\na s d f\n").unwrap();
+        fs::write(
+            dir.join("test.song"),
+            "instrument: one.instr\nbar.notes * 4\ninstrument: one.instr\nbar.notes * 2\n",
+        )
+        .unwrap();
+
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        assert_eq!(report.error_count(), 0);
+        assert_eq!(report.warning_count(), 1);
+        let beats: Vec<_> = report.track_lengths.iter().map(|t| t.beats).collect();
+        assert_eq!(beats, vec![Some(16.0), Some(8.0)]);
+
+        // Looping the short track is deliberate, so no warning
+        fs::write(
+            dir.join("test.song"),
+            "align: loop
+instrument: one.instr
+bar.notes * 4
+instrument: one.instr
+bar.notes * 2
+",
+        )
+        .unwrap();
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        assert_eq!(report.warning_count(), 0);
+        assert_eq!(report.align, song::Align::Loop);
+    }
+
+    #[test]
+    fn test_missing_skip_warns() {
+        let dir = temp_dir("missing");
+        fs::write(dir.join("one.instr"), "attack: 0.01\n").unwrap();
+        fs::write(dir.join("bar.notes"), "beats: 4\na s d f\n").unwrap();
+        let song = "instrument: one.instr\nbar.notes\nlater.notes * 2 length 8\ngone.notes\n";
+        fs::write(dir.join("test.song"), song).unwrap();
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        assert_eq!(report.error_count(), 2);
+
+        fs::write(dir.join("test.song"), format!("missing: skip\n{}", song)).unwrap();
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        let messages: Vec<String> = report.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(report.error_count(), 0, "{:?}", messages);
+        assert_eq!(report.warning_count(), 2);
+        assert!(messages[0].contains("16 beats of silence"), "{:?}", messages);
+        assert!(messages[1].contains("segment is skipped"), "{:?}", messages);
+        assert_eq!(report.track_lengths[0].beats, Some(20.0));
+    }
+
+    #[test]
+    fn test_sections_align_tracks() {
+        let dir = temp_dir("sections");
+        fs::write(dir.join("one.instr"), "attack: 0.01\n").unwrap();
+        fs::write(dir.join("bar.notes"), "beats: 4\na s d f\n").unwrap();
+        let song = "instrument: one.instr\nname: bass\ninstrument: one.instr\nname: lead\n\
+                    section verse { bass: bar.notes * 2, lead: bar.notes }\n\
+                    arrangement: verse verse\n";
+        fs::write(dir.join("test.song"), song).unwrap();
+
+        // Padded sections: every track lasts the whole arrangement
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        assert_eq!((report.error_count(), report.warning_count()), (0, 0));
+        let beats: Vec<_> = report.track_lengths.iter().map(|t| t.beats).collect();
+        assert_eq!(beats, vec![Some(16.0), Some(16.0)]);
+
+        fs::write(dir.join("test.song"), format!("section_align: error\n{}", song)).unwrap();
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        assert_eq!(report.error_count(), 1);
+        assert!(report.diagnostics[0].message.contains("track lead is 4 beats long"));
+    }
+
+    #[test]
+    fn test_warns_on_drums_without_kit() {
+        let dir = temp_dir("drums");
+        fs::write(dir.join("kit.instr"), "type: drum\n").unwrap();
+        fs::write(dir.join("tone.instr"), "attack: 0.01\n").unwrap();
+        fs::write(dir.join("beat.notes"), "kick: x - x -\n").unwrap();
+        fs::write(
+            dir.join("test.song"),
+            "instrument: kit.instr\nbeat.notes\ninstrument: tone.instr\nbeat.notes\n",
+        )
+        .unwrap();
+
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        assert_eq!(report.error_count(), 0);
+        assert_eq!(report.warning_count(), 1);
+        assert!(report.diagnostics[0].message.contains("tone.instr"));
+    }
+
+    #[test]
+    fn test_gains() {
+        let dir = temp_dir("gains");
+        fs::write(dir.join("loud.instr"), "gain: 3\n").unwrap();
+        fs::write(dir.join("silent.instr"), "gain: -1\n").unwrap();
+        fs::write(dir.join("a.notes"), "a s d f\n").unwrap();
+        fs::write(
+            dir.join("test.song"),
+            "master_volume: 2.5\nbus: drums { gain: 4 }\n\
+             instrument: loud.instr\na.notes\ninstrument: silent.instr\na.notes\n",
+        )
+        .unwrap();
+
+        let report = check_song(&dir.join("test.song"), &CheckOptions::default());
+        let messages: Vec<&str> = report.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "bus 'drums' gain 4 is above 2 and will likely clip",
+                "master_volume 2.5 is above 2 and will likely clip",
+                "gain 3 is above 2 and will likely clip",
+                "gain must be non-negative, got -1",
+            ]
+        );
+        assert_eq!((report.error_count(), report.warning_count()), (1, 3));
+        assert_eq!(report.diagnostics[2].line, Some(1));
+
+        // --strict turns the instrument's warning (only) into an error
+        let strict = CheckOptions {
+            strict: true,
+            ..CheckOptions::default()
+        };
+        let report = check_song(&dir.join("test.song"), &strict);
+        assert_eq!((report.error_count(), report.warning_count()), (2, 2));
+    }
+
+    #[test]
+    fn test_strict_bars() {
+        let path = Path::new("bars.notes");
+        let check = |text: &str| {
+            let mut report = Report::default();
+            let pattern = parser::parse_pattern(text).unwrap();
+            check_bars(path, &pattern, &mut report);
+            report
+        };
+
+        // No bar lines: nothing to check
+        assert!(check("a s d").diagnostics.is_empty());
+        // Balanced bars, including a repeat whose passes are separate bars
+        assert!(check("a s d f | g h j k |\n|: a - - - :|").diagnostics.is_empty());
+        // Fractional rests count their length
+        assert!(check("a -:0.5 s -:0.5 d | f - -:.5 g -:.5 |").diagnostics.is_empty());
+        // So do note lengths, dotted notes and tuplets
+        let tuplets = "(a s d)/3 f g | a:1. s:0.5 d:2 | (f g h j k)/5 | (a s d)/3 -:2";
+        assert!(check(tuplets).diagnostics.is_empty());
+
+        // Short first bar: a pickup
+        let report = check("a | s d f g |");
+        assert_eq!((report.error_count(), report.warning_count()), (0, 1));
+        assert!(report.diagnostics[0].message.contains("pickup"));
+
+        let report = check("time_signature: 3/4\na s d | f g | h j k l");
+        let messages: Vec<&str> = report.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "bar 2 has 2 beats, expected 3 for 3/4 time",
+                "bar 3 has 4 beats, expected 3 for 3/4 time"
+            ]
+        );
+        assert_eq!(report.error_count(), 2);
+
+        // Bars follow time signature changes
+        let changing =
+            "a s d f |\ntime_signature: 7/8\na s d f g h j |\ntime_signature: 4/4\na s d f |";
+        assert!(check(changing).diagnostics.is_empty());
+        let report = check("a s d f |\ntime_signature: 7/8\na s d f |");
+        assert_eq!(report.diagnostics[0].message, "bar 2 has 4 beats, expected 7 for 7/8 time");
+    }
+}
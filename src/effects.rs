@@ -0,0 +1,740 @@
+//! Master effects: a Freeverb-style reverb (Schroeder's parallel comb
+//! filters into series allpass filters) that tracks send into. And the
+//! per-track chains a `.song` declares with `effects:`, run in the order
+//! written on each frame of the track's left and right channels.
+//!
+//! Every buffer is allocated in `ReverbLine::new` and `EffectChain::new`
+//! from the sample rate, so processing on the audio thread never allocates.
+
+use std::f64::consts::PI;
+
+use crate::synth::Delay;
+
+/// Comb filter delays at 44.1 kHz (Freeverb's tuning), scaled to the
+/// sample rate. Mutually prime-ish lengths keep the echoes from piling up.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+
+/// Allpass filter delays at 44.1 kHz
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+
+/// Sample rate the tunings are given at
+const TUNING_RATE: f64 = 44_100.0;
+
+/// Allpass feedback, which diffuses the echoes without colouring them
+const ALLPASS_FEEDBACK: f64 = 0.5;
+
+/// Input gain so eight combs summed stay around unity
+const INPUT_GAIN: f64 = 0.015;
+
+/// Comb feedback for `size` 0 and the range `size` 1 adds: the largest
+/// room rings for seconds, the smallest is a short ambience
+const FEEDBACK_MIN: f64 = 0.7;
+const FEEDBACK_RANGE: f64 = 0.28;
+
+/// Share of each comb's high end lost per echo at `damping` 1
+const DAMPING_SCALE: f64 = 0.4;
+
+/// Level below which the reverb tail counts as silent (-60 dB)
+const TAIL_SILENCE: f64 = 0.001;
+
+/// Longest reverb tail waited for after playback ends
+const MAX_TAIL_SECS: f64 = 10.0;
+
+/// Reverb settings for the master mix
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reverb {
+    /// Level of the reverb added to the dry mix (0.0..=1.0; 0 = off)
+    pub mix: f64,
+    /// Room size: how long the tail rings (0.0..=1.0)
+    pub size: f64,
+    /// How quickly high frequencies die away in the tail (0.0..=1.0)
+    pub damping: f64,
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Self {
+            mix: 0.0,
+            size: 0.5,
+            damping: 0.5,
+        }
+    }
+}
+
+impl Reverb {
+    fn feedback(&self) -> f64 {
+        FEEDBACK_MIN + FEEDBACK_RANGE * self.size.clamp(0.0, 1.0)
+    }
+
+    /// Seconds until the tail has died away (0 when the reverb is off)
+    pub fn tail_secs(&self) -> f64 {
+        if self.mix <= 0.0 {
+            return 0.0;
+        }
+        let longest = COMB_TUNING[COMB_TUNING.len() - 1] as f64 / TUNING_RATE;
+        let repeats = TAIL_SILENCE.ln() / self.feedback().ln();
+        (longest * repeats).min(MAX_TAIL_SECS)
+    }
+}
+
+/// Feedback comb filter with a one-pole lowpass in the loop
+struct Comb {
+    buffer: Vec<f64>,
+    pos: usize,
+    /// Lowpassed output fed back, kept between samples
+    filtered: f64,
+}
+
+impl Comb {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(1)],
+            pos: 0,
+            filtered: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f64, feedback: f64, damping: f64) -> f64 {
+        let output = self.buffer[self.pos];
+        self.filtered = output * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.pos] = input + self.filtered * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        output
+    }
+}
+
+/// Schroeder allpass filter
+struct Allpass {
+    buffer: Vec<f64>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(1)],
+            pos: 0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = input + delayed * ALLPASS_FEEDBACK;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// A running reverb: feed it the sum of the tracks' sends, one sample at a
+/// time, and add what it returns to the dry mix
+pub struct ReverbLine {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+    feedback: f64,
+    damping: f64,
+    mix: f64,
+}
+
+impl ReverbLine {
+    /// None when `reverb.mix` is 0, so a dry song skips the reverb entirely
+    pub fn new(reverb: &Reverb, sample_rate: f64) -> Option<Self> {
+        if reverb.mix <= 0.0 {
+            return None;
+        }
+        let scale = |len: usize| (len as f64 * sample_rate / TUNING_RATE).round() as usize;
+        Some(Self {
+            combs: COMB_TUNING.iter().map(|&len| Comb::new(scale(len))).collect(),
+            allpasses: ALLPASS_TUNING.iter().map(|&len| Allpass::new(scale(len))).collect(),
+            feedback: reverb.feedback(),
+            damping: DAMPING_SCALE * reverb.damping.clamp(0.0, 1.0),
+            mix: reverb.mix.min(1.0),
+        })
+    }
+
+    /// Feed one sample of the send in and return the reverb, scaled by the
+    /// mix level
+    pub fn process(&mut self, input: f64) -> f64 {
+        let input = input * INPUT_GAIN;
+        let (feedback, damping) = (self.feedback, self.damping);
+        let mut wet: f64 = self
+            .combs
+            .iter_mut()
+            .map(|comb| comb.process(input, feedback, damping))
+            .sum();
+        for allpass in self.allpasses.iter_mut() {
+            wet = allpass.process(wet);
+        }
+        wet * self.mix
+    }
+}
+
+/// Longest echo a chain's `delay` can hold (seconds)
+pub const MAX_DELAY_SECS: f64 = 5.0;
+
+/// Damping of a chain filter at resonance 0 and 1: from a gentle roll-off
+/// with no peak to a sharp ring at the cutoff
+const FILTER_DAMPING_MAX: f64 = 2.0;
+const FILTER_DAMPING_MIN: f64 = 0.05;
+
+/// One effect in a track's chain, as written in `effects:`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    /// `lowpass(cutoff, resonance)`: cutoff in Hz, resonance 0..=1
+    Lowpass { cutoff: f64, resonance: f64 },
+    /// `highpass(cutoff, resonance)`
+    Highpass { cutoff: f64, resonance: f64 },
+    /// `delay(time, feedback, mix)`: an echo on each channel
+    Delay(Delay),
+    /// `gain(level)`: turn the track up or down
+    Gain(f64),
+    /// `drive(gain)`: boost into a soft clipper (tanh) that never passes
+    /// full scale, so what comes before it decides what distorts
+    Drive(f64),
+    /// `pan(position)`: -1 (left) to 1 (right), a balance control that
+    /// turns the far side down
+    Pan(f64),
+}
+
+impl Effect {
+    /// Seconds the effect rings on after its input falls silent
+    pub fn tail_secs(&self) -> f64 {
+        match self {
+            Effect::Delay(delay) if delay.mix > 0.0 => delay.tail_secs(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// A value of an effect, checked against its range
+fn value(
+    effect: &str,
+    name: &str,
+    value: f64,
+    ok: impl Fn(f64) -> bool,
+    expected: &str,
+) -> Result<f64, String> {
+    if value.is_finite() && ok(value) {
+        Ok(value)
+    } else {
+        Err(format!("invalid {} {} '{}' (expected {})", effect, name, value, expected))
+    }
+}
+
+/// Parse one `name(value, ...)` effect
+fn parse_effect(text: &str) -> Result<Effect, String> {
+    let (name, rest) = text
+        .split_once('(')
+        .ok_or_else(|| format!("expected an effect like 'lowpass(1200, 0.5)', got '{}'", text))?;
+    let name = name.trim();
+    let args = rest
+        .trim_end()
+        .strip_suffix(')')
+        .ok_or_else(|| format!("effect '{}' is missing its closing ')'", name))?;
+    let values = args
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse::<f64>().map_err(|_| format!("invalid value '{}' in {}()", v, name)))
+        .collect::<Result<Vec<f64>, String>>()?;
+    let takes = |params: &[&str]| {
+        if values.len() == params.len() {
+            return Ok(());
+        }
+        Err(format!(
+            "{} takes {} value{} ({}), got {}",
+            name,
+            params.len(),
+            if params.len() == 1 { "" } else { "s" },
+            params.join(", "),
+            values.len()
+        ))
+    };
+    let level = |x: f64| (0.0..=1.0).contains(&x);
+    match name {
+        "lowpass" | "highpass" => {
+            takes(&["cutoff", "resonance"])?;
+            let cutoff = value(name, "cutoff", values[0], |x| x > 0.0, "Hz above 0")?;
+            let resonance = value(name, "resonance", values[1], level, "0 to 1")?;
+            Ok(if name == "lowpass" {
+                Effect::Lowpass { cutoff, resonance }
+            } else {
+                Effect::Highpass { cutoff, resonance }
+            })
+        }
+        "delay" => {
+            takes(&["time", "feedback", "mix"])?;
+            let time_range = format!("seconds above 0, up to {}", MAX_DELAY_SECS);
+            let time = |x| x > 0.0 && x <= MAX_DELAY_SECS;
+            let feedback = |x| (0.0..1.0).contains(&x);
+            Ok(Effect::Delay(Delay {
+                time: value(name, "time", values[0], time, &time_range)?,
+                feedback: value(name, "feedback", values[1], feedback, "0 to below 1")?,
+                mix: value(name, "mix", values[2], level, "0 to 1")?,
+            }))
+        }
+        "gain" => {
+            takes(&["level"])?;
+            Ok(Effect::Gain(value(name, "level", values[0], |x| x >= 0.0, "0 or more")?))
+        }
+        "drive" => {
+            takes(&["gain"])?;
+            Ok(Effect::Drive(value(name, "gain", values[0], |x| x > 0.0, "above 0")?))
+        }
+        "pan" => {
+            takes(&["position"])?;
+            let position = |x| (-1.0..=1.0).contains(&x);
+            Ok(Effect::Pan(value(name, "position", values[0], position, "-1 to 1")?))
+        }
+        _ => Err(format!(
+            "unknown effect '{}' (expected lowpass, highpass, delay, gain, drive or pan)",
+            name
+        )),
+    }
+}
+
+/// Parse an `effects:` value: effects joined by `>`, in the order the
+/// track's sound passes through them
+pub fn parse_chain(text: &str) -> Result<Vec<Effect>, String> {
+    text.split('>')
+        .map(str::trim)
+        .map(|part| {
+            if part.is_empty() {
+                Err("empty effect in the chain (effects are joined by '>')".to_string())
+            } else {
+                parse_effect(part)
+            }
+        })
+        .collect()
+}
+
+/// Something that runs on a track's frames: `buf` holds one sample per
+/// channel (left, right), changed in place
+pub trait Process {
+    fn process(&mut self, buf: &mut [f64]);
+}
+
+/// Which side of the cutoff a filter keeps
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pass {
+    Low,
+    High,
+}
+
+/// State-variable filter (the trapezoidal form, which stays stable up to
+/// Nyquist), one state per channel
+#[derive(Debug, Clone)]
+struct Filter {
+    pass: Pass,
+    cutoff: f64,
+    sample_rate: f64,
+    damping: f64,
+    a1: f64,
+    a2: f64,
+    a3: f64,
+    /// Integrator states per channel
+    state: [[f64; 2]; 2],
+}
+
+impl Filter {
+    fn new(pass: Pass, cutoff: f64, resonance: f64, sample_rate: f64) -> Self {
+        let damping = FILTER_DAMPING_MAX
+            - (FILTER_DAMPING_MAX - FILTER_DAMPING_MIN) * resonance.clamp(0.0, 1.0);
+        let mut filter = Self {
+            pass,
+            cutoff,
+            sample_rate,
+            damping,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            state: [[0.0; 2]; 2],
+        };
+        filter.set_cutoff(cutoff);
+        filter
+    }
+
+    /// Move the cutoff, keeping the filter's state so the sound carries on
+    fn set_cutoff(&mut self, cutoff: f64) {
+        self.cutoff = cutoff;
+        let cutoff = cutoff.clamp(1.0, self.sample_rate * 0.49);
+        let g = (PI * cutoff / self.sample_rate).tan();
+        self.a1 = 1.0 / (1.0 + g * (g + self.damping));
+        self.a2 = g * self.a1;
+        self.a3 = g * g * self.a1;
+    }
+}
+
+impl Process for Filter {
+    fn process(&mut self, buf: &mut [f64]) {
+        for (sample, [ic1, ic2]) in buf.iter_mut().zip(self.state.iter_mut()) {
+            let v3 = *sample - *ic2;
+            let band = self.a1 * *ic1 + self.a2 * v3;
+            let low = *ic2 + self.a2 * *ic1 + self.a3 * v3;
+            *ic1 = 2.0 * band - *ic1;
+            *ic2 = 2.0 * low - *ic2;
+            *sample = match self.pass {
+                Pass::Low => low,
+                Pass::High => *sample - self.damping * band - low,
+            };
+        }
+    }
+}
+
+/// Feedback echo, one line per channel
+#[derive(Debug, Clone)]
+struct Echo {
+    buffer: Vec<[f64; 2]>,
+    pos: usize,
+    feedback: f64,
+    mix: f64,
+}
+
+impl Echo {
+    fn new(delay: &Delay, sample_rate: f64) -> Self {
+        let len = ((delay.time * sample_rate).round() as usize).max(1);
+        Self {
+            buffer: vec![[0.0; 2]; len],
+            pos: 0,
+            feedback: delay.feedback,
+            mix: delay.mix,
+        }
+    }
+}
+
+impl Process for Echo {
+    fn process(&mut self, buf: &mut [f64]) {
+        let slot = &mut self.buffer[self.pos];
+        for (sample, delayed) in buf.iter_mut().zip(slot.iter_mut()) {
+            let echo = *delayed;
+            *delayed = *sample + echo * self.feedback;
+            *sample += echo * self.mix;
+        }
+        self.pos = (self.pos + 1) % self.buffer.len();
+    }
+}
+
+/// Fixed gain per channel: `gain` and `pan`
+#[derive(Debug, Clone)]
+struct Gains([f64; 2]);
+
+impl Process for Gains {
+    fn process(&mut self, buf: &mut [f64]) {
+        for (sample, gain) in buf.iter_mut().zip(self.0) {
+            *sample *= gain;
+        }
+    }
+}
+
+/// Soft clipper: `drive`
+#[derive(Debug, Clone)]
+struct Drive(f64);
+
+impl Process for Drive {
+    fn process(&mut self, buf: &mut [f64]) {
+        for sample in buf.iter_mut() {
+            *sample = (*sample * self.0).tanh();
+        }
+    }
+}
+
+/// One running effect of a chain
+#[derive(Debug, Clone)]
+enum Stage {
+    Filter(Filter),
+    Echo(Echo),
+    Gains(Gains),
+    Drive(Drive),
+}
+
+impl Process for Stage {
+    fn process(&mut self, buf: &mut [f64]) {
+        match self {
+            Stage::Filter(filter) => filter.process(buf),
+            Stage::Echo(echo) => echo.process(buf),
+            Stage::Gains(gains) => gains.process(buf),
+            Stage::Drive(drive) => drive.process(buf),
+        }
+    }
+}
+
+/// A track's running effects, in the order they were written
+#[derive(Debug, Clone)]
+pub struct EffectChain {
+    stages: Vec<Stage>,
+}
+
+impl EffectChain {
+    /// None for an empty chain, so a track without one skips it entirely
+    pub fn new(effects: &[Effect], sample_rate: f64) -> Option<Self> {
+        if effects.is_empty() {
+            return None;
+        }
+        let stages = effects
+            .iter()
+            .map(|effect| match effect {
+                Effect::Lowpass { cutoff, resonance } => {
+                    Stage::Filter(Filter::new(Pass::Low, *cutoff, *resonance, sample_rate))
+                }
+                Effect::Highpass { cutoff, resonance } => {
+                    Stage::Filter(Filter::new(Pass::High, *cutoff, *resonance, sample_rate))
+                }
+                Effect::Delay(delay) => Stage::Echo(Echo::new(delay, sample_rate)),
+                Effect::Gain(level) => Stage::Gains(Gains([*level; 2])),
+                Effect::Drive(gain) => Stage::Drive(Drive(*gain)),
+                Effect::Pan(position) => {
+                    let position = position.clamp(-1.0, 1.0);
+                    Stage::Gains(Gains([(1.0 - position).min(1.0), (1.0 + position).min(1.0)]))
+                }
+            })
+            .collect();
+        Some(Self { stages })
+    }
+}
+
+impl EffectChain {
+    /// The filter `cutoff` automation moves (see `automated_cutoff`)
+    fn automated_filter(&mut self) -> Option<&mut Filter> {
+        self.stages.iter_mut().find_map(|stage| match stage {
+            Stage::Filter(filter) => Some(filter),
+            _ => None,
+        })
+    }
+
+    /// Cutoff of the filter `cutoff` automation moves
+    pub fn cutoff(&self) -> Option<f64> {
+        self.stages.iter().find_map(|stage| match stage {
+            Stage::Filter(filter) => Some(filter.cutoff),
+            _ => None,
+        })
+    }
+
+    /// Move that filter's cutoff (Hz)
+    pub fn set_cutoff(&mut self, cutoff: f64) {
+        if let Some(filter) = self.automated_filter() {
+            filter.set_cutoff(cutoff);
+        }
+    }
+}
+
+/// Cutoff of the first lowpass or highpass in a chain: the one `cutoff`
+/// automation moves (None without a filter)
+pub fn automated_cutoff(effects: &[Effect]) -> Option<f64> {
+    effects.iter().find_map(|effect| match effect {
+        Effect::Lowpass { cutoff, .. } | Effect::Highpass { cutoff, .. } => Some(*cutoff),
+        _ => None,
+    })
+}
+
+impl Process for EffectChain {
+    fn process(&mut self, buf: &mut [f64]) {
+        for stage in self.stages.iter_mut() {
+            stage.process(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 44_100.0;
+
+    /// The reverb's response to a single full-scale sample
+    fn impulse_response(reverb: &Reverb, secs: f64) -> Vec<f64> {
+        let mut line = ReverbLine::new(reverb, SAMPLE_RATE).unwrap();
+        (0..(secs * SAMPLE_RATE) as usize)
+            .map(|i| line.process(if i == 0 { 1.0 } else { 0.0 }))
+            .collect()
+    }
+
+    /// RMS level of each consecutive 250 ms window (single peaks wobble as
+    /// the combs' echoes line up and drift apart; the energy doesn't)
+    fn window_levels(samples: &[f64]) -> Vec<f64> {
+        samples
+            .chunks((SAMPLE_RATE * 0.25) as usize)
+            .map(|w| (w.iter().map(|s| s * s).sum::<f64>() / w.len() as f64).sqrt())
+            .collect()
+    }
+
+    #[test]
+    fn test_impulse_tail_decays() {
+        for size in [0.0, 0.5, 1.0] {
+            let reverb = Reverb {
+                mix: 1.0,
+                size,
+                damping: 0.5,
+            };
+            let response = impulse_response(&reverb, 3.0);
+            assert!(response.iter().all(|s| s.is_finite()), "size {}", size);
+            assert!(response.iter().all(|s| s.abs() <= 1.0), "size {}", size);
+            // Every window is quieter than the one before
+            let levels = window_levels(&response);
+            assert!(levels[0] > 0.0, "size {}", size);
+            for pair in levels.windows(2) {
+                assert!(pair[1] < pair[0], "size {}: {:?}", size, levels);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bigger_rooms_ring_longer() {
+        let tail = |size| {
+            let reverb = Reverb {
+                mix: 1.0,
+                size,
+                damping: 0.5,
+            };
+            let levels = window_levels(&impulse_response(&reverb, 2.0));
+            levels[levels.len() - 1]
+        };
+        assert!(tail(0.9) > tail(0.2) * 10.0);
+        let small = Reverb {
+            mix: 1.0,
+            size: 0.2,
+            ..Reverb::default()
+        };
+        let large = Reverb { size: 0.9, ..small.clone() };
+        assert!(large.tail_secs() > small.tail_secs());
+    }
+
+    #[test]
+    fn test_loud_input_stays_bounded() {
+        let reverb = Reverb {
+            mix: 1.0,
+            size: 1.0,
+            damping: 0.0,
+        };
+        let mut line = ReverbLine::new(&reverb, 48_000.0).unwrap();
+        // Ten seconds of a full-scale square wave at 100 Hz
+        let peak = (0..480_000)
+            .map(|i| line.process(if (i / 240) % 2 == 0 { 1.0 } else { -1.0 }))
+            .fold(0.0, |peak: f64, s| {
+                assert!(s.is_finite());
+                peak.max(s.abs())
+            });
+        assert!(peak < 4.0, "peak {}", peak);
+    }
+
+    /// `secs` of a sine at `freq` (level 0.5) through a chain, as frames
+    fn through_chain(chain: &str, freq: f64, secs: f64) -> Vec<[f64; 2]> {
+        let effects = parse_chain(chain).unwrap();
+        let mut chain = EffectChain::new(&effects, SAMPLE_RATE).unwrap();
+        (0..(secs * SAMPLE_RATE) as usize)
+            .map(|i| {
+                let sample = 0.5 * (2.0 * PI * freq * i as f64 / SAMPLE_RATE).sin();
+                let mut frame = [sample; 2];
+                chain.process(&mut frame);
+                frame
+            })
+            .collect()
+    }
+
+    /// RMS level of one side of the frames, skipping the first 100 ms
+    fn level(frames: &[[f64; 2]], side: usize) -> f64 {
+        let settled = &frames[(SAMPLE_RATE * 0.1) as usize..];
+        (settled.iter().map(|f| f[side] * f[side]).sum::<f64>() / settled.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_parse_chain() {
+        let chain = parse_chain("lowpass(1200, 0.5) > delay(0.375, 0.35, 0.3) > pan(-0.2)");
+        let delay = Delay {
+            time: 0.375,
+            feedback: 0.35,
+            mix: 0.3,
+        };
+        assert_eq!(
+            chain.unwrap(),
+            [
+                Effect::Lowpass {
+                    cutoff: 1200.0,
+                    resonance: 0.5
+                },
+                Effect::Delay(delay),
+                Effect::Pan(-0.2),
+            ]
+        );
+        let spaced = parse_chain("gain(0.5)>drive( 4 )").unwrap();
+        assert_eq!(spaced, [Effect::Gain(0.5), Effect::Drive(4.0)]);
+
+        let err = |text: &str| parse_chain(text).unwrap_err();
+        assert_eq!(err("lowpass(1200)"), "lowpass takes 2 values (cutoff, resonance), got 1");
+        assert_eq!(err("pan(0.1, 0.2)"), "pan takes 1 value (position), got 2");
+        assert!(err("flanger(1)").starts_with("unknown effect 'flanger'"), "{}", err("flanger(1)"));
+        assert_eq!(err("pan(-2)"), "invalid pan position '-2' (expected -1 to 1)");
+        assert!(err("delay(0.3, 1, 0.5)").contains("feedback '1'"));
+        assert!(err("delay(9, 0.5, 0.5)").contains("time '9'"));
+        assert_eq!(err("gain(x)"), "invalid value 'x' in gain()");
+        assert_eq!(err("gain(1) >"), "empty effect in the chain (effects are joined by '>')");
+        assert_eq!(err("gain(1"), "effect 'gain' is missing its closing ')'");
+        assert!(err("gain").starts_with("expected an effect like"));
+        assert!(EffectChain::new(&[], SAMPLE_RATE).is_none());
+    }
+
+    #[test]
+    fn test_filters_keep_their_side_of_the_cutoff() {
+        let low = through_chain("lowpass(500, 0)", 100.0, 0.5);
+        assert!(level(&low, 0) > 0.3, "{}", level(&low, 0));
+        assert!(level(&through_chain("lowpass(500, 0)", 8000.0, 0.5), 0) < 0.02);
+        let high = through_chain("highpass(2000, 0)", 8000.0, 0.5);
+        assert!(level(&high, 0) > 0.3, "{}", level(&high, 0));
+        assert!(level(&through_chain("highpass(2000, 0)", 100.0, 0.5), 0) < 0.02);
+        // Resonance lifts the cutoff itself
+        let flat = level(&through_chain("lowpass(1000, 0)", 1000.0, 0.5), 0);
+        let ringing = level(&through_chain("lowpass(1000, 0.9)", 1000.0, 0.5), 0);
+        assert!(ringing > flat * 4.0, "{} vs {}", ringing, flat);
+        // Stable and finite right up to Nyquist
+        let top = through_chain("lowpass(30000, 1) > highpass(30000, 1)", 15000.0, 0.5);
+        assert!(top.iter().flatten().all(|s| s.is_finite() && s.abs() < 100.0));
+    }
+
+    #[test]
+    fn test_chain_runs_in_order() {
+        // A low note, driven and then filtered, keeps the drive's overtones
+        // above the cutoff only if the filter comes second...
+        let driven_first = through_chain("drive(20) > lowpass(300, 0)", 100.0, 0.5);
+        let filtered_first = through_chain("lowpass(300, 0) > drive(20)", 100.0, 0.5);
+        // ...and the other way round the clipper has the last word: full
+        // scale square-ish waves
+        let peak = |frames: &[[f64; 2]]| frames.iter().fold(0.0_f64, |p, f| p.max(f[0].abs()));
+        assert!(peak(&filtered_first) > 0.99);
+        assert!(peak(&driven_first) < peak(&filtered_first));
+        let difference: f64 = driven_first
+            .iter()
+            .zip(&filtered_first)
+            .map(|(a, b)| (a[0] - b[0]).abs())
+            .sum::<f64>()
+            / driven_first.len() as f64;
+        assert!(difference > 0.05, "{}", difference);
+    }
+
+    #[test]
+    fn test_pan_and_echo() {
+        let left = through_chain("pan(-1)", 440.0, 0.2);
+        assert!(left.iter().all(|f| f[1] == 0.0));
+        assert!((level(&left, 0) - 0.5 / 2.0_f64.sqrt()).abs() < 0.01);
+        let leaning = through_chain("pan(0.5)", 440.0, 0.2);
+        assert!((level(&leaning, 0) / level(&leaning, 1) - 0.5).abs() < 1e-9);
+
+        // One impulse: the echo arrives a delay time later, at the mix level
+        let effects = parse_chain("delay(0.01, 0.5, 0.8)").unwrap();
+        let mut chain = EffectChain::new(&effects, SAMPLE_RATE).unwrap();
+        let response: Vec<f64> = (0..2000)
+            .map(|i| {
+                let mut frame = [if i == 0 { 1.0 } else { 0.0 }; 2];
+                chain.process(&mut frame);
+                frame[0]
+            })
+            .collect();
+        assert_eq!(response[0], 1.0);
+        assert_eq!(response[441], 0.8);
+        assert_eq!(response[882], 0.4);
+        assert!(effects[0].tail_secs() > 0.01);
+    }
+
+    #[test]
+    fn test_zero_mix_is_bypassed() {
+        assert!(ReverbLine::new(&Reverb::default(), SAMPLE_RATE).is_none());
+        assert_eq!(Reverb::default().tail_secs(), 0.0);
+    }
+}
@@ -0,0 +1,129 @@
+//! The error type shared by loading, parsing, scheduling and playback.
+//!
+//! Each variant keeps the context needed to explain it (which file, which
+//! line, which instrument); `main` turns it into the message and exit code
+//! the user sees.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::instrument::Problem;
+
+#[derive(Debug)]
+pub enum ClidawError {
+    /// A file couldn't be read or written
+    Io { path: PathBuf, source: io::Error },
+    /// Text that isn't valid `.notes`. `file` is None for text that didn't
+    /// come from a file; `col` is known for errors within a note or drum line.
+    Parse {
+        file: Option<PathBuf>,
+        line: usize,
+        col: Option<usize>,
+        msg: String,
+    },
+    /// A `.song` file that can't be loaded
+    Song { file: PathBuf, msg: String },
+    /// An instrument that can't be loaded; `name` is its file or preset
+    Instrument { name: String, problems: Vec<Problem> },
+    /// A live-mode keymap file that can't be loaded
+    Keymap { file: PathBuf, msg: String },
+    /// A MIDI file that can't be read
+    Midi { file: PathBuf, msg: String },
+    /// The audio device couldn't be opened, or the audio thread went away
+    Audio(String),
+    /// Patterns that can't be laid out on the song's timeline
+    Schedule(String),
+    /// The terminal couldn't be set up: raw mode for live mode, or the
+    /// Ctrl-C handler for playback
+    Terminal(String),
+    /// Command-line options that don't make sense
+    Usage(String),
+    /// Diagnostics already shown to the user (by `check` or `parse
+    /// --strict-bars`) included this many errors
+    Diagnosed(usize),
+}
+
+impl ClidawError {
+    /// An I/O error on `path`
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        ClidawError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for ClidawError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClidawError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ClidawError::Parse {
+                file,
+                line,
+                col,
+                msg,
+            } => {
+                if let Some(file) = file {
+                    write!(f, "{}: ", file.display())?;
+                }
+                match col {
+                    Some(col) => write!(f, "line {}, column {}: {}", line, col, msg),
+                    None => write!(f, "line {}: {}", line, msg),
+                }
+            }
+            ClidawError::Song { file, msg }
+            | ClidawError::Keymap { file, msg }
+            | ClidawError::Midi { file, msg } => {
+                write!(f, "{}: {}", file.display(), msg)
+            }
+            ClidawError::Instrument { name, problems } => {
+                let lines: Vec<String> = problems.iter().map(|p| p.located(name)).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            ClidawError::Audio(msg)
+            | ClidawError::Schedule(msg)
+            | ClidawError::Terminal(msg)
+            | ClidawError::Usage(msg) => write!(f, "{}", msg),
+            ClidawError::Diagnosed(errors) => write!(f, "{} error(s) reported", errors),
+        }
+    }
+}
+
+impl std::error::Error for ClidawError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClidawError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_names_the_file_and_position() {
+        let err = ClidawError::Parse {
+            file: Some(PathBuf::from("song/lead.notes")),
+            line: 3,
+            col: Some(7),
+            msg: "unknown character 'q'".to_string(),
+        };
+        assert_eq!(err.to_string(), "song/lead.notes: line 3, column 7: unknown character 'q'");
+
+        let err = ClidawError::Parse {
+            file: None,
+            line: 2,
+            col: None,
+            msg: "invalid tempo".to_string(),
+        };
+        assert_eq!(err.to_string(), "line 2: invalid tempo");
+
+        let source = io::Error::new(io::ErrorKind::NotFound, "not found");
+        let err = ClidawError::io("missing.song", source);
+        assert_eq!(err.to_string(), "missing.song: not found");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
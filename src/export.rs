@@ -0,0 +1,233 @@
+//! Schedule export for `clidaw schedule`: every scheduled event as JSON or
+//! CSV, for diffing schedules and feeding other tools.
+//!
+//! Field names, their order and the number formatting are part of the output
+//! format; keep them stable.
+
+use serde::Serialize;
+
+use crate::note::freq_to_midi;
+use crate::scheduler::{ScheduledEvent, beats_to_secs};
+use crate::synth::LiveCommand;
+
+/// One scheduled event, flattened. Fields that don't apply to the command
+/// are left out of JSON and empty in CSV.
+#[derive(Debug, Serialize)]
+pub struct Row {
+    pub beat: f64,
+    /// Seconds from the start at the effective tempo
+    pub secs: f64,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<usize>,
+    /// Voice key codepoint, e.g. `U+E000`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub freq: Option<f64>,
+    /// Nearest MIDI note to `freq`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub midi: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drum: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accent: Option<bool>,
+    /// Master gain target, pitch bend amount or automated setting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ramp_secs: Option<f64>,
+    /// Name of the automated setting (`gain`, `pan`, `cutoff`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub param: Option<&'static str>,
+}
+
+/// CSV column names, in `Row` field order
+pub const CSV_HEADER: &str =
+    "beat,secs,type,track,key,freq,midi,velocity,drum,accent,value,ramp_secs,param";
+
+impl Row {
+    pub fn new(ev: &ScheduledEvent, tempo: f64) -> Self {
+        let mut row = Row {
+            beat: ev.beat.as_f64(),
+            secs: beats_to_secs(ev.beat, tempo),
+            kind: "",
+            track: None,
+            key: None,
+            freq: None,
+            midi: None,
+            velocity: None,
+            drum: None,
+            accent: None,
+            value: None,
+            ramp_secs: None,
+            param: None,
+        };
+        let codepoint = |key: char| Some(format!("U+{:04X}", key as u32));
+        match ev.command {
+            LiveCommand::NoteOn {
+                track,
+                key,
+                freq,
+                velocity,
+            } => {
+                row.kind = "note_on";
+                row.track = Some(track);
+                row.key = codepoint(key);
+                row.freq = Some(freq);
+                row.midi = Some(freq_to_midi(freq).round() as i32);
+                row.velocity = Some(velocity);
+            }
+            LiveCommand::NoteOff { track, key } => {
+                row.kind = "note_off";
+                row.track = Some(track);
+                row.key = codepoint(key);
+            }
+            LiveCommand::DrumHit {
+                track,
+                drum,
+                velocity,
+            } => {
+                row.kind = "drum";
+                row.track = Some(track);
+                row.drum = Some(drum.name());
+                row.velocity = Some(velocity);
+            }
+            LiveCommand::Click { accent, volume } => {
+                row.kind = "click";
+                row.accent = Some(accent);
+                row.velocity = Some(volume);
+            }
+            LiveCommand::SetMasterGain { gain, ramp_secs } => {
+                row.kind = "gain";
+                row.value = Some(gain);
+                row.ramp_secs = Some(ramp_secs);
+            }
+            LiveCommand::SetParam {
+                track,
+                param,
+                value,
+            } => {
+                row.kind = "param";
+                row.track = Some(track);
+                row.param = Some(param.name());
+                row.value = Some(value);
+            }
+            LiveCommand::PitchBend(amount) => {
+                row.kind = "pitch_bend";
+                row.value = Some(amount);
+            }
+            LiveCommand::SetLimiter(on) => row.kind = if on { "limiter_on" } else { "limiter_off" },
+            LiveCommand::AllNotesOff { track } => {
+                row.kind = "all_notes_off";
+                row.track = track;
+            }
+            LiveCommand::HardStop => row.kind = "hard_stop",
+            LiveCommand::Panic => row.kind = "panic",
+            LiveCommand::SetMaxNoteSecs(secs) => {
+                row.kind = "max_note_secs";
+                row.value = secs;
+            }
+            LiveCommand::Shutdown => row.kind = "shutdown",
+        }
+        row
+    }
+
+    /// One CSV line (no newline), matching `CSV_HEADER`
+    pub fn to_csv(&self) -> String {
+        fn cell<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map_or(String::new(), T::to_string)
+        }
+        [
+            self.beat.to_string(),
+            self.secs.to_string(),
+            self.kind.to_string(),
+            cell(&self.track),
+            cell(&self.key),
+            cell(&self.freq),
+            cell(&self.midi),
+            cell(&self.velocity),
+            cell(&self.drum),
+            cell(&self.accent),
+            cell(&self.value),
+            cell(&self.ramp_secs),
+            cell(&self.param),
+        ]
+        .join(",")
+    }
+}
+
+/// The whole schedule as CSV, header first
+pub fn to_csv(events: &[ScheduledEvent], tempo: f64) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+    for ev in events {
+        out.push_str(&Row::new(ev, tempo).to_csv());
+        out.push('\n');
+    }
+    out
+}
+
+/// The whole schedule as a pretty-printed JSON array
+pub fn to_json(events: &[ScheduledEvent], tempo: f64) -> String {
+    let rows: Vec<Row> = events.iter().map(|ev| Row::new(ev, tempo)).collect();
+    serde_json::to_string_pretty(&rows).expect("schedule serialization cannot fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::Beat;
+    use crate::note::Drum;
+
+    #[test]
+    fn test_rows_are_stable() {
+        let events = vec![
+            ScheduledEvent {
+                beat: Beat::ZERO,
+                command: LiveCommand::NoteOn {
+                    track: 1,
+                    key: '\u{E000}',
+                    freq: 440.0,
+                    velocity: 0.7,
+                },
+            },
+            ScheduledEvent {
+                beat: Beat::ratio(3, 2),
+                command: LiveCommand::DrumHit {
+                    track: 0,
+                    drum: Drum::Snare,
+                    velocity: 1.0,
+                },
+            },
+            ScheduledEvent {
+                beat: Beat::whole(3),
+                command: LiveCommand::NoteOff {
+                    track: 1,
+                    key: '\u{E000}',
+                },
+            },
+        ];
+        // 100 BPM: 0.6 s per beat
+        assert_eq!(
+            to_csv(&events, 100.0),
+            concat!(
+                "beat,secs,type,track,key,freq,midi,velocity,drum,accent,value,ramp_secs,param\n",
+                "0,0,note_on,1,U+E000,440,69,0.7,,,,,\n",
+                "1.5,0.9,drum,0,,,,1,snare,,,,\n",
+                "3,1.8,note_off,1,U+E000,,,,,,,,\n",
+            )
+        );
+        let json = serde_json::to_string(&Row::new(&events[0], 100.0)).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"beat":0.0,"secs":0.0,"type":"note_on","track":1,"key":"U+E000","#,
+                r#""freq":440.0,"midi":69,"velocity":0.7}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Minimal FLAC encoder for `clidaw render`.
+//!
+//! Each channel of each block is coded independently with the best of the
+//! fixed predictors (orders 0-4) and a single Rice partition, falling back
+//! to verbatim samples when prediction doesn't pay. That's well short of the
+//! reference encoder's ratio, but lossless and readable by every decoder.
+
+/// Samples per frame (the reference encoder's default)
+const BLOCK_SIZE: usize = 4096;
+
+/// Largest Rice parameter codable with the 4-bit parameter field
+/// (15 is the escape code)
+const MAX_RICE_PARAM: u32 = 14;
+
+/// Big-endian bit writer
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Write the low `n` bits of `value` (n <= 32)
+    fn write(&mut self, value: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1u64 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    /// Write a two's complement value in `n` bits
+    fn write_signed(&mut self, value: i64, n: u32) {
+        self.write(value as u64, n);
+    }
+
+    /// `q` zero bits followed by a one
+    fn write_unary(&mut self, mut q: u64) {
+        while q >= 32 {
+            self.write(0, 32);
+            q -= 32;
+        }
+        self.write(1, q as u32 + 1);
+    }
+
+    /// Pad with zero bits to the next byte boundary
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in bytes {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Frame number in FLAC's UTF-8-like variable length coding
+fn utf8_number(n: u64) -> Vec<u8> {
+    if n < 0x80 {
+        return vec![n as u8];
+    }
+    // A sequence of `len` bytes holds 5 * len + 1 bits
+    let mut len = 2;
+    while n >= 1u64 << (5 * len + 1) {
+        len += 1;
+    }
+    let mut bytes = vec![0u8; len];
+    let mut v = n;
+    for byte in bytes[1..].iter_mut().rev() {
+        *byte = 0x80 | (v & 0x3f) as u8;
+        v >>= 6;
+    }
+    bytes[0] = (0xff00u16 >> len) as u8 | v as u8;
+    bytes
+}
+
+/// Fixed predictor residuals of `order` (0-4) for samples after the warm-up
+fn fixed_residuals(x: &[i64], order: usize) -> Vec<i64> {
+    (order..x.len())
+        .map(|i| {
+            let prediction = match order {
+                0 => 0,
+                1 => x[i - 1],
+                2 => 2 * x[i - 1] - x[i - 2],
+                3 => 3 * x[i - 1] - 3 * x[i - 2] + x[i - 3],
+                _ => 4 * x[i - 1] - 6 * x[i - 2] + 4 * x[i - 3] - x[i - 4],
+            };
+            x[i] - prediction
+        })
+        .collect()
+}
+
+/// Signed residual folded to unsigned (0, -1, 1, -2 ... → 0, 1, 2, 3 ...)
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+/// Rice parameter for these residuals and the bits they'd take with it
+fn rice_cost(residuals: &[i64]) -> (u32, u64) {
+    let sum: u64 = residuals.iter().map(|&r| zigzag(r)).sum();
+    let n = residuals.len().max(1) as u64;
+    let mean = sum / n;
+    let param = if mean == 0 {
+        0
+    } else {
+        (63 - mean.leading_zeros()).min(MAX_RICE_PARAM)
+    };
+    let bits = residuals
+        .iter()
+        .map(|&r| 1 + param as u64 + (zigzag(r) >> param))
+        .sum();
+    (param, bits)
+}
+
+/// One channel of one block as a subframe
+fn write_subframe(w: &mut BitWriter, x: &[i64], bps: u32) {
+    if x.iter().all(|&s| s == x[0]) {
+        // CONSTANT
+        w.write(0, 8);
+        w.write_signed(x[0], bps);
+        return;
+    }
+
+    let verbatim_bits = x.len() as u64 * bps as u64;
+    let best = (0..=4usize.min(x.len() - 1))
+        .map(|order| {
+            let residuals = fixed_residuals(x, order);
+            let (param, bits) = rice_cost(&residuals);
+            (order, residuals, param, bits + (order as u64 * bps as u64))
+        })
+        .min_by_key(|(_, _, _, bits)| *bits);
+
+    match best {
+        Some((order, residuals, param, bits)) if bits < verbatim_bits => {
+            // FIXED, type 001xxx with xxx = order
+            w.write(((0b001000 | order as u64) << 1) & 0xff, 8);
+            for &s in &x[..order] {
+                w.write_signed(s, bps);
+            }
+            // Rice coding with 4-bit parameters, partition order 0
+            w.write(0, 2);
+            w.write(0, 4);
+            w.write(param as u64, 4);
+            for &r in &residuals {
+                let u = zigzag(r);
+                w.write_unary(u >> param);
+                w.write(u, param);
+            }
+        }
+        _ => {
+            // VERBATIM
+            w.write(0b00000010, 8);
+            for &s in x {
+                w.write_signed(s, bps);
+            }
+        }
+    }
+}
+
+/// Encode interleaved integer samples (each within `bits_per_sample`) as a
+/// complete FLAC file. Supports 1-8 channels and 16 or 24 bits per sample.
+pub fn encode(samples: &[i32], channels: u16, sample_rate: u32, bits_per_sample: u32) -> Vec<u8> {
+    let ch = channels.max(1) as usize;
+    let total_frames = samples.len() / ch;
+    let sample_size_code = match bits_per_sample {
+        16 => 0b100,
+        _ => 0b110,
+    };
+
+    let mut frames = Vec::new();
+    let mut min_frame = u32::MAX;
+    let mut max_frame = 0u32;
+    let mut channel = Vec::with_capacity(BLOCK_SIZE);
+    for (number, block) in samples.chunks(BLOCK_SIZE * ch).enumerate() {
+        let block_len = block.len() / ch;
+        let mut w = BitWriter::new();
+        // Sync code, reserved bit, fixed block size
+        w.write(0b11111111111110, 14);
+        w.write(0, 1);
+        w.write(0, 1);
+        // Block size as 16-bit (size - 1) after the header; rate from STREAMINFO
+        w.write(0b0111, 4);
+        w.write(0b0000, 4);
+        // Independent channels
+        w.write(ch as u64 - 1, 4);
+        w.write(sample_size_code, 3);
+        w.write(0, 1);
+        for b in utf8_number(number as u64) {
+            w.write(b as u64, 8);
+        }
+        w.write(block_len as u64 - 1, 16);
+        let crc = crc8(&w.bytes);
+        w.write(crc as u64, 8);
+
+        for c in 0..ch {
+            channel.clear();
+            channel.extend(block.iter().skip(c).step_by(ch).map(|&s| s as i64));
+            write_subframe(&mut w, &channel, bits_per_sample);
+        }
+        w.align();
+        let crc = crc16(&w.bytes);
+        w.write(crc as u64, 16);
+
+        min_frame = min_frame.min(w.bytes.len() as u32);
+        max_frame = max_frame.max(w.bytes.len() as u32);
+        frames.extend_from_slice(&w.bytes);
+    }
+    if frames.is_empty() {
+        min_frame = 0;
+    }
+
+    let mut w = BitWriter::new();
+    w.bytes.extend_from_slice(b"fLaC");
+    // Last metadata block: STREAMINFO, 34 bytes
+    w.write(1, 1);
+    w.write(0, 7);
+    w.write(34, 24);
+    let block_size = BLOCK_SIZE.min(total_frames.max(16)) as u64;
+    w.write(block_size, 16);
+    w.write(block_size, 16);
+    w.write(min_frame as u64, 24);
+    w.write(max_frame as u64, 24);
+    w.write(sample_rate as u64, 20);
+    w.write(ch as u64 - 1, 3);
+    w.write(bits_per_sample as u64 - 1, 5);
+    w.write(total_frames as u64 >> 32, 4);
+    w.write(total_frames as u64 & 0xffff_ffff, 32);
+    // MD5 of the audio: all zero means not computed
+    for _ in 0..4 {
+        w.write(0, 32);
+    }
+    w.bytes.extend_from_slice(&frames);
+    w.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Big-endian bit reader for the decoder below
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, n: u32) -> u64 {
+            let mut v = 0;
+            for _ in 0..n {
+                let bit = (self.bytes[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+                v = (v << 1) | bit as u64;
+                self.pos += 1;
+            }
+            v
+        }
+
+        fn read_signed(&mut self, n: u32) -> i64 {
+            let v = self.read(n) as i64;
+            (v << (64 - n)) >> (64 - n)
+        }
+
+        fn align(&mut self) {
+            self.pos = self.pos.div_ceil(8) * 8;
+        }
+    }
+
+    /// Decoder for exactly the subset `encode` produces
+    fn decode(bytes: &[u8]) -> (u32, u16, u32, Vec<i32>) {
+        assert_eq!(&bytes[..4], b"fLaC");
+        let mut r = BitReader { bytes, pos: 32 };
+        assert_eq!(r.read(1), 1);
+        assert_eq!(r.read(7), 0);
+        assert_eq!(r.read(24), 34);
+        r.read(16 + 16 + 24 + 24);
+        let sample_rate = r.read(20) as u32;
+        let channels = r.read(3) as u16 + 1;
+        let bps = r.read(5) as u32 + 1;
+        let total = r.read(36) as usize;
+        r.read(128);
+
+        let mut out = Vec::new();
+        while r.pos / 8 < bytes.len() {
+            let start = r.pos / 8;
+            assert_eq!(r.read(14), 0b11111111111110);
+            r.read(2 + 4 + 4 + 4 + 3 + 1);
+            // Frame number: count the leading ones of the first byte
+            let first = r.read(8) as u8;
+            for _ in 1..first.leading_ones().max(1) {
+                r.read(8);
+            }
+            let block_len = r.read(16) as usize + 1;
+            assert_eq!(r.read(8) as u8, crc8(&bytes[start..r.pos / 8 - 1]));
+
+            let mut chans = Vec::new();
+            for _ in 0..channels {
+                let kind = r.read(8) >> 1;
+                let mut x: Vec<i64> = Vec::with_capacity(block_len);
+                match kind {
+                    0 => x.resize(block_len, r.read_signed(bps)),
+                    1 => (0..block_len).for_each(|_| x.push(r.read_signed(bps))),
+                    _ => {
+                        let order = (kind & 0b111) as usize;
+                        (0..order).for_each(|_| x.push(r.read_signed(bps)));
+                        assert_eq!(r.read(2 + 4), 0);
+                        let param = r.read(4) as u32;
+                        for i in order..block_len {
+                            let mut q = 0;
+                            while r.read(1) == 0 {
+                                q += 1;
+                            }
+                            let u = (q << param) | r.read(param);
+                            let res = (u >> 1) as i64 ^ -((u & 1) as i64);
+                            let prediction = match order {
+                                0 => 0,
+                                1 => x[i - 1],
+                                2 => 2 * x[i - 1] - x[i - 2],
+                                3 => 3 * x[i - 1] - 3 * x[i - 2] + x[i - 3],
+                                _ => 4 * x[i - 1] - 6 * x[i - 2] + 4 * x[i - 3] - x[i - 4],
+                            };
+                            x.push(prediction + res);
+                        }
+                    }
+                }
+                chans.push(x);
+            }
+            r.align();
+            let end = r.pos / 8;
+            assert_eq!(r.read(16) as u16, crc16(&bytes[start..end]));
+            for i in 0..block_len {
+                for c in &chans {
+                    out.push(c[i] as i32);
+                }
+            }
+        }
+        assert_eq!(out.len(), total * channels as usize);
+        (sample_rate, channels, bps, out)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Stereo: a sine (predictable), noise-like values (verbatim) and
+        // a silent stretch (constant), over several blocks
+        let mut samples = Vec::new();
+        let mut seed = 1u32;
+        for i in 0..10_000 {
+            let sine = ((i as f64 * 0.05).sin() * 20_000.0) as i32;
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let noise = (seed >> 8) as i32 % 32_768;
+            let right = if i < 4096 { noise } else if i < 8192 { 0 } else { -sine };
+            samples.push(sine);
+            samples.push(right);
+        }
+        let bytes = encode(&samples, 2, 48_000, 16);
+        assert_eq!(decode(&bytes), (48_000, 2, 16, samples));
+
+        let wide: Vec<i32> = (0..5000).map(|i| (i * 1601) % 8_388_607 - 4_000_000).collect();
+        assert_eq!(decode(&encode(&wide, 1, 44_100, 24)).3, wide);
+    }
+
+    #[test]
+    fn test_utf8_number() {
+        assert_eq!(utf8_number(0x7f), vec![0x7f]);
+        assert_eq!(utf8_number(0x80), vec![0xc2, 0x80]);
+        assert_eq!(utf8_number(0x800), vec![0xe0, 0xa0, 0x80]);
+    }
+}
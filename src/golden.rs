@@ -0,0 +1,211 @@
+//! Golden-audio regression tests: the fixtures in `tests/golden/` are
+//! rendered offline, exactly as `clidaw render` would, and each render's
+//! loudness envelope is compared with the one stored next to its fixture.
+//! A change to the envelope math, mixing gains or effects fails `cargo test`.
+//!
+//! A `.golden` file holds the render's length in frames, then the peak and
+//! RMS of each `BLOCK_SECS` block across both channels. Those survive float
+//! noise from harmless refactors (within `TOLERANCE`) where a hash of the
+//! samples would not. Renders are repeatable: voices are kept in the order
+//! they started and every noise source has a fixed seed.
+//!
+//! The schedule fixtures are checked more strictly: their full schedule,
+//! as `clidaw schedule --format csv` prints it, must match the `.csv` next
+//! to them exactly, so a faster scheduler can't quietly reorder a thing.
+//!
+//! After a deliberate change to the sound, write the goldens again with
+//! `CLIDAW_BLESS=1 cargo test golden` and review their diff.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::render::{self, BitDepth};
+use crate::{RenderSettings, export, load_file, render_samples, scheduler};
+
+/// Files rendered, in `tests/golden/`
+const FIXTURES: [&str; 2] = ["envelope.notes", "mix.song"];
+
+/// Songs whose schedules are checked, in `tests/golden/`
+const SCHEDULE_FIXTURES: [&str; 2] = ["schedule.song", "arrangement.song"];
+
+const SAMPLE_RATE: u32 = 48_000;
+
+/// Length of each envelope block
+const BLOCK_SECS: f64 = 0.05;
+
+/// Largest difference allowed in a block's peak or RMS
+const TOLERANCE: f64 = 1e-4;
+
+/// Set to write the goldens instead of checking them
+const BLESS_VAR: &str = "CLIDAW_BLESS";
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+/// A render's length and the (peak, RMS) of each block
+#[derive(Debug, PartialEq)]
+struct Envelope {
+    frames: usize,
+    blocks: Vec<(f64, f64)>,
+}
+
+impl Envelope {
+    fn of(samples: &[f32]) -> Self {
+        let channels = render::CHANNELS as usize;
+        let block = (BLOCK_SECS * SAMPLE_RATE as f64) as usize * channels;
+        let blocks = samples
+            .chunks(block)
+            .map(|chunk| {
+                let peak = chunk.iter().fold(0.0_f64, |peak, &s| peak.max(s.abs() as f64));
+                let power = chunk.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+                (peak, (power / chunk.len() as f64).sqrt())
+            })
+            .collect();
+        Self {
+            frames: samples.len() / channels,
+            blocks,
+        }
+    }
+
+    fn to_text(&self, fixture: &str) -> String {
+        let mut text = format!(
+            "# {} rendered at {} Hz: peak and RMS of each {} s block\nframes: {}\n",
+            fixture, SAMPLE_RATE, BLOCK_SECS, self.frames
+        );
+        for (peak, rms) in &self.blocks {
+            let _ = writeln!(text, "{:.6} {:.6}", peak, rms);
+        }
+        text
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines().filter(|line| !line.starts_with('#'));
+        let frames = lines.next()?.strip_prefix("frames: ")?.parse().ok()?;
+        let blocks = lines
+            .map(|line| {
+                let (peak, rms) = line.split_once(' ')?;
+                Some((peak.parse().ok()?, rms.parse().ok()?))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self { frames, blocks })
+    }
+
+    /// Where `self` strays from `golden`, if anywhere
+    fn mismatch(&self, golden: &Envelope) -> Option<String> {
+        if self.frames != golden.frames {
+            return Some(format!("{} frames, expected {}", self.frames, golden.frames));
+        }
+        let (idx, (got, want)) = self
+            .blocks
+            .iter()
+            .zip(&golden.blocks)
+            .enumerate()
+            .find(|(_, (a, b))| (a.0 - b.0).abs() > TOLERANCE || (a.1 - b.1).abs() > TOLERANCE)?;
+        Some(format!(
+            "block at {:.2}s has peak {:.6}, RMS {:.6}; expected {:.6}, {:.6}",
+            idx as f64 * BLOCK_SECS,
+            got.0,
+            got.1,
+            want.0,
+            want.1
+        ))
+    }
+}
+
+fn render_fixture(path: &Path) -> Vec<f32> {
+    let settings = RenderSettings {
+        format: None,
+        bit_depth: BitDepth::Int16,
+        sample_rate: SAMPLE_RATE,
+        tempo: None,
+        master_volume: None,
+        no_limiter: false,
+        seed: Some(0),
+    };
+    let loaded = load_file(path, None, None).unwrap();
+    render_samples(&loaded, &settings).unwrap()
+}
+
+#[test]
+fn test_renders_match_goldens() {
+    let bless = std::env::var_os(BLESS_VAR).is_some();
+    let mut failures = Vec::new();
+    for fixture in FIXTURES {
+        let path = golden_dir().join(fixture);
+        let envelope = Envelope::of(&render_fixture(&path));
+        let golden_path = path.with_extension("golden");
+        if bless {
+            fs::write(&golden_path, envelope.to_text(fixture)).unwrap();
+            continue;
+        }
+        let golden = fs::read_to_string(&golden_path).ok().and_then(|t| Envelope::parse(&t));
+        let problem = match golden {
+            Some(golden) => envelope.mismatch(&golden),
+            None => Some(format!("no readable {}", golden_path.display())),
+        };
+        if let Some(problem) = problem {
+            failures.push(format!("{}: {}", fixture, problem));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "renders changed (if on purpose, rerun with {}=1 and review the goldens):\n{}",
+        BLESS_VAR,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_schedules_match_goldens() {
+    let bless = std::env::var_os(BLESS_VAR).is_some();
+    let mut failures = Vec::new();
+    for fixture in SCHEDULE_FIXTURES {
+        let path = golden_dir().join(fixture);
+        let loaded = load_file(&path, None, None).unwrap();
+        let events = scheduler::compile(&loaded.song, &loaded.patterns).unwrap().events;
+        let csv = export::to_csv(&events, loaded.tempo);
+        let golden_path = path.with_extension("csv");
+        if bless {
+            fs::write(&golden_path, csv).unwrap();
+            continue;
+        }
+        let Ok(golden) = fs::read_to_string(&golden_path) else {
+            failures.push(format!("{}: no readable {}", fixture, golden_path.display()));
+            continue;
+        };
+        let stray = csv.lines().zip(golden.lines()).position(|(got, want)| got != want);
+        let problem = match stray {
+            Some(idx) => Some(format!("line {} differs", idx + 1)),
+            None if csv.lines().count() != golden.lines().count() => Some(format!(
+                "{} lines, expected {}",
+                csv.lines().count(),
+                golden.lines().count()
+            )),
+            None => None,
+        };
+        if let Some(problem) = problem {
+            failures.push(format!("{}: {}", fixture, problem));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "schedules changed (if on purpose, rerun with {}=1 and review the goldens):\n{}",
+        BLESS_VAR,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_envelope_text_round_trips() {
+    let envelope = Envelope::of(&[0.5, -0.25, 0.0, 0.0]);
+    assert_eq!(envelope.frames, 2);
+    let again = Envelope::parse(&envelope.to_text("tiny.notes")).unwrap();
+    assert_eq!(again.mismatch(&envelope), None);
+    let louder = Envelope {
+        frames: 2,
+        blocks: vec![(0.5, 0.3)],
+    };
+    assert!(louder.mismatch(&envelope).unwrap().contains("block at 0.00s"));
+}
@@ -0,0 +1,488 @@
+//! MIDI to clidaw conversion for `clidaw import` and for playing `.mid`
+//! files directly.
+//!
+//! Note starts and ends snap to the nearest step of the import grid and
+//! are written with `:length`s (`a:0.25`), so the song keeps the MIDI
+//! file's tempo and time signature. A line plays one note or chord at a
+//! time, so notes that overlap without starting and ending together go to
+//! extra tracks (`piano`, `piano-2`, ...). Channel 10 becomes a drum track
+//! with a drum line step per grid step (`drum_step: 0.25`).
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::beat::Beat;
+use crate::midi::{DEFAULT_TEMPO_USPQ, MidiFile, MidiNote, PERCUSSION_CHANNEL};
+use crate::note::Drum;
+use crate::writer::{beats_text, dynamic_mark, length_suffix, rest_token, write_key};
+
+/// Grid notes snap to, in beats, unless `--grid` says otherwise
+pub const DEFAULT_GRID: f64 = 0.25;
+
+/// Finest grid: 1/16 of a beat
+const MAX_STEPS_PER_BEAT: u32 = 16;
+
+/// Octave every `.notes` line starts in
+const BASE_OCTAVE: u8 = 4;
+
+/// One clidaw track made from (part of) a MIDI track and channel
+#[derive(Debug, Clone)]
+pub struct ImportedTrack {
+    /// File stem of its pattern, and its name in the song
+    pub name: String,
+    /// Plays drum lines on a `type: drum` instrument
+    pub drums: bool,
+    /// Contents of the track's `.notes` file
+    pub notes: String,
+}
+
+/// A MIDI file converted to a song's worth of patterns
+#[derive(Debug, Clone)]
+pub struct Import {
+    /// The MIDI file's first tempo, in BPM
+    pub tempo: f64,
+    /// The MIDI file's first time signature (4/4 if its bars don't fall on
+    /// the grid)
+    pub time_signature: (u8, u8),
+    pub tracks: Vec<ImportedTrack>,
+    /// Events left out, by kind (the MIDI file's and the import's own)
+    pub skipped: BTreeMap<&'static str, usize>,
+}
+
+/// Steps per beat for a grid given in beats: 0.25 → 4. None unless the grid
+/// divides a beat into 1 to 16 whole steps.
+pub fn steps_per_beat(grid: f64) -> Option<u32> {
+    if grid <= 0.0 {
+        return None;
+    }
+    let steps = (1.0 / grid).round();
+    let exact = (steps * grid - 1.0).abs() < 1e-9;
+    (exact && (1.0..=MAX_STEPS_PER_BEAT as f64).contains(&steps)).then_some(steps as u32)
+}
+
+/// Notes that start together and last equally long, in steps
+#[derive(Debug)]
+struct Chunk {
+    start: u64,
+    len: u64,
+    /// MIDI keys, lowest first, and the loudest velocity among them
+    keys: Vec<u8>,
+    velocity: u8,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// Convert a parsed MIDI file, snapping to `steps` per beat
+pub fn convert(midi: &MidiFile, steps: u32) -> Import {
+    let steps = steps.clamp(1, MAX_STEPS_PER_BEAT);
+    let mut skipped = midi.skipped.clone();
+    let uspq = midi.tempo.unwrap_or(DEFAULT_TEMPO_USPQ).max(1);
+    let bpm = 60_000_000.0 / uspq as f64;
+    let tempo = ((bpm * 100.0).round() / 100.0).max(1.0);
+
+    // A bar in steps, when it comes out whole (7/8 on a beat grid doesn't)
+    let (num, den) = midi.time_signature.unwrap_or((4, 4));
+    let bar = num as u32 * 4 * steps;
+    let (time_signature, bar_steps) = if num > 0 && den > 0 && bar.is_multiple_of(den as u32) {
+        ((num, den), (bar / den as u32) as u64)
+    } else {
+        ((4, 4), 4 * steps as u64)
+    };
+
+    let to_step = |tick: u64| {
+        (tick as f64 * steps as f64 / midi.ticks_per_quarter.max(1) as f64).round() as u64
+    };
+
+    // Each (MIDI track, channel) with notes, with its name and notes in steps
+    let mut parts: Vec<(String, bool, Vec<MidiNote>)> = Vec::new();
+    for (idx, track) in midi.tracks.iter().enumerate() {
+        let mut channels: BTreeMap<u8, Vec<MidiNote>> = BTreeMap::new();
+        for note in &track.notes {
+            let start = to_step(note.start);
+            let note = MidiNote {
+                start,
+                end: to_step(note.end).max(start + 1),
+                ..note.clone()
+            };
+            channels.entry(note.channel).or_default().push(note);
+        }
+        let base = track
+            .name
+            .as_deref()
+            .map(file_name)
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| format!("track{}", idx + 1));
+        let several = channels.len() > 1;
+        for (channel, notes) in channels {
+            let name = if several { format!("{}-ch{}", base, channel + 1) } else { base.clone() };
+            parts.push((name, channel == PERCUSSION_CHANNEL, notes));
+        }
+    }
+
+    // Every pattern lasts the whole song, in whole bars
+    let end = parts
+        .iter()
+        .flat_map(|(_, _, notes)| notes.iter().map(|n| n.end))
+        .max()
+        .unwrap_or(0);
+    let total = end.div_ceil(bar_steps).max(1) * bar_steps;
+
+    let mut tracks: Vec<ImportedTrack> = Vec::new();
+    for (name, drums, notes) in parts {
+        if drums {
+            let notes = drum_notes(&notes, total, bar_steps, steps, &mut skipped);
+            push_unique(&mut tracks, name, true, notes);
+            continue;
+        }
+        for (voice, chunks) in voices(&notes, &mut skipped).iter().enumerate() {
+            let name = if voice == 0 { name.clone() } else { format!("{}-{}", name, voice + 1) };
+            let notes = tonal_notes(chunks, total, bar_steps, steps);
+            push_unique(&mut tracks, name, false, notes);
+        }
+    }
+
+    Import {
+        tempo,
+        time_signature,
+        tracks,
+        skipped,
+    }
+}
+
+impl Import {
+    /// A `.song` playing every track's `<name>.notes` once, each on an
+    /// inline default (or drum) instrument
+    pub fn song_text(&self, source: &str) -> String {
+        let mut text = format!(
+            "# Imported from {}\ntempo: {}\ntime_signature: {}/{}\n",
+            source, self.tempo, self.time_signature.0, self.time_signature.1
+        );
+        for track in &self.tracks {
+            let body = if track.drums { " type: drum " } else { "" };
+            let _ = write!(
+                text,
+                "\ninstrument: {} {{{}}}\n{}.notes\n",
+                track.name, body, track.name
+            );
+        }
+        text
+    }
+
+    /// "3 control change, 1 pitch bend" style list of what was left out
+    pub fn skipped_summary(&self) -> Option<String> {
+        if self.skipped.is_empty() {
+            return None;
+        }
+        let parts: Vec<String> =
+            self.skipped.iter().map(|(kind, count)| format!("{} {}", count, kind)).collect();
+        Some(parts.join(", "))
+    }
+}
+
+/// Lowercase letters, digits and dashes: "Lead Synth #2" → "lead-synth-2"
+pub fn file_name(name: &str) -> String {
+    let mapped: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    mapped.split('-').filter(|s| !s.is_empty()).collect::<Vec<_>>().join("-")
+}
+
+/// Add a track, numbering its name if an earlier track has it
+fn push_unique(tracks: &mut Vec<ImportedTrack>, name: String, drums: bool, notes: String) {
+    let taken = |n: &str| tracks.iter().any(|t| t.name == n);
+    let mut unique = name.clone();
+    let mut n = 2;
+    while taken(&unique) {
+        unique = format!("{}-{}", name, n);
+        n += 1;
+    }
+    tracks.push(ImportedTrack {
+        name: unique,
+        drums,
+        notes,
+    });
+}
+
+/// Split notes (in steps, by start) into lines that each play one note or
+/// chord at a time. A note joins a chord with the same start and length,
+/// else the first line that is free by its start, else a new line.
+fn voices(notes: &[MidiNote], skipped: &mut BTreeMap<&'static str, usize>) -> Vec<Vec<Chunk>> {
+    let mut voices: Vec<Vec<Chunk>> = Vec::new();
+    for note in notes {
+        // Octaves 0-8 of the note characters
+        if !(12..=119).contains(&note.key) {
+            *skipped.entry("note out of range").or_default() += 1;
+            continue;
+        }
+        let len = note.end - note.start;
+        let chord = voices.iter_mut().find_map(|v| {
+            v.last_mut().filter(|c| c.start == note.start && c.len == len)
+        });
+        if let Some(chunk) = chord {
+            if !chunk.keys.contains(&note.key) {
+                chunk.keys.push(note.key);
+                chunk.keys.sort_unstable();
+            }
+            chunk.velocity = chunk.velocity.max(note.velocity);
+            continue;
+        }
+        let chunk = Chunk {
+            start: note.start,
+            len,
+            keys: vec![note.key],
+            velocity: note.velocity,
+        };
+        match voices.iter_mut().find(|v| v.last().is_none_or(|c| c.end() <= note.start)) {
+            Some(voice) => voice.push(chunk),
+            None => voices.push(vec![chunk]),
+        }
+    }
+    voices
+}
+
+/// What happens on one step of a tonal line
+enum Step<'a> {
+    Start(&'a Chunk),
+    Hold,
+    Rest,
+}
+
+/// A tonal line as `.notes` text at `per_beat` steps to a beat: one line
+/// per bar, each note or chord as long as it sounds (tied over bar lines),
+/// and dynamics marks where the velocity changes
+fn tonal_notes(chunks: &[Chunk], total: u64, bar_steps: u64, per_beat: u32) -> String {
+    let mut steps: Vec<Step> = (0..total).map(|_| Step::Rest).collect();
+    for chunk in chunks {
+        for step in chunk.start..chunk.end().min(total) {
+            steps[step as usize] = if step == chunk.start { Step::Start(chunk) } else { Step::Hold };
+        }
+    }
+
+    let beats = Beat::ratio(total as i64, per_beat as i64);
+    let mut text = format!("beats: {}\noctave: {}\n\n", beats_text(beats), BASE_OCTAVE);
+    // Notes play at full velocity until the first mark
+    let mut mark = "ff";
+    for bar in steps.chunks(bar_steps as usize) {
+        let mut octave = BASE_OCTAVE;
+        let mut tokens: Vec<String> = Vec::new();
+        let mut idx = 0;
+        while idx < bar.len() {
+            // A note and the steps it holds, a tie and the same, or a run of rests
+            let rest = matches!(bar[idx], Step::Rest);
+            let same = |step: &&Step| match step {
+                Step::Start(_) => false,
+                Step::Hold => !rest,
+                Step::Rest => rest,
+            };
+            let run = 1 + bar[idx + 1..].iter().take_while(same).count();
+            let length = Beat::ratio(run as i64, per_beat as i64);
+            tokens.push(match bar[idx] {
+                Step::Start(chunk) => {
+                    let mut token = String::new();
+                    let wanted = dynamic_mark(chunk.velocity as f64 / 127.0);
+                    if wanted != mark {
+                        let _ = write!(token, "!{}! ", wanted);
+                        mark = wanted;
+                    }
+                    if let [key] = chunk.keys.as_slice() {
+                        write_key(&mut token, *key, &mut octave);
+                    } else {
+                        token.push('[');
+                        for &key in &chunk.keys {
+                            write_key(&mut token, key, &mut octave);
+                        }
+                        token.push(']');
+                    }
+                    token.push_str(&length_suffix(length));
+                    token
+                }
+                Step::Hold => format!("_{}", length_suffix(length)),
+                Step::Rest => rest_token(length),
+            });
+            idx += run;
+        }
+        let _ = writeln!(text, "{} |", tokens.join(" "));
+    }
+    text
+}
+
+/// The kit drum for a General MIDI percussion key
+fn gm_drum(key: u8) -> Option<Drum> {
+    match key {
+        35 | 36 => Some(Drum::Kick),
+        37..=40 => Some(Drum::Snare),
+        42 | 44 | 46 => Some(Drum::Hat),
+        _ => None,
+    }
+}
+
+/// A drum track as `.notes` text at `per_beat` steps to a beat: one drum
+/// block per bar, with a line for each drum the track uses
+fn drum_notes(
+    notes: &[MidiNote],
+    total: u64,
+    bar_steps: u64,
+    per_beat: u32,
+    skipped: &mut BTreeMap<&'static str, usize>,
+) -> String {
+    let mut hits: BTreeMap<usize, Vec<bool>> = BTreeMap::new();
+    for note in notes {
+        let Some(drum) = gm_drum(note.key) else {
+            *skipped.entry("unmapped drum note").or_default() += 1;
+            continue;
+        };
+        let idx = Drum::ALL.iter().position(|&d| d == drum).expect("drum is in ALL");
+        let line = hits.entry(idx).or_insert_with(|| vec![false; total as usize]);
+        if let Some(hit) = line.get_mut(note.start as usize) {
+            *hit = true;
+        }
+    }
+
+    let mut text = format!("beats: {}\n", beats_text(Beat::ratio(total as i64, per_beat as i64)));
+    if per_beat > 1 {
+        let _ = writeln!(text, "drum_step: {}", beats_text(Beat::ratio(1, per_beat as i64)));
+    }
+    for bar in 0..(total / bar_steps) as usize {
+        text.push('\n');
+        let range = bar * bar_steps as usize..(bar + 1) * bar_steps as usize;
+        for (&idx, line) in &hits {
+            let label = format!("{}:", Drum::ALL[idx].name());
+            let steps: Vec<&str> =
+                line[range.clone()].iter().map(|&hit| if hit { "x" } else { "-" }).collect();
+            let _ = writeln!(text, "{:<7}{}", label, steps.join(" "));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::Beat;
+    use crate::midi::MidiTrack;
+    use crate::note::Event;
+    use crate::parser::parse_pattern;
+
+    fn note(channel: u8, key: u8, velocity: u8, start: u64, end: u64) -> MidiNote {
+        MidiNote {
+            channel,
+            key,
+            velocity,
+            start,
+            end,
+        }
+    }
+
+    /// 4/4 at 96 ticks per quarter, 100 BPM
+    fn midi(tracks: Vec<MidiTrack>) -> MidiFile {
+        MidiFile {
+            ticks_per_quarter: 96,
+            tempo: Some(600_000),
+            time_signature: Some((4, 4)),
+            tracks,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_grid_steps() {
+        assert_eq!(steps_per_beat(0.25), Some(4));
+        assert_eq!(steps_per_beat(1.0), Some(1));
+        assert_eq!(steps_per_beat(0.3), None);
+        assert_eq!(steps_per_beat(2.0), None);
+        assert_eq!(steps_per_beat(0.0), None);
+    }
+
+    #[test]
+    fn test_melody_snaps_to_the_grid_and_parses_back() {
+        let track = MidiTrack {
+            name: Some("Lead Synth".to_string()),
+            notes: vec![
+                // A C4 quarter, slightly late; an E5 eighth; then a soft chord
+                note(0, 60, 127, 3, 96),
+                note(0, 76, 127, 96, 144),
+                note(0, 55, 50, 192, 384),
+                note(0, 64, 50, 192, 384),
+            ],
+        };
+        let import = convert(&midi(vec![track]), 2);
+        assert_eq!(import.tempo, 100.0);
+        assert_eq!(import.time_signature, (4, 4));
+        let lead = &import.tracks[0];
+        assert_eq!(lead.name, "lead-synth");
+        assert_eq!(lead.notes, "beats: 4\noctave: 4\n\na >d:0.5 -:0.5 !p! [<<g>d]:2 |\n");
+
+        let pattern = parse_pattern(&lead.notes).unwrap();
+        assert_eq!(pattern.length_beats(), Beat::whole(4));
+        match &pattern.events[3] {
+            Event::Chord(notes, _) => {
+                let pitches: Vec<u8> = notes.iter().map(|n| n.note.to_midi(n.octave)).collect();
+                assert_eq!(pitches, vec![55, 64]);
+                assert_eq!(notes[0].velocity, 0.4);
+            }
+            other => panic!("expected a chord, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_notes_held_over_a_bar_line_are_tied() {
+        // 3/4 on a quarter-beat grid: a C4 from the last sixteenth of bar 1
+        // into bar 2, then a hit on the second sixteenth of bar 2
+        let track = MidiTrack {
+            name: None,
+            notes: vec![note(0, 60, 127, 264, 312), note(9, 36, 127, 312, 320)],
+        };
+        let mut file = midi(vec![track]);
+        file.time_signature = Some((3, 4));
+        let import = convert(&file, 4);
+        assert_eq!(import.time_signature, (3, 4));
+        let tonal = &import.tracks[0].notes;
+        assert_eq!(tonal, "beats: 6\noctave: 4\n\n-:2.75 a:0.25 |\n_:0.25 -:2.75 |\n");
+        let pattern = parse_pattern(tonal).unwrap();
+        assert_eq!(pattern.length_beats(), Beat::whole(6));
+        let drums = &import.tracks[1].notes;
+        assert!(drums.starts_with("beats: 6\ndrum_step: 0.25\n\n"), "{}", drums);
+        let pattern = parse_pattern(drums).unwrap();
+        assert_eq!(pattern.length_beats(), Beat::whole(6));
+    }
+
+    #[test]
+    fn test_overlapping_notes_split_into_tracks() {
+        let track = MidiTrack {
+            name: None,
+            notes: vec![note(0, 60, 127, 0, 192), note(0, 64, 127, 96, 192)],
+        };
+        let import = convert(&midi(vec![track]), 1);
+        let names: Vec<&str> = import.tracks.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["track1", "track1-2"]);
+        assert_eq!(import.tracks[1].notes, "beats: 4\noctave: 4\n\n- d -- |\n");
+    }
+
+    #[test]
+    fn test_drums_and_skipped_notes() {
+        let track = MidiTrack {
+            name: Some("Kit".to_string()),
+            notes: vec![
+                note(9, 36, 100, 0, 10),
+                note(9, 42, 100, 0, 10),
+                note(9, 38, 100, 96, 106),
+                note(9, 81, 100, 96, 106),
+                note(0, 127, 100, 0, 96),
+            ],
+        };
+        let import = convert(&midi(vec![track]), 1);
+        let drums = &import.tracks[0];
+        assert_eq!((drums.name.as_str(), drums.drums), ("kit-ch10", true));
+        assert_eq!(
+            drums.notes,
+            "beats: 4\n\nkick:  x - - -\nsnare: - x - -\nhat:   x - - -\n"
+        );
+        assert_eq!(import.skipped_summary().unwrap(), "1 note out of range, 1 unmapped drum note");
+        assert!(import.song_text("beat.mid").contains("instrument: kit-ch10 { type: drum }\n"));
+    }
+}
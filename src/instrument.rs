@@ -1,11 +1,81 @@
 //! Instrument definitions loaded from `.instr` files.
 //!
-//! An instrument file defines ADSR envelope parameters used during playback.
+//! An instrument file defines ADSR envelope parameters used during playback,
+//! or with `type: drum` a drum kit for the drum lines of `.notes` files.
 //! Paths in `.song` files reference these instruments.
 
 use std::fs;
 use std::path::Path;
 
+use crate::error::ClidawError;
+
+use crate::synth::{
+    Adsr, CHORUS_MAX_DELAY_MS, CHORUS_MIN_DELAY_MS, Chorus, Curve, DEFAULT_BEND_RANGE,
+    DEFAULT_GAIN, Delay, DrumKit, Fm, NoiseColor, Retrigger, VelocityResponse, Waveform,
+};
+
+/// Largest accepted `unison` value; more oscillators add cost without much thickness.
+const MAX_UNISON: u32 = 16;
+
+/// Largest accepted `bend_range` (two octaves)
+const MAX_BEND_RANGE: f64 = 24.0;
+
+/// Largest accepted `chorus_rate` in Hz; faster sweeps sound like vibrato
+const MAX_CHORUS_RATE: f64 = 10.0;
+
+/// Longest accepted `glide` in seconds
+const MAX_GLIDE: f64 = 2.0;
+
+/// Longest accepted attack, decay or release in seconds
+const MAX_ENVELOPE_SECS: f64 = 60.0;
+
+/// Gains above this are allowed but likely to clip
+pub const LOUD_GAIN: f64 = 2.0;
+
+/// Largest accepted `fm_ratio`; beyond this the modulator is far above
+/// hearing for most notes
+const MAX_FM_RATIO: f64 = 32.0;
+
+/// Largest accepted `fm_index`; higher indexes are mostly noise
+const MAX_FM_INDEX: f64 = 50.0;
+
+/// FM settings used when only some of the FM keys are given
+const DEFAULT_FM_RATIO: f64 = 1.0;
+const DEFAULT_FM_INDEX: f64 = 1.0;
+
+/// Delay time as written in an instrument: seconds, or a note length
+/// resolved against the tempo when the song is played
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayTime {
+    Secs(f64),
+    /// Length in beats (quarter notes): `1/8` is half a beat
+    Beats(f64),
+}
+
+impl DelayTime {
+    /// Parse `0.375` (seconds) or `1/8`, `3/16` (fractions of a whole note)
+    fn parse(value: &str) -> Result<DelayTime, String> {
+        let Some((num, den)) = value.split_once('/') else {
+            return parse_number("delay_time", value).map(DelayTime::Secs);
+        };
+        match (num.trim().parse::<u32>(), den.trim().parse::<u32>()) {
+            (Ok(num), Ok(den)) if den > 0 => Ok(DelayTime::Beats(4.0 * num as f64 / den as f64)),
+            _ => Err(format!(
+                "invalid delay_time '{}' (expected seconds or a note length like 1/8)",
+                value
+            )),
+        }
+    }
+
+    /// Length in seconds at `tempo` BPM
+    pub fn secs(self, tempo: f64) -> f64 {
+        match self {
+            DelayTime::Secs(secs) => secs,
+            DelayTime::Beats(beats) => beats * 60.0 / tempo.max(1.0),
+        }
+    }
+}
+
 /// Instrument definition (ADSR envelope parameters).
 /// Load from a `.instr` file and convert to `synth::Adsr` for playback.
 #[derive(Debug, Clone)]
@@ -18,6 +88,52 @@ pub struct Instrument {
     pub sustain: f64,
     /// Release time in seconds (current level → 0 after key release)
     pub release: f64,
+    /// Number of detuned oscillators per note (1 = no unison)
+    pub unison: u32,
+    /// Detune spread across the unison oscillators, in cents
+    pub detune: f64,
+    /// Drum voice settings; Some for `type: drum` instruments
+    pub kit: Option<DrumKit>,
+    /// Frequency modulation settings; Some for `type: fm` instruments
+    pub fm: Option<Fm>,
+    /// Envelope segment shapes (default linear)
+    pub attack_curve: Curve,
+    pub decay_curve: Curve,
+    pub release_curve: Curve,
+    /// Chorus: delay sweep in ms, sweeps per second and wet share; a
+    /// `chorus_mix` of 0 turns it off
+    pub chorus_depth: f64,
+    pub chorus_rate: f64,
+    pub chorus_mix: f64,
+    /// Feedback delay; a `delay_mix` of 0 turns it off
+    pub delay_time: DelayTime,
+    pub delay_feedback: f64,
+    pub delay_mix: f64,
+    /// Semitones a full pitch bend moves notes (live mode arrow keys)
+    pub bend_range: f64,
+    /// Monophonic: one note at a time, the newest held key wins
+    pub mono: bool,
+    /// Seconds a mono instrument slides between notes (0 = no glide)
+    pub glide: f64,
+    /// What playing a key that is still sounding does to its envelope
+    /// (None = legato if mono, else continue)
+    pub retrigger: Option<Retrigger>,
+    /// Oscillator shape (default sine)
+    pub waveform: Waveform,
+    /// Band-limit non-sine waveforms so high notes don't alias (default on)
+    pub antialias: bool,
+    /// Spectrum of `waveform: noise` (default white)
+    pub noise_color: NoiseColor,
+    /// Peak amplitude of one note at full velocity (default 0.3)
+    pub gain: f64,
+    /// Turn the track down as more notes sound at once (default off)
+    pub normalize: bool,
+    /// How far velocity scales a note's level (default 1: fully)
+    pub vel_to_amp: f64,
+    /// How much a full-velocity note shortens the attack (default 0)
+    pub vel_to_attack: f64,
+    /// Each key as written, with its 1-based line, in order (for messages)
+    pub keys: Vec<(String, usize)>,
 }
 
 impl Default for Instrument {
@@ -27,22 +143,138 @@ impl Default for Instrument {
             decay: 0.1,
             sustain: 0.7,
             release: 0.25,
+            unison: 1,
+            detune: 0.0,
+            kit: None,
+            fm: None,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+            chorus_depth: DEFAULT_CHORUS_DEPTH,
+            chorus_rate: DEFAULT_CHORUS_RATE,
+            chorus_mix: 0.0,
+            delay_time: DelayTime::Secs(DEFAULT_DELAY_SECS),
+            delay_feedback: DEFAULT_DELAY_FEEDBACK,
+            delay_mix: 0.0,
+            bend_range: DEFAULT_BEND_RANGE,
+            mono: false,
+            glide: 0.0,
+            retrigger: None,
+            waveform: Waveform::Sine,
+            antialias: true,
+            noise_color: NoiseColor::White,
+            gain: DEFAULT_GAIN,
+            normalize: false,
+            vel_to_amp: 1.0,
+            vel_to_attack: 0.0,
+            keys: Vec::new(),
         }
     }
 }
 
+/// Delay settings used when only some of the delay keys are given
+const DEFAULT_DELAY_SECS: f64 = 0.25;
+const DEFAULT_DELAY_FEEDBACK: f64 = 0.3;
+
+/// Chorus settings used when only some of the chorus keys are given
+const DEFAULT_CHORUS_DEPTH: f64 = 10.0;
+const DEFAULT_CHORUS_RATE: f64 = 0.8;
+
 /// Parse a single "key: value" line. Returns (key, value) or None.
-fn parse_line(line: &str) -> Option<(&str, f64)> {
+fn parse_line(line: &str) -> Option<(&str, &str)> {
     let trimmed = line.trim();
     if trimmed.is_empty() || trimmed.starts_with('#') {
         return None;
     }
     let colon = trimmed.find(':')?;
     let key = trimmed[..colon].trim();
-    let value = trimmed[colon + 1..].trim().parse::<f64>().ok()?;
+    let value = trimmed[colon + 1..].trim();
     Some((key, value))
 }
 
+/// Parse `true` or `false`, naming the key on failure.
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("invalid {} '{}' (expected true or false)", key, value)),
+    }
+}
+
+/// Parse a numeric value, naming the key on failure.
+fn parse_number(key: &str, value: &str) -> Result<f64, String> {
+    value.parse::<f64>().map_err(|_| format!("invalid number '{}' for {}", value, key))
+}
+
+/// Something wrong with an instrument's settings, and the 1-based line that
+/// set the value in question (None when it's a default)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Problem {
+    /// A problem with the value on (0-based) line `line_num`
+    fn on(line_num: usize, message: String) -> Self {
+        Problem {
+            line: Some(line_num + 1),
+            message,
+        }
+    }
+
+    /// `name:line: message`, `name` being the file (or preset) it is in
+    pub fn located(&self, name: &str) -> String {
+        match self.line {
+            Some(line) => format!("{}:{}: {}", name, line, self.message),
+            None => format!("{}: {}", name, self.message),
+        }
+    }
+}
+
+/// Every key a `.instr` file may set
+const KEYS: [&str; 38] = [
+    "type", "attack", "decay", "sustain", "release", "curve", "attack_curve", "decay_curve",
+    "release_curve", "unison", "detune", "chorus_depth", "chorus_rate", "chorus_mix",
+    "delay_time", "delay_feedback", "delay_mix", "bend_range", "mono", "glide", "retrigger",
+    "waveform", "antialias", "noise_color", "gain", "normalize", "vel_to_amp", "vel_to_attack",
+    "kick_decay", "snare_decay", "hat_decay", "fm_ratio", "fm_index", "fm_index_env",
+    "fm_attack", "fm_decay", "fm_sustain", "fm_release",
+];
+
+/// Most edits a misspelled key may be from a known one to be suggested
+const MAX_SUGGESTION_EDITS: usize = 2;
+
+/// The error for a key that isn't in `KEYS`, suggesting the nearest one
+/// when it looks like a typo
+fn unknown_key(key: &str) -> String {
+    let nearest = KEYS
+        .iter()
+        .map(|k| (edit_distance(key, k), *k))
+        .min_by_key(|&(edits, _)| edits)
+        .filter(|&(edits, _)| edits <= MAX_SUGGESTION_EDITS);
+    match nearest {
+        Some((_, known)) => format!("unknown key '{}' (did you mean '{}'?)", key, known),
+        None => format!("unknown key '{}'", key),
+    }
+}
+
+/// Insertions, deletions and substitutions turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(ca != cb)).min(above + 1).min(row[j] + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
 /// Load an instrument from a `.instr` file.
 ///
 /// Format (one per line, optional comments with #):
@@ -52,52 +284,897 @@ fn parse_line(line: &str) -> Option<(&str, f64)> {
 /// decay: 0.1
 /// sustain: 0.7
 /// release: 0.25
+/// # Optional: detuned oscillators per note and their spread in cents
+/// unison: 3
+/// detune: 12
+/// # Optional: envelope shape, linear (default) or exponential, for all
+/// # stages or per stage (attack_curve, decay_curve, release_curve)
+/// curve: exponential
+/// # Optional: chorus; delay sweep in ms (up to 25), rate in Hz, wet share
+/// chorus_depth: 10
+/// chorus_rate: 0.8
+/// chorus_mix: 0.5
+/// # Optional: feedback delay; time in seconds or as a note length (1/8)
+/// delay_time: 1/8
+/// delay_feedback: 0.4
+/// delay_mix: 0.3
+/// # Optional: pitch bend range in semitones (default 2)
+/// bend_range: 12
+/// # Optional: one note at a time (newest key wins), sliding between notes
+/// mono: true
+/// glide: 0.05
+/// # Optional: what playing a key that is still sounding does: reset (a new
+/// # attack from silence), continue (the attack from the current level;
+/// # default) or legato (no new attack; the default with mono: true)
+/// retrigger: reset
+/// # Optional: sine (default), square, saw, triangle or noise; non-sine
+/// # waveforms are band-limited unless antialias is false
+/// waveform: saw
+/// antialias: true
+/// # Optional, with waveform: noise: white (default), pink (softer), or
+/// # tuned (band-passed around each note's pitch)
+/// noise_color: pink
+/// # Optional: peak level of one note (default 0.3), and turning chords
+/// # down by the number of notes sounding (default false)
+/// gain: 0.5
+/// normalize: true
+/// # Optional: how far velocity scales the level (default 1; 0 plays every
+/// # note at full level), and how much a full-velocity note shortens the
+/// # attack (default 0; 0.5 halves it)
+/// vel_to_amp: 0.6
+/// vel_to_attack: 0.5
+/// ```
+///
+/// Drum kits set `type: drum` and the decay time of each drum in seconds:
+/// ```text
+/// type: drum
+/// kick_decay: 0.4
+/// snare_decay: 0.2
+/// hat_decay: 0.05
 /// ```
-pub fn load(path: &Path) -> Result<Instrument, String> {
-    let content = fs::read_to_string(path)
-        .map_err(|e| format!("reading instrument file: {}", e))?;
+///
+/// FM instruments set `type: fm`; a sine modulator at `fm_ratio` times the
+/// note's pitch modulates the waveform by `fm_index`:
+/// ```text
+/// type: fm
+/// fm_ratio: 2.0
+/// fm_index: 3.5
+/// # Optional: scale the index by the amplitude envelope
+/// fm_index_env: true
+/// # Optional: a separate envelope for the index
+/// fm_attack: 0.0
+/// fm_decay: 0.8
+/// fm_sustain: 0.2
+/// fm_release: 0.3
+/// ```
+pub fn load(path: &Path) -> Result<Instrument, ClidawError> {
+    let content = fs::read_to_string(path).map_err(|e| ClidawError::io(path, e))?;
+    parse(&content).map_err(|problems| ClidawError::Instrument {
+        name: path.display().to_string(),
+        problems,
+    })
+}
 
+/// Parse the contents of a `.instr` file (see `load` for the format). On
+/// failure every problem in it is returned, values out of range included.
+pub fn parse(content: &str) -> Result<Instrument, Vec<Problem>> {
+    from_entries(
+        content
+            .lines()
+            .enumerate()
+            .filter_map(|(line_num, line)| parse_line(line).map(|(k, v)| (line_num, k, v))),
+        Vec::new(),
+    )
+}
+
+/// Parse the body of an inline instrument from a `.song` file: the same
+/// keys as a `.instr` file, as comma-separated `key: value` pairs, e.g.
+/// `attack: 0.01, release: 0.3`. `line_num` (0-based) is the song line
+/// every problem is placed on.
+pub fn parse_inline(body: &str, line_num: usize) -> Result<Instrument, Vec<Problem>> {
+    let mut entries = Vec::new();
+    let mut problems = Vec::new();
+    for pair in body.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match pair.split_once(':') {
+            Some((key, value)) => entries.push((line_num, key.trim(), value.trim())),
+            None => problems
+                .push(Problem::on(line_num, format!("expected 'key: value', got '{}'", pair))),
+        }
+    }
+    from_entries(entries, problems)
+}
+
+/// Build an instrument from (0-based line, key, value) entries, adding to
+/// the `problems` already found in them.
+fn from_entries<'a>(
+    entries: impl IntoIterator<Item = (usize, &'a str, &'a str)>,
+    mut problems: Vec<Problem>,
+) -> Result<Instrument, Vec<Problem>> {
     let mut attack = None;
     let mut decay = None;
     let mut sustain = None;
     let mut release = None;
+    let mut unison = None;
+    let mut detune = None;
+    let mut chorus_depth = None;
+    let mut chorus_rate = None;
+    let mut chorus_mix = None;
+    let mut delay_feedback = None;
+    let mut delay_mix = None;
+    let mut bend_range = None;
+    let mut glide = None;
+    let mut gain = None;
+    let mut vel_to_amp = None;
+    let mut vel_to_attack = None;
+    let mut mono = false;
+    let mut normalize = false;
+    let mut waveform = Waveform::Sine;
+    let mut antialias = true;
+    let mut noise_color = None;
+    let mut retrigger = None;
+    let mut is_drum = false;
+    let mut kit = DrumKit::default();
+    let mut kit_keys_line = None;
+    let mut is_fm = false;
+    let mut fm_ratio = None;
+    let mut fm_index = None;
+    let mut fm_index_env = false;
+    let mut fm_envelope: [Option<f64>; 4] = [None; 4];
+    let mut fm_keys_line = None;
+    let mut curves = [Curve::Linear; 3];
+    let mut delay_time = None;
+    let mut keys = Vec::new();
 
-    for (line_num, line) in content.lines().enumerate() {
-        let (key, value) = match parse_line(line) {
-            Some(p) => p,
-            None => continue,
+    for (line_num, key, text) in entries {
+        keys.push((key.to_string(), line_num + 1));
+        // A bad value is noted and left out, so every line gets checked
+        let mut problem = |message: String| problems.push(Problem::on(line_num, message));
+        if key == "type" {
+            (is_drum, is_fm) = match text {
+                "tone" => (false, false),
+                "drum" => (true, false),
+                "fm" => (false, true),
+                _ => {
+                    problem(format!(
+                        "unknown instrument type '{}' (expected tone, drum or fm)",
+                        text
+                    ));
+                    continue;
+                }
+            };
+            continue;
+        }
+        if let Some(stage) = key.strip_suffix("curve") {
+            let Some(curve) = Curve::from_name(text) else {
+                problem(format!("unknown curve '{}' (expected linear or exponential)", text));
+                continue;
+            };
+            match stage {
+                "" => curves = [curve; 3],
+                "attack_" => curves[0] = curve,
+                "decay_" => curves[1] = curve,
+                "release_" => curves[2] = curve,
+                _ => problem(unknown_key(key)),
+            }
+            continue;
+        }
+        if key == "mono" || key == "antialias" || key == "normalize" || key == "fm_index_env" {
+            let value = match parse_bool(key, text) {
+                Ok(value) => value,
+                Err(message) => {
+                    problem(message);
+                    continue;
+                }
+            };
+            match key {
+                "mono" => mono = value,
+                "antialias" => antialias = value,
+                "fm_index_env" => {
+                    fm_index_env = value;
+                    fm_keys_line.get_or_insert(line_num + 1);
+                }
+                _ => normalize = value,
+            }
+            continue;
+        }
+        if key == "waveform" {
+            match Waveform::from_name(text) {
+                Some(wave) => waveform = wave,
+                None => problem(format!(
+                    "unknown waveform '{}' (expected sine, square, saw, triangle or noise)",
+                    text
+                )),
+            }
+            continue;
+        }
+        if key == "noise_color" {
+            match NoiseColor::from_name(text) {
+                Some(color) => noise_color = Some((color, line_num + 1)),
+                None => problem(format!(
+                    "unknown noise_color '{}' (expected white, pink or tuned)",
+                    text
+                )),
+            }
+            continue;
+        }
+        if key == "retrigger" {
+            match Retrigger::from_name(text) {
+                Some(mode) => retrigger = Some(mode),
+                None => problem(format!(
+                    "unknown retrigger '{}' (expected reset, continue or legato)",
+                    text
+                )),
+            }
+            continue;
+        }
+        if key == "delay_time" {
+            match DelayTime::parse(text) {
+                Ok(time) => delay_time = Some(time),
+                Err(message) => problem(message),
+            }
+            continue;
+        }
+        if !KEYS.contains(&key) {
+            problem(unknown_key(key));
+            continue;
+        }
+        let value = match parse_number(key, text) {
+            Ok(value) => value,
+            Err(message) => {
+                problem(message);
+                continue;
+            }
         };
         match key {
             "attack" => attack = Some(value),
             "decay" => decay = Some(value),
             "sustain" => sustain = Some(value),
             "release" => release = Some(value),
-            _ => {
-                return Err(format!(
-                    "unknown key '{}' at line {}",
-                    key,
-                    line_num + 1
-                ));
+            "unison" => {
+                if value < 1.0 || value.fract() != 0.0 {
+                    problem(format!("unison must be a whole number of at least 1, got {}", value));
+                    continue;
+                }
+                unison = Some(value as u32);
             }
+            "detune" => detune = Some(value),
+            "chorus_depth" => chorus_depth = Some(value),
+            "chorus_rate" => chorus_rate = Some(value),
+            "chorus_mix" => chorus_mix = Some(value),
+            "delay_feedback" => delay_feedback = Some(value),
+            "delay_mix" => delay_mix = Some(value),
+            "bend_range" => bend_range = Some(value),
+            "glide" => glide = Some(value),
+            "gain" => gain = Some(value),
+            "vel_to_amp" => vel_to_amp = Some(value),
+            "vel_to_attack" => vel_to_attack = Some(value),
+            "kick_decay" | "snare_decay" | "hat_decay" => {
+                match key {
+                    "kick_decay" => kit.kick_decay = value,
+                    "snare_decay" => kit.snare_decay = value,
+                    _ => kit.hat_decay = value,
+                }
+                kit_keys_line.get_or_insert(line_num + 1);
+            }
+            "fm_ratio" | "fm_index" | "fm_attack" | "fm_decay" | "fm_sustain" | "fm_release" => {
+                match key {
+                    "fm_ratio" => fm_ratio = Some(value),
+                    "fm_index" => fm_index = Some(value),
+                    "fm_attack" => fm_envelope[0] = Some(value),
+                    "fm_decay" => fm_envelope[1] = Some(value),
+                    "fm_sustain" => fm_envelope[2] = Some(value),
+                    _ => fm_envelope[3] = Some(value),
+                }
+                fm_keys_line.get_or_insert(line_num + 1);
+            }
+            _ => problem(unknown_key(key)),
         }
     }
 
-    Ok(Instrument {
+    let needs = |line: Option<usize>, message: &str| {
+        line.map(|line| Problem {
+            line: Some(line),
+            message: message.to_string(),
+        })
+    };
+    problems.extend(needs(kit_keys_line.filter(|_| !is_drum), "drum decay needs 'type: drum'"));
+    problems.extend(needs(fm_keys_line.filter(|_| !is_fm), "FM setting needs 'type: fm'"));
+    let stray_color = noise_color.filter(|_| waveform != Waveform::Noise).map(|(_, line)| line);
+    problems.extend(needs(stray_color, "noise_color needs 'waveform: noise'"));
+
+    // Any of the fm_ envelope keys gives the index its own envelope
+    let defaults = Adsr::default();
+    let [fm_attack, fm_decay, fm_sustain, fm_release] = fm_envelope;
+    let fm_env = fm_envelope.iter().any(Option::is_some).then(|| Adsr {
+        attack: fm_attack.unwrap_or(defaults.attack),
+        decay: fm_decay.unwrap_or(defaults.decay),
+        sustain: fm_sustain.unwrap_or(defaults.sustain),
+        release: fm_release.unwrap_or(defaults.release),
+        ..Adsr::default()
+    });
+
+    let instrument = Instrument {
         attack: attack.unwrap_or(0.01),
         decay: decay.unwrap_or(0.1),
-        sustain: sustain.unwrap_or(0.7).clamp(0.0, 1.0),
+        sustain: sustain.unwrap_or(0.7),
         release: release.unwrap_or(0.25),
-    })
+        unison: unison.unwrap_or(1),
+        detune: detune.unwrap_or(0.0),
+        kit: is_drum.then_some(kit),
+        fm: is_fm.then(|| Fm {
+            ratio: fm_ratio.unwrap_or(DEFAULT_FM_RATIO),
+            index: fm_index.unwrap_or(DEFAULT_FM_INDEX),
+            index_env: fm_index_env,
+            envelope: fm_env,
+        }),
+        attack_curve: curves[0],
+        decay_curve: curves[1],
+        release_curve: curves[2],
+        chorus_depth: chorus_depth.unwrap_or(DEFAULT_CHORUS_DEPTH),
+        chorus_rate: chorus_rate.unwrap_or(DEFAULT_CHORUS_RATE),
+        chorus_mix: chorus_mix.unwrap_or(0.0),
+        delay_time: delay_time.unwrap_or(DelayTime::Secs(DEFAULT_DELAY_SECS)),
+        delay_feedback: delay_feedback.unwrap_or(DEFAULT_DELAY_FEEDBACK),
+        delay_mix: delay_mix.unwrap_or(0.0),
+        bend_range: bend_range.unwrap_or(DEFAULT_BEND_RANGE),
+        mono,
+        glide: glide.unwrap_or(0.0),
+        retrigger,
+        waveform,
+        antialias,
+        noise_color: noise_color.map_or(NoiseColor::White, |(color, _)| color),
+        gain: gain.unwrap_or(DEFAULT_GAIN),
+        normalize,
+        vel_to_amp: vel_to_amp.unwrap_or(1.0),
+        vel_to_attack: vel_to_attack.unwrap_or(0.0),
+        keys,
+    };
+    if problems.is_empty() {
+        return Ok(instrument);
+    }
+    problems.extend(instrument.validate());
+    problems.sort_by_key(|problem| problem.line);
+    Err(problems)
+}
+
+/// Built-in instruments for `patch:` in `.notes` files, in `.instr` format
+const PRESETS: [(&str, &str); 5] = [
+    ("default", ""),
+    ("pluck", "attack: 0.005\ndecay: 0.08\nsustain: 0.3\nrelease: 0.15\n"),
+    (
+        "pad",
+        "attack: 0.2\ndecay: 0.3\nsustain: 0.8\nrelease: 0.5\nrelease_curve: exponential\n",
+    ),
+    ("lead", "attack: 0.01\ndecay: 0.1\nsustain: 0.7\nrelease: 0.2\nunison: 3\ndetune: 10\n"),
+    ("kit", "type: drum\n"),
+];
+
+/// A built-in instrument by (case-insensitive) name
+pub fn preset(name: &str) -> Result<Instrument, ClidawError> {
+    let error = |problems| ClidawError::Instrument {
+        name: name.to_string(),
+        problems,
+    };
+    let (_, content) = PRESETS
+        .iter()
+        .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let names: Vec<&str> = PRESETS.iter().map(|(n, _)| *n).collect();
+            error(vec![Problem {
+                line: None,
+                message: format!("not a built-in preset (available: {})", names.join(", ")),
+            }])
+        })?;
+    parse(content).map_err(error)
 }
 
 impl Instrument {
+    /// Describe any out-of-range values (empty if the instrument is valid).
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for (name, value) in [
+            ("attack", self.attack),
+            ("decay", self.decay),
+            ("release", self.release),
+        ] {
+            if !(0.0..=MAX_ENVELOPE_SECS).contains(&value) {
+                problems.push(format!(
+                    "{} must be between 0 and {} seconds, got {}",
+                    name, MAX_ENVELOPE_SECS, value
+                ));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.sustain) {
+            problems.push(format!("sustain must be between 0 and 1, got {}", self.sustain));
+        }
+        if self.unison > MAX_UNISON {
+            problems.push(format!("unison must be at most {}, got {}", MAX_UNISON, self.unison));
+        }
+        if self.detune < 0.0 {
+            problems.push(format!("detune must be non-negative, got {}", self.detune));
+        }
+        let max_depth = CHORUS_MAX_DELAY_MS - CHORUS_MIN_DELAY_MS;
+        if !(0.0..=max_depth).contains(&self.chorus_depth) {
+            problems.push(format!(
+                "chorus_depth must be between 0 and {} ms, got {}",
+                max_depth, self.chorus_depth
+            ));
+        }
+        if !(0.0..=MAX_CHORUS_RATE).contains(&self.chorus_rate) {
+            problems.push(format!(
+                "chorus_rate must be between 0 and {} Hz, got {}",
+                MAX_CHORUS_RATE, self.chorus_rate
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.chorus_mix) {
+            problems.push(format!("chorus_mix must be between 0 and 1, got {}", self.chorus_mix));
+        }
+        let delay_len = match self.delay_time {
+            DelayTime::Secs(len) | DelayTime::Beats(len) => len,
+        };
+        if delay_len <= 0.0 {
+            problems.push(format!("delay_time must be positive, got {}", delay_len));
+        }
+        if !(0.0..1.0).contains(&self.delay_feedback) {
+            problems.push(format!(
+                "delay_feedback must be at least 0 and below 1, got {}",
+                self.delay_feedback
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.delay_mix) {
+            problems.push(format!("delay_mix must be between 0 and 1, got {}", self.delay_mix));
+        }
+        if !(0.0..=MAX_BEND_RANGE).contains(&self.bend_range) {
+            problems.push(format!(
+                "bend_range must be between 0 and {} semitones, got {}",
+                MAX_BEND_RANGE, self.bend_range
+            ));
+        }
+        if self.gain < 0.0 {
+            problems.push(format!("gain must be non-negative, got {}", self.gain));
+        }
+        for (name, value) in [
+            ("vel_to_amp", self.vel_to_amp),
+            ("vel_to_attack", self.vel_to_attack),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                problems.push(format!("{} must be between 0 and 1, got {}", name, value));
+            }
+        }
+        if !(0.0..=MAX_GLIDE).contains(&self.glide) {
+            problems.push(format!(
+                "glide must be between 0 and {} seconds, got {}",
+                MAX_GLIDE, self.glide
+            ));
+        }
+        if let Some(fm) = &self.fm {
+            if !(fm.ratio > 0.0 && fm.ratio <= MAX_FM_RATIO) {
+                problems.push(format!(
+                    "fm_ratio must be above 0 and at most {}, got {}",
+                    MAX_FM_RATIO, fm.ratio
+                ));
+            }
+            if !(0.0..=MAX_FM_INDEX).contains(&fm.index) {
+                problems.push(format!(
+                    "fm_index must be between 0 and {}, got {}",
+                    MAX_FM_INDEX, fm.index
+                ));
+            }
+            if let Some(env) = &fm.envelope {
+                for (name, value) in [
+                    ("fm_attack", env.attack),
+                    ("fm_decay", env.decay),
+                    ("fm_release", env.release),
+                ] {
+                    if !(0.0..=MAX_ENVELOPE_SECS).contains(&value) {
+                        problems.push(format!(
+                            "{} must be between 0 and {} seconds, got {}",
+                            name, MAX_ENVELOPE_SECS, value
+                        ));
+                    }
+                }
+                if !(0.0..=1.0).contains(&env.sustain) {
+                    problems.push(format!(
+                        "fm_sustain must be between 0 and 1, got {}",
+                        env.sustain
+                    ));
+                }
+            }
+        }
+        if let Some(kit) = &self.kit {
+            for (name, value) in [
+                ("kick_decay", kit.kick_decay),
+                ("snare_decay", kit.snare_decay),
+                ("hat_decay", kit.hat_decay),
+            ] {
+                if value <= 0.0 {
+                    problems.push(format!("{} must be positive, got {}", name, value));
+                }
+            }
+        }
+        problems.into_iter().map(|message| self.problem(message)).collect()
+    }
+
+    /// Describe settings that are valid but probably a mistake
+    pub fn warnings(&self) -> Vec<Problem> {
+        let mut warnings = Vec::new();
+        for (i, (key, line)) in self.keys.iter().enumerate() {
+            if self.keys[..i].iter().any(|(earlier, _)| earlier == key) {
+                warnings.push(Problem {
+                    line: Some(*line),
+                    message: format!("{} is set more than once; the last value wins", key),
+                });
+            }
+        }
+        if self.gain > LOUD_GAIN {
+            let message = format!("gain {} is above {} and will likely clip", self.gain, LOUD_GAIN);
+            warnings.push(self.problem(message));
+        }
+        if self.glide > 0.0 && !self.mono {
+            warnings.push(self.problem("glide has no effect without mono: true".to_string()));
+        }
+        if self.detune != 0.0 && self.unison <= 1 {
+            warnings.push(self.problem("detune has no effect without unison above 1".to_string()));
+        }
+        warnings
+    }
+
+    /// A problem whose message starts with the key it is about, placed on
+    /// the line that set that key
+    fn problem(&self, message: String) -> Problem {
+        let key = message.split_whitespace().next().unwrap_or_default();
+        let line = self.keys.iter().rev().find(|(k, _)| k == key).map(|&(_, line)| line);
+        Problem { line, message }
+    }
+
+    /// Report this instrument's problems, `name` being where it came from:
+    /// errors (and warnings, if `strict`) fail; other warnings are printed
+    pub fn vet(&self, name: &str, strict: bool) -> Result<(), ClidawError> {
+        let errors = self.validate();
+        let warnings = self.warnings();
+        let failing = if strict { [errors, warnings.clone()].concat() } else { errors };
+        if !failing.is_empty() {
+            return Err(ClidawError::Instrument {
+                name: name.to_string(),
+                problems: failing,
+            });
+        }
+        for warning in &warnings {
+            eprintln!("warning: {}", warning.located(name));
+        }
+        Ok(())
+    }
+
+    /// The kind of voice and its envelope, in a few words: "saw x3, ADSR
+    /// 0.01/0.1/0.7/0.25"
+    pub fn summary(&self) -> String {
+        if self.kit.is_some() {
+            return "drum kit".to_string();
+        }
+        let voice = if self.fm.is_some() { "fm" } else { self.waveform.name() };
+        let unison = if self.unison > 1 { format!(" x{}", self.unison) } else { String::new() };
+        format!(
+            "{}{}, ADSR {}/{}/{}/{}",
+            voice, unison, self.attack, self.decay, self.sustain, self.release
+        )
+    }
+
     /// Convert to the synth's ADSR type (used when creating the audio engine).
     pub fn to_adsr(&self) -> crate::synth::Adsr {
         crate::synth::Adsr {
             attack: self.attack,
             decay: self.decay,
-            sustain: self.sustain,
+            sustain: self.sustain.clamp(0.0, 1.0),
             release: self.release,
+            attack_curve: self.attack_curve,
+            decay_curve: self.decay_curve,
+            release_curve: self.release_curve,
         }
     }
+
+    /// Convert to the engine's per-track settings; a delay given as a note
+    /// length is resolved at `tempo`.
+    pub fn to_patch(&self, tempo: f64) -> crate::synth::Patch {
+        let chorus = (self.chorus_mix > 0.0).then(|| Chorus {
+            depth: self.chorus_depth.clamp(0.0, CHORUS_MAX_DELAY_MS - CHORUS_MIN_DELAY_MS),
+            rate: self.chorus_rate.clamp(0.0, MAX_CHORUS_RATE),
+            mix: self.chorus_mix.min(1.0),
+        });
+        let delay = (self.delay_mix > 0.0).then(|| Delay {
+            time: self.delay_time.secs(tempo).max(0.0),
+            feedback: self.delay_feedback.clamp(0.0, 0.99),
+            mix: self.delay_mix.min(1.0),
+        });
+        crate::synth::Patch {
+            adsr: self.to_adsr(),
+            velocity: VelocityResponse {
+                to_amp: self.vel_to_amp.clamp(0.0, 1.0),
+                to_attack: self.vel_to_attack.clamp(0.0, 1.0),
+            },
+            unison: self.unison.clamp(1, MAX_UNISON),
+            detune: self.detune,
+            kit: self.kit.clone(),
+            fm: self.fm.as_ref().map(|fm| Fm {
+                ratio: fm.ratio.clamp(0.0, MAX_FM_RATIO),
+                index: fm.index.clamp(0.0, MAX_FM_INDEX),
+                index_env: fm.index_env,
+                envelope: fm.envelope.as_ref().map(|env| Adsr {
+                    sustain: env.sustain.clamp(0.0, 1.0),
+                    ..env.clone()
+                }),
+            }),
+            chorus,
+            delay,
+            // Like routing, a chain belongs to the song's track
+            effects: Vec::new(),
+            bend_range: self.bend_range.clamp(0.0, MAX_BEND_RANGE),
+            mono: self.mono,
+            glide: self.glide.clamp(0.0, MAX_GLIDE),
+            retrigger: self.retrigger,
+            waveform: self.waveform,
+            antialias: self.antialias,
+            noise_color: self.noise_color,
+            // Routing is a property of the song's track, not the sound
+            output_channel: None,
+            bus: None,
+            reverb_send: 1.0,
+            gain: self.gain.max(0.0),
+            normalize: self.normalize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each problem `parse` finds in `content`, as it reports them
+    fn errors(content: &str) -> Vec<String> {
+        parse(content).unwrap_err().iter().map(|p| p.located("bad.instr")).collect()
+    }
+
+    #[test]
+    fn test_presets_are_valid() {
+        for (name, _) in PRESETS {
+            assert!(preset(name).unwrap().validate().is_empty(), "{}", name);
+        }
+        assert!(preset("KIT").unwrap().kit.is_some());
+        let err = preset("tuba").unwrap_err().to_string();
+        let expected = "tuba: not a built-in preset (available: default, pluck";
+        assert!(err.starts_with(expected), "{}", err);
+    }
+
+    #[test]
+    fn test_inline_matches_file_format() {
+        let mut inline = parse_inline("attack: 0.5, release: 1.5, curve: exp", 0).unwrap();
+        let mut file = parse("attack: 0.5\nrelease: 1.5\ncurve: exp\n").unwrap();
+        // Only the lines the keys were on differ
+        assert!(inline.keys.iter().all(|&(_, line)| line == 1));
+        assert_eq!(file.keys.last(), Some(&("curve".to_string(), 3)));
+        (inline.keys, file.keys) = (Vec::new(), Vec::new());
+        assert_eq!(format!("{:?}", inline), format!("{:?}", file));
+        assert_eq!(inline.attack, 0.5);
+        assert_eq!(inline.release_curve, Curve::Exponential);
+        assert_eq!(inline.sustain, Instrument::default().sustain);
+    }
+
+    #[test]
+    fn test_delay_time_as_note_length() {
+        let instr = parse("delay_time: 3/16\ndelay_mix: 0.3\n").unwrap();
+        assert_eq!(instr.delay_time, DelayTime::Beats(0.75));
+        // Dotted eighth at 120 BPM
+        let delay = instr.to_patch(120.0).delay.unwrap();
+        assert!((delay.time - 0.375).abs() < 1e-12);
+        assert_eq!(delay.feedback, DEFAULT_DELAY_FEEDBACK);
+        assert!(parse("delay_time: 1/0\n").is_err());
+        // No mix, no delay
+        assert_eq!(parse("delay_time: 0.5\n").unwrap().to_patch(120.0).delay, None);
+        let problems = parse("delay_feedback: 1.0\n").unwrap().validate();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+    }
+
+    #[test]
+    fn test_chorus_keys() {
+        let instr = parse("chorus_mix: 0.5\nchorus_rate: 2\n").unwrap();
+        let chorus = instr.to_patch(120.0).chorus.unwrap();
+        assert_eq!(chorus.depth, DEFAULT_CHORUS_DEPTH);
+        assert_eq!((chorus.rate, chorus.mix), (2.0, 0.5));
+        assert_eq!(parse("chorus_depth: 5\n").unwrap().to_patch(120.0).chorus, None);
+        let problems = parse("chorus_depth: 40\nchorus_rate: -1\n").unwrap().validate();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+    }
+
+    #[test]
+    fn test_velocity_keys() {
+        let default = parse("").unwrap().to_patch(120.0);
+        assert_eq!(default.velocity, VelocityResponse::default());
+        let patch = parse("vel_to_amp: 0.6\nvel_to_attack: 0.5\n").unwrap().to_patch(120.0);
+        assert_eq!((patch.velocity.to_amp, patch.velocity.to_attack), (0.6, 0.5));
+        let instr = parse("vel_to_amp: 1.5\nvel_to_attack: -0.1\n").unwrap();
+        assert_eq!(instr.validate().len(), 2);
+        assert_eq!(instr.to_patch(120.0).velocity.to_amp, 1.0);
+    }
+
+    #[test]
+    fn test_mono_and_glide_keys() {
+        let patch = parse("mono: true\nglide: 0.05\n").unwrap().to_patch(120.0);
+        assert!(patch.mono);
+        assert_eq!(patch.glide, 0.05);
+        assert!(!parse("").unwrap().to_patch(120.0).mono);
+        let err = errors("mono: yes\n");
+        assert_eq!(err, ["bad.instr:1: invalid mono 'yes' (expected true or false)"]);
+        assert_eq!(parse("glide: 5\n").unwrap().validate().len(), 1);
+    }
+
+    #[test]
+    fn test_retrigger_key() {
+        let patch = parse("retrigger: reset\n").unwrap().to_patch(120.0);
+        assert_eq!(patch.retrigger, Some(Retrigger::Reset));
+        let patch = parse("mono: true\nretrigger: continue\n").unwrap().to_patch(120.0);
+        assert_eq!(patch.retrigger, Some(Retrigger::Continue));
+        assert_eq!(parse("mono: true\n").unwrap().retrigger, None);
+        let err = &errors("retrigger: restart\n")[0];
+        assert!(err.starts_with("bad.instr:1: unknown retrigger 'restart'"), "{}", err);
+    }
+
+    #[test]
+    fn test_waveform_keys() {
+        let patch = parse("waveform: saw\n").unwrap().to_patch(120.0);
+        assert_eq!((patch.waveform, patch.antialias), (Waveform::Saw, true));
+        let patch = parse("waveform: square\nantialias: false\n").unwrap().to_patch(120.0);
+        assert_eq!((patch.waveform, patch.antialias), (Waveform::Square, false));
+        assert_eq!(parse("").unwrap().waveform, Waveform::Sine);
+        let err = &errors("waveform: pulse\n")[0];
+        assert_eq!(
+            err,
+            "bad.instr:1: unknown waveform 'pulse' (expected sine, square, saw, triangle or noise)"
+        );
+        let patch = parse("waveform: noise\nnoise_color: tuned\n").unwrap().to_patch(120.0);
+        assert_eq!((patch.waveform, patch.noise_color), (Waveform::Noise, NoiseColor::Tuned));
+        assert_eq!(parse("waveform: noise\n").unwrap().noise_color, NoiseColor::White);
+        let err = errors("noise_color: pink\n");
+        assert_eq!(err, ["bad.instr:1: noise_color needs 'waveform: noise'"]);
+        let err = &errors("waveform: noise\nnoise_color: brown\n")[0];
+        assert!(err.starts_with("bad.instr:2: unknown noise_color 'brown'"), "{}", err);
+        assert_eq!(
+            errors("antialias: on\n"),
+            ["bad.instr:1: invalid antialias 'on' (expected true or false)"]
+        );
+    }
+
+    #[test]
+    fn test_gain_keys() {
+        let default = parse("").unwrap().to_patch(120.0);
+        assert_eq!((default.gain, default.normalize), (DEFAULT_GAIN, false));
+        let patch = parse("gain: 0.5\nnormalize: true\n").unwrap().to_patch(120.0);
+        assert_eq!((patch.gain, patch.normalize), (0.5, true));
+        let quiet = parse("gain: -0.5\n").unwrap();
+        let problem = quiet.validate()[0].located("quiet.instr");
+        assert_eq!(problem, "quiet.instr:1: gain must be non-negative, got -0.5");
+        assert_eq!(quiet.to_patch(120.0).gain, 0.0);
+        let loud = parse("gain: 2.5\n").unwrap();
+        assert!(loud.validate().is_empty());
+        let warnings = loud.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "gain 2.5 is above 2 and will likely clip");
+    }
+
+    #[test]
+    fn test_fm_keys() {
+        assert!(parse("").unwrap().to_patch(120.0).fm.is_none());
+        let fm = parse("type: fm\n").unwrap().fm.unwrap();
+        assert_eq!((fm.ratio, fm.index), (DEFAULT_FM_RATIO, DEFAULT_FM_INDEX));
+        assert!(!fm.index_env && fm.envelope.is_none());
+
+        let content = "type: fm\nfm_ratio: 2\nfm_index: 3.5\nfm_index_env: true\nfm_decay: 0.8\n";
+        let instr = parse(content).unwrap();
+        assert!(instr.validate().is_empty());
+        let fm = instr.to_patch(120.0).fm.unwrap();
+        assert_eq!((fm.ratio, fm.index, fm.index_env), (2.0, 3.5, true));
+        let env = fm.envelope.unwrap();
+        assert_eq!((env.decay, env.sustain), (0.8, Adsr::default().sustain));
+
+        assert_eq!(errors("fm_index: 2\n"), ["bad.instr:1: FM setting needs 'type: fm'"]);
+        let err = &errors("type: organ\n")[0];
+        assert!(err.ends_with("(expected tone, drum or fm)"), "{}", err);
+        let problems = parse("type: fm\nfm_ratio: 0\nfm_index: -1\nfm_sustain: 2\n")
+            .unwrap()
+            .validate();
+        let problems: Vec<String> = problems.iter().map(|p| p.located("fm.instr")).collect();
+        assert_eq!(
+            problems,
+            [
+                "fm.instr:2: fm_ratio must be above 0 and at most 32, got 0",
+                "fm.instr:3: fm_index must be between 0 and 50, got -1",
+                "fm.instr:4: fm_sustain must be between 0 and 1, got 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_errors_name_the_line() {
+        let err = parse_inline("attack: fast", 6).unwrap_err();
+        assert_eq!(err, [Problem::on(6, "invalid number 'fast' for attack".to_string())]);
+        let err = parse_inline("attack 0.1", 2).unwrap_err();
+        assert_eq!(err, [Problem::on(2, "expected 'key: value', got 'attack 0.1'".to_string())]);
+    }
+
+    #[test]
+    fn test_envelope_ranges() {
+        let instr = parse("attack: -1\ndecay: 61\nrelease: 60\nsustain: 1.5\n").unwrap();
+        let problems: Vec<String> =
+            instr.validate().iter().map(|p| p.located("env.instr")).collect();
+        assert_eq!(
+            problems,
+            [
+                "env.instr:1: attack must be between 0 and 60 seconds, got -1",
+                "env.instr:2: decay must be between 0 and 60 seconds, got 61",
+                "env.instr:4: sustain must be between 0 and 1, got 1.5",
+            ]
+        );
+        let problems = parse("type: fm\nfm_release: 90\n").unwrap().validate();
+        assert_eq!(problems[0].line, Some(2));
+    }
+
+    #[test]
+    fn test_unknown_keys_suggest_the_nearest() {
+        assert_eq!(
+            errors("atack: 0.1\n"),
+            ["bad.instr:1: unknown key 'atack' (did you mean 'attack'?)"]
+        );
+        assert_eq!(
+            errors("sustain: 0.5\nrelase: 0.2\n"),
+            ["bad.instr:2: unknown key 'relase' (did you mean 'release'?)"]
+        );
+        assert_eq!(errors("brightness: high\n"), ["bad.instr:1: unknown key 'brightness'"]);
+        let err = parse_inline("atack_curve: exp", 4).unwrap_err();
+        assert!(err[0].message.ends_with("(did you mean 'attack_curve'?)"), "{:?}", err);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_warnings_name_their_line() {
+        let instr = parse("attack: 0.1\nglide: 0.1\nattack: 0.2\ndetune: 8\n").unwrap();
+        assert!(instr.validate().is_empty());
+        let warnings: Vec<String> = instr.warnings().iter().map(|p| p.located("w.instr")).collect();
+        assert_eq!(
+            warnings,
+            [
+                "w.instr:3: attack is set more than once; the last value wins",
+                "w.instr:2: glide has no effect without mono: true",
+                "w.instr:4: detune has no effect without unison above 1",
+            ]
+        );
+        let intended = parse("mono: true\nglide: 0.1\nunison: 3\ndetune: 8\n").unwrap();
+        assert!(intended.warnings().is_empty());
+        for (name, _) in PRESETS {
+            assert!(preset(name).unwrap().warnings().is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_vet_fails_on_errors_and_strict_warnings() {
+        let loud = parse("gain: 3\n").unwrap();
+        assert!(loud.vet("loud.instr", false).is_ok());
+        let err = loud.vet("loud.instr", true).unwrap_err();
+        assert_eq!(err.to_string(), "loud.instr:1: gain 3 is above 2 and will likely clip");
+        let bad = parse("attack: 0.1\nrelease: -2\n").unwrap();
+        let err = bad.vet("bad.instr", false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad.instr:2: release must be between 0 and 60 seconds, got -2"
+        );
+    }
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        assert_eq!(
+            errors("atack: 0.1\nrelease: -2\nmono: yes\n"),
+            [
+                "bad.instr:1: unknown key 'atack' (did you mean 'attack'?)",
+                "bad.instr:2: release must be between 0 and 60 seconds, got -2",
+                "bad.instr:3: invalid mono 'yes' (expected true or false)",
+            ]
+        );
+    }
 }
@@ -0,0 +1,36 @@
+//! Ctrl-C during playback: the first press stops playback cleanly (notes are
+//! released and given a moment to fade before the engine shuts down), a
+//! second press exits at once.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use signal_hook::consts::SIGINT;
+use signal_hook::flag;
+
+/// Exit code after an interrupt (128 + SIGINT), as shells expect
+pub const EXIT_CODE: i32 = 130;
+
+/// Longest wait for releases to fade after Ctrl-C
+pub const MAX_RELEASE_WAIT_SECS: f64 = 2.0;
+
+static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Install the SIGINT handlers. Until this is called, Ctrl-C keeps its
+/// default behavior of killing the process.
+pub fn install() -> Result<(), String> {
+    let interrupted = INTERRUPTED.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    // Registered first so it sees the flag before this signal sets it:
+    // only a second Ctrl-C exits immediately
+    flag::register_conditional_shutdown(SIGINT, EXIT_CODE, Arc::clone(interrupted))
+        .and_then(|_| flag::register(SIGINT, Arc::clone(interrupted)))
+        .map(|_| ())
+        .map_err(|e| format!("failed to install Ctrl-C handler: {}", e))
+}
+
+/// Whether Ctrl-C has been pressed
+pub fn requested() -> bool {
+    INTERRUPTED
+        .get()
+        .is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
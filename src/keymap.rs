@@ -0,0 +1,249 @@
+//! Keyboard layouts for live mode: which typed character plays which note.
+//!
+//! The built-in layout is the US QWERTY piano mapping from `char_to_note`.
+//! A keymap file overrides individual keys; keys it doesn't mention keep
+//! their built-in notes.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::ClidawError;
+use crate::note::NoteName;
+use crate::parser::char_to_note;
+
+/// Characters mapped by the built-in layout (see `char_to_note`)
+const BUILTIN_KEYS: &str = "asdfghjkl;'wetyuop";
+
+/// A mapped key: (character, note, octave offset)
+type Mapping = (char, NoteName, u8);
+
+/// One row of the on-screen keyboard as (column in half keys, key) pairs
+pub type KeyboardRow = Vec<(usize, char)>;
+
+/// Active character → (note, octave offset) mapping for live mode
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    keys: HashMap<char, (NoteName, u8)>,
+}
+
+impl Keymap {
+    /// The built-in US QWERTY layout
+    pub fn builtin() -> Self {
+        let keys = BUILTIN_KEYS
+            .chars()
+            .filter_map(|c| char_to_note(c).map(|note| (c, note)))
+            .collect();
+        Self { keys }
+    }
+
+    /// Note and octave offset for a typed character
+    pub fn lookup(&self, c: char) -> Option<(NoteName, u8)> {
+        self.keys.get(&c).copied()
+    }
+
+    /// Parse keymap file contents on top of the built-in layout.
+    ///
+    /// Format (one key per line, optional comments with #):
+    /// ```text
+    /// # AZERTY home row
+    /// q = C
+    /// z = C#
+    /// # Optional octave offset (0-8) for keys past the octave
+    /// k = C 1
+    /// ```
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut keymap = Self::builtin();
+        // Line where each key was mapped, to report duplicates
+        let mut seen: HashMap<char, usize> = HashMap::new();
+
+        for (line_idx, line) in content.lines().enumerate() {
+            let line_num = line_idx + 1;
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            let mut chars = trimmed.chars();
+            let key = chars.next().expect("line is not empty");
+            let Some(mapping) = chars.as_str().trim_start().strip_prefix('=') else {
+                return Err(format!(
+                    "line {}: expected '<key> = <note> [octave offset]'",
+                    line_num
+                ));
+            };
+            if key.is_ascii_digit() {
+                return Err(format!(
+                    "line {}: digit keys select the octave and can't be mapped",
+                    line_num
+                ));
+            }
+            if let Some(first) = seen.insert(key, line_num) {
+                return Err(format!(
+                    "line {}: key '{}' is already mapped on line {}",
+                    line_num, key, first
+                ));
+            }
+
+            let mut parts = mapping.split_whitespace();
+            let name = parts.next().ok_or_else(|| {
+                format!("line {}: missing note name for key '{}'", line_num, key)
+            })?;
+            let note = NoteName::from_name(name).ok_or_else(|| {
+                format!(
+                    "line {}: invalid note name '{}' (expected C, C#, D ... B)",
+                    line_num, name
+                )
+            })?;
+            let offset = match parts.next() {
+                None => 0,
+                Some(text) => match text.parse::<u8>() {
+                    Ok(n) if n <= 8 => n,
+                    _ => {
+                        return Err(format!(
+                            "line {}: octave offset must be between 0 and 8, got '{}'",
+                            line_num, text
+                        ));
+                    }
+                },
+            };
+            if let Some(extra) = parts.next() {
+                return Err(format!("line {}: unexpected '{}'", line_num, extra));
+            }
+
+            keymap.keys.insert(key, (note, offset));
+        }
+
+        Ok(keymap)
+    }
+
+    /// Load a keymap file
+    pub fn load(path: &Path) -> Result<Self, ClidawError> {
+        let content = fs::read_to_string(path).map_err(|e| ClidawError::io(path, e))?;
+        Self::parse(&content).map_err(|msg| ClidawError::Keymap {
+            file: path.to_path_buf(),
+            msg,
+        })
+    }
+
+    /// Load `~/.config/clidaw/keymap` if it exists, otherwise the built-in layout
+    pub fn load_default() -> Result<Self, ClidawError> {
+        match default_path() {
+            Some(path) if path.exists() => Self::load(&path),
+            _ => Ok(Self::builtin()),
+        }
+    }
+
+    /// Mapped keys split into naturals and sharps, each in pitch order
+    fn rows(&self) -> (Vec<Mapping>, Vec<Mapping>) {
+        let mut entries: Vec<Mapping> =
+            self.keys.iter().map(|(&c, &(n, o))| (c, n, o)).collect();
+        entries.sort_by_key(|&(c, n, o)| (o, n.semitone(), c));
+        entries.into_iter().partition(|(_, n, _)| !n.name().ends_with('#'))
+    }
+
+    /// Piano layout for the on-screen keyboard: the sharp keys on the top
+    /// row, the natural keys on the bottom, as (column, key) pairs. Columns
+    /// count half keys, so each sharp sits between the naturals around it.
+    pub fn keyboard_rows(&self) -> (KeyboardRow, KeyboardRow) {
+        let (naturals, sharps) = self.rows();
+        let bottom = naturals.iter().enumerate().map(|(i, &(c, _, _))| (i * 2, c)).collect();
+        let top = sharps
+            .iter()
+            .map(|&(c, n, o)| {
+                let below = naturals
+                    .iter()
+                    .filter(|&&(_, nn, no)| (no, nn.semitone()) < (o, n.semitone()))
+                    .count();
+                ((below * 2).saturating_sub(1), c)
+            })
+            .collect();
+        (top, bottom)
+    }
+
+    /// Banner lines listing the natural and sharp keys in pitch order, each
+    /// key above the note it plays.
+    pub fn banner_lines(&self) -> Vec<String> {
+        let (naturals, sharps) = self.rows();
+
+        let mut lines = Vec::new();
+        for (label, row) in [("Natural notes:", naturals), ("Sharps/flats:", sharps)] {
+            if row.is_empty() {
+                continue;
+            }
+            let mut keys = String::new();
+            let mut notes = String::new();
+            for (c, n, _) in &row {
+                let width = n.name().len();
+                keys.push_str(&format!("{:<width$} ", c, width = width));
+                notes.push_str(&format!("{:<width$} ", n.name(), width = width));
+            }
+            lines.push(format!("  {:<16}{}", label, keys.trim_end()));
+            lines.push(format!("  {:<16}{}", "", notes.trim_end()));
+            lines.push(String::new());
+        }
+        lines
+    }
+}
+
+/// `$XDG_CONFIG_HOME/clidaw/keymap`, falling back to `~/.config/clidaw/keymap`
+fn default_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("clidaw").join("keymap"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides_keep_builtin_fallback() {
+        let keymap = Keymap::parse("# AZERTY\nq = C\nz = c#\nm = E 1\n").unwrap();
+        assert_eq!(keymap.lookup('q'), Some((NoteName::C, 0)));
+        assert_eq!(keymap.lookup('z'), Some((NoteName::CSharp, 0)));
+        assert_eq!(keymap.lookup('m'), Some((NoteName::E, 1)));
+        // Not mentioned in the file: built-in mapping
+        assert_eq!(keymap.lookup('s'), Some((NoteName::D, 0)));
+        assert_eq!(keymap.lookup('x'), None);
+    }
+
+    #[test]
+    fn test_punctuation_keys() {
+        let keymap = Keymap::parse("; = F\n= = G\n").unwrap();
+        assert_eq!(keymap.lookup(';'), Some((NoteName::F, 0)));
+        assert_eq!(keymap.lookup('='), Some((NoteName::G, 0)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = Keymap::parse("q = C\nq = D\n").unwrap_err();
+        assert_eq!(err, "line 2: key 'q' is already mapped on line 1");
+        let err = Keymap::parse("q = H\n").unwrap_err();
+        assert!(err.starts_with("line 1: invalid note name 'H'"), "{}", err);
+        assert!(Keymap::parse("q C\n").is_err());
+        assert!(Keymap::parse("q = C 9\n").is_err());
+        assert!(Keymap::parse("3 = C\n").is_err());
+    }
+
+    #[test]
+    fn test_banner_follows_mapping() {
+        let lines = Keymap::builtin().banner_lines();
+        assert_eq!(lines[0], "  Natural notes:  a s d f g h j k l ; '");
+        assert_eq!(lines[1], "                  C D E F G A B C D E F");
+        assert_eq!(lines[3], "  Sharps/flats:   w  e  t  y  u  o  p");
+        assert_eq!(lines[4], "                  C# D# F# G# A# C# D#");
+    }
+
+    #[test]
+    fn test_keyboard_rows_sit_sharps_between_naturals() {
+        let (top, bottom) = Keymap::builtin().keyboard_rows();
+        let keys = |row: &[(usize, char)]| row.iter().map(|&(_, c)| c).collect::<String>();
+        assert_eq!(keys(&bottom), "asdfghjkl;'");
+        assert_eq!(keys(&top), "wetyuop");
+        // w (C#) between a and s, t (F#) between f and g
+        assert_eq!((bottom[0].0, top[0].0, bottom[1].0), (0, 1, 2));
+        assert_eq!((bottom[3].0, top[2].0, bottom[4].0), (6, 7, 8));
+    }
+}
@@ -0,0 +1,39 @@
+//! The pieces of clidaw: parsing, scheduling, synthesis and the live
+//! modes. `main` is the command line on top; the library exists so the
+//! benchmarks in `benches/` can reach the scheduler too.
+
+pub mod backend;
+pub mod beat;
+pub mod cache;
+pub mod check;
+pub mod effects;
+pub mod error;
+pub mod export;
+pub mod flac;
+pub mod import;
+pub mod instrument;
+pub mod interrupt;
+pub mod keymap;
+pub mod looper;
+pub mod meter;
+pub mod midi;
+pub mod note;
+pub mod palette;
+pub mod parser;
+pub mod practice;
+pub mod record;
+pub mod render;
+pub mod repl;
+pub mod roll;
+pub mod rng;
+pub mod scaffold;
+pub mod scheduler;
+pub mod seq;
+pub mod song;
+pub mod synth;
+pub mod take;
+pub mod tempo;
+#[cfg(test)]
+mod test_util;
+pub mod watch;
+pub mod writer;
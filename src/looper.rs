@@ -0,0 +1,451 @@
+//! Live mode looper: record a phrase, then layer more over it while it repeats.
+//!
+//! The first recording sets the loop length (rounded to whole bars while the
+//! metronome is on). Each layer replays on its own engine tracks, one per
+//! keyboard instrument, so undoing one can silence exactly its notes and
+//! every note keeps the instrument it was played with. Time is passed in
+//! rather than read, so the clock thread and the tests drive the same logic.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::synth::LiveCommand;
+
+/// Most layers a loop can hold. With `n` keyboard instruments on tracks
+/// `0..n`, what layer `i` recorded from instrument `k` plays on engine
+/// track `(i + 1) * n + k`.
+pub const MAX_LAYERS: usize = 8;
+
+/// Engine tracks live mode needs with `instruments` keyboard instruments:
+/// the keyboard's plus a copy of them for each layer
+pub fn tracks(instruments: usize) -> usize {
+    (MAX_LAYERS + 1) * instruments.max(1)
+}
+
+/// Shortest loop accepted; anything shorter was probably a double tap
+const MIN_LOOP: Duration = Duration::from_millis(250);
+
+/// Notes and releases of one pass, at offsets from the start of the loop.
+/// Offsets run from 0 up to and including the loop length.
+#[derive(Debug, Clone, Default)]
+struct Layer {
+    events: Vec<(Duration, LiveCommand)>,
+}
+
+impl Layer {
+    /// Release notes that were still held when recording stopped, at `offset`
+    fn close(&mut self, offset: Duration) {
+        let mut held: Vec<(usize, char)> = Vec::new();
+        for (_, command) in &self.events {
+            match *command {
+                LiveCommand::NoteOn { track, key, .. } => held.push((track, key)),
+                LiveCommand::NoteOff { track, key } => held.retain(|&h| h != (track, key)),
+                _ => {}
+            }
+        }
+        for (track, key) in held {
+            self.events.push((offset, LiveCommand::NoteOff { track, key }));
+        }
+        self.events.sort_by_key(|(offset, _)| *offset);
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Idle,
+    /// Recording the first pass, which sets the length
+    Recording { start: Instant, layer: Layer },
+    /// Repeating; `overdub` is the layer being recorded over the loop
+    Playing {
+        origin: Instant,
+        length: Duration,
+        /// Time since `origin` up to which events have been sent
+        played: Duration,
+        overdub: Option<Layer>,
+    },
+}
+
+/// What a loop key press did, for the status line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopAction {
+    Recording,
+    Looping,
+    Overdubbing,
+    LayerAdded,
+    /// The first pass was too short to loop and was thrown away
+    TooShort,
+    /// No room for another layer
+    Full,
+}
+
+pub struct Looper {
+    state: State,
+    layers: Vec<Layer>,
+    /// Length of a bar, when the loop length should be whole bars
+    bar: Option<Duration>,
+    /// Keyboard instruments, each with its own track in every layer
+    instruments: usize,
+}
+
+impl Looper {
+    /// A looper for a keyboard playing on tracks `0..instruments`
+    pub fn new(instruments: usize) -> Self {
+        Self {
+            state: State::Idle,
+            layers: Vec::new(),
+            bar: None,
+            instruments: instruments.max(1),
+        }
+    }
+
+    /// Round the loop length to whole bars of this length (None = free timing)
+    pub fn set_bar(&mut self, bar: Option<Duration>) {
+        self.bar = bar;
+    }
+
+    /// The loop key: start recording, close the first pass into a loop,
+    /// or start/finish an overdub layer
+    pub fn toggle(&mut self, now: Instant) -> LoopAction {
+        let offset = self.offset(now);
+        match &mut self.state {
+            State::Idle => {
+                self.state = State::Recording {
+                    start: now,
+                    layer: Layer::default(),
+                };
+                LoopAction::Recording
+            }
+            State::Recording { start, layer } => {
+                let origin = *start;
+                let elapsed = now.duration_since(origin);
+                if elapsed < MIN_LOOP {
+                    self.state = State::Idle;
+                    return LoopAction::TooShort;
+                }
+                let length = match self.bar {
+                    Some(bar) if !bar.is_zero() => {
+                        let bars = (elapsed.as_secs_f64() / bar.as_secs_f64()).round().max(1.0);
+                        bar.mul_f64(bars)
+                    }
+                    _ => elapsed,
+                };
+                let mut layer = std::mem::take(layer);
+                // A quantized loop can be shorter than the pass
+                layer.events.retain(|(offset, command)| {
+                    *offset < length || matches!(command, LiveCommand::NoteOff { .. })
+                });
+                for (offset, _) in layer.events.iter_mut() {
+                    *offset = (*offset).min(length);
+                }
+                layer.close(length);
+                self.layers.push(layer);
+                self.state = State::Playing {
+                    origin,
+                    length,
+                    played: length,
+                    overdub: None,
+                };
+                LoopAction::Looping
+            }
+            State::Playing { overdub, .. } => match overdub.take() {
+                None if self.layers.len() >= MAX_LAYERS => LoopAction::Full,
+                None => {
+                    *overdub = Some(Layer::default());
+                    LoopAction::Overdubbing
+                }
+                Some(mut layer) => {
+                    layer.close(offset.unwrap_or_default());
+                    if !layer.events.is_empty() {
+                        self.layers.push(layer);
+                    }
+                    LoopAction::LayerAdded
+                }
+            },
+        }
+    }
+
+    /// Drop the overdub in progress, or else the newest layer (and with the
+    /// last one, the loop). Returns AllNotesOffs for the tracks it was
+    /// playing on.
+    pub fn undo(&mut self) -> Vec<LiveCommand> {
+        match &mut self.state {
+            State::Idle => Vec::new(),
+            State::Recording { .. } => {
+                self.state = State::Idle;
+                Vec::new()
+            }
+            State::Playing { overdub, .. } => {
+                if overdub.take().is_some() {
+                    return Vec::new();
+                }
+                // The newest layer's tracks start after the ones before it
+                let base = self.layers.len() * self.instruments;
+                self.layers.pop();
+                if self.layers.is_empty() {
+                    self.state = State::Idle;
+                }
+                (base..base + self.instruments)
+                    .map(|track| LiveCommand::AllNotesOff { track: Some(track) })
+                    .collect()
+            }
+        }
+    }
+
+    /// Offset of `now` into the loop (None if not looping)
+    fn offset(&self, now: Instant) -> Option<Duration> {
+        let State::Playing { origin, length, .. } = &self.state else {
+            return None;
+        };
+        let elapsed = now.saturating_duration_since(*origin).as_secs_f64();
+        Some(Duration::from_secs_f64(elapsed % length.as_secs_f64()))
+    }
+
+    /// Note a command played on the keyboard, if recording a pass
+    pub fn record(&mut self, now: Instant, command: &LiveCommand) {
+        if !matches!(command, LiveCommand::NoteOn { .. } | LiveCommand::NoteOff { .. }) {
+            return;
+        }
+        let offset = self.offset(now);
+        match &mut self.state {
+            State::Recording { start, layer } => {
+                layer.events.push((now.duration_since(*start), command.clone()));
+            }
+            State::Playing {
+                overdub: Some(layer),
+                ..
+            } => {
+                // Releases of notes played before the overdub began aren't part of it
+                let LiveCommand::NoteOff { key, .. } = command else {
+                    layer.events.push((offset.unwrap_or_default(), command.clone()));
+                    return;
+                };
+                if layer
+                    .events
+                    .iter()
+                    .any(|(_, c)| matches!(c, LiveCommand::NoteOn { key: k, .. } if k == key))
+                {
+                    layer.events.push((offset.unwrap_or_default(), command.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Layer commands due up to `now`, each on its layer's track
+    pub fn due(&mut self, now: Instant) -> Vec<LiveCommand> {
+        let State::Playing {
+            origin,
+            length,
+            played,
+            ..
+        } = &mut self.state
+        else {
+            return Vec::new();
+        };
+        let elapsed = now.saturating_duration_since(*origin);
+        if elapsed <= *played || length.is_zero() {
+            return Vec::new();
+        }
+        let len = length.as_secs_f64();
+        let from = played.as_secs_f64();
+        let to = elapsed.as_secs_f64();
+        *played = elapsed;
+
+        // Walk each loop pass the interval (from, to] touches; an offset of
+        // exactly the length ends a pass before the next one's 0 begins
+        let mut due = Vec::new();
+        let mut pass = (from / len).floor();
+        while pass * len <= to {
+            for (idx, layer) in self.layers.iter().enumerate() {
+                for (offset, command) in &layer.events {
+                    let at = pass * len + offset.as_secs_f64();
+                    if at > from && at <= to {
+                        due.push((at, idx, command.on_track((idx + 1) * self.instruments)));
+                    }
+                }
+            }
+            pass += 1.0;
+        }
+        due.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        due.into_iter().map(|(_, _, command)| command).collect()
+    }
+
+    /// Short description for the status line
+    pub fn status(&self) -> String {
+        match &self.state {
+            State::Idle => "off (Tab to record)".to_string(),
+            State::Recording { .. } => "recording... (Tab to loop)".to_string(),
+            State::Playing {
+                length, overdub, ..
+            } => format!(
+                "{:.1}s, {} layer(s){}",
+                length.as_secs_f64(),
+                self.layers.len(),
+                if overdub.is_some() { ", overdubbing" } else { "" }
+            ),
+        }
+    }
+}
+
+/// Clock thread for the looper: sends each layer's commands as they come
+/// due, until `stop` is set
+pub fn spawn_clock(
+    tx: Sender<LiveCommand>,
+    looper: Arc<Mutex<Looper>>,
+    stop: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            let due = looper.lock().unwrap().due(Instant::now());
+            for command in due {
+                if tx.send(command).is_err() {
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(key: char) -> LiveCommand {
+        LiveCommand::NoteOn {
+            track: 0,
+            key,
+            freq: 440.0,
+            velocity: 1.0,
+        }
+    }
+
+    fn note_off(key: char) -> LiveCommand {
+        LiveCommand::NoteOff { track: 0, key }
+    }
+
+    /// (on, track, key) of each note command
+    fn describe(commands: &[LiveCommand]) -> Vec<(bool, usize, char)> {
+        commands
+            .iter()
+            .map(|c| match *c {
+                LiveCommand::NoteOn { track, key, .. } => (true, track, key),
+                LiveCommand::NoteOff { track, key } => (false, track, key),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_first_pass_repeats_on_its_own_track() {
+        let t0 = Instant::now();
+        let ms = |n| t0 + Duration::from_millis(n);
+        let mut looper = Looper::new(1);
+        assert_eq!(looper.toggle(t0), LoopAction::Recording);
+        looper.record(ms(100), &note_on('a'));
+        looper.record(ms(300), &note_off('a'));
+        // Still held when the loop closes: released at the loop's end
+        looper.record(ms(600), &note_on('s'));
+        assert_eq!(looper.toggle(ms(1000)), LoopAction::Looping);
+
+        assert!(looper.due(ms(1050)).is_empty());
+        assert_eq!(describe(&looper.due(ms(1400))), [(true, 1, 'a'), (false, 1, 'a')]);
+        // The end of one pass comes before the start of the next
+        assert_eq!(
+            describe(&looper.due(ms(2100))),
+            [(true, 1, 's'), (false, 1, 's'), (true, 1, 'a')]
+        );
+    }
+
+    #[test]
+    fn test_length_rounds_to_bars() {
+        let t0 = Instant::now();
+        let mut looper = Looper::new(1);
+        looper.set_bar(Some(Duration::from_secs(2)));
+        looper.toggle(t0);
+        looper.record(t0 + Duration::from_millis(4500), &note_on('a'));
+        looper.toggle(t0 + Duration::from_millis(4800));
+        let State::Playing { length, .. } = looper.state else {
+            panic!("not looping");
+        };
+        assert_eq!(length, Duration::from_secs(4));
+        // The note past the rounded end is dropped
+        assert!(looper.layers[0].events.is_empty());
+
+        // A too-short tap records nothing
+        let mut looper = Looper::new(1);
+        looper.toggle(t0);
+        assert_eq!(looper.toggle(t0 + Duration::from_millis(50)), LoopAction::TooShort);
+        assert_eq!(looper.status(), "off (Tab to record)");
+    }
+
+    #[test]
+    fn test_overdub_layers_and_undo() {
+        let t0 = Instant::now();
+        let ms = |n| t0 + Duration::from_millis(n);
+        let mut looper = Looper::new(1);
+        looper.toggle(t0);
+        looper.record(ms(100), &note_on('a'));
+        looper.record(ms(200), &note_off('a'));
+        looper.toggle(ms(1000));
+        looper.due(ms(1000));
+
+        assert_eq!(looper.toggle(ms(1100)), LoopAction::Overdubbing);
+        // A release of a note from before the overdub isn't recorded
+        looper.record(ms(1150), &note_off('x'));
+        looper.record(ms(1500), &note_on('d'));
+        looper.record(ms(1700), &note_off('d'));
+        assert_eq!(looper.toggle(ms(1900)), LoopAction::LayerAdded);
+        assert_eq!(looper.status(), "1.0s, 2 layer(s)");
+
+        looper.due(ms(2000));
+        assert_eq!(
+            describe(&looper.due(ms(2600))),
+            [(true, 1, 'a'), (false, 1, 'a'), (true, 2, 'd')]
+        );
+        // Undo silences the newest layer's track
+        assert_eq!(looper.undo(), [LiveCommand::AllNotesOff { track: Some(2) }]);
+        assert_eq!(describe(&looper.due(ms(2800))), []);
+        looper.undo();
+        assert_eq!(looper.status(), "off (Tab to record)");
+        assert!(looper.due(ms(4000)).is_empty());
+    }
+
+    #[test]
+    fn test_layers_keep_their_instruments() {
+        let t0 = Instant::now();
+        let ms = |n| t0 + Duration::from_millis(n);
+        let on = |track, key| LiveCommand::NoteOn {
+            track,
+            key,
+            freq: 440.0,
+            velocity: 1.0,
+        };
+        // Two keyboard instruments on tracks 0 and 1
+        let mut looper = Looper::new(2);
+        looper.toggle(t0);
+        looper.record(ms(100), &on(0, 'a'));
+        looper.record(ms(200), &on(1, 's'));
+        looper.toggle(ms(1000));
+        assert_eq!(
+            describe(&looper.due(ms(1250))),
+            [(true, 2, 'a'), (true, 3, 's')]
+        );
+
+        looper.toggle(ms(1300));
+        looper.record(ms(1400), &on(1, 'd'));
+        looper.toggle(ms(1500));
+        // The second layer's copies of the instruments are tracks 4 and 5
+        assert_eq!(
+            looper.undo(),
+            [
+                LiveCommand::AllNotesOff { track: Some(4) },
+                LiveCommand::AllNotesOff { track: Some(5) },
+            ]
+        );
+        assert_eq!(tracks(2), 18);
+    }
+}
@@ -1,15 +1,19 @@
-mod instrument;
-mod note;
-mod parser;
-mod repl;
-mod scheduler;
-mod song;
-mod synth;
-
-use clap::{Parser, Subcommand};
+#[cfg(test)]
+mod golden;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use clidaw::{
+    backend, beat, cache, check, effects, error, export, import, instrument, interrupt, keymap,
+    midi, note, parser, practice, render, repl, roll, rng, scaffold, scheduler, seq, song, synth,
+    tempo, watch, writer,
+};
+use beat::Beat;
+use error::ClidawError;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 
 #[derive(Parser)]
 #[command(name = "clidaw", about = "Command-line digital audio workstation")]
@@ -19,11 +23,13 @@ struct Cli {
     command: Command,
 }
 
+// Parsed once at startup, so `Play`'s many flags cost nothing to keep inline
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Command {
     /// Play a .song file (multi-track) or a single .notes pattern
     Play {
-        /// Path to a .song file or .notes file
+        /// Path to a .song, .notes or .mid file (- reads a pattern from stdin)
         file: PathBuf,
 
         /// Instrument file (.instr); only used when playing a single .notes file
@@ -31,200 +37,2683 @@ enum Command {
         instrument: Option<PathBuf>,
 
         /// Override tempo (BPM); for .notes or as override in .song
+        #[arg(long, value_parser = tempo::parse_arg)]
+        tempo: Option<f64>,
+
+        /// Play only these tracks (index or name; repeatable); .song only
+        #[arg(long, conflicts_with = "mute")]
+        solo: Vec<String>,
+
+        /// Play just this one track (index or name); the same as a single --solo
+        #[arg(long, value_name = "TRACK", conflicts_with_all = ["solo", "mute"])]
+        only_track: Option<String>,
+
+        /// Silence these tracks (index or name; repeatable); .song only
+        #[arg(long)]
+        mute: Vec<String>,
+
+        /// Random timing jitter in milliseconds (either direction); .song only
+        #[arg(long, value_name = "MS")]
+        humanize: Option<f64>,
+
+        /// Random velocity jitter, 0.0-1.0 (either direction); .song only
+        #[arg(long, value_name = "AMOUNT")]
+        humanize_vel: Option<f64>,
+
+        /// Seed for --humanize and for the patterns' chance notes and random
+        /// alternatives (default: different every run)
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Pull note starts toward a grid this many beats apart (0.25 = sixteenths)
+        #[arg(long, value_name = "BEATS")]
+        quantize: Option<f64>,
+
+        /// How far --quantize moves notes: 0.0 leaves them, 1.0 snaps them to the grid
+        #[arg(long, value_name = "AMOUNT", default_value_t = 1.0, requires = "quantize")]
+        quantize_strength: f64,
+
+        /// Fade in over this many seconds (overrides the song's fade_in)
+        #[arg(long, value_name = "SECS")]
+        fade_in: Option<f64>,
+
+        /// Fade out over this many seconds before the end (overrides fade_out)
+        #[arg(long, value_name = "SECS")]
+        fade_out: Option<f64>,
+
+        /// Output device (index or name from `clidaw devices`)
+        #[arg(long)]
+        device: Option<String>,
+
+        /// Output sample rate in Hz (default: 48000 or 44100, whichever the device supports)
+        #[arg(long, value_name = "HZ")]
+        sample_rate: Option<u32>,
+
+        /// Frames per audio buffer; smaller is lower latency but may crackle
+        #[arg(long, value_name = "FRAMES")]
+        buffer_size: Option<u32>,
+
+        /// Bars of metronome clicks before the song starts; .song only
+        #[arg(long, value_name = "BARS")]
+        count_in: Option<u32>,
+
+        /// Keep the metronome clicking throughout the song; .song only
+        #[arg(long)]
+        click: bool,
+
+        /// Metronome volume, 0.0-1.0
+        #[arg(long, value_name = "AMOUNT", default_value_t = 0.5)]
+        click_volume: f64,
+
+        /// Gain on the whole mix (overrides the song's master_volume)
+        #[arg(long, value_name = "GAIN")]
+        master_volume: Option<f64>,
+
+        /// Don't show the progress line during playback
+        #[arg(long, short)]
+        quiet: bool,
+
+        /// Replay from the top whenever the file (or anything it references) changes
+        #[arg(long)]
+        watch: bool,
+
+        /// Turn off the master limiter (loud passages may clip)
+        #[arg(long)]
+        no_limiter: bool,
+
+        /// Fail on unknown characters in .notes files instead of skipping them,
+        /// and on instrument warnings instead of printing them
+        #[arg(long)]
+        strict: bool,
+
+        /// Reuse the song's schedule from .clidaw-cache/ next to it while none of
+        /// its files have changed; .song only
+        #[arg(long)]
+        cache: bool,
+
+        /// Play on when a pattern file is missing, as `missing: skip` in the song
+        /// does; .song only
+        #[arg(long)]
+        allow_missing: bool,
+
+        /// Start playing at this bar (1 = the first)
+        #[arg(long, value_name = "BAR", conflicts_with = "start_beat")]
+        start_bar: Option<u32>,
+
+        /// Start playing at this beat (0 = the start)
+        #[arg(long, value_name = "BEAT")]
+        start_beat: Option<f64>,
+
+        /// Stop after this bar
+        #[arg(long, value_name = "BAR", conflicts_with = "end_beat")]
+        end_bar: Option<u32>,
+
+        /// Stop at this beat
+        #[arg(long, value_name = "BEAT")]
+        end_beat: Option<f64>,
+
+        /// Repeat until stopped with Ctrl-C (the --start/--end range, if given)
+        #[arg(long = "loop")]
+        looped: bool,
+
+        /// Load and schedule everything, print a summary and exit without
+        /// opening an audio device
+        #[arg(long, conflicts_with_all = ["watch", "looped"])]
+        dry_run: bool,
+
+        /// The file (or stdin) is JSON events, as `clidaw parse --format json`
+        /// prints them, instead of .notes text
         #[arg(long)]
-        tempo: Option<u32>,
+        json: bool,
     },
 
     /// Parse a .notes file and show pattern (beats, loop, events)
     Parse {
+        /// Path to a .notes file (- reads it from stdin)
+        file: PathBuf,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// Fail on unknown characters instead of skipping them
+        #[arg(long)]
+        strict: bool,
+
+        /// Fail if a bar doesn't add up to the time signature's beats per bar
+        #[arg(long)]
+        strict_bars: bool,
+
+        /// Mark where each included file's events begin and end (text output)
+        #[arg(long)]
+        show_includes: bool,
+
+        /// Show where each event starts and every chord note's frequency, then
+        /// sum up the bars (text output)
+        #[arg(long)]
+        verbose: bool,
+
+        /// Draw the notes as a piano roll instead of listing them
+        #[arg(long, conflicts_with_all = ["format", "show_includes", "verbose"])]
+        roll: bool,
+
+        /// Beats per column of the piano roll (0.25 = sixteenths)
+        #[arg(long, value_name = "BEATS", default_value_t = 0.25, requires = "roll")]
+        grid: f64,
+    },
+
+    /// Render a .song or .notes file to an audio file instead of playing it
+    Render {
+        /// Path to a .song file or .notes file
+        file: PathBuf,
+
+        /// Output file; its extension picks the format unless --format is given
+        #[arg(long, short, required_unless_present = "stems", conflicts_with = "stems")]
+        output: Option<PathBuf>,
+
+        /// Write one file per track into this directory instead (01-bass.wav,
+        /// 02-lead.wav, ...), all the length of the full mix
+        #[arg(long, value_name = "DIR")]
+        stems: Option<PathBuf>,
+
+        /// Output format (default: from the output file's extension)
+        #[arg(long, value_enum)]
+        format: Option<render::AudioFormat>,
+
+        /// Sample format for WAV and FLAC
+        #[arg(long, value_enum, default_value = "16")]
+        bit_depth: render::BitDepth,
+
+        /// Sample rate of the render (44100 or 48000)
+        #[arg(long, default_value_t = 48_000)]
+        sample_rate: u32,
+
+        /// Override tempo (BPM); for .notes or as override in .song
+        #[arg(long, value_parser = tempo::parse_arg)]
+        tempo: Option<f64>,
+
+        /// Instrument file (.instr); only used when rendering a single .notes file
+        #[arg(long)]
+        instrument: Option<PathBuf>,
+
+        /// Gain on the whole mix (overrides the song's master_volume)
+        #[arg(long, value_name = "GAIN")]
+        master_volume: Option<f64>,
+
+        /// Turn off the master limiter (loud passages may clip)
+        #[arg(long)]
+        no_limiter: bool,
+
+        /// Seed for the patterns' chance notes and random alternatives
+        /// (default: different every run)
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+
+    /// Print every scheduled event of a .song or .notes file without playing it
+    Schedule {
+        /// Path to a .song file or .notes file
+        file: PathBuf,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ScheduleFormat::Json)]
+        format: ScheduleFormat,
+
+        /// Override tempo (BPM) used for the seconds column
+        #[arg(long, value_parser = tempo::parse_arg)]
+        tempo: Option<f64>,
+
+        /// Seed for the patterns' chance notes and random alternatives
+        /// (default: different every run)
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+
+    /// Convert a MIDI file to .notes patterns and a .song that plays them
+    Import {
+        /// Path to a .mid file
+        file: PathBuf,
+
+        /// Directory to write the .song and .notes files to
+        #[arg(long, short)]
+        output: PathBuf,
+
+        /// Grid note starts and lengths snap to, in beats (0.25 = sixteenths)
+        #[arg(long, value_name = "BEATS", default_value_t = import::DEFAULT_GRID)]
+        grid: f64,
+    },
+
+    /// Transpose, invert, reverse or stretch the notes of a .notes file and
+    /// write the result as .notes text
+    Transform {
         /// Path to a .notes file
         file: PathBuf,
+
+        /// Move every note this many semitones (e.g. +7 or -12)
+        #[arg(long, value_name = "SEMITONES", allow_negative_numbers = true)]
+        transpose: Option<i32>,
+
+        /// Mirror every note around this pitch (e.g. C4)
+        #[arg(long, value_name = "NOTE")]
+        invert_around: Option<String>,
+
+        /// Play the notes backwards
+        #[arg(long)]
+        reverse: bool,
+
+        /// Multiply every length by this factor (2 doubles them)
+        #[arg(long, value_name = "FACTOR")]
+        stretch: Option<f64>,
+
+        /// Write the result to this file instead of printing it
+        #[arg(long, short, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /// Start a project: a directory with a song, patterns and instruments
+    /// that play straight away, or single commented template files
+    New {
+        /// Directory to create the project in
+        #[arg(required_unless_present_any = ["pattern", "instrument"])]
+        dir: Option<PathBuf>,
+
+        /// Write a commented .notes template to this file instead
+        #[arg(long, value_name = "FILE", conflicts_with = "dir")]
+        pattern: Option<PathBuf>,
+
+        /// Write a commented .instr template to this file instead
+        #[arg(long, value_name = "FILE", conflicts_with = "dir")]
+        instrument: Option<PathBuf>,
+
+        /// Overwrite files that already exist
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Check a .song file and every file it references; exits non-zero on errors
+    Check {
+        /// Path to a .song file
+        file: PathBuf,
+
+        /// Also check that every bar adds up to the time signature's beats per bar
+        #[arg(long)]
+        strict_bars: bool,
+
+        /// Treat instrument warnings as errors
+        #[arg(long)]
+        strict: bool,
+    },
+
+    /// Show a file's title, author and description, with its length, bars,
+    /// and each track's note count, range and instrument
+    Info {
+        /// Path to a .song, .notes or .mid file
+        file: PathBuf,
     },
 
     /// Interactive keyboard mode — play notes by typing
-    Live,
+    Live {
+        /// Keyboard layout file (default: ~/.config/clidaw/keymap if present)
+        #[arg(long)]
+        keymap: Option<PathBuf>,
+
+        /// Instrument to play (.instr file or built-in preset name); repeat
+        /// to load several and switch between them with [ and ] or F1, F2, ...
+        #[arg(long = "instrument", value_name = "INSTRUMENT")]
+        instruments: Vec<PathBuf>,
+
+        /// Output device (index or name from `clidaw devices`)
+        #[arg(long)]
+        device: Option<String>,
+
+        /// Output sample rate in Hz (default: 48000 or 44100, whichever the device supports)
+        #[arg(long, value_name = "HZ")]
+        sample_rate: Option<u32>,
+
+        /// Frames per audio buffer (default 256); smaller is lower latency but may crackle
+        #[arg(long, value_name = "FRAMES")]
+        buffer_size: Option<u32>,
+
+        /// Metronome tempo (BPM; default 120, or the backing song's own);
+        /// toggle the metronome with Space
+        #[arg(long, value_parser = tempo::parse_arg)]
+        tempo: Option<f64>,
+
+        /// Metronome volume, 0.0-1.0
+        #[arg(long, value_name = "AMOUNT", default_value_t = 0.5)]
+        click_volume: f64,
+
+        /// Gain on the whole mix
+        #[arg(long, value_name = "GAIN", default_value_t = 1.0)]
+        master_volume: f64,
+
+        /// Turn off the master limiter (loud chords may clip)
+        #[arg(long)]
+        no_limiter: bool,
+
+        /// Song, .notes or MIDI file to play along with; it plays from the
+        /// start until it ends or you quit
+        #[arg(long, value_name = "FILE")]
+        backing: Option<PathBuf>,
+
+        /// Play the backing song over and over
+        #[arg(long = "loop", requires = "backing")]
+        looped: bool,
+
+        /// Record the session's audio to this WAV file
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+
+        /// Release any note held this long, in case its key-up was lost (0 = never)
+        #[arg(long, value_name = "SECS", default_value_t = 30.0)]
+        max_note_secs: f64,
+
+        /// Without key-release events from the terminal, release a key after
+        /// this long without a repeat (default: timed from its key repeat,
+        /// or 0.1 until repeats are measured)
+        #[arg(long, value_name = "SECS")]
+        release_timeout: Option<f64>,
+
+        /// Print the output's buffer size, sample rate and latency, then quit
+        #[arg(long, conflicts_with = "record")]
+        latency: bool,
+    },
+
+    /// Practice a scale on the live keyboard: the banner shows which keys
+    /// are in it, --strict mutes the rest and --quiz plays notes to echo
+    Practice {
+        /// Scale to practice, e.g. "D dorian" or "Bb major"
+        #[arg(long)]
+        scale: String,
+
+        /// Octave the keyboard starts in and quiz notes play in
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=8))]
+        octave: u8,
+
+        /// Mute keys that aren't in the scale
+        #[arg(long)]
+        strict: bool,
+
+        /// Play random notes of the scale to find and play back, and keep score
+        #[arg(long)]
+        quiz: bool,
+
+        /// Keyboard layout file (default: ~/.config/clidaw/keymap if present)
+        #[arg(long)]
+        keymap: Option<PathBuf>,
+
+        /// Instrument to play (.instr file or built-in preset name)
+        #[arg(long)]
+        instrument: Option<PathBuf>,
+
+        /// Output device (index or name from `clidaw devices`)
+        #[arg(long)]
+        device: Option<String>,
+
+        /// Output sample rate in Hz (default: 48000 or 44100, whichever the device supports)
+        #[arg(long, value_name = "HZ")]
+        sample_rate: Option<u32>,
+
+        /// Frames per audio buffer (default 256); smaller is lower latency but may crackle
+        #[arg(long, value_name = "FRAMES")]
+        buffer_size: Option<u32>,
+    },
+
+    /// Edit a pattern on a step grid (a row per pitch or drum, a column per
+    /// step), loop it with p and write it back with w
+    Seq {
+        /// The .notes file to edit; a new one is written on the first save
+        file: PathBuf,
+
+        /// Make a new file a drum grid (kick, snare, hat) instead of pitches
+        #[arg(long)]
+        drums: bool,
+
+        /// Steps in the grid (default: the file's length, or 16 for a new file)
+        #[arg(long)]
+        steps: Option<usize>,
+
+        /// Steps per beat of the music (4 = sixteenths); a new file's tempo is
+        /// written times this, one .notes beat per step
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=16))]
+        steps_per_beat: u8,
+
+        /// Tempo in BPM of the music (default: the file's, or 120)
+        #[arg(long, value_parser = tempo::parse_arg)]
+        tempo: Option<f64>,
+
+        /// Octave shown first (pitch grids)
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(0..=7))]
+        octave: u8,
+
+        /// Instrument to play (.instr file or built-in preset name; default:
+        /// the file's patch, else the default preset or the drum kit)
+        #[arg(long)]
+        instrument: Option<PathBuf>,
+
+        /// Output device (index or name from `clidaw devices`)
+        #[arg(long)]
+        device: Option<String>,
+
+        /// Output sample rate in Hz (default: 48000 or 44100, whichever the device supports)
+        #[arg(long, value_name = "HZ")]
+        sample_rate: Option<u32>,
+
+        /// Frames per audio buffer (default 256); smaller is lower latency but may crackle
+        #[arg(long, value_name = "FRAMES")]
+        buffer_size: Option<u32>,
+    },
+
+    /// Play a short test phrase through one instrument: a staccato scale,
+    /// a legato scale, a chord and a long held note
+    Audition {
+        /// Instrument to hear (.instr file or built-in preset name)
+        instrument: PathBuf,
+
+        /// Tempo of the phrase (BPM)
+        #[arg(long, default_value_t = 120.0, value_parser = tempo::parse_arg)]
+        tempo: f64,
+
+        /// Octave the phrase starts in (C4 = middle C)
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(0..=8))]
+        octave: u8,
+
+        /// Output device (index or name from `clidaw devices`)
+        #[arg(long)]
+        device: Option<String>,
+
+        /// Output sample rate in Hz (default: 48000 or 44100, whichever the device supports)
+        #[arg(long, value_name = "HZ")]
+        sample_rate: Option<u32>,
+
+        /// Frames per audio buffer; smaller is lower latency but may crackle
+        #[arg(long, value_name = "FRAMES")]
+        buffer_size: Option<u32>,
+
+        /// Gain on the whole mix
+        #[arg(long, value_name = "GAIN")]
+        master_volume: Option<f64>,
+
+        /// Turn off the master limiter (loud chords may clip)
+        #[arg(long)]
+        no_limiter: bool,
+
+        /// Don't show the progress line during playback
+        #[arg(long, short)]
+        quiet: bool,
+
+        /// Play the phrase again whenever the .instr file changes
+        #[arg(long)]
+        watch: bool,
+    },
+
+    /// List audio output devices
+    Devices,
+
+    /// Tap any key on the beat to find a tempo; prints the BPM
+    Tap,
+}
+
+/// Output format for commands that can print machine-readable data
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Output format for `schedule`
+#[derive(Clone, Copy, ValueEnum)]
+enum ScheduleFormat {
+    Json,
+    Csv,
+}
+
+/// Options for `play` that shape the schedule of a .song
+#[derive(Clone, Default)]
+struct PlayOptions {
+    tempo: Option<f64>,
+    solo: Vec<String>,
+    mute: Vec<String>,
+    humanize: Option<scheduler::Humanize>,
+    quantize: Option<scheduler::Quantize>,
+    fade_in: Option<f64>,
+    fade_out: Option<f64>,
+    output: backend::OutputOptions,
+    metronome: Option<scheduler::Metronome>,
+    quiet: bool,
+    watch: bool,
+    no_limiter: bool,
+    /// Parse .notes files in strict mode and fail on instrument warnings
+    strict: bool,
+    /// Reuse (or save) the song's compiled schedule
+    cache: bool,
+    /// Play songs on past missing pattern files
+    allow_missing: bool,
+    start: Option<scheduler::Position>,
+    end: Option<scheduler::Position>,
+    /// Repeat until stopped
+    looped: bool,
+    /// Summarize the schedule instead of playing it
+    dry_run: bool,
+    /// Overrides the song's master volume
+    master_volume: Option<f64>,
+    /// Read the pattern as JSON events (`play --json`)
+    json: bool,
+    /// `--seed` for the patterns' chances and alternatives (humanize has
+    /// its own copy)
+    seed: Option<u64>,
 }
 
 fn main() {
     let cli = Cli::parse();
+    if let Err(e) = run(cli.command) {
+        // The reader of our output went away (`clidaw parse x.notes | head`)
+        if is_broken_pipe(&e) {
+            return;
+        }
+        match e {
+            ClidawError::Usage(_) => eprintln!("{}", e),
+            // Its diagnostics have been printed already
+            ClidawError::Diagnosed(_) => {}
+            _ => eprintln!("{} error: {}", error_kind(&e), e),
+        }
+        std::process::exit(exit_code(&e));
+    }
+    if interrupt::requested() {
+        std::process::exit(interrupt::EXIT_CODE);
+    }
+}
+
+/// Whether `err` is stdout's reader having closed the pipe
+fn is_broken_pipe(err: &ClidawError) -> bool {
+    matches!(err, ClidawError::Io { source, .. } if source.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// A failed write to stdout
+fn stdout_error(source: io::Error) -> ClidawError {
+    ClidawError::io("<stdout>", source)
+}
+
+/// What went wrong, for the start of the message
+fn error_kind(err: &ClidawError) -> &'static str {
+    match err {
+        ClidawError::Io { .. } => "File",
+        ClidawError::Parse { .. } => "Parse",
+        ClidawError::Song { .. } => "Song",
+        ClidawError::Instrument { .. } => "Instrument",
+        ClidawError::Keymap { .. } => "Keymap",
+        ClidawError::Midi { .. } => "MIDI",
+        ClidawError::Audio(_) => "Audio",
+        ClidawError::Schedule(_) => "Schedule",
+        ClidawError::Terminal(_) => "Terminal",
+        ClidawError::Usage(_) => "Usage",
+        ClidawError::Diagnosed(_) => "Check",
+    }
+}
 
-    match cli.command {
+/// Exit status for a failed command: 2 for a file that doesn't parse, 3 for
+/// audio device trouble, 4 for a file that can't be read or written and 1
+/// for anything else, errors found by `check` or `parse --strict-bars`
+/// included. (Ctrl-C exits with 130.)
+fn exit_code(err: &ClidawError) -> i32 {
+    match err {
+        ClidawError::Parse { .. } | ClidawError::Midi { .. } => 2,
+        ClidawError::Audio(_) => 3,
+        ClidawError::Io { .. } => 4,
+        _ => 1,
+    }
+}
+
+fn run(command: Command) -> Result<(), ClidawError> {
+    match command {
         Command::Play {
             file,
             instrument: instrument_override,
             tempo,
+            solo,
+            only_track,
+            mute,
+            humanize,
+            humanize_vel,
+            seed,
+            quantize,
+            quantize_strength,
+            fade_in,
+            fade_out,
+            device,
+            sample_rate,
+            buffer_size,
+            count_in,
+            click,
+            click_volume,
+            master_volume,
+            quiet,
+            watch,
+            no_limiter,
+            strict,
+            cache,
+            allow_missing,
+            start_bar,
+            start_beat,
+            end_bar,
+            end_beat,
+            looped,
+            dry_run,
+            json,
         } => {
+            check_click_volume(click_volume)?;
+            check_master_volume(master_volume)?;
+            let quantize = quantize_settings(quantize, quantize_strength)?;
+            let output = output_options(device, sample_rate, buffer_size)?;
+            if start_bar == Some(0) || end_bar == Some(0) {
+                return Err(ClidawError::Usage("bars are numbered from 1".to_string()));
+            }
+            let start = start_bar
+                .map(scheduler::Position::Bar)
+                .or(start_beat.map(scheduler::Position::Beat));
+            let end = end_bar
+                .map(scheduler::Position::Bar)
+                .or(end_beat.map(scheduler::Position::Beat));
+            interrupt::install().map_err(ClidawError::Terminal)?;
+            if watch && file == Path::new(STDIN_ARG) {
+                return Err(ClidawError::Usage("--watch can't follow stdin".to_string()));
+            }
             if file
                 .extension()
                 .is_some_and(|e| e.eq_ignore_ascii_case("song"))
             {
-                play_song(&file, tempo);
+                if json {
+                    let msg = "--json reads patterns; a .song is always text";
+                    return Err(ClidawError::Usage(msg.to_string()));
+                }
+                let humanize = humanize_settings(humanize, humanize_vel, seed)?;
+                if fade_in.is_some_and(|s| s < 0.0) || fade_out.is_some_and(|s| s < 0.0) {
+                    let msg = "--fade-in and --fade-out must be non-negative";
+                    return Err(ClidawError::Usage(msg.to_string()));
+                }
+                if looped && (fade_in.is_some() || fade_out.is_some()) {
+                    let msg = "--loop can't be combined with fades";
+                    return Err(ClidawError::Usage(msg.to_string()));
+                }
+                let options = PlayOptions {
+                    tempo,
+                    solo: only_track.map_or(solo, |track| vec![track]),
+                    mute,
+                    humanize,
+                    quantize,
+                    fade_in,
+                    fade_out,
+                    output,
+                    metronome: (count_in.is_some() || click).then_some(scheduler::Metronome {
+                        count_in_bars: count_in.unwrap_or(0),
+                        throughout: click,
+                        volume: click_volume,
+                    }),
+                    quiet,
+                    watch,
+                    no_limiter,
+                    strict,
+                    cache,
+                    allow_missing,
+                    start,
+                    end,
+                    looped,
+                    dry_run,
+                    master_volume,
+                    json: false,
+                    seed,
+                };
+                play_song(&file, &options)?;
             } else {
-                play_notes_file(&file, instrument_override, tempo);
+                if !solo.is_empty()
+                    || only_track.is_some()
+                    || !mute.is_empty()
+                    || humanize.is_some()
+                    || humanize_vel.is_some()
+                    || fade_in.is_some()
+                    || fade_out.is_some()
+                    || count_in.is_some()
+                    || click
+                    || cache
+                    || allow_missing
+                {
+                    let msg = "--solo, --only-track, --mute, --humanize, fades, the metronome, \
+                               --cache and --allow-missing only apply to .song files";
+                    return Err(ClidawError::Usage(msg.to_string()));
+                }
+                let options = PlayOptions {
+                    tempo,
+                    quantize,
+                    output,
+                    quiet,
+                    watch,
+                    no_limiter,
+                    strict,
+                    start,
+                    end,
+                    looped,
+                    dry_run,
+                    master_volume,
+                    json,
+                    seed,
+                    ..PlayOptions::default()
+                };
+                play_notes_file(&file, instrument_override, &options)?;
+            }
+        }
+        Command::Parse {
+            file,
+            format,
+            strict,
+            strict_bars,
+            show_includes,
+            verbose,
+            roll,
+            grid,
+        } => {
+            let input = read_input(&file)?;
+            let options = parser::ParseOptions::for_file(input_name(&file), strict);
+            let comp = parser::parse_with(&input, options)?;
+            if strict_bars {
+                let mut report = check::Report::default();
+                for track in &comp.tracks {
+                    check::check_bars(input_name(&file), &comp.track_pattern(track), &mut report);
+                }
+                for diagnostic in &report.diagnostics {
+                    eprintln!("{}", diagnostic);
+                }
+                if report.error_count() > 0 {
+                    return Err(ClidawError::Diagnosed(report.error_count()));
+                }
+            }
+            let mut out = io::stdout().lock();
+            if roll {
+                return print_roll(&mut out, comp, grid);
+            }
+            // Files with tracks or patches are shown track by track
+            let json = match (format, comp.has_tracks()) {
+                (OutputFormat::Text, true) => {
+                    print_composition(&mut out, &comp, show_includes, verbose)
+                        .map_err(stdout_error)?;
+                    None
+                }
+                (OutputFormat::Text, false) => {
+                    let includes = match comp.tracks.first() {
+                        Some(track) if show_includes => track.includes.clone(),
+                        _ => Vec::new(),
+                    };
+                    print_pattern(&mut out, &comp.into_pattern(), &includes, verbose)
+                        .map_err(stdout_error)?;
+                    None
+                }
+                (OutputFormat::Json, true) => Some(serde_json::to_string_pretty(&comp)),
+                (OutputFormat::Json, false) => {
+                    Some(serde_json::to_string_pretty(&comp.into_pattern()))
+                }
+            };
+            if let Some(json) = json {
+                let json = json.expect("pattern serialization cannot fail");
+                writeln!(out, "{}", json).map_err(stdout_error)?;
+            }
+        }
+        Command::Render {
+            file,
+            output,
+            stems,
+            format,
+            bit_depth,
+            sample_rate,
+            tempo,
+            instrument,
+            master_volume,
+            no_limiter,
+            seed,
+        } => {
+            check_master_volume(master_volume)?;
+            let settings = RenderSettings {
+                format,
+                bit_depth,
+                sample_rate,
+                tempo,
+                master_volume,
+                no_limiter,
+                seed,
+            };
+            match (output, stems) {
+                (_, Some(dir)) => render_stem_files(&file, &dir, instrument.as_deref(), &settings)?,
+                (Some(output), None) => {
+                    render_file(&file, &output, instrument.as_deref(), &settings)?
+                }
+                (None, None) => unreachable!("clap requires --output or --stems"),
+            }
+        }
+        Command::Schedule {
+            file,
+            format,
+            tempo,
+            seed,
+        } => {
+            print_schedule(&file, format, tempo, seed)?;
+        }
+        Command::Transform {
+            file,
+            transpose,
+            invert_around,
+            reverse,
+            stretch,
+            out,
+        } => {
+            let axis = match invert_around {
+                Some(name) => Some(note::midi_from_name(&name).ok_or_else(|| {
+                    ClidawError::Usage(format!(
+                        "--invert-around takes a note and octave 0-8 like C4 or F#3, not '{}'",
+                        name
+                    ))
+                })?),
+                None => None,
+            };
+            let transform = Transform {
+                transpose,
+                invert_around: axis,
+                reverse,
+                stretch,
+            };
+            transform_notes(&file, &transform, out.as_deref())?;
+        }
+        Command::Import { file, output, grid } => {
+            import_midi(&file, &output, grid)?;
+        }
+        Command::Check {
+            file,
+            strict_bars,
+            strict,
+        } => {
+            let report = check::check_song(&file, &check::CheckOptions { strict_bars, strict });
+            print_report(&mut io::stdout().lock(), &file, &report).map_err(stdout_error)?;
+            if report.error_count() > 0 {
+                return Err(ClidawError::Diagnosed(report.error_count()));
             }
         }
-        Command::Parse { file } => {
-            let input = read_file(&file);
-            let pattern = parser::parse_pattern(&input).unwrap_or_else(|e| {
-                eprintln!("Parse error: {}", e);
-                std::process::exit(1);
-            });
-            print_pattern(&pattern);
+        Command::Info { file } => print_info(&mut io::stdout().lock(), &file)?,
+        Command::Live {
+            keymap,
+            instruments,
+            device,
+            sample_rate,
+            buffer_size,
+            tempo,
+            click_volume,
+            master_volume,
+            no_limiter,
+            backing,
+            looped,
+            record,
+            max_note_secs,
+            release_timeout,
+            latency,
+        } => {
+            check_click_volume(click_volume)?;
+            if release_timeout.is_some_and(|secs| !(secs > 0.0 && secs.is_finite())) {
+                return Err(ClidawError::Usage(
+                    "--release-timeout must be a number of seconds above 0".to_string(),
+                ));
+            }
+            check_master_volume(Some(master_volume))?;
+            let output = output_options(device, sample_rate, buffer_size)?;
+            let keymap = match keymap {
+                Some(path) => keymap::Keymap::load(&path)?,
+                None => keymap::Keymap::load_default()?,
+            };
+            let backing = match backing {
+                Some(path) => Some(load_backing(&path, tempo, looped)?),
+                None => None,
+            };
+            let tempo = backing.as_ref().map_or(tempo.unwrap_or(120.0), |b| b.tempo);
+            let options = repl::LiveOptions {
+                keymap,
+                instruments: load_live_instruments(&instruments, tempo)?,
+                output,
+                tempo,
+                click_volume,
+                master_volume,
+                no_limiter,
+                record,
+                max_note_secs: (max_note_secs > 0.0).then_some(max_note_secs),
+                release_timeout,
+                latency_report: latency,
+                backing,
+                practice: None,
+            };
+            repl::run(&options)?;
+        }
+        Command::Practice {
+            scale,
+            octave,
+            strict,
+            quiz,
+            keymap,
+            instrument,
+            device,
+            sample_rate,
+            buffer_size,
+        } => {
+            let key = note::Key::parse(&scale).ok_or_else(|| {
+                ClidawError::Usage(format!(
+                    "invalid scale '{}' (expected a note and one of {}, e.g. 'D dorian')",
+                    scale,
+                    note::Scale::names().join(", ")
+                ))
+            })?;
+            let output = output_options(device, sample_rate, buffer_size)?;
+            let keymap = match keymap {
+                Some(path) => keymap::Keymap::load(&path)?,
+                None => keymap::Keymap::load_default()?,
+            };
+            let tempo = 120.0;
+            let options = repl::LiveOptions {
+                keymap,
+                instruments: load_live_instruments(instrument.as_slice(), tempo)?,
+                output,
+                tempo,
+                click_volume: 0.5,
+                master_volume: 1.0,
+                no_limiter: false,
+                record: None,
+                max_note_secs: Some(30.0),
+                release_timeout: None,
+                latency_report: false,
+                backing: None,
+                practice: Some(practice::Practice {
+                    key,
+                    octave,
+                    strict,
+                    quiz,
+                }),
+            };
+            repl::run(&options)?;
+        }
+        Command::Seq {
+            file,
+            drums,
+            steps,
+            steps_per_beat,
+            tempo,
+            octave,
+            instrument,
+            device,
+            sample_rate,
+            buffer_size,
+        } => {
+            if steps == Some(0) {
+                return Err(ClidawError::Usage("--steps must be at least 1".to_string()));
+            }
+            let output = output_options(device, sample_rate, buffer_size)?;
+            let grid = GridSettings {
+                drums,
+                steps,
+                steps_per_beat: steps_per_beat as usize,
+                tempo,
+                octave,
+            };
+            edit_steps(&file, &grid, instrument.as_deref(), output)?;
+        }
+        Command::Audition {
+            instrument,
+            tempo,
+            octave,
+            device,
+            sample_rate,
+            buffer_size,
+            master_volume,
+            no_limiter,
+            quiet,
+            watch,
+        } => {
+            check_master_volume(master_volume)?;
+            let output = output_options(device, sample_rate, buffer_size)?;
+            interrupt::install().map_err(ClidawError::Terminal)?;
+            let options = PlayOptions {
+                output,
+                quiet,
+                watch,
+                no_limiter,
+                master_volume,
+                ..PlayOptions::default()
+            };
+            audition(&instrument, tempo, octave, &options)?;
+        }
+        Command::New {
+            dir,
+            pattern,
+            instrument,
+            force,
+        } => {
+            let mut files = dir.as_deref().map(scaffold::project).unwrap_or_default();
+            if let Some(path) = pattern {
+                files.push((path, scaffold::pattern_template()));
+            }
+            if let Some(path) = instrument {
+                files.push((path, scaffold::instrument_template()));
+            }
+            scaffold::write_files(&files, force)?;
+            for (path, _) in &files {
+                println!("Created {}", path.display());
+            }
+            if let Some(dir) = dir {
+                let song = dir.join(scaffold::SONG_FILE);
+                println!("Play it with: clidaw play {}", song.display());
+            }
         }
-        Command::Live => {
-            if let Err(e) = repl::run() {
-                eprintln!("Live mode error: {}", e);
-                std::process::exit(1);
+        Command::Devices => {
+            let devices = backend::list_output_devices()?;
+            if devices.is_empty() {
+                println!("No output devices found");
+            }
+            for d in &devices {
+                println!(
+                    "{:>3}: {} ({} Hz, {} ch){}",
+                    d.index,
+                    d.name,
+                    d.sample_rate,
+                    d.channels,
+                    if d.is_default { " [default]" } else { "" }
+                );
             }
         }
+        Command::Tap => tempo::tap()?,
     }
+    Ok(())
 }
 
-fn play_song(song_path: &PathBuf, tempo_override: Option<u32>) {
-    let song = song::load(song_path).unwrap_or_else(|e| {
-        eprintln!("Song error: {}", e);
-        std::process::exit(1);
-    });
-
-    let tempo = tempo_override.unwrap_or(song.tempo);
-
-    let mut adsrs = Vec::with_capacity(song.tracks.len());
-    for track in &song.tracks {
-        let adsr = instrument::load(&track.instrument_path)
-            .unwrap_or_else(|e| {
-                eprintln!(
-                    "Instrument error {}: {}",
-                    track.instrument_path.display(),
-                    e
-                );
-                std::process::exit(1);
-            })
-            .to_adsr();
-        adsrs.push(adsr);
+fn check_click_volume(volume: f64) -> Result<(), ClidawError> {
+    if !(0.0..=1.0).contains(&volume) {
+        return Err(ClidawError::Usage("--click-volume must be between 0 and 1".to_string()));
     }
+    Ok(())
+}
 
-    let mut patterns: HashMap<std::path::PathBuf, note::Pattern> = HashMap::new();
-    for track in &song.tracks {
-        for seg in &track.sequence {
-            if !patterns.contains_key(&seg.notes_path) {
-                let content = fs::read_to_string(&seg.notes_path).unwrap_or_else(|e| {
-                    eprintln!("Error reading {}: {}", seg.notes_path.display(), e);
-                    std::process::exit(1);
-                });
-                let pattern = parser::parse_pattern(&content).unwrap_or_else(|e| {
-                    eprintln!("Parse error in {}: {}", seg.notes_path.display(), e);
-                    std::process::exit(1);
-                });
-                patterns.insert(seg.notes_path.clone(), pattern);
-            }
+/// `--master-volume` must not be negative; above 2 it is allowed with a warning
+fn check_master_volume(volume: Option<f64>) -> Result<(), ClidawError> {
+    match volume {
+        Some(v) if v.is_nan() || v < 0.0 => Err(ClidawError::Usage(
+            "--master-volume must be non-negative".to_string(),
+        )),
+        Some(v) if v > instrument::LOUD_GAIN => {
+            eprintln!(
+                "warning: --master-volume {} is above {} and will likely clip",
+                v,
+                instrument::LOUD_GAIN
+            );
+            Ok(())
         }
+        _ => Ok(()),
     }
+}
 
-    let schedule = scheduler::build_schedule(&song, &patterns).unwrap_or_else(|e| {
-        eprintln!("Schedule error: {}", e);
-        std::process::exit(1);
+/// Warn of the song's bus gains and `master_volume` above 2, as `check`
+/// does; a `--master-volume` replaces the latter and `check_master_volume`
+/// warns of it instead
+fn warn_loud_gains(song: &song::Song, master_volume: Option<f64>) {
+    for warning in song.loud_gains(master_volume.is_some()) {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+/// `live --backing`: the song at `path` (at `tempo`, if given) ready to
+/// play under the keyboard
+fn load_backing(
+    path: &Path,
+    tempo: Option<f64>,
+    looped: bool,
+) -> Result<repl::Backing, ClidawError> {
+    let loaded = load_file(path, None, tempo)?;
+    let compiled = match loaded.compiled {
+        Some(compiled) => compiled,
+        None => scheduler::compile(&loaded.song, &loaded.patterns)?,
+    };
+    warn_clamped(&compiled.clamped);
+    Ok(repl::Backing {
+        compiled,
+        tempo: loaded.tempo,
+        patches: loaded.patches,
+        mix: loaded.song.mix(),
+        looped,
+    })
+}
+
+/// Load `live --instrument`s, each named after its file stem or preset
+fn load_live_instruments(
+    specs: &[PathBuf],
+    tempo: f64,
+) -> Result<Vec<repl::LiveInstrument>, ClidawError> {
+    let mut instruments = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let source = song::InstrumentSource::from_patch(&spec.to_string_lossy());
+        let patch = source.load_checked(false)?.to_patch(tempo);
+        if patch.kit.is_some() {
+            return Err(ClidawError::Usage(format!(
+                "{} is a drum kit; live mode plays tonal instruments",
+                source
+            )));
+        }
+        let name = spec.file_stem().unwrap_or(spec.as_os_str());
+        instruments.push(repl::LiveInstrument {
+            name: name.to_string_lossy().into_owned(),
+            patch,
+        });
+    }
+    Ok(instruments)
+}
+
+/// Output settings from the CLI flags, rejecting impossible values
+fn output_options(
+    device: Option<String>,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+) -> Result<backend::OutputOptions, ClidawError> {
+    if sample_rate == Some(0) || buffer_size == Some(0) {
+        let msg = "--sample-rate and --buffer-size must be positive";
+        return Err(ClidawError::Usage(msg.to_string()));
+    }
+    Ok(backend::OutputOptions {
+        device,
+        sample_rate,
+        buffer_size,
+        default_buffer_size: None,
+    })
+}
+
+/// Build humanize settings from the CLI flags (None if neither flag was given).
+fn humanize_settings(
+    timing_ms: Option<f64>,
+    velocity: Option<f64>,
+    seed: Option<u64>,
+) -> Result<Option<scheduler::Humanize>, ClidawError> {
+    if timing_ms.is_none() && velocity.is_none() {
+        return Ok(None);
+    }
+    let timing_ms = timing_ms.unwrap_or(0.0);
+    let velocity = velocity.unwrap_or(0.0);
+    if timing_ms < 0.0 || !(0.0..=1.0).contains(&velocity) {
+        return Err(ClidawError::Usage(
+            "--humanize must be non-negative and --humanize-vel between 0 and 1".to_string(),
+        ));
+    }
+    let seed = seed.unwrap_or_else(|| {
+        let seed = rng::Rng::from_time().next_u64();
+        println!("Humanize seed: {} (pass --seed to reproduce)", seed);
+        seed
     });
+    Ok(Some(scheduler::Humanize {
+        timing_ms,
+        velocity,
+        seed,
+    }))
+}
 
-    println!(
-        "Playing song: {} BPM, {}/{} time, {} tracks, {} scheduled events",
+/// Build quantize settings from the CLI flags (None without --quantize).
+fn quantize_settings(
+    grid: Option<f64>,
+    strength: f64,
+) -> Result<Option<scheduler::Quantize>, ClidawError> {
+    let Some(grid) = grid else {
+        return Ok(None);
+    };
+    if grid <= 0.0 || !(0.0..=1.0).contains(&strength) {
+        return Err(ClidawError::Usage(
+            "--quantize must be positive and --quantize-strength between 0 and 1".to_string(),
+        ));
+    }
+    Ok(Some(scheduler::Quantize { grid, strength }))
+}
+
+/// A .song with its instruments and patterns loaded, ready to play
+struct LoadedSong {
+    song: song::Song,
+    tempo: f64,
+    patches: Vec<synth::Patch>,
+    patterns: HashMap<PathBuf, note::Pattern>,
+    /// The song's notes from `play --cache` (None: schedule `patterns`)
+    compiled: Option<scheduler::Compiled>,
+    /// Every .notes file a .song's patterns were read from, includes and all
+    notes_files: Vec<PathBuf>,
+    /// Segments played without their pattern file (see `song::Missing`)
+    skipped: Vec<song::SkippedSegment>,
+}
+
+impl LoadedSong {
+    /// The song file and every instrument and pattern file it uses
+    fn files(&self, song_path: &Path) -> Vec<PathBuf> {
+        let mut files = vec![song_path.to_path_buf()];
+        files.extend(self.instrument_files());
+        files.extend(self.notes_files.iter().cloned());
+        // Watch for the missing ones to turn up
+        files.extend(self.skipped.iter().map(|s| s.notes_path.clone()));
+        files
+    }
+
+    /// The `.instr` files the tracks use
+    fn instrument_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        for track in &self.song.tracks {
+            if let song::InstrumentSource::File(path) = &track.instrument
+                && !files.contains(path)
+            {
+                files.push(path.clone());
+            }
+        }
+        files
+    }
+}
+
+fn load_song(song_path: &Path, options: &PlayOptions) -> Result<LoadedSong, ClidawError> {
+    let mut song = song::load(song_path)?
+        .select_tracks(&options.solo, &options.mute)
+        .map_err(ClidawError::Usage)?;
+    let skipped = if options.allow_missing || song.missing == song::Missing::Skip {
+        song.skip_missing()
+    } else {
+        Vec::new()
+    };
+    for skip in &skipped {
+        eprintln!("WARNING: {}", skip);
+    }
+
+    let tempo = options.tempo.unwrap_or(song.tempo);
+
+    let mut patches = Vec::with_capacity(song.tracks.len());
+    for track in &song.tracks {
+        let mut patch = track.instrument.load_checked(options.strict)?.to_patch(tempo);
+        patch.output_channel = track.output_channel.map(usize::from);
+        patch.bus = track.bus;
+        patch.reverb_send = track.reverb_send;
+        patch.effects = track.effects.clone();
+        patches.push(patch);
+    }
+
+    let params = cache::Params {
+        solo: options.solo.clone(),
+        mute: options.mute.clone(),
+        strict: options.strict,
+    };
+    if options.cache
+        && skipped.is_empty()
+        && let Some(hit) = cache::load(song_path, &params)
+    {
+        println!("Using the cached schedule in {}", cache::path_for(song_path).display());
+        return Ok(LoadedSong {
+            song,
+            tempo,
+            patches,
+            patterns: HashMap::new(),
+            compiled: Some(hit.compiled),
+            notes_files: hit.files.into_iter().filter(|f| f != song_path).collect(),
+            skipped,
+        });
+    }
+
+    let mut patterns: HashMap<PathBuf, note::Pattern> = HashMap::new();
+    let mut notes_files: Vec<PathBuf> = Vec::new();
+    for track in &song.tracks {
+        for seg in &track.sequence {
+            if !patterns.contains_key(&seg.notes_path) && !skipped_path(&skipped, seg) {
+                let content = fs::read_to_string(&seg.notes_path)
+                    .map_err(|e| ClidawError::io(&seg.notes_path, e))?;
+                let mut parse_options =
+                    parser::ParseOptions::for_file(&seg.notes_path, options.strict);
+                parse_options.prefer_flats = song.prefer_flats;
+                let comp = parser::parse_with(&content, parse_options)?;
+                notes_files.push(seg.notes_path.clone());
+                for include in comp.tracks.iter().flat_map(|t| &t.includes) {
+                    if !notes_files.contains(&include.file) {
+                        notes_files.push(include.file.clone());
+                    }
+                }
+                patterns.insert(seg.notes_path.clone(), comp.into_pattern());
+            }
+        }
+    }
+
+    let generative = patterns.values().any(|p| note::is_generative(&p.events));
+    if options.cache && generative {
+        println!("Not caching the schedule: its patterns change from one play to the next");
+    }
+    // A schedule with holes in it isn't worth keeping
+    let compiled = if options.cache && skipped.is_empty() && !generative {
+        let compiled = scheduler::compile(&song, &patterns)?;
+        let mut files = vec![song_path.to_path_buf()];
+        files.extend(notes_files.iter().cloned());
+        if let Err(e) = cache::store(song_path, &params, &files, &compiled) {
+            eprintln!("warning: couldn't cache the schedule: {}", e);
+        }
+        Some(compiled)
+    } else {
+        None
+    };
+
+    Ok(LoadedSong {
+        song,
+        tempo,
+        patches,
+        patterns,
+        compiled,
+        notes_files,
+        skipped,
+    })
+}
+
+/// The seed for a song's chance notes and random alternatives: `seed` if
+/// given, else one from the clock, printed (to stderr, clear of
+/// `schedule`'s output) so the run can be repeated, when the song has any
+fn pattern_seed(seed: Option<u64>, generative: bool) -> u64 {
+    match seed {
+        Some(seed) => seed,
+        None if generative => {
+            let seed = rng::Rng::from_time().next_u64();
+            eprintln!("Pattern seed: {} (pass --seed to reproduce)", seed);
+            seed
+        }
+        None => 0,
+    }
+}
+
+/// Seed a loaded song's patterns with `pattern_seed`
+fn seed_patterns(mut loaded: LoadedSong, seed: Option<u64>) -> LoadedSong {
+    let generative = loaded.patterns.values().any(|p| note::is_generative(&p.events));
+    loaded.song.seed = pattern_seed(seed, generative);
+    loaded
+}
+
+/// Whether `seg` is one of the segments playing without its file
+fn skipped_path(skipped: &[song::SkippedSegment], seg: &song::Segment) -> bool {
+    skipped.iter().any(|s| s.notes_path == seg.notes_path)
+}
+
+/// The segments played without their pattern files, listed once more at
+/// the end so the warnings aren't lost above the playback output
+fn print_skipped(out: &mut impl Write, skipped: &[song::SkippedSegment]) -> io::Result<()> {
+    if skipped.is_empty() {
+        return Ok(());
+    }
+    let plural = if skipped.len() == 1 { "" } else { "s" };
+    writeln!(out, "{} segment{} played without a pattern file:", skipped.len(), plural)?;
+    for skip in skipped {
+        writeln!(out, "  {}", skip)?;
+    }
+    Ok(())
+}
+
+fn play_song(song_path: &Path, options: &PlayOptions) -> Result<(), ClidawError> {
+    if options.watch {
+        let path = song_path.to_path_buf();
+        let load_options = options.clone();
+        watch::run(
+            move || {
+                let loaded = seed_patterns(load_song(&path, &load_options)?, load_options.seed);
+                let files = loaded.files(&path);
+                Ok((loaded, files))
+            },
+            |loaded, stop| play_loaded_song(loaded, options, Some(stop)),
+        )
+    } else {
+        load_song(song_path, options)
+            .map(|loaded| seed_patterns(loaded, options.seed))
+            .and_then(|loaded| play_loaded_song(&loaded, options, None))
+    }
+}
+
+fn play_loaded_song(
+    loaded: &LoadedSong,
+    options: &PlayOptions,
+    stop: Option<&AtomicBool>,
+) -> Result<(), ClidawError> {
+    let LoadedSong {
+        song,
+        tempo,
+        patterns,
+        ..
+    } = loaded;
+    let tempo = *tempo;
+
+    let schedule_options = scheduler::ScheduleOptions {
+        humanize: options.humanize.clone(),
+        quantize: options.quantize.clone(),
+        fade_in: options.fade_in.unwrap_or(song.fade_in),
+        fade_out: options.fade_out.unwrap_or(song.fade_out),
+        metronome: options.metronome.clone(),
+        start: options.start,
+        end: options.end,
+        looped: options.looped,
+    };
+    let stream = match &loaded.compiled {
+        Some(compiled) => scheduler::stream_compiled(compiled, tempo, &schedule_options)?,
+        None => scheduler::stream(song, patterns, tempo, &schedule_options)?,
+    };
+    warn_clamped(&stream.clamped);
+
+    writeln!(
+        io::stdout().lock(),
+        "{} song: {} BPM, {}/{} time, {} tracks\n",
+        if options.dry_run { "Loaded" } else { "Playing" },
         tempo,
         song.time_signature.0,
         song.time_signature.1,
-        song.tracks.len(),
-        schedule.len()
-    );
-    println!();
+        song.tracks.len()
+    )
+    .map_err(stdout_error)?;
+    play_stream(loaded, stream, options, stop)
+}
 
-    let engine = synth::AudioEngine::with_instruments(adsrs).unwrap_or_else(|e| {
-        eprintln!("Audio error: {}", e);
-        std::process::exit(1);
-    });
+/// Open the audio device and play a scheduled song or pattern
+fn play_stream(
+    loaded: &LoadedSong,
+    stream: scheduler::SongStream<'_>,
+    options: &PlayOptions,
+    stop: Option<&AtomicBool>,
+) -> Result<(), ClidawError> {
+    let LoadedSong {
+        song,
+        tempo,
+        patches,
+        ..
+    } = loaded;
+    warn_loud_gains(song, options.master_volume);
+    let mut mix = song.mix();
+    if let Some(volume) = options.master_volume {
+        mix.master_volume = volume;
+    }
+    let ring_out = synth::ring_out_secs(patches, &mix);
+    if options.dry_run {
+        let mut out = io::stdout().lock();
+        return print_dry_run(&mut out, loaded, stream, ring_out).map_err(stdout_error);
+    }
+    let engine = synth::AudioEngine::new(patches.clone(), &mix, &options.output)?;
+    if options.no_limiter {
+        engine.send(synth::LiveCommand::SetLimiter(false))?;
+    }
+
+    let progress = synth::Progress::of(&stream);
+    let progress = (!options.quiet).then_some(&progress);
+    let result = synth::play_schedule(stream.events, *tempo, ring_out, &engine, progress, stop);
+    let skipped = print_skipped(&mut io::stdout().lock(), &loaded.skipped);
+    result.and(skipped.map_err(stdout_error))
+}
+
+/// `play --dry-run`: what the schedule would play, without an audio device
+fn print_dry_run(
+    out: &mut impl Write,
+    loaded: &LoadedSong,
+    stream: scheduler::SongStream<'_>,
+    ring_out: f64,
+) -> io::Result<()> {
+    let tracks = &loaded.song.tracks;
+    // A dry run never loops, so the stream has an end
+    let end_beat = stream.end_beat.unwrap_or_default();
+    let summary = scheduler::summarize(stream.events, tracks.len());
+    writeln!(
+        out,
+        "Dry run: {} beats, {} at {} BPM (+{:.1}s ring-out)",
+        end_beat,
+        synth::format_secs(scheduler::beats_to_secs(end_beat, loaded.tempo)),
+        loaded.tempo,
+        ring_out
+    )?;
+    for (idx, count) in summary.events_per_track.iter().enumerate() {
+        let name = tracks.get(idx).map_or("", |t| t.name.as_str());
+        let plural = if *count == 1 { "" } else { "s" };
+        writeln!(out, "  Track {} ({}): {} event{}", idx, name, count, plural)?;
+    }
+    match summary.peak_voices {
+        0 => writeln!(out, "  Peak voices: 0")?,
+        n => writeln!(out, "  Peak voices: {} (first at beat {})", n, summary.peak_beat)?,
+    }
+    print_skipped(out, &loaded.skipped)
+}
+
+/// `clidaw check`'s diagnostics, track lengths and totals
+fn print_report(out: &mut impl Write, file: &Path, report: &check::Report) -> io::Result<()> {
+    for diagnostic in &report.diagnostics {
+        writeln!(out, "{}", diagnostic)?;
+    }
+    if !report.track_lengths.is_empty() {
+        writeln!(out, "Track lengths (align: {}):", report.align.name())?;
+        for (idx, track) in report.track_lengths.iter().enumerate() {
+            match track.beats {
+                Some(beats) => writeln!(out, "  {}: {} - {} beats", idx, track.name, beats)?,
+                None => writeln!(out, "  {}: {} - unknown", idx, track.name)?,
+            }
+        }
+    }
+    writeln!(
+        out,
+        "{}: {} error(s), {} warning(s)",
+        file.display(),
+        report.error_count(),
+        report.warning_count()
+    )
+}
+
+/// `clidaw info`: metadata, then what the file plays
+fn print_info(out: &mut impl Write, path: &Path) -> Result<(), ClidawError> {
+    let loaded = load_file(path, None, None)?;
+    let song = &loaded.song;
+    for (key, value) in song.metadata.entries() {
+        let mut lines = value.lines();
+        let label = format!("{}{}", key[..1].to_uppercase(), &key[1..]);
+        let first = lines.next().unwrap_or_default();
+        writeln!(out, "{}: {}", label, first).map_err(stdout_error)?;
+        for line in lines {
+            writeln!(out, "  {}", line).map_err(stdout_error)?;
+        }
+    }
+    let stats = scheduler::song_stats(song, &loaded.patterns)?;
+    let (num, den) = song.time_signature;
+    writeln!(out, "Tempo: {} BPM, {}/{}", loaded.tempo, num, den).map_err(stdout_error)?;
+    writeln!(
+        out,
+        "Length: {} beats, {} bar{}, {}",
+        stats.length,
+        stats.bars,
+        if stats.bars == 1 { "" } else { "s" },
+        synth::format_secs(scheduler::beats_to_secs(stats.length, loaded.tempo))
+    )
+    .map_err(stdout_error)?;
+    for (idx, track) in song.tracks.iter().enumerate() {
+        let count = stats.summary.events_per_track[idx];
+        // Tracks with no pitched notes only play drum hits
+        let (kind, range) = match stats.summary.range_per_track[idx] {
+            Some((low, high)) => {
+                let spelling = if song.prefer_flats {
+                    note::Spelling::Flat
+                } else {
+                    note::Spelling::Sharp
+                };
+                let names = (note::midi_name(low, spelling), note::midi_name(high, spelling));
+                ("note", format!(", {}-{}", names.0, names.1))
+            }
+            None if count > 0 => ("hit", String::new()),
+            None => ("note", String::new()),
+        };
+        let plural = if count == 1 { "" } else { "s" };
+        let summary = track.instrument.load()?.summary();
+        writeln!(out, "  Track {} ({}): {} {}{}{}", idx, track.name, count, kind, plural, range)
+            .and_then(|()| writeln!(out, "    {}: {}", track.instrument, summary))
+            .map_err(stdout_error)?;
+    }
+    print_skipped(out, &loaded.skipped).map_err(stdout_error)
+}
+
+/// Load a .notes file as a song with one track per `[track:]` section, so
+/// its tracks play in parallel like a .song's. Each track plays its `patch:`
+/// (or the file's); `instrument_path` stands in for the file-wide patch.
+fn load_notes(
+    path: &Path,
+    instrument_path: Option<&Path>,
+    tempo_override: Option<f64>,
+    strict: bool,
+    json: bool,
+) -> Result<LoadedSong, ClidawError> {
+    let input = read_input(path)?;
+    let name = input_name(path);
+    let comp = if json {
+        composition_from_json(name, &input)?
+    } else {
+        parser::parse_with(&input, parser::ParseOptions::for_file(name, strict))?
+    };
+    let tempo = tempo_override.unwrap_or(comp.tempo);
+    let stem = name
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
 
-    if let Err(e) = synth::play_schedule(&schedule, tempo, &engine) {
-        eprintln!("Playback error: {}", e);
-        std::process::exit(1);
+    let mut tracks = Vec::with_capacity(comp.tracks.len());
+    let mut patches = Vec::with_capacity(comp.tracks.len());
+    let mut patterns = HashMap::new();
+    for (idx, track) in comp.tracks.iter().enumerate() {
+        let instrument = match (track.patch.as_deref(), instrument_path) {
+            (Some(patch), _) => song::InstrumentSource::from_patch(patch),
+            (None, Some(instr)) => song::InstrumentSource::File(instr.to_path_buf()),
+            (None, None) => match comp.default_patch.as_deref() {
+                Some(patch) => song::InstrumentSource::from_patch(patch),
+                None => song::InstrumentSource::Preset("default".to_string()),
+            },
+        };
+        patches.push(instrument.load_checked(strict)?.to_patch(tempo));
+        // Each track's pattern needs its own key; a lone track uses the file's
+        let notes_path = if comp.tracks.len() == 1 {
+            path.to_path_buf()
+        } else {
+            PathBuf::from(format!("{}#{}", path.display(), idx))
+        };
+        patterns.insert(notes_path.clone(), comp.track_pattern(track));
+        tracks.push(song::SongTrack {
+            name: if comp.has_tracks() { track.name.clone() } else { stem.clone() },
+            instrument,
+            sequence: vec![song::Segment {
+                notes_path,
+                times: 1,
+                transpose: 0,
+                slot: None,
+                length: None,
+            }],
+            output_channel: None,
+            bus: None,
+            reverb_send: 1.0,
+            meter_changes: Vec::new(),
+            offset: 0.0,
+            effects: Vec::new(),
+        });
+    }
+    if tracks.is_empty() {
+        return Err(ClidawError::Parse {
+            file: Some(name.to_path_buf()),
+            line: 1,
+            col: None,
+            msg: "the file has no notes".to_string(),
+        });
+    }
+
+    let song = song::Song {
+        tempo,
+        time_signature: comp.time_signature,
+        transpose: 0,
+        fade_in: 0.0,
+        fade_out: 0.0,
+        align: song::Align::Pad,
+        tracks,
+        buses: Vec::new(),
+        reverb: effects::Reverb::default(),
+        master_volume: 1.0,
+        automation: Vec::new(),
+        sections: Vec::new(),
+        arrangement: None,
+        missing: song::Missing::Error,
+        prefer_flats: comp.prefer_flats,
+        metadata: comp.metadata.clone(),
+        seed: 0,
+    };
+    Ok(LoadedSong {
+        song,
+        tempo,
+        patches,
+        patterns,
+        compiled: None,
+        notes_files: Vec::new(),
+        skipped: Vec::new(),
+    })
+}
+
+/// File argument that stands for standard input
+const STDIN_ARG: &str = "-";
+
+/// What `path` holds, or what was piped in if it is `-`
+fn read_input(path: &Path) -> Result<String, ClidawError> {
+    if path != Path::new(STDIN_ARG) {
+        return fs::read_to_string(path).map_err(|e| ClidawError::io(path, e));
+    }
+    let mut stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Err(ClidawError::Usage(
+            "- reads from stdin, but nothing is piped in (e.g. `cat riff.notes | clidaw play -`)"
+                .to_string(),
+        ));
+    }
+    let mut input = String::new();
+    stdin.read_to_string(&mut input).map_err(|e| ClidawError::io(input_name(path), e))?;
+    Ok(input)
+}
+
+/// How `path` is named in messages: `<stdin>` for `-`
+fn input_name(path: &Path) -> &Path {
+    if path == Path::new(STDIN_ARG) {
+        Path::new("<stdin>")
+    } else {
+        path
+    }
+}
+
+/// A pattern or composition in `clidaw parse --format json`'s shape
+fn composition_from_json(name: &Path, input: &str) -> Result<note::Composition, ClidawError> {
+    serde_json::from_str(input).map_err(|e| {
+        let message = e.to_string();
+        // serde_json ends its messages with where they happened, and has
+        // no position for a problem with the whole document
+        let message = message.rsplit_once(" at line ").map_or(&*message, |(msg, _)| msg);
+        ClidawError::Parse {
+            file: Some(name.to_path_buf()),
+            line: e.line().max(1),
+            col: (e.column() > 0).then_some(e.column()),
+            msg: message.to_string(),
+        }
+    })
+}
+
+fn is_midi(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("mid") || e.eq_ignore_ascii_case("midi"))
+}
+
+/// Load a .notes file, or a MIDI file converted on the fly
+fn load_pattern_file(
+    path: &Path,
+    instrument_path: Option<&Path>,
+    tempo_override: Option<f64>,
+    strict: bool,
+    json: bool,
+) -> Result<LoadedSong, ClidawError> {
+    if is_midi(path) && !json {
+        if instrument_path.is_some() {
+            return Err(ClidawError::Usage("--instrument only applies to .notes files".to_string()));
+        }
+        load_midi(path, tempo_override)
+    } else {
+        load_notes(path, instrument_path, tempo_override, strict, json)
+    }
+}
+
+/// Load a MIDI file as a song, converted as `clidaw import` would on the
+/// default grid
+fn load_midi(path: &Path, tempo_override: Option<f64>) -> Result<LoadedSong, ClidawError> {
+    let steps = import::steps_per_beat(import::DEFAULT_GRID).expect("default grid is valid");
+    let converted = import::convert(&midi::load(path)?, steps);
+    if let Some(summary) = converted.skipped_summary() {
+        eprintln!("warning: skipped MIDI events: {}", summary);
+    }
+    let tempo = tempo_override.unwrap_or(converted.tempo);
+
+    let mut tracks = Vec::with_capacity(converted.tracks.len());
+    let mut patches = Vec::with_capacity(converted.tracks.len());
+    let mut patterns = HashMap::new();
+    for track in &converted.tracks {
+        let preset = if track.drums { "kit" } else { "default" };
+        let instrument = song::InstrumentSource::Preset(preset.to_string());
+        patches.push(instrument.load()?.to_patch(tempo));
+        let notes_path = PathBuf::from(format!("{}#{}", path.display(), track.name));
+        let pattern = parser::parse_pattern(&track.notes)?;
+        patterns.insert(notes_path.clone(), pattern);
+        tracks.push(song::SongTrack {
+            name: track.name.clone(),
+            instrument,
+            sequence: vec![song::Segment {
+                notes_path,
+                times: 1,
+                transpose: 0,
+                slot: None,
+                length: None,
+            }],
+            output_channel: None,
+            bus: None,
+            reverb_send: 1.0,
+            meter_changes: Vec::new(),
+            offset: 0.0,
+            effects: Vec::new(),
+        });
+    }
+    if tracks.is_empty() {
+        return Err(ClidawError::Midi {
+            file: path.to_path_buf(),
+            msg: "the file has no notes".to_string(),
+        });
     }
+
+    let song = song::Song {
+        tempo,
+        time_signature: converted.time_signature,
+        transpose: 0,
+        fade_in: 0.0,
+        fade_out: 0.0,
+        align: song::Align::Pad,
+        tracks,
+        buses: Vec::new(),
+        reverb: effects::Reverb::default(),
+        master_volume: 1.0,
+        automation: Vec::new(),
+        sections: Vec::new(),
+        arrangement: None,
+        missing: song::Missing::Error,
+        prefer_flats: false,
+        metadata: note::Metadata::default(),
+        seed: 0,
+    };
+    Ok(LoadedSong {
+        song,
+        tempo,
+        patches,
+        patterns,
+        compiled: None,
+        notes_files: Vec::new(),
+        skipped: Vec::new(),
+    })
 }
 
 fn play_notes_file(
-    path: &PathBuf,
+    path: &Path,
     instrument_override: Option<PathBuf>,
-    tempo_override: Option<u32>,
-) {
-    let input = read_file(path);
-    let pattern = parser::parse_pattern(&input).unwrap_or_else(|e| {
-        eprintln!("Parse error: {}", e);
-        std::process::exit(1);
-    });
+    options: &PlayOptions,
+) -> Result<(), ClidawError> {
+    if options.watch {
+        let path = path.to_path_buf();
+        let tempo = options.tempo;
+        let (strict, json, seed) = (options.strict, options.json, options.seed);
+        watch::run(
+            move || {
+                let instrument = instrument_override.as_deref();
+                let loaded = load_pattern_file(&path, instrument, tempo, strict, json)?;
+                let loaded = seed_patterns(loaded, seed);
+                let mut files = vec![path.clone()];
+                files.extend(loaded.instrument_files());
+                Ok((loaded, files))
+            },
+            |loaded, stop| play_loaded_notes(loaded, options, Some(stop)),
+        )
+    } else {
+        let instrument = instrument_override.as_deref();
+        load_pattern_file(path, instrument, options.tempo, options.strict, options.json)
+            .map(|loaded| seed_patterns(loaded, options.seed))
+            .and_then(|loaded| play_loaded_notes(&loaded, options, None))
+    }
+}
+
+fn play_loaded_notes(
+    loaded: &LoadedSong,
+    options: &PlayOptions,
+    stop: Option<&AtomicBool>,
+) -> Result<(), ClidawError> {
+    let schedule_options = scheduler::ScheduleOptions {
+        quantize: options.quantize.clone(),
+        start: options.start,
+        end: options.end,
+        looped: options.looped,
+        ..scheduler::ScheduleOptions::default()
+    };
+    let stream =
+        scheduler::stream(&loaded.song, &loaded.patterns, loaded.tempo, &schedule_options)?;
+    warn_clamped(&stream.clamped);
+    let beats = scheduler::track_lengths(&loaded.song, &loaded.patterns)?
+        .into_iter()
+        .max()
+        .unwrap_or_default();
+
+    let loop_pattern = loaded.patterns.values().any(|p| p.loop_pattern);
+    let action = if options.dry_run { "Loaded" } else { "Playing" };
+    let header = match loaded.song.tracks.len() {
+        1 => format!(
+            "{} pattern: {} beats, loop={}, {} BPM",
+            action, beats, loop_pattern, loaded.tempo
+        ),
+        n => format!(
+            "{} pattern: {} beats, loop={}, {} BPM, {} tracks",
+            action, beats, loop_pattern, loaded.tempo, n
+        ),
+    };
+    writeln!(io::stdout().lock(), "{}\n", header).map_err(stdout_error)?;
+    play_stream(loaded, stream, options, stop)
+}
+
+/// `audition`: play the built-in test phrase through one instrument
+fn audition(spec: &Path, tempo: f64, octave: u8, options: &PlayOptions) -> Result<(), ClidawError> {
+    if options.watch {
+        let spec = spec.to_path_buf();
+        watch::run(
+            move || {
+                let loaded = load_audition(&spec, tempo, octave)?;
+                let files = loaded.instrument_files();
+                Ok((loaded, files))
+            },
+            |loaded, stop| play_loaded_notes(loaded, options, Some(stop)),
+        )
+    } else {
+        load_audition(spec, tempo, octave)
+            .and_then(|loaded| play_loaded_notes(&loaded, options, None))
+    }
+}
+
+/// A one-track song playing `Pattern::audition` on the instrument `spec`
+/// (a .instr file or built-in preset name)
+fn load_audition(spec: &Path, tempo: f64, octave: u8) -> Result<LoadedSong, ClidawError> {
+    let instrument = song::InstrumentSource::from_patch(&spec.to_string_lossy());
+    let patch = instrument.load_checked(false)?.to_patch(tempo);
+    if patch.kit.is_some() {
+        return Err(ClidawError::Usage(format!(
+            "{} is a drum kit; audition plays tonal instruments",
+            instrument
+        )));
+    }
+    let pattern = note::Pattern::audition(octave);
+    let notes_path = PathBuf::from("audition");
+    let name = spec.file_stem().unwrap_or(spec.as_os_str());
+    let track = song::SongTrack {
+        name: name.to_string_lossy().into_owned(),
+        instrument,
+        sequence: vec![song::Segment {
+            notes_path: notes_path.clone(),
+            times: 1,
+            transpose: 0,
+            slot: None,
+            length: None,
+        }],
+        output_channel: None,
+        bus: None,
+        reverb_send: 1.0,
+        meter_changes: Vec::new(),
+        offset: 0.0,
+        effects: Vec::new(),
+    };
+    let song = song::Song {
+        tempo,
+        time_signature: pattern.time_signature,
+        transpose: 0,
+        fade_in: 0.0,
+        fade_out: 0.0,
+        align: song::Align::Pad,
+        tracks: vec![track],
+        buses: Vec::new(),
+        reverb: effects::Reverb::default(),
+        master_volume: 1.0,
+        automation: Vec::new(),
+        sections: Vec::new(),
+        arrangement: None,
+        missing: song::Missing::Error,
+        prefer_flats: false,
+        metadata: note::Metadata::default(),
+        seed: 0,
+    };
+    Ok(LoadedSong {
+        song,
+        tempo,
+        patches: vec![patch],
+        patterns: HashMap::from([(notes_path, pattern)]),
+        compiled: None,
+        notes_files: Vec::new(),
+        skipped: Vec::new(),
+    })
+}
+
+/// Output settings for `render`
+struct RenderSettings {
+    format: Option<render::AudioFormat>,
+    bit_depth: render::BitDepth,
+    sample_rate: u32,
+    tempo: Option<f64>,
+    master_volume: Option<f64>,
+    no_limiter: bool,
+    seed: Option<u64>,
+}
+
+/// Load a .song, or a .notes file as a song, with default options
+fn load_file(
+    path: &Path,
+    instrument_path: Option<&Path>,
+    tempo: Option<f64>,
+) -> Result<LoadedSong, ClidawError> {
+    if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("song")) {
+        if instrument_path.is_some() {
+            return Err(ClidawError::Usage("--instrument only applies to .notes files".to_string()));
+        }
+        let options = PlayOptions {
+            tempo,
+            ..PlayOptions::default()
+        };
+        load_song(path, &options)
+    } else {
+        load_pattern_file(path, instrument_path, tempo, false, false)
+    }
+}
+
+fn render_file(
+    path: &Path,
+    output: &Path,
+    instrument_path: Option<&Path>,
+    settings: &RenderSettings,
+) -> Result<(), ClidawError> {
+    let format = match settings.format {
+        Some(format) => format,
+        None => render::AudioFormat::from_path(output).ok_or_else(|| {
+            ClidawError::Usage(format!(
+                "Can't tell the format of {} from its extension; pass --format wav or flac",
+                output.display()
+            ))
+        })?,
+    };
+    check_render_settings(format, settings)?;
+
+    let loaded = seed_patterns(load_file(path, instrument_path, settings.tempo)?, settings.seed);
+    let samples = render_samples(&loaded, settings)?;
+    let bytes = render::encode(&samples, settings.sample_rate, format, settings.bit_depth)
+        .map_err(ClidawError::Usage)?;
+    render::write_atomic(output, &bytes)?;
 
-    let tempo = tempo_override.unwrap_or(120);
+    let secs = samples.len() as f64 / render::CHANNELS as f64 / settings.sample_rate as f64;
+    println!(
+        "Rendered {} ({:.1}s, {} Hz) to {}",
+        path.display(),
+        secs,
+        settings.sample_rate,
+        output.display()
+    );
+    Ok(())
+}
+
+/// Fail early on a format and bit depth that can't go together, or an
+/// unsupported sample rate
+fn check_render_settings(
+    format: render::AudioFormat,
+    settings: &RenderSettings,
+) -> Result<(), ClidawError> {
+    render::check_format(format, settings.bit_depth).map_err(ClidawError::Usage)?;
+    if !render::SAMPLE_RATES.contains(&settings.sample_rate) {
+        return Err(ClidawError::Usage("--sample-rate must be 44100 or 48000".to_string()));
+    }
+    Ok(())
+}
+
+/// `clidaw render --stems`: each track rendered alone into `out_dir` as
+/// `01-<track name>.wav` and so on (or `.flac` with `--format flac`)
+fn render_stem_files(
+    path: &Path,
+    out_dir: &Path,
+    instrument_path: Option<&Path>,
+    settings: &RenderSettings,
+) -> Result<(), ClidawError> {
+    let format = settings.format.unwrap_or(render::AudioFormat::Wav);
+    check_render_settings(format, settings)?;
 
+    let loaded = seed_patterns(load_file(path, instrument_path, settings.tempo)?, settings.seed);
+    let stems = render_stem_samples(&loaded, settings)?;
+    fs::create_dir_all(out_dir).map_err(|e| ClidawError::io(out_dir, e))?;
+    for (i, (track, samples)) in loaded.song.tracks.iter().zip(&stems).enumerate() {
+        let name = match import::file_name(&track.name) {
+            name if name.is_empty() => "track".to_string(),
+            name => name,
+        };
+        let stem_path = out_dir.join(format!("{:02}-{}.{}", i + 1, name, format.extension()));
+        let bytes = render::encode(samples, settings.sample_rate, format, settings.bit_depth)
+            .map_err(ClidawError::Usage)?;
+        render::write_atomic(&stem_path, &bytes)?;
+    }
+
+    let frames = stems.first().map_or(0, |s| s.len() / render::CHANNELS as usize);
     println!(
-        "Playing pattern: {} beats, loop={}, {} BPM",
-        pattern.length_beats(),
-        pattern.loop_pattern,
-        tempo
+        "Rendered {} stem{} of {} ({:.1}s, {} Hz) to {}",
+        stems.len(),
+        if stems.len() == 1 { "" } else { "s" },
+        path.display(),
+        frames as f64 / settings.sample_rate as f64,
+        settings.sample_rate,
+        out_dir.display()
     );
-    println!();
+    Ok(())
+}
 
-    let result = if let Some(instr_path) = instrument_override {
-        let instr = instrument::load(&instr_path).unwrap_or_else(|e| {
-            eprintln!("Instrument error: {}", e);
-            std::process::exit(1);
-        });
-        let engine = synth::AudioEngine::with_adsr(instr.to_adsr()).unwrap_or_else(|e| {
-            eprintln!("Audio error: {}", e);
-            std::process::exit(1);
+/// The song's mix, with `--master-volume` applied
+fn render_mix(loaded: &LoadedSong, settings: &RenderSettings) -> synth::Mix {
+    warn_loud_gains(&loaded.song, settings.master_volume);
+    let mut mix = loaded.song.mix();
+    if let Some(volume) = settings.master_volume {
+        mix.master_volume = volume;
+    }
+    mix
+}
+
+/// What `render_samples` renders, one buffer per track (see `render::render_stems`)
+fn render_stem_samples(
+    loaded: &LoadedSong,
+    settings: &RenderSettings,
+) -> Result<Vec<Vec<f32>>, ClidawError> {
+    let schedule_options = scheduler::ScheduleOptions {
+        fade_in: loaded.song.fade_in,
+        fade_out: loaded.song.fade_out,
+        ..scheduler::ScheduleOptions::default()
+    };
+    let stream =
+        scheduler::stream(&loaded.song, &loaded.patterns, loaded.tempo, &schedule_options)?;
+    warn_clamped(&stream.clamped);
+    let events: Vec<scheduler::ScheduledEvent> = stream.events.collect();
+    let mix = render_mix(loaded, settings);
+    let ring_out = synth::ring_out_secs(&loaded.patches, &mix);
+    render::render_stems(
+        &events,
+        &loaded.patches,
+        &mix,
+        loaded.tempo,
+        ring_out,
+        settings.sample_rate,
+        !settings.no_limiter,
+    )
+}
+
+/// A loaded file rendered offline, fades, reverb tail and all, as
+/// interleaved stereo samples
+fn render_samples(loaded: &LoadedSong, settings: &RenderSettings) -> Result<Vec<f32>, ClidawError> {
+    let schedule_options = scheduler::ScheduleOptions {
+        fade_in: loaded.song.fade_in,
+        fade_out: loaded.song.fade_out,
+        ..scheduler::ScheduleOptions::default()
+    };
+    let stream =
+        scheduler::stream(&loaded.song, &loaded.patterns, loaded.tempo, &schedule_options)?;
+    warn_clamped(&stream.clamped);
+    let mix = render_mix(loaded, settings);
+    let ring_out = synth::ring_out_secs(&loaded.patches, &mix);
+    render::render(
+        stream.events,
+        &loaded.patches,
+        &mix,
+        loaded.tempo,
+        ring_out,
+        settings.sample_rate,
+        !settings.no_limiter,
+    )
+}
+
+/// `clidaw import`: write a MIDI file's tracks as .notes files and a .song
+/// named after it into `out_dir`
+fn import_midi(path: &Path, out_dir: &Path, grid: f64) -> Result<(), ClidawError> {
+    let steps = import::steps_per_beat(grid).ok_or_else(|| {
+        ClidawError::Usage(
+            "--grid must divide a beat into 1 to 16 equal steps (e.g. 0.25 or 0.5)".to_string(),
+        )
+    })?;
+    let converted = import::convert(&midi::load(path)?, steps);
+    if converted.tracks.is_empty() {
+        return Err(ClidawError::Midi {
+            file: path.to_path_buf(),
+            msg: "the file has no notes".to_string(),
         });
-        synth::play_pattern_with_engine(&pattern, tempo, &engine)
+    }
+    fs::create_dir_all(out_dir).map_err(|e| ClidawError::io(out_dir, e))?;
+    for track in &converted.tracks {
+        let notes_path = out_dir.join(format!("{}.notes", track.name));
+        fs::write(&notes_path, &track.notes).map_err(|e| ClidawError::io(&notes_path, e))?;
+    }
+    let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let song_path = out_dir.join(format!("{}.song", stem));
+    fs::write(&song_path, converted.song_text(&file_name))
+        .map_err(|e| ClidawError::io(&song_path, e))?;
+
+    println!(
+        "Imported {} track{} to {} (tempo {}, notes on a {}-beat grid)",
+        converted.tracks.len(),
+        if converted.tracks.len() == 1 { "" } else { "s" },
+        song_path.display(),
+        converted.tempo,
+        grid
+    );
+    if let Some(summary) = converted.skipped_summary() {
+        println!("Skipped: {}", summary);
+    }
+    Ok(())
+}
+
+/// The changes `clidaw transform` makes, in the order it makes them
+struct Transform {
+    transpose: Option<i32>,
+    /// MIDI note to invert around
+    invert_around: Option<u8>,
+    reverse: bool,
+    stretch: Option<f64>,
+}
+
+/// `clidaw transform`: `path`'s tracks inverted, transposed, reversed and
+/// stretched, written to `out` (or printed)
+fn transform_notes(
+    path: &Path,
+    transform: &Transform,
+    out: Option<&Path>,
+) -> Result<(), ClidawError> {
+    if transform.stretch.is_some_and(|factor| !(factor > 0.0 && factor.is_finite())) {
+        return Err(ClidawError::Usage("--stretch must be a number above 0".to_string()));
+    }
+    let input = fs::read_to_string(path).map_err(|e| ClidawError::io(path, e))?;
+    let mut comp = parser::parse_with(&input, parser::ParseOptions::for_file(path, false))?;
+    let mut clamped = 0;
+    for track in &mut comp.tracks {
+        if let Some(axis) = transform.invert_around {
+            clamped += note::invert(&mut track.events, axis);
+        }
+        if let Some(semitones) = transform.transpose {
+            clamped += note::transpose(&mut track.events, semitones);
+        }
+        if transform.reverse {
+            if !track.meter_changes.is_empty() {
+                return Err(ClidawError::Usage(format!(
+                    "can't reverse track '{}': it changes time signature",
+                    track.name
+                )));
+            }
+            note::reverse(&mut track.events);
+        }
+        if let Some(factor) = transform.stretch
+            && !note::stretch(&mut track.events, factor)
+        {
+            return Err(ClidawError::Usage(format!(
+                "can't stretch track '{}' by {}: drum steps can't be shortened",
+                track.name, factor
+            )));
+        }
+    }
+    if let Some(factor) = transform.stretch {
+        comp.beats = comp.beats.scale(factor);
+    }
+    if clamped > 0 {
+        eprintln!(
+            "warning: {} note{} left octaves 0-8 and moved back by whole octaves",
+            clamped,
+            if clamped == 1 { "" } else { "s" }
+        );
+    }
+
+    let Some(out) = out else {
+        let text = writer::composition_text(&comp);
+        return io::stdout().lock().write_all(text.as_bytes()).map_err(stdout_error);
+    };
+    write_notes_file(&comp, out)
+}
+
+/// Write `comp` as .notes text to `out`. Instrument paths were read
+/// relative to the file they came from; they are written relative to `out`.
+fn write_notes_file(comp: &note::Composition, out: &Path) -> Result<(), ClidawError> {
+    let mut comp = comp.clone();
+    let out_dir = out.parent().unwrap_or_else(|| Path::new(""));
+    let patches = comp
+        .tracks
+        .iter_mut()
+        .map(|track| &mut track.patch)
+        .chain([&mut comp.default_patch]);
+    for patch in patches.flatten() {
+        if parser::is_patch_file(patch) {
+            *patch = relative_patch(Path::new(patch), out_dir);
+        }
+    }
+    render::write_atomic(out, writer::composition_text(&comp).as_bytes())
+}
+
+/// How `clidaw seq` sets up its grid
+struct GridSettings {
+    /// A new file is a drum grid
+    drums: bool,
+    steps: Option<usize>,
+    steps_per_beat: usize,
+    /// BPM of the music (before scaling by `steps_per_beat`)
+    tempo: Option<f64>,
+    octave: u8,
+}
+
+/// `clidaw seq`: `path`'s pattern (or a new one) on a step grid, played by
+/// `instrument`, the file's patch or a preset
+fn edit_steps(
+    path: &Path,
+    settings: &GridSettings,
+    instrument: Option<&Path>,
+    output: backend::OutputOptions,
+) -> Result<(), ClidawError> {
+    let mut comp = if path.exists() {
+        let input = fs::read_to_string(path).map_err(|e| ClidawError::io(path, e))?;
+        parser::parse_with(&input, parser::ParseOptions::for_file(path, false))?
     } else {
-        synth::play_pattern(&pattern, tempo)
+        let mut comp = note::Composition::new();
+        let bar = (4 * settings.steps_per_beat).min(u8::MAX as usize) as u8;
+        comp.time_signature = (bar, bar);
+        comp
     };
+    if comp.tracks.len() > 1 {
+        return Err(ClidawError::Usage(format!(
+            "{} has {} tracks; seq edits one-track patterns",
+            path.display(),
+            comp.tracks.len()
+        )));
+    }
+    let events = comp.tracks.first().map_or(&[][..], |t| &t.events[..]);
+    // A grid has no way to show them, and saving would drop them
+    if note::is_generative(events) {
+        return Err(ClidawError::Usage(format!(
+            "{} has chance notes or alt{{...}} groups; seq edits fixed patterns",
+            path.display()
+        )));
+    }
+    let (mut grid, skipped) =
+        seq::Grid::from_events(events, settings.steps, settings.octave, settings.steps_per_beat);
+    if events.is_empty() {
+        grid.drums = settings.drums;
+        grid.cursor = (grid.rows() - 1, 0);
+    }
+    grid.steps_per_bar = comp.time_signature.0.max(1) as usize;
+    if let Some(bpm) = settings.tempo {
+        comp.tempo = bpm * settings.steps_per_beat as f64;
+    } else if !path.exists() {
+        comp.tempo = 120.0 * settings.steps_per_beat as f64;
+    }
 
-    if let Err(e) = result {
-        eprintln!("Playback error: {}", e);
-        std::process::exit(1);
+    let source = match (instrument, comp.tracks.first().and_then(|t| comp.track_patch(t))) {
+        (Some(spec), _) => song::InstrumentSource::from_patch(&spec.to_string_lossy()),
+        (None, Some(patch)) => song::InstrumentSource::from_patch(patch),
+        (None, None) if grid.drums => song::InstrumentSource::Preset("kit".to_string()),
+        (None, None) => song::InstrumentSource::Preset("default".to_string()),
+    };
+    let patch = source.load_checked(false)?.to_patch(comp.tempo);
+    if patch.kit.is_some() != grid.drums {
+        let (grid_kind, wanted) = if grid.drums {
+            ("drum", "a drum kit")
+        } else {
+            ("pitch", "a tonal instrument")
+        };
+        return Err(ClidawError::Usage(format!(
+            "{} can't play a {} grid; pass --instrument with {}",
+            source, grid_kind, wanted
+        )));
     }
+
+    let notice = (skipped > 0).then(|| {
+        format!(
+            "{} note{} off the step grid left out (writing drops {})",
+            skipped,
+            if skipped == 1 { "" } else { "s" },
+            if skipped == 1 { "it" } else { "them" }
+        )
+    });
+    let options = seq::SeqOptions {
+        path,
+        comp,
+        grid,
+        notice,
+        patch,
+        output,
+    };
+    seq::run(options, |comp| write_notes_file(comp, path))
 }
 
-fn read_file(path: &PathBuf) -> String {
-    fs::read_to_string(path).unwrap_or_else(|e| {
-        eprintln!("Error reading {}: {}", path.display(), e);
-        std::process::exit(1);
-    })
+/// `patch`, a path from the working directory, as seen from `dir`: relative
+/// if it is inside `dir`, else absolute
+fn relative_patch(patch: &Path, dir: &Path) -> String {
+    let patch = match patch.strip_prefix(dir) {
+        Ok(inside) if patch.is_relative() => inside.to_path_buf(),
+        _ if dir.as_os_str().is_empty() || patch.is_absolute() => patch.to_path_buf(),
+        _ => std::path::absolute(patch).unwrap_or_else(|_| patch.to_path_buf()),
+    };
+    patch.to_string_lossy().into_owned()
 }
 
-fn print_pattern(pattern: &note::Pattern) {
-    println!("Pattern: {} beats", pattern.length_beats());
-    println!("Loop: {}", pattern.loop_pattern);
-    println!("Time signature: {}/{}", pattern.time_signature.0, pattern.time_signature.1);
-    println!("Octave: {}", pattern.default_octave);
-    println!();
-    for event in &pattern.events {
-        match event {
-            note::Event::Note(n) => {
-                println!(
-                    "  {:?}{} ({:.1} Hz)",
-                    n.note,
-                    n.octave,
-                    n.note.to_freq(n.octave)
-                );
-            }
-            note::Event::Chord(notes) => {
-                let desc: Vec<String> = notes
-                    .iter()
-                    .map(|n| format!("{:?}{}", n.note, n.octave))
-                    .collect();
-                println!("  Chord [{}]", desc.join(" "));
-            }
-            note::Event::Rest(beats) => {
-                println!(
-                    "  Rest ({} beat{})",
-                    beats,
-                    if *beats != 1.0 { "s" } else { "" }
-                );
+/// Warn about segments whose notes the scheduler clamped to the MIDI range
+fn warn_clamped(clamped: &[scheduler::Clamped]) {
+    for segment in clamped {
+        eprintln!("warning: {}", segment);
+    }
+}
+
+/// `clidaw schedule`: the song's full schedule on stdout
+fn print_schedule(
+    path: &Path,
+    format: ScheduleFormat,
+    tempo: Option<f64>,
+    seed: Option<u64>,
+) -> Result<(), ClidawError> {
+    let loaded = seed_patterns(load_file(path, None, tempo)?, seed);
+    let compiled = scheduler::compile(&loaded.song, &loaded.patterns)?;
+    warn_clamped(&compiled.clamped);
+    let events = compiled.events;
+    let mut out = io::stdout().lock();
+    match format {
+        ScheduleFormat::Json => writeln!(out, "{}", export::to_json(&events, loaded.tempo)),
+        ScheduleFormat::Csv => write!(out, "{}", export::to_csv(&events, loaded.tempo)),
+    }
+    .map_err(stdout_error)
+}
+
+fn print_pattern(
+    out: &mut impl Write,
+    pattern: &note::Pattern,
+    includes: &[note::Include],
+    verbose: bool,
+) -> io::Result<()> {
+    writeln!(out, "Pattern: {} beats", pattern.length_beats())?;
+    writeln!(out, "Loop: {}", pattern.loop_pattern)?;
+    writeln!(out, "Time signature: {}/{}", pattern.time_signature.0, pattern.time_signature.1)?;
+    for (beat, (num, den)) in pattern.meter.changes() {
+        writeln!(out, "  {}/{} from beat {}", num, den, beat)?;
+    }
+    writeln!(out, "Octave: {}", pattern.default_octave)?;
+    if let Some(key) = &pattern.key {
+        writeln!(out, "Key: {}", key)?;
+    }
+    writeln!(out)?;
+    print_events(out, pattern, includes, verbose)
+}
+
+fn print_composition(
+    out: &mut impl Write,
+    comp: &note::Composition,
+    show_includes: bool,
+    verbose: bool,
+) -> io::Result<()> {
+    writeln!(out, "Tempo: {} BPM", comp.tempo)?;
+    writeln!(out, "Loop: {}", comp.loop_pattern)?;
+    writeln!(out, "Time signature: {}/{}", comp.time_signature.0, comp.time_signature.1)?;
+    writeln!(out, "Octave: {}", comp.default_octave)?;
+    if let Some(key) = &comp.key {
+        writeln!(out, "Key: {}", key)?;
+    }
+    if let Some(patch) = &comp.default_patch {
+        writeln!(out, "Patch: {}", patch)?;
+    }
+    for (idx, track) in comp.tracks.iter().enumerate() {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "Track {}: {} ({} beats, patch: {})",
+            idx,
+            track.name,
+            comp.track_pattern(track).length_beats(),
+            comp.track_patch(track).unwrap_or("default")
+        )?;
+        let includes = if show_includes { &track.includes[..] } else { &[] };
+        print_events(out, &comp.track_pattern(track), includes, verbose)?;
+    }
+    Ok(())
+}
+
+/// `parse --roll`: the file as a piano roll, track by track if it has
+/// tracks, `grid` beats to a column and wrapped to the terminal's width
+fn print_roll(
+    out: &mut impl Write,
+    comp: note::Composition,
+    grid: f64,
+) -> Result<(), ClidawError> {
+    let steps = import::steps_per_beat(grid).ok_or_else(|| {
+        ClidawError::Usage(
+            "--grid must divide a beat into 1 to 16 equal steps (e.g. 0.25 or 0.5)".to_string(),
+        )
+    })?;
+    let width = crossterm::terminal::size().map_or(roll::DEFAULT_WIDTH, |(cols, _)| cols as usize);
+    if !comp.has_tracks() {
+        let roll = roll::render(&comp.into_pattern(), steps, width);
+        return write!(out, "{}", roll).map_err(stdout_error);
+    }
+    for (idx, track) in comp.tracks.iter().enumerate() {
+        if idx > 0 {
+            writeln!(out).map_err(stdout_error)?;
+        }
+        let roll = roll::render(&comp.track_pattern(track), steps, width);
+        write!(out, "Track {}: {}\n{}", idx, track.name, roll).map_err(stdout_error)?;
+    }
+    Ok(())
+}
+
+/// Lines for the includes that end or begin just before event `idx`:
+/// inner ones close first and outer ones open first
+fn print_include_markers(
+    out: &mut impl Write,
+    includes: &[note::Include],
+    idx: usize,
+) -> io::Result<()> {
+    let mut ending: Vec<&note::Include> = includes
+        .iter()
+        .filter(|i| i.events.end == idx && !i.events.is_empty())
+        .collect();
+    ending.sort_by_key(|i| std::cmp::Reverse(i.events.start));
+    for include in ending {
+        writeln!(out, "  <<< end of {}", include.file.display())?;
+    }
+    let mut starting: Vec<&note::Include> =
+        includes.iter().filter(|i| i.events.start == idx).collect();
+    starting.sort_by_key(|i| std::cmp::Reverse(i.events.end));
+    for include in starting {
+        if include.events.is_empty() {
+            writeln!(out, "  --- {} (no events)", include.file.display())?;
+        } else {
+            writeln!(out, "  >>> {}", include.file.display())?;
+        }
+    }
+    Ok(())
+}
+
+/// `verbose` starts each line with the event's beat and bar:beat, lists
+/// chord notes with their frequencies and ends with a summary of the bars
+fn print_events(
+    out: &mut impl Write,
+    pattern: &note::Pattern,
+    includes: &[note::Include],
+    verbose: bool,
+) -> io::Result<()> {
+    let events = &pattern.events;
+    let positions = if verbose {
+        note::event_positions(events, &pattern.meter)
+    } else {
+        Vec::new()
+    };
+    for (idx, event) in events.iter().enumerate() {
+        print_include_markers(out, includes, idx)?;
+        if let Some(position) = positions.get(idx) {
+            write!(out, "  {:>8.3}  {:<8}", position.beat.as_f64(), position.to_string())?;
+        }
+        // Lines under an `alt{...}` line up past the position column
+        print_event(out, event, if verbose { 20 } else { 0 }, verbose)?;
+    }
+    print_include_markers(out, includes, events.len())?;
+    if verbose {
+        print_bar_summary(out, pattern)?;
+    }
+    Ok(())
+}
+
+/// One event's line of `print_events`, after the `indent` columns already
+/// printed. An `alt{...}` lists each choice's events below it, further in.
+fn print_event(
+    out: &mut impl Write,
+    event: &note::Event,
+    indent: usize,
+    verbose: bool,
+) -> io::Result<()> {
+    // Notes written as scale degrees show the degree too: "b3=F4"
+    let describe = |n: &note::NoteEvent| match n.degree {
+        Some(degree) => format!("{}={}", degree, n.label()),
+        None => n.label(),
+    };
+    // Velocity is only worth showing once dynamics change it
+    let velocity = |v: f64| {
+        if v == 1.0 {
+            String::new()
+        } else {
+            format!(", vel {:.2}", v)
+        }
+    };
+    // Likewise lengths other than one beat, and chances below certain
+    let length = |beats: Beat| (beats != Beat::ONE).then(|| format!("{:.3} beats", beats.as_f64()));
+    let chance = |c: f64| (c < 1.0).then(|| format!("{:.0}% chance", c * 100.0));
+    let lead = "  ";
+    match event {
+        note::Event::Note(n, beats) => {
+            let extra: String = [length(*beats), chance(n.chance)]
+                .into_iter()
+                .flatten()
+                .map(|text| format!(", {}", text))
+                .collect();
+            writeln!(
+                out,
+                "{}{} ({:.1} Hz{}{})",
+                lead,
+                describe(n),
+                n.note.to_freq(n.octave),
+                velocity(n.velocity),
+                extra
+            )?;
+        }
+        note::Event::Chord(notes, beats) => {
+            let desc: Vec<String> = notes
+                .iter()
+                .map(|n| {
+                    if verbose {
+                        format!("{} {:.1} Hz", describe(n), n.note.to_freq(n.octave))
+                    } else {
+                        describe(n)
+                    }
+                })
+                .collect();
+            let desc = desc.join(if verbose { ", " } else { " " });
+            let vel = match notes.first() {
+                Some(n) if n.velocity != 1.0 => format!(" (vel {:.2})", n.velocity),
+                _ => String::new(),
+            };
+            let beats = length(*beats).map(|l| format!(" ({})", l)).unwrap_or_default();
+            let odds = notes.first().and_then(|n| chance(n.chance));
+            let odds = odds.map(|c| format!(" ({})", c)).unwrap_or_default();
+            writeln!(out, "{}Chord [{}]{}{}{}", lead, desc, vel, beats, odds)?;
+        }
+        note::Event::Drums(drums, beats) => {
+            let names: Vec<&str> = drums.iter().map(|d| d.name()).collect();
+            let beats = length(*beats).map(|l| format!(" ({})", l)).unwrap_or_default();
+            writeln!(out, "{}Drums [{}]{}", lead, names.join(" "), beats)?;
+        }
+        note::Event::Rest(beats) => {
+            writeln!(
+                out,
+                "{}Rest ({} beat{})",
+                lead,
+                beats,
+                if *beats != Beat::ONE { "s" } else { "" }
+            )?;
+        }
+        note::Event::Tie(beats) => {
+            let beats = length(*beats).map(|l| format!(" ({})", l)).unwrap_or_default();
+            writeln!(out, "{}Tie{}", lead, beats)?;
+        }
+        note::Event::BarLine => writeln!(out, "{}|", lead)?,
+        note::Event::Alt(alt) => {
+            let pick = if alt.random { "one at random" } else { "each in turn" };
+            let beats = alt.length();
+            let plural = if beats != Beat::ONE { "s" } else { "" };
+            writeln!(out, "{}Alt: {} per repeat ({} beat{})", lead, pick, beats, plural)?;
+            for (idx, choice) in alt.choices.iter().enumerate() {
+                writeln!(out, "{:indent$}    {}:", "", idx + 1, indent = indent)?;
+                for event in choice {
+                    write!(out, "{:indent$}", "", indent = indent + 4)?;
+                    print_event(out, event, indent + 4, verbose)?;
+                }
             }
-            note::Event::BarLine => println!("  |"),
         }
     }
+    Ok(())
+}
+
+/// `parse --verbose`'s closing lines: the pattern's length, its bars and
+/// whether each bar line closes a full bar
+fn print_bar_summary(out: &mut impl Write, pattern: &note::Pattern) -> io::Result<()> {
+    let beats = pattern.length_beats();
+    let bars = pattern.meter.bar_count(beats.as_f64());
+    writeln!(out)?;
+    writeln!(out, "Total: {} beats in {} bar{}", beats, bars, if bars == 1 { "" } else { "s" })?;
+    if !pattern.events.iter().any(|e| matches!(e, note::Event::BarLine)) {
+        return writeln!(out, "Bars: no bar lines to check");
+    }
+    let mut report = check::Report::default();
+    check::check_bars(Path::new(""), pattern, &mut report);
+    if report.diagnostics.is_empty() {
+        return writeln!(out, "Bars: balanced");
+    }
+    let label = if report.error_count() > 0 { "unbalanced" } else { "balanced" };
+    writeln!(out, "Bars: {}", label)?;
+    for diagnostic in &report.diagnostics {
+        writeln!(out, "  {}", diagnostic.message)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,184 @@
+//! Bar positions for music whose time signature changes along the way.
+//!
+//! A bar holds as many beats as its signature's numerator, as everywhere
+//! else in clidaw: a 7/8 bar is seven beats at the song's tempo. A change
+//! that lands in the middle of a bar cuts that bar short and starts a new
+//! one on the spot.
+
+/// Tolerance for beat positions built up from fractional note lengths
+const EPSILON: f64 = 1e-9;
+
+/// One time signature and where it takes over
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Change {
+    beat: f64,
+    /// 0-based bar the signature starts on
+    bar: u32,
+    signature: (u8, u8),
+}
+
+/// The time signature in effect at every beat, and where bars start.
+/// Bars count from 0 here; the command line's bar numbers count from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeterMap {
+    /// Never empty; the first starts at beat 0, bar 0
+    changes: Vec<Change>,
+}
+
+impl MeterMap {
+    /// A map with one signature throughout
+    pub fn new(signature: (u8, u8)) -> Self {
+        Self {
+            changes: vec![Change {
+                beat: 0.0,
+                bar: 0,
+                signature,
+            }],
+        }
+    }
+
+    /// Switch to `signature` at `beat`. Changes are added in order; one at
+    /// the beat of the last replaces it, and one before it is moved there.
+    pub fn change(&mut self, beat: f64, signature: (u8, u8)) {
+        let last = self.changes[self.changes.len() - 1];
+        let beat = beat.max(last.beat);
+        if beat - last.beat < EPSILON {
+            self.changes.pop();
+            if self.changes.last().is_some_and(|c| c.signature == signature) {
+                return;
+            }
+            self.changes.push(Change { signature, ..last });
+            return;
+        }
+        let bar = self.bar_at_beat(beat);
+        let mid_bar = beat - self.beat_of_bar(bar) > EPSILON;
+        if signature == last.signature && !mid_bar {
+            return;
+        }
+        self.changes.push(Change {
+            beat,
+            bar: bar + mid_bar as u32,
+            signature,
+        });
+    }
+
+    /// The change in effect at `beat`
+    fn at_beat(&self, beat: f64) -> &Change {
+        let idx = self.changes.partition_point(|c| c.beat <= beat + EPSILON);
+        &self.changes[idx.saturating_sub(1)]
+    }
+
+    /// The time signature in effect at `beat`
+    pub fn signature_at(&self, beat: f64) -> (u8, u8) {
+        self.at_beat(beat).signature
+    }
+
+    /// The 0-based bar `beat` falls in (beats before 0 are in bar 0)
+    pub fn bar_at_beat(&self, beat: f64) -> u32 {
+        let change = self.at_beat(beat);
+        let into = (beat - change.beat).max(0.0);
+        change.bar + ((into + EPSILON) / beats_per_bar(change.signature)) as u32
+    }
+
+    /// The beat 0-based `bar` starts on
+    pub fn beat_of_bar(&self, bar: u32) -> f64 {
+        let idx = self.changes.partition_point(|c| c.bar <= bar);
+        let change = &self.changes[idx.saturating_sub(1)];
+        change.beat + (bar - change.bar) as f64 * beats_per_bar(change.signature)
+    }
+
+    /// Bars that start before `end`, a last one cut short included
+    pub fn bar_count(&self, end: f64) -> u32 {
+        if end <= EPSILON {
+            return 0;
+        }
+        let bar = self.bar_at_beat(end);
+        bar + (end - self.beat_of_bar(bar) > EPSILON) as u32
+    }
+
+    /// Each change after the opening signature, as (beat, signature)
+    pub fn changes(&self) -> impl Iterator<Item = (f64, (u8, u8))> + '_ {
+        self.changes[1..].iter().map(|c| (c.beat, c.signature))
+    }
+
+    /// The map as heard from `start`, which becomes beat 0 of bar 0. A
+    /// negative `start` adds bars of the opening signature in front, as a
+    /// count-in does.
+    pub fn starting_at(&self, start: f64) -> MeterMap {
+        let mut map = MeterMap::new(self.signature_at(start));
+        for change in self.changes.iter().filter(|c| c.beat > start + EPSILON) {
+            map.change(change.beat - start, change.signature);
+        }
+        map
+    }
+}
+
+/// Beats in one bar of `signature` (a 0 numerator counts as 1)
+fn beats_per_bar(signature: (u8, u8)) -> f64 {
+    signature.0.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two bars of 4/4, two of 7/8, then 4/4 again
+    fn mixed() -> MeterMap {
+        let mut map = MeterMap::new((4, 4));
+        map.change(8.0, (7, 8));
+        map.change(22.0, (4, 4));
+        map
+    }
+
+    #[test]
+    fn test_bar_boundaries_follow_changes() {
+        let map = mixed();
+        let starts: Vec<f64> = (0..7).map(|bar| map.beat_of_bar(bar)).collect();
+        assert_eq!(starts, [0.0, 4.0, 8.0, 15.0, 22.0, 26.0, 30.0]);
+        for (bar, &start) in starts.iter().enumerate() {
+            assert_eq!(map.bar_at_beat(start), bar as u32);
+            assert_eq!(map.bar_at_beat(start - 0.5), (bar as u32).saturating_sub(1));
+        }
+        assert_eq!(map.signature_at(7.9), (4, 4));
+        assert_eq!(map.signature_at(8.0), (7, 8));
+        assert_eq!(map.signature_at(21.0), (7, 8));
+        assert_eq!(map.signature_at(100.0), (4, 4));
+        assert_eq!(map.changes().collect::<Vec<_>>(), [(8.0, (7, 8)), (22.0, (4, 4))]);
+        assert_eq!(map.bar_count(0.0), 0);
+        assert_eq!(map.bar_count(22.0), 4);
+        assert_eq!(map.bar_count(23.5), 5);
+    }
+
+    #[test]
+    fn test_mid_bar_change_starts_a_new_bar() {
+        let mut map = MeterMap::new((4, 4));
+        // A bar of 4/4 cut off after two beats, then 3/4
+        map.change(6.0, (3, 4));
+        assert_eq!(map.bar_at_beat(5.0), 1);
+        assert_eq!(map.bar_at_beat(6.0), 2);
+        assert_eq!(map.beat_of_bar(3), 9.0);
+        // Repeating the current signature on a bar line changes nothing
+        let before = map.clone();
+        map.change(9.0, (3, 4));
+        assert_eq!(map, before);
+        // A second change at the same beat replaces the first
+        map.change(12.0, (5, 4));
+        map.change(12.0, (6, 8));
+        assert_eq!(map.signature_at(12.0), (6, 8));
+        assert_eq!(map.beat_of_bar(5), 18.0);
+    }
+
+    #[test]
+    fn test_starting_at_shifts_and_counts_in() {
+        let map = mixed();
+        let seeked = map.starting_at(15.0);
+        assert_eq!(seeked.signature_at(0.0), (7, 8));
+        assert_eq!(seeked.beat_of_bar(1), 7.0);
+        assert_eq!(seeked.beat_of_bar(2), 11.0);
+        // A one-bar count-in in the opening signature
+        let counted = map.starting_at(-4.0);
+        assert_eq!(counted.beat_of_bar(3), 12.0);
+        assert_eq!(counted.signature_at(12.0), (7, 8));
+        assert_eq!(counted.bar_at_beat(26.0), 5);
+    }
+}
@@ -0,0 +1,344 @@
+//! Standard MIDI File reader for `clidaw import` and for playing `.mid`
+//! files directly.
+//!
+//! Only what clidaw can use is kept: notes with their channel and velocity,
+//! the first tempo and time signature, and track names. Everything else is
+//! counted by kind so the import can say what it left out.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+
+use crate::error::ClidawError;
+
+/// The General MIDI percussion channel (channel 10, counting from 1)
+pub const PERCUSSION_CHANNEL: u8 = 9;
+
+/// Tempo when a file has no tempo event: 120 BPM
+pub const DEFAULT_TEMPO_USPQ: u32 = 500_000;
+
+/// One note from its note-on to its note-off, in ticks
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiNote {
+    /// 0-15
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// One `MTrk` chunk
+#[derive(Debug, Clone, Default)]
+pub struct MidiTrack {
+    /// From the track name meta event
+    pub name: Option<String>,
+    /// Ordered by start tick
+    pub notes: Vec<MidiNote>,
+}
+
+/// The parts of a MIDI file clidaw understands
+#[derive(Debug, Clone)]
+pub struct MidiFile {
+    pub ticks_per_quarter: u16,
+    /// Microseconds per quarter note of the earliest tempo event
+    pub tempo: Option<u32>,
+    /// Earliest time signature as (numerator, denominator)
+    pub time_signature: Option<(u8, u8)>,
+    pub tracks: Vec<MidiTrack>,
+    /// Events left out, by kind
+    pub skipped: BTreeMap<&'static str, usize>,
+}
+
+/// Read and parse a MIDI file
+pub fn load(path: &Path) -> Result<MidiFile, ClidawError> {
+    let bytes = fs::read(path).map_err(|e| ClidawError::io(path, e))?;
+    parse(&bytes).map_err(|msg| ClidawError::Midi {
+        file: path.to_path_buf(),
+        msg,
+    })
+}
+
+/// Cursor over big-endian chunk data
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or_else(|| format!("unexpected end of data at byte {}", self.pos))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Variable-length quantity: 7 bits per byte, high bit set on all but
+    /// the last (at most 4 bytes)
+    fn varlen(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format!("variable-length number too long at byte {}", self.pos))
+    }
+}
+
+/// Tempo and time signature events of every track, with their ticks
+#[derive(Default)]
+struct Meta {
+    tempos: Vec<(u64, u32)>,
+    time_signatures: Vec<(u64, (u8, u8))>,
+}
+
+/// Parse the bytes of a format 0 or 1 MIDI file
+pub fn parse(bytes: &[u8]) -> Result<MidiFile, String> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(4).ok() != Some(b"MThd".as_slice()) {
+        return Err("not a MIDI file (no MThd header)".to_string());
+    }
+    let header_len = reader.u32()? as usize;
+    if header_len < 6 {
+        return Err("MIDI header is too short".to_string());
+    }
+    let format = reader.u16()?;
+    let _track_count = reader.u16()?;
+    let division = reader.u16()?;
+    reader.take(header_len - 6)?;
+    if format > 1 {
+        return Err(format!("MIDI format {} is not supported (only 0 and 1)", format));
+    }
+    if division & 0x8000 != 0 || division == 0 {
+        return Err("SMPTE time division is not supported (only ticks per quarter note)".to_string());
+    }
+
+    let mut skipped = BTreeMap::new();
+    let mut meta = Meta::default();
+    let mut tracks = Vec::new();
+    while !reader.is_done() {
+        let id = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let data = reader.take(len)?;
+        if id == b"MTrk" {
+            tracks.push(parse_track(data, &mut meta, &mut skipped)?);
+        }
+    }
+
+    // The earliest tempo and time signature apply to the whole file
+    meta.tempos.sort_by_key(|&(tick, _)| tick);
+    meta.time_signatures.sort_by_key(|&(tick, _)| tick);
+    if meta.tempos.len() > 1 {
+        *skipped.entry("tempo change").or_default() += meta.tempos.len() - 1;
+    }
+    if meta.time_signatures.len() > 1 {
+        *skipped.entry("time signature change").or_default() += meta.time_signatures.len() - 1;
+    }
+    Ok(MidiFile {
+        ticks_per_quarter: division,
+        tempo: meta.tempos.first().map(|&(_, tempo)| tempo),
+        time_signature: meta.time_signatures.first().map(|&(_, ts)| ts),
+        tracks,
+        skipped,
+    })
+}
+
+fn parse_track(
+    data: &[u8],
+    meta: &mut Meta,
+    skipped: &mut BTreeMap<&'static str, usize>,
+) -> Result<MidiTrack, String> {
+    let mut reader = Reader::new(data);
+    let mut track = MidiTrack::default();
+    let mut tick = 0u64;
+    let mut running_status = None;
+    // Notes still sounding per (channel, key), oldest first: (start, velocity)
+    let mut open: HashMap<(u8, u8), VecDeque<(u64, u8)>> = HashMap::new();
+    let mut skip = |kind| *skipped.entry(kind).or_default() += 1;
+
+    while !reader.is_done() {
+        tick += reader.varlen()? as u64;
+        let mut status = reader.u8()?;
+        if status < 0x80 {
+            // Running status: the data byte belongs to the previous status
+            status = running_status.ok_or("data byte without a status byte")?;
+            reader.pos -= 1;
+        }
+        match status {
+            0xFF => {
+                running_status = None;
+                let kind = reader.u8()?;
+                let len = reader.varlen()? as usize;
+                let data = reader.take(len)?;
+                match kind {
+                    0x03 if track.name.is_none() => {
+                        let name = String::from_utf8_lossy(data).trim().to_string();
+                        track.name = Some(name).filter(|n| !n.is_empty());
+                    }
+                    0x2F => break,
+                    0x51 if len == 3 => {
+                        let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                        meta.tempos.push((tick, tempo));
+                    }
+                    0x58 if len >= 2 => {
+                        let denominator = 1u8.checked_shl(data[1] as u32).unwrap_or(4);
+                        meta.time_signatures.push((tick, (data[0], denominator)));
+                    }
+                    _ => skip("meta event"),
+                }
+            }
+            0xF0 | 0xF7 => {
+                running_status = None;
+                let len = reader.varlen()? as usize;
+                reader.take(len)?;
+                skip("system exclusive");
+            }
+            0x80..=0xEF => {
+                running_status = Some(status);
+                let channel = status & 0x0F;
+                match status & 0xF0 {
+                    0x80 | 0x90 => {
+                        let key = reader.u8()? & 0x7F;
+                        let velocity = reader.u8()? & 0x7F;
+                        let notes = open.entry((channel, key)).or_default();
+                        if status & 0xF0 == 0x90 && velocity > 0 {
+                            notes.push_back((tick, velocity));
+                        } else if let Some((start, velocity)) = notes.pop_front() {
+                            track.notes.push(MidiNote {
+                                channel,
+                                key,
+                                velocity,
+                                start,
+                                end: tick,
+                            });
+                        }
+                    }
+                    0xA0 => {
+                        reader.take(2)?;
+                        skip("aftertouch");
+                    }
+                    0xB0 => {
+                        reader.take(2)?;
+                        skip("control change");
+                    }
+                    0xC0 => {
+                        reader.take(1)?;
+                        skip("program change");
+                    }
+                    0xD0 => {
+                        reader.take(1)?;
+                        skip("channel pressure");
+                    }
+                    _ => {
+                        reader.take(2)?;
+                        skip("pitch bend");
+                    }
+                }
+            }
+            _ => return Err(format!("unexpected status byte 0x{:02X} at tick {}", status, tick)),
+        }
+    }
+
+    // Notes never released end with the track
+    for ((channel, key), notes) in open {
+        for (start, velocity) in notes {
+            track.notes.push(MidiNote {
+                channel,
+                key,
+                velocity,
+                start,
+                end: tick,
+            });
+        }
+    }
+    track.notes.sort_by_key(|n| (n.start, n.channel, n.key));
+    Ok(track)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file with the given `MTrk` bodies at 96 ticks per quarter
+    fn smf(format: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd\0\0\0\x06".to_vec();
+        bytes.extend(format.to_be_bytes());
+        bytes.extend((tracks.len() as u16).to_be_bytes());
+        bytes.extend(96u16.to_be_bytes());
+        for track in tracks {
+            bytes.extend(b"MTrk");
+            bytes.extend((track.len() as u32).to_be_bytes());
+            bytes.extend(*track);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_notes_tempo_and_running_status() {
+        let conductor: &[u8] = &[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 500000 us per quarter
+            0x00, 0xFF, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08, // 3/4
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let piano: &[u8] = &[
+            0x00, 0xFF, 0x03, 0x05, b'P', b'i', b'a', b'n', b'o',
+            0x00, 0x90, 60, 100, // C4 on
+            0x00, 64, 90, // E4 on, running status
+            0x60, 60, 0, // C4 off (velocity 0) after a quarter
+            0x00, 0x80, 64, 0, // E4 off
+            0x00, 0xB0, 7, 100, // volume: skipped
+            0x81, 0x40, 0x99, 36, 127, // kick on channel 10, 192 ticks later
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let file = parse(&smf(1, &[conductor, piano])).unwrap();
+        assert_eq!(file.ticks_per_quarter, 96);
+        assert_eq!(file.tempo, Some(500_000));
+        assert_eq!(file.time_signature, Some((3, 4)));
+        assert_eq!(file.tracks[1].name.as_deref(), Some("Piano"));
+        let notes: Vec<(u8, u8, u64, u64)> = file.tracks[1]
+            .notes
+            .iter()
+            .map(|n| (n.channel, n.key, n.start, n.end))
+            .collect();
+        // The unreleased kick ends with the track
+        assert_eq!(notes, vec![(0, 60, 0, 96), (0, 64, 0, 96), (9, 36, 288, 288)]);
+        assert_eq!(file.skipped.get("control change"), Some(&1));
+    }
+
+    #[test]
+    fn test_unsupported_files() {
+        assert!(parse(b"RIFF....").unwrap_err().contains("not a MIDI file"));
+        assert!(parse(&smf(2, &[])).unwrap_err().contains("format 2"));
+        let mut smpte = smf(0, &[]);
+        smpte[12] = 0xE7;
+        assert!(parse(&smpte).unwrap_err().contains("SMPTE"));
+        let truncated = smf(0, &[&[0x00, 0x90, 60]]);
+        assert!(parse(&truncated).unwrap_err().contains("unexpected end"));
+    }
+}
@@ -1,3 +1,15 @@
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::beat::Beat;
+use crate::meter::MeterMap;
+use crate::rng::Rng;
+
 /// Musical note names (chromatic scale)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NoteName {
@@ -16,6 +28,46 @@ pub enum NoteName {
 }
 
 impl NoteName {
+    pub const ALL: [NoteName; 12] = [
+        NoteName::C,
+        NoteName::CSharp,
+        NoteName::D,
+        NoteName::DSharp,
+        NoteName::E,
+        NoteName::F,
+        NoteName::FSharp,
+        NoteName::G,
+        NoteName::GSharp,
+        NoteName::A,
+        NoteName::ASharp,
+        NoteName::B,
+    ];
+
+    /// Parse a note name as written by `name()` or `flat_name()`; the letter
+    /// may be lowercase.
+    pub fn from_name(name: &str) -> Option<NoteName> {
+        NoteName::from_written(name).map(|(note, _)| note)
+    }
+
+    /// Parse a note name with its accidental: "C", "F#" or "Bb" (`♯` and
+    /// `♭` work too). The spelling is None for a natural.
+    pub fn from_written(name: &str) -> Option<(NoteName, Option<Spelling>)> {
+        let mut chars = name.chars();
+        let letter = chars.next()?.to_ascii_uppercase();
+        let accidental = chars.as_str().replace('♯', "#").replace('♭', "b");
+        let canonical = format!("{}{}", letter, accidental);
+        let spelling = match accidental.as_str() {
+            "" => None,
+            "#" => Some(Spelling::Sharp),
+            "b" => Some(Spelling::Flat),
+            _ => return None,
+        };
+        let note = NoteName::ALL
+            .into_iter()
+            .find(|n| n.name() == canonical || n.flat_name() == canonical)?;
+        Some((note, spelling))
+    }
+
     /// MIDI note number within an octave (C=0, B=11)
     pub fn semitone(self) -> u8 {
         match self {
@@ -34,6 +86,44 @@ impl NoteName {
         }
     }
 
+    /// Conventional name with sharps ("C", "C#", ...)
+    pub fn name(self) -> &'static str {
+        match self {
+            NoteName::C => "C",
+            NoteName::CSharp => "C#",
+            NoteName::D => "D",
+            NoteName::DSharp => "D#",
+            NoteName::E => "E",
+            NoteName::F => "F",
+            NoteName::FSharp => "F#",
+            NoteName::G => "G",
+            NoteName::GSharp => "G#",
+            NoteName::A => "A",
+            NoteName::ASharp => "A#",
+            NoteName::B => "B",
+        }
+    }
+
+    /// Conventional name with flats ("C", "Db", ...)
+    pub fn flat_name(self) -> &'static str {
+        match self {
+            NoteName::CSharp => "Db",
+            NoteName::DSharp => "Eb",
+            NoteName::FSharp => "Gb",
+            NoteName::GSharp => "Ab",
+            NoteName::ASharp => "Bb",
+            _ => self.name(),
+        }
+    }
+
+    /// The name with sharps or flats, as `spelling` says
+    pub fn spelled(self, spelling: Spelling) -> &'static str {
+        match spelling {
+            Spelling::Sharp => self.name(),
+            Spelling::Flat => self.flat_name(),
+        }
+    }
+
     /// Convert to MIDI note number given an octave (0-8)
     /// Middle C (C4) = MIDI 60
     pub fn to_midi(self, octave: u8) -> u8 {
@@ -42,8 +132,252 @@ impl NoteName {
 
     /// Frequency in Hz (A4 = 440 Hz)
     pub fn to_freq(self, octave: u8) -> f64 {
-        let midi = self.to_midi(octave) as f64;
-        440.0 * 2.0_f64.powf((midi - 69.0) / 12.0)
+        midi_to_freq(self.to_midi(octave))
+    }
+}
+
+/// How a black key is written: `A#` or `Bb`. Only names change with it;
+/// both are the same pitch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Spelling {
+    #[default]
+    Sharp,
+    Flat,
+}
+
+/// Frequency of every MIDI note number, worked out on first use: a song
+/// asks for the same few notes thousands of times over
+static MIDI_FREQS: LazyLock<[f64; 256]> =
+    LazyLock::new(|| std::array::from_fn(|midi| 440.0 * 2.0_f64.powf((midi as f64 - 69.0) / 12.0)));
+
+/// Frequency in Hz of a MIDI note number (A4 = 69 = 440 Hz)
+pub fn midi_to_freq(midi: u8) -> f64 {
+    MIDI_FREQS[midi as usize]
+}
+
+/// MIDI note number of a frequency in Hz; fractional between semitones
+pub fn freq_to_midi(freq: f64) -> f64 {
+    69.0 + 12.0 * (freq / 440.0).log2()
+}
+
+/// Name and octave of a MIDI note number, e.g. "C4" for 60 or "Db4" for
+/// 61 spelled with flats
+pub fn midi_name(midi: u8, spelling: Spelling) -> String {
+    let name = NoteName::ALL[midi as usize % 12].spelled(spelling);
+    format!("{}{}", name, midi as i32 / 12 - 1)
+}
+
+/// MIDI note number of a name like "C4", "f#3" or "Bb2" in octaves 0-8 (the
+/// reverse of `midi_name`)
+pub fn midi_from_name(text: &str) -> Option<u8> {
+    let text = text.trim();
+    let split = text.find(|c: char| c.is_ascii_digit())?;
+    let (name, octave) = text.split_at(split);
+    let octave: u8 = octave.parse().ok().filter(|o| *o <= 8)?;
+    Some(NoteName::from_name(name)?.to_midi(octave))
+}
+
+/// Percussion voices available on drum tracks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drum {
+    Kick,
+    Snare,
+    Hat,
+}
+
+impl Drum {
+    pub const ALL: [Drum; 3] = [Drum::Kick, Drum::Snare, Drum::Hat];
+
+    /// Name used in drum lines of .notes files ("kick", "snare", "hat")
+    pub fn name(self) -> &'static str {
+        match self {
+            Drum::Kick => "kick",
+            Drum::Snare => "snare",
+            Drum::Hat => "hat",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Drum> {
+        Drum::ALL.into_iter().find(|d| d.name() == name)
+    }
+}
+
+/// Scale types usable in a `key:` directive or `clidaw practice`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+}
+
+/// A scale, its name and other names, the semitones of degrees 1-7 above
+/// the tonic, and how far its tonic sits above its relative major's (which
+/// gives it the same key signature)
+type ScaleEntry = (Scale, &'static str, &'static [&'static str], [u8; 7], u8);
+
+/// Every scale
+const SCALES: [ScaleEntry; 8] = [
+    (Scale::Major, "major", &["ionian"], [0, 2, 4, 5, 7, 9, 11], 0),
+    (Scale::NaturalMinor, "minor", &["natural minor", "aeolian"], [0, 2, 3, 5, 7, 8, 10], 9),
+    (Scale::HarmonicMinor, "harmonic minor", &[], [0, 2, 3, 5, 7, 8, 11], 9),
+    (Scale::Dorian, "dorian", &[], [0, 2, 3, 5, 7, 9, 10], 2),
+    (Scale::Phrygian, "phrygian", &[], [0, 1, 3, 5, 7, 8, 10], 4),
+    (Scale::Lydian, "lydian", &[], [0, 2, 4, 6, 7, 9, 11], 5),
+    (Scale::Mixolydian, "mixolydian", &[], [0, 2, 4, 5, 7, 9, 10], 7),
+    (Scale::Locrian, "locrian", &[], [0, 1, 3, 5, 6, 8, 10], 11),
+];
+
+impl Scale {
+    fn entry(self) -> &'static ScaleEntry {
+        SCALES.iter().find(|entry| entry.0 == self).expect("every scale is in SCALES")
+    }
+
+    /// Semitones above the tonic of degrees 1-7
+    pub fn intervals(self) -> [u8; 7] {
+        self.entry().3
+    }
+
+    pub fn name(self) -> &'static str {
+        self.entry().1
+    }
+
+    /// Semitones from the tonic of the major scale with the same notes
+    /// down to this scale's tonic (9 for minor: A minor is C major's)
+    pub fn relative_major_offset(self) -> u8 {
+        self.entry().4
+    }
+
+    /// Parse a scale's name, e.g. "major", "minor" (natural minor),
+    /// "harmonic minor" or "dorian"; case and spacing don't matter
+    pub fn from_name(name: &str) -> Option<Scale> {
+        let words: Vec<String> = name.split_whitespace().map(str::to_ascii_lowercase).collect();
+        let name = words.join(" ");
+        SCALES
+            .iter()
+            .find(|(_, main, others, ..)| *main == name || others.contains(&name.as_str()))
+            .map(|entry| entry.0)
+    }
+
+    /// Names of every scale, for error messages
+    pub fn names() -> Vec<&'static str> {
+        SCALES.iter().map(|entry| entry.1).collect()
+    }
+}
+
+/// Key signature of a pattern: scale degrees resolve against it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub tonic: NoteName,
+    pub scale: Scale,
+    /// Sharps or flats, as the tonic was written (a natural tonic takes its
+    /// key signature's: F major, D, G, C and F minor and G dorian have flats)
+    pub spelling: Spelling,
+}
+
+impl Key {
+    /// Parse "D minor", "F# harmonic minor", "Bb major", "E phrygian"
+    pub fn parse(text: &str) -> Option<Key> {
+        let text = text.trim();
+        let (tonic, scale) = text.split_once(char::is_whitespace)?;
+        let (tonic, written) = NoteName::from_written(tonic)?;
+        let scale = Scale::from_name(scale)?;
+        // Major keys with flats: F, Bb, Eb, Ab and Db
+        let relative_major = (tonic.semitone() + 12 - scale.relative_major_offset()) % 12;
+        let spelling = written.unwrap_or(if [5, 10, 3, 8, 1].contains(&relative_major) {
+            Spelling::Flat
+        } else {
+            Spelling::Sharp
+        });
+        Some(Key {
+            tonic,
+            scale,
+            spelling,
+        })
+    }
+
+    /// The note for `degree` with the tonic in `octave`. Degrees 8 and 9
+    /// continue into the next octave; the accidental raises (#) or lowers
+    /// (b) by a semitone, and spells the note to match. None if the result
+    /// is outside MIDI 12-127.
+    pub fn resolve(&self, degree: Degree, octave: u8) -> Option<NoteEvent> {
+        let step = degree.number.checked_sub(1)? as i32;
+        let midi = self.tonic.to_midi(octave) as i32
+            + 12 * (step / 7)
+            + self.scale.intervals()[(step % 7) as usize] as i32
+            + degree.accidental as i32;
+        if !(12..=127).contains(&midi) {
+            return None;
+        }
+        Some(NoteEvent {
+            note: NoteName::ALL[(midi % 12) as usize],
+            octave: (midi / 12 - 1) as u8,
+            degree: Some(degree),
+            velocity: 1.0,
+            spelling: match degree.accidental {
+                a if a < 0 => Spelling::Flat,
+                a if a > 0 => Spelling::Sharp,
+                _ => self.spelling,
+            },
+            chance: 1.0,
+        })
+    }
+
+    /// The notes of the scale, tonic first
+    pub fn notes(&self) -> [NoteName; 7] {
+        self.scale
+            .intervals()
+            .map(|interval| NoteName::ALL[((self.tonic.semitone() + interval) % 12) as usize])
+    }
+
+    /// Whether `note` is in the scale (in any octave)
+    pub fn contains(&self, note: NoteName) -> bool {
+        self.notes().contains(&note)
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.tonic.spelled(self.spelling), self.scale.name())
+    }
+}
+
+/// A scale degree as written in a pattern: `3`, `b7`, `#4`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Degree {
+    /// 1-9 (8 and 9 are 1 and 2 an octave up)
+    pub number: u8,
+    /// Semitones: -1 for b, +1 for #
+    pub accidental: i8,
+}
+
+impl Degree {
+    /// Parse a degree as it is displayed: `3`, `b7`, `#4`
+    pub fn parse(text: &str) -> Option<Degree> {
+        let (accidental, number) = match text.strip_prefix('b') {
+            Some(rest) => (-1, rest),
+            None => match text.strip_prefix('#') {
+                Some(rest) => (1, rest),
+                None => (0, text),
+            },
+        };
+        let number = number.parse().ok().filter(|n| (1..=9).contains(n))?;
+        Some(Degree { number, accidental })
+    }
+}
+
+impl std::fmt::Display for Degree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = match self.accidental {
+            a if a < 0 => "b",
+            a if a > 0 => "#",
+            _ => "",
+        };
+        write!(f, "{}{}", prefix, self.number)
     }
 }
 
@@ -52,60 +386,559 @@ impl NoteName {
 pub struct NoteEvent {
     pub note: NoteName,
     pub octave: u8,
+    /// Scale degree it was written as, if any (display only)
+    pub degree: Option<Degree>,
+    /// Loudness 0.0..=1.0; full unless the pattern marks dynamics
+    pub velocity: f64,
+    /// Sharp or flat name, as written or as the pattern prefers (display
+    /// only)
+    pub spelling: Spelling,
+    /// Chance 0.0..=1.0 that it plays on each pass (`a?0.3`); 1.0 always
+    pub chance: f64,
+}
+
+impl NoteEvent {
+    /// Name and octave as written, e.g. "Bb3"
+    pub fn label(&self) -> String {
+        format!("{}{}", self.note.spelled(self.spelling), self.octave)
+    }
 }
 
 /// An event in the composition timeline
 #[derive(Debug, Clone, PartialEq)]
 pub enum Event {
-    /// A single note
-    Note(NoteEvent),
-    /// Multiple notes sounding together
-    Chord(Vec<NoteEvent>),
+    /// A single note (duration in beats)
+    Note(NoteEvent, Beat),
+    /// Multiple notes sounding together (duration in beats)
+    Chord(Vec<NoteEvent>, Beat),
+    /// One step of a drum grid: the drums hit together, then the step's
+    /// length in beats (one unless the pattern sets `drum_step:`)
+    Drums(Vec<Drum>, Beat),
     /// A rest (duration in beats)
-    Rest(f64),
+    Rest(Beat),
+    /// Holds the note or chord before it this many beats longer (`_`)
+    Tie(Beat),
     /// A bar line (visual/structural marker)
     BarLine,
+    /// `alt{...}`: one of several runs of events, picked anew on each
+    /// repeat of the pattern
+    Alt(Alternatives),
 }
 
-/// Duration in beats of a single event (Note = 1, Chord = 1, Rest = beats, BarLine = 0)
-pub fn event_duration(e: &Event) -> f64 {
+/// The choices of an `alt{1: a s | 2: d f}`. Each lasts as long as the
+/// longest; shorter ones end in silence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alternatives {
+    pub choices: Vec<Vec<Event>>,
+    /// `alt?{...}`: pick at random rather than each in turn
+    pub random: bool,
+}
+
+impl Alternatives {
+    /// Beats the group takes: its longest choice's
+    pub fn length(&self) -> Beat {
+        let lengths = self.choices.iter().map(|c| c.iter().map(event_duration).sum());
+        lengths.max().unwrap_or_default()
+    }
+
+    /// Which choice plays on repeat `round`: the next in turn, or a random
+    /// one drawn from `rng`
+    pub fn choose(&self, round: u64, rng: &mut Rng) -> usize {
+        let count = self.choices.len() as u64;
+        if self.random {
+            (rng.next_u64() % count) as usize
+        } else {
+            (round % count) as usize
+        }
+    }
+}
+
+/// Whether a note with this `chance` plays this time. Notes that always play
+/// draw nothing from `rng`, so patterns without chances don't disturb it.
+pub fn rolls(chance: f64, rng: &mut Rng) -> bool {
+    chance >= 1.0 || rng.next_f64() < chance
+}
+
+/// Whether `events` change from one repeat to the next: they have notes
+/// with a chance or `alt{...}` groups
+pub fn is_generative(events: &[Event]) -> bool {
+    events.iter().any(|e| match e {
+        Event::Alt(_) => true,
+        e => e.notes().iter().any(|n| n.chance < 1.0),
+    })
+}
+
+impl Event {
+    /// The notes this event plays (none for drums, rests and bar lines)
+    pub fn notes(&self) -> &[NoteEvent] {
+        match self {
+            Event::Note(n, _) => std::slice::from_ref(n),
+            Event::Chord(notes, _) => notes,
+            Event::Drums(..) | Event::Rest(_) | Event::Tie(_) | Event::BarLine | Event::Alt(_) => {
+                &[]
+            }
+        }
+    }
+
+    /// The notes this event plays, to change in place
+    pub fn notes_mut(&mut self) -> &mut [NoteEvent] {
+        match self {
+            Event::Note(n, _) => std::slice::from_mut(n),
+            Event::Chord(notes, _) => notes,
+            Event::Drums(..) | Event::Rest(_) | Event::Tie(_) | Event::BarLine | Event::Alt(_) => {
+                &mut []
+            }
+        }
+    }
+}
+
+/// Every note of `events`, those in each choice of an `alt{...}` included
+pub fn all_notes_mut(events: &mut [Event]) -> Vec<&mut NoteEvent> {
+    let mut notes = Vec::new();
+    for event in events {
+        match event {
+            Event::Alt(alt) => {
+                for choice in &mut alt.choices {
+                    notes.extend(all_notes_mut(choice));
+                }
+            }
+            event => notes.extend(event.notes_mut()),
+        }
+    }
+    notes
+}
+
+/// Duration in beats of a single event (BarLine = 0, `alt{...}` its
+/// longest choice, the rest carry their own: 1 unless written with a length,
+/// inside a tuplet or, for drum steps, set by `drum_step:`)
+pub fn event_duration(e: &Event) -> Beat {
     match e {
-        Event::Note(_) | Event::Chord(_) => 1.0,
-        Event::Rest(beats) => *beats,
-        Event::BarLine => 0.0,
+        Event::Note(_, beats)
+        | Event::Chord(_, beats)
+        | Event::Drums(_, beats)
+        | Event::Rest(beats)
+        | Event::Tie(beats) => *beats,
+        Event::BarLine => Beat::ZERO,
+        Event::Alt(alt) => alt.length(),
+    }
+}
+
+/// How long the note or chord at `events[idx]` sounds: its own length plus
+/// that of each tie right after it (bar lines don't break a tie)
+pub fn tied_length(events: &[Event], idx: usize) -> Beat {
+    let ties: Beat = events[idx + 1..]
+        .iter()
+        .filter(|e| !matches!(e, Event::BarLine))
+        .map_while(|e| match e {
+            Event::Tie(beats) => Some(*beats),
+            _ => None,
+        })
+        .sum();
+    event_duration(&events[idx]) + ties
+}
+
+/// Start beat of each event, accumulated from `event_duration`.
+pub fn beat_positions(events: &[Event]) -> Vec<Beat> {
+    let mut beat = Beat::ZERO;
+    events
+        .iter()
+        .map(|e| {
+            let start = beat;
+            beat += event_duration(e);
+            start
+        })
+        .collect()
+}
+
+/// Where an event starts: its beat from the top, and the bar and beat
+/// within that bar, both counted from 1 as a musician would
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub beat: Beat,
+    pub bar: u32,
+    /// 1 on the downbeat; fractional for an event off the beat
+    pub beat_in_bar: Beat,
+}
+
+/// `bar:beat`, e.g. `3:2` or `3:2.5`
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let beat = format!("{:.3}", self.beat_in_bar.as_f64());
+        let beat = beat.trim_end_matches('0').trim_end_matches('.');
+        write!(f, "{}:{}", self.bar, beat)
     }
 }
 
-/// A named track with its own settings and events (used for legacy Composition)
+/// The `Position` of each event, from `beat_positions` and the bars of
+/// `meter`
+pub fn event_positions(events: &[Event], meter: &MeterMap) -> Vec<Position> {
+    beat_positions(events)
+        .into_iter()
+        .map(|beat| {
+            let bar = meter.bar_at_beat(beat.as_f64());
+            let downbeat = Beat::from_f64(meter.beat_of_bar(bar));
+            Position {
+                beat,
+                bar: bar + 1,
+                beat_in_bar: beat - downbeat + Beat::ONE,
+            }
+        })
+        .collect()
+}
+
+/// Every note of `events` as (start beat, length in beats, note), in
+/// order. A chord gives one entry per note; ties lengthen the notes before
+/// them. Every choice of an `alt{...}` is included, overlapping.
+pub fn timeline(events: &[Event]) -> Vec<(Beat, Beat, NoteEvent)> {
+    let starts = beat_positions(events);
+    let mut notes = Vec::new();
+    for (idx, event) in events.iter().enumerate() {
+        if let Event::Alt(alt) = event {
+            for choice in &alt.choices {
+                let inner = timeline(choice).into_iter();
+                notes.extend(inner.map(|(start, length, n)| (starts[idx] + start, length, n)));
+            }
+            continue;
+        }
+        if event.notes().is_empty() {
+            continue;
+        }
+        let length = tied_length(events, idx);
+        notes.extend(event.notes().iter().map(|n| (starts[idx], length, n.clone())));
+    }
+    notes
+}
+
+/// Lowest and highest MIDI notes of octaves 0-8 (C0 and B8)
+const LOWEST_MIDI: i32 = 12;
+const HIGHEST_MIDI: i32 = 119;
+
+/// Set `note` to MIDI note `midi`, moved by whole octaves into octaves 0-8
+/// if it falls outside them. Returns true if it had to be moved. The note
+/// no longer stands for a scale degree.
+fn set_pitch(note: &mut NoteEvent, mut midi: i32) -> bool {
+    let outside = !(LOWEST_MIDI..=HIGHEST_MIDI).contains(&midi);
+    while midi < LOWEST_MIDI {
+        midi += 12;
+    }
+    while midi > HIGHEST_MIDI {
+        midi -= 12;
+    }
+    note.note = NoteName::ALL[(midi % 12) as usize];
+    note.octave = (midi / 12 - 1) as u8;
+    note.degree = None;
+    outside
+}
+
+/// Move every note `semitones` up (down if negative). A note that would
+/// leave octaves 0-8 keeps its new pitch class in the nearest octave
+/// inside them; returns how many did.
+pub fn transpose(events: &mut [Event], semitones: i32) -> usize {
+    let mut clamped = 0;
+    for note in all_notes_mut(events) {
+        let midi = note.note.to_midi(note.octave) as i32 + semitones;
+        clamped += set_pitch(note, midi) as usize;
+    }
+    clamped
+}
+
+/// Mirror every note around MIDI note `axis`, so a note a third above it
+/// ends up a third below. Notes outside octaves 0-8 are moved back inside
+/// as in `transpose`; returns how many were.
+pub fn invert(events: &mut [Event], axis: u8) -> usize {
+    let mut clamped = 0;
+    for note in all_notes_mut(events) {
+        let midi = 2 * axis as i32 - note.note.to_midi(note.octave) as i32;
+        clamped += set_pitch(note, midi) as usize;
+    }
+    clamped
+}
+
+/// Play `events` backwards. A note or chord keeps its ties after it, but
+/// their lengths swap end for end, so bar lines among them stay where they
+/// fall in the reversed time. A bar line closing the events stays last.
+/// Each choice of an `alt{...}` is reversed in place, its silence first.
+pub fn reverse(events: &mut Vec<Event>) {
+    let closing = events.pop_if(|e| matches!(e, Event::BarLine));
+    // A note or chord with its ties and the bar lines among them, or any
+    // other event on its own
+    let mut units: Vec<Vec<Event>> = Vec::new();
+    for event in events.drain(..) {
+        match (&event, units.last_mut()) {
+            (Event::Tie(_) | Event::BarLine, Some(unit)) if !unit[0].notes().is_empty() => {
+                unit.push(event);
+            }
+            _ => units.push(vec![event]),
+        }
+    }
+    for mut unit in units.into_iter().rev() {
+        if let Event::Alt(alt) = &mut unit[0] {
+            let length = alt.length();
+            for choice in &mut alt.choices {
+                let played: Beat = choice.iter().map(event_duration).sum();
+                reverse(choice);
+                if played < length {
+                    choice.insert(0, Event::Rest(length - played));
+                }
+            }
+        }
+        if unit[0].notes().is_empty() {
+            events.append(&mut unit);
+            continue;
+        }
+        // Bar lines after the last tie aren't part of the note
+        let mut bars = 0;
+        while unit.len() > 1 && matches!(unit.last(), Some(Event::BarLine)) {
+            unit.pop();
+            bars += 1;
+        }
+        events.extend(std::iter::repeat_n(Event::BarLine, bars));
+        events.extend(reverse_tied(unit));
+    }
+    events.extend(closing);
+}
+
+/// A note or chord and its ties, backwards: the lengths in reverse order,
+/// with the bar lines between them reversed too
+fn reverse_tied(unit: Vec<Event>) -> Vec<Event> {
+    // Each length, and the bar lines after it
+    let mut lengths = Vec::new();
+    let mut bars: Vec<usize> = Vec::new();
+    for event in &unit {
+        match event {
+            Event::BarLine => *bars.last_mut().expect("a tied unit starts with a note") += 1,
+            event => {
+                lengths.push(event_duration(event));
+                bars.push(0);
+            }
+        }
+    }
+    bars.pop();
+    let mut lengths = lengths.into_iter().rev();
+    let first = lengths.next().unwrap_or_default();
+    let mut events = Vec::with_capacity(unit.len());
+    events.push(match unit.into_iter().next() {
+        Some(Event::Chord(notes, _)) => Event::Chord(notes, first),
+        Some(Event::Note(note, _)) => Event::Note(note, first),
+        other => unreachable!("a tied unit starts with a note, not {:?}", other),
+    });
+    for (bars, length) in bars.into_iter().rev().zip(lengths) {
+        events.extend(std::iter::repeat_n(Event::BarLine, bars));
+        events.push(Event::Tie(length));
+    }
+    events
+}
+
+/// Make every event `factor` times as long. A drum step keeps its length,
+/// so a rest after it makes up the rest of its new length; a factor below 1
+/// can't shorten it, and leaves events with drum steps unchanged (returns
+/// false).
+pub fn stretch(events: &mut Vec<Event>, factor: f64) -> bool {
+    let has_drums = events.iter().any(|e| matches!(e, Event::Drums(..)));
+    if has_drums && factor < 1.0 {
+        return false;
+    }
+    let mut stretched = Vec::with_capacity(events.len());
+    for event in events.drain(..) {
+        match event {
+            Event::Note(note, beats) => stretched.push(Event::Note(note, beats.scale(factor))),
+            Event::Chord(notes, beats) => stretched.push(Event::Chord(notes, beats.scale(factor))),
+            Event::Rest(beats) => stretched.push(Event::Rest(beats.scale(factor))),
+            Event::Tie(beats) => stretched.push(Event::Tie(beats.scale(factor))),
+            Event::Drums(drums, beats) => {
+                stretched.push(Event::Drums(drums, beats));
+                let gap = beats.scale(factor) - beats;
+                if gap > Beat::ZERO {
+                    stretched.push(Event::Rest(gap));
+                }
+            }
+            Event::BarLine => stretched.push(Event::BarLine),
+            Event::Alt(mut alt) => {
+                for choice in &mut alt.choices {
+                    stretch(choice, factor);
+                }
+                stretched.push(Event::Alt(alt));
+            }
+        }
+    }
+    *events = stretched;
+    true
+}
+
+/// One `[track: name]` section of a .notes file
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct Track {
     pub name: String,
+    /// `patch:` inside the section: a preset name or `.instr` path
     pub patch: Option<String>,
+    /// Octave in effect at the end of the section
     pub octave: u8,
     pub events: Vec<Event>,
+    /// Where `include:`d files' events landed, nested ones included
+    pub includes: Vec<Include>,
+    /// `time_signature:` lines after the track's first notes, in order
+    pub meter_changes: Vec<MeterChange>,
+}
+
+/// A `time_signature:` partway through a track
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeterChange {
+    /// Index of the first event in the new signature
+    pub event: usize,
+    pub signature: (u8, u8),
+}
+
+/// The events an `include:` spliced into a track
+#[derive(Debug, Clone, PartialEq)]
+pub struct Include {
+    /// The included file, as seen from the working directory
+    pub file: PathBuf,
+    /// Indices of its events in the track (repeats of them aren't marked)
+    pub events: Range<usize>,
 }
 
-/// A full parsed composition (legacy: single linear play-through)
+/// The free-text `title:`, `author:` and `description:` of a .song or
+/// .notes file; they don't change how it plays
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// Each `description:` line, joined with newlines
+    pub description: Option<String>,
+}
+
+impl Metadata {
+    /// Store `key: value` if `key` is one of the metadata keys (false if not)
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        let value = value.trim().to_string();
+        match key {
+            "title" => self.title = Some(value),
+            "author" => self.author = Some(value),
+            "description" => match &mut self.description {
+                Some(text) => {
+                    text.push('\n');
+                    text.push_str(&value);
+                }
+                None => self.description = Some(value),
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Each key that is set, as (key, value)
+    pub fn entries(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("title", &self.title),
+            ("author", &self.author),
+            ("description", &self.description),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.as_deref().map(|value| (key, value)))
+    }
+}
+
+/// A whole .notes file: header directives and its tracks. Tracks play in
+/// parallel; `Pattern` is the single-track view used by songs.
 #[derive(Debug, Clone)]
 pub struct Composition {
-    pub tempo: u32,
+    pub tempo: f64,
     pub time_signature: (u8, u8),
     pub default_octave: u8,
+    /// `patch:` before the first section, for tracks without their own
     pub default_patch: Option<String>,
+    /// Explicit `beats:` (0 = computed from each track's events)
+    pub beats: Beat,
+    pub loop_pattern: bool,
+    pub key: Option<Key>,
+    /// `prefer_flats: true`: black keys are named with flats
+    pub prefer_flats: bool,
     pub tracks: Vec<Track>,
+    pub metadata: Metadata,
+}
+
+impl Default for Composition {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Composition {
     pub fn new() -> Self {
         Self {
-            tempo: 120,
+            tempo: 120.0,
             time_signature: (4, 4),
             default_octave: 4,
             default_patch: None,
+            beats: Beat::ZERO,
+            loop_pattern: false,
+            key: None,
+            prefer_flats: false,
             tracks: Vec::new(),
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// Whether the file uses tracks or patches, so it can't be shown as one
+    /// plain pattern
+    pub fn has_tracks(&self) -> bool {
+        self.tracks.len() > 1
+            || self.default_patch.is_some()
+            || self.tracks.iter().any(|t| t.patch.is_some())
+    }
+
+    /// The patch a track plays with: its own, else the file's default
+    pub fn track_patch<'a>(&'a self, track: &'a Track) -> Option<&'a str> {
+        track.patch.as_deref().or(self.default_patch.as_deref())
+    }
+
+    /// A pattern holding `events` with this file's header settings and
+    /// the signature `changes` among them
+    fn pattern_of(&self, events: Vec<Event>, changes: &[MeterChange]) -> Pattern {
+        let computed: Beat = events.iter().map(event_duration).sum();
+        Pattern {
+            beats: if self.beats > Beat::ZERO { self.beats } else { computed },
+            loop_pattern: self.loop_pattern,
+            time_signature: self.time_signature,
+            meter: meter_map(self.time_signature, &events, changes),
+            default_octave: self.default_octave,
+            key: self.key,
+            events,
+        }
+    }
+
+    /// One track as a pattern of its own
+    pub fn track_pattern(&self, track: &Track) -> Pattern {
+        self.pattern_of(track.events.clone(), &track.meter_changes)
+    }
+
+    /// Every track's events one after another, as a single pattern
+    pub fn into_pattern(mut self) -> Pattern {
+        let mut events = Vec::new();
+        let mut changes = Vec::new();
+        for track in std::mem::take(&mut self.tracks) {
+            let start = events.len();
+            changes.extend(track.meter_changes.iter().map(|c| MeterChange {
+                event: c.event + start,
+                ..*c
+            }));
+            events.extend(track.events);
         }
+        self.pattern_of(events, &changes)
+    }
+}
+
+/// The meter of `events` starting in `signature`, with `changes` at their
+/// events' beats
+fn meter_map(signature: (u8, u8), events: &[Event], changes: &[MeterChange]) -> MeterMap {
+    let beats = beat_positions(events);
+    let end: Beat = events.iter().map(event_duration).sum();
+    let mut map = MeterMap::new(signature);
+    for change in changes {
+        let beat = beats.get(change.event).copied().unwrap_or(end);
+        map.change(beat.as_f64(), change.signature);
     }
+    map
 }
 
 /// A note pattern: a fixed number of beats (e.g. one bar) that can be repeated in a song.
@@ -113,28 +946,497 @@ impl Composition {
 #[derive(Debug, Clone)]
 pub struct Pattern {
     /// Length of this pattern in beats. If None, computed from events.
-    pub beats: f64,
+    pub beats: Beat,
     /// Whether this pattern loops when used in a song (for display/editor use; playback uses song's repeat counts).
     pub loop_pattern: bool,
+    /// Opening time signature
     pub time_signature: (u8, u8),
+    /// Where bars fall, with any `time_signature:` changes partway through
+    pub meter: MeterMap,
     pub default_octave: u8,
+    /// `key:` directive, which enables scale-degree notes
+    pub key: Option<Key>,
     pub events: Vec<Event>,
 }
 
 impl Pattern {
+    /// `beats` of silence, standing in for a pattern file that's missing
+    pub fn silence(beats: Beat) -> Pattern {
+        Pattern {
+            beats,
+            loop_pattern: false,
+            time_signature: (4, 4),
+            meter: MeterMap::new((4, 4)),
+            default_octave: 4,
+            key: None,
+            events: Vec::new(),
+        }
+    }
+
     /// Total beats of the pattern (sum of event durations)
-    pub fn computed_beats(&self) -> f64 {
+    pub fn computed_beats(&self) -> Beat {
         self.events.iter().map(event_duration).sum()
     }
 
     /// Effective pattern length in beats (explicit if set and positive, else computed)
-    pub fn length_beats(&self) -> f64 {
-        if self.beats > 0.0 {
+    pub fn length_beats(&self) -> Beat {
+        if self.beats > Beat::ZERO {
             self.beats
         } else {
             self.computed_beats()
         }
     }
+
+    /// Every note with its start beat and length (see `timeline`)
+    pub fn to_timeline(&self) -> Vec<(Beat, Beat, NoteEvent)> {
+        timeline(&self.events)
+    }
+
+    /// The phrase `clidaw audition` plays, in 4/4 starting at C in `octave`:
+    /// a staccato scale up (attack and release with little sustain), a legato
+    /// scale down, a held chord, a long note that settles on the sustain
+    /// level, then a bar of rest for the release to ring out.
+    pub fn audition(octave: u8) -> Pattern {
+        let note = |semitones: u8| {
+            let midi = NoteName::C.to_midi(octave) + semitones;
+            NoteEvent {
+                note: NoteName::ALL[(midi % 12) as usize],
+                octave: midi / 12 - 1,
+                degree: None,
+                velocity: 1.0,
+                spelling: Spelling::Sharp,
+                chance: 1.0,
+            }
+        };
+        const MAJOR: [u8; 8] = [0, 2, 4, 5, 7, 9, 11, 12];
+        let mut events = Vec::new();
+        for step in MAJOR {
+            let quarter = Beat::ratio(1, 4);
+            events.extend([Event::Note(note(step), quarter), Event::Rest(quarter)]);
+        }
+        events.push(Event::BarLine);
+        for step in MAJOR.into_iter().rev() {
+            events.push(Event::Note(note(step), Beat::ratio(1, 2)));
+        }
+        events.push(Event::BarLine);
+        let chord = [0, 4, 7].map(note).to_vec();
+        let chord = Event::Chord(chord, Beat::whole(3));
+        events.extend([chord, Event::Rest(Beat::ONE), Event::BarLine]);
+        let whole = Beat::whole(4);
+        events.extend([Event::Note(note(0), whole), Event::BarLine, Event::Rest(whole)]);
+        let signature = (4, 4);
+        Pattern {
+            beats: Beat::ZERO,
+            loop_pattern: false,
+            time_signature: signature,
+            meter: MeterMap::new(signature),
+            default_octave: octave,
+            key: None,
+            events,
+        }
+    }
+}
+
+// JSON serialization (used by `clidaw parse --format json`). Field names are
+// part of the output format; keep them stable.
+
+impl Serialize for NoteName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+fn write_note_fields<M: SerializeMap>(map: &mut M, n: &NoteEvent) -> Result<(), M::Error> {
+    if let Some(degree) = n.degree {
+        map.serialize_entry("degree", &degree.to_string())?;
+    }
+    map.serialize_entry("note", n.note.spelled(n.spelling))?;
+    map.serialize_entry("octave", &n.octave)?;
+    map.serialize_entry("midi", &n.note.to_midi(n.octave))?;
+    map.serialize_entry("freq", &n.note.to_freq(n.octave))?;
+    map.serialize_entry("velocity", &n.velocity)?;
+    if n.chance < 1.0 {
+        map.serialize_entry("chance", &n.chance)?;
+    }
+    Ok(())
+}
+
+fn write_event_fields<M: SerializeMap>(map: &mut M, event: &Event) -> Result<(), M::Error> {
+    let kind = match event {
+        Event::Note(..) => "note",
+        Event::Chord(..) => "chord",
+        Event::Drums(..) => "drums",
+        Event::Rest(_) => "rest",
+        Event::Tie(_) => "tie",
+        Event::BarLine => "bar",
+        Event::Alt(_) => "alt",
+    };
+    map.serialize_entry("type", kind)?;
+    map.serialize_entry("duration", &event_duration(event).as_f64())?;
+    match event {
+        Event::Note(n, _) => write_note_fields(map, n),
+        Event::Chord(notes, _) => map.serialize_entry("notes", notes),
+        Event::Drums(drums, _) => map.serialize_entry("drums", drums),
+        Event::Rest(_) | Event::Tie(_) | Event::BarLine => Ok(()),
+        Event::Alt(alt) => {
+            map.serialize_entry("random", &alt.random)?;
+            let choices: Vec<_> = alt.choices.iter().map(|c| positioned(c)).collect();
+            map.serialize_entry("choices", &choices)
+        }
+    }
+}
+
+impl Serialize for Drum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Drum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Drum::from_name(&name)
+            .ok_or_else(|| de::Error::custom(format!("unknown drum '{}'", name)))
+    }
+}
+
+impl Serialize for NoteEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        write_note_fields(&mut map, self)?;
+        map.end()
+    }
+}
+
+impl Serialize for Event {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        write_event_fields(&mut map, self)?;
+        map.end()
+    }
+}
+
+/// An event with its start beat, as it appears in serialized event lists.
+struct PositionedEvent<'a> {
+    beat: Beat,
+    event: &'a Event,
+}
+
+impl Serialize for PositionedEvent<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("beat", &self.beat.as_f64())?;
+        write_event_fields(&mut map, self.event)?;
+        map.end()
+    }
+}
+
+fn positioned(events: &[Event]) -> Vec<PositionedEvent<'_>> {
+    beat_positions(events)
+        .into_iter()
+        .zip(events)
+        .map(|(beat, event)| PositionedEvent { beat, event })
+        .collect()
+}
+
+impl Serialize for Track {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(4))?;
+        map.serialize_entry("name", &self.name)?;
+        map.serialize_entry("patch", &self.patch)?;
+        map.serialize_entry("octave", &self.octave)?;
+        map.serialize_entry("events", &positioned(&self.events))?;
+        map.end()
+    }
+}
+
+impl Serialize for Composition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        // Whole tempos stay integers, as they were before fractional ones
+        if self.tempo.fract() == 0.0 {
+            map.serialize_entry("tempo", &(self.tempo as u64))?;
+        } else {
+            map.serialize_entry("tempo", &self.tempo)?;
+        }
+        if self.beats > Beat::ZERO {
+            map.serialize_entry("beats", &self.beats.as_f64())?;
+        }
+        if self.loop_pattern {
+            map.serialize_entry("loop", &self.loop_pattern)?;
+        }
+        map.serialize_entry("time_signature", &self.time_signature)?;
+        map.serialize_entry("octave", &self.default_octave)?;
+        if let Some(key) = &self.key {
+            map.serialize_entry("key", &key.to_string())?;
+        }
+        map.serialize_entry("patch", &self.default_patch)?;
+        for (key, value) in self.metadata.entries() {
+            map.serialize_entry(key, value)?;
+        }
+        map.serialize_entry("tracks", &self.tracks)?;
+        map.end()
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("beats", &self.length_beats().as_f64())?;
+        map.serialize_entry("loop", &self.loop_pattern)?;
+        map.serialize_entry("time_signature", &self.time_signature)?;
+        map.serialize_entry("octave", &self.default_octave)?;
+        if let Some(key) = &self.key {
+            map.serialize_entry("key", &key.to_string())?;
+        }
+        map.serialize_entry("events", &positioned(&self.events))?;
+        map.end()
+    }
+}
+
+// JSON deserialization (`clidaw play --json`): either shape above read back
+// as a Composition, a pattern's `events` becoming its one track. Event
+// positions, MIDI numbers and frequencies are worked out again rather than
+// read, except that a note may be given by `midi` alone.
+
+#[derive(serde::Deserialize)]
+struct NoteJson {
+    note: Option<String>,
+    #[serde(default, deserialize_with = "octave_from_json")]
+    octave: Option<u8>,
+    midi: Option<u8>,
+    degree: Option<String>,
+    velocity: Option<f64>,
+    chance: Option<f64>,
+}
+
+impl<'de> Deserialize<'de> for NoteEvent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = NoteJson::deserialize(deserializer)?;
+        let (note, octave, spelling) = match (json.note, json.octave, json.midi) {
+            (Some(name), Some(octave), _) => {
+                let (note, written) = NoteName::from_written(&name)
+                    .ok_or_else(|| de::Error::custom(format!("unknown note '{}'", name)))?;
+                (note, octave, written.unwrap_or_default())
+            }
+            (_, _, Some(midi)) if (12..=119).contains(&midi) => {
+                (NoteName::ALL[midi as usize % 12], midi / 12 - 1, Spelling::Sharp)
+            }
+            (_, _, Some(midi)) => {
+                return Err(de::Error::custom(format!("midi {} is out of range 12-119", midi)));
+            }
+            _ => return Err(de::Error::custom("a note needs 'note' and 'octave', or 'midi'")),
+        };
+        let velocity = json.velocity.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&velocity) {
+            return Err(de::Error::custom(format!("velocity {} is out of range 0-1", velocity)));
+        }
+        let chance = json.chance.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&chance) {
+            return Err(de::Error::custom(format!("chance {} is out of range 0-1", chance)));
+        }
+        let degree = match json.degree {
+            Some(text) => Some(
+                Degree::parse(&text)
+                    .ok_or_else(|| de::Error::custom(format!("invalid degree '{}'", text)))?,
+            ),
+            None => None,
+        };
+        Ok(NoteEvent {
+            note,
+            octave,
+            degree,
+            velocity,
+            spelling,
+            chance,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum EventJson {
+    Note {
+        #[serde(deserialize_with = "length_from_json")]
+        duration: Beat,
+        #[serde(flatten)]
+        note: NoteEvent,
+    },
+    Chord {
+        #[serde(deserialize_with = "length_from_json")]
+        duration: Beat,
+        notes: Vec<NoteEvent>,
+    },
+    Drums {
+        #[serde(default = "one_beat", deserialize_with = "length_from_json")]
+        duration: Beat,
+        drums: Vec<Drum>,
+    },
+    Rest {
+        #[serde(deserialize_with = "length_from_json")]
+        duration: Beat,
+    },
+    Tie {
+        #[serde(deserialize_with = "length_from_json")]
+        duration: Beat,
+    },
+    Bar,
+    Alt {
+        #[serde(default)]
+        random: bool,
+        choices: Vec<Vec<Event>>,
+    },
+}
+
+impl<'de> Deserialize<'de> for Event {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match EventJson::deserialize(deserializer)? {
+            EventJson::Note { duration, note } => Event::Note(note, duration),
+            EventJson::Chord { duration, notes } => Event::Chord(notes, duration),
+            EventJson::Drums { duration, drums } => Event::Drums(drums, duration),
+            EventJson::Rest { duration } => Event::Rest(duration),
+            EventJson::Tie { duration } => Event::Tie(duration),
+            EventJson::Bar => Event::BarLine,
+            EventJson::Alt { random, choices } => {
+                if choices.len() < 2 {
+                    return Err(de::Error::custom("an alt needs at least two choices"));
+                }
+                let nested =
+                    |e: &Event| matches!(e, Event::Alt(_) | Event::Drums(..) | Event::BarLine);
+                if choices.iter().flatten().any(nested) {
+                    return Err(de::Error::custom("an alt's choices hold only notes and rests"));
+                }
+                Event::Alt(Alternatives { choices, random })
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Key::parse(&text).ok_or_else(|| de::Error::custom(format!("invalid key '{}'", text)))
+    }
+}
+
+/// Length of a drum step written before steps had one
+fn one_beat() -> Beat {
+    Beat::ONE
+}
+
+/// A length in beats above 0
+fn length_from_json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Beat, D::Error> {
+    let beats = f64::deserialize(deserializer)?;
+    if !(beats > 0.0 && beats.is_finite()) {
+        return Err(de::Error::custom(format!("invalid length {} (expected beats above 0)", beats)));
+    }
+    Ok(Beat::from_f64(beats))
+}
+
+fn octave_from_json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+    let octave = u8::deserialize(deserializer)?;
+    if octave > 8 {
+        return Err(de::Error::custom(format!("octave {} is out of range 0-8", octave)));
+    }
+    Ok(Some(octave))
+}
+
+fn tempo_from_json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let tempo = f64::deserialize(deserializer)?;
+    if !(tempo > 0.0 && tempo.is_finite()) {
+        return Err(de::Error::custom(format!("invalid tempo {}", tempo)));
+    }
+    Ok(Some(tempo))
+}
+
+fn time_signature_from_json<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<(u8, u8)>, D::Error> {
+    let (num, den) = <(u8, u8)>::deserialize(deserializer)?;
+    if num == 0 || den == 0 {
+        return Err(de::Error::custom("invalid time_signature (expected [beats, unit])"));
+    }
+    Ok(Some((num, den)))
+}
+
+#[derive(serde::Deserialize)]
+struct TrackJson {
+    name: Option<String>,
+    patch: Option<String>,
+    #[serde(default, deserialize_with = "octave_from_json")]
+    octave: Option<u8>,
+    events: Vec<Event>,
+}
+
+#[derive(serde::Deserialize)]
+struct CompositionJson {
+    #[serde(default, deserialize_with = "tempo_from_json")]
+    tempo: Option<f64>,
+    /// A pattern's length, which an explicit `beats:` sets
+    #[serde(default)]
+    beats: f64,
+    #[serde(rename = "loop", default)]
+    loop_pattern: bool,
+    #[serde(default, deserialize_with = "time_signature_from_json")]
+    time_signature: Option<(u8, u8)>,
+    #[serde(default, deserialize_with = "octave_from_json")]
+    octave: Option<u8>,
+    key: Option<Key>,
+    patch: Option<String>,
+    title: Option<String>,
+    author: Option<String>,
+    description: Option<String>,
+    tracks: Option<Vec<TrackJson>>,
+    events: Option<Vec<Event>>,
+}
+
+impl<'de> Deserialize<'de> for Composition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = CompositionJson::deserialize(deserializer)?;
+        let mut comp = Composition::new();
+        comp.tempo = json.tempo.unwrap_or(comp.tempo);
+        if json.beats > 0.0 && json.beats.is_finite() {
+            comp.beats = Beat::from_f64(json.beats);
+        }
+        comp.loop_pattern = json.loop_pattern;
+        comp.time_signature = json.time_signature.unwrap_or(comp.time_signature);
+        comp.default_octave = json.octave.unwrap_or(comp.default_octave);
+        comp.key = json.key;
+        comp.default_patch = json.patch;
+        comp.metadata = Metadata {
+            title: json.title,
+            author: json.author,
+            description: json.description,
+        };
+        let track = |name: Option<String>, patch, octave: Option<u8>, events| Track {
+            name: name.unwrap_or_else(|| "default".to_string()),
+            patch,
+            octave: octave.unwrap_or(comp.default_octave),
+            events,
+            includes: Vec::new(),
+            meter_changes: Vec::new(),
+        };
+        comp.tracks = match (json.tracks, json.events) {
+            (Some(tracks), None) => tracks
+                .into_iter()
+                .map(|t| track(t.name, t.patch, t.octave, t.events))
+                .collect(),
+            (None, Some(events)) => vec![track(None, None, None, events)],
+            _ => {
+                return Err(de::Error::custom(
+                    "expected 'events' (a pattern) or 'tracks' (a composition), not both",
+                ));
+            }
+        };
+        // Naturals are spelled as the key spells, as when parsed
+        let spelling = comp.key.map_or(Spelling::Sharp, |key| key.spelling);
+        for note in comp.tracks.iter_mut().flat_map(|track| all_notes_mut(&mut track.events)) {
+            if !note.note.name().ends_with('#') {
+                note.spelling = spelling;
+            }
+        }
+        Ok(comp)
+    }
 }
 
 #[cfg(test)]
@@ -157,4 +1459,426 @@ mod tests {
         assert_eq!(NoteName::C.semitone(), 0);
         assert_eq!(NoteName::B.semitone(), 11);
     }
+
+    #[test]
+    fn test_flat_names_and_keys() {
+        assert_eq!(NoteName::from_written("Bb"), Some((NoteName::ASharp, Some(Spelling::Flat))));
+        assert_eq!(NoteName::from_written("e♭"), Some((NoteName::DSharp, Some(Spelling::Flat))));
+        assert_eq!(NoteName::from_written("F♯"), Some((NoteName::FSharp, Some(Spelling::Sharp))));
+        assert_eq!(NoteName::from_written("G"), Some((NoteName::G, None)));
+        assert_eq!(NoteName::from_written("Cb"), None);
+        assert_eq!(NoteName::from_name("ab"), Some(NoteName::GSharp));
+        assert_eq!(NoteName::GSharp.spelled(Spelling::Flat), "Ab");
+        assert_eq!(NoteName::E.spelled(Spelling::Flat), "E");
+        assert_eq!(midi_name(70, Spelling::Flat), "Bb4");
+        assert_eq!(midi_from_name("Bb4"), Some(70));
+
+        let key = |text: &str| Key::parse(text).unwrap();
+        assert_eq!(key("Bb major").to_string(), "Bb major");
+        assert_eq!(key("A# major").to_string(), "A# major");
+        assert_eq!(key("F major").spelling, Spelling::Flat);
+        assert_eq!(key("G minor").spelling, Spelling::Flat);
+        assert_eq!(key("E minor").spelling, Spelling::Sharp);
+        let fourth = Degree {
+            number: 4,
+            accidental: 0,
+        };
+        assert_eq!(key("F major").resolve(fourth, 4).unwrap().label(), "Bb4");
+    }
+
+    #[test]
+    fn test_modes() {
+        assert_eq!(Scale::from_name("Natural  Minor"), Some(Scale::NaturalMinor));
+        assert_eq!(Scale::from_name("aeolian"), Some(Scale::NaturalMinor));
+        assert_eq!(Scale::from_name("ionian"), Some(Scale::Major));
+        assert_eq!(Scale::from_name("blues"), None);
+        let key = |text: &str| Key::parse(text).unwrap();
+        // Every mode of C major has C major's notes
+        let semitones = |key: Key| {
+            let mut semitones = key.notes().map(NoteName::semitone);
+            semitones.sort();
+            semitones
+        };
+        let white = semitones(key("C major"));
+        for mode in ["D dorian", "E phrygian", "F lydian", "G mixolydian", "A minor", "B locrian"] {
+            assert_eq!(semitones(key(mode)), white, "{}", mode);
+            assert_eq!(key(mode).notes()[0], key(mode).tonic);
+            assert_eq!(key(mode).spelling, Spelling::Sharp, "{}", mode);
+        }
+        assert_eq!(key("G dorian").spelling, Spelling::Flat);
+        assert_eq!(key("C mixolydian").spelling, Spelling::Flat);
+        assert_eq!(key("E lydian").spelling, Spelling::Sharp);
+        assert!(key("D dorian").contains(NoteName::B));
+        assert!(!key("D minor").contains(NoteName::B));
+    }
+
+    #[test]
+    fn test_beat_positions() {
+        let events = vec![
+            Event::Rest(Beat::whole(2)),
+            Event::BarLine,
+            Event::Note(NoteEvent {
+                note: NoteName::C,
+                octave: 4,
+                degree: None,
+                velocity: 1.0,
+                spelling: Spelling::Sharp,
+                chance: 1.0,
+            }, Beat::ONE),
+            Event::Rest(Beat::ONE),
+        ];
+        let positions: Vec<f64> = beat_positions(&events).into_iter().map(Beat::as_f64).collect();
+        assert_eq!(positions, vec![0.0, 2.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_beat_positions_with_fractional_lengths() {
+        let note = NoteEvent {
+            note: NoteName::C,
+            octave: 4,
+            degree: None,
+            velocity: 1.0,
+            spelling: Spelling::Sharp,
+            chance: 1.0,
+        };
+        let events = vec![
+            Event::Note(note.clone(), Beat::ratio(3, 2)),
+            Event::Rest(Beat::ratio(1, 3)),
+            Event::Note(note, Beat::ratio(2, 3)),
+            Event::Rest(Beat::ONE),
+        ];
+        let positions: Vec<f64> = beat_positions(&events).into_iter().map(Beat::as_f64).collect();
+        assert_eq!(positions, vec![0.0, 1.5, 1.5 + 1.0 / 3.0, 2.5]);
+    }
+
+    #[test]
+    fn test_event_positions_follow_the_meter() {
+        let note = |beats| {
+            let c = NoteEvent {
+                note: NoteName::C,
+                octave: 4,
+                degree: None,
+                velocity: 1.0,
+                spelling: Spelling::Sharp,
+                chance: 1.0,
+            };
+            Event::Note(c, beats)
+        };
+        let events = vec![
+            note(Beat::whole(3)),
+            Event::BarLine,
+            note(Beat::ratio(1, 2)),
+            note(Beat::ratio(5, 2)),
+            note(Beat::ONE),
+        ];
+        // A bar of 3/4, then 2/4
+        let mut meter = MeterMap::new((3, 4));
+        meter.change(3.0, (2, 4));
+        let positions = event_positions(&events, &meter);
+        let labels: Vec<String> = positions.iter().map(Position::to_string).collect();
+        assert_eq!(labels, ["1:1", "2:1", "2:1", "2:1.5", "3:2"]);
+        assert_eq!(positions[4].beat, Beat::whole(6));
+    }
+
+    #[test]
+    fn test_timeline_spreads_chords_and_follows_ties() {
+        let audition = Pattern::audition(4).to_timeline();
+        let midi = |i: usize| {
+            let (start, length, n) = &audition[i];
+            (start.as_f64(), length.as_f64(), n.note.to_midi(n.octave))
+        };
+        assert_eq!(audition.len(), 20);
+        assert_eq!(midi(1), (0.5, 0.25, 62));
+        // The chord is three notes at once
+        let chord: Vec<_> = (16..19).map(midi).collect();
+        assert_eq!(chord, [(8.0, 3.0, 60), (8.0, 3.0, 64), (8.0, 3.0, 67)]);
+        assert_eq!(midi(19), (12.0, 4.0, 60));
+
+        let c4 = NoteEvent {
+            note: NoteName::C,
+            octave: 4,
+            degree: None,
+            velocity: 1.0,
+            spelling: Spelling::Sharp,
+            chance: 1.0,
+        };
+        let half = Beat::ratio(1, 2);
+        let events = vec![Event::Note(c4.clone(), Beat::ONE), Event::BarLine, Event::Tie(half)];
+        assert_eq!(timeline(&events), vec![(Beat::ZERO, Beat::ratio(3, 2), c4)]);
+    }
+
+    #[test]
+    fn test_audition_phrase() {
+        let pattern = Pattern::audition(4);
+        assert_eq!(pattern.length_beats(), Beat::whole(20));
+        let notes: Vec<(u8, f64)> = pattern
+            .events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Note(n, beats) => Some((n.note.to_midi(n.octave), beats.as_f64())),
+                _ => None,
+            })
+            .collect();
+        // Staccato up from middle C, legato back down, then a long C
+        assert_eq!(notes[0], (60, 0.25));
+        assert_eq!(notes[7], (72, 0.25));
+        assert_eq!(notes[8], (72, 0.5));
+        assert_eq!(notes[16], (60, 4.0));
+        let chords: Vec<_> = pattern
+            .events
+            .iter()
+            .filter(|e| matches!(e, Event::Chord(notes, _) if notes.len() == 3))
+            .collect();
+        assert_eq!(chords.len(), 1);
+    }
+
+    #[test]
+    fn test_pattern_json_shape() {
+        let pattern = Pattern {
+            beats: Beat::ZERO,
+            loop_pattern: true,
+            time_signature: (3, 4),
+            meter: MeterMap::new((3, 4)),
+            default_octave: 4,
+            key: None,
+            events: vec![
+                Event::Note(NoteEvent {
+                    note: NoteName::A,
+                    octave: 4,
+                    degree: None,
+                    velocity: 1.0,
+                    spelling: Spelling::Sharp,
+                    chance: 1.0,
+                }, Beat::ONE),
+                Event::Rest(Beat::ONE),
+                Event::BarLine,
+                Event::Chord(vec![
+                    NoteEvent {
+                        note: NoteName::A,
+                        octave: 3,
+                        degree: None,
+                        velocity: 1.0,
+                        spelling: Spelling::Sharp,
+                        chance: 1.0,
+                    },
+                    NoteEvent {
+                        note: NoteName::CSharp,
+                        octave: 4,
+                        degree: None,
+                        velocity: 1.0,
+                        spelling: Spelling::Flat,
+                        chance: 1.0,
+                    },
+                ], Beat::ONE),
+            ],
+        };
+        let json = serde_json::to_string(&pattern).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"beats":3.0,"loop":true,"time_signature":[3,4],"octave":4,"events":["#,
+                r#"{"beat":0.0,"type":"note","duration":1.0,"note":"A","octave":4,"midi":69,"#,
+                r#""freq":440.0,"velocity":1.0},"#,
+                r#"{"beat":1.0,"type":"rest","duration":1.0},"#,
+                r#"{"beat":2.0,"type":"bar","duration":0.0},"#,
+                r#"{"beat":2.0,"type":"chord","duration":1.0,"notes":["#,
+                r#"{"note":"A","octave":3,"midi":57,"freq":220.0,"velocity":1.0},"#,
+                r#"{"note":"Db","octave":4,"midi":61,"freq":277.1826309768721,"velocity":1.0}]}]}"#
+            )
+        );
+    }
+
+    fn melody() -> Vec<Event> {
+        let note = |name, octave, velocity| NoteEvent {
+            note: name,
+            octave,
+            degree: None,
+            velocity,
+            spelling: Spelling::Sharp,
+            chance: 1.0,
+        };
+        vec![
+            Event::Note(note(NoteName::C, 4, 1.0), Beat::ONE),
+            Event::Chord(
+                vec![note(NoteName::E, 4, 0.7), note(NoteName::G, 4, 0.7)],
+                Beat::ratio(1, 2),
+            ),
+            Event::Tie(Beat::ratio(1, 2)),
+            Event::BarLine,
+            Event::Tie(Beat::ratio(1, 3)),
+            Event::Rest(Beat::ratio(2, 3)),
+            Event::Note(note(NoteName::B, 7, 0.4), Beat::whole(2)),
+            Event::BarLine,
+        ]
+    }
+
+    #[test]
+    fn test_transpose_and_invert() {
+        let mut events = melody();
+        assert_eq!(transpose(&mut events, 7), 0);
+        assert_eq!(events[0].notes()[0].note, NoteName::G);
+        assert_eq!(transpose(&mut events, -7), 0);
+        assert_eq!(events, melody());
+
+        // B7 up two octaves is past B8: it stays a B, in octave 8
+        assert_eq!(transpose(&mut events, 24), 1);
+        assert_eq!((events[6].notes()[0].note, events[6].notes()[0].octave), (NoteName::B, 8));
+        assert_eq!(events[0].notes()[0].octave, 6);
+
+        // Around E4, C4 and G#4 trade places, and B7 drops to A0
+        let mut events = melody();
+        assert_eq!(invert(&mut events, midi_from_name("E4").unwrap()), 0);
+        assert_eq!(events[0].notes()[0].note, NoteName::GSharp);
+        assert_eq!(events[1].notes()[1].note, NoteName::CSharp);
+        assert_eq!((events[6].notes()[0].note, events[6].notes()[0].octave), (NoteName::A, 0));
+        assert_eq!(invert(&mut events, 64), 0);
+        assert_eq!(events, melody());
+        // Around C2, C4 lands on C0 and the rest below it: B7 becomes C#0
+        assert_eq!(invert(&mut events, midi_from_name("C2").unwrap()), 3);
+        assert_eq!((events[6].notes()[0].note, events[6].notes()[0].octave), (NoteName::CSharp, 0));
+        assert_eq!(midi_from_name("f#3"), Some(54));
+        assert_eq!(midi_from_name("C9"), None);
+        assert_eq!(midi_from_name("H4"), None);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut events = melody();
+        reverse(&mut events);
+        let starts = beat_positions(&events);
+        // The long B comes first; the chord's two ties swap lengths around
+        // the bar line, so it still falls 1/3 beat after the chord starts
+        assert_eq!(events[0], melody()[6]);
+        assert_eq!(events[2], Event::Chord(melody()[1].notes().to_vec(), Beat::ratio(1, 3)));
+        assert_eq!(events[3], Event::BarLine);
+        assert_eq!(starts[3], Beat::whole(2) + Beat::ratio(2, 3) + Beat::ratio(1, 3));
+        assert_eq!(events[4], Event::Tie(Beat::ratio(1, 2)));
+        assert_eq!(events.last(), Some(&Event::BarLine));
+        let total: Beat = events.iter().map(event_duration).sum();
+        assert_eq!(total, melody().iter().map(event_duration).sum());
+        reverse(&mut events);
+        assert_eq!(events, melody());
+    }
+
+    #[test]
+    fn test_stretch() {
+        let mut events = melody();
+        assert!(stretch(&mut events, 2.0));
+        assert_eq!(events[1], Event::Chord(melody()[1].notes().to_vec(), Beat::ONE));
+        assert_eq!(events[4], Event::Tie(Beat::ratio(2, 3)));
+        assert!(stretch(&mut events, 0.5));
+        assert_eq!(events, melody());
+
+        // Drum steps stay a beat, with a rest making up the difference
+        let mut drums = vec![Event::Drums(vec![Drum::Kick], Beat::ONE), Event::Rest(Beat::ONE)];
+        assert!(!stretch(&mut drums, 0.5));
+        assert!(stretch(&mut drums, 1.5));
+        assert_eq!(drums[1], Event::Rest(Beat::ratio(1, 2)));
+        assert_eq!(drums[2], Event::Rest(Beat::ratio(3, 2)));
+    }
+
+    #[test]
+    fn test_alternatives_transform_each_choice() {
+        use crate::parser::parse_pattern;
+        let choices = |event: &Event| -> Vec<Vec<Event>> {
+            match event {
+                Event::Alt(alt) => alt.choices.clone(),
+                other => panic!("expected alt, got {:?}", other),
+            }
+        };
+        let events = parse_pattern("a alt{a s | d}").unwrap().events;
+        assert!(is_generative(&events));
+        assert_eq!(event_duration(&events[1]), Beat::whole(2));
+
+        let mut transposed = events.clone();
+        transpose(&mut transposed, 2);
+        let labels: Vec<String> = choices(&transposed[1])
+            .iter()
+            .flatten()
+            .flat_map(Event::notes)
+            .map(|n| n.label())
+            .collect();
+        assert_eq!(labels, ["D4", "E4", "F#4"]);
+
+        // A shorter choice keeps its silence at the end of the group
+        let mut reversed = events.clone();
+        reverse(&mut reversed);
+        let reversed = choices(&reversed[0]);
+        let forward = choices(&events[1]);
+        assert_eq!(reversed[0], forward[0].iter().rev().cloned().collect::<Vec<_>>());
+        assert_eq!(reversed[1][0], Event::Rest(Beat::ONE));
+
+        let mut stretched = events.clone();
+        assert!(stretch(&mut stretched, 2.0));
+        assert_eq!(event_duration(&stretched[1]), Beat::whole(4));
+        assert_eq!(timeline(&stretched).len(), 4);
+    }
+
+    #[test]
+    fn test_composition_json_shape() {
+        let mut comp = Composition::new();
+        comp.default_patch = Some("lead.instr".to_string());
+        comp.tracks.push(Track {
+            name: "bass".to_string(),
+            patch: None,
+            octave: 2,
+            events: vec![Event::Rest(Beat::whole(2))],
+            includes: Vec::new(),
+            meter_changes: Vec::new(),
+        });
+        let json = serde_json::to_string(&comp).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"tempo":120,"time_signature":[4,4],"octave":4,"patch":"lead.instr","tracks":["#,
+                r#"{"name":"bass","patch":null,"octave":2,"events":["#,
+                r#"{"beat":0.0,"type":"rest","duration":2.0}]}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_json_reads_back_what_it_writes() {
+        use crate::parser::parse;
+        let text = "tempo: 90\ntime_signature: 3/4\nkey: F major\ntitle: Round trip\n\
+                    [track: lead]\npatch: pluck\n1 b3:0.5 _:0.5 - [a d]! 5?0.3 |\n\
+                    alt?{1 | 2:2 3} alt{1: | 2: !p! 4?} |\n\
+                    [track: drums]\nkick:  x - x\nhat:   x x x\n";
+        let comp = parse(text).unwrap();
+        let json = serde_json::to_string(&comp).unwrap();
+        let read: Composition = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), json);
+        assert_eq!(read.tracks[0].events, comp.tracks[0].events);
+        assert_eq!(read.tracks[1].events, comp.tracks[1].events);
+        assert_eq!(read.key, comp.key);
+        assert_eq!(read.metadata.title.as_deref(), Some("Round trip"));
+
+        // A pattern's events become one track
+        let pattern = parse("octave: 3\nloop: true\na s d").unwrap();
+        let json = serde_json::to_string(&pattern.clone().into_pattern()).unwrap();
+        let read: Composition = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.tracks[0].events, pattern.tracks[0].events);
+        assert!(read.loop_pattern);
+        assert_eq!(read.into_pattern().length_beats(), Beat::whole(3));
+    }
+
+    #[test]
+    fn test_json_notes_by_midi_and_bad_input() {
+        let json = r#"{"events":[{"type":"note","midi":70,"duration":0.5,"velocity":0.6}]}"#;
+        let comp: Composition = serde_json::from_str(json).unwrap();
+        let Event::Note(note, length) = &comp.tracks[0].events[0] else {
+            panic!("expected a note");
+        };
+        assert_eq!((note.note, note.octave, note.velocity), (NoteName::ASharp, 4, 0.6));
+        assert_eq!(*length, Beat::ratio(1, 2));
+
+        let error = |json: &str| serde_json::from_str::<Composition>(json).unwrap_err().to_string();
+        assert!(error(r#"{"events":[{"type":"note","duration":1}]}"#)
+            .starts_with("a note needs 'note' and 'octave', or 'midi'"));
+        assert!(error(r#"{"events":[{"type":"rest","duration":0}]}"#)
+            .starts_with("invalid length 0"));
+        assert!(error(r#"{"events":[{"type":"hum"}]}"#).starts_with("unknown variant `hum`"));
+        assert!(error(r#"{"key":"D blues","events":[]}"#).starts_with("invalid key 'D blues'"));
+        assert!(error(r#"{"tempo":120}"#).starts_with("expected 'events'"));
+    }
 }
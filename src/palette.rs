@@ -0,0 +1,122 @@
+//! Live mode's command line: `:` opens it (note keys stop playing), Enter
+//! runs what was typed and Esc closes it again.
+//!
+//! A command is a name and at most one argument, separated by spaces. Names
+//! can be cut short to any prefix that picks out one command (`:q`, `:oct
+//! 3`). What the commands do is up to `repl`; this module only reads them.
+
+use std::path::PathBuf;
+
+use crate::tempo;
+
+/// Every command, with its argument as shown in `:help`
+pub const COMMANDS: [(&str, &str); 6] = [
+    ("tempo", "BPM"),
+    ("instrument", "NAME"),
+    ("octave", "1-8"),
+    ("record", "[FILE.notes]"),
+    ("help", ""),
+    ("quit", ""),
+];
+
+/// A command typed after `:`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Metronome (and recording) tempo in BPM
+    Tempo(f64),
+    /// Play the `--instrument` with this name or number
+    Instrument(String),
+    Octave(u8),
+    /// Start a take to this file, or (None) finish the one going
+    Record(Option<PathBuf>),
+    /// List the commands
+    Help,
+    Quit,
+}
+
+/// The commands and their arguments on one line, for `:help`
+pub fn help() -> String {
+    let usage: Vec<String> = COMMANDS
+        .iter()
+        .map(|(name, arg)| format!(":{} {}", name, arg).trim_end().to_string())
+        .collect();
+    usage.join("  ")
+}
+
+/// Read a command line (without its `:`)
+pub fn parse(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let Some(word) = words.next() else {
+        return Err(format!("type a command: {}", help()));
+    };
+    let arg = words.next();
+    if words.next().is_some() {
+        return Err(format!("too many arguments for :{}", word));
+    }
+    let name = command_name(word)?;
+    let need = |arg: Option<&str>| {
+        let usage = COMMANDS.iter().find(|(n, _)| *n == name).map_or("", |(_, a)| *a);
+        arg.map(str::to_string).ok_or_else(|| format!("usage: :{} {}", name, usage))
+    };
+    let command = match name {
+        "tempo" => {
+            let text = need(arg)?;
+            Command::Tempo(tempo::parse(&text).ok_or_else(|| {
+                format!("invalid tempo '{}' (expected beats per minute above 0)", text)
+            })?)
+        }
+        "instrument" => Command::Instrument(need(arg)?),
+        "octave" => {
+            let text = need(arg)?;
+            let octave = text.parse().ok().filter(|o| (1..=8).contains(o));
+            Command::Octave(octave.ok_or_else(|| format!("invalid octave '{}' (1-8)", text))?)
+        }
+        "record" => Command::Record(arg.map(PathBuf::from)),
+        _ if arg.is_some() => return Err(format!(":{} takes no argument", name)),
+        "help" => Command::Help,
+        _ => Command::Quit,
+    };
+    Ok(command)
+}
+
+/// The command `word` names or starts, if only one
+fn command_name(word: &str) -> Result<&'static str, String> {
+    let word = word.to_ascii_lowercase();
+    let matches: Vec<&str> = COMMANDS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| name.starts_with(&word))
+        .collect();
+    match matches.as_slice() {
+        [name] => Ok(name),
+        [] => Err(format!("unknown command :{} ({})", word, help())),
+        _ => Err(format!(":{} could be :{}", word, matches.join(" or :"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_and_prefixes() {
+        assert_eq!(parse("tempo 100"), Ok(Command::Tempo(100.0)));
+        assert_eq!(parse("  oct 3 "), Ok(Command::Octave(3)));
+        assert_eq!(parse("i pad.instr"), Ok(Command::Instrument("pad.instr".to_string())));
+        assert_eq!(parse("record out.notes"), Ok(Command::Record(Some("out.notes".into()))));
+        assert_eq!(parse("rec"), Ok(Command::Record(None)));
+        assert_eq!(parse("q"), Ok(Command::Quit));
+        assert_eq!(parse("HELP"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn test_bad_commands_say_why() {
+        assert_eq!(parse("tempo"), Err("usage: :tempo BPM".to_string()));
+        assert!(parse("tempo fast").unwrap_err().starts_with("invalid tempo 'fast'"));
+        assert_eq!(parse("octave 9"), Err("invalid octave '9' (1-8)".to_string()));
+        assert_eq!(parse("quit now"), Err(":quit takes no argument".to_string()));
+        assert_eq!(parse("tempo 1 2"), Err("too many arguments for :tempo".to_string()));
+        assert!(parse("loop").unwrap_err().starts_with("unknown command :loop (:tempo BPM"));
+        assert!(parse("").unwrap_err().starts_with("type a command"));
+    }
+}
@@ -1,4 +1,13 @@
-use crate::note::{Composition, Event, NoteEvent, NoteName, Pattern, Track, event_duration};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::beat::Beat;
+use crate::error::ClidawError;
+use crate::note::{
+    event_duration, Alternatives, Composition, Degree, Drum, Event, Include, Key, MeterChange,
+    NoteEvent, NoteName, Pattern, Scale, Spelling, Track,
+};
+use crate::tempo;
 
 /// Map a keyboard character to a (NoteName, octave_offset) pair.
 /// The octave_offset indicates notes that spill into the next octave
@@ -31,126 +40,324 @@ pub fn char_to_note(c: char) -> Option<(NoteName, u8)> {
     }
 }
 
-/// Parse errors with location info
+/// Parse errors with location info. Columns count characters from 1 and
+/// are known for errors within a note or drum line. `parse_with` hands them
+/// on as `ClidawError::Parse`, with the file they came from.
 #[derive(Debug)]
 pub struct ParseError {
     pub line: usize,
+    pub column: Option<usize>,
     pub message: String,
 }
 
+impl ParseError {
+    fn into_error(self, file: Option<PathBuf>) -> ClidawError {
+        ClidawError::Parse {
+            file,
+            line: self.line,
+            col: self.column,
+            msg: self.message,
+        }
+    }
+}
+
+impl From<ParseError> for ClidawError {
+    fn from(e: ParseError) -> Self {
+        e.into_error(None)
+    }
+}
+
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "line {}: {}", self.line, self.message)
+        match self.column {
+            Some(column) => write!(f, "line {}, column {}: {}", self.line, column, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
     }
 }
 
-/// Parse a .notes file into a Pattern (one pattern = fixed beats, loop flag, single event list).
-pub fn parse_pattern(input: &str) -> Result<Pattern, ParseError> {
-    let mut beats: f64 = 0.0; // 0 = "compute from events"
-    let mut loop_pattern = false;
-    let mut time_signature = (4u8, 4u8);
-    let mut default_octave = 4u8;
-    let mut current_octave = 4u8;
-    let mut events: Vec<Event> = Vec::new();
-
-    for (line_idx, line) in input.lines().enumerate() {
-        let line_num = line_idx + 1;
-        let trimmed = line.trim();
+/// How forgiving the parser is, and where the text came from
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Unknown characters in note lines are errors instead of being skipped
+    /// (also turned on by a `strict: true` directive)
+    pub strict: bool,
+    /// The .notes file being parsed, named in errors. Relative paths the
+    /// file names (`.instr` patches) resolve against its directory; None
+    /// resolves them against the working directory, for text that isn't
+    /// from a file.
+    pub file: Option<PathBuf>,
+    /// The files whose `include:`s led to this one, outermost first
+    includes: Vec<PathBuf>,
+    /// Starting octave, when included: the including file's at that point
+    octave: Option<u8>,
+    /// Black keys are named with flats whatever the file's key (a song's
+    /// `prefer_flats: true`, for the patterns it plays)
+    pub prefer_flats: bool,
+}
 
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
+impl ParseOptions {
+    /// Options for parsing the file at `path`
+    pub fn for_file(path: &Path, strict: bool) -> Self {
+        Self {
+            strict,
+            file: Some(path.to_path_buf()),
+            ..Self::default()
         }
+    }
 
-        if let Some(value) = trimmed.strip_prefix("beats:") {
-            beats = value.trim().parse().map_err(|_| ParseError {
-                line: line_num,
-                message: format!("invalid beats: {}", value.trim()),
-            })?;
-            continue;
-        }
-        if let Some(value) = trimmed.strip_prefix("loop:") {
-            loop_pattern = value.trim().eq_ignore_ascii_case("true")
-                || value.trim().eq_ignore_ascii_case("1")
-                || value.trim().eq_ignore_ascii_case("yes");
-            continue;
+    /// A path named in the file, as seen from the working directory
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        match self.file.as_deref().and_then(Path::parent) {
+            Some(base) if path.is_relative() => base.join(path),
+            _ => path.to_path_buf(),
         }
-        if let Some(value) = trimmed.strip_prefix("time_signature:") {
-            let parts: Vec<&str> = value.trim().split('/').collect();
-            if parts.len() == 2 {
-                let num: u8 = parts[0].parse().map_err(|_| ParseError {
-                    line: line_num,
-                    message: "invalid time signature numerator".into(),
-                })?;
-                let den: u8 = parts[1].parse().map_err(|_| ParseError {
-                    line: line_num,
-                    message: "invalid time signature denominator".into(),
-                })?;
-                time_signature = (num, den);
-            }
-            continue;
-        }
-        if let Some(value) = trimmed.strip_prefix("octave:") {
-            let oct: u8 = value.trim().parse().map_err(|_| ParseError {
-                line: line_num,
-                message: format!("invalid octave: {}", value.trim()),
-            })?;
-            if oct > 8 {
-                return Err(ParseError {
-                    line: line_num,
-                    message: "octave must be 0-8".into(),
-                });
-            }
-            default_octave = oct;
-            current_octave = oct;
-            continue;
+    }
+}
+
+/// Whether a `patch:` value names an `.instr` file (by extension, or by
+/// having a directory part) rather than a built-in preset
+pub fn is_patch_file(patch: &str) -> bool {
+    let path = Path::new(patch);
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("instr"))
+        || path.components().count() > 1
+}
+
+/// The characters of one line, counting columns as they are read
+struct LineChars<'a> {
+    /// The text not read yet
+    rest: &'a str,
+    /// Column of the next character
+    column: usize,
+}
+
+impl<'a> LineChars<'a> {
+    /// `first_column` is the column of the text's first character in its line
+    fn new(text: &'a str, first_column: usize) -> Self {
+        Self {
+            rest: text,
+            column: first_column,
         }
+    }
 
-        // Track headers are ignored for pattern: one flat event list
-        if trimmed.starts_with("[track:") && trimmed.ends_with(']') {
-            current_octave = default_octave;
-            continue;
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    /// Read `prefix` if the text continues with it
+    fn eat(&mut self, prefix: &str) -> bool {
+        let Some(rest) = self.rest.strip_prefix(prefix) else {
+            return false;
+        };
+        self.rest = rest;
+        self.column += prefix.chars().count();
+        true
+    }
+}
+
+impl Iterator for LineChars<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        self.column += 1;
+        Some(c)
+    }
+}
+
+/// Whether a `loop:` or `strict:` value means yes
+fn is_true(value: &str) -> bool {
+    let value = value.trim();
+    value.eq_ignore_ascii_case("true") || value == "1" || value.eq_ignore_ascii_case("yes")
+}
+
+/// Consecutive drum lines (`kick: x - x -`), played in parallel.
+/// A blank line or any non-drum line ends the block.
+#[derive(Default)]
+struct DrumBlock {
+    lines: Vec<(Drum, Vec<bool>)>,
+}
+
+impl DrumBlock {
+    /// Try to read `trimmed` as a drum line. Returns Ok(false) if it isn't one.
+    fn push_line(
+        &mut self,
+        trimmed: &str,
+        first_column: usize,
+        line_num: usize,
+    ) -> Result<bool, ParseError> {
+        let Some((name, steps)) = trimmed.split_once(':') else {
+            return Ok(false);
+        };
+        let Some(drum) = Drum::from_name(name.trim()) else {
+            return Ok(false);
+        };
+        if self.lines.iter().any(|(d, _)| *d == drum) {
+            return Err(ParseError {
+                line: line_num,
+                column: None,
+                message: format!(
+                    "{} appears twice in one drum block (separate blocks with a blank line)",
+                    drum.name()
+                ),
+            });
         }
-        if trimmed.starts_with("patch:") {
-            continue;
+        let mut hits = Vec::new();
+        let steps_column = first_column + name.chars().count() + 1;
+        for (i, c) in steps.chars().enumerate() {
+            match c {
+                'x' | 'X' => hits.push(true),
+                '-' => hits.push(false),
+                ' ' | '\t' | '|' => {}
+                other => {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(steps_column + i),
+                        message: format!(
+                            "invalid drum step '{}' (use x for a hit, - for a rest)",
+                            other
+                        ),
+                    });
+                }
+            }
         }
+        self.lines.push((drum, hits));
+        Ok(true)
+    }
 
-        let line_events = parse_line(trimmed, current_octave, line_num)?;
-        events.extend(line_events);
+    /// Merge the block's lines into one event per step (`step` beats each)
+    /// and start a new block. Shorter lines are padded with rests.
+    fn take_events(&mut self, step: Beat) -> Vec<Event> {
+        let lines = std::mem::take(&mut self.lines);
+        let steps = lines.iter().map(|(_, hits)| hits.len()).max().unwrap_or(0);
+        (0..steps)
+            .map(|i| {
+                let drums: Vec<Drum> = lines
+                    .iter()
+                    .filter(|(_, hits)| hits.get(i) == Some(&true))
+                    .map(|(drum, _)| *drum)
+                    .collect();
+                if drums.is_empty() {
+                    Event::Rest(step)
+                } else {
+                    Event::Drums(drums, step)
+                }
+            })
+            .collect()
     }
+}
 
-    let computed: f64 = events.iter().map(event_duration).sum();
-    let pattern_beats = if beats > 0.0 { beats } else { computed };
+/// Parse a .notes file into a Pattern (one pattern = fixed beats, loop flag,
+/// single event list). The events of all `[track:]` sections are joined in
+/// file order; use `parse` to keep the tracks apart.
+pub fn parse_pattern(input: &str) -> Result<Pattern, ParseError> {
+    Ok(parse(input)?.into_pattern())
+}
 
-    Ok(Pattern {
-        beats: pattern_beats,
-        loop_pattern,
-        time_signature,
-        default_octave,
-        events,
-    })
+/// `parse_pattern` with `options`, its errors naming the file
+pub fn parse_pattern_with(input: &str, options: ParseOptions) -> Result<Pattern, ClidawError> {
+    Ok(parse_with(input, options)?.into_pattern())
 }
 
-/// Parse a .notes file into a Composition (legacy: multi-track, used for Parse display).
+/// Parse a .notes file into a Composition: the header directives plus one
+/// track per `[track: name]` section (a file without sections has a single
+/// track named "default"). `octave:` and `patch:` before the first section
+/// are file-wide defaults; inside a section they apply to that track only.
 pub fn parse(input: &str) -> Result<Composition, ParseError> {
+    parse_composition(input, &ParseOptions::default())
+}
+
+/// `parse` with `options`, its errors naming the file
+pub fn parse_with(input: &str, options: ParseOptions) -> Result<Composition, ClidawError> {
+    parse_composition(input, &options).map_err(|e| e.into_error(options.file))
+}
+
+fn parse_composition(input: &str, options: &ParseOptions) -> Result<Composition, ParseError> {
+    let mut strict = options.strict;
     let mut comp = Composition::new();
+    if let Some(octave) = options.octave {
+        comp.default_octave = octave;
+    }
     let mut current_track_events: Vec<Event> = Vec::new();
+    let mut current_track_includes: Vec<Include> = Vec::new();
+    let mut current_track_meter: Vec<MeterChange> = Vec::new();
     let mut current_track_name = String::from("default");
     let mut current_track_patch: Option<String> = None;
+    let mut in_track = false;
     let mut current_octave = comp.default_octave;
+    let mut drums = DrumBlock::default();
+    let mut default_drum_step = Beat::ONE;
+    let mut drum_step = default_drum_step;
+    let mut repeat = RepeatState::default();
+    let mut default_level = 1.0;
+    let mut dynamics = Dynamics::new(default_level);
 
     for (line_idx, line) in input.lines().enumerate() {
         let line_num = line_idx + 1;
         let trimmed = line.trim();
+        // Column of the first non-blank character
+        let first_column = line.chars().take_while(|c| c.is_whitespace()).count() + 1;
 
-        // Skip empty lines and comments
-        if trimmed.is_empty() || trimmed.starts_with('#') {
+        // Skip comments; drum lines accumulate until a blank or other line
+        if trimmed.starts_with('#') {
+            continue;
+        }
+        if drums.push_line(trimmed, first_column, line_num)? {
+            continue;
+        }
+        current_track_events.extend(drums.take_events(drum_step));
+        if trimmed.is_empty() {
             continue;
         }
 
         // Metadata directives
+        if let Some((key, value)) = trimmed.split_once(':')
+            && comp.metadata.set(key.trim(), value)
+        {
+            continue;
+        }
+        if let Some(value) = trimmed.strip_prefix("beats:") {
+            let beats: f64 = value.trim().parse().map_err(|_| ParseError {
+                line: line_num,
+                column: None,
+                message: format!("invalid beats: {}", value.trim()),
+            })?;
+            comp.beats = Beat::from_f64(beats);
+            continue;
+        }
+        if let Some(value) = trimmed.strip_prefix("drum_step:") {
+            drum_step = match value.trim().parse::<f64>() {
+                Ok(beats) if beats > 0.0 && beats.is_finite() => Beat::from_f64(beats),
+                _ => {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: None,
+                        message: format!("invalid drum_step: {} (expected beats above 0)", value.trim()),
+                    });
+                }
+            };
+            if !in_track {
+                default_drum_step = drum_step;
+            }
+            continue;
+        }
+        if let Some(value) = trimmed.strip_prefix("loop:") {
+            comp.loop_pattern = is_true(value);
+            continue;
+        }
+        if let Some(value) = trimmed.strip_prefix("prefer_flats:") {
+            comp.prefer_flats = is_true(value);
+            continue;
+        }
+        if let Some(value) = trimmed.strip_prefix("strict:") {
+            strict = strict || is_true(value);
+            continue;
+        }
         if let Some(value) = trimmed.strip_prefix("tempo:") {
-            comp.tempo = value.trim().parse().map_err(|_| ParseError {
+            comp.tempo = tempo::parse(value).ok_or_else(|| ParseError {
                 line: line_num,
+                column: None,
                 message: format!("invalid tempo: {}", value.trim()),
             })?;
             continue;
@@ -160,43 +367,117 @@ pub fn parse(input: &str) -> Result<Composition, ParseError> {
             if parts.len() == 2 {
                 let num: u8 = parts[0].parse().map_err(|_| ParseError {
                     line: line_num,
+                    column: None,
                     message: "invalid time signature numerator".into(),
                 })?;
                 let den: u8 = parts[1].parse().map_err(|_| ParseError {
                     line: line_num,
+                    column: None,
                     message: "invalid time signature denominator".into(),
                 })?;
-                comp.time_signature = (num, den);
+                // After the first notes it changes the meter from here on
+                if current_track_events.is_empty() && comp.tracks.is_empty() {
+                    comp.time_signature = (num, den);
+                } else if repeat.open.is_some() {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: None,
+                        message: "time_signature can't change inside a repeat".into(),
+                    });
+                } else {
+                    current_track_meter.push(MeterChange {
+                        event: current_track_events.len(),
+                        signature: (num, den),
+                    });
+                }
             }
             continue;
         }
         if let Some(value) = trimmed.strip_prefix("octave:") {
             let oct: u8 = value.trim().parse().map_err(|_| ParseError {
                 line: line_num,
+                column: None,
                 message: format!("invalid octave: {}", value.trim()),
             })?;
             if oct > 8 {
                 return Err(ParseError {
                     line: line_num,
+                    column: None,
                     message: "octave must be 0-8".into(),
                 });
             }
-            comp.default_octave = oct;
+            if !in_track {
+                comp.default_octave = oct;
+            }
             current_octave = oct;
             continue;
         }
+        if let Some(value) = trimmed.strip_prefix("key:") {
+            comp.key = Some(Key::parse(value).ok_or_else(|| ParseError {
+                line: line_num,
+                column: None,
+                message: format!(
+                    "invalid key '{}' (expected a note and one of {}, e.g. 'D minor')",
+                    value.trim(),
+                    Scale::names().join(", ")
+                ),
+            })?);
+            continue;
+        }
+        if let Some(value) = trimmed.strip_prefix("dyn:") {
+            let level = dynamic_level(value.trim()).ok_or_else(|| ParseError {
+                line: line_num,
+                column: None,
+                message: format!(
+                    "invalid dynamic '{}' (expected pp, p, mp, mf, f or ff)",
+                    value.trim()
+                ),
+            })?;
+            if !in_track {
+                default_level = level;
+            }
+            dynamics.set(level, &mut current_track_events);
+            continue;
+        }
         if let Some(value) = trimmed.strip_prefix("patch:") {
-            let patch = value.trim().to_string();
-            if current_track_name == "default" && comp.tracks.is_empty() {
-                comp.default_patch = Some(patch);
-            } else {
+            let mut patch = value.trim().to_string();
+            if is_patch_file(&patch) {
+                patch = options.resolve(Path::new(&patch)).to_string_lossy().into_owned();
+            }
+            if in_track {
                 current_track_patch = Some(patch);
+            } else {
+                comp.default_patch = Some(patch);
             }
             continue;
         }
+        if let Some(value) = trimmed.strip_prefix("include:") {
+            dynamics.settle(&mut current_track_events);
+            let file = options.resolve(Path::new(value.trim()));
+            let included = include_file(&file, options, strict, current_octave, line_num)?;
+            let start = current_track_events.len();
+            current_track_events.extend(included.events);
+            current_track_includes.push(Include {
+                file,
+                events: start..current_track_events.len(),
+            });
+            current_track_includes.extend(included.includes.into_iter().map(|nested| Include {
+                events: nested.events.start + start..nested.events.end + start,
+                ..nested
+            }));
+            current_track_meter.extend(included.meter_changes.into_iter().map(|change| {
+                MeterChange {
+                    event: change.event + start,
+                    ..change
+                }
+            }));
+            continue;
+        }
 
         // Track header: [track: name]
         if trimmed.starts_with("[track:") && trimmed.ends_with(']') {
+            repeat.finish()?;
+            dynamics.finish(&mut current_track_events)?;
             // Save previous track if it has events
             if !current_track_events.is_empty() {
                 comp.tracks.push(Track {
@@ -204,8 +485,12 @@ pub fn parse(input: &str) -> Result<Composition, ParseError> {
                     patch: current_track_patch.take(),
                     octave: current_octave,
                     events: std::mem::take(&mut current_track_events),
+                    includes: std::mem::take(&mut current_track_includes),
+                    meter_changes: std::mem::take(&mut current_track_meter),
                 });
             }
+            // Changes in a section without notes go with it
+            current_track_meter.clear();
             current_track_name = trimmed
                 .strip_prefix("[track:")
                 .unwrap()
@@ -213,14 +498,39 @@ pub fn parse(input: &str) -> Result<Composition, ParseError> {
                 .unwrap()
                 .trim()
                 .to_string();
+            current_track_patch = None;
+            in_track = true;
             current_octave = comp.default_octave;
+            drum_step = default_drum_step;
+            dynamics = Dynamics::new(default_level);
             continue;
         }
 
         // Parse note line
-        let events = parse_line(trimmed, current_octave, line_num)?;
-        current_track_events.extend(events);
+        let spelling = if options.prefer_flats || comp.prefer_flats {
+            Spelling::Flat
+        } else {
+            comp.key.map_or(Spelling::Sharp, |key| key.spelling)
+        };
+        let key = comp.key.map(|key| Key { spelling, ..key });
+        let settings = LineSettings {
+            key: key.as_ref(),
+            spelling,
+            strict,
+        };
+        parse_line(
+            &mut LineChars::new(trimmed, first_column),
+            current_octave,
+            &settings,
+            line_num,
+            &mut current_track_events,
+            &mut repeat,
+            &mut dynamics,
+        )?;
     }
+    current_track_events.extend(drums.take_events(drum_step));
+    repeat.finish()?;
+    dynamics.finish(&mut current_track_events)?;
 
     // Push final track
     if !current_track_events.is_empty() {
@@ -229,83 +539,1299 @@ pub fn parse(input: &str) -> Result<Composition, ParseError> {
             patch: current_track_patch,
             octave: current_octave,
             events: current_track_events,
+            includes: current_track_includes,
+            meter_changes: current_track_meter,
         });
     }
 
     Ok(comp)
 }
 
-/// Parse a single line of note text into events
-fn parse_line(line: &str, octave: u8, _line_num: usize) -> Result<Vec<Event>, ParseError> {
-    let mut events = Vec::new();
-    let mut chars = line.chars().peekable();
+/// Deepest chain of files including each other that is followed
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Parse the file an `include:` on `line_num` names, starting from the
+/// including file's `octave`. Its other directives only apply inside it.
+fn include_file(
+    file: &Path,
+    options: &ParseOptions,
+    strict: bool,
+    octave: u8,
+    line_num: usize,
+) -> Result<Track, ParseError> {
+    let error = |message| ParseError {
+        line: line_num,
+        column: None,
+        message,
+    };
+    let mut chain = options.includes.clone();
+    chain.extend(options.file.clone());
+    let same_file = |a: &Path| match (a.canonicalize(), file.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == file,
+    };
+    if chain.iter().any(|f| same_file(f)) {
+        let names: Vec<String> = chain
+            .iter()
+            .map(|f| f.as_path())
+            .chain([file])
+            .map(|f| f.display().to_string())
+            .collect();
+        return Err(error(format!("include cycle: {}", names.join(" -> "))));
+    }
+    if chain.len() >= MAX_INCLUDE_DEPTH {
+        return Err(error(format!(
+            "includes nest more than {} files deep at {}",
+            MAX_INCLUDE_DEPTH,
+            file.display()
+        )));
+    }
+
+    let input = fs::read_to_string(file)
+        .map_err(|e| error(format!("can't read included file {}: {}", file.display(), e)))?;
+    let nested = ParseOptions {
+        strict,
+        file: Some(file.to_path_buf()),
+        includes: chain,
+        octave: Some(octave),
+        prefer_flats: options.prefer_flats,
+    };
+    let mut comp = parse_composition(&input, &nested)
+        .map_err(|e| error(format!("in {}: {}", file.display(), e)))?;
+    if comp.tracks.len() > 1 {
+        return Err(error(format!(
+            "{} has [track:] sections; only a single part can be included",
+            file.display()
+        )));
+    }
+    Ok(comp.tracks.pop().unwrap_or(Track {
+        name: String::new(),
+        patch: None,
+        octave,
+        events: Vec::new(),
+        includes: Vec::new(),
+        meter_changes: Vec::new(),
+    }))
+}
+
+/// Chord qualities for chord symbols: suffix and semitones above the root
+const CHORD_QUALITIES: [(&str, &[u8]); 10] = [
+    ("", &[0, 4, 7]),
+    ("maj", &[0, 4, 7]),
+    ("m", &[0, 3, 7]),
+    ("7", &[0, 4, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("m7", &[0, 3, 7, 10]),
+    ("dim", &[0, 3, 6]),
+    ("aug", &[0, 4, 8]),
+    ("sus2", &[0, 2, 7]),
+    ("sus4", &[0, 5, 7]),
+];
+
+/// Expand a chord symbol like `Cmaj`, `Am7`, `F#dim` or `Bb7` into the
+/// notes of a root-position chord with the root in `octave`; upper notes
+/// spill into the next octave. They're spelled like the root, or with
+/// `spelling` under a natural one.
+fn parse_chord_symbol(
+    symbol: &str,
+    octave: u8,
+    spelling: Spelling,
+    line_num: usize,
+    column: usize,
+) -> Result<Vec<NoteEvent>, ParseError> {
+    let root_len = if symbol[1..].starts_with(['#', 'b']) { 2 } else { 1 };
+    let (root_name, quality) = symbol.split_at(root_len);
+    let (root, written) = NoteName::from_written(root_name).ok_or_else(|| ParseError {
+        line: line_num,
+        column: Some(column),
+        message: format!("invalid chord root '{}' in '{}'", root_name, symbol),
+    })?;
+    let (_, intervals) = CHORD_QUALITIES
+        .iter()
+        .find(|(suffix, _)| *suffix == quality)
+        .ok_or_else(|| ParseError {
+            line: line_num,
+            column: Some(column),
+            message: format!(
+                "unknown chord quality '{}' in '{}' (expected maj, m, 7, maj7, m7, dim, aug, sus2 or sus4)",
+                quality, symbol
+            ),
+        })?;
+    let notes = intervals
+        .iter()
+        .map(|&interval| {
+            let semitone = root.semitone() + interval;
+            NoteEvent {
+                note: NoteName::ALL[(semitone % 12) as usize],
+                octave: octave.saturating_add(semitone / 12),
+                degree: None,
+                velocity: 1.0,
+                spelling: written.unwrap_or(spelling),
+                chance: 1.0,
+            }
+        })
+        .collect();
+    Ok(notes)
+}
+
+/// An open `|:` repeat: where its section starts in the event list
+#[derive(Default)]
+struct RepeatState {
+    /// (index of the first repeated event, line and column of the `|:`)
+    open: Option<(usize, usize, usize)>,
+}
+
+impl RepeatState {
+    fn start(
+        &mut self,
+        events: &[Event],
+        line_num: usize,
+        column: usize,
+    ) -> Result<(), ParseError> {
+        if let Some((_, open_line, _)) = self.open {
+            return Err(ParseError {
+                line: line_num,
+                column: Some(column),
+                message: format!(
+                    "nested repeats are not supported ('|:' already open from line {})",
+                    open_line
+                ),
+            });
+        }
+        self.open = Some((events.len(), line_num, column));
+        Ok(())
+    }
+
+    /// Close the open repeat, appending the section so it plays `times` in total
+    fn end(
+        &mut self,
+        events: &mut Vec<Event>,
+        times: u32,
+        line_num: usize,
+        column: usize,
+    ) -> Result<(), ParseError> {
+        let Some((start, _, _)) = self.open.take() else {
+            return Err(ParseError {
+                line: line_num,
+                column: Some(column),
+                message: "':|' without a matching '|:'".into(),
+            });
+        };
+        // Each pass ends at the `:|`, which is a bar line like `|`
+        let section = events[start..].to_vec();
+        for _ in 1..times {
+            events.push(Event::BarLine);
+            events.extend(section.iter().cloned());
+        }
+        Ok(())
+    }
+
+    /// Error if a `|:` was never closed
+    fn finish(&mut self) -> Result<(), ParseError> {
+        match self.open.take() {
+            Some((_, line, column)) => Err(ParseError {
+                line,
+                column: Some(column),
+                message: "'|:' is never closed with ':|'".into(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Dynamic marks for `dyn:` and inline `!mf!`, softest first, with the
+/// velocity each stands for
+pub const DYNAMICS: [(&str, f64); 6] = [
+    ("pp", 0.25),
+    ("p", 0.4),
+    ("mp", 0.55),
+    ("mf", 0.7),
+    ("f", 0.85),
+    ("ff", 1.0),
+];
+
+fn dynamic_level(name: &str) -> Option<f64> {
+    DYNAMICS
+        .iter()
+        .find(|(mark, _)| *mark == name)
+        .map(|(_, level)| *level)
+}
+
+/// A crescendo or decrescendo region: `!cresc! ... !/cresc!`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Hairpin {
+    Cresc,
+    Decresc,
+}
+
+impl Hairpin {
+    fn name(self) -> &'static str {
+        match self {
+            Hairpin::Cresc => "cresc",
+            Hairpin::Decresc => "decresc",
+        }
+    }
+
+    /// The next dynamic mark past `level` in this direction
+    fn step(self, level: f64) -> f64 {
+        let idx = DYNAMICS
+            .iter()
+            .position(|(_, l)| *l >= level)
+            .unwrap_or(DYNAMICS.len() - 1);
+        let idx = match self {
+            Hairpin::Cresc => (idx + 1).min(DYNAMICS.len() - 1),
+            Hairpin::Decresc => idx.saturating_sub(1),
+        };
+        DYNAMICS[idx].1
+    }
+}
+
+/// Velocity of new notes, and the hairpin regions that ramp it.
+///
+/// A region ramps from the level where it opens to the next dynamic mark
+/// after it closes, or one mark louder (softer) if a note comes first.
+/// Until then a closed region is pending; its notes are set once the
+/// target is known.
+struct Dynamics {
+    level: f64,
+    /// Open region: kind, index of its first event, line and column of the mark
+    open: Option<(Hairpin, usize, usize, usize)>,
+    /// Closed region waiting for its target: kind, event range, start level
+    pending: Option<(Hairpin, std::ops::Range<usize>, f64)>,
+}
+
+impl Dynamics {
+    fn new(level: f64) -> Self {
+        Self {
+            level,
+            open: None,
+            pending: None,
+        }
+    }
+
+    fn open(
+        &mut self,
+        hairpin: Hairpin,
+        events: &mut [Event],
+        line_num: usize,
+        column: usize,
+    ) -> Result<(), ParseError> {
+        if let Some((open, _, open_line, _)) = self.open {
+            return Err(ParseError {
+                line: line_num,
+                column: Some(column),
+                message: format!(
+                    "nested dynamics are not supported ('!{}!' already open from line {})",
+                    open.name(),
+                    open_line
+                ),
+            });
+        }
+        self.settle(events);
+        self.open = Some((hairpin, events.len(), line_num, column));
+        Ok(())
+    }
+
+    fn close(
+        &mut self,
+        hairpin: Hairpin,
+        events: &[Event],
+        line_num: usize,
+        column: usize,
+    ) -> Result<(), ParseError> {
+        match self.open.take() {
+            Some((open, start, _, _)) if open == hairpin => {
+                self.pending = Some((hairpin, start..events.len(), self.level));
+                Ok(())
+            }
+            _ => Err(ParseError {
+                line: line_num,
+                column: Some(column),
+                message: format!("'!/{0}!' without a matching '!{0}!'", hairpin.name()),
+            }),
+        }
+    }
+
+    /// A dynamic mark: new notes play at `level`, and a pending region
+    /// ramps to it
+    fn set(&mut self, level: f64, events: &mut [Event]) {
+        if let Some((_, range, from)) = self.pending.take() {
+            ramp(&mut events[range], from, level);
+        }
+        self.level = level;
+    }
+
+    /// Something other than a mark follows a pending region: it ramps one
+    /// mark louder (softer)
+    fn settle(&mut self, events: &mut [Event]) {
+        if let Some((hairpin, range, from)) = self.pending.take() {
+            let to = hairpin.step(from);
+            ramp(&mut events[range], from, to);
+            self.level = to;
+        }
+    }
+
+    /// End of a track: settle any pending region; an open one is an error
+    fn finish(&mut self, events: &mut [Event]) -> Result<(), ParseError> {
+        self.settle(events);
+        match self.open.take() {
+            Some((hairpin, _, line, column)) => Err(ParseError {
+                line,
+                column: Some(column),
+                message: format!("'!{0}!' is never closed with '!/{0}!'", hairpin.name()),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Set the velocities of the notes in `events` on a straight line from
+/// `from` (first note) to `to` (last note)
+fn ramp(events: &mut [Event], from: f64, to: f64) {
+    let count = events
+        .iter()
+        .filter(|e| matches!(e, Event::Note(..) | Event::Chord(..)))
+        .count();
+    let mut idx = 0;
+    for event in events.iter_mut() {
+        let notes = event.notes_mut();
+        if notes.is_empty() {
+            continue;
+        }
+        let t = if count > 1 { idx as f64 / (count - 1) as f64 } else { 1.0 };
+        for note in notes {
+            note.velocity = from + (to - from) * t;
+        }
+        idx += 1;
+    }
+}
+
+/// An inline dynamics mark
+enum Mark {
+    Open(Hairpin),
+    Close(Hairpin),
+    Level(f64),
+}
+
+/// Read a mark like `!cresc!`, `!/cresc!` or `!mf!` if one comes next
+fn take_mark(chars: &mut LineChars) -> Option<Mark> {
+    let word = chars.rest.strip_prefix('!')?.split_once('!')?.0;
+    let mark = match word {
+        "cresc" => Mark::Open(Hairpin::Cresc),
+        "decresc" => Mark::Open(Hairpin::Decresc),
+        "/cresc" => Mark::Close(Hairpin::Cresc),
+        "/decresc" => Mark::Close(Hairpin::Decresc),
+        _ => Mark::Level(dynamic_level(word)?),
+    };
+    chars.eat(&format!("!{}!", word));
+    Some(mark)
+}
+
+/// Octave after a `<` (down) or `>` (up) shift token
+fn shift_octave(octave: u8, token: char) -> u8 {
+    match token {
+        '<' => octave.saturating_sub(1),
+        _ => (octave + 1).min(8),
+    }
+}
+
+/// `octave` moved `shift` octaves by a chord's `+`/`-`, staying within
+/// 0-8 (a next-octave key already above 8 can only come down)
+fn spread_octave(octave: u8, shift: i8) -> u8 {
+    let top = octave.max(8) as i16;
+    (octave as i16 + shift as i16).clamp(0, top) as u8
+}
+
+/// Whether `c` starts a scale degree (`3`, `b7`, `#4`)
+fn starts_degree(c: char) -> bool {
+    matches!(c, '1'..='9' | 'b' | '#')
+}
+
+/// Read a scale degree and resolve it in `key` with the tonic in `octave`
+fn take_degree(
+    chars: &mut LineChars,
+    key: &Key,
+    octave: u8,
+    line_num: usize,
+) -> Result<NoteEvent, ParseError> {
+    let column = chars.column;
+    let accidental = match chars.peek() {
+        Some('b') => -1,
+        Some('#') => 1,
+        _ => 0,
+    };
+    if accidental != 0 {
+        chars.next();
+    }
+    let number = chars
+        .peek()
+        .and_then(|c| c.to_digit(10))
+        .filter(|&d| d >= 1)
+        .ok_or_else(|| ParseError {
+            line: line_num,
+            column: Some(column),
+            message: "expected a scale degree 1-9 after '#' or 'b'".into(),
+        })?;
+    chars.next();
+    let degree = Degree {
+        number: number as u8,
+        accidental,
+    };
+    key.resolve(degree, octave).ok_or_else(|| ParseError {
+        line: line_num,
+        column: Some(column),
+        message: format!("degree {} of {} in octave {} is out of range", degree, key, octave),
+    })
+}
+
+/// Read a chord's `^2` inversion, if one comes next (0 when there is none).
+/// On error, returns the text after the caret.
+fn take_inversion(chars: &mut LineChars) -> Result<usize, String> {
+    if chars.peek() != Some('^') {
+        return Ok(0);
+    }
+    chars.next();
+    let mut digits = String::new();
+    while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+        digits.push(d);
+        chars.next();
+    }
+    digits.parse().map_err(|_| digits)
+}
+
+/// Invert a chord `times` times: each moves its lowest note up an octave
+/// and to the end of the chord (a note already in octave 8 stays put)
+fn invert(notes: &mut Vec<NoteEvent>, times: usize) {
+    let pitch = |n: &NoteEvent| n.note.semitone() as u16 + n.octave as u16 * 12;
+    for _ in 0..times {
+        let Some(lowest) = (0..notes.len()).min_by_key(|&i| pitch(&notes[i])) else {
+            return;
+        };
+        let mut note = notes.remove(lowest);
+        note.octave = spread_octave(note.octave, 1);
+        notes.push(note);
+    }
+}
+
+/// Read a `:0.5` length in beats, if one comes next (`:|` ends a repeat
+/// instead). A trailing `.` dots it: `:1.` is 1.5 beats. On error, returns
+/// the text after the colon.
+fn take_length(chars: &mut LineChars) -> Result<Option<Beat>, String> {
+    if !chars.rest.starts_with(':') || chars.rest.starts_with(":|") {
+        return Ok(None);
+    }
+    chars.next();
+    let mut text = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit() || *c == '.') {
+        text.push(c);
+        chars.next();
+    }
+    let (number, dot) = match text.strip_suffix('.') {
+        Some(number) => (number, 1.5),
+        None => (text.as_str(), 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(beats) if beats > 0.0 && beats.is_finite() && !number.ends_with('.') => {
+            Ok(Some(Beat::from_f64(beats * dot)))
+        }
+        _ => {
+            let rest = chars.rest.split(char::is_whitespace).next().unwrap_or("");
+            Err(text + rest)
+        }
+    }
+}
+
+/// Read the length after a note, chord or tie (1 beat when there is none).
+/// `column` is the note's, for errors.
+fn note_length(chars: &mut LineChars, line_num: usize, column: usize) -> Result<Beat, ParseError> {
+    let length = take_length(chars).map_err(|text| ParseError {
+        line: line_num,
+        column: Some(column),
+        message: format!("invalid length ':{}' (expected beats, e.g. ':0.5' or ':1.')", text),
+    })?;
+    Ok(length.unwrap_or(Beat::ONE))
+}
+
+/// Read a `?` chance after a note or chord, if one comes next: `?` alone is
+/// 0.5, `?0.25` gives its own
+fn take_chance(chars: &mut LineChars, line_num: usize) -> Result<Option<f64>, ParseError> {
+    let column = chars.column;
+    if !chars.eat("?") {
+        return Ok(None);
+    }
+    let mut text = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit() || *c == '.') {
+        text.push(c);
+        chars.next();
+    }
+    if text.is_empty() {
+        return Ok(Some(0.5));
+    }
+    match text.parse::<f64>() {
+        Ok(chance) if (0.0..=1.0).contains(&chance) => Ok(Some(chance)),
+        _ => Err(ParseError {
+            line: line_num,
+            column: Some(column),
+            message: format!("invalid chance '?{}' (expected 0 to 1, e.g. '?0.25')", text),
+        }),
+    }
+}
+
+/// Read the length and chance after a note or chord, in either order (1
+/// beat and 1.0 when they're left out)
+fn note_timing(
+    chars: &mut LineChars,
+    line_num: usize,
+    column: usize,
+) -> Result<(Beat, f64), ParseError> {
+    let before = take_chance(chars, line_num)?;
+    let length = note_length(chars, line_num, column)?;
+    let after = match before {
+        Some(_) => None,
+        None => take_chance(chars, line_num)?,
+    };
+    if chars.peek() == Some('?') {
+        return Err(ParseError {
+            line: line_num,
+            column: Some(chars.column),
+            message: "a note or chord takes one '?' chance".into(),
+        });
+    }
+    Ok((length, before.or(after).unwrap_or(1.0)))
+}
+
+/// Read an `alt{1: a s | 2: d f}` group (`alt?{...}` when picked at
+/// random). Each choice is a run of notes and rests of its own, starting in
+/// `octave` at dynamics `level`. The `1:` labels may be left out, but must
+/// count up from 1. The group must close on the line it opens.
+fn take_alternatives(
+    chars: &mut LineChars,
+    octave: u8,
+    settings: &LineSettings,
+    line_num: usize,
+    level: f64,
+) -> Result<Alternatives, ParseError> {
+    let column = chars.column;
+    let error = |column, message: &str| ParseError {
+        line: line_num,
+        column: Some(column),
+        message: message.to_string(),
+    };
+    let random = chars.eat("alt?{");
+    if !random {
+        chars.eat("alt{");
+    }
+    let Some(close) = chars.rest.find('}') else {
+        return Err(error(column, "'alt{' is never closed (it must end on the same line)"));
+    };
+    let body = &chars.rest[..close];
+    if body.contains('{') {
+        return Err(error(column, "alt groups can't be nested"));
+    }
+    let mut choices = Vec::new();
+    let mut choice_column = chars.column;
+    for (idx, text) in body.split('|').enumerate() {
+        let mut inner = LineChars::new(text, choice_column);
+        choice_column += text.chars().count() + 1;
+        while inner.peek().is_some_and(char::is_whitespace) {
+            inner.next();
+        }
+        let digits: String = inner.rest.chars().take_while(char::is_ascii_digit).collect();
+        // `1:` then a space, unlike a scale degree's `1:0.5` length
+        let label = inner.rest[digits.len()..].strip_prefix(':');
+        let label = label.is_some_and(|after| after.chars().next().is_none_or(char::is_whitespace));
+        if !digits.is_empty() && label {
+            if digits.parse() != Ok(idx + 1) {
+                let message = format!(
+                    "alt choice {} is labelled '{}:' (labels count up from 1)",
+                    idx + 1,
+                    digits
+                );
+                return Err(error(inner.column, &message));
+            }
+            inner.eat(&digits);
+            inner.eat(":");
+        }
+        let mut events = Vec::new();
+        let mut dynamics = Dynamics::new(level);
+        let mut repeat = RepeatState::default();
+        parse_line(
+            &mut inner,
+            octave,
+            settings,
+            line_num,
+            &mut events,
+            &mut repeat,
+            &mut dynamics,
+        )?;
+        dynamics.finish(&mut events)?;
+        choices.push(events);
+    }
+    for _ in 0..=body.chars().count() {
+        chars.next();
+    }
+    let alt = Alternatives { choices, random };
+    if alt.choices.len() < 2 {
+        return Err(error(column, "an alt group needs two or more choices separated by '|'"));
+    }
+    if alt.length() == Beat::ZERO {
+        return Err(error(column, "empty alt group"));
+    }
+    Ok(alt)
+}
+
+/// Squeeze the events of a `(...)/count` tuplet into the time of the
+/// largest power of two below `count` (`(a s d)/3`: three in the time of
+/// two). Each event ends where its share of the span does, so the group
+/// adds up to exactly its span even when a share rounds to the nearest tick.
+fn fit_tuplet(events: &mut [Event], count: u32) {
+    let space = 1u32 << (u32::BITS - 1 - (count - 1).leading_zeros());
+    let mut written = Beat::ZERO;
+    let mut end = Beat::ZERO;
+    for event in events {
+        let beats = match event {
+            Event::Note(_, beats) | Event::Chord(_, beats) => beats,
+            Event::Rest(beats) | Event::Tie(beats) => beats,
+            Event::Drums(..) | Event::BarLine | Event::Alt(_) => continue,
+        };
+        written += *beats;
+        let fitted_end = written * space / count;
+        *beats = fitted_end - end;
+        end = fitted_end;
+    }
+}
+
+/// Error for a `+` or `-` in a chord with no note after it
+fn stray_spread(line_num: usize, column: usize) -> ParseError {
+    ParseError {
+        line: line_num,
+        column: Some(column),
+        message: "'+' and '-' in a chord must come right before a note".into(),
+    }
+}
+
+/// Error for a character the parser doesn't know (only raised in strict mode)
+fn unknown_character(c: char, line_num: usize, column: usize) -> ParseError {
+    ParseError {
+        line: line_num,
+        column: Some(column),
+        message: format!("unknown character '{}'", c),
+    }
+}
+
+/// File-wide settings that affect how note lines read
+struct LineSettings<'a> {
+    /// With a key, digits are scale degrees
+    key: Option<&'a Key>,
+    /// How black keys typed as letters (and chords on a natural root) are
+    /// named
+    spelling: Spelling,
+    /// Unknown characters are errors instead of being skipped
+    strict: bool,
+}
 
-    while let Some(&c) = chars.peek() {
+/// Parse a single line of note text, appending its events. Repeat markers
+/// (`|:` ... `:|`, optionally `:|x3`) and dynamics regions may span lines
+/// and are expanded here. `<` and `>` shift `octave` down or up for the
+/// rest of the line. A `(...)/3` tuplet must close on the line it opens.
+/// `?` chances and `alt{...}` groups are kept as written for the scheduler
+/// to roll on each repeat.
+fn parse_line(
+    chars: &mut LineChars,
+    mut octave: u8,
+    settings: &LineSettings,
+    line_num: usize,
+    events: &mut Vec<Event>,
+    repeat: &mut RepeatState,
+    dynamics: &mut Dynamics,
+) -> Result<(), ParseError> {
+    let key = settings.key;
+    // Open tuplet: index of its first event and the column of its `(`
+    let mut tuplet: Option<(usize, usize)> = None;
+    while let Some(c) = chars.peek() {
+        let column = chars.column;
+        // Anything that isn't a mark settles a closed dynamics region
+        if !matches!(c, ' ' | '\t' | '|' | '!' | '<' | '>') {
+            dynamics.settle(events);
+        }
         match c {
             // Whitespace: skip
             ' ' | '\t' => {
                 chars.next();
             }
 
-            // Bar line
+            // Dynamics mark
+            '!' => match take_mark(chars) {
+                Some(Mark::Open(hairpin)) => dynamics.open(hairpin, events, line_num, column)?,
+                Some(Mark::Close(hairpin)) => dynamics.close(hairpin, events, line_num, column)?,
+                Some(Mark::Level(level)) => dynamics.set(level, events),
+                None if settings.strict => {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "'!' must start a dynamics mark like '!mf!' or '!cresc!'".into(),
+                    });
+                }
+                None => {
+                    chars.next();
+                }
+            },
+
+            // Octave shift (clamped to 0-8)
+            '<' | '>' => {
+                chars.next();
+                octave = shift_octave(octave, c);
+            }
+
+            // Bar line, or start of a repeat
             '|' => {
+                if tuplet.is_some() {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "a bar line can't fall inside a tuplet".into(),
+                    });
+                }
+                chars.next();
+                events.push(Event::BarLine);
+                if chars.peek() == Some(':') {
+                    chars.next();
+                    repeat.start(events, line_num, column)?;
+                }
+            }
+
+            // End of a repeat: `:|` plays the section twice, `:|x3` three times
+            ':' => {
                 chars.next();
+                if chars.next() != Some('|') {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "expected ':|' to end a repeat".into(),
+                    });
+                }
+                if tuplet.is_some() {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "a repeat can't end inside a tuplet".into(),
+                    });
+                }
+                let mut times = 2;
+                if chars.peek() == Some('x') {
+                    chars.next();
+                    let mut digits = String::new();
+                    while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                        digits.push(d);
+                        chars.next();
+                    }
+                    times = digits.parse().ok().filter(|&n| n >= 1).ok_or_else(|| ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: format!("invalid repeat count 'x{}'", digits),
+                    })?;
+                }
+                repeat.end(events, times, line_num, column)?;
                 events.push(Event::BarLine);
             }
 
-            // Rest: count consecutive dashes
+            // A chance only follows a note or chord, which reads its own
+            '?' => {
+                return Err(ParseError {
+                    line: line_num,
+                    column: Some(column),
+                    message: "a '?' chance must follow a note or chord".into(),
+                });
+            }
+
+            // Rest: each dash is one beat, or `-:0.5` gives its length
             '-' => {
                 let mut count = 0;
-                while chars.peek() == Some(&'-') {
+                while chars.peek() == Some('-') {
                     chars.next();
                     count += 1;
                 }
-                // Each dash = 1 beat of rest
-                events.push(Event::Rest(count as f64));
+                let length = take_length(chars).map_err(|text| ParseError {
+                    line: line_num,
+                    column: Some(column),
+                    message: format!(
+                        "invalid rest length '-:{}' (expected beats, e.g. '-:0.5')",
+                        text
+                    ),
+                })?;
+                let beats = match length {
+                    Some(_) if count > 1 => {
+                        return Err(ParseError {
+                            line: line_num,
+                            column: Some(column),
+                            message: "a rest with a length has one dash, e.g. '-:1.5'".into(),
+                        });
+                    }
+                    Some(beats) => beats,
+                    None => Beat::whole(count as i64),
+                };
+                events.push(Event::Rest(beats));
+            }
+
+            // Tie: hold the last note or chord one more beat (or `_:0.5`)
+            '_' => {
+                chars.next();
+                let tied = events
+                    .iter()
+                    .rev()
+                    .find(|e| !matches!(e, Event::BarLine | Event::Tie(_)));
+                if !matches!(tied, Some(Event::Note(..) | Event::Chord(..))) {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "tie '_' has no note or chord before it to hold".into(),
+                    });
+                }
+                events.push(Event::Tie(note_length(chars, line_num, column)?));
             }
 
-            // Chord: [notes]
+            // Chord: [notes], `+`/`-` before a note moves just that note an
+            // octave, and `^n` after the `]` inverts the chord
             '[' => {
                 chars.next(); // consume '['
                 let mut chord_notes = Vec::new();
-                while let Some(&inner) = chars.peek() {
+                // Octaves the next note moves by, and the column of its first `+`/`-`
+                let mut spread: Option<(i8, usize)> = None;
+                while let Some(inner) = chars.peek() {
                     if inner == ']' {
                         chars.next();
                         break;
                     }
+                    if inner == '+' || inner == '-' {
+                        let shift = spread.map_or(0, |(shift, _)| shift);
+                        let start = spread.map_or(chars.column, |(_, start)| start);
+                        spread = Some((shift + if inner == '+' { 1 } else { -1 }, start));
+                        chars.next();
+                        continue;
+                    }
+                    let pending = spread.take();
+                    let shift = pending.map_or(0, |(shift, _)| shift);
+                    if let Some(key) = key
+                        && starts_degree(inner)
+                    {
+                        let mut note = take_degree(chars, key, octave, line_num)?;
+                        note.octave = spread_octave(note.octave, shift);
+                        note.velocity = dynamics.level;
+                        chord_notes.push(note);
+                        continue;
+                    }
                     if let Some((name, oct_offset)) = char_to_note(inner) {
                         chord_notes.push(NoteEvent {
                             note: name,
-                            octave: octave.saturating_add(oct_offset),
+                            octave: spread_octave(octave.saturating_add(oct_offset), shift),
+                            degree: None,
+                            velocity: dynamics.level,
+                            spelling: settings.spelling,
+                            chance: 1.0,
                         });
+                    } else if let Some((_, start)) = pending {
+                        return Err(stray_spread(line_num, start));
+                    } else if inner == '<' || inner == '>' {
+                        octave = shift_octave(octave, inner);
+                    } else if settings.strict && !matches!(inner, ' ' | '\t') {
+                        return Err(unknown_character(inner, line_num, chars.column));
                     }
                     chars.next();
                 }
+                if let Some((_, start)) = spread {
+                    return Err(stray_spread(line_num, start));
+                }
+                let inversion = take_inversion(chars).map_err(|text| ParseError {
+                    line: line_num,
+                    column: Some(column),
+                    message: format!(
+                        "invalid inversion '^{}' (expected a number, e.g. '^1')",
+                        text
+                    ),
+                })?;
+                invert(&mut chord_notes, inversion);
+                let (length, chance) = note_timing(chars, line_num, column)?;
+                for note in chord_notes.iter_mut() {
+                    note.chance = chance;
+                }
                 if !chord_notes.is_empty() {
-                    events.push(Event::Chord(chord_notes));
+                    events.push(Event::Chord(chord_notes, length));
                 }
             }
 
+            // Chord symbol: uppercase root, optional # or b, quality (Cmaj, Am7, Bbdim)
+            'A'..='G' => {
+                let mut symbol = String::new();
+                while let Some(sc) = chars.peek() {
+                    if !(sc.is_ascii_alphanumeric() || sc == '#') {
+                        break;
+                    }
+                    symbol.push(sc);
+                    chars.next();
+                }
+                let mut notes =
+                    parse_chord_symbol(&symbol, octave, settings.spelling, line_num, column)?;
+                let (length, chance) = note_timing(chars, line_num, column)?;
+                for note in notes.iter_mut() {
+                    note.velocity = dynamics.level;
+                    note.chance = chance;
+                }
+                events.push(Event::Chord(notes, length));
+            }
+
+            // Scale degree, once the pattern has a key
+            c if key.is_some() && starts_degree(c) => {
+                let key = key.expect("guarded by is_some");
+                let mut note = take_degree(chars, key, octave, line_num)?;
+                note.velocity = dynamics.level;
+                let (length, chance) = note_timing(chars, line_num, column)?;
+                note.chance = chance;
+                events.push(Event::Note(note, length));
+            }
+
+            // Tuplet: `(a s d)/3` plays its events in 2/3 of their length
+            '(' => {
+                if let Some((_, open)) = tuplet {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: format!(
+                            "nested tuplets are not supported (one is already open at column {})",
+                            open
+                        ),
+                    });
+                }
+                chars.next();
+                tuplet = Some((events.len(), column));
+            }
+
+            ')' => {
+                chars.next();
+                let Some((start, _)) = tuplet.take() else {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "')' without a '(' to close".into(),
+                    });
+                };
+                let mut digits = String::new();
+                if chars.eat("/") {
+                    while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                        digits.push(d);
+                        chars.next();
+                    }
+                }
+                let count = digits.parse().ok().filter(|&n: &u32| (3..=64).contains(&n));
+                let count = count.ok_or_else(|| ParseError {
+                    line: line_num,
+                    column: Some(column),
+                    message: format!(
+                        "expected a tuplet count from 3 to 64 after ')', e.g. ')/3', got ')/{}'",
+                        digits
+                    ),
+                })?;
+                if events[start..].iter().all(|e| event_duration(e) == Beat::ZERO) {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "empty tuplet".into(),
+                    });
+                }
+                fit_tuplet(&mut events[start..], count);
+            }
+
+            // Alternatives: one choice per repeat of the pattern
+            'a' if chars.rest.starts_with("alt{") || chars.rest.starts_with("alt?{") => {
+                if tuplet.is_some() {
+                    return Err(ParseError {
+                        line: line_num,
+                        column: Some(column),
+                        message: "an alt group can't fall inside a tuplet".into(),
+                    });
+                }
+                let alt = take_alternatives(chars, octave, settings, line_num, dynamics.level)?;
+                events.push(Event::Alt(alt));
+            }
+
             // Note character
             _ => {
+                chars.next();
                 if let Some((name, oct_offset)) = char_to_note(c) {
-                    events.push(Event::Note(NoteEvent {
+                    let (length, chance) = note_timing(chars, line_num, column)?;
+                    let note = NoteEvent {
                         note: name,
                         octave: octave.saturating_add(oct_offset),
-                    }));
+                        degree: None,
+                        velocity: dynamics.level,
+                        spelling: settings.spelling,
+                        chance,
+                    };
+                    events.push(Event::Note(note, length));
+                } else if settings.strict {
+                    return Err(unknown_character(c, line_num, column));
                 }
-                // Unknown characters are silently skipped
-                chars.next();
+                // Otherwise unknown characters are skipped
             }
         }
     }
 
-    Ok(events)
+    if let Some((_, column)) = tuplet {
+        return Err(ParseError {
+            line: line_num,
+            column: Some(column),
+            message: "tuplet '(' is never closed (it must end on the same line)".into(),
+        });
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::beat::Beat;
+    use crate::test_util::temp_dir;
+
+    fn notes(events: &[Event]) -> String {
+        events
+            .iter()
+            .map(|e| match e {
+                Event::Note(n, _) => n.note.name().to_string(),
+                Event::Rest(_) => "-".to_string(),
+                Event::Tie(_) => "_".to_string(),
+                Event::BarLine => "|".to_string(),
+                _ => "?".to_string(),
+            })
+            .collect()
+    }
+
+    fn chord_names(event: &Event) -> Vec<String> {
+        match event {
+            Event::Chord(notes, _) => notes
+                .iter()
+                .map(|n| format!("{}{}", n.note.name(), n.octave))
+                .collect(),
+            other => panic!("expected chord, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ties_extend_across_bars_and_lines() {
+        let input = "a _ _ _ | _ _ - -\n[ad] _\n_ s";
+        let pattern = parse_pattern(input).unwrap();
+        assert_eq!(notes(&pattern.events[..9]), "C___|__--");
+        assert_eq!(pattern.length_beats(), Beat::whole(12));
+        assert_eq!(crate::note::tied_length(&pattern.events, 0), Beat::whole(6));
+        assert_eq!(crate::note::tied_length(&pattern.events, 9), Beat::whole(3));
+
+        let err = parse_pattern("_ a").unwrap_err();
+        assert_eq!((err.line, err.column), (1, Some(1)));
+        assert_eq!(err.message, "tie '_' has no note or chord before it to hold");
+        assert!(parse_pattern("a - _").is_err());
+        assert!(parse_pattern("[track: a]\na\n[track: b]\n_").is_err());
+    }
+
+    #[test]
+    fn test_chord_symbols() {
+        let pattern = parse_pattern("octave: 3\nCmaj Am | F G7").unwrap();
+        let chords: Vec<Vec<String>> = pattern
+            .events
+            .iter()
+            .filter(|e| matches!(e, Event::Chord(..)))
+            .map(chord_names)
+            .collect();
+        assert_eq!(
+            chords,
+            vec![
+                vec!["C3", "E3", "G3"],
+                vec!["A3", "C4", "E4"],
+                vec!["F3", "A3", "C4"],
+                vec!["G3", "B3", "D4", "F4"],
+            ]
+        );
+        assert_eq!(pattern.length_beats(), Beat::whole(4));
+
+        let pattern = parse_pattern("F#dim Bsus4 Eaug Dmaj7 Gm7 Csus2").unwrap();
+        let chords: Vec<Vec<String>> = pattern.events.iter().map(chord_names).collect();
+        assert_eq!(chords[0], vec!["F#4", "A4", "C5"]);
+        assert_eq!(chords[1], vec!["B4", "E5", "F#5"]);
+        assert_eq!(chords[2], vec!["E4", "G#4", "C5"]);
+        assert_eq!(chords[3], vec!["D4", "F#4", "A4", "C#5"]);
+        assert_eq!(chords[4], vec!["G4", "A#4", "D5", "F5"]);
+        assert_eq!(chords[5], vec!["C4", "D4", "G4"]);
+    }
+
+    #[test]
+    fn test_chord_symbol_mixes_with_keys() {
+        // Lowercase letters stay single notes
+        let pattern = parse_pattern("a C s").unwrap();
+        assert!(matches!(pattern.events[0], Event::Note(..)));
+        assert!(matches!(pattern.events[1], Event::Chord(..)));
+        assert!(matches!(pattern.events[2], Event::Note(..)));
+    }
+
+    #[test]
+    fn test_unknown_chord_quality() {
+        let err = parse_pattern("a\nCmaj9").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("'maj9'"), "{}", err);
+    }
+
+    #[test]
+    fn test_octave_shift() {
+        let octaves = |text: &str| -> Vec<u8> {
+            parse_pattern(text)
+                .unwrap()
+                .events
+                .iter()
+                .flat_map(|e| match e {
+                    Event::Note(n, _) => vec![n.octave],
+                    Event::Chord(notes, _) => notes.iter().map(|n| n.octave).collect(),
+                    _ => vec![],
+                })
+                .collect()
+        };
+        assert_eq!(octaves("a s d > a s d < a"), [4, 4, 4, 5, 5, 5, 4]);
+        // Lasts until the end of the line only
+        assert_eq!(octaves(">> a\na"), [6, 4]);
+        // Next-octave keys spill above the shifted octave
+        assert_eq!(octaves("> a k"), [5, 6]);
+        // Clamped at 0 and 8
+        assert_eq!(octaves("octave: 7\n>>> a <"), [8]);
+        assert_eq!(octaves("octave: 1\n<<< a > a"), [0, 1]);
+        // Inside a chord, and carried past it
+        assert_eq!(octaves("[a > g] a"), [4, 5, 5]);
+    }
+
+    #[test]
+    fn test_chord_spread_and_inversions() {
+        let voicings = |text: &str| -> Vec<Vec<String>> {
+            let pattern = parse_pattern(text).unwrap();
+            pattern.events.iter().map(chord_names).collect()
+        };
+        assert_eq!(voicings("[a d +g]"), [["C4", "E4", "G5"]]);
+        assert_eq!(voicings("[-a d g] [a ++d g]"), [["C3", "E4", "G4"], ["C4", "E6", "G4"]]);
+        // Each inversion moves the lowest note up, so the chord reads low to high
+        assert_eq!(voicings("[adg]^1 [adg]^2:2"), [["E4", "G4", "C5"], ["G4", "C5", "E5"]]);
+        assert_eq!(voicings("[-a d g]^1"), [["E4", "G4", "C4"]]);
+        // Next-octave keys spill first, then move
+        assert_eq!(voicings("[-k +a]"), [["C4", "C5"]]);
+        // Clamped at 0 and 8: a key already above 8 can only come down
+        assert_eq!(voicings("octave: 0\n[--a s]"), [["C0", "D0"]]);
+        assert_eq!(voicings("octave: 8\n[+a -k +k]^1"), [["C8", "C9", "C8"]]);
+        // With a key, degrees move the same way
+        assert_eq!(voicings("key: C major\n[1 +3 5]"), [["C4", "E5", "G4"]]);
+
+        let pattern = parse_pattern("[adg]^2:2").unwrap();
+        assert_eq!(pattern.length_beats(), Beat::whole(2));
+        let err = |text: &str| parse_pattern(text).unwrap_err();
+        let stray = err("a [a d +]");
+        assert_eq!(
+            stray.to_string(),
+            "line 1, column 8: '+' and '-' in a chord must come right before a note"
+        );
+        assert!(err("[a + d]").to_string().contains("column 4"));
+        assert!(err("[adg]^x").to_string().contains("invalid inversion '^'"));
+    }
+
+    #[test]
+    fn test_scale_degrees() {
+        let names = |text: &str| -> Vec<String> {
+            parse_pattern(text)
+                .unwrap()
+                .events
+                .iter()
+                .flat_map(|e| match e {
+                    Event::Note(n, _) => vec![format!("{}{}", n.note.name(), n.octave)],
+                    Event::Chord(notes, _) => notes
+                        .iter()
+                        .map(|n| format!("{}{}", n.note.name(), n.octave))
+                        .collect(),
+                    _ => vec![],
+                })
+                .collect()
+        };
+        assert_eq!(
+            names("key: D minor\n1 2 3 4 5 6 7 8"),
+            ["D4", "E4", "F4", "G4", "A4", "A#4", "C5", "D5"]
+        );
+        assert_eq!(names("key: A harmonic minor\noctave: 3\n7 9"), ["G#4", "B4"]);
+        assert_eq!(names("key: C major\nb3 #4 > 1 [1 3 5]"), ["D#4", "F#4", "C5", "C5", "E5", "G5"]);
+
+        let pattern = parse_pattern("key: G major\n3").unwrap();
+        assert_eq!(pattern.key.unwrap().to_string(), "G major");
+        let Event::Note(n, _) = &pattern.events[0] else {
+            panic!("expected a note");
+        };
+        assert_eq!(n.degree.unwrap().to_string(), "3");
+
+        // Without a key, digits are ignored as before
+        assert_eq!(names("a 3 s"), ["C4", "D4"]);
+
+        let err = parse_pattern("key: H major").unwrap_err();
+        assert!(err.message.contains("invalid key 'H major'"), "{}", err);
+        let err = parse_pattern("key: C major\na # s").unwrap_err();
+        assert!(err.message.contains("expected a scale degree"), "{}", err);
+    }
+
+    #[test]
+    fn test_flat_spellings() {
+        let labels = |text: &str, options: ParseOptions| -> Vec<String> {
+            let pattern = parse_pattern_with(text, options).unwrap();
+            pattern.to_timeline().iter().map(|(_, _, n)| n.label()).collect()
+        };
+        let default = ParseOptions::default;
+        assert_eq!(labels("w u Bb7", default()), ["C#4", "A#4", "Bb4", "D5", "F5", "Ab5"]);
+        // A chord's root spells the rest of it; a key or prefer_flats the letters
+        assert_eq!(labels("F#m Ebmaj", default()), ["F#4", "A4", "C#5", "Eb4", "G4", "Bb4"]);
+        assert_eq!(labels("key: F major\n4 u b2 #1", default()), ["Bb4", "Bb4", "Gb4", "F#4"]);
+        assert_eq!(labels("key: A# major\n1 u", default()), ["A#4", "A#4"]);
+        assert_eq!(labels("prefer_flats: true\nw Dm", default()), ["Db4", "D4", "F4", "A4"]);
+        let song = ParseOptions {
+            prefer_flats: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(labels("key: E major\n3 C#m", song), ["Ab4", "C#4", "E4", "G#4"]);
+
+        // Only the names differ
+        let sharp = parse_pattern("u").unwrap();
+        let flat = parse_pattern("prefer_flats: true\nu").unwrap();
+        let (Event::Note(a, _), Event::Note(b, _)) = (&sharp.events[0], &flat.events[0]) else {
+            panic!("expected notes");
+        };
+        assert_eq!((a.note, a.octave), (b.note, b.octave));
+        assert_eq!(b.spelling, Spelling::Flat);
+        let err = parse_pattern("Cbmaj").unwrap_err();
+        assert!(err.message.contains("invalid chord root 'Cb'"), "{}", err);
+    }
+
+    #[test]
+    fn test_repeats_expand() {
+        let pattern = parse_pattern("a |: s d :| f").unwrap();
+        assert_eq!(notes(&pattern.events), "C|DE|DE|F");
+        assert_eq!(pattern.length_beats(), Beat::whole(6));
+
+        let pattern = parse_pattern("|: a -\ns :|x3").unwrap();
+        assert_eq!(notes(&pattern.events), "|C-D|C-D|C-D|");
+        assert_eq!(pattern.length_beats(), Beat::whole(9));
+    }
+
+    #[test]
+    fn test_repeat_errors() {
+        let err = parse_pattern("a s :| d").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert!(err.message.contains("without a matching"), "{}", err);
+
+        let err = parse_pattern("|: a\ns d\n").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert!(err.message.contains("never closed"), "{}", err);
+
+        let err = parse_pattern("|: a\n|: s :| :|").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("nested"), "{}", err);
+
+        assert!(parse_pattern("|: a :|x0").is_err());
+        assert!(parse_pattern("|: a :x").is_err());
+    }
 
     #[test]
     fn test_char_mapping() {
@@ -319,7 +1845,7 @@ mod tests {
     fn test_parse_simple_melody() {
         let input = "tempo: 120\noctave: 4\n\na s d f";
         let comp = parse(input).unwrap();
-        assert_eq!(comp.tempo, 120);
+        assert_eq!(comp.tempo, 120.0);
         assert_eq!(comp.default_octave, 4);
         assert_eq!(comp.tracks.len(), 1);
 
@@ -327,17 +1853,31 @@ mod tests {
         assert_eq!(events.len(), 4);
         assert_eq!(
             events[0],
-            Event::Note(NoteEvent {
-                note: NoteName::C,
-                octave: 4
-            })
+            Event::Note(
+                NoteEvent {
+                    note: NoteName::C,
+                    octave: 4,
+                    degree: None,
+                    velocity: 1.0,
+                    spelling: Spelling::Sharp,
+                    chance: 1.0,
+                },
+                Beat::ONE
+            )
         );
         assert_eq!(
             events[3],
-            Event::Note(NoteEvent {
-                note: NoteName::F,
-                octave: 4
-            })
+            Event::Note(
+                NoteEvent {
+                    note: NoteName::F,
+                    octave: 4,
+                    degree: None,
+                    velocity: 1.0,
+                    spelling: Spelling::Sharp,
+                    chance: 1.0,
+                },
+                Beat::ONE
+            )
         );
     }
 
@@ -347,7 +1887,7 @@ mod tests {
         let comp = parse(input).unwrap();
         let events = &comp.tracks[0].events;
         assert_eq!(events.len(), 4);
-        assert_eq!(events[1], Event::Rest(1.0));
+        assert_eq!(events[1], Event::Rest(Beat::ONE));
         assert_eq!(events[2], Event::BarLine);
     }
 
@@ -356,7 +1896,159 @@ mod tests {
         let input = "a --- s";
         let comp = parse(input).unwrap();
         let events = &comp.tracks[0].events;
-        assert_eq!(events[1], Event::Rest(3.0));
+        assert_eq!(events[1], Event::Rest(Beat::whole(3)));
+    }
+
+    #[test]
+    fn test_parse_fractional_rests() {
+        let pattern = parse_pattern("a -:0.5 s -:1.5 d | -:.25").unwrap();
+        let rests: Vec<f64> = pattern
+            .events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Rest(beats) => Some(beats.as_f64()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rests, vec![0.5, 1.5, 0.25]);
+        assert_eq!(pattern.length_beats(), Beat::ratio(21, 4));
+
+        // `:|` right after a rest still ends a repeat
+        let pattern = parse_pattern("|: a -:| s").unwrap();
+        assert_eq!(pattern.length_beats(), Beat::whole(5));
+
+        let err = parse_pattern("a s -:0").unwrap_err();
+        assert_eq!((err.line, err.column), (1, Some(5)));
+        assert_eq!(err.message, "invalid rest length '-:0' (expected beats, e.g. '-:0.5')");
+        assert!(parse_pattern("a -:x").is_err());
+        let err = parse_pattern("a --:0.5").unwrap_err();
+        assert!(err.message.contains("one dash"), "{}", err);
+    }
+
+    #[test]
+    fn test_note_lengths_and_dots() {
+        let pattern = parse_pattern("a:1. s:0.5 [dg]:2 _:0.5 -:1. Am:.5");
+        let lengths: Vec<f64> =
+            pattern.unwrap().events.iter().map(|e| event_duration(e).as_f64()).collect();
+        assert_eq!(lengths, vec![1.5, 0.5, 2.0, 0.5, 1.5, 0.5]);
+
+        let pattern = parse_pattern("key: C major\n1:2 5:0.5 a").unwrap();
+        assert_eq!(pattern.length_beats(), Beat::ratio(7, 2));
+        let tied = parse_pattern("a:1. _:.5").unwrap();
+        assert_eq!(crate::note::tied_length(&tied.events, 0), Beat::whole(2));
+
+        let err = parse_pattern("a s:x").unwrap_err();
+        assert_eq!((err.line, err.column), (1, Some(3)));
+        assert_eq!(err.message, "invalid length ':x' (expected beats, e.g. ':0.5' or ':1.')");
+        assert!(parse_pattern("a:0").is_err());
+        assert!(parse_pattern("a:1..").is_err());
+    }
+
+    #[test]
+    fn test_time_signature_changes() {
+        let input = "time_signature: 4/4\na s d f |\ntime_signature: 7/8\na s d f g h j |\n\
+                     time_signature: 4/4\na s d f";
+        let comp = parse(input).unwrap();
+        assert_eq!(comp.time_signature, (4, 4));
+        let events: Vec<usize> = comp.tracks[0].meter_changes.iter().map(|c| c.event).collect();
+        assert_eq!(events, [5, 13]);
+        let meter = comp.into_pattern().meter;
+        assert_eq!(meter.changes().collect::<Vec<_>>(), [(4.0, (7, 8)), (11.0, (4, 4))]);
+
+        // A later track's changes count from its own start
+        let input = "[track: a]\na s\n[track: b]\ntime_signature: 3/4\nd f g";
+        let comp = parse(input).unwrap();
+        let meter = comp.track_pattern(&comp.tracks[1]).meter;
+        assert_eq!(meter.signature_at(0.0), (3, 4));
+
+        let err = parse_pattern("|: a s\ntime_signature: 3/4\nd :|")
+            .unwrap_err();
+        assert_eq!(err.message, "time_signature can't change inside a repeat");
+    }
+
+    #[test]
+    fn test_tuplets() {
+        let pattern = parse_pattern("(a s d)/3 f | (g h j)/3").unwrap();
+        let positions = crate::note::beat_positions(&pattern.events);
+        // The three notes of a triplet add up to exactly its span
+        let triplet: Beat = pattern.events[..3].iter().map(event_duration).sum();
+        assert_eq!(triplet, Beat::whole(2));
+        assert_eq!(positions[1], Beat::ratio(2, 3));
+        assert_eq!(positions[2], Beat::ratio(4, 3));
+        assert_eq!(positions[3], Beat::whole(2));
+        assert_eq!(pattern.length_beats(), Beat::whole(5));
+
+        // Lengths inside scale too: five in the time of four, a dotted
+        // note and a rest in a triplet
+        let pattern = parse_pattern("(a s d f g)/5 (h:2 -)/3").unwrap();
+        let spans: Vec<Beat> = pattern.events.iter().map(event_duration).collect();
+        assert_eq!(spans[..5].iter().copied().sum::<Beat>(), Beat::whole(4));
+        assert_eq!((spans[5], spans[6]), (Beat::ratio(4, 3), Beat::ratio(2, 3)));
+
+        let err = parse_pattern("(a (s d)/3 f)/3").unwrap_err();
+        assert_eq!((err.line, err.column), (1, Some(4)));
+        assert!(err.message.starts_with("nested tuplets are not supported"), "{}", err);
+        let err = parse_pattern("a (s d\nf)/3").unwrap_err();
+        assert_eq!((err.line, err.column), (1, Some(3)));
+        assert!(err.message.contains("never closed"), "{}", err);
+        assert!(parse_pattern("(a s d)").is_err());
+        assert!(parse_pattern("(a s d)/2").is_err());
+        assert!(parse_pattern("()/3").is_err());
+        assert!(parse_pattern("a s)/3").is_err());
+        assert!(parse_pattern("(a | s d)/3").is_err());
+    }
+
+    #[test]
+    fn test_chances_and_alternatives() {
+        let pattern = parse_pattern("a? s?0.25 d:2?0.5 f?0.1:0.5 [gh]? Cmaj?0").unwrap();
+        let chances: Vec<f64> =
+            pattern.events.iter().map(|e| e.notes()[0].chance).collect();
+        assert_eq!(chances, [0.5, 0.25, 0.5, 0.1, 0.5, 0.0]);
+        assert_eq!(event_duration(&pattern.events[2]), Beat::whole(2));
+        assert_eq!(event_duration(&pattern.events[3]), Beat::ratio(1, 2));
+        assert!(pattern.events[4].notes().iter().all(|n| n.chance == 0.5));
+
+        let pattern = parse_pattern("a alt{1: s > d | 2: f:2} !p! alt?{g | h}").unwrap();
+        let Event::Alt(first) = &pattern.events[1] else {
+            panic!("expected alt, got {:?}", pattern.events[1]);
+        };
+        assert!(!first.random);
+        assert_eq!(notes(&first.choices[0]), "DE");
+        // Octave shifts stay inside their choice
+        assert_eq!(first.choices[0][1].notes()[0].octave, 5);
+        assert_eq!(first.choices[1][0].notes()[0].octave, 4);
+        let Event::Alt(second) = &pattern.events[2] else {
+            panic!("expected alt, got {:?}", pattern.events[2]);
+        };
+        assert!(second.random);
+        assert!(second.choices[1][0].notes()[0].velocity < 1.0);
+        assert_eq!(pattern.length_beats(), Beat::whole(4));
+
+        // With a key, `1:` then a space is a label and `2:2` a long degree
+        let pattern = parse_pattern("key: C major
+alt{1: 2:2 | 2: 3}").unwrap();
+        let Event::Alt(alt) = &pattern.events[0] else {
+            panic!("expected alt, got {:?}", pattern.events[0]);
+        };
+        assert_eq!(event_duration(&alt.choices[0][0]), Beat::whole(2));
+
+        let error = |text: &str| parse_pattern(text).unwrap_err();
+        let err = error("a alt{1: s | 3: d}");
+        assert_eq!((err.line, err.column), (1, Some(14)));
+        assert!(err.message.contains("labels count up from 1"), "{}", err);
+        let err = error("s a?1.5");
+        assert_eq!(err.column, Some(4));
+        assert!(err.message.starts_with("invalid chance '?1.5'"), "{}", err);
+        assert_eq!(error("a??").column, Some(3));
+        assert_eq!(error("[ad]?:2?0.5").column, Some(8));
+        assert!(error("a ? s").message.contains("must follow a note"));
+        assert!(error("a -?0.3").message.contains("must follow a note"));
+        assert!(error("alt{a s\n| d}").message.contains("never closed"));
+        assert!(error("alt{a}").message.contains("two or more choices"));
+        assert!(error("alt{a | alt{s | d}}").message.contains("nested"));
+        assert!(error("alt{ | }").message.contains("empty"));
+        assert!(error("(a alt{s | d})/3").message.contains("tuplet"));
+        assert!(error("alt{_ | a}").message.contains("tie"));
     }
 
     #[test]
@@ -366,7 +2058,7 @@ mod tests {
         let comp = parse(input).unwrap();
         let events = &comp.tracks[0].events;
         assert_eq!(events.len(), 1);
-        if let Event::Chord(notes) = &events[0] {
+        if let Event::Chord(notes, _) = &events[0] {
             assert_eq!(notes.len(), 3);
             assert_eq!(notes[0].note, NoteName::C);
             assert_eq!(notes[1].note, NoteName::E);
@@ -391,6 +2083,154 @@ a --- a ---";
         assert_eq!(comp.tracks[1].name, "bass");
     }
 
+    #[test]
+    fn test_patch_paths_resolve_against_the_file() {
+        let input = "patch: ../shared/lead.instr\n[track: a]\npatch: pad\na\n\
+                     [track: b]\npatch: /abs/bass.instr\ns\n";
+        let options = ParseOptions::for_file(Path::new("sub/dir/song.notes"), false);
+        let comp = parse_with(input, options).unwrap();
+        assert_eq!(comp.default_patch.as_deref(), Some("sub/dir/../shared/lead.instr"));
+        // Presets and absolute paths are left alone
+        assert_eq!(comp.tracks[0].patch.as_deref(), Some("pad"));
+        assert_eq!(comp.tracks[1].patch.as_deref(), Some("/abs/bass.instr"));
+
+        // Text that isn't from a file resolves against the working directory
+        let comp = parse(input).unwrap();
+        assert_eq!(comp.default_patch.as_deref(), Some("../shared/lead.instr"));
+    }
+
+    #[test]
+    fn test_errors_name_the_file() {
+        let options = ParseOptions::for_file(Path::new("sub/lead.notes"), true);
+        let err = parse_with("a s\nd q", options).unwrap_err();
+        assert_eq!(err.to_string(), "sub/lead.notes: line 2, column 3: unknown character 'q'");
+        match err {
+            ClidawError::Parse { line, col, .. } => assert_eq!((line, col), (2, Some(3))),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_track_octave_and_patch_stay_in_their_track() {
+        let input = "\
+patch: pluck
+octave: 5
+[track: bass]
+octave: 2
+patch: bass.instr
+a s
+[track: lead]
+a s";
+        let comp = parse(input).unwrap();
+        assert_eq!(comp.default_octave, 5);
+        let octaves = |t: &Track| -> Vec<u8> {
+            t.events
+                .iter()
+                .filter_map(|e| match e {
+                    Event::Note(n, _) => Some(n.octave),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(octaves(&comp.tracks[0]), vec![2, 2]);
+        assert_eq!(octaves(&comp.tracks[1]), vec![5, 5]);
+        assert_eq!(comp.track_patch(&comp.tracks[0]), Some("bass.instr"));
+        assert_eq!(comp.track_patch(&comp.tracks[1]), Some("pluck"));
+        assert!(comp.has_tracks());
+        assert_eq!(comp.track_pattern(&comp.tracks[1]).length_beats(), Beat::whole(2));
+
+        // As a single pattern the tracks play one after the other
+        let pattern = parse_pattern(input).unwrap();
+        assert_eq!(pattern.events.len(), 4);
+        assert!(!parse("a s d").unwrap().has_tracks());
+    }
+
+    #[test]
+    fn test_include_splices_events() {
+        let dir = temp_dir("include");
+        fs::create_dir_all(dir.join("riffs")).unwrap();
+        fs::write(dir.join("riffs/motif.notes"), "g h\ninclude: tail.notes\n").unwrap();
+        fs::write(dir.join("riffs/tail.notes"), "octave: 5\nj\n").unwrap();
+        let main = dir.join("main.notes");
+        fs::write(&main, "octave: 3\na s |\ninclude: riffs/motif.notes\nd\n").unwrap();
+
+        let input = fs::read_to_string(&main).unwrap();
+        let comp = parse_with(&input, ParseOptions::for_file(&main, false)).unwrap();
+        let track = &comp.tracks[0];
+        let names: Vec<String> = track
+            .events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Note(n, _) => Some(format!("{}{}", n.note.name(), n.octave)),
+                _ => None,
+            })
+            .collect();
+        // The motif starts in the including file's octave; its own octave
+        // change stays inside it
+        assert_eq!(names, ["C3", "D3", "G3", "A3", "B5", "E3"]);
+        assert_eq!(
+            track.includes,
+            vec![
+                Include {
+                    file: dir.join("riffs/motif.notes"),
+                    events: 3..6,
+                },
+                Include {
+                    file: dir.join("riffs/tail.notes"),
+                    events: 5..6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = temp_dir("include-errors");
+        fs::create_dir_all(dir.join("riffs")).unwrap();
+        let parse_file = |name: &str| {
+            let path = dir.join(name);
+            let input = fs::read_to_string(&path).unwrap();
+            parse_composition(&input, &ParseOptions::for_file(&path, false)).unwrap_err()
+        };
+        fs::write(dir.join("a.notes"), "a\ninclude: riffs/b.notes\n").unwrap();
+        fs::write(dir.join("riffs/b.notes"), "s\ninclude: ../a.notes\n").unwrap();
+        let err = parse_file("a.notes");
+        assert_eq!(err.line, 2);
+        let chain = format!(
+            "include cycle: {} -> {} -> {}",
+            dir.join("a.notes").display(),
+            dir.join("riffs/b.notes").display(),
+            dir.join("riffs/../a.notes").display()
+        );
+        assert!(err.message.ends_with(&chain), "{}", err);
+
+        fs::write(dir.join("self.notes"), "include: self.notes\n").unwrap();
+        assert!(parse_file("self.notes").message.starts_with("include cycle"));
+
+        // Errors inside an included file say where they are
+        fs::write(dir.join("riffs/bad.notes"), "a\ns |:\n").unwrap();
+        fs::write(dir.join("outer.notes"), "a\n\ninclude: riffs/bad.notes\n").unwrap();
+        let err = parse_file("outer.notes");
+        assert_eq!(err.line, 3);
+        let expected = format!(
+            "in {}: line 2, column 3: '|:' is never closed with ':|'",
+            dir.join("riffs/bad.notes").display()
+        );
+        assert_eq!(err.message, expected);
+
+        fs::write(dir.join("missing.notes"), "include: nowhere.notes\n").unwrap();
+        assert!(parse_file("missing.notes").message.contains("can't read included file"));
+        fs::write(dir.join("riffs/parts.notes"), "[track: a]\na\n[track: b]\ns\n").unwrap();
+        fs::write(dir.join("parts.notes"), "include: riffs/parts.notes\n").unwrap();
+        assert!(parse_file("parts.notes").message.contains("single part"));
+
+        for depth in 0..=MAX_INCLUDE_DEPTH {
+            let text = format!("a\ninclude: deep{}.notes\n", depth + 1);
+            fs::write(dir.join(format!("deep{}.notes", depth)), text).unwrap();
+        }
+        assert!(parse_file("deep0.notes").message.contains("nest more than 16 files deep"));
+    }
+
     #[test]
     fn test_comments_ignored() {
         let input = "# this is a comment\na s d";
@@ -398,11 +2238,22 @@ a --- a ---";
         assert_eq!(comp.tracks[0].events.len(), 3);
     }
 
+    #[test]
+    fn test_metadata_directives() {
+        let input = "title: Verse: take 2\nauthor: KM\n\
+                     description: Slow\ndescription: then fast\na s";
+        let comp = parse(input).unwrap();
+        assert_eq!(comp.metadata.title.as_deref(), Some("Verse: take 2"));
+        assert_eq!(comp.metadata.author.as_deref(), Some("KM"));
+        assert_eq!(comp.metadata.description.as_deref(), Some("Slow\nthen fast"));
+        assert_eq!(notes(&comp.tracks[0].events), "CD");
+    }
+
     #[test]
     fn test_parse_pattern_beats_and_loop() {
         let input = "beats: 4\nloop: true\noctave: 4\na s d f";
         let pattern = parse_pattern(input).unwrap();
-        assert_eq!(pattern.beats, 4.0);
+        assert_eq!(pattern.beats, Beat::whole(4));
         assert!(pattern.loop_pattern);
         assert_eq!(pattern.default_octave, 4);
         assert_eq!(pattern.events.len(), 4);
@@ -412,7 +2263,158 @@ a --- a ---";
     fn test_parse_pattern_computed_beats() {
         let input = "octave: 4\na s d f";
         let pattern = parse_pattern(input).unwrap();
-        assert_eq!(pattern.computed_beats(), 4.0);
-        assert_eq!(pattern.length_beats(), 4.0);
+        assert_eq!(pattern.computed_beats(), Beat::whole(4));
+        assert_eq!(pattern.length_beats(), Beat::whole(4));
+    }
+
+    #[test]
+    fn test_parse_drum_block() {
+        let input = include_str!("../examples/drums.notes");
+        let pattern = parse_pattern(input).unwrap();
+        assert_eq!(pattern.length_beats(), Beat::whole(4));
+        assert_eq!(
+            pattern.events,
+            vec![
+                Event::Drums(vec![Drum::Kick, Drum::Hat], Beat::ONE),
+                Event::Drums(vec![Drum::Kick, Drum::Snare, Drum::Hat], Beat::ONE),
+                Event::Drums(vec![Drum::Kick, Drum::Hat], Beat::ONE),
+                Event::Drums(vec![Drum::Kick, Drum::Snare, Drum::Hat], Beat::ONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drum_lines_pad_and_separate_blocks() {
+        let input = "kick: x - x\nhat: x\n\nsnare: - x";
+        let pattern = parse_pattern(input).unwrap();
+        assert_eq!(
+            pattern.events,
+            vec![
+                Event::Drums(vec![Drum::Kick, Drum::Hat], Beat::ONE),
+                Event::Rest(Beat::ONE),
+                Event::Drums(vec![Drum::Kick], Beat::ONE),
+                Event::Rest(Beat::ONE),
+                Event::Drums(vec![Drum::Snare], Beat::ONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drum_step_sets_the_length_of_later_steps() {
+        let input = "kick: x -\n\ndrum_step: 0.25\nkick: x -\n";
+        let pattern = parse_pattern(input).unwrap();
+        let quarter = Beat::ratio(1, 4);
+        assert_eq!(
+            pattern.events,
+            vec![
+                Event::Drums(vec![Drum::Kick], Beat::ONE),
+                Event::Rest(Beat::ONE),
+                Event::Drums(vec![Drum::Kick], quarter),
+                Event::Rest(quarter),
+            ]
+        );
+        let err = parse_pattern("drum_step: 0").unwrap_err();
+        assert!(err.message.starts_with("invalid drum_step"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_error_columns() {
+        let err = parse_pattern("a s\n  d |: f").unwrap_err();
+        assert_eq!((err.line, err.column), (2, Some(5)));
+        assert_eq!(err.to_string(), "line 2, column 5: '|:' is never closed with ':|'");
+
+        let err = parse_pattern("a Cmaj9").unwrap_err();
+        assert_eq!(err.column, Some(3));
+        let err = parse_pattern("kick: x o").unwrap_err();
+        assert_eq!(err.column, Some(9));
+        // Directive errors have no column
+        let err = parse_pattern("tempo: fast").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: invalid tempo: fast");
+        assert!(parse_pattern("tempo: -60").is_err());
+        let comp = parse("tempo: 93.5").unwrap();
+        assert_eq!(comp.tempo, 93.5);
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_unknown_characters() {
+        // Lenient by default: the typo just disappears
+        assert_eq!(parse_pattern("a q s").unwrap().events.len(), 2);
+
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let err = parse_composition("# comment\n\na s | [dq] -", &strict).unwrap_err();
+        assert_eq!((err.line, err.column), (3, Some(9)));
+        assert_eq!(err.message, "unknown character 'q'");
+        let err = parse_pattern("strict: true\n a q s").unwrap_err();
+        assert_eq!((err.line, err.column), (2, Some(4)));
+
+        // Everything the parser knows is still fine
+        let text = "key: C major\n|: a - [s d] > 3 < Cmaj :|x2 |\n\tkick: x -\nhat:  x x";
+        assert!(parse_pattern_with(text, strict).is_ok());
+    }
+
+    fn velocities(events: &[Event]) -> Vec<f64> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Note(n, _) => Some((n.velocity * 100.0).round() / 100.0),
+                Event::Chord(notes, _) => Some((notes[0].velocity * 100.0).round() / 100.0),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dynamics_marks_and_hairpins() {
+        // Marks set the level; the default is full
+        let pat = parse_pattern("a !p! s [df] !ff! g").unwrap();
+        assert_eq!(velocities(&pat.events), vec![1.0, 0.4, 0.4, 1.0]);
+
+        // A crescendo ramps to the mark after it...
+        let pat = parse_pattern("!p! !cresc! a s d !/cresc! !f! g").unwrap();
+        assert_eq!(velocities(&pat.events), vec![0.4, 0.63, 0.85, 0.85]);
+        // ...across lines and to a directive, or one mark past its start
+        let pat = parse_pattern("dyn: f\n!decresc! a s\nd !/decresc!\ndyn: p\nf").unwrap();
+        assert_eq!(velocities(&pat.events), vec![0.85, 0.63, 0.4, 0.4]);
+        let pat = parse_pattern("!mp! !cresc! a s !/cresc! d").unwrap();
+        assert_eq!(velocities(&pat.events), vec![0.55, 0.7, 0.7]);
+
+        // `<` and `>` still shift the octave, even around a mark's name
+        let mut pat = parse_pattern("<a >s").unwrap();
+        let octaves: Vec<u8> = pat.events.iter_mut().map(|e| e.notes_mut()[0].octave).collect();
+        assert_eq!(octaves, vec![3, 4]);
+        let pat = parse_pattern("a <f> s").unwrap();
+        let notes: Vec<(NoteName, u8)> =
+            pat.events.iter().map(|e| (e.notes()[0].note, e.notes()[0].octave)).collect();
+        assert_eq!(notes, vec![(NoteName::C, 4), (NoteName::F, 3), (NoteName::D, 4)]);
+        assert_eq!(velocities(&pat.events), vec![1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_dynamics_errors() {
+        let err = parse_pattern("!cresc! a\n!decresc! s").unwrap_err();
+        assert_eq!((err.line, err.column), (2, Some(1)));
+        assert!(err.message.contains("nested"), "{}", err.message);
+
+        let err = parse_pattern("a !cresc! s").unwrap_err();
+        assert_eq!(err.to_string(), "line 1, column 3: '!cresc!' is never closed with '!/cresc!'");
+        let err = parse_pattern("a !/decresc!").unwrap_err();
+        assert_eq!(err.message, "'!/decresc!' without a matching '!decresc!'");
+        let strict = ParseOptions { strict: true, ..ParseOptions::default() };
+        let err = parse_composition("a !loud! s", &strict).unwrap_err();
+        assert_eq!((err.line, err.column), (1, Some(3)));
+        let err = parse_pattern("dyn: loud").unwrap_err();
+        assert!(err.message.starts_with("invalid dynamic 'loud'"));
+    }
+
+    #[test]
+    fn test_drum_line_errors() {
+        let err = parse_pattern("kick: x o x").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert!(err.message.contains("'o'"));
+        let err = parse_pattern("kick: x\nkick: x").unwrap_err();
+        assert_eq!(err.line, 2);
     }
 }
@@ -0,0 +1,299 @@
+//! Scale practice for live mode (`clidaw practice`).
+//!
+//! The banner shows which keyboard keys play notes of the scale. With
+//! `--strict` the others are muted, and with `--quiz` a random note of the
+//! scale plays for the player to find and play back; any octave counts.
+//! Like the looper, the quiz is driven by the times passed in, so the event
+//! loop and the tests run the same logic.
+
+use std::time::{Duration, Instant};
+
+use crate::keymap::{Keymap, KeyboardRow};
+use crate::note::{Key, NoteName};
+use crate::rng::Rng;
+
+/// How long a quiz note sounds
+const QUESTION_NOTE: Duration = Duration::from_millis(900);
+
+/// Wait after an answer (and before the first question) so the answer is
+/// heard before the next note plays
+const ANSWER_PAUSE: Duration = Duration::from_millis(1200);
+
+/// What `clidaw practice` was asked for
+#[derive(Debug, Clone, Copy)]
+pub struct Practice {
+    pub key: Key,
+    /// Octave the keyboard starts in and quiz notes play in
+    pub octave: u8,
+    /// Mute keys that aren't in the scale
+    pub strict: bool,
+    /// Play notes for the player to echo
+    pub quiz: bool,
+}
+
+impl Practice {
+    /// Whether a key playing `note` sounds
+    pub fn allows(&self, note: NoteName) -> bool {
+        !self.strict || self.key.contains(note)
+    }
+
+    /// What the practice line says when a muted key is pressed
+    pub fn nudge(&self, note: NoteName) -> String {
+        format!(
+            "{} isn't in {}: try {}",
+            note.spelled(self.key.spelling),
+            self.key,
+            self.scale_names()
+        )
+    }
+
+    /// The scale's notes, spelled for its key, e.g. "D E F G A B C"
+    pub fn scale_names(&self) -> String {
+        let spelling = self.key.spelling;
+        let names: Vec<&str> = self.key.notes().iter().map(|n| n.spelled(spelling)).collect();
+        names.join(" ")
+    }
+
+    /// Banner lines: the scale, and the keyboard with only the keys in it
+    /// showing (the rest are dots)
+    pub fn banner_lines(&self, keymap: &Keymap) -> Vec<String> {
+        let (top, bottom) = keymap.keyboard_rows();
+        let mut lines = vec![format!("  {:<16}{} ({})", "Scale:", self.key, self.scale_names())];
+        lines.push(format!("  {:<15}{}", "In scale:", self.scale_row(keymap, &top)));
+        lines.push(format!("  {:<15}{}", "", self.scale_row(keymap, &bottom)));
+        let mut how = Vec::new();
+        if self.strict {
+            how.push("other keys are muted");
+        }
+        if self.quiz {
+            how.push("play back each note you hear (Enter plays it again)");
+        }
+        if !how.is_empty() {
+            lines.push(format!("  {:<16}{}", "Practice:", how.join("; ")));
+        }
+        lines.push(String::new());
+        lines
+    }
+
+    /// One keyboard row laid out like the status block's, keys outside the
+    /// scale shown as dots
+    fn scale_row(&self, keymap: &Keymap, row: &KeyboardRow) -> String {
+        let mut line = String::new();
+        for &(column, key) in row {
+            let start = column * 2;
+            if start < line.chars().count() {
+                continue;
+            }
+            line.push_str(&" ".repeat(start - line.chars().count()));
+            let in_scale = keymap.lookup(key).is_some_and(|(note, _)| self.key.contains(note));
+            line.push_str(&format!(" {} ", if in_scale { key } else { '·' }));
+        }
+        line.trim_end().to_string()
+    }
+}
+
+/// What the quiz wants played
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cue {
+    /// Start the question's note (Hz)
+    Play(f64),
+    /// End it
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    /// Between questions, until the next one
+    Pause(Instant),
+    /// The question's note is sounding, since then
+    Asking(Instant),
+    /// Waiting for the player's answer
+    Listening,
+}
+
+/// The quiz: ask a note, wait for the answer, score it, pause, ask again
+#[derive(Debug, Clone)]
+pub struct Quiz {
+    key: Key,
+    octave: u8,
+    rng: Rng,
+    stage: Stage,
+    /// The note asked, and its MIDI number
+    question: Option<(NoteName, u8)>,
+    right: usize,
+    asked: usize,
+    /// What the last answer got
+    verdict: Option<String>,
+}
+
+impl Quiz {
+    /// A quiz on `practice`'s scale, asking its first question a pause
+    /// after `now`
+    pub fn new(practice: &Practice, rng: Rng, now: Instant) -> Self {
+        Self {
+            key: practice.key,
+            octave: practice.octave,
+            rng,
+            stage: Stage::Pause(now + ANSWER_PAUSE),
+            question: None,
+            right: 0,
+            asked: 0,
+            verdict: None,
+        }
+    }
+
+    /// Move on at `now`: ask the next question once the pause is over, and
+    /// end its note once it has sounded long enough
+    pub fn tick(&mut self, now: Instant) -> Option<Cue> {
+        match self.stage {
+            Stage::Pause(until) if now >= until => {
+                let midi = self.pick();
+                self.stage = Stage::Asking(now);
+                Some(Cue::Play(crate::note::midi_to_freq(midi)))
+            }
+            Stage::Asking(since) if now.duration_since(since) >= QUESTION_NOTE => {
+                self.stage = Stage::Listening;
+                Some(Cue::Stop)
+            }
+            _ => None,
+        }
+    }
+
+    /// A new note of the scale from the tonic up, not the one just asked
+    fn pick(&mut self) -> u8 {
+        let notes = self.key.notes();
+        let mut idx = (self.rng.next_u64() % 7) as usize;
+        if self.question.is_some_and(|(note, _)| note == notes[idx]) {
+            idx = (idx + 1) % 7;
+        }
+        let interval = self.key.scale.intervals()[idx];
+        let midi = self.key.tonic.to_midi(self.octave) + interval;
+        self.question = Some((notes[idx], midi));
+        midi
+    }
+
+    /// Play the question again (Enter), if one is waiting for an answer
+    pub fn replay(&mut self, now: Instant) -> Option<f64> {
+        let (_, midi) = self.question?;
+        match self.stage {
+            Stage::Asking(_) | Stage::Listening => {
+                self.stage = Stage::Asking(now);
+                Some(crate::note::midi_to_freq(midi))
+            }
+            Stage::Pause(_) => None,
+        }
+    }
+
+    /// The player played `note`. If a question was waiting, it is scored
+    /// and the next one asked after a pause; returns whether it was right.
+    pub fn answer(&mut self, note: NoteName, now: Instant) -> Option<bool> {
+        let (asked, _) = self.question?;
+        if matches!(self.stage, Stage::Pause(_)) {
+            return None;
+        }
+        let right = note == asked;
+        self.asked += 1;
+        let spelled = |n: NoteName| n.spelled(self.key.spelling);
+        self.verdict = Some(if right {
+            self.right += 1;
+            format!("Right, {}", spelled(asked))
+        } else {
+            format!("No: that was {}, the note was {}", spelled(note), spelled(asked))
+        });
+        self.stage = Stage::Pause(now + ANSWER_PAUSE);
+        Some(right)
+    }
+
+    /// Whether the question's note is sounding
+    pub fn sounding(&self) -> bool {
+        matches!(self.stage, Stage::Asking(_))
+    }
+
+    /// The practice line: the last verdict or what to do, and the score
+    pub fn line(&self) -> String {
+        let prompt = match self.stage {
+            Stage::Pause(_) if self.question.is_none() => "Listen...",
+            Stage::Pause(_) => self.verdict.as_deref().unwrap_or("Listen..."),
+            _ => "Which note was that? Play it back",
+        };
+        format!("Quiz: {}  |  Score: {}/{}", prompt, self.right, self.asked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn practice(scale: &str, strict: bool) -> Practice {
+        Practice {
+            key: Key::parse(scale).unwrap(),
+            octave: 4,
+            strict,
+            quiz: true,
+        }
+    }
+
+    #[test]
+    fn test_banner_shows_only_scale_keys() {
+        let d_dorian = practice("D dorian", false);
+        let lines = d_dorian.banner_lines(&Keymap::builtin());
+        assert_eq!(lines[0], "  Scale:          D dorian (D E F G A B C)");
+        // D dorian has no sharps: every top-row key is a dot
+        assert_eq!(lines[1], "  In scale:         ·   ·       ·   ·   ·       ·   ·");
+        assert_eq!(lines[2], "                  a   s   d   f   g   h   j   k   l   ;   '");
+        let lines = practice("E major", false).banner_lines(&Keymap::builtin());
+        assert_eq!(lines[1], "  In scale:         w   e       t   y   ·       o   p");
+        assert_eq!(lines[2], "                  ·   ·   d   ·   ·   h   j   ·   ·   ;   ·");
+        let g_dorian = practice("G dorian", true);
+        assert_eq!(g_dorian.scale_names(), "G A Bb C D E F");
+        assert!(g_dorian.banner_lines(&Keymap::builtin())[3].contains("muted"));
+    }
+
+    #[test]
+    fn test_strict_mutes_notes_outside_the_scale() {
+        let loose = practice("A minor", false);
+        assert!(loose.allows(NoteName::CSharp));
+        let strict = practice("A minor", true);
+        assert!(strict.allows(NoteName::C));
+        assert!(!strict.allows(NoteName::CSharp));
+        assert_eq!(strict.nudge(NoteName::CSharp), "C# isn't in A minor: try A B C D E F G");
+    }
+
+    #[test]
+    fn test_quiz_asks_listens_and_scores() {
+        let practice = practice("D dorian", false);
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut quiz = Quiz::new(&practice, Rng::new(3), start);
+        // Nothing is asked yet, so nothing can be answered
+        assert_eq!(quiz.tick(at(100)), None);
+        assert_eq!(quiz.answer(NoteName::D, at(100)), None);
+        assert_eq!(quiz.line(), "Quiz: Listen...  |  Score: 0/0");
+
+        let Some(Cue::Play(freq)) = quiz.tick(at(1200)) else {
+            panic!("expected the first question");
+        };
+        let (asked, midi) = quiz.question.unwrap();
+        assert!(practice.key.contains(asked));
+        assert!((62..74).contains(&midi), "{}", midi);
+        assert_eq!(freq, crate::note::midi_to_freq(midi));
+        assert!(quiz.sounding());
+        assert_eq!(quiz.tick(at(2100)), Some(Cue::Stop));
+        assert_eq!(quiz.replay(at(3000)), Some(freq));
+        assert_eq!(quiz.tick(at(3900)), Some(Cue::Stop));
+
+        // Right in any octave; then a pause before the next question
+        assert_eq!(quiz.answer(asked, at(4000)), Some(true));
+        assert_eq!(quiz.line(), format!("Quiz: Right, {}  |  Score: 1/1", asked.name()));
+        assert_eq!(quiz.answer(asked, at(4100)), None);
+        assert_eq!(quiz.replay(at(4100)), None);
+        assert_eq!(quiz.tick(at(5100)), None);
+        assert!(matches!(quiz.tick(at(5200)), Some(Cue::Play(_))));
+        let (next, _) = quiz.question.unwrap();
+        assert_ne!(next, asked);
+        let wrong = if next == NoteName::C { NoteName::D } else { NoteName::C };
+        assert_eq!(quiz.answer(wrong, at(5300)), Some(false));
+        assert!(quiz.line().starts_with(&format!("Quiz: No: that was {}", wrong.name())));
+        assert!(quiz.line().ends_with("Score: 1/2"));
+    }
+}
@@ -0,0 +1,198 @@
+//! `live --record`: capture the engine's output to a WAV file.
+//!
+//! The audio callback hands each buffer to a writer thread without ever
+//! waiting on it: buffers come from a fixed pool and go back once written.
+//! If the writer falls behind and the pool runs dry, buffers are dropped and
+//! counted rather than stalling the audio.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::error::ClidawError;
+use crate::render::{self, BitDepth};
+
+/// Recordings are 16-bit PCM, like `render`'s default
+const DEPTH: BitDepth = BitDepth::Int16;
+
+/// Buffers in flight between the callback and the writer
+const POOL_BUFFERS: usize = 32;
+
+/// Initial capacity of each pooled buffer, in samples (enough for the usual
+/// device buffer sizes without reallocating)
+const BUFFER_CAPACITY: usize = 8192;
+
+/// Audio-thread end of a recording: copies each rendered buffer out
+pub struct Tap {
+    full_tx: SyncSender<Vec<f32>>,
+    free_rx: Receiver<Vec<f32>>,
+    channels: usize,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Tap {
+    /// Queue interleaved samples for writing; never blocks. Counts the
+    /// frames as dropped if no pooled buffer is free.
+    pub fn push(&self, samples: &[f32]) {
+        let Ok(mut buf) = self.free_rx.try_recv() else {
+            self.dropped
+                .fetch_add(samples.len() / self.channels, Ordering::Relaxed);
+            return;
+        };
+        buf.clear();
+        buf.extend_from_slice(samples);
+        if self.full_tx.try_send(buf).is_err() {
+            self.dropped
+                .fetch_add(samples.len() / self.channels, Ordering::Relaxed);
+        }
+    }
+}
+
+/// What was written once a recording is finished
+#[derive(Debug, Clone)]
+pub struct RecordingSummary {
+    pub path: PathBuf,
+    pub frames: usize,
+    pub sample_rate: u32,
+    /// Frames lost because the writer fell behind
+    pub dropped_frames: usize,
+}
+
+impl RecordingSummary {
+    pub fn seconds(&self) -> f64 {
+        self.frames as f64 / self.sample_rate as f64
+    }
+}
+
+/// Writer-thread end of a recording
+pub struct Recorder {
+    path: PathBuf,
+    sample_rate: u32,
+    writer: JoinHandle<Result<usize, ClidawError>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    /// Create `path` and start the writer thread. The file's format matches
+    /// the stream: `sample_rate` and `channels` as negotiated with the device.
+    pub fn start(
+        path: &Path,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Result<(Recorder, Tap), ClidawError> {
+        Self::with_pool(path, sample_rate, channels, POOL_BUFFERS)
+    }
+
+    fn with_pool(
+        path: &Path,
+        sample_rate: u32,
+        channels: u16,
+        buffers: usize,
+    ) -> Result<(Recorder, Tap), ClidawError> {
+        let io_error = |e| ClidawError::io(path, e);
+        let mut file = BufWriter::new(File::create(path).map_err(io_error)?);
+        // Placeholder sizes, filled in by `finish`
+        file.write_all(&render::wav_header(channels, sample_rate, DEPTH, 0))
+            .map_err(io_error)?;
+
+        let (full_tx, full_rx) = mpsc::sync_channel::<Vec<f32>>(buffers);
+        let (free_tx, free_rx) = mpsc::sync_channel::<Vec<f32>>(buffers);
+        for _ in 0..buffers {
+            let _ = free_tx.send(Vec::with_capacity(BUFFER_CAPACITY));
+        }
+
+        let writer_path = path.to_path_buf();
+        let writer = thread::spawn(move || {
+            let io_error = |e| ClidawError::io(&writer_path, e);
+            let mut samples = 0;
+            let mut bytes = Vec::new();
+            // Ends when the tap is dropped along with the audio stream
+            for buf in full_rx {
+                bytes.clear();
+                render::push_wav_samples(&mut bytes, &buf, DEPTH);
+                file.write_all(&bytes).map_err(io_error)?;
+                samples += buf.len();
+                let _ = free_tx.try_send(buf);
+            }
+            let frames = samples / channels as usize;
+            file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+            file.write_all(&render::wav_header(channels, sample_rate, DEPTH, frames))
+                .map_err(io_error)?;
+            file.flush().map_err(io_error)?;
+            Ok(frames)
+        });
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let tap = Tap {
+            full_tx,
+            free_rx,
+            channels: channels.max(1) as usize,
+            dropped: Arc::clone(&dropped),
+        };
+        let recorder = Recorder {
+            path: path.to_path_buf(),
+            sample_rate,
+            writer,
+            dropped,
+        };
+        Ok((recorder, tap))
+    }
+
+    /// Wait for the writer to drain and fix up the WAV header. The `Tap`
+    /// must have been dropped first (it is owned by the audio stream).
+    pub fn finish(self) -> Result<RecordingSummary, ClidawError> {
+        let frames = self
+            .writer
+            .join()
+            .map_err(|_| ClidawError::Audio("recording writer thread panicked".to_string()))??;
+        Ok(RecordingSummary {
+            path: self.path,
+            frames,
+            sample_rate: self.sample_rate,
+            dropped_frames: self.dropped.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("clidaw-record-{}-{}.wav", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_recording_finalizes_header() {
+        let path = temp_path("header");
+        let (recorder, tap) = Recorder::start(&path, 44_100, 2).unwrap();
+        tap.push(&[0.0, 0.0, 1.0, -1.0]);
+        tap.push(&[0.5, 0.5]);
+        drop(tap);
+        let summary = recorder.finish().unwrap();
+        assert_eq!(summary.frames, 3);
+        assert_eq!(summary.dropped_frames, 0);
+
+        // Byte-for-byte what `render` writes for the same samples
+        let samples = [0.0, 0.0, 1.0, -1.0, 0.5, 0.5];
+        let expected = render::encode(&samples, 44_100, render::AudioFormat::Wav, DEPTH).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_full_pool_drops_and_counts() {
+        let path = temp_path("drop");
+        let (recorder, tap) = Recorder::with_pool(&path, 48_000, 1, 0).unwrap();
+        tap.push(&[0.1; 64]);
+        drop(tap);
+        let summary = recorder.finish().unwrap();
+        assert_eq!(summary.frames, 0);
+        assert_eq!(summary.dropped_frames, 64);
+        let _ = std::fs::remove_file(&path);
+    }
+}
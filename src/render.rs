@@ -0,0 +1,365 @@
+//! Offline rendering: run a schedule through the synth as fast as it will go
+//! and write the result to an audio file.
+//!
+//! The whole render is produced once as an interleaved f32 buffer; each
+//! output format is just an encoder over that buffer.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::backend::FileBackend;
+use crate::error::ClidawError;
+use crate::flac;
+use crate::scheduler::ScheduledEvent;
+use crate::synth::{AudioEngine, LiveCommand, Mix, Patch};
+
+/// Rendered files are stereo (both channels carry the same mix for now)
+pub const CHANNELS: u16 = 2;
+
+/// Sample rates `render` accepts
+pub const SAMPLE_RATES: [u32; 2] = [44_100, 48_000];
+
+/// Audio file format written by `clidaw render`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+}
+
+impl AudioFormat {
+    /// Format implied by a file's extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "wav" | "wave" => Some(AudioFormat::Wav),
+            "flac" => Some(AudioFormat::Flac),
+            _ => None,
+        }
+    }
+
+    /// Extension for files in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+        }
+    }
+}
+
+/// Sample encoding for WAV and FLAC output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BitDepth {
+    #[value(name = "16")]
+    Int16,
+    #[value(name = "24")]
+    Int24,
+    #[value(name = "32f")]
+    Float32,
+}
+
+impl BitDepth {
+    fn bits(self) -> u32 {
+        match self {
+            BitDepth::Int16 => 16,
+            BitDepth::Int24 => 24,
+            BitDepth::Float32 => 32,
+        }
+    }
+}
+
+/// Whether `format` can be written at `depth`; checked before rendering so
+/// an unsupported combination fails fast.
+pub fn check_format(format: AudioFormat, depth: BitDepth) -> Result<(), String> {
+    match (format, depth) {
+        (AudioFormat::Flac, BitDepth::Float32) => {
+            Err("FLAC stores integer samples; use --bit-depth 16 or 24".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Render a schedule to interleaved stereo samples: every event is applied
+/// at its exact sample position, then `ring_out` seconds follow the last one.
+/// `limiter` matches live playback's `--no-limiter` setting. Tracks routed
+/// to their own output channel are mixed down with the rest.
+pub fn render(
+    schedule: impl IntoIterator<Item = ScheduledEvent>,
+    patches: &[Patch],
+    mix: &Mix,
+    tempo: f64,
+    ring_out: f64,
+    sample_rate: u32,
+    limiter: bool,
+) -> Result<Vec<f32>, ClidawError> {
+    let ring_frames = (ring_out * sample_rate as f64).round() as usize;
+    render_until(schedule, patches, mix, tempo, sample_rate, limiter, |last_frame| {
+        last_frame + ring_frames
+    })
+}
+
+/// Render each track on its own, as `render` would with every other track
+/// silent: one buffer per track, in track order. Commands for no one track
+/// (master fades) apply to every stem, and all stems are as long as the full
+/// render, so they line up sample for sample.
+pub fn render_stems(
+    schedule: &[ScheduledEvent],
+    patches: &[Patch],
+    mix: &Mix,
+    tempo: f64,
+    ring_out: f64,
+    sample_rate: u32,
+    limiter: bool,
+) -> Result<Vec<Vec<f32>>, ClidawError> {
+    let frames_per_beat = 60.0 / tempo * sample_rate as f64;
+    let last_frame = schedule.iter().map(|e| frame_of(e, frames_per_beat)).max().unwrap_or(0);
+    let end = last_frame + (ring_out * sample_rate as f64).round() as usize;
+    (0..patches.len())
+        .map(|track| {
+            let solo = schedule
+                .iter()
+                .filter(|e| e.command.track().is_none_or(|t| t == track))
+                .cloned();
+            render_until(solo, patches, mix, tempo, sample_rate, limiter, |_| end)
+        })
+        .collect()
+}
+
+/// The frame an event falls on
+fn frame_of(event: &ScheduledEvent, frames_per_beat: f64) -> usize {
+    (event.beat.as_f64().max(0.0) * frames_per_beat).round() as usize
+}
+
+/// Run a schedule through an engine writing to tape, ending at the frame
+/// `end` gives for the last event's frame
+fn render_until(
+    schedule: impl IntoIterator<Item = ScheduledEvent>,
+    patches: &[Patch],
+    mix: &Mix,
+    tempo: f64,
+    sample_rate: u32,
+    limiter: bool,
+    end: impl FnOnce(usize) -> usize,
+) -> Result<Vec<f32>, ClidawError> {
+    let patches: Vec<Patch> = patches
+        .iter()
+        .map(|p| Patch {
+            output_channel: None,
+            ..p.clone()
+        })
+        .collect();
+    let (backend, mut tape) = FileBackend::new(sample_rate, CHANNELS);
+    let engine = AudioEngine::with_backend(Box::new(backend), patches, mix, None)?;
+    engine.send(LiveCommand::SetLimiter(limiter))?;
+    let frames_per_beat = 60.0 / tempo * sample_rate as f64;
+
+    // Each command is sent once the tape reaches its frame, so the engine
+    // applies it right there
+    let mut last_frame = 0;
+    for event in schedule {
+        let frame = frame_of(&event, frames_per_beat);
+        tape.render_to(frame);
+        engine.send(event.command)?;
+        last_frame = last_frame.max(frame);
+    }
+    tape.render_to(end(last_frame));
+    Ok(tape.into_samples())
+}
+
+/// Scale a sample in -1..1 to a signed integer of `bits`
+fn to_int(sample: f32, bits: u32) -> i32 {
+    let max = ((1i64 << (bits - 1)) - 1) as f64;
+    (sample.clamp(-1.0, 1.0) as f64 * max).round() as i32
+}
+
+/// Encode interleaved stereo samples in `format`
+pub fn encode(
+    samples: &[f32],
+    sample_rate: u32,
+    format: AudioFormat,
+    depth: BitDepth,
+) -> Result<Vec<u8>, String> {
+    check_format(format, depth)?;
+    Ok(match format {
+        AudioFormat::Wav => encode_wav(samples, sample_rate, depth),
+        _ => {
+            let ints: Vec<i32> = samples.iter().map(|&s| to_int(s, depth.bits())).collect();
+            flac::encode(&ints, CHANNELS, sample_rate, depth.bits())
+        }
+    })
+}
+
+/// RIFF/WAVE: integer PCM for 16/24 bits, IEEE float for 32f
+fn encode_wav(samples: &[f32], sample_rate: u32, depth: BitDepth) -> Vec<u8> {
+    let frames = samples.len() / CHANNELS as usize;
+    let mut out = wav_header(CHANNELS, sample_rate, depth, frames);
+    push_wav_samples(&mut out, samples, depth);
+    out
+}
+
+/// Header of a WAV file holding `frames` frames; everything up to the start
+/// of the sample data.
+pub fn wav_header(channels: u16, sample_rate: u32, depth: BitDepth, frames: usize) -> Vec<u8> {
+    let bytes_per_sample = depth.bits() as usize / 8;
+    let block_align = channels as usize * bytes_per_sample;
+    let data_len = frames * block_align;
+    let is_float = depth == BitDepth::Float32;
+    // Non-PCM formats carry an extension size field and a fact chunk
+    let fmt_len: u32 = if is_float { 18 } else { 16 };
+    let fact_len = if is_float { 12 } else { 0 };
+
+    let mut out = Vec::with_capacity(44 + fact_len + data_len);
+    out.extend_from_slice(b"RIFF");
+    let riff_len = 4 + (8 + fmt_len as usize) + fact_len + 8 + data_len;
+    out.extend_from_slice(&(riff_len as u32).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&fmt_len.to_le_bytes());
+    out.extend_from_slice(&(if is_float { 3u16 } else { 1u16 }).to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&((sample_rate as usize * block_align) as u32).to_le_bytes());
+    out.extend_from_slice(&(block_align as u16).to_le_bytes());
+    out.extend_from_slice(&(depth.bits() as u16).to_le_bytes());
+    if is_float {
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(b"fact");
+        out.extend_from_slice(&4u32.to_le_bytes());
+        out.extend_from_slice(&(frames as u32).to_le_bytes());
+    }
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&(data_len as u32).to_le_bytes());
+    out
+}
+
+/// Append samples in WAV sample encoding
+pub fn push_wav_samples(out: &mut Vec<u8>, samples: &[f32], depth: BitDepth) {
+    for &s in samples {
+        match depth {
+            BitDepth::Int16 => out.extend_from_slice(&(to_int(s, 16) as i16).to_le_bytes()),
+            BitDepth::Int24 => out.extend_from_slice(&to_int(s, 24).to_le_bytes()[..3]),
+            BitDepth::Float32 => out.extend_from_slice(&s.to_le_bytes()),
+        }
+    }
+}
+
+/// Write `bytes` to `path` through a temporary file in the same directory,
+/// so a failed write never leaves a partial file at `path`.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), ClidawError> {
+    let name = path.file_name().ok_or_else(|| {
+        ClidawError::io(path, io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp: PathBuf = path.with_file_name(temp_name);
+
+    let result = fs::write(&temp, bytes).and_then(|()| fs::rename(&temp, path));
+    result.map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        ClidawError::io(path, e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::Beat;
+    use crate::test_util::temp_dir;
+
+    fn note(beat: f64, on: bool) -> ScheduledEvent {
+        let command = if on {
+            LiveCommand::NoteOn {
+                track: 0,
+                key: 'a',
+                freq: 440.0,
+                velocity: 1.0,
+            }
+        } else {
+            LiveCommand::NoteOff { track: 0, key: 'a' }
+        };
+        ScheduledEvent {
+            beat: Beat::from_f64(beat),
+            command,
+        }
+    }
+
+    #[test]
+    fn test_render_places_events_on_the_sample_clock() {
+        // 120 BPM: one beat is half a second
+        let schedule = vec![note(1.0, true), note(2.0, false)];
+        let patches = [Patch::default()];
+        let samples =
+            render(schedule, &patches, &Mix::default(), 120.0, 0.5, 48_000, true).unwrap();
+        assert_eq!(samples.len(), (48_000 + 24_000) * 2);
+        // Silent until the note starts at 24000 frames
+        assert!(samples[..24_000 * 2].iter().all(|&s| s == 0.0));
+        assert!(samples[24_000 * 2..30_000 * 2].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn test_stems_hold_one_track_each_at_full_length() {
+        let mut schedule = vec![note(0.0, true), note(0.5, false)];
+        // The second track plays later, so the first stem must run on to its end
+        for mut event in [note(2.0, true), note(3.0, false)] {
+            event.command = event.command.on_track(1);
+            schedule.push(event);
+        }
+        let patches = [Patch::default(), Patch::default()];
+        let mix = Mix::default();
+        let stems = render_stems(&schedule, &patches, &mix, 120.0, 0.5, 48_000, true).unwrap();
+        let full = render(schedule, &patches, &mix, 120.0, 0.5, 48_000, true).unwrap();
+        assert_eq!(stems.len(), 2);
+        assert!(stems.iter().all(|stem| stem.len() == full.len()));
+        // Track 0 sounds first, then is silent once its release is over
+        let second_note = 48_000 * 2;
+        assert!(stems[0][..24_000].iter().any(|&s| s != 0.0));
+        assert!(stems[0][second_note..].iter().all(|&s| s == 0.0));
+        assert!(stems[1][..second_note].iter().all(|&s| s == 0.0));
+        assert!(stems[1][second_note..].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn test_wav_header() {
+        let samples = [0.0, 0.0, 1.0, -1.0];
+        let wav = encode(&samples, 44_100, AudioFormat::Wav, BitDepth::Int16).unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()) as usize, wav.len() - 8);
+        assert_eq!(u16::from_le_bytes([wav[20], wav[21]]), 1);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 44_100);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(&wav[44..], &[0, 0, 0, 0, 0xff, 0x7f, 0x01, 0x80]);
+
+        let wav = encode(&samples, 48_000, AudioFormat::Wav, BitDepth::Int24).unwrap();
+        assert_eq!(wav.len(), 44 + 4 * 3);
+        let wav = encode(&samples, 48_000, AudioFormat::Wav, BitDepth::Float32).unwrap();
+        assert_eq!(u16::from_le_bytes([wav[20], wav[21]]), 3);
+        assert_eq!(wav.len(), 58 + 4 * 4);
+    }
+
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(AudioFormat::from_path(Path::new("a.WAV")), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::from_path(Path::new("a.flac")), Some(AudioFormat::Flac));
+        assert_eq!(AudioFormat::from_path(Path::new("a.mp3")), None);
+        assert_eq!(AudioFormat::from_path(Path::new("a.ogg")), None);
+        assert!(check_format(AudioFormat::Flac, BitDepth::Float32).is_err());
+    }
+
+    #[test]
+    fn test_failed_write_leaves_nothing_behind() {
+        let dir = temp_dir("render");
+        // A directory in the way makes the final rename fail
+        fs::create_dir(dir.join("out.wav")).unwrap();
+        assert!(write_atomic(&dir.join("out.wav"), b"data").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        write_atomic(&dir.join("ok.wav"), b"data").unwrap();
+        assert_eq!(fs::read(dir.join("ok.wav")).unwrap(), b"data");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+}
@@ -1,55 +1,826 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc as std_mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use crossterm::event::{
-    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyboardEnhancementFlags,
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
     PushKeyboardEnhancementFlags,
 };
+use crossterm::style::Stylize;
 use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
 use crossterm::{execute, queue};
 
-use crate::parser::char_to_note;
-use crate::synth::{AudioEngine, LiveCommand};
+use crate::backend::OutputOptions;
+use crate::error::ClidawError;
+use crate::keymap::{Keymap, KeyboardRow};
+use crate::looper::{self, LoopAction, Looper};
+use crate::note::NoteName;
+use crate::palette::{self, Command};
+use crate::practice::{Cue, Practice, Quiz};
+use crate::rng::Rng;
+use crate::scheduler::{self, Compiled, Metronome, ScheduleOptions};
+use crate::synth::{self, AudioEngine, Latency, LiveCommand, Mix, Patch, Progress};
+use crate::take::Take;
+
+/// Without release events, a key counts as released once it has gone this
+/// long without a press or repeat event, until its repeats have measured
+/// the terminal's own cadence
+const REPEAT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Without release events and before the repeat delay is measured, a press
+/// of a key the timeout released this recently may be its first auto-repeat
+const MAX_REPEAT_DELAY: Duration = Duration::from_millis(1000);
+
+/// Without release events, a key counts as released once its next repeat is
+/// this much overdue: the expected gap times `REPEAT_SLACK`, but never
+/// less than `MIN_REPEAT_TIMEOUT`
+const REPEAT_SLACK: f64 = 1.5;
+const MIN_REPEAT_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// With release events, a key idle this long (repeats stopped, no release
+/// arrived) means the terminal isn't really delivering releases
+const RELEASE_GRACE: Duration = Duration::from_millis(1000);
+
+/// Frames per audio buffer unless `--buffer-size` says otherwise: small,
+/// for a short delay between a key and its note (kept within what the
+/// device allows, and its own size if the device won't open with it)
+pub const LIVE_BUFFER_FRAMES: u32 = 256;
+
+/// How long to wait for the stream's first callback to measure its latency
+const LATENCY_WAIT: Duration = Duration::from_secs(1);
+
+/// Longest wait for input between looks at the clock (input ends the wait
+/// at once), and the shorter one while something is animating
+const IDLE_POLL: Duration = Duration::from_millis(10);
+const BUSY_POLL: Duration = Duration::from_millis(5);
+
+/// How fast the pitch bend glides toward its target, in full bends per second
+const BEND_RATE: f64 = 5.0;
+
+/// Shortest time between redraws of the status block, so fast playing
+/// doesn't flood the terminal
+const REDRAW_INTERVAL: Duration = Duration::from_millis(30);
+
+/// Row of the looper's line, counted down from the saved cursor position,
+/// below the `StatusBlock` lines
+const LOOP_ROW: usize = 3;
+
+/// Row of the command line (`:`), and of what its last command said
+const PALETTE_ROW: usize = 4;
+
+/// Row of `clidaw practice`'s line: the quiz and the `--strict` nudge
+const PRACTICE_ROW: usize = 5;
+
+/// Note velocities: a plain key, one held with Shift (an accent), and a
+/// plain key in soft mode
+const PLAIN_VELOCITY: f64 = 0.8;
+const ACCENT_VELOCITY: f64 = 1.0;
+const SOFT_VELOCITY: f64 = 0.5;
+
+/// Key that turns soft mode on and off (unless the keymap plays a note on it)
+const SOFT_KEY: char = '\\';
+
+/// Key that opens the command line (likewise)
+const PALETTE_KEY: char = ':';
+
+/// Engine key the practice quiz plays its notes on; no keyboard key is a
+/// private-use character, so it never cuts off a note being played
+const QUIZ_KEY: char = '\u{F000}';
+
+/// The note key a typed character stands for, and whether it is accented:
+/// with Shift, terminals report a letter key as its capital
+fn note_key(keymap: &Keymap, c: char) -> (char, bool) {
+    let lower = c.to_ascii_lowercase();
+    if lower != c && keymap.lookup(c).is_none() && keymap.lookup(lower).is_some() {
+        (lower, true)
+    } else {
+        (c, false)
+    }
+}
+
+/// `KeyTracker` entries for the arrow keys that bend the pitch
+const BEND_UP_KEY: char = '↑';
+const BEND_DOWN_KEY: char = '↓';
+
+/// Bend target (-1.0..=1.0) while a bend key is held
+fn bend_direction(key: char) -> Option<f64> {
+    match key {
+        BEND_UP_KEY => Some(1.0),
+        BEND_DOWN_KEY => Some(-1.0),
+        _ => None,
+    }
+}
+
+/// Pitch bend that glides toward its target instead of jumping
+#[derive(Debug, Default)]
+struct Bend {
+    value: f64,
+    target: f64,
+}
+
+impl Bend {
+    /// Move toward the target by `BEND_RATE * dt`. Returns true if the
+    /// value changed (a new `PitchBend` should be sent).
+    fn step(&mut self, dt: f64) -> bool {
+        if self.value == self.target {
+            return false;
+        }
+        let max_step = BEND_RATE * dt;
+        let diff = self.target - self.value;
+        self.value = if diff.abs() <= max_step {
+            self.target
+        } else {
+            self.value + max_step.copysign(diff)
+        };
+        true
+    }
+
+    /// A bend key was released: return to center if it set the target
+    fn release(&mut self, key: char) {
+        if bend_direction(key) == Some(self.target) {
+            self.target = 0.0;
+        }
+    }
+}
+
+/// Held keys and how their release is detected. Starts trusting release
+/// events if the terminal claims to support them; switches to trusting them
+/// as soon as one arrives, and back to timeouts if one fails to arrive.
+///
+/// Without release events, a held key is known only by its auto-repeat, so
+/// a key is released once its next repeat is overdue. The wait is timed
+/// from the terminal's own cadence, measured as keys are held: the delay
+/// before the first repeat, then the interval between repeats. Terminals
+/// without release events mostly report repeats as presses, so there a
+/// press that comes within the cadence is taken for a repeat. A fixed
+/// `--release-timeout` replaces both.
+struct KeyTracker {
+    keys: HashMap<char, Held>,
+    /// Keys the timeout released, kept to notice when it was too early
+    lapsed: HashMap<char, Held>,
+    release_events: bool,
+    /// Measured delay before the first repeat, and between repeats
+    delay: Option<Duration>,
+    interval: Option<Duration>,
+    /// `--release-timeout`: a fixed wait instead of the measured cadence
+    fixed: Option<Duration>,
+}
+
+/// A held key: its last press or repeat and how many repeats it has had
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    last: Instant,
+    repeats: u32,
+}
+
+/// What a press event means for the note on its key
+#[derive(Debug, PartialEq)]
+enum Press {
+    /// A key that wasn't held: start its note
+    New,
+    /// A held key struck again: end its note and start it again
+    Retrigger,
+    /// Auto-repeat reported as a press: the note keeps sounding
+    Repeat,
+    /// Auto-repeat reported as a press, of a key the timeout released
+    /// before its first repeat: its note starts again
+    Resume,
+}
+
+impl KeyTracker {
+    fn new(release_events: bool, fixed: Option<Duration>) -> Self {
+        Self {
+            keys: HashMap::new(),
+            lapsed: HashMap::new(),
+            release_events,
+            delay: None,
+            interval: None,
+            fixed,
+        }
+    }
+
+    /// Key pressed. With release events, a press of a held key is the key
+    /// struck again: an implied release, then a new note. Without them, it
+    /// is a repeat if it comes within the key's timeout, and so is a press
+    /// soon after the timeout released a key, until the delay before the
+    /// first repeat has been measured.
+    fn press(&mut self, key: char, now: Instant) -> Press {
+        if !self.release_events {
+            if let Some(held) = self.keys.get(&key)
+                && now.duration_since(held.last) <= self.timeout(held)
+            {
+                self.held_again(key, now);
+                return Press::Repeat;
+            }
+            if let Some(held) = self.lapsed.get(&key)
+                && held.repeats == 0
+                && self.delay.is_none()
+                && self.fixed.is_none()
+                && now.duration_since(held.last) <= MAX_REPEAT_DELAY
+            {
+                self.held_again(key, now);
+                return Press::Resume;
+            }
+        }
+        self.lapsed.remove(&key);
+        let fresh = Held {
+            last: now,
+            repeats: 0,
+        };
+        match self.keys.insert(key, fresh) {
+            None => Press::New,
+            Some(_) => Press::Retrigger,
+        }
+    }
+
+    /// Repeat event for a held key. Returns true if the timeout had already
+    /// released the key (its note should start again).
+    fn repeat(&mut self, key: char, now: Instant) -> bool {
+        self.held_again(key, now)
+    }
+
+    /// Time the gap since the key's last event as a repeat delay or interval.
+    /// Returns true if the key had lapsed, in which case the gap it waited
+    /// shows the timeout was too short.
+    fn held_again(&mut self, key: char, now: Instant) -> bool {
+        let (held, lapsed) = match self.keys.get(&key) {
+            Some(held) => (*held, false),
+            None => match self.lapsed.remove(&key) {
+                Some(held) => (held, true),
+                None => return false,
+            },
+        };
+        let gap = now.duration_since(held.last);
+        if held.repeats == 0 {
+            self.delay = Some(gap);
+        } else {
+            self.interval = Some(gap);
+        }
+        self.keys.insert(key, Held {
+            last: now,
+            repeats: held.repeats + 1,
+        });
+        lapsed
+    }
+
+    /// Release event from the terminal. Returns true if the key was still
+    /// held (false if the timeout already released it).
+    fn release(&mut self, key: char) -> bool {
+        self.release_events = true;
+        self.lapsed.remove(&key);
+        self.keys.remove(&key).is_some()
+    }
+
+    /// How long `held` may go without an event before it counts as released
+    fn timeout(&self, held: &Held) -> Duration {
+        if self.release_events {
+            return RELEASE_GRACE;
+        }
+        if let Some(fixed) = self.fixed {
+            return fixed;
+        }
+        let gap = if held.repeats == 0 {
+            self.delay
+        } else {
+            self.interval
+        };
+        gap.map_or(REPEAT_TIMEOUT, |gap| gap.mul_f64(REPEAT_SLACK).max(MIN_REPEAT_TIMEOUT))
+    }
+
+    /// Remove and return keys whose release has timed out
+    fn expired(&mut self, now: Instant) -> Vec<char> {
+        let expired: Vec<char> = self
+            .keys
+            .iter()
+            .filter(|(_, held)| now.duration_since(held.last) > self.timeout(held))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &expired {
+            if let Some(held) = self.keys.remove(key) {
+                self.lapsed.insert(*key, held);
+            }
+        }
+        if !expired.is_empty() {
+            self.release_events = false;
+        }
+        expired
+    }
+}
+
+/// The status block under the banner: an on-screen keyboard with the held
+/// keys highlighted, and the octave, last note, voice count and instrument.
+///
+/// It also owns the set of keys that are sounding, which is the authority
+/// on what has been played: each key gets one NoteOn until its NoteOff,
+/// whether the release came from the terminal or from a `KeyTracker`
+/// timeout. A press of a sounding key ends its note and starts it again,
+/// unless the tracker takes it for auto-repeat. The NoteOff goes
+/// to the track the NoteOn did, even if the instrument changed in between.
+struct StatusBlock {
+    octave: u8,
+    note: Option<String>,
+    voices: usize,
+    /// Sounding keys and the keyboard track each one plays on
+    sounding: BTreeMap<char, usize>,
+    /// Names of the `--instrument`s (empty: the default sound, not shown)
+    /// and the one the keyboard plays, which is also its track
+    instruments: Vec<String>,
+    instrument: usize,
+    /// Soft mode: plain keys play at `SOFT_VELOCITY`
+    soft: bool,
+    /// Where the backing song is (None without one)
+    backing: Option<String>,
+    /// File the palette's `:record` is taking notes to
+    recording: Option<String>,
+    /// Keyboard layout from `Keymap::keyboard_rows`
+    top: KeyboardRow,
+    bottom: KeyboardRow,
+    /// Lines as last drawn; redraws only rewrite the ones that changed
+    drawn: [String; 3],
+    last_draw: Option<Instant>,
+}
+
+impl StatusBlock {
+    fn new(keymap: &Keymap, octave: u8, instruments: Vec<String>) -> Self {
+        let (top, bottom) = keymap.keyboard_rows();
+        Self {
+            octave,
+            note: None,
+            voices: 0,
+            sounding: BTreeMap::new(),
+            instruments,
+            instrument: 0,
+            soft: false,
+            backing: None,
+            recording: None,
+            top,
+            bottom,
+            drawn: Default::default(),
+            last_draw: None,
+        }
+    }
+
+    /// A note key was pressed. Returns the track to send its NoteOn to, or
+    /// None if it is already sounding (a repeat).
+    fn note_on(&mut self, key: char, note: String) -> Option<usize> {
+        if self.sounding.contains_key(&key) {
+            return None;
+        }
+        self.sounding.insert(key, self.instrument);
+        self.note = Some(note);
+        Some(self.instrument)
+    }
+
+    /// Every sounding key and its track, now released
+    fn release_all(&mut self) -> Vec<(char, usize)> {
+        self.note = None;
+        std::mem::take(&mut self.sounding).into_iter().collect()
+    }
+
+    /// Forget every sounding key (after a panic has silenced them)
+    fn clear(&mut self) {
+        self.sounding.clear();
+        self.note = None;
+    }
+
+    /// A note key was released. Returns the track to send its NoteOff to,
+    /// or None if it wasn't sounding (already released another way).
+    fn note_off(&mut self, key: char) -> Option<usize> {
+        let track = self.sounding.remove(&key)?;
+        self.note = None;
+        Some(track)
+    }
+
+    /// Velocity of a note played now, `accent`ed (Shift held) or not
+    fn velocity(&self, accent: bool) -> f64 {
+        if accent {
+            ACCENT_VELOCITY
+        } else if self.soft {
+            SOFT_VELOCITY
+        } else {
+            PLAIN_VELOCITY
+        }
+    }
+
+    /// Play the next (`step` 1) or previous (-1) instrument, wrapping around
+    fn cycle_instrument(&mut self, step: isize) {
+        let count = self.instruments.len().max(1) as isize;
+        self.instrument = (self.instrument as isize + step).rem_euclid(count) as usize;
+    }
+
+    fn lines(&self) -> [String; 3] {
+        let note = self.note.as_deref().unwrap_or("---");
+        let mut info = format!(
+            "  Octave: {}  |  Note: {}  |  Voices: {}",
+            self.octave, note, self.voices
+        );
+        if let Some(name) = self.instruments.get(self.instrument) {
+            info.push_str(&format!("  |  Instrument: {}", name));
+        }
+        if self.soft {
+            info.push_str("  |  Soft");
+        }
+        if let Some(backing) = &self.backing {
+            info.push_str(&format!("  |  {}", backing));
+        }
+        if let Some(path) = &self.recording {
+            info.push_str(&format!("  |  Recording: {}", path));
+        }
+        [
+            keyboard_line(&self.top, &self.sounding),
+            keyboard_line(&self.bottom, &self.sounding),
+            info,
+        ]
+    }
+
+    /// Whether something changed since the last draw
+    fn pending(&self) -> bool {
+        self.lines() != self.drawn
+    }
+
+    /// Redraw the lines that changed, unless the last redraw was less than
+    /// `REDRAW_INTERVAL` ago (the change is drawn on a later call)
+    fn draw(&mut self, out: &mut impl Write, now: Instant) {
+        if let Some(last) = self.last_draw
+            && now.duration_since(last) < REDRAW_INTERVAL
+        {
+            return;
+        }
+        let lines = self.lines();
+        if lines == self.drawn {
+            return;
+        }
+        for (row, line) in lines.iter().enumerate() {
+            if *line != self.drawn[row] {
+                draw_row(out, row, line);
+            }
+        }
+        let _ = out.flush();
+        self.drawn = lines;
+        self.last_draw = Some(now);
+    }
+}
+
+/// One keyboard row, each key in a three-character cell, inverse video
+/// while it sounds
+fn keyboard_line(row: &[(usize, char)], sounding: &BTreeMap<char, usize>) -> String {
+    let mut line = String::from("  ");
+    let mut width = 0;
+    for &(column, key) in row {
+        let start = column * 2;
+        // Keys mapped to the same note would overlap; show the first
+        if start < width {
+            continue;
+        }
+        line.push_str(&" ".repeat(start - width));
+        let cell = format!(" {} ", key);
+        if sounding.contains_key(&key) {
+            line.push_str(&cell.reverse().to_string());
+        } else {
+            line.push_str(&cell);
+        }
+        width = start + 3;
+    }
+    line
+}
+
+/// Overwrite one row of the status block
+fn draw_row(out: &mut impl Write, row: usize, text: &str) {
+    let down = if row > 0 {
+        format!("\x1b[{}B", row)
+    } else {
+        String::new()
+    };
+    let _ = write!(out, "\x1b[u{}\x1b[2K{}\r", down, text);
+}
+
+/// An instrument loaded with `live --instrument`
+pub struct LiveInstrument {
+    /// Shown on the status line
+    pub name: String,
+    pub patch: Patch,
+}
+
+/// Settings for live mode
+pub struct LiveOptions {
+    pub keymap: Keymap,
+    /// Instruments the keyboard switches between (empty: the default sound)
+    pub instruments: Vec<LiveInstrument>,
+    /// Output device, sample rate and buffer size
+    pub output: OutputOptions,
+    /// Metronome tempo (BPM)
+    pub tempo: f64,
+    /// Metronome click loudness 0.0..=1.0
+    pub click_volume: f64,
+    /// Gain on the whole mix
+    pub master_volume: f64,
+    /// Turn off the master limiter
+    pub no_limiter: bool,
+    /// Release notes held this long without a NoteOff (None = never)
+    pub max_note_secs: Option<f64>,
+    /// Without key-release events, a key idle this long (seconds) counts as
+    /// released (None: timed from the measured key-repeat cadence)
+    pub release_timeout: Option<f64>,
+    /// Write the session's audio to this WAV file
+    pub record: Option<PathBuf>,
+    /// Open the audio output, print its latency and quit
+    pub latency_report: bool,
+    /// Song to play along with (`--backing`)
+    pub backing: Option<Backing>,
+    /// Scale practice (`clidaw practice`)
+    pub practice: Option<Practice>,
+}
+
+/// A song played under the keyboard, on engine tracks after the keyboard's
+/// and the looper's
+pub struct Backing {
+    pub compiled: Compiled,
+    /// Tempo it plays at (BPM)
+    pub tempo: f64,
+    /// Its tracks' patches, and the buses and reverb they send to
+    pub patches: Vec<Patch>,
+    pub mix: Mix,
+    /// Start it over each time it ends (`--loop`)
+    pub looped: bool,
+}
+
+impl Backing {
+    /// The song's schedule options: clicks on its beats all the way
+    /// through, so the metronome follows its bars (see `play_backing`)
+    fn schedule_options(&self, click_volume: f64) -> ScheduleOptions {
+        ScheduleOptions {
+            metronome: Some(Metronome {
+                count_in_bars: 0,
+                throughout: true,
+                volume: click_volume,
+            }),
+            looped: self.looped,
+            ..ScheduleOptions::default()
+        }
+    }
+}
+
+/// Where the backing song is, for the status block
+struct BackingClock {
+    progress: Progress,
+    tempo: f64,
+    start: Instant,
+}
+
+impl BackingClock {
+    /// `Backing: bar 3:2 at 120 BPM`
+    fn label(&self, now: Instant) -> String {
+        let elapsed = now.saturating_duration_since(self.start).as_secs_f64();
+        let position = self.progress.position(elapsed, self.tempo);
+        format!("Backing: {} at {} BPM", position, self.tempo)
+    }
+}
+
+/// Play the backing song from `start` on the engine tracks from `base`,
+/// until it ends or `stop` is set. Its clicks stand in for the metronome's,
+/// and are only sent while it is `enabled`. Stopping releases only its own
+/// tracks: the keyboard and the loop aren't its to silence.
+fn play_backing(
+    backing: &Backing,
+    options: &ScheduleOptions,
+    base: usize,
+    tx: std_mpsc::Sender<LiveCommand>,
+    enabled: &AtomicBool,
+    start: Instant,
+    stop: &AtomicBool,
+) -> Result<(), ClidawError> {
+    let stream = scheduler::stream_compiled(&backing.compiled, backing.tempo, options)?;
+    let tracks = base..base + backing.patches.len();
+    synth::play_alongside(stream.events, backing.tempo, start, stop, |command| {
+        if matches!(command, LiveCommand::Click { .. }) && !enabled.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        // The engine only goes away once the session is over
+        if matches!(command, LiveCommand::AllNotesOff { track: None } | LiveCommand::HardStop) {
+            for track in tracks.clone() {
+                let _ = tx.send(LiveCommand::AllNotesOff { track: Some(track) });
+            }
+            return Ok(());
+        }
+        let _ = tx.send(command.on_track(base));
+        Ok(())
+    })
+}
+
+/// The metronome's tempo (BPM), which `:tempo` changes while its clock
+/// thread reads it
+struct SharedTempo(AtomicU64);
+
+impl SharedTempo {
+    fn new(bpm: f64) -> Self {
+        Self(AtomicU64::new(bpm.to_bits()))
+    }
+
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, bpm: f64) {
+        self.0.store(bpm.to_bits(), Ordering::Relaxed);
+    }
+
+    /// One beat at the tempo
+    fn beat(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.get().max(1.0))
+    }
+}
+
+/// Metronome for live mode: a clock thread that sends a click every beat
+/// (accented every 4) at `tempo` while `enabled` is set, until `stop` is
+/// set.
+fn spawn_metronome(
+    tx: std_mpsc::Sender<LiveCommand>,
+    enabled: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    tempo: Arc<SharedTempo>,
+    volume: f64,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut next = Instant::now();
+        let mut count = 0u64;
+        let mut was_enabled = false;
+        while !stop.load(Ordering::Relaxed) {
+            let on = enabled.load(Ordering::Relaxed);
+            if on && !was_enabled {
+                // Start on a downbeat as soon as the click is switched on
+                next = Instant::now();
+                count = 0;
+            }
+            was_enabled = on;
+            if on && Instant::now() >= next {
+                let click = LiveCommand::Click {
+                    accent: count.is_multiple_of(4),
+                    volume,
+                };
+                if tx.send(click).is_err() {
+                    break;
+                }
+                count += 1;
+                next += tempo.beat();
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    })
+}
 
 /// Run the interactive live keyboard mode
-pub fn run() -> Result<(), String> {
-    let engine = AudioEngine::new()?;
+pub fn run(options: &LiveOptions) -> Result<(), ClidawError> {
+    // The keyboard plays on one track per instrument; the looper's layers
+    // play on copies of them, and a backing song on the tracks after those
+    let keyboard: Vec<Patch> = if options.instruments.is_empty() {
+        vec![Patch::default()]
+    } else {
+        options.instruments.iter().map(|i| i.patch.clone()).collect()
+    };
+    let mut patches: Vec<Patch> = (0..looper::tracks(keyboard.len()))
+        .map(|track| keyboard[track % keyboard.len()].clone())
+        .collect();
+    let base = patches.len();
+    let mut mix = Mix::default();
+    let mut backing = None;
+    if let Some(song) = &options.backing {
+        let schedule = song.schedule_options(options.click_volume);
+        let stream = scheduler::stream_compiled(&song.compiled, song.tempo, &schedule)?;
+        let progress = Progress::of(&stream);
+        drop(stream);
+        patches.extend(song.patches.iter().cloned());
+        mix = song.mix.clone();
+        backing = Some((song, schedule, progress));
+    }
+    let mix = Mix {
+        master_volume: options.master_volume,
+        ..mix
+    };
+    let output = OutputOptions {
+        default_buffer_size: Some(LIVE_BUFFER_FRAMES),
+        ..options.output.clone()
+    };
+    let engine = match &options.record {
+        Some(path) => AudioEngine::recording(patches, &mix, &output, path)?,
+        None => AudioEngine::new(patches, &mix, &output)?,
+    };
+    let latency = engine.latency(LATENCY_WAIT);
+    if options.latency_report {
+        print!("{}", latency_report(latency.as_ref()));
+        return Ok(());
+    }
+    if options.no_limiter {
+        engine.send(LiveCommand::SetLimiter(false))?;
+    }
+    engine.send(LiveCommand::SetMaxNoteSecs(options.max_note_secs))?;
+    let keymap = &options.keymap;
+    let click_enabled = Arc::new(AtomicBool::new(false));
+    let stop_metronome = Arc::new(AtomicBool::new(false));
+    let tempo = Arc::new(SharedTempo::new(options.tempo));
+    // A backing song clicks along with its own bars instead
+    let metronome = backing.is_none().then(|| {
+        spawn_metronome(
+            engine.sender(),
+            Arc::clone(&click_enabled),
+            Arc::clone(&stop_metronome),
+            Arc::clone(&tempo),
+            options.click_volume,
+        )
+    });
+    let looper = Arc::new(Mutex::new(Looper::new(keyboard.len())));
+    let stop_looper = Arc::new(AtomicBool::new(false));
+    let loop_clock = looper::spawn_clock(
+        engine.sender(),
+        Arc::clone(&looper),
+        Arc::clone(&stop_looper),
+    );
 
     let mut stdout = io::stdout();
 
     // Enter raw mode
-    terminal::enable_raw_mode().map_err(|e| format!("failed to enable raw mode: {}", e))?;
-    execute!(stdout, EnterAlternateScreen).map_err(|e| format!("alternate screen: {}", e))?;
-
-    // Enable keyboard enhancement for key release and repeat detection.
-    // We always try to enable it, and use a hybrid approach:
-    // - If Release events work, great!
-    // - If only Repeat events work, we use those to detect held keys
-    // - If neither work reliably, we fall back to timeout-based release
+    terminal::enable_raw_mode()
+        .map_err(|e| ClidawError::Terminal(format!("failed to enable raw mode: {}", e)))?;
+    execute!(stdout, EnterAlternateScreen)
+        .map_err(|e| ClidawError::Terminal(format!("alternate screen: {}", e)))?;
+
+    // Ask the terminal to report key release and repeat events. Whether
+    // releases really arrive is probed up front and then confirmed at runtime
+    // by `KeyTracker`, which falls back to timeout-based release if not.
     let kb_enhanced = queue!(
         stdout,
         PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
     )
     .is_ok()
         && stdout.flush().is_ok();
+    let has_key_release = kb_enhanced && terminal::supports_keyboard_enhancement().unwrap_or(false);
 
-    // On macOS, even if enhancement succeeds, Release events may not work
-    // so we always use the fallback logic there
-    let has_key_release = kb_enhanced && !cfg!(target_os = "macos");
+    let names = options.instruments.iter().map(|i| i.name.clone()).collect();
+    let octave = options.practice.map_or(4, |practice| practice.octave);
+    let mut status = StatusBlock::new(keymap, octave, names);
 
-    let mut octave: u8 = 4;
+    let practice = options.practice.as_ref();
+    print_banner(&mut stdout, keymap, practice, &status.instruments, latency.as_ref());
+    status.draw(&mut stdout, Instant::now());
+    update_loop_status(&mut stdout, &looper.lock().unwrap().status());
 
-    print_banner(&mut stdout, octave);
+    let start = Instant::now();
+    let session = Session {
+        engine: &engine,
+        looper: &looper,
+        click_enabled: &click_enabled,
+        tempo: &tempo,
+        take: Mutex::new(None),
+        backing: backing.as_ref().map(|(song, _, progress)| BackingClock {
+            progress: progress.clone(),
+            tempo: song.tempo,
+            start,
+        }),
+        practice: options.practice,
+    };
+    let release_timeout = options.release_timeout.map(Duration::from_secs_f64);
+    let stop_backing = AtomicBool::new(false);
+    let result = std::thread::scope(|scope| {
+        if let Some((song, schedule, _)) = &backing {
+            let tx = engine.sender();
+            let (enabled, stop) = (&*click_enabled, &stop_backing);
+            scope.spawn(move || play_backing(song, schedule, base, tx, enabled, start, stop));
+        }
+        let result = event_loop(
+            &session,
+            keymap,
+            &mut stdout,
+            &mut status,
+            has_key_release,
+            release_timeout,
+        );
+        stop_backing.store(true, Ordering::Relaxed);
+        result
+    });
 
-    let result = event_loop(&engine, &mut stdout, &mut octave, has_key_release);
+    stop_metronome.store(true, Ordering::Relaxed);
+    if let Some(metronome) = metronome {
+        let _ = metronome.join();
+    }
+    stop_looper.store(true, Ordering::Relaxed);
+    let _ = loop_clock.join();
 
-    // Restore terminal
-    let _ = engine.send(LiveCommand::AllNotesOff);
+    // A recording gets the releases' tails; otherwise there's no one left
+    // to hear them
+    if engine.is_recording() {
+        let _ = engine.send(LiveCommand::AllNotesOff { track: None });
+        wait_for_release_tails(&engine);
+    } else {
+        let _ = engine.send(LiveCommand::HardStop);
+    }
     std::thread::sleep(Duration::from_millis(20));
     let _ = engine.send(LiveCommand::Shutdown);
+    std::thread::sleep(Duration::from_millis(20));
+
+    // Restore terminal
 
     if kb_enhanced {
         let _ = execute!(
@@ -62,18 +833,192 @@ pub fn run() -> Result<(), String> {
     }
     let _ = terminal::disable_raw_mode();
 
-    result
+    // A take still going when the session ends is kept
+    let take = session.take.lock().unwrap().take();
+    let take = take.map(|take| session.finish_take(&take)).transpose();
+    let recording = engine.finish_recording();
+    result?;
+    if let Some(message) = take? {
+        println!("{}", message);
+    }
+    if let Some(summary) = recording? {
+        println!("Recorded {:.1}s to {}", summary.seconds(), summary.path.display());
+        if summary.dropped_frames > 0 {
+            eprintln!(
+                "warning: {} frame(s) were dropped because the disk could not keep up",
+                summary.dropped_frames
+            );
+        }
+    }
+    Ok(())
+}
+
+/// After the final AllNotesOff, let released notes ring out so the recording
+/// ends in silence rather than a cut (bounded, like Ctrl-C during playback)
+fn wait_for_release_tails(engine: &AudioEngine) {
+    let deadline = Instant::now() + Duration::from_secs_f64(crate::interrupt::MAX_RELEASE_WAIT_SECS);
+    // The callback may not have processed the AllNotesOff yet
+    std::thread::sleep(Duration::from_millis(20));
+    while engine.active_voices() > 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// What the event loop plays into
+struct Session<'a> {
+    engine: &'a AudioEngine,
+    looper: &'a Mutex<Looper>,
+    click_enabled: &'a AtomicBool,
+    /// The metronome's tempo; loop lengths round to its 4-beat bars while
+    /// it is on
+    tempo: &'a SharedTempo,
+    /// The palette's `:record` take, while one is going
+    take: Mutex<Option<Take>>,
+    /// The backing song's position, when there is one
+    backing: Option<BackingClock>,
+    /// Scale practice, whose `--strict` mutes keys outside the scale
+    practice: Option<Practice>,
+}
+
+impl Session<'_> {
+    /// Send a note played on the keyboard, recording it if the looper or a
+    /// take is
+    fn play(&self, command: LiveCommand) -> Result<(), ClidawError> {
+        let now = Instant::now();
+        self.looper.lock().unwrap().record(now, &command);
+        if let Some(take) = self.take.lock().unwrap().as_mut() {
+            take.record(&command, now);
+        }
+        self.engine.send(command)
+    }
+
+    /// The tempo notes are played to: the backing song's, or the metronome's
+    fn tempo(&self) -> f64 {
+        self.backing.as_ref().map_or_else(|| self.tempo.get(), |b| b.tempo)
+    }
+
+    /// Write a finished take, saying where it went
+    fn finish_take(&self, take: &Take) -> Result<String, ClidawError> {
+        let path = take.path().display();
+        Ok(match take.write(Instant::now())? {
+            0 => format!("Nothing was played; {} not written", path),
+            1 => format!("Wrote 1 note to {}", path),
+            notes => format!("Wrote {} notes to {}", notes, path),
+        })
+    }
+
+    /// Carry out a palette command. Returns what to show on the command
+    /// line, or None to quit.
+    fn run_command(
+        &self,
+        status: &mut StatusBlock,
+        command: Command,
+    ) -> Result<Option<String>, String> {
+        let reply = match command {
+            Command::Tempo(bpm) => {
+                if self.backing.is_some() {
+                    return Err("the backing song sets the tempo".to_string());
+                }
+                self.tempo.set(bpm);
+                format!("Tempo: {} BPM", bpm)
+            }
+            Command::Instrument(name) => {
+                let idx = find_instrument(&status.instruments, &name)?;
+                status.instrument = idx;
+                format!("Instrument: {}", status.instruments[idx])
+            }
+            Command::Octave(octave) => {
+                status.octave = octave;
+                format!("Octave: {}", octave)
+            }
+            Command::Record(path) => {
+                let mut take = self.take.lock().unwrap();
+                match (path, take.as_ref()) {
+                    (Some(_), Some(going)) => {
+                        let path = going.path().display();
+                        return Err(format!("already recording to {} (:record ends it)", path));
+                    }
+                    (Some(path), None) => {
+                        let message = format!("Recording to {} (:record ends it)", path.display());
+                        status.recording = Some(path.display().to_string());
+                        *take = Some(Take::new(path, self.tempo(), Instant::now()));
+                        message
+                    }
+                    (None, _) => {
+                        let finished = take.take().ok_or("not recording (:record FILE starts)")?;
+                        status.recording = None;
+                        self.finish_take(&finished).map_err(|e| e.to_string())?
+                    }
+                }
+            }
+            Command::Help => palette::help(),
+            Command::Quit => return Ok(None),
+        };
+        Ok(Some(reply))
+    }
+
+    /// Start the note on keymap key `key` unless it is already sounding
+    fn start_note(
+        &self,
+        keymap: &Keymap,
+        status: &mut StatusBlock,
+        key: char,
+        accent: bool,
+    ) -> Result<(), ClidawError> {
+        let Some((note_name, oct_offset)) = keymap.lookup(key) else {
+            return Ok(());
+        };
+        if self.practice.is_some_and(|practice| !practice.allows(note_name)) {
+            return Ok(());
+        }
+        let effective_octave = status.octave.saturating_add(oct_offset).min(8);
+        let freq = note_name.to_freq(effective_octave);
+        let note = format!("{:?}{}", note_name, effective_octave);
+        if let Some(track) = status.note_on(key, note) {
+            self.play(LiveCommand::NoteOn {
+                track,
+                key,
+                freq,
+                velocity: status.velocity(accent),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The `--instrument` that `name` picks: its number (from 1), its name or
+/// the file it was loaded from
+fn find_instrument(names: &[String], name: &str) -> Result<usize, String> {
+    if names.is_empty() {
+        return Err("there are no --instruments to switch between".to_string());
+    }
+    let number = name.parse::<usize>().ok().filter(|n| (1..=names.len()).contains(n));
+    let stem = Path::new(name).file_stem().map(|s| s.to_string_lossy());
+    let found = number.map(|n| n - 1).or_else(|| {
+        names.iter().position(|n| n == name || stem.as_deref() == Some(n.as_str()))
+    });
+    found.ok_or_else(|| {
+        format!(
+            "no instrument '{}' (loaded: {}); start live with --instrument {} to add it",
+            name,
+            names.join(", "),
+            name
+        )
+    })
 }
 
 fn event_loop(
-    engine: &AudioEngine,
+    session: &Session,
+    keymap: &Keymap,
     stdout: &mut io::Stdout,
-    octave: &mut u8,
+    status: &mut StatusBlock,
     has_key_release: bool,
-) -> Result<(), String> {
-    // For the fallback path: track when each key was last pressed/repeated
-    // so we can detect when a key is released (no more repeat events)
-    let active_keys: Arc<Mutex<HashMap<char, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+    release_timeout: Option<Duration>,
+) -> Result<(), ClidawError> {
+    let engine = session.engine;
+    let click_enabled = session.click_enabled;
+    let tracker = KeyTracker::new(has_key_release, release_timeout);
+    let tracker = Arc::new(Mutex::new(tracker));
 
     // Channel to receive keys that should be released
     let (release_tx, release_rx) = std_mpsc::channel::<char>();
@@ -81,104 +1026,321 @@ fn event_loop(
     // Channel to signal the monitor thread to shut down
     let (shutdown_tx, shutdown_rx) = std_mpsc::channel::<()>();
 
-    // Spawn a background thread that checks for keys that haven't been updated recently.
+    // Spawn a background thread that releases keys that haven't been updated recently.
     // The thread will exit when it receives a shutdown signal via shutdown_rx channel.
-    let _monitor_thread = if !has_key_release {
-        let keys_clone = Arc::clone(&active_keys);
-        let tx_clone = release_tx.clone();
-        Some(std::thread::spawn(move || {
-            loop {
-                // Check for shutdown signal
-                if shutdown_rx.try_recv().is_ok() {
-                    break;
-                }
-
-                std::thread::sleep(Duration::from_millis(50));
-                let now = Instant::now();
-                let mut keys = keys_clone.lock().unwrap();
-                let mut to_release = Vec::new();
-
-                // Find keys that haven't been updated in the last 100ms
-                // (meaning no repeat events, so the key was released)
-                for (key, last_time) in keys.iter() {
-                    if now.duration_since(*last_time) > Duration::from_millis(100) {
-                        to_release.push(*key);
-                    }
-                }
-
-                // Remove and send release events for stale keys
-                for key in to_release {
-                    keys.remove(&key);
-                    let _ = tx_clone.send(key);
-                }
+    let tracker_clone = Arc::clone(&tracker);
+    let mut bend = Bend::default();
+    let mut last_step = Instant::now();
+    // What's typed on the command line, while it is open
+    let mut palette: Option<String> = None;
+    // The practice quiz, the track its note plays on, and its line as drawn
+    let mut quiz = session
+        .practice
+        .filter(|practice| practice.quiz)
+        .map(|practice| Quiz::new(&practice, Rng::from_time(), Instant::now()));
+    let mut quiz_track = status.instrument;
+    let mut quiz_line = String::new();
+    let _monitor_thread = std::thread::spawn(move || {
+        while shutdown_rx.try_recv().is_err() {
+            std::thread::sleep(Duration::from_millis(50));
+            let expired = tracker_clone.lock().unwrap().expired(Instant::now());
+            for key in expired {
+                let _ = release_tx.send(key);
             }
-        }))
-    } else {
-        None
-    };
+        }
+    });
 
     loop {
         // Drain any release messages from the monitor thread
-        if !has_key_release {
-            while let Ok(key) = release_rx.try_recv() {
-                engine.send(LiveCommand::NoteOff { track: 0, key })?;
-                update_status(stdout, *octave, None);
+        while let Ok(key) = release_rx.try_recv() {
+            if bend_direction(key).is_some() {
+                bend.release(key);
+                continue;
+            }
+            if let Some(track) = status.note_off(key) {
+                session.play(LiveCommand::NoteOff { track, key })?;
+            }
+        }
+
+        let now = Instant::now();
+        status.voices = engine.active_voices();
+        status.backing = session.backing.as_ref().map(|b| b.label(now));
+        status.draw(stdout, now);
+        if bend.step(now.duration_since(last_step).as_secs_f64()) {
+            engine.send(LiveCommand::PitchBend(bend.value))?;
+        }
+        last_step = now;
+        if let Some(quiz) = quiz.as_mut() {
+            match quiz.tick(now) {
+                Some(Cue::Play(freq)) => {
+                    quiz_track = status.instrument;
+                    engine.send(quiz_note(quiz_track, freq))?;
+                }
+                Some(Cue::Stop) => engine.send(LiveCommand::NoteOff {
+                    track: quiz_track,
+                    key: QUIZ_KEY,
+                })?,
+                None => {}
+            }
+            if quiz.line() != quiz_line {
+                quiz_line = quiz.line();
+                update_practice_line(stdout, &quiz_line);
             }
         }
 
-        if !event::poll(Duration::from_millis(50))
-            .map_err(|e| format!("event poll error: {}", e))?
+        // Poll faster while the bend is gliding so it moves smoothly, and
+        // while a throttled redraw is waiting
+        let poll = if bend.value == bend.target && !status.pending() {
+            IDLE_POLL
+        } else {
+            BUSY_POLL
+        };
+        if !event::poll(poll)
+            .map_err(|e| ClidawError::Terminal(format!("event poll error: {}", e)))?
         {
             continue;
         }
 
-        let ev = event::read().map_err(|e| format!("event read error: {}", e))?;
+        let ev = event::read()
+            .map_err(|e| ClidawError::Terminal(format!("event read error: {}", e)))?;
+
+        // The command line takes every key until Enter or Esc closes it
+        if let Some(line) = palette.as_mut() {
+            let Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press | KeyEventKind::Repeat,
+                ..
+            }) = ev
+            else {
+                continue;
+            };
+            match code {
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    let _ = shutdown_tx.send(());
+                    return Ok(());
+                }
+                KeyCode::Char(c) => line.push(c),
+                KeyCode::Backspace => {
+                    line.pop();
+                }
+                KeyCode::Esc => {
+                    palette = None;
+                    update_palette_line(stdout, "");
+                    continue;
+                }
+                KeyCode::Enter => {
+                    let reply = palette::parse(line)
+                        .and_then(|command| session.run_command(status, command));
+                    palette = None;
+                    match reply {
+                        Ok(Some(message)) => update_palette_line(stdout, &message),
+                        Ok(None) => {
+                            let _ = shutdown_tx.send(());
+                            return Ok(());
+                        }
+                        Err(message) => update_palette_line(stdout, &format!("error: {}", message)),
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            update_palette_line(stdout, &format!(":{}", line));
+            continue;
+        }
 
         match ev {
+            // Esc, or Ctrl-C (a key event in raw mode, not a signal): quit
             Event::Key(KeyEvent {
                 code: KeyCode::Esc,
                 kind: KeyEventKind::Press,
                 ..
+            })
+            | Event::Key(KeyEvent {
+                code: KeyCode::Char('c'),
+                modifiers: KeyModifiers::CONTROL,
+                kind: KeyEventKind::Press,
+                ..
             }) => {
                 // Signal the monitor thread to shut down
                 let _ = shutdown_tx.send(());
                 return Ok(());
             }
 
+            Event::Key(KeyEvent {
+                code: KeyCode::Char(' '),
+                kind: KeyEventKind::Press,
+                ..
+            }) => {
+                click_enabled.fetch_xor(true, Ordering::Relaxed);
+            }
+
+            Event::Key(KeyEvent {
+                code: KeyCode::Tab,
+                kind: KeyEventKind::Press,
+                ..
+            }) => {
+                let mut looper = session.looper.lock().unwrap();
+                let click = click_enabled.load(Ordering::Relaxed);
+                looper.set_bar(click.then_some(session.tempo.beat() * 4));
+                let status = match looper.toggle(Instant::now()) {
+                    LoopAction::TooShort => "too short to loop; discarded".to_string(),
+                    LoopAction::Full => format!("{} layers is the most", looper::MAX_LAYERS),
+                    _ => looper.status(),
+                };
+                update_loop_status(stdout, &status);
+            }
+
+            Event::Key(KeyEvent {
+                code: KeyCode::Backspace,
+                kind: KeyEventKind::Press,
+                ..
+            }) => {
+                let mut looper = session.looper.lock().unwrap();
+                for command in looper.undo() {
+                    engine.send(command)?;
+                }
+                update_loop_status(stdout, &looper.status());
+            }
+
+            // Panic: silence everything now, stuck notes included
+            Event::Key(KeyEvent {
+                code: KeyCode::Delete,
+                kind: KeyEventKind::Press,
+                ..
+            }) => {
+                engine.send(LiveCommand::Panic)?;
+                status.clear();
+            }
+
+            Event::Key(KeyEvent {
+                code: code @ (KeyCode::Up | KeyCode::Down),
+                kind,
+                ..
+            }) => {
+                let key = if code == KeyCode::Up {
+                    BEND_UP_KEY
+                } else {
+                    BEND_DOWN_KEY
+                };
+                if kind == KeyEventKind::Release {
+                    if tracker.lock().unwrap().release(key) {
+                        bend.release(key);
+                    }
+                } else {
+                    let mut tracker = tracker.lock().unwrap();
+                    if kind == KeyEventKind::Repeat {
+                        tracker.repeat(key, Instant::now());
+                    } else {
+                        tracker.press(key, Instant::now());
+                    }
+                    drop(tracker);
+                    bend.target = bend_direction(key).unwrap_or(0.0);
+                }
+            }
+
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('0'),
+                kind: KeyEventKind::Press,
+                ..
+            }) => {
+                bend.target = 0.0;
+            }
+
+            // Instrument switching: F-keys pick one, `[` and `]` step through
+            // them (unless the keymap plays notes on those keys). Held notes
+            // keep sounding with the instrument they started on.
+            Event::Key(KeyEvent {
+                code: KeyCode::F(n),
+                kind: KeyEventKind::Press,
+                ..
+            }) if (1..=status.instruments.len()).contains(&(n as usize)) => {
+                status.instrument = n as usize - 1;
+            }
+
+            Event::Key(KeyEvent {
+                code: KeyCode::Char(c @ ('[' | ']')),
+                kind: KeyEventKind::Press,
+                ..
+            }) if keymap.lookup(c).is_none() => {
+                status.cycle_instrument(if c == ']' { 1 } else { -1 });
+            }
+
+            // Open the command line; notes held now are let go, since their
+            // releases go to it
+            Event::Key(KeyEvent {
+                code: KeyCode::Char(PALETTE_KEY),
+                kind: KeyEventKind::Press,
+                ..
+            }) if keymap.lookup(PALETTE_KEY).is_none() => {
+                for (key, track) in status.release_all() {
+                    tracker.lock().unwrap().release(key);
+                    session.play(LiveCommand::NoteOff { track, key })?;
+                }
+                palette = Some(String::new());
+                update_palette_line(stdout, ":");
+            }
+
+            // Hear the quiz's note again
+            Event::Key(KeyEvent {
+                code: KeyCode::Enter,
+                kind: KeyEventKind::Press,
+                ..
+            }) if quiz.is_some() => {
+                let quiz = quiz.as_mut().expect("checked above");
+                let sounding = quiz.sounding();
+                if let Some(freq) = quiz.replay(Instant::now()) {
+                    if sounding {
+                        engine.send(LiveCommand::NoteOff {
+                            track: quiz_track,
+                            key: QUIZ_KEY,
+                        })?;
+                    }
+                    quiz_track = status.instrument;
+                    engine.send(quiz_note(quiz_track, freq))?;
+                }
+            }
+
+            Event::Key(KeyEvent {
+                code: KeyCode::Char(SOFT_KEY),
+                kind: KeyEventKind::Press,
+                ..
+            }) if keymap.lookup(SOFT_KEY).is_none() => {
+                status.soft = !status.soft;
+            }
+
             Event::Key(KeyEvent {
                 code: KeyCode::Char(c),
                 kind: KeyEventKind::Press,
                 ..
             }) => {
                 // Octave change with number keys
-                if let Some(digit) = c.to_digit(10) {
-                    if (1..=8).contains(&digit) {
-                        *octave = digit as u8;
-                        update_status(stdout, *octave, None);
-                        continue;
-                    }
+                if let Some(digit) = c.to_digit(10)
+                    && (1..=8).contains(&digit)
+                {
+                    status.octave = digit as u8;
+                    continue;
                 }
 
-                // Note key
-                if let Some((note_name, oct_offset)) = char_to_note(c) {
-                    let effective_octave = octave.saturating_add(oct_offset).min(8);
-                    let freq = note_name.to_freq(effective_octave);
-
-                    engine.send(LiveCommand::NoteOn {
-                        track: 0,
-                        key: c,
-                        freq,
-                    })?;
-                    update_status(
-                        stdout,
-                        *octave,
-                        Some(format!("{:?}{}", note_name, effective_octave)),
-                    );
-
-                    // Track this key as active for the fallback path
-                    if !has_key_release {
-                        let mut keys = active_keys.lock().unwrap();
-                        keys.insert(c, Instant::now());
+                // Note key, accented with Shift
+                let (c, accent) = note_key(keymap, c);
+                if let Some((note, _)) = keymap.lookup(c) {
+                    // A held key struck again plays its note again; terminals
+                    // without release events report auto-repeat as presses,
+                    // which only keep the key held
+                    let press = tracker.lock().unwrap().press(c, Instant::now());
+                    match press {
+                        Press::Repeat => {}
+                        Press::Resume => session.start_note(keymap, status, c, accent)?,
+                        Press::New | Press::Retrigger => {
+                            if press == Press::Retrigger
+                                && let Some(track) = status.note_off(c)
+                            {
+                                session.play(LiveCommand::NoteOff { track, key: c })?;
+                            }
+                            session.start_note(keymap, status, c, accent)?;
+                            answer_practice(session, quiz.as_mut(), quiz_track, note, stdout)?;
+                        }
                     }
                 }
             }
@@ -187,11 +1349,12 @@ fn event_loop(
                 code: KeyCode::Char(c),
                 kind: KeyEventKind::Repeat,
                 ..
-            }) => {
-                // Key is being held - update its timestamp so it doesn't get released
-                if !has_key_release && char_to_note(c).is_some() {
-                    let mut keys = active_keys.lock().unwrap();
-                    keys.insert(c, Instant::now());
+            }) if keymap.lookup(note_key(keymap, c).0).is_some() => {
+                // Key is being held - update its timestamp so it doesn't get
+                // released, and restart its note if the timeout cut it short
+                let (c, accent) = note_key(keymap, c);
+                if tracker.lock().unwrap().repeat(c, Instant::now()) {
+                    session.start_note(keymap, status, c, accent)?;
                 }
             }
 
@@ -199,13 +1362,13 @@ fn event_loop(
                 code: KeyCode::Char(c),
                 kind: KeyEventKind::Release,
                 ..
-            }) => {
-                if char_to_note(c).is_some() {
-                    engine.send(LiveCommand::NoteOff {
-                    track: 0,
-                    key: c,
-                })?;
-                    update_status(stdout, *octave, None);
+            }) if keymap.lookup(note_key(keymap, c).0).is_some() => {
+                // Shift may have been let go first; the note is the same
+                let (c, _) = note_key(keymap, c);
+                // The monitor may already have released this key
+                tracker.lock().unwrap().release(c);
+                if let Some(track) = status.note_off(c) {
+                    session.play(LiveCommand::NoteOff { track, key: c })?;
                 }
             }
 
@@ -214,30 +1377,453 @@ fn event_loop(
     }
 }
 
-fn print_banner(stdout: &mut io::Stdout, octave: u8) {
-    let banner = "\x1b[2J\x1b[H\
+/// A quiz note on `track`
+fn quiz_note(track: usize, freq: f64) -> LiveCommand {
+    LiveCommand::NoteOn {
+        track,
+        key: QUIZ_KEY,
+        freq,
+        velocity: PLAIN_VELOCITY,
+    }
+}
+
+/// A note key was pressed during practice: nudge toward the scale if
+/// `--strict` muted it, otherwise take it as the quiz's answer (ending the
+/// quiz note if it is still sounding)
+fn answer_practice(
+    session: &Session,
+    quiz: Option<&mut Quiz>,
+    quiz_track: usize,
+    note: NoteName,
+    stdout: &mut io::Stdout,
+) -> Result<(), ClidawError> {
+    let Some(practice) = session.practice else {
+        return Ok(());
+    };
+    if !practice.allows(note) {
+        update_practice_line(stdout, &practice.nudge(note));
+        return Ok(());
+    }
+    if let Some(quiz) = quiz {
+        let sounding = quiz.sounding();
+        if quiz.answer(note, Instant::now()).is_some() && sounding {
+            session.engine.send(LiveCommand::NoteOff {
+                track: quiz_track,
+                key: QUIZ_KEY,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// `live --latency`: the stream's buffer size, sample rate and the delays
+/// they add up to
+fn latency_report(latency: Option<&Latency>) -> String {
+    let Some(latency) = latency else {
+        return format!(
+            "No audio callback within {} s; the output may not be running\n",
+            LATENCY_WAIT.as_secs()
+        );
+    };
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let device = match latency.output {
+        Some(output) => format!("{:.1} ms", ms(output)),
+        None => "not reported (counted as one more buffer)".to_string(),
+    };
+    format!(
+        "Buffer size:     {} frames\n\
+         Sample rate:     {} Hz\n\
+         Buffer length:   {:.1} ms\n\
+         Device latency:  {}\n\
+         Key to sound:    up to {:.1} ms\n\
+         Smaller buffers (--buffer-size) cut the delay; larger ones stop crackling.\n",
+        latency.buffer_frames,
+        latency.sample_rate,
+        ms(latency.buffer()),
+        device,
+        ms(latency.total())
+    )
+}
+
+fn print_banner(
+    stdout: &mut io::Stdout,
+    keymap: &Keymap,
+    practice: Option<&Practice>,
+    instruments: &[String],
+    latency: Option<&Latency>,
+) {
+    let mut banner = String::from(
+        "\x1b[2J\x1b[H\
 clidaw live - interactive keyboard mode\r\n\
 ─────────────────────────────────────────\r\n\
-\r\n\
-  Natural notes:  a s d f g h j k l ; '\r\n\
-                  C D E F G A B C D E F\r\n\
-\r\n\
-  Sharps/flats:   w e   t y u   o p\r\n\
-                  C# D#  F# G# A#  C# D#\r\n\
-\r\n\
-  Octave (1-8):   press number keys\r\n\
-  Quit:           Esc\r\n\
-\r\n";
+\r\n",
+    );
+    let mut lines = keymap.banner_lines();
+    if let Some(practice) = practice {
+        lines.extend(practice.banner_lines(keymap));
+    }
+    for line in lines {
+        banner.push_str(&line);
+        banner.push_str("\r\n");
+    }
+    banner.push_str(
+        "  Octave (1-8):   press number keys\r\n\
+  Pitch bend:     Up/Down arrows (0 recenters)\r\n\
+  Metronome:      Space (on/off)\r\n\
+  Looper:         Tab (record, loop, overdub), Backspace (undo layer)\r\n\
+  Panic:          Delete (silence all notes)\r\n\
+  Dynamics:       Shift+note (accent), \\ (soft mode on/off)\r\n\
+  Commands:       : then tempo, instrument, octave, record, help or quit; Esc closes\r\n",
+    );
+    if instruments.len() > 1 {
+        banner.push_str(&format!(
+            "  Instrument:     [ ] or F1-F{} ({})\r\n",
+            instruments.len().min(12),
+            instruments.join(", ")
+        ));
+    }
+    if let Some(latency) = latency {
+        banner.push_str(&format!(
+            "  Latency:        up to {:.0} ms ({} frames at {} Hz)\r\n",
+            latency.total().as_secs_f64() * 1000.0,
+            latency.buffer_frames,
+            latency.sample_rate
+        ));
+    }
+    banner.push_str("  Quit:           Esc or Ctrl-C\r\n\r\n");
+    // Save the cursor position; the status block is redrawn below it
+    banner.push_str("\x1b[s");
     let _ = write!(stdout, "{}", banner);
-    update_status(stdout, octave, None);
 }
 
-fn update_status(stdout: &mut io::Stdout, octave: u8, note: Option<String>) {
-    let note_display = note.unwrap_or_else(|| "---".to_string());
-    let _ = write!(
-        stdout,
-        "\x1b[16;1H\x1b[2K  Octave: {}  |  Note: {}\r",
-        octave, note_display
-    );
+/// The looper's line, just below the octave/note line
+fn update_loop_status(stdout: &mut io::Stdout, status: &str) {
+    draw_row(stdout, LOOP_ROW, &format!("  Loop: {}", status));
+    let _ = stdout.flush();
+}
+
+/// The command line, below the looper's
+fn update_palette_line(stdout: &mut io::Stdout, text: &str) {
+    draw_row(stdout, PALETTE_ROW, &format!("  {}", text));
     let _ = stdout.flush();
 }
+
+/// The practice line, below the command line
+fn update_practice_line(stdout: &mut io::Stdout, text: &str) {
+    draw_row(stdout, PRACTICE_ROW, &format!("  {}", text));
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_block_sounds_each_key_once() {
+        let mut status = StatusBlock::new(&Keymap::builtin(), 4, Vec::new());
+        assert_eq!(status.note_on('a', "C4".to_string()), Some(0));
+        // Auto-repeat arriving as a press
+        assert_eq!(status.note_on('a', "C4".to_string()), None);
+        assert_eq!(status.note_on('w', "CSharp4".to_string()), Some(0));
+        let lines = status.lines();
+        assert!(lines[0].starts_with(&format!("    {}", " w ".reverse())), "{:?}", lines[0]);
+        assert!(lines[1].starts_with(&format!("  {} ", " a ".reverse())), "{:?}", lines[1]);
+        assert!(lines[1].ends_with(" ;   ' "));
+        assert_eq!(lines[2], "  Octave: 4  |  Note: CSharp4  |  Voices: 0");
+        assert_eq!(status.note_off('a'), Some(0));
+        // Release event after the timeout already released it
+        assert_eq!(status.note_off('a'), None);
+        assert_eq!(status.sounding, BTreeMap::from([('w', 0)]));
+    }
+
+    #[test]
+    fn test_switching_instruments_keeps_held_notes_on_their_track() {
+        let names = vec!["pluck".to_string(), "pad".to_string(), "bass".to_string()];
+        let mut status = StatusBlock::new(&Keymap::builtin(), 4, names);
+        assert_eq!(status.note_on('a', "C4".to_string()), Some(0));
+        status.cycle_instrument(1);
+        assert!(status.lines()[2].ends_with("  |  Instrument: pad"));
+        assert_eq!(status.note_on('s', "D4".to_string()), Some(1));
+        // The note held from before the switch is released where it started
+        assert_eq!(status.note_off('a'), Some(0));
+        assert_eq!(status.note_off('s'), Some(1));
+        status.cycle_instrument(-1);
+        status.cycle_instrument(-1);
+        assert_eq!(status.instrument, 2);
+        status.cycle_instrument(1);
+        assert_eq!(status.instrument, 0);
+    }
+
+    #[test]
+    fn test_palette_finds_instruments_and_lets_go_of_held_keys() {
+        let names = vec!["pluck".to_string(), "pad".to_string()];
+        assert_eq!(find_instrument(&names, "pad"), Ok(1));
+        assert_eq!(find_instrument(&names, "sounds/pad.instr"), Ok(1));
+        assert_eq!(find_instrument(&names, "1"), Ok(0));
+        let err = find_instrument(&names, "3").unwrap_err();
+        assert!(err.starts_with("no instrument '3' (loaded: pluck, pad)"), "{}", err);
+        assert!(find_instrument(&[], "pad").is_err());
+
+        let mut status = StatusBlock::new(&Keymap::builtin(), 4, names);
+        status.note_on('a', "C4".to_string());
+        status.instrument = 1;
+        status.note_on('s', "D4".to_string());
+        status.recording = Some("take.notes".to_string());
+        assert_eq!(status.release_all(), [('a', 0), ('s', 1)]);
+        assert_eq!(status.note_off('a'), None);
+        assert!(status.lines()[2].ends_with("  |  Recording: take.notes"));
+    }
+
+    #[test]
+    fn test_shift_accents_and_soft_mode() {
+        let keymap = Keymap::builtin();
+        assert_eq!(note_key(&keymap, 'a'), ('a', false));
+        assert_eq!(note_key(&keymap, 'A'), ('a', true));
+        // Not a note key either way
+        assert_eq!(note_key(&keymap, 'Z'), ('Z', false));
+
+        let mut status = StatusBlock::new(&keymap, 4, Vec::new());
+        assert_eq!((status.velocity(false), status.velocity(true)), (0.8, 1.0));
+        status.soft = true;
+        assert_eq!((status.velocity(false), status.velocity(true)), (0.5, 1.0));
+        assert!(status.lines()[2].ends_with("  |  Soft"));
+    }
+
+    #[test]
+    fn test_backing_clicks_on_its_bars_and_shows_where_it_is() {
+        let backing = Backing {
+            compiled: Compiled {
+                events: Vec::new(),
+                meter: crate::meter::MeterMap::new((3, 4)),
+                length: crate::beat::Beat::whole(6),
+                clamped: Vec::new(),
+            },
+            tempo: 90.0,
+            patches: Vec::new(),
+            mix: Mix::default(),
+            looped: true,
+        };
+        let options = backing.schedule_options(0.5);
+        let stream = scheduler::stream_compiled(&backing.compiled, 90.0, &options).unwrap();
+        let progress = Progress::of(&stream);
+        let accents: Vec<bool> = stream
+            .events
+            .take(7)
+            .map(|ev| matches!(ev.command, LiveCommand::Click { accent: true, .. }))
+            .collect();
+        assert_eq!(accents, [true, false, false, true, false, false, true]);
+
+        // 90 BPM: 5 s in is beat 7.5, the second pass's bar 1
+        let start = Instant::now();
+        let clock = BackingClock {
+            progress,
+            tempo: 90.0,
+            start,
+        };
+        let label = clock.label(start + Duration::from_secs(5));
+        assert_eq!(label, "Backing: loop 2  bar 1:2 at 90 BPM");
+        let mut status = StatusBlock::new(&Keymap::builtin(), 4, Vec::new());
+        status.backing = Some(label);
+        assert!(status.lines()[2].ends_with("  |  Backing: loop 2  bar 1:2 at 90 BPM"));
+    }
+
+    #[test]
+    fn test_latency_report() {
+        let latency = Latency {
+            sample_rate: 48000,
+            buffer_frames: 240,
+            output: None,
+        };
+        let report = latency_report(Some(&latency));
+        assert!(report.contains("Buffer length:   5.0 ms\n"), "{}", report);
+        // Without the device's figure, a second buffer stands in for it
+        assert!(report.contains("Key to sound:    up to 10.0 ms\n"), "{}", report);
+        let reported = Latency {
+            output: Some(Duration::from_millis(12)),
+            ..latency
+        };
+        let report = latency_report(Some(&reported));
+        assert!(report.contains("Device latency:  12.0 ms\n"), "{}", report);
+        assert!(report.contains("up to 17.0 ms"), "{}", report);
+        assert!(latency_report(None).starts_with("No audio callback"));
+    }
+
+    #[test]
+    fn test_status_block_redraws_are_throttled_and_partial() {
+        let start = Instant::now();
+        let mut status = StatusBlock::new(&Keymap::builtin(), 4, Vec::new());
+        let mut out = Vec::new();
+        status.draw(&mut out, start);
+        assert_eq!(String::from_utf8_lossy(&out).matches("\x1b[2K").count(), 3);
+
+        // Too soon after the last draw: held back until later
+        out.clear();
+        status.octave = 5;
+        status.draw(&mut out, start + Duration::from_millis(10));
+        assert!(out.is_empty());
+        assert!(status.pending());
+
+        // Only the octave line changed
+        status.draw(&mut out, start + REDRAW_INTERVAL);
+        let drawn = String::from_utf8_lossy(&out);
+        assert_eq!(drawn.matches("\x1b[2K").count(), 1);
+        assert!(drawn.starts_with("\x1b[u\x1b[2B\x1b[2K  Octave: 5"));
+        assert!(!status.pending());
+    }
+
+    #[test]
+    fn test_bend_glides_to_target() {
+        let mut bend = Bend::default();
+        assert!(!bend.step(0.1));
+        bend.target = 1.0;
+        // 5 full bends per second: 0.1 s moves halfway
+        assert!(bend.step(0.1));
+        assert!((bend.value - 0.5).abs() < 1e-12);
+        assert!(bend.step(1.0));
+        assert_eq!(bend.value, 1.0);
+        assert!(!bend.step(0.1));
+        // Releasing the other arrow doesn't recenter
+        bend.release(BEND_DOWN_KEY);
+        assert_eq!(bend.target, 1.0);
+        bend.release(BEND_UP_KEY);
+        bend.step(0.05);
+        assert!((bend.value - 0.75).abs() < 1e-12);
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_timeout_release_without_release_events() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(false, None);
+        assert_eq!(tracker.press('a', start), Press::New);
+        assert!(tracker.expired(start + Duration::from_millis(50)).is_empty());
+        assert_eq!(tracker.expired(start + Duration::from_millis(150)), vec!['a']);
+        // A late release event for the same key must not release it again
+        assert!(!tracker.release('a'));
+    }
+
+    #[test]
+    fn test_timeout_follows_measured_repeat_cadence() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(false, None);
+        // Repeat events: 250 ms to the first, then every 40 ms
+        tracker.press('a', start);
+        assert_eq!(tracker.expired(start + ms(150)), vec!['a']);
+        assert!(tracker.repeat('a', start + ms(250)));
+        assert!(!tracker.repeat('a', start + ms(290)));
+        assert!(!tracker.repeat('a', start + ms(330)));
+        assert!(tracker.expired(start + ms(375)).is_empty());
+        assert_eq!(tracker.expired(start + ms(400)), vec!['a']);
+        // A tap now waits out the measured delay
+        let tap = start + ms(1000);
+        tracker.press('s', tap);
+        assert!(tracker.expired(tap + ms(350)).is_empty());
+        assert_eq!(tracker.expired(tap + ms(400)), vec!['s']);
+    }
+
+    #[test]
+    fn test_slow_first_repeat_lengthens_the_wait() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(false, None);
+        tracker.press('a', start);
+        // A 900 ms repeat delay: the note is cut before the first repeat,
+        // which then restarts it and sets the delay
+        assert_eq!(tracker.expired(start + ms(150)), vec!['a']);
+        assert!(tracker.repeat('a', start + ms(900)));
+        assert!(!tracker.repeat('a', start + ms(950)));
+        assert!(!tracker.repeat('x', start + ms(950)));
+        assert_eq!(tracker.expired(start + ms(1100)), vec!['a']);
+        // The next hold lasts through its first repeat
+        let next = start + ms(2000);
+        tracker.press('a', next);
+        assert!(tracker.expired(next + ms(1300)).is_empty());
+        assert!(!tracker.repeat('a', next + ms(900)));
+    }
+
+    #[test]
+    fn test_press_of_held_key_retriggers() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(true, None);
+        assert_eq!(tracker.press('a', start), Press::New);
+        assert_eq!(tracker.press('a', start + ms(60)), Press::Retrigger);
+        assert!(tracker.release('a'));
+        // Without release events, only a press later than the key's timeout
+        // is a new strike; it waits for its first repeat again
+        let mut tracker = KeyTracker::new(false, None);
+        tracker.press('a', start);
+        assert!(!tracker.repeat('a', start + ms(300)));
+        assert!(!tracker.repeat('a', start + ms(330)));
+        assert_eq!(tracker.press('a', start + ms(400)), Press::Retrigger);
+        assert!(tracker.expired(start + ms(800)).is_empty());
+        assert_eq!(tracker.expired(start + ms(900)), vec!['a']);
+        // Once the delay is known, a press after the timeout is a new strike
+        assert_eq!(tracker.press('a', start + ms(1000)), Press::New);
+    }
+
+    #[test]
+    fn test_repeats_reported_as_presses() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(false, None);
+        // A held key on a terminal without release events: presses every 33 ms
+        assert_eq!(tracker.press('a', start), Press::New);
+        for n in 1..10 {
+            assert_eq!(tracker.press('a', start + ms(33 * n)), Press::Repeat);
+        }
+        // The measured interval sets the timeout, not `REPEAT_TIMEOUT`
+        let last = start + ms(33 * 9);
+        assert!(tracker.expired(last + ms(45)).is_empty());
+        assert_eq!(tracker.expired(last + ms(55)), vec!['a']);
+    }
+
+    #[test]
+    fn test_slow_first_repeat_reported_as_a_press() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(false, None);
+        tracker.press('s', start);
+        // The timeout cuts the note before a 400 ms first repeat, which
+        // resumes it and sets the delay
+        assert_eq!(tracker.expired(start + ms(150)), vec!['s']);
+        assert_eq!(tracker.press('s', start + ms(400)), Press::Resume);
+        assert_eq!(tracker.press('s', start + ms(440)), Press::Repeat);
+        assert_eq!(tracker.expired(start + ms(520)), vec!['s']);
+        // The next hold lasts through its first repeat
+        let next = start + ms(1000);
+        assert_eq!(tracker.press('s', next), Press::New);
+        assert_eq!(tracker.press('s', next + ms(400)), Press::Repeat);
+    }
+
+    #[test]
+    fn test_fixed_release_timeout() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(false, Some(ms(200)));
+        tracker.press('a', start);
+        assert!(tracker.expired(start + ms(150)).is_empty());
+        assert_eq!(tracker.expired(start + ms(250)), vec!['a']);
+    }
+
+    #[test]
+    fn test_release_event_switches_strategy() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(false, None);
+        tracker.press('a', start);
+        assert!(tracker.release('a'));
+        // Now trusting release events: a held key is not timed out quickly
+        tracker.press('s', start);
+        assert!(tracker.expired(start + ms(500)).is_empty());
+        assert!(tracker.release('s'));
+        assert!(!tracker.release('s'));
+    }
+
+    #[test]
+    fn test_missing_release_falls_back_to_timeout() {
+        let start = Instant::now();
+        let mut tracker = KeyTracker::new(true, None);
+        tracker.press('a', start);
+        assert_eq!(tracker.expired(start + ms(1500)), vec!['a']);
+        tracker.press('s', start + ms(1500));
+        assert_eq!(tracker.expired(start + ms(1650)), vec!['s']);
+    }
+}
@@ -0,0 +1,148 @@
+//! `clidaw parse --roll`: a pattern drawn as a piano roll.
+//!
+//! Each pitch the pattern plays gets a row, highest first, and each grid
+//! step a column; `|` marks bar lines. Bars are wrapped into groups that
+//! fit the terminal, each under a line of bar numbers.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::note::Pattern;
+
+/// A step a note sounds on
+const NOTE_CELL: char = '█';
+/// Silent steps: a dot on each beat, blank in between
+const BEAT_CELL: char = '.';
+const STEP_CELL: char = ' ';
+
+/// Width to wrap at when the terminal's is unknown
+pub const DEFAULT_WIDTH: usize = 80;
+
+/// Steps a beat position falls on, rounded to the nearest
+fn to_step(beat: f64, steps_per_beat: u32) -> usize {
+    (beat * steps_per_beat as f64).round().max(0.0) as usize
+}
+
+/// Draw `pattern` with `steps_per_beat` columns per beat, wrapped to lines
+/// of at most `width` characters (a bar wider than that gets a line of its
+/// own). Notes shorter than a step still fill one.
+pub fn render(pattern: &Pattern, steps_per_beat: u32, width: usize) -> String {
+    let total = to_step(pattern.length_beats().as_f64(), steps_per_beat);
+    // Rows by MIDI key, drawn in reverse so the highest pitch is on top
+    let mut rows: BTreeMap<u8, (String, Vec<bool>)> = BTreeMap::new();
+    for (start, length, note) in pattern.to_timeline() {
+        let first = to_step(start.as_f64(), steps_per_beat);
+        if first >= total {
+            continue;
+        }
+        let end = to_step((start + length).as_f64(), steps_per_beat).clamp(first + 1, total);
+        let (_, cells) = rows.entry(note.note.to_midi(note.octave)).or_insert_with(|| {
+            (note.label(), vec![false; total])
+        });
+        cells[first..end].fill(true);
+    }
+    if rows.is_empty() {
+        return "(no notes)\n".to_string();
+    }
+
+    // Bars as step ranges, cut off at the end of the pattern
+    let mut bars = Vec::new();
+    let mut bar = 0;
+    loop {
+        let start = to_step(pattern.meter.beat_of_bar(bar), steps_per_beat);
+        if start >= total {
+            break;
+        }
+        let end = to_step(pattern.meter.beat_of_bar(bar + 1), steps_per_beat).min(total);
+        bars.push(start..end);
+        bar += 1;
+    }
+
+    let label_width = rows.values().map(|(label, _)| label.len()).max().unwrap_or(0);
+    // The label and its space, and the closing bar line
+    let room = width.saturating_sub(label_width + 2);
+    let mut out = String::new();
+    let mut first_bar = 0;
+    while first_bar < bars.len() {
+        // Each bar takes its steps and the `|` in front of them
+        let mut last_bar = first_bar + 1;
+        let mut used = bars[first_bar].len() + 1;
+        while last_bar < bars.len() && used + bars[last_bar].len() < room {
+            used += bars[last_bar].len() + 1;
+            last_bar += 1;
+        }
+        let group = &bars[first_bar..last_bar];
+
+        if first_bar > 0 {
+            out.push('\n');
+        }
+        let mut numbers = " ".repeat(label_width + 1);
+        for (idx, steps) in group.iter().enumerate() {
+            let number = (first_bar + idx + 1).to_string();
+            let cell = steps.len() + 1;
+            let _ = write!(numbers, "{:<cell$}", number.chars().take(cell).collect::<String>());
+        }
+        let _ = writeln!(out, "{}", numbers.trim_end());
+        for (label, cells) in rows.values().rev() {
+            let _ = write!(out, "{:>label_width$} ", label);
+            for steps in group {
+                out.push('|');
+                for step in steps.clone() {
+                    out.push(if cells[step] {
+                        NOTE_CELL
+                    } else if step % steps_per_beat as usize == 0 {
+                        BEAT_CELL
+                    } else {
+                        STEP_CELL
+                    });
+                }
+            }
+            out.push_str("|\n");
+        }
+        first_bar = last_bar;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_pattern;
+
+    fn roll(input: &str, steps_per_beat: u32, width: usize) -> String {
+        let pattern = parse_pattern(input).unwrap();
+        render(&pattern, steps_per_beat, width)
+    }
+
+    #[test]
+    fn test_chords_ties_and_bar_lines() {
+        let out = roll("beats: 8\na:0.5 s:0.5 [ad] _ - | d:2 - g:0.25 -:0.75 |\n", 2, 80);
+        // A chord is a row per note, held a second beat by the tie; the
+        // quarter-beat G still fills a half-beat step
+        let expected = "   1        2
+G4 |. . . . |. . . █ |
+E4 |. ████. |████. . |
+D4 |.█. . . |. . . . |
+C4 |█ ████. |. . . . |
+";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_long_patterns_wrap_by_bars() {
+        let out = roll("a - - - | - s - - | - - d - |\n", 1, 15);
+        // Two four-step bars fit in 15 columns, the third goes below
+        let expected = "   1    2
+E4 |....|....|
+D4 |....|.█..|
+C4 |█...|....|
+
+   3
+E4 |..█.|
+D4 |....|
+C4 |....|
+";
+        assert_eq!(out, expected);
+        assert_eq!(roll("- - |\n", 4, 80), "(no notes)\n");
+    }
+}
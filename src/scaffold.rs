@@ -0,0 +1,265 @@
+//! `clidaw new`: starter projects and commented template files.
+//!
+//! Each template is a function so tests can load what it generates the
+//! same way `play` would. Files are only written once none of them exist
+//! (or `--force` is given), so a half-made project is never left behind.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::ClidawError;
+
+/// File name of a new project's song
+pub const SONG_FILE: &str = "song.song";
+
+/// A new project's song: two tracks, each on its own pattern and instrument
+pub fn song_template(name: &str) -> String {
+    format!(
+        "\
+# {name}: a starter song from `clidaw new`. Play it with
+#   clidaw play {name}/{SONG_FILE}
+# Paths are relative to this file. Tracks play in parallel; each plays its
+# patterns one after another (`* 2` repeats one).
+tempo: 110
+time_signature: 4/4
+
+# A plucked bass line
+instrument: bass.instr
+name: bass
+bass.notes * 2
+
+# Chords, then a melody over them
+instrument: keys.instr
+name: keys
+keys.notes * 2
+",
+    )
+}
+
+/// The bass track's pattern: notes, rests and octave changes
+pub fn bass_pattern() -> String {
+    "\
+# Bass: two bars of 4/4 in octave 2
+beats: 8
+octave: 2
+
+# Home row letters are notes (a = C, h = A); - rests for a beat
+a - a g | h - > a < g |
+"
+    .to_string()
+}
+
+/// The keys track's pattern: chords, then a melody with an octave change
+pub fn keys_pattern() -> String {
+    "\
+# Keys: a bar of chords, then a bar of melody
+beats: 8
+octave: 4
+
+# [..] plays notes together; chord symbols (Cmaj, Am7) work too
+[adg] Am Fmaj G7 |
+# > goes up an octave for the rest of the line; -:0.5 rests half a beat
+> a:0.5 -:0.5 s d:2 |
+"
+    .to_string()
+}
+
+/// A .notes file with tracks of its own, playable without the song
+pub fn jam_pattern(name: &str) -> String {
+    format!(
+        "\
+# Jam: tracks in one file play in parallel when it is played on its own:
+#   clidaw play {name}/jam.notes
+tempo: 110
+beats: 4
+
+[track: bass]
+patch: bass.instr
+octave: 2
+a _ _ _ |
+
+[track: keys]
+patch: keys.instr
+[adg] - Fmaj -- |
+",
+    )
+}
+
+/// The bass instrument: a short, bright pluck
+pub fn bass_instrument() -> String {
+    "\
+# Bass: a short pluck. Times are in seconds, sustain is a level (0-1).
+attack: 0.005
+decay: 0.15
+sustain: 0.4
+release: 0.1
+waveform: saw
+"
+    .to_string()
+}
+
+/// The keys instrument: a soft pad with a little detune
+pub fn keys_instrument() -> String {
+    "\
+# Keys: a soft pad, three slightly detuned oscillators per note
+attack: 0.05
+decay: 0.3
+sustain: 0.7
+release: 0.4
+unison: 3
+detune: 8
+gain: 0.25
+"
+    .to_string()
+}
+
+/// `clidaw new --pattern`: every directive, commented, around a short line
+pub fn pattern_template() -> String {
+    "\
+# A .notes pattern. Play it with: clidaw play <file> [--instrument <file.instr>]
+# Length in beats (default: the sum of the notes)
+beats: 4
+# Starting octave, 0-8 (C4 is middle C)
+octave: 4
+# Other directives:
+#   tempo: 120              playback tempo when played on its own
+#   time_signature: 3/4     bars checked by `clidaw parse --strict-bars`
+#   key: D minor            enables scale degrees (1 2 3 ...)
+#   dyn: mf                 dynamics for the notes after it
+#   include: other.notes    play another file's notes here
+
+# a s d f g h j k = C D E F G A B C; w e t y u = the sharps
+# - rests a beat, _ holds the note before, | is a bar line
+a s d f |
+"
+    .to_string()
+}
+
+/// `clidaw new --instrument`: the envelope and the common settings, the
+/// optional ones commented out
+pub fn instrument_template() -> String {
+    "\
+# An instrument. Use it with `instrument:` in a .song, `patch:` in a
+# .notes file, or: clidaw audition <file>
+# Envelope: times in seconds, sustain is a level (0-1)
+attack: 0.01
+decay: 0.1
+sustain: 0.7
+release: 0.25
+
+# Oscillator: sine, square, saw, triangle or noise
+waveform: sine
+# Loudness of each note (default 0.3)
+gain: 0.3
+
+# Optional:
+#   unison: 3               oscillators per note
+#   detune: 10              their spread in cents
+#   mono: true              one note at a time
+#   glide: 0.05             slide between mono notes (seconds)
+#   retrigger: reset        replaying a sounding key (reset, continue or legato)
+#   noise_color: pink       with waveform: noise (white, pink or tuned)
+#   vel_to_amp: 0.6         how far velocity sets the level (default 1)
+#   vel_to_attack: 0.5      harder notes get a shorter attack
+#   chorus_mix: 0.3         chorus (also chorus_depth, chorus_rate)
+#   delay_mix: 0.3          echo (also delay_time, delay_feedback)
+#   type: fm                two-operator FM (fm_ratio, fm_index, ...)
+"
+    .to_string()
+}
+
+/// Every file of a new project named `name`, as (file name, contents)
+pub fn project_files(name: &str) -> Vec<(&'static str, String)> {
+    vec![
+        (SONG_FILE, song_template(name)),
+        ("bass.notes", bass_pattern()),
+        ("keys.notes", keys_pattern()),
+        ("jam.notes", jam_pattern(name)),
+        ("bass.instr", bass_instrument()),
+        ("keys.instr", keys_instrument()),
+    ]
+}
+
+/// Write `files` as (path, contents), creating their directories. Unless
+/// `force` is set, nothing is written if any of them already exists.
+pub fn write_files(files: &[(PathBuf, String)], force: bool) -> Result<(), ClidawError> {
+    if !force && let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+        return Err(ClidawError::Usage(format!(
+            "{} already exists (use --force to overwrite it)",
+            path.display()
+        )));
+    }
+    for (path, contents) in files {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| ClidawError::io(dir, e))?;
+        }
+        fs::write(path, contents).map_err(|e| ClidawError::io(path, e))?;
+    }
+    Ok(())
+}
+
+/// A new project's files, placed in `dir`
+pub fn project(dir: &Path) -> Vec<(PathBuf, String)> {
+    let name = dir.display().to_string();
+    project_files(&name)
+        .into_iter()
+        .map(|(file, contents)| (dir.join(file), contents))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::Beat;
+    use crate::check::{CheckOptions, check_song};
+    use crate::test_util::temp_dir;
+    use crate::{instrument, parser};
+
+    #[test]
+    fn test_project_checks_clean() {
+        let dir = temp_dir("project");
+        write_files(&project(&dir), false).unwrap();
+        let options = CheckOptions {
+            strict_bars: true,
+            strict: true,
+        };
+        let report = check_song(&dir.join(SONG_FILE), &options);
+        assert!(report.diagnostics.is_empty(), "{:?}", report.diagnostics);
+
+        // The multi-track file finds its instruments next to it
+        let jam = dir.join("jam.notes");
+        let input = fs::read_to_string(&jam).unwrap();
+        let comp = parser::parse_with(&input, parser::ParseOptions::for_file(&jam, true)).unwrap();
+        assert_eq!(comp.tracks.len(), 2);
+        for track in &comp.tracks {
+            let patch = comp.track_patch(track).unwrap();
+            assert!(Path::new(patch).exists(), "{}", patch);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_templates_load() {
+        let pattern = parser::parse_pattern(&pattern_template());
+        assert_eq!(pattern.unwrap().length_beats(), Beat::whole(4));
+        let instr = instrument::parse(&instrument_template()).unwrap();
+        assert!(instr.validate().is_empty());
+    }
+
+    #[test]
+    fn test_existing_files_are_kept_without_force() {
+        let dir = temp_dir("force");
+        let files = project(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("keys.instr"), "attack: 1\n").unwrap();
+        let err = write_files(&files, false).unwrap_err();
+        assert!(err.to_string().contains("keys.instr"), "{}", err);
+        // Nothing else was written either
+        assert!(!dir.join(SONG_FILE).exists());
+        assert_eq!(fs::read_to_string(dir.join("keys.instr")).unwrap(), "attack: 1\n");
+
+        write_files(&files, true).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("keys.instr")).unwrap(), keys_instrument());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
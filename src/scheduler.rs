@@ -1,100 +1,3097 @@
 //! Builds a sorted timeline of (beat, command) from a Song and loaded patterns.
+//!
+//! `ScheduleIter` produces the timeline lazily, a few events ahead of
+//! playback; `compile` collects it, with the song's automation, into a
+//! `Vec`.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::iter::Peekable;
 use std::path::PathBuf;
+use std::rc::Rc;
 
-use crate::note::{Event, Pattern, event_duration};
-use crate::synth::LiveCommand;
+use serde::{Deserialize, Serialize};
+
+use crate::beat::Beat;
+use crate::error::ClidawError;
+use crate::meter::MeterMap;
+use crate::note::{
+    Alternatives, Drum, Event, NoteEvent, Pattern, event_duration, freq_to_midi, midi_to_freq,
+    is_generative, rolls, tied_length,
+};
+use crate::rng::Rng;
+use crate::song::{Align, SectionAlign, Segment, Song, SongTrack};
+use crate::synth::{LiveCommand, Param};
 
 /// One scheduled event: at this beat, send this command.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ScheduledEvent {
-    pub beat: f64,
+    pub beat: Beat,
     pub command: LiveCommand,
 }
 
+/// Seconds from the start of the schedule to `beat` at `tempo` BPM
+pub fn beats_to_secs(beat: Beat, tempo: f64) -> f64 {
+    beat.as_f64() * 60.0 / tempo
+}
+
+/// Voice keys: private-use codepoints that no typed character can collide
+/// with
+const VOICE_KEYS: [char; 0x200] = {
+    let mut keys = ['\u{E000}'; 0x200];
+    let mut idx = 0;
+    while idx < keys.len() {
+        keys[idx] = match char::from_u32(0xE000 + idx as u32) {
+            Some(key) => key,
+            None => panic!("private-use codepoint"),
+        };
+        idx += 1;
+    }
+    keys
+};
+
+/// Unique key for the next scheduled voice, cycling through `VOICE_KEYS`
+fn next_voice_key(counter: &mut u32) -> char {
+    let key = VOICE_KEYS[*counter as usize % VOICE_KEYS.len()];
+    *counter += 1;
+    key
+}
+
 /// Build a sorted list of (beat, command) for the entire song.
 /// patterns: map from notes file path (as used in song) to loaded Pattern.
+/// Tests check `ScheduleIter` against this eager version.
+#[cfg(test)]
 pub fn build_schedule(
-    song: &crate::song::Song,
+    song: &Song,
     patterns: &HashMap<PathBuf, Pattern>,
-) -> Result<Vec<ScheduledEvent>, String> {
+) -> Result<Vec<ScheduledEvent>, ClidawError> {
     let mut events: Vec<ScheduledEvent> = Vec::new();
+    let lengths = track_lengths(song, patterns)?;
+    let end = aligned_end(song.align, &lengths);
+    let starts = slot_starts(song, patterns)?;
+    let mut seeds = Rng::new(song.seed);
 
     for (track_idx, track) in song.tracks.iter().enumerate() {
-        let mut track_beat = 0.0_f64;
+        let mut track_beat = Beat::from_f64(track.offset);
+        let sequence = sequence_length(track, patterns)?;
         let mut key_counter: u32 = 0;
+        let mut rng = Rng::new(seeds.next_u64());
+        let mut cycle: u64 = 0;
 
-        for segment in &track.sequence {
-            let pattern = patterns.get(&segment.notes_path).ok_or_else(|| {
-                format!(
-                    "pattern not loaded: {}",
-                    segment.notes_path.display()
-                )
-            })?;
-
-            let pattern_len = pattern.length_beats();
-
-            for _rep in 0..segment.times {
-                let mut event_beat = 0.0_f64;
-
-                for ev in &pattern.events {
-                    match ev {
-                        Event::Note(n) => {
-                            // Use private-use codepoints for unique keys per voice
-                            let key = char::from_u32(0xE000u32.saturating_add(key_counter % 0x200))
-                                .unwrap_or('\0');
-                            key_counter += 1;
-                            let freq = n.note.to_freq(n.octave);
-                            events.push(ScheduledEvent {
-                                beat: track_beat + event_beat,
-                                command: LiveCommand::NoteOn {
-                                    track: track_idx,
-                                    key,
-                                    freq,
-                                },
-                            });
-                            events.push(ScheduledEvent {
-                                beat: track_beat + event_beat + 1.0,
-                                command: LiveCommand::NoteOff {
-                                    track: track_idx,
-                                    key,
-                                },
-                            });
+        'passes: loop {
+            for segment in &track.sequence {
+                let pattern = find_pattern(patterns, segment)?;
+                let pattern_len = pattern.length_beats();
+                let shift = song.transpose as i32 + segment.transpose as i32;
+                if let Some(slot) = segment.slot {
+                    track_beat = track_beat.max(starts[slot]);
+                }
+
+                for rep in 0..segment.times {
+                    let mut event_beat = Beat::ZERO;
+                    let round = cycle * segment.times as u64 + rep as u64;
+                    let played = realize(&pattern.events, round, &mut rng);
+
+                    for (idx, ev) in played.iter().enumerate() {
+                        let start = track_beat + event_beat;
+                        if end.is_some_and(|end| start >= end) {
+                            break 'passes;
                         }
-                        Event::Chord(notes) => {
-                            for n in notes {
-                                let key = char::from_u32(0xE000u32.saturating_add(key_counter % 0x200))
-                                    .unwrap_or('\0');
-                                key_counter += 1;
-                                let freq = n.note.to_freq(n.octave);
-                                events.push(ScheduledEvent {
-                                    beat: track_beat + event_beat,
-                                    command: LiveCommand::NoteOn {
-                                        track: track_idx,
-                                        key,
-                                        freq,
-                                    },
-                                });
-                                events.push(ScheduledEvent {
-                                    beat: track_beat + event_beat + 1.0,
-                                    command: LiveCommand::NoteOff {
-                                        track: track_idx,
-                                        key,
-                                    },
-                                });
-                            }
+                        let rest = &played[idx..];
+                        let (counter, out) = (&mut key_counter, &mut events);
+                        expand_clipped(rest, track_idx, start, shift, end, counter, out);
+                        event_beat += event_duration(ev);
+                    }
+
+                    track_beat += pattern_len;
+                }
+            }
+            if !loops_again(song.align, sequence, track_beat, end) {
+                break;
+            }
+            cycle += 1;
+        }
+    }
+
+    sort_schedule(&mut events);
+    Ok(events)
+}
+
+/// One repeat of `events` as `build_schedule` plays it: each `alt{...}` replaced by the
+/// choice picked for repeat `round`, and notes that miss their chance
+/// replaced by rest. Every group is picked before any chance is rolled,
+/// in order, as `TrackCursor` draws them.
+#[cfg(test)]
+fn realize(events: &[Event], round: u64, rng: &mut Rng) -> Vec<Event> {
+    let picks: Vec<usize> = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Alt(alt) => Some(alt.choose(round, rng)),
+            _ => None,
+        })
+        .collect();
+    let mut picks = picks.into_iter();
+    let mut realized = Vec::with_capacity(events.len());
+    for event in events {
+        match event {
+            Event::Alt(alt) => {
+                let choice = &alt.choices[picks.next().expect("one pick per group")];
+                // Rests at both ends keep ties from reaching across the group
+                realized.push(Event::Rest(Beat::ZERO));
+                realized.extend(choice.iter().map(|e| roll_notes(e, rng)));
+                let played: Beat = choice.iter().map(event_duration).sum();
+                realized.push(Event::Rest(alt.length() - played));
+            }
+            event => realized.push(roll_notes(event, rng)),
+        }
+    }
+    realized
+}
+
+/// `event` with only the notes that make their chance, or a rest if none do
+#[cfg(test)]
+fn roll_notes(event: &Event, rng: &mut Rng) -> Event {
+    let notes = event.notes();
+    if notes.iter().all(|n| n.chance >= 1.0) {
+        return event.clone();
+    }
+    let mut kept: Vec<NoteEvent> = notes.iter().filter(|n| rolls(n.chance, rng)).cloned().collect();
+    let beats = event_duration(event);
+    match (event, kept.len()) {
+        (_, 0) => Event::Rest(beats),
+        (Event::Note(..), _) => Event::Note(kept.remove(0), beats),
+        _ => Event::Chord(kept, beats),
+    }
+}
+
+/// Length in beats of each track's offset plus one pass through its
+/// sequence. In a song with sections every track lasts the whole
+/// arrangement.
+pub fn track_lengths(
+    song: &Song,
+    patterns: &HashMap<PathBuf, Pattern>,
+) -> Result<Vec<Beat>, ClidawError> {
+    if song.arrangement.is_some() {
+        let total = slot_starts(song, patterns)?.last().copied().unwrap_or_default();
+        return Ok(vec![total; song.tracks.len()]);
+    }
+    song.tracks
+        .iter()
+        .map(|track| Ok(Beat::from_f64(track.offset) + sequence_length(track, patterns)?))
+        .collect()
+}
+
+/// Length in beats of one pass through a track's sequence, leaving out its
+/// offset and any gaps between its sections.
+fn sequence_length(
+    track: &SongTrack,
+    patterns: &HashMap<PathBuf, Pattern>,
+) -> Result<Beat, ClidawError> {
+    track.sequence.iter().try_fold(Beat::ZERO, |total, segment| {
+        let pattern = find_pattern(patterns, segment)?;
+        Ok(total + pattern.length_beats() * segment.times)
+    })
+}
+
+/// Start beat of each arrangement position of a song with sections, then
+/// the end of the last one. A section lasts as long as its longest part;
+/// under `section_align: error` its parts must all be that long.
+pub fn slot_starts(
+    song: &Song,
+    patterns: &HashMap<PathBuf, Pattern>,
+) -> Result<Vec<Beat>, ClidawError> {
+    let Some(arrangement) = &song.arrangement else {
+        return Ok(Vec::new());
+    };
+    let slots = arrangement.sections.len();
+    // Length of each track's part in each position (None: it sits out)
+    let mut parts: Vec<Vec<Option<Beat>>> = vec![vec![None; song.tracks.len()]; slots];
+    for (track_idx, track) in song.tracks.iter().enumerate() {
+        for segment in &track.sequence {
+            let Some(slot) = segment.slot else { continue };
+            let pattern = find_pattern(patterns, segment)?;
+            let part = parts[slot][track_idx].get_or_insert(Beat::ZERO);
+            *part += pattern.length_beats() * segment.times;
+        }
+    }
+    let mut starts = vec![Beat::ZERO];
+    for (slot, lengths) in parts.iter().enumerate() {
+        let longest = lengths.iter().flatten().copied().max().unwrap_or_default();
+        if arrangement.align == SectionAlign::Error
+            && let Some(idx) = lengths.iter().position(|l| l.is_some_and(|l| l != longest))
+        {
+            return Err(ClidawError::Schedule(format!(
+                "section '{}' (position {} of the arrangement): track {} is {} beats long but \
+                 the section is {} beats (see 'section_align:')",
+                song.slot_name(slot),
+                slot + 1,
+                song.tracks[idx].name,
+                lengths[idx].unwrap_or_default(),
+                longest
+            )));
+        }
+        starts.push(starts[slot] + longest);
+    }
+    Ok(starts)
+}
+
+/// Beat at which every track stops under `align` (None: each track plays
+/// its sequence once and stops on its own).
+fn aligned_end(align: Align, lengths: &[Beat]) -> Option<Beat> {
+    let lengths = lengths.iter().copied();
+    match align {
+        Align::Pad => None,
+        Align::Loop => lengths.max(),
+        Align::Truncate => lengths.min(),
+    }
+}
+
+/// Whether a looping track that has reached `track_beat` starts its
+/// sequence (`length` beats a pass) again. One that takes no time never
+/// does, whatever its offset.
+fn loops_again(align: Align, length: Beat, track_beat: Beat, end: Option<Beat>) -> bool {
+    align == Align::Loop && length > Beat::ZERO && end.is_some_and(|end| track_beat < end)
+}
+
+/// `expand_event` for the first of `events` (the rest are the events after
+/// it in its pattern, for ties), with NoteOffs that would land after `end`
+/// pulled back to it so a pattern cut off mid-way leaves nothing sounding.
+#[cfg(test)]
+fn expand_clipped(
+    events: &[Event],
+    track: usize,
+    beat: Beat,
+    shift: i32,
+    end: Option<Beat>,
+    key_counter: &mut u32,
+    out: &mut Vec<ScheduledEvent>,
+) {
+    let first = out.len();
+    expand_event(&events[0], track, beat, tied_length(events, 0), shift, key_counter, out);
+    if let Some(end) = end {
+        for scheduled in &mut out[first..] {
+            scheduled.beat = scheduled.beat.min(end);
+        }
+    }
+}
+
+/// The pattern `segment` plays: its file's, or silence of its `length`
+/// when the file was missing (see `song::Missing`)
+fn find_pattern<'a>(
+    patterns: &'a HashMap<PathBuf, Pattern>,
+    segment: &Segment,
+) -> Result<Cow<'a, Pattern>, ClidawError> {
+    let path = &segment.notes_path;
+    match (patterns.get(path), segment.length) {
+        (Some(pattern), _) => Ok(Cow::Borrowed(pattern)),
+        (None, Some(beats)) => Ok(Cow::Owned(Pattern::silence(Beat::from_f64(beats)))),
+        (None, None) => Err(ClidawError::Schedule(format!(
+            "pattern not loaded: {}",
+            path.display()
+        ))),
+    }
+}
+
+/// Append the commands for one pattern event starting at `beat`. Notes
+/// sound for `length` beats (more than one when tied). `Step` does the same
+/// for `ScheduleIter`; `build_schedule` keeps this to check it against.
+#[cfg(test)]
+fn expand_event(
+    ev: &Event,
+    track: usize,
+    beat: Beat,
+    length: Beat,
+    shift: i32,
+    key_counter: &mut u32,
+    out: &mut Vec<ScheduledEvent>,
+) {
+    let notes: &[NoteEvent] = match ev {
+        Event::Note(n, _) => std::slice::from_ref(n),
+        Event::Chord(notes, _) => notes,
+        Event::Drums(drums, _) => {
+            for &drum in drums {
+                out.push(ScheduledEvent {
+                    beat,
+                    command: LiveCommand::DrumHit {
+                        track,
+                        drum,
+                        velocity: 1.0,
+                    },
+                });
+            }
+            return;
+        }
+        Event::Rest(_) | Event::Tie(_) | Event::BarLine | Event::Alt(_) => return,
+    };
+    for n in notes {
+        let key = next_voice_key(key_counter);
+        out.push(ScheduledEvent {
+            beat,
+            command: LiveCommand::NoteOn {
+                track,
+                key,
+                freq: transposed_freq(n, shift),
+                velocity: n.velocity,
+            },
+        });
+        out.push(ScheduledEvent {
+            beat: beat + length,
+            command: LiveCommand::NoteOff { track, key },
+        });
+    }
+}
+
+/// MIDI number of a note shifted by `semitones` (may be out of range).
+fn shifted_midi(n: &NoteEvent, semitones: i32) -> i32 {
+    n.note.to_midi(n.octave) as i32 + semitones
+}
+
+/// Frequency of a note shifted by `semitones`, clamped to MIDI 0-127.
+fn transposed_freq(n: &NoteEvent, semitones: i32) -> f64 {
+    midi_to_freq(shifted_midi(n, semitones).clamp(0, 127) as u8)
+}
+
+/// A segment whose transposition pushed notes outside the MIDI range; they
+/// are clamped when scheduled
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clamped {
+    pub track: usize,
+    pub track_name: String,
+    pub notes_path: PathBuf,
+    /// Semitones, song and segment transposes together
+    pub shift: i32,
+    /// Notes clamped over all the segment's repeats
+    pub notes: usize,
+}
+
+impl fmt::Display for Clamped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "track {} ({}): transposing {} by {} put {} note(s) outside the MIDI range; clamped",
+            self.track,
+            self.track_name,
+            self.notes_path.display(),
+            self.shift,
+            self.notes
+        )
+    }
+}
+
+/// Every segment whose transposition pushes notes outside the MIDI range
+fn clamped_segments(song: &Song, patterns: &HashMap<PathBuf, Pattern>) -> Vec<Clamped> {
+    let mut clamped = Vec::new();
+    for (track_idx, track) in song.tracks.iter().enumerate() {
+        for segment in &track.sequence {
+            let Some(pattern) = patterns.get(&segment.notes_path) else {
+                continue;
+            };
+            let shift = song.transpose as i32 + segment.transpose as i32;
+            let per_rep = pattern
+                .events
+                .iter()
+                .flat_map(|ev| match ev {
+                    Event::Note(n, _) => std::slice::from_ref(n),
+                    Event::Chord(notes, _) => notes.as_slice(),
+                    _ => &[],
+                })
+                .filter(|n| !(0..=127).contains(&shifted_midi(n, shift)))
+                .count();
+            let notes = per_rep * segment.times as usize;
+            if notes > 0 {
+                clamped.push(Clamped {
+                    track: track_idx,
+                    track_name: track.name.clone(),
+                    notes_path: segment.notes_path.clone(),
+                    shift,
+                    notes,
+                });
+            }
+        }
+    }
+    clamped
+}
+
+/// One pattern event that makes a sound, worked out once and replayed on
+/// every repeat
+struct Step {
+    /// Beat from the start of the pattern
+    offset: Beat,
+    /// How long its notes sound, ties included
+    length: Beat,
+    /// Frequency (transposed), velocity and chance of each note
+    notes: Vec<(f64, f64, f64)>,
+    drums: Vec<Drum>,
+    /// (group, choice) when it belongs to a choice of an `alt{...}`, which
+    /// the plan's `alts` count in order
+    alt: Option<(usize, usize)>,
+}
+
+impl Step {
+    /// Append its commands for a repeat where it starts at `start`, what
+    /// `expand_clipped` would for the event. Notes with a chance roll it
+    /// from `rng`.
+    fn expand(
+        &self,
+        track: usize,
+        start: Beat,
+        end: Option<Beat>,
+        key_counter: &mut u32,
+        rng: &mut Rng,
+        out: &mut Vec<ScheduledEvent>,
+    ) {
+        let clip = |beat: Beat| end.map_or(beat, |end| beat.min(end));
+        let (start, stop) = (clip(start), clip(start + self.length));
+        for &drum in &self.drums {
+            out.push(ScheduledEvent {
+                beat: start,
+                command: LiveCommand::DrumHit {
+                    track,
+                    drum,
+                    velocity: 1.0,
+                },
+            });
+        }
+        for &(freq, velocity, chance) in &self.notes {
+            if !rolls(chance, rng) {
+                continue;
+            }
+            let key = next_voice_key(key_counter);
+            out.push(ScheduledEvent {
+                beat: start,
+                command: LiveCommand::NoteOn {
+                    track,
+                    key,
+                    freq,
+                    velocity,
+                },
+            });
+            out.push(ScheduledEvent {
+                beat: stop,
+                command: LiveCommand::NoteOff { track, key },
+            });
+        }
+    }
+}
+
+/// A command of a `Template`, waiting for its track and voice key
+#[derive(Clone, Copy)]
+enum Sound {
+    Drum(Drum),
+    /// The repeat's `voice`th note starts
+    On { voice: u32, freq: f64, velocity: f64 },
+    Off { voice: u32 },
+}
+
+/// Every command of one repeat, sorted as a schedule is and at beats from
+/// the repeat's start, for a plan whose repeats all play the same
+struct Template {
+    sounds: Vec<(Beat, Sound)>,
+    voices: u32,
+    /// Beat of the last command, past the plan's length if `beats:` cuts
+    /// the pattern short
+    span: Beat,
+}
+
+impl Template {
+    /// Append a repeat starting at `start`: what expanding the plan's steps
+    /// and sorting them would give, with the same voice keys
+    fn stamp(&self, track: usize, start: Beat, key_counter: &mut u32, out: &mut Vec<ScheduledEvent>) {
+        let base = *key_counter as usize;
+        let key = |voice: u32| VOICE_KEYS[(base + voice as usize) % VOICE_KEYS.len()];
+        out.extend(self.sounds.iter().map(|&(offset, sound)| ScheduledEvent {
+            beat: start + offset,
+            command: match sound {
+                Sound::Drum(drum) => LiveCommand::DrumHit {
+                    track,
+                    drum,
+                    velocity: 1.0,
+                },
+                Sound::On {
+                    voice,
+                    freq,
+                    velocity,
+                } => LiveCommand::NoteOn {
+                    track,
+                    key: key(voice),
+                    freq,
+                    velocity,
+                },
+                Sound::Off { voice } => LiveCommand::NoteOff {
+                    track,
+                    key: key(voice),
+                },
+            },
+        }));
+        *key_counter += self.voices;
+    }
+}
+
+/// A pattern at one transposition, ready to repeat: only the events that
+/// sound, with their beats, lengths and frequencies worked out. Every
+/// choice of an `alt{...}` is planned; a repeat plays the steps of the
+/// ones picked for it.
+struct Plan {
+    steps: Vec<Step>,
+    length: Beat,
+    /// The `alt{...}` groups, in order
+    alts: Vec<Alternatives>,
+    /// Set when nothing is left to chance, so every repeat is the same
+    template: Option<Template>,
+}
+
+impl Plan {
+    fn new(pattern: &Pattern, shift: i32) -> Plan {
+        let mut plan = Plan {
+            steps: Vec::new(),
+            length: pattern.length_beats(),
+            alts: Vec::new(),
+            template: None,
+        };
+        plan.add_steps(&pattern.events, Beat::ZERO, shift, None);
+        plan.template = plan.template();
+        plan
+    }
+
+    /// The steps expanded once and sorted, unless an `alt{...}` or a chance
+    /// can make one repeat differ from the next
+    fn template(&self) -> Option<Template> {
+        let certain = |step: &Step| step.notes.iter().all(|&(_, _, chance)| chance >= 1.0);
+        if !self.alts.is_empty() || !self.steps.iter().all(certain) {
+            return None;
+        }
+        let mut sounds = Vec::with_capacity(self.commands());
+        let mut voices = 0;
+        for step in &self.steps {
+            sounds.extend(step.drums.iter().map(|&drum| (step.offset, Sound::Drum(drum))));
+            for &(freq, velocity, _) in &step.notes {
+                let voice = voices;
+                sounds.push((step.offset, Sound::On { voice, freq, velocity }));
+                sounds.push((step.offset + step.length, Sound::Off { voice }));
+                voices += 1;
+            }
+        }
+        // NoteOffs before anything else on their beat, as `command_rank` has it
+        sounds.sort_by_key(|&(offset, sound)| (offset, !matches!(sound, Sound::Off { .. })));
+        let span = sounds.last().map_or(Beat::ZERO, |&(offset, _)| offset);
+        Some(Template {
+            sounds,
+            voices,
+            span,
+        })
+    }
+
+    /// Plan `events` starting `start` beats in, as part of `alt`'s choice
+    /// if given. Ties only reach within the same run of events.
+    fn add_steps(
+        &mut self,
+        events: &[Event],
+        start: Beat,
+        shift: i32,
+        alt: Option<(usize, usize)>,
+    ) {
+        let mut offset = start;
+        for (idx, ev) in events.iter().enumerate() {
+            if let Event::Alt(group) = ev {
+                let id = self.alts.len();
+                self.alts.push(group.clone());
+                for (choice, events) in group.choices.iter().enumerate() {
+                    self.add_steps(events, offset, shift, Some((id, choice)));
+                }
+                offset += event_duration(ev);
+                continue;
+            }
+            let drums = match ev {
+                Event::Drums(drums, _) => drums.clone(),
+                _ => Vec::new(),
+            };
+            let notes: Vec<(f64, f64, f64)> = ev
+                .notes()
+                .iter()
+                .map(|n| (transposed_freq(n, shift), n.velocity, n.chance))
+                .collect();
+            if !notes.is_empty() || !drums.is_empty() {
+                self.steps.push(Step {
+                    offset,
+                    length: tied_length(events, idx),
+                    notes,
+                    drums,
+                    alt,
+                });
+            }
+            offset += event_duration(ev);
+        }
+    }
+
+    /// Commands one repeat produces (before any clipping at the end)
+    fn commands(&self) -> usize {
+        self.steps.iter().map(|step| step.drums.len() + 2 * step.notes.len()).sum()
+    }
+}
+
+/// Walks one track's segments, expanding one pattern event at a time.
+/// `alt{...}` groups are picked at the start of each repeat, then chances
+/// are rolled note by note, all from `rng`, as `build_schedule` does.
+struct TrackCursor {
+    track_idx: usize,
+    /// (plan, times, start beat of its section) for each segment
+    segments: Vec<(Rc<Plan>, u32, Option<Beat>)>,
+    align: Align,
+    /// One pass through the segments (see `sequence_length`)
+    length: Beat,
+    /// Nothing starts at or after this beat (see `aligned_end`)
+    end: Option<Beat>,
+    segment: usize,
+    rep: u32,
+    event: usize,
+    track_beat: Beat,
+    key_counter: u32,
+    rng: Rng,
+    /// Loop pass of the stream, and times through the segments within it,
+    /// which turn-by-turn alternatives carry on counting from
+    pass: u64,
+    cycle: u64,
+    /// The choice of each group in the current repeat's plan
+    picks: Option<Vec<usize>>,
+}
+
+impl TrackCursor {
+    /// Expand the next pattern event into `out`, returning its start beat
+    /// (every command it produces is at or after that beat). A repeat of a
+    /// plan with a template goes in whole, unless the song's end cuts it.
+    fn next_group(&mut self, out: &mut Vec<ScheduledEvent>) -> Option<Beat> {
+        loop {
+            let Some((plan, times, start)) = self.segments.get(self.segment) else {
+                if self.segments.is_empty()
+                    || !loops_again(self.align, self.length, self.track_beat, self.end) {
+                    return None;
+                }
+                self.segment = 0;
+                self.cycle += 1;
+                continue;
+            };
+            let (times, start) = (*times, *start);
+            if self.rep >= times {
+                self.segment += 1;
+                self.rep = 0;
+                continue;
+            }
+            if let Some(start) = start
+                && self.rep == 0
+                && self.event == 0
+            {
+                self.track_beat = self.track_beat.max(start);
+            }
+            let rep_end = self.track_beat + plan.length;
+            if let Some(template) = &plan.template
+                && self.event == 0
+                && self.end.is_none_or(|end| rep_end.max(self.track_beat + template.span) < end)
+            {
+                let start = self.track_beat;
+                template.stamp(self.track_idx, start, &mut self.key_counter, out);
+                self.track_beat = rep_end;
+                self.rep += 1;
+                return Some(start);
+            }
+            let picks = match &mut self.picks {
+                Some(picks) => picks,
+                None => {
+                    let round = (self.pass + self.cycle) * times as u64 + self.rep as u64;
+                    let rng = &mut self.rng;
+                    self.picks.insert(plan.alts.iter().map(|alt| alt.choose(round, rng)).collect())
+                }
+            };
+            let Some(step) = plan.steps.get(self.event) else {
+                if self.end.is_some_and(|end| rep_end >= end) {
+                    return None;
+                }
+                self.track_beat = rep_end;
+                self.rep += 1;
+                self.event = 0;
+                self.picks = None;
+                continue;
+            };
+            if step.alt.is_some_and(|(group, choice)| picks[group] != choice) {
+                self.event += 1;
+                continue;
+            }
+            let start = self.track_beat + step.offset;
+            if self.end.is_some_and(|end| start >= end) {
+                return None;
+            }
+            let (counter, rng) = (&mut self.key_counter, &mut self.rng);
+            step.expand(self.track_idx, start, self.end, counter, rng, out);
+            self.event += 1;
+            return Some(start);
+        }
+    }
+}
+
+/// One track's cursor plus the events it has produced but not yet emitted,
+/// kept in schedule order.
+struct TrackStream {
+    cursor: TrackCursor,
+    /// Events of the next pattern event (or repeat), all at or after
+    /// `next_start`
+    group: Vec<ScheduledEvent>,
+    next_start: Option<Beat>,
+    pending: VecDeque<ScheduledEvent>,
+}
+
+impl TrackStream {
+    /// Pull pattern events until the earliest pending event can no longer be
+    /// preceded by anything the cursor has yet to produce.
+    fn fill(&mut self) {
+        while let Some(start) = self.next_start {
+            if let Some(first) = self.pending.front()
+                && first.beat < start
+            {
+                break;
+            }
+            // A stamped repeat is sorted and after everything pending
+            let after = self.pending.back().zip(self.group.first());
+            let in_order = after.is_none_or(|(last, first)| !comes_before(first, last))
+                && self.group.is_sorted_by(|a, b| !comes_before(b, a));
+            if in_order {
+                self.pending.extend(self.group.drain(..));
+            } else {
+                for ev in self.group.drain(..) {
+                    insert_sorted(&mut self.pending, ev);
+                }
+            }
+            self.next_start = self.cursor.next_group(&mut self.group);
+        }
+    }
+}
+
+/// Lazily produces the same events as `build_schedule`, in the same order.
+/// Memory use is bounded by the notes sounding at once rather than the
+/// length of the song.
+pub struct ScheduleIter {
+    tracks: Vec<TrackStream>,
+    /// (beat, rank, track) of each track's earliest pending event. Only the
+    /// track an event was taken from can change, so only it is refilled.
+    heads: BinaryHeap<Reverse<(Beat, u8, usize)>>,
+}
+
+impl ScheduleIter {
+    /// Each pattern is planned once per transposition, however many
+    /// segments and repeats play it
+    pub fn new(song: &Song, patterns: &HashMap<PathBuf, Pattern>) -> Result<Self, ClidawError> {
+        Self::pass(song, patterns, 0)
+    }
+
+    /// Pass `pass` of a looping song: chances and random alternatives roll
+    /// from the song's seed plus `pass`, so each pass plays differently but
+    /// the same seed plays them all the same way again. Alternatives taken
+    /// in turn carry on from where the pass before left off.
+    pub fn pass(
+        song: &Song,
+        patterns: &HashMap<PathBuf, Pattern>,
+        pass: u64,
+    ) -> Result<Self, ClidawError> {
+        let lengths = track_lengths(song, patterns)?;
+        let end = aligned_end(song.align, &lengths);
+        let starts = slot_starts(song, patterns)?;
+        let mut plans: HashMap<(&PathBuf, i32), Rc<Plan>> = HashMap::new();
+        let mut tracks = Vec::with_capacity(song.tracks.len());
+        let mut seeds = Rng::new(song.seed.wrapping_add(pass));
+        for (track_idx, track) in song.tracks.iter().enumerate() {
+            let mut segments = Vec::with_capacity(track.sequence.len());
+            for segment in &track.sequence {
+                let shift = song.transpose as i32 + segment.transpose as i32;
+                let key = (&segment.notes_path, shift);
+                let plan = match plans.get(&key) {
+                    Some(plan) => Rc::clone(plan),
+                    None => {
+                        let pattern = find_pattern(patterns, segment)?;
+                        let plan = Rc::new(Plan::new(&pattern, shift));
+                        // A missing pattern's silence lasts each segment's own length
+                        if matches!(pattern, Cow::Borrowed(_)) {
+                            plans.insert(key, Rc::clone(&plan));
                         }
-                        Event::Rest(_) | Event::BarLine => {}
+                        plan
                     }
-                    event_beat += event_duration(ev);
+                };
+                let start = segment.slot.map(|slot| starts[slot]);
+                segments.push((plan, segment.times, start));
+            }
+            let mut cursor = TrackCursor {
+                track_idx,
+                segments,
+                align: song.align,
+                length: sequence_length(track, patterns)?,
+                end,
+                segment: 0,
+                rep: 0,
+                event: 0,
+                track_beat: Beat::from_f64(track.offset),
+                key_counter: 0,
+                rng: Rng::new(seeds.next_u64()),
+                pass,
+                cycle: 0,
+                picks: None,
+            };
+            let mut group = Vec::new();
+            let next_start = cursor.next_group(&mut group);
+            tracks.push(TrackStream {
+                cursor,
+                group,
+                next_start,
+                pending: VecDeque::new(),
+            });
+        }
+        let mut iter = Self {
+            tracks,
+            heads: BinaryHeap::new(),
+        };
+        for idx in 0..iter.tracks.len() {
+            iter.refill(idx);
+        }
+        Ok(iter)
+    }
+
+    /// Fill track `idx`'s pending events and queue its earliest
+    fn refill(&mut self, idx: usize) {
+        let track = &mut self.tracks[idx];
+        track.fill();
+        if let Some(front) = track.pending.front() {
+            let rank = command_rank(&front.command);
+            self.heads.push(Reverse((front.beat, rank, idx)));
+        }
+    }
+    /// Commands in one pass through every track: a capacity hint, as
+    /// looping tracks play more and the song's end can cut some off
+    fn pass_len(&self) -> usize {
+        let segments = self.tracks.iter().flat_map(|t| &t.cursor.segments);
+        segments.map(|(plan, times, _)| plan.commands() * *times as usize).sum()
+    }
+}
+
+impl Iterator for ScheduleIter {
+    type Item = ScheduledEvent;
+
+    fn next(&mut self) -> Option<ScheduledEvent> {
+        // Earliest track wins; ties go to the lower track index
+        let Reverse((_, _, idx)) = self.heads.pop()?;
+        let event = self.tracks[idx].pending.pop_front();
+        self.refill(idx);
+        event
+    }
+}
+
+/// Insert into an already sorted queue, after any events that share its
+/// beat and rank (the same tie order as `sort_schedule`).
+fn insert_sorted(queue: &mut VecDeque<ScheduledEvent>, ev: ScheduledEvent) {
+    let pos = queue.partition_point(|p| !comes_before(&ev, p));
+    queue.insert(pos, ev);
+}
+
+/// Merge two sorted streams; on ties events from `a` come first.
+pub struct Merge<A: Iterator, B: Iterator> {
+    a: Peekable<A>,
+    b: Peekable<B>,
+}
+
+pub fn merge<A, B>(a: A, b: B) -> Merge<A::IntoIter, B::IntoIter>
+where
+    A: IntoIterator<Item = ScheduledEvent>,
+    B: IntoIterator<Item = ScheduledEvent>,
+{
+    Merge {
+        a: a.into_iter().peekable(),
+        b: b.into_iter().peekable(),
+    }
+}
+
+impl<A, B> Iterator for Merge<A, B>
+where
+    A: Iterator<Item = ScheduledEvent>,
+    B: Iterator<Item = ScheduledEvent>,
+{
+    type Item = ScheduledEvent;
+
+    fn next(&mut self) -> Option<ScheduledEvent> {
+        let take_b = match (self.a.peek(), self.b.peek()) {
+            (Some(a), Some(b)) => comes_before(b, a),
+            (None, _) => true,
+            (_, None) => false,
+        };
+        if take_b { self.b.next() } else { self.a.next() }
+    }
+}
+
+/// Order of commands that share a beat: gain and other setting changes
+/// first, then NoteOffs, then everything that starts a sound. A repeated
+/// note's NoteOff must not land after the NoteOn of the next repetition.
+fn command_rank(command: &LiveCommand) -> u8 {
+    match command {
+        LiveCommand::SetMasterGain { .. } | LiveCommand::SetParam { .. } => 0,
+        LiveCommand::NoteOff { .. } => 1,
+        _ => 2,
+    }
+}
+
+/// Whether `a` sorts strictly before `b` (by beat, then `command_rank`)
+fn comes_before(a: &ScheduledEvent, b: &ScheduledEvent) -> bool {
+    a.beat < b.beat || (a.beat == b.beat && command_rank(&a.command) < command_rank(&b.command))
+}
+
+/// Sort events by beat, then by `command_rank`; otherwise simultaneous
+/// events keep their existing order.
+fn sort_schedule(events: &mut [ScheduledEvent]) {
+    events.sort_by(|a, b| {
+        a.beat
+            .cmp(&b.beat)
+            .then_with(|| command_rank(&a.command).cmp(&command_rank(&b.command)))
+    });
+}
+
+/// Master gain ramps for a fade-in from beat 0 and a fade-out that reaches
+/// silence at `end` (the last scheduled beat). Lengths are in seconds.
+/// The result is sorted, ready to `merge` into the schedule.
+pub fn fade_events(end: Beat, fade_in: f64, fade_out: f64, tempo: f64) -> Vec<ScheduledEvent> {
+    let beats_per_sec = tempo / 60.0;
+    let mut events = Vec::new();
+    if fade_in > 0.0 {
+        events.push(ScheduledEvent {
+            beat: Beat::ZERO,
+            command: LiveCommand::SetMasterGain {
+                gain: 0.0,
+                ramp_secs: 0.0,
+            },
+        });
+        events.push(ScheduledEvent {
+            beat: Beat::ZERO,
+            command: LiveCommand::SetMasterGain {
+                gain: 1.0,
+                ramp_secs: fade_in,
+            },
+        });
+    }
+    if fade_out > 0.0 {
+        let start = (end - Beat::from_f64(fade_out * beats_per_sec)).max(Beat::ZERO);
+        events.push(ScheduledEvent {
+            beat: start,
+            command: LiveCommand::SetMasterGain {
+                gain: 0.0,
+                ramp_secs: (end - start).as_f64() / beats_per_sec,
+            },
+        });
+    }
+    events
+}
+
+/// Steps per beat an automation lane is sent in
+const AUTOMATION_STEPS_PER_BEAT: i64 = 32;
+
+/// The song's `automate:` lanes as sorted SetParam commands: every
+/// automated setting starts at its written value on beat 0, steps along
+/// each lane `AUTOMATION_STEPS_PER_BEAT` times a beat and stays where the
+/// lane ends. Lanes stop at the end of the song, `length` beats in.
+pub fn automation_events(song: &Song, meter: &MeterMap, length: Beat) -> Vec<ScheduledEvent> {
+    let step = Beat::ratio(1, AUTOMATION_STEPS_PER_BEAT);
+    let mut events = Vec::new();
+    let mut started: Vec<(usize, Param)> = Vec::new();
+    let set = |track, param, value| LiveCommand::SetParam {
+        track,
+        param,
+        value,
+    };
+    for lane in &song.automation {
+        if !started.contains(&(lane.track, lane.param)) {
+            started.push((lane.track, lane.param));
+            if let Some(value) = song.tracks[lane.track].param_value(lane.param) {
+                events.push(ScheduledEvent {
+                    beat: Beat::ZERO,
+                    command: set(lane.track, lane.param, value),
+                });
+            }
+        }
+        let start = Beat::from_f64(meter.beat_of_bar(lane.start_bar - 1));
+        let end = Beat::from_f64(meter.beat_of_bar(lane.end_bar - 1));
+        let mut beat = start;
+        while beat <= end.min(length) {
+            let at = (beat - start).as_f64() / (end - start).as_f64();
+            events.push(ScheduledEvent {
+                beat,
+                command: set(lane.track, lane.param, lane.value_at(at)),
+            });
+            if beat == end {
+                break;
+            }
+            beat = (beat + step).min(end);
+        }
+    }
+    sort_schedule(&mut events);
+    events
+}
+
+/// Metronome settings for song playback
+#[derive(Debug, Clone)]
+pub struct Metronome {
+    /// Bars of clicks before the song starts
+    pub count_in_bars: u32,
+    /// Keep clicking for the whole song
+    pub throughout: bool,
+    /// Click loudness 0.0..=1.0
+    pub volume: f64,
+}
+
+/// Metronome clicks on every beat before `end` (None: until stopped),
+/// accented on each downbeat.
+pub struct Clicks {
+    beat: u32,
+    end: Option<Beat>,
+    /// Bars from the first click on
+    meter: MeterMap,
+    /// When looping: the beat the first pass starts on and the pass
+    /// length, so every pass is accented like the first
+    looping: Option<(Beat, Beat)>,
+    volume: f64,
+}
+
+impl Iterator for Clicks {
+    type Item = ScheduledEvent;
+
+    fn next(&mut self) -> Option<ScheduledEvent> {
+        let beat = Beat::whole(self.beat as i64);
+        if self.end.is_some_and(|end| beat >= end) {
+            return None;
+        }
+        self.beat += 1;
+        let at = match self.looping {
+            Some((first, period)) if beat >= first => first + (beat - first) % period,
+            _ => beat,
+        };
+        let downbeat = self.meter.beat_of_bar(self.meter.bar_at_beat(at.as_f64()));
+        Some(ScheduledEvent {
+            beat,
+            command: LiveCommand::Click {
+                accent: Beat::from_f64(downbeat) == at,
+                volume: self.volume,
+            },
+        })
+    }
+}
+
+/// Beats a count-in of `bars` takes: bars of the opening signature
+pub fn count_in_beats(bars: u32, meter: &MeterMap) -> Beat {
+    Beat::whole(bars as i64 * meter.signature_at(0.0).0.max(1) as i64)
+}
+
+/// Add metronome clicks to a sorted schedule whose last event is at `end`
+/// (None: it never ends) and whose bars fall as `meter` says. A count-in delays the whole
+/// schedule by that many bars of the opening signature. A looping schedule
+/// repeats every `period` beats, and so do its accents.
+pub fn with_clicks<I>(
+    events: I,
+    metronome: &Metronome,
+    meter: &MeterMap,
+    end: Option<Beat>,
+    period: Option<Beat>,
+) -> Merge<impl Iterator<Item = ScheduledEvent> + use<I>, Clicks>
+where
+    I: IntoIterator<Item = ScheduledEvent>,
+{
+    let offset = count_in_beats(metronome.count_in_bars, meter);
+    let shifted = events.into_iter().map(move |mut ev| {
+        ev.beat += offset;
+        ev
+    });
+    let clicks = Clicks {
+        beat: 0,
+        end: if metronome.throughout { end.map(|end| end + offset) } else { Some(offset) },
+        meter: meter.starting_at(-offset.as_f64()),
+        looping: period.map(|period| (offset, period)),
+        volume: metronome.volume,
+    };
+    merge(shifted, clicks)
+}
+
+/// Random timing and velocity variation, applied after the schedule is built.
+#[derive(Debug, Clone)]
+pub struct Humanize {
+    /// Maximum timing offset in milliseconds (either direction)
+    pub timing_ms: f64,
+    /// Maximum velocity offset (either direction); results are clamped to 0..=1
+    pub velocity: f64,
+    pub seed: u64,
+}
+
+/// Jitters note timing and velocity of a sorted schedule. Each NoteOff moves
+/// together with its NoteOn so note lengths are preserved, and no note moves
+/// before beat 0. Events are held back only as long as a later event could
+/// still be moved ahead of them.
+pub struct Humanized<I> {
+    events: I,
+    rng: Rng,
+    /// Maximum timing offset in beats
+    max_shift: Beat,
+    velocity: f64,
+    /// Shift applied to the currently sounding NoteOn of each (track, key)
+    shifts: HashMap<(usize, char), Beat>,
+    buffer: VecDeque<ScheduledEvent>,
+    /// Unshifted beat of the last event read
+    input_beat: Beat,
+    done: bool,
+}
+
+impl<I: Iterator<Item = ScheduledEvent>> Humanized<I> {
+    pub fn new(events: I, humanize: &Humanize, tempo: f64) -> Self {
+        Self {
+            events,
+            rng: Rng::new(humanize.seed),
+            max_shift: Beat::from_f64(humanize.timing_ms * tempo / 60_000.0),
+            velocity: humanize.velocity,
+            shifts: HashMap::new(),
+            buffer: VecDeque::new(),
+            input_beat: Beat::ZERO,
+            done: false,
+        }
+    }
+
+    fn jitter(&mut self, ev: &mut ScheduledEvent) {
+        match &mut ev.command {
+            LiveCommand::NoteOn {
+                track,
+                key,
+                velocity,
+                ..
+            } => {
+                let shift = self.max_shift.scale(self.rng.next_signed()).max(-ev.beat);
+                let vel_offset = self.rng.next_signed() * self.velocity;
+                ev.beat += shift;
+                *velocity = (*velocity + vel_offset).clamp(0.0, 1.0);
+                self.shifts.insert((*track, *key), shift);
+            }
+            LiveCommand::DrumHit { velocity, .. } => {
+                let shift = self.max_shift.scale(self.rng.next_signed()).max(-ev.beat);
+                let vel_offset = self.rng.next_signed() * self.velocity;
+                ev.beat += shift;
+                *velocity = (*velocity + vel_offset).clamp(0.0, 1.0);
+            }
+            LiveCommand::NoteOff { track, key } => {
+                if let Some(shift) = self.shifts.remove(&(*track, *key)) {
+                    ev.beat += shift;
                 }
+            }
+            _ => {}
+        }
+    }
+}
 
-                track_beat += pattern_len;
+impl<I: Iterator<Item = ScheduledEvent>> Iterator for Humanized<I> {
+    type Item = ScheduledEvent;
+
+    fn next(&mut self) -> Option<ScheduledEvent> {
+        loop {
+            // Nothing still to be read can land before this
+            let horizon = self.input_beat - self.max_shift;
+            if let Some(first) = self.buffer.front()
+                && (self.done || first.beat < horizon)
+            {
+                return self.buffer.pop_front();
+            }
+            if self.done {
+                return None;
+            }
+            match self.events.next() {
+                Some(mut ev) => {
+                    self.input_beat = ev.beat;
+                    self.jitter(&mut ev);
+                    insert_sorted(&mut self.buffer, ev);
+                }
+                None => self.done = true,
             }
         }
     }
+}
 
-    events.sort_by(|a, b| a.beat.partial_cmp(&b.beat).unwrap_or(std::cmp::Ordering::Equal));
-    Ok(events)
+/// Pull note starts toward a grid, applied after humanize
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantize {
+    /// Grid spacing in beats (0.25 = sixteenth notes in 4/4)
+    pub grid: f64,
+    /// How far each note moves toward its grid point: 0 leaves it, 1 snaps it
+    pub strength: f64,
+}
+
+/// Moves every NoteOn and DrumHit of a sorted schedule toward the nearest
+/// multiple of the grid (halfway rounds later) and each NoteOff by the same
+/// amount as its NoteOn, so note lengths are kept. Like `Humanized`, events
+/// are held back only while a later one could still move ahead of them.
+pub struct Quantized<I> {
+    events: I,
+    grid: Beat,
+    strength: f64,
+    /// Shift applied to the currently sounding NoteOn of each (track, key)
+    shifts: HashMap<(usize, char), Beat>,
+    buffer: VecDeque<ScheduledEvent>,
+    /// Unshifted beat of the last event read
+    input_beat: Beat,
+    done: bool,
+}
+
+/// Quantize a sorted schedule (see `Quantized`)
+pub fn quantize<I>(schedule: I, grid_beats: f64, strength: f64) -> Quantized<I::IntoIter>
+where
+    I: IntoIterator<Item = ScheduledEvent>,
+{
+    Quantized {
+        events: schedule.into_iter(),
+        grid: Beat::from_f64(grid_beats),
+        strength: strength.clamp(0.0, 1.0),
+        shifts: HashMap::new(),
+        buffer: VecDeque::new(),
+        input_beat: Beat::ZERO,
+        done: false,
+    }
+}
+
+impl<I> Quantized<I> {
+    /// Distance from `beat` toward its nearest grid point, scaled by strength
+    fn shift(&self, beat: Beat) -> Beat {
+        (beat.round_to(self.grid) - beat).scale(self.strength)
+    }
+
+    fn snap(&mut self, ev: &mut ScheduledEvent) {
+        match &ev.command {
+            LiveCommand::NoteOn { track, key, .. } => {
+                let shift = self.shift(ev.beat);
+                ev.beat += shift;
+                self.shifts.insert((*track, *key), shift);
+            }
+            LiveCommand::DrumHit { .. } => ev.beat += self.shift(ev.beat),
+            LiveCommand::NoteOff { track, key } => {
+                if let Some(shift) = self.shifts.remove(&(*track, *key)) {
+                    ev.beat += shift;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<I: Iterator<Item = ScheduledEvent>> Iterator for Quantized<I> {
+    type Item = ScheduledEvent;
+
+    fn next(&mut self) -> Option<ScheduledEvent> {
+        // A note moves at most half a grid step, and a NoteOff only as far
+        // as its NoteOn did
+        let max_shift = self.grid.max(Beat::ZERO).scale(0.5 * self.strength);
+        loop {
+            let horizon = self.input_beat - max_shift;
+            if let Some(first) = self.buffer.front()
+                && (self.done || first.beat < horizon)
+            {
+                return self.buffer.pop_front();
+            }
+            if self.done {
+                return None;
+            }
+            match self.events.next() {
+                Some(mut ev) => {
+                    self.input_beat = ev.beat;
+                    self.snap(&mut ev);
+                    insert_sorted(&mut self.buffer, ev);
+                }
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+/// A point in the song given on the command line: a 1-based bar number or
+/// a beat offset from the start
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Bar(u32),
+    Beat(f64),
+}
+
+impl Position {
+    /// Beats from the start of the song; `end` positions name the last bar
+    /// to play, so they resolve to the end of that bar
+    fn beat(self, meter: &MeterMap, end: bool) -> Beat {
+        Beat::from_f64(match self {
+            Position::Bar(bar) => meter.beat_of_bar(if end { bar } else { bar.saturating_sub(1) }),
+            Position::Beat(beat) => beat,
+        })
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Position::Bar(bar) => write!(f, "bar {}", bar),
+            Position::Beat(beat) => write!(f, "beat {}", beat),
+        }
+    }
+}
+
+/// Plays the part of a sorted schedule from `start` to `end` (in beats),
+/// shifted so `start` becomes beat 0.
+///
+/// Notes that began before `start` and are still held there are restarted
+/// at beat 0, and the master gain and automated settings jump to wherever
+/// the fades and automation had taken them.
+/// Notes still held at `end` are released there. Drum hits and clicks before
+/// `start` are skipped.
+pub struct Seek<I> {
+    events: I,
+    start: Beat,
+    /// `end`, shifted like the events
+    stop: Option<Beat>,
+    /// NoteOns of the notes sounding at the current point, in start order
+    held: Vec<LiveCommand>,
+    /// Events to emit before reading on
+    queue: VecDeque<ScheduledEvent>,
+    started: bool,
+    done: bool,
+}
+
+impl<I: Iterator<Item = ScheduledEvent>> Seek<I> {
+    pub fn new(events: I, start: Beat, end: Option<Beat>) -> Self {
+        Self {
+            events,
+            start,
+            stop: end.map(|end| end - start),
+            held: Vec::new(),
+            queue: VecDeque::new(),
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Read everything before the seek point, then queue the restarted
+    /// notes and the first event after it
+    fn skip_to_start(&mut self) {
+        self.started = true;
+        let mut gain = None;
+        // The latest SetParam of each automated setting
+        let mut params: Vec<LiveCommand> = Vec::new();
+        let mut first = None;
+        for ev in self.events.by_ref() {
+            // NoteOffs at the seek point end notes before it
+            let before = ev.beat < self.start
+                || (ev.beat == self.start && matches!(ev.command, LiveCommand::NoteOff { .. }));
+            if !before {
+                first = Some(ev);
+                break;
+            }
+            if let LiveCommand::SetMasterGain { gain: target, .. } = ev.command {
+                gain = Some(target);
+            }
+            if let LiveCommand::SetParam { track, param, .. } = ev.command {
+                params.retain(|p| {
+                    !matches!(p, LiveCommand::SetParam { track: t, param: q, .. }
+                        if *t == track && *q == param)
+                });
+                params.push(ev.command.clone());
+            }
+            update_held(&mut self.held, &ev.command);
+        }
+        if let Some(gain) = gain {
+            self.queue.push_back(ScheduledEvent {
+                beat: Beat::ZERO,
+                command: LiveCommand::SetMasterGain {
+                    gain,
+                    ramp_secs: 0.0,
+                },
+            });
+        }
+        for command in params.into_iter().chain(self.held.iter().cloned()) {
+            self.queue.push_back(ScheduledEvent {
+                beat: Beat::ZERO,
+                command,
+            });
+        }
+        if let Some(mut ev) = first {
+            ev.beat -= self.start;
+            self.queue.push_back(ev);
+        }
+    }
+
+    /// Drop everything from `stop` on, releasing the notes still held
+    fn finish(&mut self, stop: Beat) {
+        self.done = true;
+        self.queue.clear();
+        for command in std::mem::take(&mut self.held) {
+            if let LiveCommand::NoteOn { track, key, .. } = command {
+                self.queue.push_back(ScheduledEvent {
+                    beat: stop,
+                    command: LiveCommand::NoteOff { track, key },
+                });
+            }
+        }
+    }
+}
+
+/// Keep the NoteOns of sounding notes up to date with a NoteOn or NoteOff
+fn update_held(held: &mut Vec<LiveCommand>, command: &LiveCommand) {
+    let (LiveCommand::NoteOn { track, key, .. } | LiveCommand::NoteOff { track, key }) = command
+    else {
+        return;
+    };
+    held.retain(|h| !matches!(h, LiveCommand::NoteOn { track: t, key: k, .. } if t == track && k == key));
+    if matches!(command, LiveCommand::NoteOn { .. }) {
+        held.push(command.clone());
+    }
+}
+
+impl<I: Iterator<Item = ScheduledEvent>> Iterator for Seek<I> {
+    type Item = ScheduledEvent;
+
+    fn next(&mut self) -> Option<ScheduledEvent> {
+        if !self.started {
+            self.skip_to_start();
+        }
+        loop {
+            if let Some(ev) = self.queue.pop_front() {
+                if !self.done
+                    && let Some(stop) = self.stop
+                    && ev.beat >= stop
+                {
+                    self.finish(stop);
+                    continue;
+                }
+                update_held(&mut self.held, &ev.command);
+                return Some(ev);
+            }
+            if self.done {
+                return None;
+            }
+            let mut ev = self.events.next()?;
+            ev.beat -= self.start;
+            self.queue.push_back(ev);
+        }
+    }
+}
+
+/// Resolve `--start-*`/`--end-*` to beats, checking them against the
+/// song's last beat `end`. The stop beat is None when the song ends first.
+fn seek_range(
+    options: &ScheduleOptions,
+    meter: &MeterMap,
+    end: Beat,
+) -> Result<(Beat, Option<Beat>), String> {
+    let start = options.start.map_or(Beat::ZERO, |p| p.beat(meter, false));
+    let stop = options.end.map(|p| p.beat(meter, true));
+    if start < Beat::ZERO || stop.is_some_and(|stop| stop <= Beat::ZERO) {
+        return Err("start and end positions must be positive".to_string());
+    }
+    if let Some(position) = options.start
+        && start >= end
+    {
+        return Err(format!(
+            "{} is past the end of the song ({} beats)",
+            position, end
+        ));
+    }
+    if let Some(stop) = stop
+        && stop <= start
+    {
+        return Err("the end position must come after the start".to_string());
+    }
+    Ok((start, stop.filter(|&stop| stop < end)))
+}
+
+/// Length in beats of one pass through the whole song: where `align` stops
+/// the tracks, or else where the longest one ends
+pub fn song_length(
+    song: &Song,
+    patterns: &HashMap<PathBuf, Pattern>,
+) -> Result<Beat, ClidawError> {
+    let lengths = track_lengths(song, patterns)?;
+    let longest = lengths.iter().copied().max().unwrap_or_default();
+    Ok(aligned_end(song.align, &lengths).unwrap_or(longest))
+}
+
+/// Plays passes of a schedule back to back forever, `period` beats apart.
+///
+/// Pass `n` is offset by exactly `n * period` beats rather than by a running
+/// sum, so the loop never drifts against the clock however long it plays.
+/// Each pass must end by `period` (see `Seek`): its voice keys are reused by
+/// the next one, whose events only start once it has finished.
+pub struct Looped<F, I> {
+    make_pass: F,
+    events: I,
+    period: Beat,
+    pass: u64,
+    /// Whether the current pass has produced anything yet
+    started: bool,
+    /// Empty passes in a row that end the loop (see `varying`)
+    patience: u64,
+    /// Empty passes in a row so far
+    empty: u64,
+}
+
+/// Empty passes in a row a `varying` loop plays through before taking it
+/// to be silent for good
+const MAX_EMPTY_PASSES: u64 = 10_000;
+
+impl<F, I> Looped<F, I>
+where
+    F: FnMut(u64) -> I,
+    I: Iterator<Item = ScheduledEvent>,
+{
+    /// `make_pass(n)` builds the events of pass `n`, starting from beat 0
+    pub fn new(mut make_pass: F, period: Beat) -> Self {
+        let events = make_pass(0);
+        Self {
+            make_pass,
+            events,
+            period,
+            pass: 0,
+            started: false,
+            patience: 1,
+            empty: 0,
+        }
+    }
+
+    /// For passes that differ from one another: chance notes can leave one
+    /// silent and the next not, so it takes `MAX_EMPTY_PASSES` empty passes
+    /// in a row rather than one to end the loop
+    pub fn varying(mut self) -> Self {
+        self.patience = MAX_EMPTY_PASSES;
+        self
+    }
+}
+
+impl<F, I> Iterator for Looped<F, I>
+where
+    F: FnMut(u64) -> I,
+    I: Iterator<Item = ScheduledEvent>,
+{
+    type Item = ScheduledEvent;
+
+    fn next(&mut self) -> Option<ScheduledEvent> {
+        loop {
+            if let Some(mut ev) = self.events.next() {
+                self.started = true;
+                ev.beat += self.period * self.pass;
+                return Some(ev);
+            }
+            // Empty passes would spin forever
+            if self.started {
+                self.empty = 0;
+            } else {
+                self.empty += 1;
+                if self.empty >= self.patience {
+                    return None;
+                }
+            }
+            self.pass += 1;
+            self.started = false;
+            self.events = (self.make_pass)(self.pass);
+        }
+    }
+}
+
+/// Everything applied to a song's schedule after it is built, in order
+#[derive(Debug, Clone, Default)]
+pub struct ScheduleOptions {
+    pub humanize: Option<Humanize>,
+    pub quantize: Option<Quantize>,
+    /// Fade lengths in seconds (0 for none)
+    pub fade_in: f64,
+    pub fade_out: f64,
+    pub metronome: Option<Metronome>,
+    /// Play only from `start` to `end` (see `Seek`)
+    pub start: Option<Position>,
+    pub end: Option<Position>,
+    /// Repeat the song (or the `start`..`end` range) until stopped; fades
+    /// are left out
+    pub looped: bool,
+}
+
+/// A song's schedule as a stream, with the beat of its last event
+pub struct SongStream<'a> {
+    pub events: Box<dyn Iterator<Item = ScheduledEvent> + 'a>,
+    /// None when looping
+    pub end_beat: Option<Beat>,
+    /// When looping: the beat the first pass starts on (after any count-in)
+    /// and the length of a pass
+    pub looping: Option<(Beat, Beat)>,
+    /// Where bars fall, counted from the first beat of the stream (count-in
+    /// included), or from the start of a pass when looping
+    pub meter: MeterMap,
+    /// Segments with notes clamped to the MIDI range, for the caller to
+    /// warn about
+    pub clamped: Vec<Clamped>,
+}
+
+/// A boxed stream of scheduled events
+type Events<'a> = Box<dyn Iterator<Item = ScheduledEvent> + 'a>;
+
+/// A song's notes built once, so they can be streamed without its patterns
+/// (see `cache`): one pass of the schedule before any `ScheduleOptions`,
+/// where its bars fall and its length
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compiled {
+    pub events: Vec<ScheduledEvent>,
+    pub meter: MeterMap,
+    /// See `song_length`
+    pub length: Beat,
+    /// See `SongStream::clamped` (empty when read back from the cache)
+    pub clamped: Vec<Clamped>,
+}
+
+/// Build everything `stream_compiled` needs from the song's patterns. The
+/// events come out in the order `stream` would produce them.
+pub fn compile(
+    song: &Song,
+    patterns: &HashMap<PathBuf, Pattern>,
+) -> Result<Compiled, ClidawError> {
+    let meter = meter_map(song, patterns)?;
+    let length = song_length(song, patterns)?;
+    let automation = automation_events(song, &meter, length);
+    let notes = ScheduleIter::new(song, patterns)?;
+    let mut events = Vec::with_capacity(automation.len() + notes.pass_len());
+    events.extend(merge(automation, notes));
+    Ok(Compiled {
+        events,
+        meter,
+        length,
+        clamped: clamped_segments(song, patterns),
+    })
+}
+
+/// Stream the song's full schedule: notes and automation, humanize,
+/// quantize, fades, the seek range, then clicks.
+///
+/// Finding the last beat (for the fade-out, clicks and progress display)
+/// takes one quick pass over the patterns before the first event.
+pub fn stream<'a>(
+    song: &'a Song,
+    patterns: &'a HashMap<PathBuf, Pattern>,
+    tempo: f64,
+    options: &'a ScheduleOptions,
+) -> Result<SongStream<'a>, ClidawError> {
+    let meter = meter_map(song, patterns)?;
+    let length = song_length(song, patterns)?;
+    let automation = automation_events(song, &meter, length);
+    let notes = move |pass| -> Result<Events<'a>, ClidawError> {
+        let notes = ScheduleIter::pass(song, patterns, pass)?;
+        Ok(Box::new(merge(automation.clone(), notes)))
+    };
+    let varying = patterns.values().any(|p| is_generative(&p.events));
+    let mut stream = stream_notes(notes, meter, length, tempo, options, varying)?;
+    stream.clamped = clamped_segments(song, patterns);
+    Ok(stream)
+}
+
+/// `stream` for notes compiled earlier
+pub fn stream_compiled<'a>(
+    compiled: &'a Compiled,
+    tempo: f64,
+    options: &'a ScheduleOptions,
+) -> Result<SongStream<'a>, ClidawError> {
+    let notes = move |_pass| -> Result<Events<'a>, ClidawError> {
+        Ok(Box::new(compiled.events.iter().cloned()))
+    };
+    let mut stream =
+        stream_notes(notes, compiled.meter.clone(), compiled.length, tempo, options, false)?;
+    stream.clamped = compiled.clamped.clone();
+    Ok(stream)
+}
+
+/// Apply `options` to passes of the notes `base(pass)` builds, which last
+/// `length` beats and have their bars where `meter` says. `varying` passes
+/// can differ (see `Looped::varying`).
+fn stream_notes<'a>(
+    base: impl Fn(u64) -> Result<Events<'a>, ClidawError> + 'a,
+    meter: MeterMap,
+    length: Beat,
+    tempo: f64,
+    options: &'a ScheduleOptions,
+    varying: bool,
+) -> Result<SongStream<'a>, ClidawError> {
+    // Each loop pass humanizes with its own seed so the passes differ
+    let notes = move |pass: u64| -> Result<Events<'a>, ClidawError> {
+        let events = base(pass)?;
+        let events: Events<'a> = match &options.humanize {
+            Some(h) => {
+                let h = Humanize {
+                    seed: h.seed.wrapping_add(pass),
+                    ..h.clone()
+                };
+                Box::new(Humanized::new(events, &h, tempo))
+            }
+            None => Box::new(events),
+        };
+        Ok(match &options.quantize {
+            Some(q) => Box::new(quantize(events, q.grid, q.strength)),
+            None => events,
+        })
+    };
+    let events = notes(0)?;
+    let (events, end, looping, start): (Events<'a>, Option<Beat>, _, Beat) =
+        if options.looped {
+            let (start, stop) =
+                seek_range(options, &meter, length).map_err(ClidawError::Schedule)?;
+            let period = stop.unwrap_or(length) - start;
+            if period <= Beat::ZERO {
+                return Err(ClidawError::Schedule("nothing to loop: the song is empty".to_string()));
+            }
+            // Notes still held at the end of a pass are released there.
+            // Later passes can't fail to build once the first has.
+            let mut first = Some(events);
+            let make_pass = move |pass| {
+                let events = first.take().unwrap_or_else(|| {
+                    notes(pass).unwrap_or_else(|_| Box::new(std::iter::empty()))
+                });
+                Seek::new(events, start, Some(start + period))
+            };
+            let looped = Looped::new(make_pass, period);
+            let looped = if varying { looped.varying() } else { looped };
+            (Box::new(looped), None, Some(period), start)
+        } else {
+            let end = notes(0)?.map(|ev| ev.beat).fold(Beat::ZERO, Beat::max);
+            let events = merge(
+                events,
+                fade_events(end, options.fade_in, options.fade_out, tempo),
+            );
+            if options.start.is_some() || options.end.is_some() {
+                let (start, stop) =
+                    seek_range(options, &meter, end).map_err(ClidawError::Schedule)?;
+                let length = stop.unwrap_or(end) - start;
+                (Box::new(Seek::new(events, start, stop)), Some(length), None, start)
+            } else {
+                (Box::new(events), Some(end), None, Beat::ZERO)
+            }
+        };
+    let meter = meter.starting_at(start.as_f64());
+    Ok(match &options.metronome {
+        Some(m) => {
+            let count_in = count_in_beats(m.count_in_bars, &meter);
+            SongStream {
+                events: Box::new(with_clicks(events, m, &meter, end, looping)),
+                end_beat: end.map(|end| end + count_in),
+                looping: looping.map(|period| (count_in, period)),
+                meter: if looping.is_some() {
+                    meter
+                } else {
+                    meter.starting_at(-count_in.as_f64())
+                },
+                clamped: Vec::new(),
+            }
+        }
+        None => SongStream {
+            events: Box::new(events),
+            end_beat: end,
+            looping: looping.map(|period| (Beat::ZERO, period)),
+            meter,
+            clamped: Vec::new(),
+        },
+    })
+}
+
+/// Where the song's bars fall. Tracks' `time_signature:` changes take
+/// effect at their segments; a pattern that changes signature partway
+/// plays in its own signatures each time through, after which the song's
+/// signature resumes. Changes from every track are merged.
+pub fn meter_map(
+    song: &Song,
+    patterns: &HashMap<PathBuf, Pattern>,
+) -> Result<MeterMap, ClidawError> {
+    let starts = slot_starts(song, patterns)?;
+    let mut changes: Vec<(Beat, (u8, u8))> = Vec::new();
+    for track in &song.tracks {
+        let mut signature = song.time_signature;
+        let mut beat = Beat::from_f64(track.offset);
+        let mut slot = None;
+        for (idx, segment) in track.sequence.iter().enumerate() {
+            // Parts of a section start together at the section's start
+            if segment.slot != slot {
+                slot = segment.slot;
+                beat = slot.map_or(beat, |slot| starts[slot]);
+            }
+            for change in track.meter_changes.iter().filter(|c| c.segment == idx) {
+                signature = change.signature;
+                changes.push((beat, signature));
+            }
+            let pattern = find_pattern(patterns, segment)?;
+            let length = pattern.length_beats();
+            if pattern.meter.changes().next().is_some() {
+                for pass in 0..segment.times {
+                    let pass_start = beat + length * pass;
+                    changes.push((pass_start, pattern.time_signature));
+                    let inside = pattern.meter.changes().map(|(b, sig)| (Beat::from_f64(b), sig));
+                    let inside = inside.filter(|&(b, _)| b < length);
+                    changes.extend(inside.map(|(b, sig)| (pass_start + b, sig)));
+                }
+                changes.push((beat + length * segment.times, signature));
+            }
+            beat += length * segment.times;
+        }
+        let trailing = track.meter_changes.iter().filter(|c| c.segment >= track.sequence.len());
+        changes.extend(trailing.map(|c| (beat, c.signature)));
+    }
+    // Stable, so changes at the same beat keep their order
+    changes.sort_by_key(|&(beat, _)| beat);
+    let mut map = MeterMap::new(song.time_signature);
+    for (beat, signature) in changes {
+        map.change(beat.as_f64(), signature);
+    }
+    Ok(map)
+}
+
+/// What a schedule plays, for `play --dry-run` and `clidaw info`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScheduleSummary {
+    /// NoteOns and drum hits on each track
+    pub events_per_track: Vec<usize>,
+    /// Each track's lowest and highest MIDI note (None: no pitched notes)
+    pub range_per_track: Vec<Option<(u8, u8)>>,
+    /// Most notes sounding at once (drum hits aren't counted: they have no
+    /// NoteOff), and the beat that peak is first reached
+    pub peak_voices: usize,
+    pub peak_beat: Beat,
+}
+
+/// Sweep a sorted schedule, counting each track's events and the notes
+/// held between their NoteOn and NoteOff
+pub fn summarize<I>(schedule: I, tracks: usize) -> ScheduleSummary
+where
+    I: IntoIterator<Item = ScheduledEvent>,
+{
+    let mut summary = ScheduleSummary {
+        events_per_track: vec![0; tracks],
+        range_per_track: vec![None; tracks],
+        ..ScheduleSummary::default()
+    };
+    let mut sounding = HashSet::new();
+    let mut count = |track: usize, midi: Option<u8>| {
+        if track >= summary.events_per_track.len() {
+            summary.events_per_track.resize(track + 1, 0);
+            summary.range_per_track.resize(track + 1, None);
+        }
+        summary.events_per_track[track] += 1;
+        if let Some(midi) = midi {
+            let range = &mut summary.range_per_track[track];
+            let (low, high) = range.unwrap_or((midi, midi));
+            *range = Some((low.min(midi), high.max(midi)));
+        }
+    };
+    let mut peak = (0, Beat::ZERO);
+    for event in schedule {
+        match event.command {
+            LiveCommand::NoteOn {
+                track, key, freq, ..
+            } => {
+                count(track, Some(freq_to_midi(freq).round().clamp(0.0, 127.0) as u8));
+                sounding.insert((track, key));
+                if sounding.len() > peak.0 {
+                    peak = (sounding.len(), event.beat);
+                }
+            }
+            LiveCommand::NoteOff { track, key } => {
+                sounding.remove(&(track, key));
+            }
+            LiveCommand::DrumHit { track, .. } => count(track, None),
+            LiveCommand::AllNotesOff { track: Some(track) } => {
+                sounding.retain(|&(t, _)| t != track);
+            }
+            LiveCommand::AllNotesOff { track: None }
+            | LiveCommand::HardStop
+            | LiveCommand::Panic => sounding.clear(),
+            _ => {}
+        }
+    }
+    (summary.peak_voices, summary.peak_beat) = peak;
+    summary
+}
+
+/// What `clidaw info` reports about a song, besides its metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongStats {
+    pub length: Beat,
+    /// Bars in `length`, a last one cut short included
+    pub bars: u32,
+    pub summary: ScheduleSummary,
+}
+
+/// Length, bar count and per-track counts and ranges of `song`
+pub fn song_stats(
+    song: &Song,
+    patterns: &HashMap<PathBuf, Pattern>,
+) -> Result<SongStats, ClidawError> {
+    let length = song_length(song, patterns)?;
+    let meter = meter_map(song, patterns)?;
+    let summary = summarize(ScheduleIter::new(song, patterns)?, song.tracks.len());
+    Ok(SongStats {
+        length,
+        bars: meter.bar_count(length.as_f64()),
+        summary,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::Reverb;
+    use crate::note::{Metadata, NoteName};
+    use crate::parser::parse_pattern;
+    use crate::song::{
+        Align, Automation, InstrumentSource, Missing, Segment, SegmentMeter, Song, SongTrack,
+    };
+
+    fn one_segment_song(song_transpose: i8, segment_transpose: i8) -> Song {
+        Song {
+            tempo: 120.0,
+            time_signature: (4, 4),
+            transpose: song_transpose,
+            fade_in: 0.0,
+            fade_out: 0.0,
+            align: Align::Pad,
+            tracks: vec![SongTrack {
+                name: "lead".to_string(),
+                instrument: InstrumentSource::File(PathBuf::from("lead.instr")),
+                sequence: vec![Segment {
+                    notes_path: PathBuf::from("a.notes"),
+                    times: 1,
+                    transpose: segment_transpose,
+                    slot: None,
+                    length: None,
+                }],
+                output_channel: None,
+                bus: None,
+                reverb_send: 1.0,
+                meter_changes: Vec::new(),
+                offset: 0.0,
+                effects: Vec::new(),
+            }],
+            buses: Vec::new(),
+            reverb: Reverb::default(),
+            master_volume: 1.0,
+            automation: Vec::new(),
+            sections: Vec::new(),
+            arrangement: None,
+            missing: Missing::Error,
+            prefer_flats: false,
+            metadata: Metadata::default(),
+            seed: 0,
+        }
+    }
+
+    fn midi_of(freq: f64) -> u8 {
+        freq_to_midi(freq).round() as u8
+    }
+
+    fn beats(values: &[f64]) -> Vec<Beat> {
+        values.iter().map(|&beat| Beat::from_f64(beat)).collect()
+    }
+
+    fn pattern(notes: &str) -> Pattern {
+        parse_pattern(notes).unwrap()
+    }
+
+    fn schedule_for(song: &Song, notes: &str) -> Vec<ScheduledEvent> {
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern(notes))]);
+        build_schedule(song, &patterns).unwrap()
+    }
+
+    fn note_on_freqs(song: &Song, notes: &str) -> Vec<f64> {
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern(notes))]);
+        build_schedule(song, &patterns)
+            .unwrap()
+            .iter()
+            .filter_map(|ev| match ev.command {
+                LiveCommand::NoteOn { freq, .. } => Some(freq),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn gain_events(events: &[ScheduledEvent]) -> Vec<(f64, f64, f64)> {
+        events
+            .iter()
+            .filter_map(|ev| match ev.command {
+                LiveCommand::SetMasterGain { gain, ramp_secs } => {
+                    Some((ev.beat.as_f64(), gain, ramp_secs))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fades_anchor_to_schedule_ends() {
+        // 120 BPM: 2 beats per second; last event at beat 4
+        let fades = fade_events(Beat::whole(4), 0.5, 1.0, 120.0);
+        let events: Vec<_> = merge(four_notes(), fades).collect();
+        assert_eq!(
+            gain_events(&events),
+            vec![(0.0, 0.0, 0.0), (0.0, 1.0, 0.5), (2.0, 0.0, 1.0)]
+        );
+        // Fade-in is set before the first note starts
+        assert!(matches!(events[0].command, LiveCommand::SetMasterGain { .. }));
+        assert!(matches!(events[1].command, LiveCommand::SetMasterGain { .. }));
+    }
+
+    #[test]
+    fn test_fade_out_longer_than_song_starts_at_zero() {
+        let fades = fade_events(Beat::whole(4), 0.0, 10.0, 120.0);
+        let events: Vec<_> = merge(four_notes(), fades).collect();
+        assert_eq!(gain_events(&events), vec![(0.0, 0.0, 2.0)]);
+    }
+
+    #[test]
+    fn test_each_voice_releases_only_its_own_key() {
+        // A note followed by an 11-note cluster: every voice gets its own key
+        let schedule = schedule_for(&one_segment_song(0, 0), "a [asdfghjwety] -");
+
+        let ons: Vec<(f64, char)> = schedule
+            .iter()
+            .filter_map(|e| match e.command {
+                LiveCommand::NoteOn { key, .. } => Some((e.beat.as_f64(), key)),
+                _ => None,
+            })
+            .collect();
+        let offs: Vec<(f64, char)> = schedule
+            .iter()
+            .filter_map(|e| match e.command {
+                LiveCommand::NoteOff { key, .. } => Some(((e.beat - Beat::ONE).as_f64(), key)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ons.len(), 12);
+        let keys: std::collections::HashSet<char> = ons.iter().map(|&(_, k)| k).collect();
+        assert_eq!(keys.len(), 12);
+        // Each key is released exactly once, a beat after it started
+        assert_eq!(offs, ons);
+        assert!(!schedule.iter().any(|e| matches!(e.command, LiveCommand::AllNotesOff { .. })));
+    }
+
+    #[test]
+    fn test_tied_notes_are_one_note_on_and_off() {
+        let schedule = schedule_for(&one_segment_song(0, 0), "a _ _ _ | _ _ - - [ad] _ s");
+        let commands: Vec<(f64, &str)> = schedule
+            .iter()
+            .map(|e| match e.command {
+                LiveCommand::NoteOn { .. } => (e.beat.as_f64(), "on"),
+                LiveCommand::NoteOff { .. } => (e.beat.as_f64(), "off"),
+                _ => (e.beat.as_f64(), "?"),
+            })
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                (0.0, "on"),
+                (6.0, "off"),
+                (8.0, "on"),
+                (8.0, "on"),
+                (10.0, "off"),
+                (10.0, "off"),
+                (10.0, "on"),
+                (11.0, "off"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_note_off_before_note_on_on_shared_beat() {
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].sequence[0].times = 3;
+        let events = schedule_for(&song, "a");
+        assert_eq!(
+            describe(&events),
+            vec![
+                "0.000000 on e000 1.0000",
+                "1.000000 off e000",
+                "1.000000 on e001 1.0000",
+                "2.000000 off e001",
+                "2.000000 on e002 1.0000",
+                "3.000000 off e002",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_moves_note_off_ahead_of_note_on() {
+        let mut events = vec![
+            ScheduledEvent {
+                beat: Beat::ONE,
+                command: LiveCommand::NoteOn {
+                    track: 0,
+                    key: 'a',
+                    freq: 440.0,
+                    velocity: 1.0,
+                },
+            },
+            ScheduledEvent {
+                beat: Beat::ONE,
+                command: LiveCommand::NoteOff { track: 0, key: 'a' },
+            },
+        ];
+        sort_schedule(&mut events);
+        assert_eq!(describe(&events), vec!["1.000000 off 61", "1.000000 on 61 1.0000"]);
+    }
+
+    #[test]
+    fn test_count_in_delays_song() {
+        let metronome = Metronome {
+            count_in_bars: 1,
+            throughout: false,
+            volume: 0.5,
+        };
+        let meter = MeterMap::new((3, 4));
+        let end = Some(Beat::whole(4));
+        let events: Vec<_> = with_clicks(four_notes(), &metronome, &meter, end, None).collect();
+        let lines = describe(&events);
+        assert_eq!(&lines[..4], [
+            "0.000000 Click { accent: true, volume: 0.5 }",
+            "1.000000 Click { accent: false, volume: 0.5 }",
+            "2.000000 Click { accent: false, volume: 0.5 }",
+            "3.000000 on e000 1.0000",
+        ]);
+        assert_eq!(lines.last().unwrap(), "7.000000 off e003");
+    }
+
+    #[test]
+    fn test_click_throughout() {
+        let metronome = Metronome {
+            count_in_bars: 0,
+            throughout: true,
+            volume: 1.0,
+        };
+        let meter = MeterMap::new((2, 4));
+        let end = Some(Beat::whole(4));
+        let events: Vec<_> = with_clicks(four_notes(), &metronome, &meter, end, None).collect();
+        let accents: Vec<bool> = events
+            .iter()
+            .filter_map(|ev| match ev.command {
+                LiveCommand::Click { accent, .. } => Some(accent),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(accents, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_stream_matches_eager_schedule() {
+        let patterns = HashMap::from([
+            (
+                PathBuf::from("lead.notes"),
+                pattern("a _ - [adg] _ | _ s |: d f :|x3 -- g"),
+            ),
+            (
+                PathBuf::from("pad.notes"),
+                pattern("beats: 8\nCmaj --- Am7"),
+            ),
+            (
+                PathBuf::from("beat.notes"),
+                pattern("kick: x - x -\nhat:  x x x x"),
+            ),
+        ]);
+        let mut song = one_segment_song(3, 0);
+        let track = |name: &str, segments: &[(&str, u32, i8)]| SongTrack {
+            name: name.to_string(),
+            instrument: InstrumentSource::File(PathBuf::from("x.instr")),
+            sequence: segments
+                .iter()
+                .map(|&(path, times, transpose)| Segment {
+                    notes_path: PathBuf::from(path),
+                    times,
+                    transpose,
+                    slot: None,
+                    length: None,
+                })
+                .collect(),
+            output_channel: None,
+            bus: None,
+            reverb_send: 1.0,
+            meter_changes: Vec::new(),
+            offset: 0.0,
+            effects: Vec::new(),
+        };
+        song.tracks = vec![
+            track("lead", &[("lead.notes", 3, 0), ("lead.notes", 0, 0), ("lead.notes", 2, -12)]),
+            track("pad", &[("pad.notes", 2, 0)]),
+            track("drums", &[("beat.notes", 5, 0)]),
+        ];
+
+        let tracks = |events: &[ScheduledEvent]| -> Vec<String> {
+            events.iter().map(|ev| format!("{:?}", ev.command)).collect()
+        };
+        for align in [Align::Pad, Align::Loop, Align::Truncate] {
+            song.align = align;
+            let eager = build_schedule(&song, &patterns).unwrap();
+            let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+            assert!(eager.len() > 50);
+            assert_eq!(describe(&streamed), describe(&eager));
+            assert_eq!(tracks(&streamed), tracks(&eager));
+        }
+    }
+
+    #[test]
+    fn test_looping_track_with_no_segments_ends() {
+        use crate::song::{Arrangement, Section};
+
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a s"))]);
+        let mut song = one_segment_song(0, 0);
+        song.align = Align::Loop;
+        song.tracks[0].sequence[0].slot = Some(0);
+        let mut idle = song.tracks[0].clone();
+        idle.sequence.clear();
+        song.tracks.push(idle);
+        song.sections = vec![Section {
+            name: "verse".to_string(),
+            line: 1,
+        }];
+        song.arrangement = Some(Arrangement {
+            sections: vec![0, 0],
+            align: SectionAlign::Pad,
+        });
+        let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+        assert_eq!(streamed.len(), 4);
+        assert!(streamed.iter().all(|ev| ev.command.track() == Some(0)));
+    }
+
+    #[test]
+    fn test_looping_offset_track_that_takes_no_time_ends() {
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a s"))]);
+        let mut song = one_segment_song(0, 0);
+        song.align = Align::Loop;
+        song.tracks[0].sequence[0].times = 2;
+        let mut idle = song.tracks[0].clone();
+        idle.offset = 3.0;
+        idle.sequence[0].times = 0;
+        song.tracks.push(idle);
+        let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+        assert_eq!(streamed, build_schedule(&song, &patterns).unwrap());
+        assert_eq!(streamed.len(), 8);
+        assert!(streamed.iter().all(|ev| ev.command.track() == Some(0)));
+    }
+
+    #[test]
+    fn test_sections_keep_tracks_aligned() {
+        use crate::song::{Arrangement, Section};
+
+        let patterns = HashMap::from([
+            (PathBuf::from("a.notes"), pattern("a s d f")),
+            (PathBuf::from("b.notes"), pattern("g h")),
+        ]);
+        // verse: bass a, lead b (shorter); chorus: lead b only; verse again
+        let mut song = one_segment_song(0, 0);
+        let segment = |path: &str, slot| Segment {
+            notes_path: PathBuf::from(path),
+            times: 1,
+            transpose: 0,
+            slot: Some(slot),
+            length: None,
+        };
+        song.tracks[0].sequence = vec![segment("a.notes", 0), segment("a.notes", 2)];
+        let mut lead = song.tracks[0].clone();
+        lead.name = "lead".to_string();
+        lead.sequence = vec![segment("b.notes", 0), segment("b.notes", 1), segment("b.notes", 2)];
+        song.tracks.push(lead);
+        song.sections = ["verse", "chorus"]
+            .map(|name| Section {
+                name: name.to_string(),
+                line: 1,
+            })
+            .to_vec();
+        song.arrangement = Some(Arrangement {
+            sections: vec![0, 1, 0],
+            align: SectionAlign::Pad,
+        });
+
+        assert_eq!(slot_starts(&song, &patterns).unwrap(), beats(&[0.0, 4.0, 6.0, 10.0]));
+        assert_eq!(track_lengths(&song, &patterns).unwrap(), beats(&[10.0, 10.0]));
+        let lead_ons = |events: &[ScheduledEvent]| -> Vec<f64> {
+            events
+                .iter()
+                .filter(|ev| matches!(ev.command, LiveCommand::NoteOn { track: 1, .. }))
+                .map(|ev| ev.beat.as_f64())
+                .collect()
+        };
+        // The lead waits out the rest of each verse before its next part
+        let eager = build_schedule(&song, &patterns).unwrap();
+        assert_eq!(lead_ons(&eager), vec![0.0, 1.0, 4.0, 5.0, 6.0, 7.0]);
+        let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+        assert_eq!(describe(&streamed), describe(&eager));
+
+        song.arrangement.as_mut().unwrap().align = SectionAlign::Error;
+        let err = slot_starts(&song, &patterns).unwrap_err().to_string();
+        let expected = "section 'verse' (position 1 of the arrangement): track lead is 2 beats";
+        assert!(err.starts_with(expected), "{}", err);
+    }
+
+    #[test]
+    fn test_offset_delays_a_track() {
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a s d f"))]);
+        let mut song = one_segment_song(0, 0);
+        let mut late = song.tracks[0].clone();
+        late.offset = 6.0;
+        song.tracks.push(late);
+        assert_eq!(track_lengths(&song, &patterns).unwrap(), beats(&[4.0, 10.0]));
+        assert_eq!(song_length(&song, &patterns).unwrap(), Beat::whole(10));
+
+        let note_ons = |events: &[ScheduledEvent]| -> Vec<f64> {
+            events
+                .iter()
+                .filter(|ev| matches!(ev.command, LiveCommand::NoteOn { track: 1, .. }))
+                .map(|ev| ev.beat.as_f64())
+                .collect()
+        };
+        let eager = build_schedule(&song, &patterns).unwrap();
+        assert_eq!(note_ons(&eager), vec![6.0, 7.0, 8.0, 9.0]);
+        let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+        assert_eq!(describe(&streamed), describe(&eager));
+
+        // Looping to the longest track, the other plays from beat 0 again
+        // while the offset track only waits once
+        song.align = Align::Loop;
+        song.tracks[1].offset = 2.0;
+        let looped = build_schedule(&song, &patterns).unwrap();
+        assert_eq!(note_ons(&looped), vec![2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn test_repeats_cut_short_by_beats_match_build_schedule() {
+        // Two beats long with four beats of notes: each repeat sounds past
+        // the next one's start, and past the end of a truncated song
+        let patterns = HashMap::from([
+            (PathBuf::from("a.notes"), pattern("beats: 2\na s d f")),
+            (PathBuf::from("b.notes"), pattern("g h j")),
+        ]);
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].sequence[0].times = 3;
+        let mut other = song.tracks[0].clone();
+        other.sequence[0].notes_path = PathBuf::from("b.notes");
+        other.sequence[0].times = 1;
+        song.tracks.push(other);
+        for align in [Align::Pad, Align::Loop, Align::Truncate] {
+            song.align = align;
+            let eager = build_schedule(&song, &patterns).unwrap();
+            let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+            assert_eq!(streamed, eager, "{:?}", align);
+            assert_eq!(compile(&song, &patterns).unwrap().events, eager, "{:?}", align);
+        }
+    }
+
+    #[test]
+    fn test_align_loop_and_truncate() {
+        let patterns = HashMap::from([
+            (PathBuf::from("a.notes"), pattern("a s d f")),
+            (PathBuf::from("b.notes"), pattern("g h j")),
+        ]);
+        let mut song = one_segment_song(0, 0);
+        let mut second = song.tracks[0].clone();
+        second.sequence[0].notes_path = PathBuf::from("b.notes");
+        second.sequence[0].times = 3;
+        song.tracks.push(second);
+        assert_eq!(track_lengths(&song, &patterns).unwrap(), beats(&[4.0, 9.0]));
+
+        let note_ons = |song: &Song, track: usize| -> Vec<f64> {
+            build_schedule(song, &patterns)
+                .unwrap()
+                .iter()
+                .filter(|ev| matches!(ev.command, LiveCommand::NoteOn { track: t, .. } if t == track))
+                .map(|ev| ev.beat.as_f64())
+                .collect()
+        };
+        let last_beat = |song: &Song| build_schedule(song, &patterns).unwrap().last().unwrap().beat;
+
+        assert_eq!(note_ons(&song, 0), vec![0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(last_beat(&song), Beat::whole(9));
+
+        // The short track repeats, cut off partway through its third pass
+        song.align = Align::Loop;
+        assert_eq!(note_ons(&song, 0), (0..9).map(f64::from).collect::<Vec<_>>());
+        assert_eq!(last_beat(&song), Beat::whole(9));
+
+        song.align = Align::Truncate;
+        assert_eq!(note_ons(&song, 1), vec![0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(last_beat(&song), Beat::whole(4));
+    }
+
+    #[test]
+    fn test_seek_restarts_held_notes_and_releases_at_end() {
+        let key = |i: u32| char::from_u32(0xE000 + i).unwrap();
+        let (start, end) = (Beat::ratio(3, 2), Beat::ratio(7, 2));
+        let events: Vec<_> = Seek::new(four_notes().into_iter(), start, Some(end))
+            .map(|ev| match ev.command {
+                LiveCommand::NoteOn { key: k, .. } => (ev.beat.as_f64(), true, k),
+                LiveCommand::NoteOff { key: k, .. } => (ev.beat.as_f64(), false, k),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                // Held across the seek point, so restarted at 0
+                (0.0, true, key(1)),
+                (0.5, false, key(1)),
+                (0.5, true, key(2)),
+                (1.5, false, key(2)),
+                (1.5, true, key(3)),
+                // Cut off at the end point
+                (2.0, false, key(3)),
+            ]
+        );
+
+        // A note ending exactly at the start isn't restarted
+        let first = Seek::new(four_notes().into_iter(), Beat::ONE, None).next().unwrap();
+        assert_eq!(first.beat, Beat::ZERO);
+        assert!(matches!(first.command, LiveCommand::NoteOn { key: k, .. } if k == key(1)));
+    }
+
+    #[test]
+    fn test_reused_voice_keys_leave_releasing_notes_alone() {
+        use crate::synth::{Mix, Patch, Synth};
+        // More notes than there are voice keys, so keys come round again
+        // while the notes that had them are still releasing
+        let song = one_segment_song(0, 0);
+        let events = schedule_for(&song, &"a:0.25 s:0.25 ".repeat(300));
+        let notes = events.iter().filter(|ev| matches!(ev.command, LiveCommand::NoteOn { .. }));
+        assert_eq!(notes.count(), 600);
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), 48_000.0, 2);
+        for ev in events {
+            synth.process_command(ev.command);
+        }
+        // Nothing has been rendered, so every note still has its own voice
+        assert_eq!(synth.active_voices(), 600);
+    }
+
+    #[test]
+    fn test_seek_jumps_to_faded_gain() {
+        let events: Vec<_> = Seek::new(
+            merge(four_notes(), fade_events(Beat::whole(4), 0.5, 0.0, 120.0)),
+            Beat::whole(2),
+            None,
+        )
+        .collect();
+        assert_eq!(gain_events(&events)[0], (0.0, 1.0, 0.0));
+    }
+
+    fn param_values(events: &[ScheduledEvent]) -> Vec<(f64, f64)> {
+        events
+            .iter()
+            .filter_map(|ev| match ev.command {
+                LiveCommand::SetParam { value, .. } => Some((ev.beat.as_f64(), value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_automation_steps_along_lanes() {
+        let mut song = one_segment_song(0, 0);
+        let lane = |param, from, to, start_bar, end_bar| Automation {
+            track: 0,
+            param,
+            from,
+            to,
+            start_bar,
+            end_bar,
+        };
+        // A fade over the first bar, and a pan running past the song's end
+        song.automation = vec![
+            lane(Param::Gain, 1.0, 0.0, 1, 2),
+            lane(Param::Pan, -1.0, 1.0, 2, 4),
+        ];
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a s d f g h j k"))]);
+        let compiled = compile(&song, &patterns).unwrap();
+        let setting = |param| -> Vec<ScheduledEvent> {
+            let events = compiled.events.iter().filter(|ev| {
+                matches!(ev.command, LiveCommand::SetParam { param: p, .. } if p == param)
+            });
+            events.cloned().collect()
+        };
+
+        let gain = param_values(&setting(Param::Gain));
+        // The resting value, then a step every 1/32 beat through beat 4
+        assert_eq!(gain.len(), 2 + 4 * 32);
+        assert_eq!(gain[0], (0.0, 1.0));
+        assert!(gain.contains(&(2.0, 0.5)));
+        assert_eq!(gain.last(), Some(&(4.0, 0.0)));
+
+        let pan = param_values(&setting(Param::Pan));
+        assert_eq!(pan[0], (0.0, 0.0));
+        assert_eq!(pan[1], (4.0, -1.0));
+        // Cut off where the song ends, halfway along the lane
+        assert_eq!(pan.last(), Some(&(8.0, 0.0)));
+
+        // A seek starts from where each setting had got to
+        let seeked: Vec<_> = Seek::new(compiled.events.into_iter(), Beat::whole(6), None)
+            .take_while(|ev| ev.beat == Beat::ZERO)
+            .collect();
+        let values = param_values(&seeked);
+        // The finished fade, the pan's last step before beat 6, then its own
+        assert_eq!(values, [(0.0, 0.0), (0.0, -0.5 - 1.0 / 128.0), (0.0, -0.5)]);
+    }
+
+    /// Two bars of 4/4, two of 7/8 and one of 4/4, the 7/8 set by
+    /// `time_signature:` lines between the segments
+    fn changing_meter_song() -> (Song, HashMap<PathBuf, Pattern>) {
+        let patterns = HashMap::from([
+            (PathBuf::from("a.notes"), pattern("a s d f")),
+            (PathBuf::from("b.notes"), pattern("a s d f g h j")),
+        ]);
+        let mut song = one_segment_song(0, 0);
+        let segment = |path: &str, times| Segment {
+            notes_path: PathBuf::from(path),
+            times,
+            transpose: 0,
+            slot: None,
+            length: None,
+        };
+        let track = &mut song.tracks[0];
+        track.sequence = vec![segment("a.notes", 2), segment("b.notes", 2), segment("a.notes", 1)];
+        track.meter_changes = vec![
+            SegmentMeter {
+                segment: 1,
+                signature: (7, 8),
+            },
+            SegmentMeter {
+                segment: 2,
+                signature: (4, 4),
+            },
+        ];
+        (song, patterns)
+    }
+
+    #[test]
+    fn test_meter_map_follows_song_changes() {
+        let (song, patterns) = changing_meter_song();
+        let meter = meter_map(&song, &patterns).unwrap();
+        let starts: Vec<f64> = (0..6).map(|bar| meter.beat_of_bar(bar)).collect();
+        assert_eq!(starts, [0.0, 4.0, 8.0, 15.0, 22.0, 26.0]);
+        assert_eq!(meter.bar_at_beat(14.5), 2);
+
+        // Bar numbers on the command line count the 7/8 bars as 7 beats
+        let options = ScheduleOptions {
+            start: Some(Position::Bar(4)),
+            end: Some(Position::Bar(4)),
+            ..ScheduleOptions::default()
+        };
+        let range = seek_range(&options, &meter, Beat::whole(26));
+        assert_eq!(range, Ok((Beat::whole(15), Some(Beat::whole(22)))));
+
+        // Clicks accent the first beat of every bar
+        let options = ScheduleOptions {
+            metronome: Some(Metronome {
+                count_in_bars: 1,
+                throughout: true,
+                volume: 1.0,
+            }),
+            ..ScheduleOptions::default()
+        };
+        let stream = stream(&song, &patterns, 120.0, &options).unwrap();
+        assert_eq!(stream.meter.beat_of_bar(3), 12.0);
+        let accents: Vec<f64> = stream
+            .events
+            .filter(|ev| matches!(ev.command, LiveCommand::Click { accent: true, .. }))
+            .map(|ev| ev.beat.as_f64())
+            .collect();
+        assert_eq!(accents, [0.0, 4.0, 8.0, 12.0, 19.0, 26.0]);
+    }
+
+    #[test]
+    fn test_song_stats_count_bars_in_the_song_meter() {
+        let (song, patterns) = changing_meter_song();
+        let stats = song_stats(&song, &patterns).unwrap();
+        assert_eq!((stats.length, stats.bars), (Beat::whole(26), 5));
+        assert_eq!(stats.summary.events_per_track, vec![26]);
+        assert_eq!(stats.summary.range_per_track, vec![Some((60, 71))]);
+    }
+
+    #[test]
+    fn test_pattern_meter_changes_repeat_with_the_pattern() {
+        // Each pass is a bar of 4/4 and one of 3/4; the song's 4/4 resumes after
+        let patterns = HashMap::from([
+            (PathBuf::from("a.notes"), pattern("a s d f\ntime_signature: 3/4\ng h j")),
+            (PathBuf::from("b.notes"), pattern("a s d f")),
+        ]);
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].sequence = ["a.notes", "a.notes", "b.notes"]
+            .map(|path| Segment {
+                notes_path: PathBuf::from(path),
+                times: 1,
+                transpose: 0,
+                slot: None,
+                length: None,
+            })
+            .to_vec();
+        let meter = meter_map(&song, &patterns).unwrap();
+        let starts: Vec<f64> = (0..6).map(|bar| meter.beat_of_bar(bar)).collect();
+        assert_eq!(starts, [0.0, 4.0, 7.0, 11.0, 14.0, 18.0]);
+        assert_eq!(meter.signature_at(12.0), (3, 4));
+    }
+
+    #[test]
+    fn test_seek_range_resolves_bars_and_checks_bounds() {
+        let range = |start, end| {
+            let options = ScheduleOptions {
+                start,
+                end,
+                ..ScheduleOptions::default()
+            };
+            seek_range(&options, &MeterMap::new((4, 4)), Beat::whole(32))
+                .map(|(start, end)| (start.as_f64(), end.map(Beat::as_f64)))
+        };
+        // The end bar is played in full
+        assert_eq!(
+            range(Some(Position::Bar(2)), Some(Position::Bar(3))),
+            Ok((4.0, Some(12.0)))
+        );
+        assert_eq!(range(Some(Position::Beat(6.5)), None), Ok((6.5, None)));
+        assert_eq!(range(None, Some(Position::Bar(8))), Ok((0.0, None)));
+        assert_eq!(
+            range(Some(Position::Bar(9)), None),
+            Err("bar 9 is past the end of the song (32 beats)".to_string())
+        );
+        assert!(range(Some(Position::Beat(8.0)), Some(Position::Beat(8.0))).is_err());
+        assert!(range(Some(Position::Beat(-1.0)), None).is_err());
+    }
+
+    #[test]
+    fn test_stream_reports_missing_pattern() {
+        let song = one_segment_song(0, 0);
+        let err = ScheduleIter::new(&song, &HashMap::new()).err().unwrap();
+        assert_eq!(err.to_string(), "pattern not loaded: a.notes");
+    }
+
+    #[test]
+    fn test_missing_pattern_with_length_plays_silence() {
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a s d f"))]);
+        let mut song = one_segment_song(0, 0);
+        let gap = Segment {
+            notes_path: PathBuf::from("gap.notes"),
+            times: 2,
+            length: Some(3.0),
+            ..song.tracks[0].sequence[0].clone()
+        };
+        song.tracks[0].sequence.insert(0, gap);
+        assert_eq!(track_lengths(&song, &patterns).unwrap(), beats(&[10.0]));
+        let events = build_schedule(&song, &patterns).unwrap();
+        assert_eq!(events[0].beat, Beat::whole(6));
+        let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+        assert_eq!(describe(&streamed), describe(&events));
+    }
+
+    #[test]
+    fn test_transpose_adds_song_and_segment_shift() {
+        // C4 + 5 - 2 = D#4
+        let freqs = note_on_freqs(&one_segment_song(-2, 5), "a");
+        assert_eq!(freqs, vec![NoteName::DSharp.to_freq(4)]);
+    }
+
+    #[test]
+    fn test_transpose_clamps_to_midi_range() {
+        let song = one_segment_song(0, -100);
+        let freqs = note_on_freqs(&song, "octave: 0\na");
+        assert_eq!(freqs, vec![midi_to_freq(0)]);
+        // The clamp is reported with the schedule, for the caller to warn
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("octave: 0\na"))]);
+        let clamped = compile(&song, &patterns).unwrap().clamped;
+        assert_eq!(clamped.len(), 1);
+        assert_eq!(
+            clamped[0].to_string(),
+            "track 0 (lead): transposing a.notes by -100 put 1 note(s) outside the MIDI range; clamped"
+        );
+    }
+
+    #[test]
+    fn test_loop_passes_stay_on_the_beat_grid() {
+        // A 3-beat pattern with a trailing rest: the rest is part of the loop
+        let song = one_segment_song(0, 0);
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a s -"))]);
+        let options = ScheduleOptions {
+            looped: true,
+            ..ScheduleOptions::default()
+        };
+        let stream = stream(&song, &patterns, 120.0, &options).unwrap();
+        assert_eq!(stream.looping, Some((Beat::ZERO, Beat::whole(3))));
+        assert!(stream.end_beat.is_none());
+
+        // Ten minutes at 120 BPM and well beyond: every pass starts exactly
+        // on its multiple of the period
+        let ons: Vec<Beat> = stream
+            .events
+            .take(4 * 10_000)
+            .filter(|ev| matches!(ev.command, LiveCommand::NoteOn { .. }))
+            .map(|ev| ev.beat)
+            .collect();
+        assert_eq!(ons.len(), 2 * 10_000);
+        for (pass, pair) in ons.chunks(2).enumerate() {
+            let origin = Beat::whole(3) * pass as u32;
+            assert_eq!(pair, [origin, origin + Beat::ONE]);
+        }
+    }
+
+    #[test]
+    fn test_alternatives_take_turns_per_repeat() {
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].sequence[0].times = 4;
+        let freqs = note_on_freqs(&song, "alt{1: a | 2: s:2 | 3: d} f");
+        let midi: Vec<u8> = freqs.iter().map(|&f| midi_of(f)).collect();
+        assert_eq!(midi, [60, 65, 62, 65, 64, 65, 60, 65]);
+        // The group lasts as long as its longest choice
+        let events = schedule_for(&song, "alt{1: a | 2: s:2 | 3: d} f");
+        let f_starts: Vec<Beat> = events
+            .iter()
+            .filter(|ev| {
+                matches!(ev.command, LiveCommand::NoteOn { freq, .. } if midi_of(freq) == 65)
+            })
+            .map(|ev| ev.beat)
+            .collect();
+        assert_eq!(f_starts, beats(&[2.0, 5.0, 8.0, 11.0]));
+    }
+
+    #[test]
+    fn test_generative_stream_matches_eager_schedule() {
+        let patterns = HashMap::from([
+            (PathBuf::from("a.notes"), pattern("a? [sd]?0.3 _ alt?{f g | h:2? | -} j:0.5 | k")),
+            (PathBuf::from("b.notes"), pattern("alt{1: a s | 2: d} f?0.8 alt?{g | -}")),
+        ]);
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].sequence[0].times = 3;
+        let mut second = song.tracks[0].clone();
+        second.sequence[0].notes_path = PathBuf::from("b.notes");
+        second.sequence[0].times = 5;
+        song.tracks.push(second);
+        let debug = |events: &[ScheduledEvent]| -> Vec<String> {
+            events.iter().map(|ev| format!("{:?}", ev)).collect()
+        };
+        for align in [Align::Pad, Align::Loop] {
+            song.align = align;
+            for seed in 0..20 {
+                song.seed = seed;
+                let eager = build_schedule(&song, &patterns).unwrap();
+                let streamed: Vec<_> = ScheduleIter::new(&song, &patterns).unwrap().collect();
+                assert_eq!(debug(&streamed), debug(&eager), "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chances_roll_from_the_seed() {
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].sequence[0].times = 100;
+        let notes = "a? s?0 d?1";
+        let count = |song: &Song, midi: u8| {
+            let freqs = note_on_freqs(song, notes);
+            freqs.iter().filter(|&&f| midi_of(f) == midi).count()
+        };
+        assert!((30..=70).contains(&count(&song, 60)), "{}", count(&song, 60));
+        assert_eq!(count(&song, 62), 0);
+        assert_eq!(count(&song, 64), 100);
+        // The same seed plays the same notes; another seed plays others
+        assert_eq!(schedule_for(&song, notes), schedule_for(&song, notes));
+        let mut other = song.clone();
+        other.seed = 1;
+        assert_ne!(schedule_for(&other, notes), schedule_for(&song, notes));
+    }
+
+    #[test]
+    fn test_looped_passes_roll_again() {
+        let song = one_segment_song(0, 0);
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a? s? d? f?"))]);
+        let options = ScheduleOptions {
+            looped: true,
+            ..ScheduleOptions::default()
+        };
+        let passes = |song: &Song| -> Vec<Vec<u8>> {
+            let stream = stream(song, &patterns, 120.0, &options).unwrap();
+            let mut passes = vec![Vec::new(); 8];
+            let ons = stream.events.filter_map(|ev| match ev.command {
+                LiveCommand::NoteOn { freq, .. } => Some((ev.beat, midi_of(freq))),
+                _ => None,
+            });
+            for (beat, midi) in ons.take_while(|(beat, _)| *beat < Beat::whole(32)) {
+                passes[(beat.as_f64() / 4.0) as usize].push(midi);
+            }
+            passes
+        };
+        let first = passes(&song);
+        assert!(first.iter().any(|pass| *pass != first[0]), "{:?}", first);
+        assert_eq!(passes(&song), first);
+    }
+
+    #[test]
+    fn test_repeats_land_exactly_on_bar_lines() {
+        // A bar of a triplet and decimal lengths, none of them exact in f64
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].sequence[0].times = 1000;
+        let patterns = HashMap::from([(
+            PathBuf::from("a.notes"),
+            pattern("(a s d)/3 f:0.1 g:0.9 h:0.3 j:0.7"),
+        )]);
+        assert_eq!(song_length(&song, &patterns).unwrap(), Beat::whole(4000));
+        let ons: Vec<Beat> = build_schedule(&song, &patterns)
+            .unwrap()
+            .iter()
+            .filter(|ev| matches!(ev.command, LiveCommand::NoteOn { .. }))
+            .map(|ev| ev.beat)
+            .collect();
+        assert_eq!(ons.len(), 7 * 1000);
+        for (bar, notes) in ons.chunks(7).enumerate() {
+            assert_eq!(notes[0], Beat::whole(4 * bar as i64));
+            assert_eq!(notes[3], Beat::whole(4 * bar as i64 + 2));
+        }
+    }
+
+    #[test]
+    fn test_compiled_streams_like_the_patterns() {
+        let mut song = one_segment_song(0, 0);
+        song.tracks[0].meter_changes = vec![SegmentMeter {
+            segment: 0,
+            signature: (3, 4),
+        }];
+        let patterns = HashMap::from([(PathBuf::from("a.notes"), pattern("a [sd] _ f - g"))]);
+        let compiled = compile(&song, &patterns).unwrap();
+        let humanize = Humanize {
+            timing_ms: 10.0,
+            velocity: 0.2,
+            seed: 3,
+        };
+        for options in [
+            ScheduleOptions {
+                humanize: Some(humanize),
+                fade_out: 1.0,
+                ..ScheduleOptions::default()
+            },
+            ScheduleOptions {
+                looped: true,
+                start: Some(Position::Bar(2)),
+                ..ScheduleOptions::default()
+            },
+        ] {
+            let from_patterns = stream(&song, &patterns, 120.0, &options).unwrap();
+            let from_compiled = stream_compiled(&compiled, 120.0, &options).unwrap();
+            assert_eq!(from_compiled.end_beat, from_patterns.end_beat);
+            assert_eq!(from_compiled.looping, from_patterns.looping);
+            assert_eq!(from_compiled.meter, from_patterns.meter);
+            let take = |events: Events| -> Vec<ScheduledEvent> { events.take(40).collect() };
+            assert_eq!(take(from_compiled.events), take(from_patterns.events));
+        }
+    }
+
+    #[test]
+    fn test_loop_releases_notes_at_the_seam() {
+        let key = |i: u32| char::from_u32(0xE000 + i).unwrap();
+        // Loop beats 1.5-3.5 of four held notes
+        let (start, end) = (Beat::ratio(3, 2), Beat::ratio(7, 2));
+        let make_pass = |_| Seek::new(four_notes().into_iter(), start, Some(end));
+        let events: Vec<_> = Looped::new(make_pass, Beat::whole(2))
+            .take(10)
+            .map(|ev| match ev.command {
+                LiveCommand::NoteOn { key: k, .. } => (ev.beat.as_f64(), true, k),
+                LiveCommand::NoteOff { key: k, .. } => (ev.beat.as_f64(), false, k),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            events[5..9],
+            [
+                // The last note is cut off before the next pass reuses keys
+                (2.0, false, key(3)),
+                (2.0, true, key(1)),
+                (2.5, false, key(1)),
+                (2.5, true, key(2)),
+            ]
+        );
+
+        // An empty pass ends the loop instead of spinning
+        assert_eq!(Looped::new(|_| std::iter::empty(), Beat::whole(4)).count(), 0);
+        // unless passes vary, when a silent one can be followed by notes
+        let every_third = |pass: u64| four_notes().into_iter().take(usize::from(pass % 3 == 2));
+        let beats: Vec<Beat> = Looped::new(every_third, Beat::whole(4))
+            .varying()
+            .take(2)
+            .map(|ev| ev.beat)
+            .collect();
+        assert_eq!(beats, [Beat::whole(8), Beat::whole(20)]);
+        let silent = Looped::new(|_| std::iter::empty(), Beat::whole(4)).varying();
+        assert_eq!(silent.count(), 0);
+    }
+
+    /// Four one-beat notes on track 0, starting at beat 0
+    fn four_notes() -> Vec<ScheduledEvent> {
+        let mut events = Vec::new();
+        for i in 0..4 {
+            let key = char::from_u32(0xE000 + i).unwrap();
+            events.push(ScheduledEvent {
+                beat: Beat::whole(i as i64),
+                command: LiveCommand::NoteOn {
+                    track: 0,
+                    key,
+                    freq: 440.0,
+                    velocity: 1.0,
+                },
+            });
+            events.push(ScheduledEvent {
+                beat: Beat::whole(i as i64 + 1),
+                command: LiveCommand::NoteOff { track: 0, key },
+            });
+        }
+        sort_schedule(&mut events);
+        events
+    }
+
+    fn describe(events: &[ScheduledEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|ev| match &ev.command {
+                LiveCommand::NoteOn { key, velocity, .. } => {
+                    format!("{:.6} on {:x} {:.4}", ev.beat, *key as u32, velocity)
+                }
+                LiveCommand::NoteOff { key, .. } => format!("{:.6} off {:x}", ev.beat, *key as u32),
+                other => format!("{:.6} {:?}", ev.beat, other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_humanize_golden() {
+        let settings = Humanize {
+            timing_ms: 10.0,
+            velocity: 0.2,
+            seed: 42,
+        };
+        let events: Vec<_> = Humanized::new(four_notes().into_iter(), &settings, 120.0).collect();
+        assert_eq!(
+            describe(&events),
+            vec![
+                "0.009663 on e000 0.8640",
+                "0.991144 on e001 0.9377",
+                "1.009663 off e000",
+                "1.981521 on e002 1.0000",
+                "1.991144 off e001",
+                "2.981521 off e002",
+                "2.988736 on e003 1.0000",
+                "3.988736 off e003",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_humanize_preserves_durations_and_start() {
+        let settings = Humanize {
+            timing_ms: 400.0,
+            velocity: 0.0,
+            seed: 3,
+        };
+        let events: Vec<_> = Humanized::new(four_notes().into_iter(), &settings, 120.0).collect();
+
+        let mut starts = HashMap::new();
+        for ev in &events {
+            assert!(ev.beat >= Beat::ZERO);
+            match ev.command {
+                LiveCommand::NoteOn { key, velocity, .. } => {
+                    assert_eq!(velocity, 1.0);
+                    starts.insert(key, ev.beat);
+                }
+                LiveCommand::NoteOff { key, .. } => {
+                    assert_eq!(ev.beat - starts[&key], Beat::ONE);
+                }
+                _ => {}
+            }
+        }
+        assert!(events.windows(2).all(|w| w[0].beat <= w[1].beat));
+    }
+
+    /// Sorted NoteOn/NoteOff pairs for (start, length) notes, keys from e000
+    fn notes_at(notes: &[(f64, f64)]) -> Vec<ScheduledEvent> {
+        let mut events = Vec::new();
+        for (i, &(start, length)) in notes.iter().enumerate() {
+            let key = char::from_u32(0xE000 + i as u32).unwrap();
+            events.push(ScheduledEvent {
+                beat: Beat::from_f64(start),
+                command: LiveCommand::NoteOn {
+                    track: 0,
+                    key,
+                    freq: 440.0,
+                    velocity: 1.0,
+                },
+            });
+            events.push(ScheduledEvent {
+                beat: Beat::from_f64(start + length),
+                command: LiveCommand::NoteOff { track: 0, key },
+            });
+        }
+        sort_schedule(&mut events);
+        events
+    }
+
+    #[test]
+    fn test_summary_counts_events_and_peak_voices() {
+        // A held note, then a triad starting as it releases, plus a kick
+        let mut schedule = schedule_for(&one_segment_song(0, 0), "a _ [sdf] g | h:0.5 j:0.5");
+        schedule.push(ScheduledEvent {
+            beat: Beat::whole(2),
+            command: LiveCommand::DrumHit {
+                track: 1,
+                drum: crate::note::Drum::Kick,
+                velocity: 1.0,
+            },
+        });
+        sort_schedule(&mut schedule);
+        let summary = summarize(schedule, 2);
+        assert_eq!(summary.events_per_track, vec![7, 1]);
+        // The held C is released before the triad starts
+        assert_eq!((summary.peak_voices, summary.peak_beat), (3, Beat::whole(2)));
+        // C4 up to B4; the kick has no pitch
+        assert_eq!(summary.range_per_track, vec![Some((60, 71)), None]);
+        let empty = ScheduleSummary {
+            events_per_track: vec![0],
+            range_per_track: vec![None],
+            ..ScheduleSummary::default()
+        };
+        assert_eq!(summarize(Vec::new(), 1), empty);
+    }
+
+    #[test]
+    fn test_quantize_halfway_rounds_later() {
+        let input = notes_at(&[(0.125, 0.25), (0.375, 0.5)]);
+        let events: Vec<_> = quantize(input, 0.25, 1.0).collect();
+        assert_eq!(
+            describe(&events),
+            vec![
+                "0.250000 on e000 1.0000",
+                "0.500000 off e000",
+                "0.500000 on e001 1.0000",
+                "1.000000 off e001",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quantize_strength_scales_the_move() {
+        let input = || notes_at(&[(0.1, 1.0), (1.2, 0.5)]);
+        let events: Vec<_> = quantize(input(), 0.5, 0.0).collect();
+        assert_eq!(describe(&events), describe(&input()));
+        let events: Vec<_> = quantize(input(), 0.5, 0.5).collect();
+        assert_eq!(
+            describe(&events),
+            vec![
+                "0.050000 on e000 1.0000",
+                "1.050000 off e000",
+                "1.100000 on e001 1.0000",
+                "1.600000 off e001",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quantize_resorts_moved_events() {
+        // The first note's NoteOff moves past the second note's NoteOn
+        let events: Vec<_> = quantize(notes_at(&[(0.375, 0.25), (0.625, 0.25)]), 0.5, 1.0).collect();
+        assert_eq!(
+            describe(&events),
+            vec![
+                "0.500000 on e000 1.0000",
+                "0.500000 on e001 1.0000",
+                "0.750000 off e000",
+                "0.750000 off e001",
+            ]
+        );
+    }
 }
@@ -0,0 +1,628 @@
+//! Step sequencer (`clidaw seq`): a grid with a row per pitch or drum and a
+//! column per step, edited from the keyboard, looped through the engine
+//! and written back as `.notes` text.
+//!
+//! Each step is one `.notes` beat, as in `clidaw import`: `steps_per_beat`
+//! steps make a beat of the music, so a new file's tempo is the music's
+//! times `steps_per_beat` (120 BPM in sixteenths is written as 480). Notes
+//! last one step. The grid shows one octave at a time (C to C); notes in
+//! other octaves are kept and come back into view with `<` and `>`.
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+
+use crate::backend::OutputOptions;
+use crate::beat::Beat;
+use crate::error::ClidawError;
+use crate::note::{self, Composition, Drum, Event, NoteEvent, NoteName, Spelling};
+use crate::synth::{AudioEngine, LiveCommand, Mix, Patch};
+
+/// Steps in a new grid: a bar of sixteenths
+pub const DEFAULT_STEPS: usize = 16;
+
+/// Columns taken by a row's label ("C#4 ", "snare ")
+const LABEL_WIDTH: usize = 7;
+
+/// Columns taken by one step
+const CELL_WIDTH: usize = 2;
+
+/// Lines above the grid (title and help) and below it (status)
+const HEADER_LINES: usize = 2;
+const FOOTER_LINES: usize = 1;
+
+/// Event poll timeout while the loop plays (so steps land on time) and
+/// while it doesn't
+const PLAYING_POLL: Duration = Duration::from_millis(2);
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// Highest octave the grid can show (its top row is the next C)
+const MAX_OCTAVE: u8 = 7;
+
+/// Steps toggled on a grid of pitches or drums, and the cursor
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    /// Rows are drum voices rather than pitches
+    pub drums: bool,
+    pub steps: usize,
+    /// Octave whose C is the bottom row (pitch grids)
+    pub octave: u8,
+    /// Steps per beat of the music, for the beat marks between columns
+    pub steps_per_beat: usize,
+    /// Steps per bar, for bar lines in the written notes
+    pub steps_per_bar: usize,
+    /// (lane, step) of every hit: a MIDI note, or a drum's index in `Drum::ALL`
+    hits: BTreeSet<(u8, usize)>,
+    /// Row and step the cursor is on
+    pub cursor: (usize, usize),
+}
+
+impl Grid {
+    /// An empty grid, the cursor on its first step and bottom row
+    pub fn new(drums: bool, steps: usize, octave: u8, steps_per_beat: usize) -> Self {
+        let mut grid = Self {
+            drums,
+            steps: steps.max(1),
+            octave: octave.min(MAX_OCTAVE),
+            steps_per_beat: steps_per_beat.max(1),
+            steps_per_bar: 4 * steps_per_beat.max(1),
+            hits: BTreeSet::new(),
+            cursor: (0, 0),
+        };
+        grid.cursor.0 = grid.rows() - 1;
+        grid
+    }
+
+    /// A grid holding `events`, one step per beat, with the number of
+    /// notes left out because they don't start on a step (or are notes in
+    /// a drum pattern). The grid is as long as the events unless `steps`
+    /// says otherwise; hits beyond it are dropped too.
+    pub fn from_events(
+        events: &[Event],
+        steps: Option<usize>,
+        octave: u8,
+        steps_per_beat: usize,
+    ) -> (Self, usize) {
+        let drums = events.iter().any(|e| matches!(e, Event::Drums(..)));
+        let length: Beat = events.iter().map(note::event_duration).sum();
+        let whole = (length.as_f64().ceil() as usize).max(1);
+        let steps = steps.unwrap_or(whole.max(DEFAULT_STEPS));
+        let mut grid = Grid::new(drums, steps, octave, steps_per_beat);
+        let mut skipped = 0;
+        let mut at = Beat::ZERO;
+        for event in events {
+            let on_step = at % Beat::ONE == Beat::ZERO;
+            let step = at.as_f64().round() as usize;
+            match event {
+                Event::Drums(hit, _) if on_step && step < grid.steps => {
+                    for drum in hit {
+                        grid.hits.insert((drum_lane(*drum), step));
+                    }
+                }
+                Event::Note(..) | Event::Chord(..) if on_step && !drums && step < grid.steps => {
+                    for n in event.notes() {
+                        grid.hits.insert((n.note.to_midi(n.octave), step));
+                    }
+                }
+                _ => skipped += event.notes().len(),
+            }
+            at += note::event_duration(event);
+        }
+        (grid, skipped)
+    }
+
+    pub fn rows(&self) -> usize {
+        if self.drums { Drum::ALL.len() } else { 13 }
+    }
+
+    /// The lane a row shows: drums top to bottom in `Drum::ALL` order, or
+    /// the octave's C up to the next C from the bottom
+    fn lane(&self, row: usize) -> u8 {
+        if self.drums {
+            row as u8
+        } else {
+            NoteName::C.to_midi(self.octave) + 12 - row as u8
+        }
+    }
+
+    /// A row's label: the drum, or the note and octave ("C#4")
+    pub fn row_label(&self, row: usize) -> String {
+        let lane = self.lane(row);
+        if self.drums {
+            Drum::ALL[lane as usize].name().to_string()
+        } else {
+            format!("{}{}", NoteName::ALL[(lane % 12) as usize].name(), lane / 12 - 1)
+        }
+    }
+
+    pub fn is_set(&self, row: usize, step: usize) -> bool {
+        self.hits.contains(&(self.lane(row), step))
+    }
+
+    /// Turn the step under the cursor on or off
+    pub fn toggle(&mut self) {
+        let (row, step) = self.cursor;
+        let hit = (self.lane(row), step);
+        if !self.hits.remove(&hit) {
+            self.hits.insert(hit);
+        }
+    }
+
+    /// Move the cursor, stopping at the edges
+    pub fn move_cursor(&mut self, rows: isize, steps: isize) {
+        let (row, step) = self.cursor;
+        self.cursor = (
+            row.saturating_add_signed(rows).min(self.rows() - 1),
+            step.saturating_add_signed(steps).min(self.steps - 1),
+        );
+    }
+
+    /// Show the octave above or below (pitch grids); false at the ends
+    pub fn shift_octave(&mut self, up: bool) -> bool {
+        match (up, self.drums) {
+            (_, true) => false,
+            (true, false) if self.octave < MAX_OCTAVE => {
+                self.octave += 1;
+                true
+            }
+            (false, false) if self.octave > 0 => {
+                self.octave -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The grid as pattern events: one beat per step, a note, chord, drum
+    /// hit or rest each, with a bar line after every bar of a pitch grid
+    pub fn events(&self) -> Vec<Event> {
+        let mut events = Vec::with_capacity(self.steps);
+        for step in 0..self.steps {
+            let lanes: Vec<u8> =
+                self.hits.iter().filter(|&&(_, s)| s == step).map(|&(lane, _)| lane).collect();
+            events.push(if self.drums {
+                if lanes.is_empty() {
+                    Event::Rest(Beat::ONE)
+                } else {
+                    let drums = lanes.iter().map(|&lane| Drum::ALL[lane as usize]).collect();
+                    Event::Drums(drums, Beat::ONE)
+                }
+            } else {
+                let mut notes: Vec<NoteEvent> = lanes.iter().copied().map(note_event).collect();
+                match notes.len() {
+                    0 => Event::Rest(Beat::ONE),
+                    1 => Event::Note(notes.remove(0), Beat::ONE),
+                    _ => Event::Chord(notes, Beat::ONE),
+                }
+            });
+            let bar_end = (step + 1).is_multiple_of(self.steps_per_bar);
+            if !self.drums && bar_end && step + 1 < self.steps {
+                events.push(Event::BarLine);
+            }
+        }
+        events
+    }
+
+    /// What the engine is sent to play `step` on `track` (each note is
+    /// keyed by its MIDI number, for the NoteOff a step later)
+    pub fn step_commands(&self, step: usize, track: usize) -> Vec<LiveCommand> {
+        let lanes = self.hits.iter().filter(|&&(_, s)| s == step).map(|&(lane, _)| lane);
+        if self.drums {
+            lanes
+                .map(|lane| LiveCommand::DrumHit {
+                    track,
+                    drum: Drum::ALL[lane as usize],
+                    velocity: 1.0,
+                })
+                .collect()
+        } else {
+            lanes
+                .map(|midi| LiveCommand::NoteOn {
+                    track,
+                    key: char::from(midi),
+                    freq: note::midi_to_freq(midi),
+                    velocity: 1.0,
+                })
+                .collect()
+        }
+    }
+
+    /// The grid drawn in `width` columns: a line per row, as many steps as
+    /// fit, scrolled to keep the cursor in view. `x` is a hit, `·` a rest,
+    /// `|` starts each beat, the cursor's step is in brackets and the
+    /// playhead's column shows `>` in the ruler line on top.
+    pub fn lines(&self, width: usize, playhead: Option<usize>) -> Vec<String> {
+        let visible = (width.saturating_sub(LABEL_WIDTH) / CELL_WIDTH).clamp(1, self.steps);
+        let first = (self.cursor.1 + 1).saturating_sub(visible);
+        let shown = first..first + visible;
+
+        let mut ruler = format!("{:<width$}", "", width = LABEL_WIDTH);
+        for step in shown.clone() {
+            let mark = match playhead {
+                Some(p) if p == step => '>',
+                _ if step.is_multiple_of(self.steps_per_bar) => '1',
+                _ => ' ',
+            };
+            ruler.push(mark);
+            ruler.push(' ');
+        }
+        let mut lines = vec![ruler.trim_end().to_string()];
+        for row in 0..self.rows() {
+            let mut line = format!("{:<width$}", self.row_label(row), width = LABEL_WIDTH);
+            for step in shown.clone() {
+                let on_beat = step.is_multiple_of(self.steps_per_beat);
+                let cell = if self.is_set(row, step) { 'x' } else { '·' };
+                let left = if self.cursor == (row, step) {
+                    '['
+                } else if on_beat {
+                    '|'
+                } else {
+                    ' '
+                };
+                line.push(left);
+                line.push(cell);
+            }
+            if self.cursor.0 == row && self.cursor.1 + 1 == shown.end {
+                line.push(']');
+            } else if self.cursor.0 == row {
+                // Close the bracket over the next cell's separator
+                let at = LABEL_WIDTH + (self.cursor.1 - first + 1) * CELL_WIDTH;
+                line.replace_range(at..at + 1, "]");
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// A drum's lane: its index in `Drum::ALL`
+fn drum_lane(drum: Drum) -> u8 {
+    Drum::ALL.iter().position(|&d| d == drum).unwrap_or(0) as u8
+}
+
+/// A full-velocity note for MIDI number `midi`
+fn note_event(midi: u8) -> NoteEvent {
+    NoteEvent {
+        note: NoteName::ALL[(midi % 12) as usize],
+        octave: midi / 12 - 1,
+        degree: None,
+        velocity: 1.0,
+        spelling: Spelling::Sharp,
+        chance: 1.0,
+    }
+}
+
+/// What `clidaw seq` edits and how it plays
+pub struct SeqOptions<'a> {
+    /// The .notes file the grid is written to
+    pub path: &'a Path,
+    /// The file as read (or a new one), its first track replaced on save
+    pub comp: Composition,
+    pub grid: Grid,
+    /// Shown when the sequencer opens, e.g. notes the grid couldn't hold
+    pub notice: Option<String>,
+    pub patch: Patch,
+    pub output: OutputOptions,
+}
+
+/// The loop being auditioned: when it started and the notes it holds
+struct Playback {
+    start: Instant,
+    step_secs: f64,
+    step: Option<usize>,
+    sounding: Vec<LiveCommand>,
+}
+
+impl Playback {
+    /// The step sounding at `now`
+    fn step_at(&self, now: Instant, steps: usize) -> usize {
+        (now.duration_since(self.start).as_secs_f64() / self.step_secs) as usize % steps
+    }
+}
+
+/// Run the sequencer until the player quits. `save` writes the composition
+/// to `options.path`.
+pub fn run(
+    mut options: SeqOptions,
+    save: impl Fn(&Composition) -> Result<(), ClidawError>,
+) -> Result<(), ClidawError> {
+    let engine = AudioEngine::new(vec![options.patch.clone()], &Mix::default(), &options.output)?;
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()
+        .map_err(|e| ClidawError::Terminal(format!("failed to enable raw mode: {}", e)))?;
+    execute!(stdout, EnterAlternateScreen, cursor::Hide)
+        .map_err(|e| ClidawError::Terminal(format!("alternate screen: {}", e)))?;
+
+    let result = event_loop(&mut options, &engine, &mut stdout, save);
+
+    let _ = engine.send(LiveCommand::HardStop);
+    std::thread::sleep(Duration::from_millis(20));
+    let _ = engine.send(LiveCommand::Shutdown);
+    let _ = execute!(stdout, cursor::Show, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    result
+}
+
+fn event_loop(
+    options: &mut SeqOptions,
+    engine: &AudioEngine,
+    stdout: &mut io::Stdout,
+    save: impl Fn(&Composition) -> Result<(), ClidawError>,
+) -> Result<(), ClidawError> {
+    let mut status = options.notice.take().unwrap_or_default();
+    let mut playback: Option<Playback> = None;
+    let mut dirty = false;
+    let mut quit_armed = false;
+    draw(stdout, options, &status, None);
+
+    loop {
+        // Move the playhead: end the last step's notes, start this one's
+        if let Some(play) = playback.as_mut() {
+            let step = play.step_at(Instant::now(), options.grid.steps);
+            if play.step != Some(step) {
+                for note in play.sounding.drain(..) {
+                    if let LiveCommand::NoteOn { track, key, .. } = note {
+                        engine.send(LiveCommand::NoteOff { track, key })?;
+                    }
+                }
+                for command in options.grid.step_commands(step, 0) {
+                    engine.send(command.clone())?;
+                    play.sounding.push(command);
+                }
+                play.step = Some(step);
+                draw(stdout, options, &status, play.step);
+            }
+        }
+
+        let poll = if playback.is_some() { PLAYING_POLL } else { IDLE_POLL };
+        if !event::poll(poll)
+            .map_err(|e| ClidawError::Terminal(format!("event poll error: {}", e)))?
+        {
+            continue;
+        }
+        let ev = event::read()
+            .map_err(|e| ClidawError::Terminal(format!("event read error: {}", e)))?;
+        let (code, modifiers) = match ev {
+            TermEvent::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press | KeyEventKind::Repeat,
+                ..
+            }) => (code, modifiers),
+            TermEvent::Resize(..) => {
+                draw(stdout, options, &status, playback.as_ref().and_then(|p| p.step));
+                continue;
+            }
+            _ => continue,
+        };
+
+        let grid = &mut options.grid;
+        let quitting = matches!(code, KeyCode::Char('q') | KeyCode::Esc);
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            KeyCode::Char('q') | KeyCode::Esc if !dirty || quit_armed => return Ok(()),
+            KeyCode::Char('q') | KeyCode::Esc => {
+                status = "Unsaved changes: w writes them, q again quits without".to_string();
+                quit_armed = true;
+            }
+            KeyCode::Up => grid.move_cursor(-1, 0),
+            KeyCode::Down => grid.move_cursor(1, 0),
+            KeyCode::Left => grid.move_cursor(0, -1),
+            KeyCode::Right => grid.move_cursor(0, 1),
+            KeyCode::Home => grid.move_cursor(0, -(grid.steps as isize)),
+            KeyCode::End => grid.move_cursor(0, grid.steps as isize),
+            KeyCode::Char(' ') | KeyCode::Enter => {
+                grid.toggle();
+                dirty = true;
+            }
+            KeyCode::Char(c @ ('<' | '>')) => {
+                let moved = grid.shift_octave(c == '>');
+                if !moved {
+                    status = "No more octaves that way".to_string();
+                }
+            }
+            KeyCode::Char('p') => {
+                if let Some(mut play) = playback.take() {
+                    for note in play.sounding.drain(..) {
+                        if let LiveCommand::NoteOn { track, key, .. } = note {
+                            engine.send(LiveCommand::NoteOff { track, key })?;
+                        }
+                    }
+                    status = "Stopped".to_string();
+                } else {
+                    playback = Some(Playback {
+                        start: Instant::now(),
+                        step_secs: 60.0 / options.comp.tempo,
+                        step: None,
+                        sounding: Vec::new(),
+                    });
+                    status = "Playing (p stops)".to_string();
+                }
+            }
+            KeyCode::Char('w') => {
+                write_grid(&mut options.comp, grid);
+                status = match save(&options.comp) {
+                    Ok(()) => {
+                        dirty = false;
+                        format!("Wrote {}", options.path.display())
+                    }
+                    Err(e) => format!("error: {}", e),
+                };
+            }
+            _ => {}
+        }
+        if !quitting {
+            quit_armed = false;
+        }
+        draw(stdout, options, &status, playback.as_ref().and_then(|p| p.step));
+    }
+}
+
+/// Put the grid into `comp` as its only track
+pub fn write_grid(comp: &mut Composition, grid: &Grid) {
+    comp.beats = Beat::from_f64(grid.steps as f64);
+    let events = grid.events();
+    match comp.tracks.first_mut() {
+        Some(track) => {
+            track.events = events;
+            track.includes.clear();
+            track.meter_changes.clear();
+        }
+        None => comp.tracks.push(note::Track {
+            name: "default".to_string(),
+            patch: None,
+            octave: comp.default_octave,
+            events,
+            includes: Vec::new(),
+            meter_changes: Vec::new(),
+        }),
+    }
+}
+
+/// Redraw the whole screen, or say the terminal is too small for it
+fn draw(stdout: &mut io::Stdout, options: &SeqOptions, status: &str, playhead: Option<usize>) {
+    let (width, height) = terminal::size().map_or((80, 24), |(w, h)| (w as usize, h as usize));
+    let grid = &options.grid;
+    let _ = queue!(stdout, terminal::Clear(ClearType::All), cursor::MoveTo(0, 0));
+    let needed = HEADER_LINES + 1 + grid.rows() + FOOTER_LINES;
+    if width < LABEL_WIDTH + CELL_WIDTH || height < needed {
+        let text = format!("Terminal too small: need {} lines", needed);
+        let _ = queue!(stdout, Print(truncate(&text, width)));
+        let _ = stdout.flush();
+        return;
+    }
+
+    let (row, step) = grid.cursor;
+    let title = format!(
+        "{} - {} steps, step {} of {}, {}",
+        options.path.display(),
+        grid.steps,
+        step + 1,
+        grid.steps,
+        grid.row_label(row)
+    );
+    let help = "arrows move  space toggles  p plays  < > octave  w writes  q quits";
+    let mut lines = vec![title, help.to_string()];
+    lines.extend(grid.lines(width, playhead));
+    lines.push(status.to_string());
+    for (i, line) in lines.iter().enumerate() {
+        let _ = queue!(stdout, cursor::MoveTo(0, i as u16), Print(truncate(line, width)));
+    }
+    let _ = stdout.flush();
+}
+
+/// `text` cut to `width` characters
+fn truncate(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use crate::writer;
+
+    /// Write `grid` as a new file and read it back
+    fn round_trip(grid: &Grid) -> Composition {
+        let mut comp = Composition::new();
+        write_grid(&mut comp, grid);
+        let text = writer::composition_text(&comp);
+        parser::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn test_pitch_grid_round_trips_through_notes_text() {
+        let mut grid = Grid::new(false, 8, 4, 4);
+        grid.steps_per_bar = 4;
+        // C4 on step 0, C4 + E4 on step 2, the top C5 on step 5
+        grid.toggle();
+        grid.cursor = (12, 2);
+        grid.toggle();
+        grid.cursor = (8, 2);
+        grid.toggle();
+        grid.cursor = (0, 5);
+        grid.toggle();
+
+        let comp = round_trip(&grid);
+        let events = &comp.tracks[0].events;
+        assert!(events.contains(&Event::BarLine));
+        let (back, skipped) = Grid::from_events(events, Some(8), 4, 4);
+        assert_eq!(skipped, 0);
+        assert_eq!(back.hits, grid.hits);
+        assert_eq!(comp.beats, Beat::from_f64(8.0));
+    }
+
+    #[test]
+    fn test_drum_grid_round_trips_through_notes_text() {
+        let mut grid = Grid::new(true, 4, 4, 4);
+        assert_eq!(grid.rows(), 3);
+        for step in 0..4 {
+            grid.cursor = (0, step);
+            grid.toggle();
+        }
+        grid.cursor = (1, 2);
+        grid.toggle();
+        let comp = round_trip(&grid);
+        let (back, _) = Grid::from_events(&comp.tracks[0].events, None, 4, 4);
+        assert!(back.drums);
+        assert_eq!(back.hits, grid.hits);
+        assert_eq!(back.row_label(1), "snare");
+    }
+
+    #[test]
+    fn test_off_grid_notes_are_counted_and_left_out() {
+        let text = "a:0.5 s:0.5 d f\n";
+        let comp = parser::parse(text).unwrap();
+        let (grid, skipped) = Grid::from_events(&comp.tracks[0].events, None, 4, 4);
+        // 's' starts half a beat in
+        assert_eq!(skipped, 1);
+        assert_eq!(grid.steps, DEFAULT_STEPS);
+        assert!(grid.is_set(12, 0) && grid.is_set(8, 1) && grid.is_set(7, 2));
+    }
+
+    #[test]
+    fn test_cursor_and_octave_stay_in_range() {
+        let mut grid = Grid::new(false, 16, 7, 4);
+        grid.move_cursor(-100, 100);
+        assert_eq!(grid.cursor, (0, 15));
+        grid.move_cursor(100, -100);
+        assert_eq!(grid.cursor, (12, 0));
+        assert!(!grid.shift_octave(true));
+        assert!(grid.shift_octave(false));
+        assert_eq!(grid.row_label(12), "C6");
+        assert!(!Grid::new(true, 4, 4, 4).shift_octave(true));
+    }
+
+    #[test]
+    fn test_step_commands() {
+        let mut grid = Grid::new(false, 4, 4, 4);
+        grid.toggle();
+        let commands = grid.step_commands(0, 0);
+        assert_eq!(commands.len(), 1);
+        let LiveCommand::NoteOn { freq, .. } = commands[0] else { panic!("{:?}", commands) };
+        assert!((freq - 261.63).abs() < 0.01);
+        assert!(grid.step_commands(1, 0).is_empty());
+    }
+
+    #[test]
+    fn test_narrow_views_scroll_to_the_cursor() {
+        let mut grid = Grid::new(true, 16, 4, 4);
+        grid.cursor = (0, 15);
+        grid.toggle();
+        let lines = grid.lines(LABEL_WIDTH + 4 * CELL_WIDTH, None);
+        assert_eq!(lines.len(), 4);
+        // Steps 12-15 show, the last one under the cursor
+        assert_eq!(lines[1], "kick   |· · ·[x]");
+        let wide = grid.lines(200, Some(0));
+        assert!(wide[0].starts_with(&format!("{:<7}>", "")), "{:?}", wide[0]);
+        assert_eq!(wide[1].chars().count(), LABEL_WIDTH + 16 * CELL_WIDTH + 1);
+        // Too narrow for even one step still shows one
+        assert_eq!(grid.lines(3, None)[1].chars().count(), LABEL_WIDTH + CELL_WIDTH + 1);
+    }
+}
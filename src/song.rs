@@ -1,31 +1,284 @@
 //! Song definitions: multiple instruments, each with a sequence of .notes patterns.
 //!
-//! A `.song` file lists instruments (.instr) and then per-track sequences of
-//! (notes_file, repeat_count) to build the full song.
+//! A `.song` file lists instruments (.instr files or inline definitions) and
+//! then per-track sequences of (notes_file, repeat_count) to build the full song,
+//! or named sections played in the order of an `arrangement:` line.
 
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::effects::{self, Effect, Reverb};
+use crate::error::ClidawError;
+use crate::instrument::{self, Instrument};
+use crate::note::Metadata;
+use crate::parser;
+use crate::synth::{self, Param};
+use crate::tempo;
+
 /// One segment in a track: play this pattern N times.
 #[derive(Debug, Clone)]
 pub struct Segment {
     pub notes_path: PathBuf,
     pub times: u32,
+    /// Semitones added to every note in this segment
+    pub transpose: i8,
+    /// Position in the song's arrangement this segment plays in (songs
+    /// with sections); it starts with that section, not when the previous
+    /// segment ends
+    pub slot: Option<usize>,
+    /// Beats of silence each pass plays instead while the pattern file is
+    /// missing (`length 16`; see `Missing::Skip`)
+    pub length: Option<f64>,
+}
+
+/// What playing a song does about pattern files that don't exist
+/// (`missing:`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Missing {
+    /// Stop with an error naming the file
+    #[default]
+    Error,
+    /// Warn, then play the segment's `length` of silence, or leave the
+    /// segment out when it has none
+    Skip,
+}
+
+/// Where a track's instrument comes from
+#[derive(Debug, Clone)]
+pub enum InstrumentSource {
+    /// A `.instr` file, loaded when the song is played
+    File(PathBuf),
+    /// Defined in the song itself: `instrument: lead { attack: 0.01 }`
+    Inline {
+        /// 1-based line of the definition in the .song file
+        line: usize,
+        instrument: Box<Instrument>,
+    },
+    /// A built-in instrument named by `patch:` in a .notes file
+    Preset(String),
+}
+
+impl InstrumentSource {
+    /// The instrument, reading it from disk if it lives in a file
+    pub fn load(&self) -> Result<Instrument, ClidawError> {
+        match self {
+            InstrumentSource::File(path) => instrument::load(path),
+            InstrumentSource::Inline { instrument, .. } => Ok(instrument.as_ref().clone()),
+            InstrumentSource::Preset(name) => instrument::preset(name),
+        }
+    }
+
+    /// `load`, then fail on invalid values (and, if `strict`, on warnings),
+    /// printing any warnings that don't fail
+    pub fn load_checked(&self, strict: bool) -> Result<Instrument, ClidawError> {
+        let instrument = self.load()?;
+        instrument.vet(&self.to_string(), strict)?;
+        Ok(instrument)
+    }
+
+    /// A parsed .notes `patch:` value: a `.instr` path (already resolved by
+    /// the parser) or the name of a built-in preset
+    pub fn from_patch(patch: &str) -> InstrumentSource {
+        if parser::is_patch_file(patch) {
+            InstrumentSource::File(PathBuf::from(patch))
+        } else {
+            InstrumentSource::Preset(patch.to_string())
+        }
+    }
+}
+
+impl fmt::Display for InstrumentSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstrumentSource::File(path) => write!(f, "{}", path.display()),
+            InstrumentSource::Inline { line, .. } => write!(f, "inline instrument (line {})", line),
+            InstrumentSource::Preset(name) => write!(f, "preset '{}'", name),
+        }
+    }
 }
 
 /// One track: one instrument + a sequence of (pattern, repeat count).
 #[derive(Debug, Clone)]
 pub struct SongTrack {
-    pub instrument_path: PathBuf,
+    /// Display name: `name:` from the song, else the instrument file stem
+    /// (or the inline instrument's name)
+    pub name: String,
+    pub instrument: InstrumentSource,
     pub sequence: Vec<Segment>,
+    /// `output_channel:` the track plays on by itself (None = the shared mix)
+    pub output_channel: Option<u16>,
+    /// Index into `Song::buses` of the track's `bus:` (None = the master mix)
+    pub bus: Option<usize>,
+    /// `reverb_send:` level into the song's reverb (default 1: the full
+    /// `reverb_mix`)
+    pub reverb_send: f64,
+    /// `time_signature:` lines between the track's sequence lines
+    pub meter_changes: Vec<SegmentMeter>,
+    /// Beats of silence before the track starts (`offset:`, or
+    /// `offset_bars:` in bars of the song's time signature)
+    pub offset: f64,
+    /// `effects:` the track's sound runs through, in order
+    pub effects: Vec<Effect>,
+}
+
+impl SongTrack {
+    /// `param` as the track is written, before any automation moves it
+    /// (None for a cutoff on a track without a filter)
+    pub fn param_value(&self, param: Param) -> Option<f64> {
+        match param {
+            Param::Gain => Some(1.0),
+            Param::Pan => Some(0.0),
+            Param::Cutoff => effects::automated_cutoff(&self.effects),
+        }
+    }
+}
+
+/// An `automate:` line: one track setting moving from `from` to `to`
+/// between the downbeats of two bars, then staying at `to`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Automation {
+    /// Index into the song's tracks
+    pub track: usize,
+    pub param: Param,
+    pub from: f64,
+    pub to: f64,
+    /// Bars counted from 1; the move ends as `end_bar` starts
+    pub start_bar: u32,
+    pub end_bar: u32,
+}
+
+impl Automation {
+    /// The setting `at` (0..=1) of the way along: a straight line, except
+    /// that a cutoff moves evenly in pitch
+    pub fn value_at(&self, at: f64) -> f64 {
+        match self.param {
+            Param::Cutoff => self.from * (self.to / self.from).powf(at),
+            Param::Gain | Param::Pan => self.from + (self.to - self.from) * at,
+        }
+    }
+}
+
+/// A time signature change before one of a track's segments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentMeter {
+    /// Index into the track's sequence of the first segment in the new
+    /// signature (the sequence length for a change after the last one)
+    pub segment: usize,
+    pub signature: (u8, u8),
+}
+
+/// A group of tracks mixed together before the master sum, declared with
+/// `bus: drums { gain: 0.8 }` and joined by tracks with `bus: drums`
+#[derive(Debug, Clone)]
+pub struct Bus {
+    pub name: String,
+    pub gain: f64,
+}
+
+impl Bus {
+    /// The engine's bus for this declaration
+    pub fn to_bus(&self) -> synth::Bus {
+        synth::Bus {
+            gain: self.gain,
+            ..synth::Bus::default()
+        }
+    }
+}
+
+/// How tracks of different lengths line up at the end of a song (`align:`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Align {
+    /// Shorter tracks fall silent while the longest one finishes
+    #[default]
+    Pad,
+    /// Shorter tracks repeat their sequence until the longest one ends
+    Loop,
+    /// Every track stops where the shortest one ends
+    Truncate,
+}
+
+impl Align {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "pad" => Some(Align::Pad),
+            "loop" => Some(Align::Loop),
+            "truncate" => Some(Align::Truncate),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Align::Pad => "pad",
+            Align::Loop => "loop",
+            Align::Truncate => "truncate",
+        }
+    }
+}
+
+/// What happens when a section's patterns have different lengths
+/// (`section_align:`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SectionAlign {
+    /// Shorter parts fall silent until the longest one finishes
+    #[default]
+    Pad,
+    /// Every part of a section must be the same length
+    Error,
+}
+
+/// A named part of the song that binds tracks to patterns:
+/// `section verse { bass: bassline.notes, lead: melody.notes * 2 }`
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    /// 1-based line of the definition in the .song file
+    pub line: usize,
+}
+
+/// The order sections play in: `arrangement: intro verse chorus verse`
+#[derive(Debug, Clone, Default)]
+pub struct Arrangement {
+    /// Index into `Song::sections` for each position
+    pub sections: Vec<usize>,
+    pub align: SectionAlign,
 }
 
 /// A song: tempo, time signature, and one or more tracks (instrument + pattern sequence).
 #[derive(Debug, Clone)]
 pub struct Song {
-    pub tempo: u32,
+    pub tempo: f64,
     pub time_signature: (u8, u8),
+    /// Semitones added to every note of every track (on top of segment transposes)
+    pub transpose: i8,
+    /// Fade from silence over this many seconds at the start (0 = none)
+    pub fade_in: f64,
+    /// Fade to silence over this many seconds before the end (0 = none)
+    pub fade_out: f64,
+    /// What happens to tracks shorter than the longest one
+    pub align: Align,
     pub tracks: Vec<SongTrack>,
+    /// Buses the tracks can be grouped into
+    pub buses: Vec<Bus>,
+    /// Master reverb from `reverb_mix:`, `reverb_size:` and `reverb_damping:`
+    pub reverb: Reverb,
+    /// Gain on the whole mix from `master_volume:` (default 1)
+    pub master_volume: f64,
+    /// `automate:` lines, in the order written
+    pub automation: Vec<Automation>,
+    /// Sections and their order, for songs written with sections. Their
+    /// segments are already in the tracks' sequences, tagged with `slot`.
+    pub sections: Vec<Section>,
+    pub arrangement: Option<Arrangement>,
+    /// What playing does about missing pattern files
+    pub missing: Missing,
+    /// `prefer_flats: true`: the patterns name black keys with flats
+    pub prefer_flats: bool,
+    pub metadata: Metadata,
+    /// Seeds the rolls of chance notes and random `alt{...}` groups (see
+    /// `--seed`); the same seed plays them the same way
+    pub seed: u64,
 }
 
 fn parse_kv(line: &str) -> Option<(&str, &str)> {
@@ -39,23 +292,563 @@ fn parse_kv(line: &str) -> Option<(&str, &str)> {
     Some((key, value))
 }
 
-/// Parse "file.notes * 4" or "file.notes" (times = 1)
-fn parse_sequence_line(line: &str) -> Option<(String, u32)> {
+/// A segment played without its pattern file under `missing: skip`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedSegment {
+    pub track: String,
+    pub notes_path: PathBuf,
+    /// Beats of silence played in its place, all passes together (0: the
+    /// segment is left out)
+    pub beats: f64,
+}
+
+impl fmt::Display for SkippedSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "track {}: {} is missing; ", self.track, self.notes_path.display())?;
+        if self.beats > 0.0 {
+            write!(f, "{} beats of silence play in its place", self.beats)
+        } else {
+            write!(f, "its segment is skipped")
+        }
+    }
+}
+
+impl Song {
+    /// Let each segment whose pattern file doesn't exist play its `length`
+    /// of silence instead (none if it has no length), and list them
+    pub fn skip_missing(&mut self) -> Vec<SkippedSegment> {
+        let mut skipped = Vec::new();
+        for track in &mut self.tracks {
+            for seg in track.sequence.iter_mut().filter(|seg| !seg.notes_path.exists()) {
+                let length = *seg.length.get_or_insert(0.0);
+                skipped.push(SkippedSegment {
+                    track: track.name.clone(),
+                    notes_path: seg.notes_path.clone(),
+                    beats: length * seg.times as f64,
+                });
+            }
+        }
+        skipped
+    }
+
+    /// Warnings for the gains above `LOUD_GAIN`, which will likely clip:
+    /// each bus's, then `master_volume` unless `--master-volume` replaces it
+    pub fn loud_gains(&self, master_replaced: bool) -> Vec<String> {
+        let buses = self.buses.iter().map(|b| (format!("bus '{}' gain", b.name), b.gain));
+        let master = (!master_replaced).then(|| ("master_volume".to_string(), self.master_volume));
+        buses
+            .chain(master)
+            .filter(|(_, gain)| *gain > instrument::LOUD_GAIN)
+            .map(|(name, gain)| {
+                format!("{} {} is above {} and will likely clip", name, gain, instrument::LOUD_GAIN)
+            })
+            .collect()
+    }
+
+    /// The engine's buses, reverb and master volume for this song
+    pub fn mix(&self) -> synth::Mix {
+        synth::Mix {
+            buses: self.buses.iter().map(Bus::to_bus).collect(),
+            reverb: self.reverb.clone(),
+            master_volume: self.master_volume,
+        }
+    }
+
+    /// Keep only the audible tracks given `--solo` or `--mute` selections
+    /// (track indices or names). Solo and mute cannot be combined.
+    pub fn select_tracks(&self, solo: &[String], mute: &[String]) -> Result<Song, String> {
+        if !solo.is_empty() && !mute.is_empty() {
+            return Err("--solo and --mute cannot be used together".to_string());
+        }
+        let mut keep = vec![solo.is_empty(); self.tracks.len()];
+        for spec in solo {
+            keep[self.find_track(spec)?] = true;
+        }
+        for spec in mute {
+            keep[self.find_track(spec)?] = false;
+        }
+        let tracks: Vec<SongTrack> = self
+            .tracks
+            .iter()
+            .zip(&keep)
+            .filter(|(_, keep)| **keep)
+            .map(|(t, _)| t.clone())
+            .collect();
+        if tracks.is_empty() {
+            return Err("all tracks are muted".to_string());
+        }
+        // Automation follows its track to its new index, or goes with it
+        let mut kept = 0;
+        let index: Vec<Option<usize>> = keep
+            .iter()
+            .map(|&keep| {
+                kept += keep as usize;
+                keep.then(|| kept - 1)
+            })
+            .collect();
+        let automation = self
+            .automation
+            .iter()
+            .filter_map(|lane| {
+                Some(Automation {
+                    track: index[lane.track]?,
+                    ..lane.clone()
+                })
+            })
+            .collect();
+        Ok(Song {
+            tracks,
+            automation,
+            ..self.clone()
+        })
+    }
+
+    /// Resolve a track index or (case-insensitive) name to an index.
+    pub fn find_track(&self, spec: &str) -> Result<usize, String> {
+        if let Ok(idx) = spec.parse::<usize>() {
+            if idx < self.tracks.len() {
+                return Ok(idx);
+            }
+            return Err(format!(
+                "track index {} out of range; available tracks: {}",
+                idx,
+                self.track_list()
+            ));
+        }
+        let matches: Vec<usize> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.name.eq_ignore_ascii_case(spec))
+            .map(|(i, _)| i)
+            .collect();
+        match matches.as_slice() {
+            [idx] => Ok(*idx),
+            [] => Err(format!(
+                "no track named '{}'; available tracks: {}",
+                spec,
+                self.track_list()
+            )),
+            _ => Err(format!(
+                "track name '{}' is ambiguous (use an index); available tracks: {}",
+                spec,
+                self.track_list()
+            )),
+        }
+    }
+
+    /// Name of the section played at arrangement position `slot`
+    pub fn slot_name(&self, slot: usize) -> &str {
+        self.arrangement
+            .as_ref()
+            .and_then(|a| a.sections.get(slot))
+            .map_or("?", |&idx| self.sections[idx].name.as_str())
+    }
+
+    /// "0: bass, 1: lead" style listing for error messages.
+    fn track_list(&self) -> String {
+        self.tracks
+            .iter()
+            .enumerate()
+            .map(|(i, t)| format!("{}: {}", i, t.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Track name from an instrument path ("bass.instr" → "bass").
+fn default_track_name(instrument_path: &Path) -> String {
+    instrument_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Parse a transpose amount in semitones ("+5", "-12", "3").
+fn parse_transpose(value: &str) -> Option<i8> {
+    let value = value.trim();
+    value.strip_prefix('+').unwrap_or(value).parse().ok()
+}
+
+/// Parse the value of an `instrument:` line: a path, or `name { key: value, ... }`.
+/// Returns the source and the track's default name.
+fn parse_instrument(
+    value: &str,
+    base: &Path,
+    line_num: usize,
+) -> Result<(InstrumentSource, String), String> {
+    let Some((name, body)) = value.split_once('{') else {
+        let path = base.join(value);
+        let name = default_track_name(&path);
+        return Ok((InstrumentSource::File(path), name));
+    };
+    let body = body.trim_end().strip_suffix('}').ok_or_else(|| {
+        format!("line {}: inline instrument is missing its closing '}}'", line_num + 1)
+    })?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("line {}: inline instrument needs a name before '{{'", line_num + 1));
+    }
+    let instrument = instrument::parse_inline(body, line_num).map_err(|problems| {
+        let messages: Vec<String> = problems
+            .iter()
+            .map(|p| format!("{} at line {}", p.message, line_num + 1))
+            .collect();
+        messages.join("; ")
+    })?;
+    let source = InstrumentSource::Inline {
+        line: line_num + 1,
+        instrument: Box::new(instrument),
+    };
+    Ok((source, name.to_string()))
+}
+
+/// A sequence line's path, repeat count, transpose and placeholder length
+type SequenceLine = (String, u32, i8, Option<f64>);
+
+/// Parse "file.notes * 4", "file.notes" (times = 1), optionally followed by
+/// "transpose +5" and "length 16" (beats), in either order. Returns
+/// Ok(None) for blank and comment lines.
+fn parse_sequence_line(line: &str) -> Result<Option<SequenceLine>, String> {
     let trimmed = line.trim();
     if trimmed.is_empty() || trimmed.starts_with('#') {
-        return None;
+        return Ok(None);
+    }
+    let mut rest = trimmed;
+    let mut transpose = 0;
+    let mut length = None;
+    // Take the options off the end, the last one first
+    while let Some((idx, option)) = [" transpose ", " length "]
+        .into_iter()
+        .filter_map(|option| rest.rfind(option).map(|idx| (idx, option)))
+        .max()
+    {
+        let value = rest[idx + option.len()..].trim();
+        if option == " transpose " {
+            transpose =
+                parse_transpose(value).ok_or_else(|| format!("invalid transpose '{}'", value))?;
+        } else {
+            let beats = value.parse().ok().filter(|b: &f64| b.is_finite() && *b > 0.0);
+            length = Some(beats.ok_or_else(|| {
+                format!("invalid length '{}' (expected beats above 0)", value)
+            })?);
+        }
+        rest = rest[..idx].trim_end();
     }
-    let (path, times) = if let Some((left, right)) = trimmed.split_once('*') {
+    let (path, times) = if let Some((left, right)) = rest.split_once('*') {
         let path = left.trim();
         let times = right.trim().parse::<u32>().unwrap_or(1);
         (path, times)
     } else {
-        (trimmed, 1)
+        (rest, 1)
     };
     if path.is_empty() {
-        return None;
+        return Ok(None);
+    }
+    Ok(Some((path.to_string(), times, transpose, length)))
+}
+
+/// Parse a bus declaration `drums { gain: 0.8 }`
+fn parse_bus(value: &str, line_num: usize) -> Result<Bus, String> {
+    let (name, body) = value.split_once('{').ok_or_else(|| {
+        format!("line {}: expected 'bus: name {{ gain: 0.8 }}'", line_num + 1)
+    })?;
+    let body = body.trim_end().strip_suffix('}').ok_or_else(|| {
+        format!("line {}: bus is missing its closing '}}'", line_num + 1)
+    })?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("line {}: invalid bus name '{}'", line_num + 1, name));
+    }
+    let mut bus = Bus {
+        name: name.to_string(),
+        gain: 1.0,
+    };
+    for part in body.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = part.split_once(':').ok_or_else(|| {
+            format!("line {}: expected 'key: value', got '{}'", line_num + 1, part)
+        })?;
+        let value = value.trim();
+        match key.trim() {
+            "gain" => {
+                bus.gain = value.parse().ok().filter(|g: &f64| *g >= 0.0).ok_or_else(|| {
+                    format!("invalid bus gain '{}' at line {}", value, line_num + 1)
+                })?;
+            }
+            other => {
+                return Err(format!("line {}: unknown bus setting '{}'", line_num + 1, other));
+            }
+        }
+    }
+    Ok(bus)
+}
+
+/// Parse a level from 0 to 1 given for `key` (`reverb_mix:`, `reverb_send:`, ...)
+fn parse_level(key: &str, value: &str, line_num: usize) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|v: &f64| (0.0..=1.0).contains(v))
+        .ok_or_else(|| {
+            format!("invalid {} '{}' at line {} (expected 0 to 1)", key, value, line_num + 1)
+        })
+}
+
+/// A track's `offset:` or `offset_bars:` and its line, resolved to beats
+/// once the song's time signature is known
+struct Offset {
+    amount: f64,
+    bars: bool,
+    line: usize,
+}
+
+/// Parse an `offset:` or `offset_bars:` value: a number of beats or bars,
+/// not negative
+fn parse_offset(key: &str, value: &str, line_num: usize) -> Result<Offset, String> {
+    let amount = value
+        .parse()
+        .ok()
+        .filter(|v: &f64| v.is_finite() && *v >= 0.0)
+        .ok_or_else(|| {
+            format!(
+                "invalid {} '{}' at line {} (expected 0 or more {})",
+                key,
+                value,
+                line_num + 1,
+                if key == "offset" { "beats" } else { "bars" }
+            )
+        })?;
+    Ok(Offset {
+        amount,
+        bars: key == "offset_bars",
+        line: line_num + 1,
+    })
+}
+
+/// Set each track's offset in beats. Tracks of a song with sections start
+/// with their sections, so they can't have one.
+fn apply_offsets(
+    tracks: &mut [SongTrack],
+    offsets: &[Option<Offset>],
+    time_signature: (u8, u8),
+    arranged: bool,
+) -> Result<(), String> {
+    for (track, offset) in tracks.iter_mut().zip(offsets) {
+        let Some(offset) = offset else {
+            continue;
+        };
+        if arranged {
+            return Err(format!(
+                "line {}: track '{}' can't have an offset in a song with an 'arrangement:'",
+                offset.line, track.name
+            ));
+        }
+        let per_unit = if offset.bars { time_signature.0.max(1) as f64 } else { 1.0 };
+        track.offset = offset.amount * per_unit;
+    }
+    Ok(())
+}
+
+/// Resolve each track's `bus:` name (with its line) to an index into `buses`
+fn assign_buses(
+    tracks: &mut [SongTrack],
+    memberships: &[Option<(String, usize)>],
+    buses: &[Bus],
+) -> Result<(), String> {
+    for (track, membership) in tracks.iter_mut().zip(memberships) {
+        let Some((name, line)) = membership else {
+            continue;
+        };
+        let idx = buses.iter().position(|b| b.name == *name).ok_or_else(|| {
+            let known: Vec<&str> = buses.iter().map(|b| b.name.as_str()).collect();
+            if known.is_empty() {
+                format!("line {}: unknown bus '{}' (no buses are declared)", line, name)
+            } else {
+                format!("line {}: unknown bus '{}' (buses: {})", line, name, known.join(", "))
+            }
+        })?;
+        if track.output_channel.is_some() {
+            return Err(format!(
+                "line {}: track '{}' has an output_channel, so it can't also join bus '{}'",
+                line, track.name, name
+            ));
+        }
+        track.bus = Some(idx);
+    }
+    Ok(())
+}
+
+/// Parse an `automate:` value, `track=1 param=cutoff from=400 to=4000
+/// start_bar=9 end_bar=17`, into the track it names (an index or a name,
+/// resolved once every track is known) and the lane
+fn parse_automation(value: &str, line_num: usize) -> Result<(String, Automation), String> {
+    let line = line_num + 1;
+    let mut fields: Vec<(&str, &str)> = Vec::new();
+    for part in value.split_whitespace() {
+        let (key, value) = part.split_once('=').ok_or_else(|| {
+            format!("line {}: expected 'key=value' in automate:, got '{}'", line, part)
+        })?;
+        if !["track", "param", "from", "to", "start_bar", "end_bar"].contains(&key) {
+            return Err(format!(
+                "line {}: unknown automate setting '{}' (expected track, param, from, to, \
+                 start_bar and end_bar)",
+                line, key
+            ));
+        }
+        fields.push((key, value));
+    }
+    let field = |key: &str| {
+        fields
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| *v)
+            .ok_or_else(|| format!("line {}: automate: is missing {}=", line, key))
+    };
+    let track = field("track")?.to_string();
+    let name = field("param")?;
+    let param = Param::from_name(name).ok_or_else(|| {
+        let known: Vec<&str> = Param::ALL.iter().map(|p| p.name()).collect();
+        format!("line {}: unknown param '{}' (expected {})", line, name, known.join(", "))
+    })?;
+    let level = |key: &str| -> Result<f64, String> {
+        let text = field(key)?;
+        let value: f64 = text
+            .parse()
+            .map_err(|_| format!("line {}: invalid {} '{}'", line, key, text))?;
+        param.check(value).map_err(|expected| {
+            let name = param.name();
+            format!("line {}: invalid {} {} '{}' (expected {})", line, name, key, text, expected)
+        })?;
+        Ok(value)
+    };
+    let bar = |key: &str| -> Result<u32, String> {
+        let text = field(key)?;
+        text.parse()
+            .ok()
+            .filter(|&bar: &u32| bar >= 1)
+            .ok_or_else(|| format!("line {}: invalid {} '{}' (bars count from 1)", line, key, text))
+    };
+    let (start_bar, end_bar) = (bar("start_bar")?, bar("end_bar")?);
+    if end_bar <= start_bar {
+        return Err(format!("line {}: end_bar must come after start_bar", line));
+    }
+    let lane = Automation {
+        track: 0,
+        param,
+        from: level("from")?,
+        to: level("to")?,
+        start_bar,
+        end_bar,
+    };
+    Ok((track, lane))
+}
+
+/// Point each `automate:` lane (with its track spec and line) at its
+/// track, checking the track has the setting and that lanes moving the
+/// same setting don't overlap
+fn resolve_automation(
+    song: &Song,
+    lanes: Vec<(String, Automation, usize)>,
+) -> Result<Vec<Automation>, String> {
+    let mut resolved: Vec<Automation> = Vec::with_capacity(lanes.len());
+    for (spec, mut lane, line) in lanes {
+        lane.track = song.find_track(&spec).map_err(|e| format!("line {}: {}", line, e))?;
+        let track = &song.tracks[lane.track];
+        if track.param_value(lane.param).is_none() {
+            return Err(format!(
+                "line {}: track '{}' has no lowpass or highpass in its effects: for cutoff to move",
+                line, track.name
+            ));
+        }
+        let overlaps = resolved.iter().any(|other| {
+            other.track == lane.track
+                && other.param == lane.param
+                && other.start_bar < lane.end_bar
+                && lane.start_bar < other.end_bar
+        });
+        if overlaps {
+            return Err(format!(
+                "line {}: automation of {} on track '{}' overlaps an earlier automate: line",
+                line,
+                lane.param.name(),
+                track.name
+            ));
+        }
+        resolved.push(lane);
+    }
+    Ok(resolved)
+}
+
+/// Parse `section name { track: file.notes * 2, other: file.notes }` into
+/// the section name and its (track name, sequence line) bindings
+fn parse_section(value: &str, line_num: usize) -> Result<(String, Vec<(String, String)>), String> {
+    let (name, body) = value.split_once('{').ok_or_else(|| {
+        format!("line {}: expected 'section name {{ track: file.notes, ... }}'", line_num + 1)
+    })?;
+    let body = body.trim_end().strip_suffix('}').ok_or_else(|| {
+        format!("line {}: section is missing its closing '}}'", line_num + 1)
+    })?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("line {}: invalid section name '{}'", line_num + 1, name));
+    }
+    let mut parts = Vec::new();
+    for part in body.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (track, sequence) = part.split_once(':').ok_or_else(|| {
+            format!("line {}: expected 'track: file.notes', got '{}'", line_num + 1, part)
+        })?;
+        parts.push((track.trim().to_string(), sequence.trim().to_string()));
+    }
+    Ok((name.to_string(), parts))
+}
+
+/// Expand sections into the tracks' sequences in `arrangement` order
+fn arrange(
+    tracks: &mut [SongTrack],
+    sections: &[(Section, Vec<(String, String)>)],
+    names: &[(String, usize)],
+    base: &Path,
+) -> Result<Vec<usize>, String> {
+    let mut order = Vec::with_capacity(names.len());
+    for (name, line) in names {
+        let idx = sections.iter().position(|(s, _)| s.name == *name).ok_or_else(|| {
+            let known: Vec<&str> = sections.iter().map(|(s, _)| s.name.as_str()).collect();
+            format!(
+                "line {}: unknown section '{}' in arrangement (sections: {})",
+                line,
+                name,
+                known.join(", ")
+            )
+        })?;
+        order.push(idx);
+    }
+    for (slot, &idx) in order.iter().enumerate() {
+        let (section, parts) = &sections[idx];
+        for (track_name, sequence) in parts {
+            let track = tracks
+                .iter_mut()
+                .find(|t| t.name.eq_ignore_ascii_case(track_name))
+                .ok_or_else(|| {
+                    format!(
+                        "line {}: section '{}' names unknown track '{}'",
+                        section.line, section.name, track_name
+                    )
+                })?;
+            let parsed = parse_sequence_line(sequence)
+                .map_err(|e| format!("line {}: {}", section.line, e))?;
+            if let Some((path, times, transpose, length)) = parsed {
+                track.sequence.push(Segment {
+                    notes_path: base.join(&path),
+                    times,
+                    transpose,
+                    slot: Some(slot),
+                    length,
+                });
+            }
+        }
     }
-    Some((path.to_string(), times))
+    Ok(order)
 }
 
 /// Load a song from a `.song` file.
@@ -68,28 +861,106 @@ fn parse_sequence_line(line: &str) -> Option<(String, u32)> {
 /// verse.notes * 4
 /// chorus.notes * 4
 /// instrument: lead.instr
+/// name: lead
 /// melody.notes * 8
 /// ```
-/// Paths are relative to the directory containing the .song file.
-pub fn load(song_path: &Path) -> Result<Song, String> {
-    let content = fs::read_to_string(song_path)
-        .map_err(|e| format!("reading song file: {}", e))?;
+/// Paths are relative to the directory containing the .song file. `name:`
+/// after an `instrument:` line names that track (default: instrument file stem).
+/// An instrument can also be defined inline with the `.instr` keys, which
+/// names the track: `instrument: lead { attack: 0.01, release: 0.3 }`.
+/// A sequence line may end in `transpose +5` to shift that segment by
+/// semitones, and a top-level `transpose: -2` shifts the whole song.
+/// A `time_signature:` between a track's sequence lines changes the meter
+/// from the next segment on.
+/// `fade_in:` and `fade_out:` give fade lengths in seconds, and
+/// `align: pad|loop|truncate` sets how tracks of different lengths end.
+/// `reverb_mix:`, `reverb_size:` and `reverb_damping:` (0 to 1) set up a
+/// reverb on the master mix, and `reverb_send:` after an `instrument:`
+/// sets how much of that track goes into it (default 1). `master_volume:`
+/// scales the whole mix (default 1). `offset: 32` after an `instrument:`
+/// starts that track 32 beats in (`offset_bars: 8`: eight bars in).
+/// `effects: lowpass(1200, 0.5) > pan(-0.2)` after an `instrument:` runs
+/// that track through the effects in the order written.
+///
+/// Instead of sequence lines, a song can declare its tracks and then bind
+/// them to patterns in named sections, played in the order given by
+/// `arrangement:`. Tracks stay aligned at the start of every section;
+/// `section_align: pad|error` says whether parts of a section may differ in
+/// length:
+/// ```text
+/// instrument: bass.instr
+/// instrument: lead.instr
+/// section verse { bass: verse.notes * 2, lead: melody.notes * 2 }
+/// section chorus { bass: chorus.notes * 2 }
+/// arrangement: verse chorus verse
+/// ```
+pub fn load(song_path: &Path) -> Result<Song, ClidawError> {
+    let content = fs::read_to_string(song_path).map_err(|e| ClidawError::io(song_path, e))?;
 
     let base = song_path
         .parent()
         .unwrap_or_else(|| Path::new("."));
+    parse(&content, base).map_err(|msg| ClidawError::Song {
+        file: song_path.to_path_buf(),
+        msg,
+    })
+}
 
-    let mut tempo = 120u32;
+/// Parse `.song` contents; paths are resolved against `base`.
+fn parse(content: &str, base: &Path) -> Result<Song, String> {
+    let mut tempo = 120.0;
     let mut time_signature = (4u8, 4u8);
+    let mut transpose = 0i8;
+    let mut fade_in = 0.0_f64;
+    let mut fade_out = 0.0_f64;
+    let mut align = Align::Pad;
+    let mut missing = Missing::Error;
+    let mut prefer_flats = false;
+    let mut metadata = Metadata::default();
+    let mut section_align = None;
+    let mut sections: Vec<(Section, Vec<(String, String)>)> = Vec::new();
+    let mut arrangement: Option<Vec<(String, usize)>> = None;
+    // Line of the first sequence line, which can't be mixed with sections
+    let mut first_sequence_line = None;
     let mut tracks: Vec<SongTrack> = Vec::new();
-    let mut current_instrument: Option<PathBuf> = None;
+    // Current track's instrument and default name
+    let mut current_instrument: Option<(InstrumentSource, String)> = None;
+    let mut current_name: Option<String> = None;
+    let mut current_output: Option<u16> = None;
+    let mut current_send: Option<f64> = None;
+    let mut reverb = Reverb::default();
+    let mut master_volume = 1.0;
+    let mut buses: Vec<Bus> = Vec::new();
+    // Each track's `bus:` name and its line, checked once every bus is known
+    let mut memberships: Vec<Option<(String, usize)>> = Vec::new();
+    let mut current_bus: Option<(String, usize)> = None;
+    // Each track's offset, resolved once the time signature is final
+    let mut offsets: Vec<Option<Offset>> = Vec::new();
+    let mut current_offset: Option<Offset> = None;
+    let mut current_effects: Option<Vec<Effect>> = None;
     let mut current_sequence: Vec<Segment> = Vec::new();
+    let mut current_meter: Vec<SegmentMeter> = Vec::new();
+    // `automate:` lanes with their track spec and line, resolved once every
+    // track is known
+    let mut automation: Vec<(String, Automation, usize)> = Vec::new();
 
     for (line_num, line) in content.lines().enumerate() {
+        if let Some(value) = line.trim().strip_prefix("section ") {
+            let (name, parts) = parse_section(value, line_num)?;
+            if sections.iter().any(|(s, _)| s.name == name) {
+                return Err(format!("line {}: section '{}' is defined twice", line_num + 1, name));
+            }
+            let section = Section {
+                name,
+                line: line_num + 1,
+            };
+            sections.push((section, parts));
+            continue;
+        }
         if let Some((key, value)) = parse_kv(line) {
             match key {
                 "tempo" => {
-                    tempo = value.parse().map_err(|_| {
+                    tempo = tempo::parse(value).ok_or_else(|| {
                         format!("invalid tempo '{}' at line {}", value, line_num + 1)
                     })?;
                 }
@@ -102,30 +973,220 @@ pub fn load(song_path: &Path) -> Result<Song, String> {
                         let den: u8 = parts[1].trim().parse().map_err(|_| {
                             format!("invalid time_signature at line {}", line_num + 1)
                         })?;
-                        time_signature = (num, den);
+                        // Between a track's sequence lines it changes the meter there
+                        match current_instrument {
+                            Some(_) if !current_sequence.is_empty() => {
+                                current_meter.push(SegmentMeter {
+                                    segment: current_sequence.len(),
+                                    signature: (num, den),
+                                });
+                            }
+                            _ => time_signature = (num, den),
+                        }
                     }
                 }
-                "instrument" => {
-                    if let Some(inst) = current_instrument.take() {
-                        if !current_sequence.is_empty() {
-                            tracks.push(SongTrack {
-                                instrument_path: inst,
-                                sequence: std::mem::take(&mut current_sequence),
-                            });
+                "transpose" => {
+                    transpose = parse_transpose(value).ok_or_else(|| {
+                        format!("invalid transpose '{}' at line {}", value, line_num + 1)
+                    })?;
+                }
+                "fade_in" | "fade_out" => {
+                    let secs: f64 = value
+                        .parse()
+                        .ok()
+                        .filter(|s: &f64| *s >= 0.0)
+                        .ok_or_else(|| {
+                            format!("invalid {} '{}' at line {}", key, value, line_num + 1)
+                        })?;
+                    if key == "fade_in" {
+                        fade_in = secs;
+                    } else {
+                        fade_out = secs;
+                    }
+                }
+                "align" => {
+                    align = Align::from_name(value).ok_or_else(|| {
+                        format!(
+                            "invalid align '{}' at line {} (expected pad, loop or truncate)",
+                            value,
+                            line_num + 1
+                        )
+                    })?;
+                }
+                "missing" => {
+                    missing = match value {
+                        "error" => Missing::Error,
+                        "skip" => Missing::Skip,
+                        _ => {
+                            return Err(format!(
+                                "invalid missing '{}' at line {} (expected error or skip)",
+                                value,
+                                line_num + 1
+                            ));
                         }
+                    };
+                }
+                "prefer_flats" => {
+                    prefer_flats = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => {
+                            return Err(format!(
+                                "invalid prefer_flats '{}' at line {} (expected true or false)",
+                                value,
+                                line_num + 1
+                            ));
+                        }
+                    };
+                }
+                "section_align" => {
+                    section_align = Some(match value {
+                        "pad" => SectionAlign::Pad,
+                        "error" => SectionAlign::Error,
+                        _ => {
+                            return Err(format!(
+                                "invalid section_align '{}' at line {} (expected pad or error)",
+                                value,
+                                line_num + 1
+                            ));
+                        }
+                    });
+                }
+                "arrangement" => {
+                    let names = value.split_whitespace().map(|n| (n.to_string(), line_num + 1));
+                    arrangement = Some(names.collect());
+                }
+                "instrument" => {
+                    // Tracks of a song with sections have no sequence lines
+                    if let Some((inst, default_name)) = current_instrument.take() {
+                        tracks.push(SongTrack {
+                            name: current_name.take().unwrap_or(default_name),
+                            instrument: inst,
+                            sequence: std::mem::take(&mut current_sequence),
+                            output_channel: current_output.take(),
+                            bus: None,
+                            reverb_send: current_send.take().unwrap_or(1.0),
+                            meter_changes: std::mem::take(&mut current_meter),
+                            offset: 0.0,
+                            effects: current_effects.take().unwrap_or_default(),
+                        });
+                        memberships.push(current_bus.take());
+                        offsets.push(current_offset.take());
+                    }
+                    current_name = None;
+                    current_output = None;
+                    current_send = None;
+                    current_bus = None;
+                    current_offset = None;
+                    current_effects = None;
+                    current_instrument = Some(parse_instrument(value, base, line_num)?);
+                }
+                "name" => {
+                    if current_instrument.is_none() {
+                        return Err(format!(
+                            "line {}: 'name:' before any 'instrument:'",
+                            line_num + 1
+                        ));
+                    }
+                    current_name = Some(value.to_string());
+                }
+                "output_channel" => {
+                    if current_instrument.is_none() {
+                        return Err(format!(
+                            "line {}: 'output_channel:' before any 'instrument:'",
+                            line_num + 1
+                        ));
+                    }
+                    current_output = Some(value.parse().map_err(|_| {
+                        format!(
+                            "invalid output_channel '{}' at line {} (expected a channel number \
+                             counting from 0)",
+                            value,
+                            line_num + 1
+                        )
+                    })?);
+                }
+                "title" | "author" | "description" => {
+                    metadata.set(key, value);
+                }
+                "master_volume" => {
+                    master_volume = value.parse().ok().filter(|v: &f64| *v >= 0.0).ok_or_else(|| {
+                        format!("invalid master_volume '{}' at line {}", value, line_num + 1)
+                    })?;
+                }
+                "reverb_mix" => reverb.mix = parse_level(key, value, line_num)?,
+                "reverb_size" => reverb.size = parse_level(key, value, line_num)?,
+                "reverb_damping" => reverb.damping = parse_level(key, value, line_num)?,
+                "reverb_send" => {
+                    if current_instrument.is_none() {
+                        return Err(format!(
+                            "line {}: 'reverb_send:' before any 'instrument:'",
+                            line_num + 1
+                        ));
+                    }
+                    current_send = Some(parse_level(key, value, line_num)?);
+                }
+                "bus" if value.contains('{') => {
+                    let bus = parse_bus(value, line_num)?;
+                    if buses.iter().any(|b| b.name == bus.name) {
+                        return Err(format!(
+                            "line {}: bus '{}' is defined twice",
+                            line_num + 1,
+                            bus.name
+                        ));
+                    }
+                    buses.push(bus);
+                }
+                "bus" => {
+                    if current_instrument.is_none() {
+                        return Err(format!(
+                            "line {}: 'bus:' before any 'instrument:'",
+                            line_num + 1
+                        ));
+                    }
+                    current_bus = Some((value.to_string(), line_num + 1));
+                }
+                "offset" | "offset_bars" => {
+                    if current_instrument.is_none() {
+                        return Err(format!(
+                            "line {}: '{}:' before any 'instrument:'",
+                            line_num + 1,
+                            key
+                        ));
+                    }
+                    current_offset = Some(parse_offset(key, value, line_num)?);
+                }
+                "effects" => {
+                    if current_instrument.is_none() {
+                        return Err(format!(
+                            "line {}: 'effects:' before any 'instrument:'",
+                            line_num + 1
+                        ));
                     }
-                    current_instrument = Some(base.join(value));
+                    let chain = effects::parse_chain(value)
+                        .map_err(|e| format!("line {}: {}", line_num + 1, e))?;
+                    current_effects = Some(chain);
+                }
+                "automate" => {
+                    let (track, lane) = parse_automation(value, line_num)?;
+                    automation.push((track, lane, line_num + 1));
                 }
                 _ => {}
             }
             continue;
         }
 
-        if let Some((path, times)) = parse_sequence_line(line) {
+        let parsed = parse_sequence_line(line)
+            .map_err(|e| format!("line {}: {}", line_num + 1, e))?;
+        if let Some((path, times, seg_transpose, length)) = parsed {
+            first_sequence_line.get_or_insert(line_num + 1);
             if current_instrument.is_some() {
                 current_sequence.push(Segment {
                     notes_path: base.join(&path),
                     times,
+                    transpose: seg_transpose,
+                    slot: None,
+                    length,
                 });
             } else {
                 return Err(format!(
@@ -137,22 +1198,587 @@ pub fn load(song_path: &Path) -> Result<Song, String> {
         }
     }
 
-    if let Some(inst) = current_instrument.take() {
-        if !current_sequence.is_empty() {
-            tracks.push(SongTrack {
-                instrument_path: inst,
-                sequence: current_sequence,
-            });
-        }
+    if let Some((inst, default_name)) = current_instrument.take() {
+        tracks.push(SongTrack {
+            name: current_name.unwrap_or(default_name),
+            instrument: inst,
+            sequence: current_sequence,
+            output_channel: current_output,
+            bus: None,
+            reverb_send: current_send.unwrap_or(1.0),
+            meter_changes: current_meter,
+            offset: 0.0,
+            effects: current_effects.unwrap_or_default(),
+        });
+        memberships.push(current_bus);
+        offsets.push(current_offset);
     }
+    assign_buses(&mut tracks, &memberships, &buses)?;
+    apply_offsets(&mut tracks, &offsets, time_signature, arrangement.is_some())?;
+
+    let arrangement = match arrangement {
+        Some(names) => {
+            if let Some(line) = first_sequence_line {
+                return Err(format!(
+                    "line {}: sequence lines can't be mixed with an 'arrangement:'",
+                    line
+                ));
+            }
+            let sections = arrange(&mut tracks, &sections, &names, base)?;
+            // A track no section gives a part has nothing to play
+            tracks.retain(|t| !t.sequence.is_empty());
+            Some(Arrangement {
+                sections,
+                align: section_align.unwrap_or_default(),
+            })
+        }
+        None => {
+            if let Some((section, _)) = sections.first() {
+                return Err(format!(
+                    "line {}: section '{}' is never played (add an 'arrangement:' line)",
+                    section.line, section.name
+                ));
+            }
+            tracks.retain(|t| !t.sequence.is_empty());
+            None
+        }
+    };
 
     if tracks.is_empty() {
         return Err("song has no tracks (need 'instrument:' followed by 'file.notes * N' lines)".to_string());
     }
 
-    Ok(Song {
+    let mut song = Song {
         tempo,
         time_signature,
+        transpose,
+        fade_in,
+        fade_out,
+        align,
         tracks,
-    })
+        buses,
+        reverb,
+        master_volume,
+        automation: Vec::new(),
+        sections: sections.into_iter().map(|(section, _)| section).collect(),
+        arrangement,
+        missing,
+        prefer_flats,
+        metadata,
+        seed: 0,
+    };
+    song.automation = resolve_automation(&song, automation)?;
+    Ok(song)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn track(name: &str) -> SongTrack {
+        SongTrack {
+            name: name.to_string(),
+            instrument: InstrumentSource::File(PathBuf::from(format!("{}.instr", name))),
+            sequence: Vec::new(),
+            output_channel: None,
+            bus: None,
+            reverb_send: 1.0,
+            meter_changes: Vec::new(),
+            offset: 0.0,
+            effects: Vec::new(),
+        }
+    }
+
+    fn song() -> Song {
+        Song {
+            tempo: 120.0,
+            time_signature: (4, 4),
+            transpose: 0,
+            fade_in: 0.0,
+            fade_out: 0.0,
+            align: Align::Pad,
+            tracks: vec![track("bass"), track("lead"), track("pad"), track("drums")],
+            buses: Vec::new(),
+            reverb: Reverb::default(),
+            master_volume: 1.0,
+            automation: Vec::new(),
+            sections: Vec::new(),
+            arrangement: None,
+            missing: Missing::Error,
+            prefer_flats: false,
+            metadata: Metadata::default(),
+            seed: 0,
+        }
+    }
+
+    fn names(song: &Song) -> Vec<&str> {
+        song.tracks.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    fn specs(specs: &[&str]) -> Vec<String> {
+        specs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_mute_by_index_and_name() {
+        let selected = song().select_tracks(&[], &specs(&["0", "PAD"])).unwrap();
+        assert_eq!(names(&selected), vec!["lead", "drums"]);
+    }
+
+    #[test]
+    fn test_solo_multiple_tracks() {
+        let selected = song().select_tracks(&specs(&["drums", "1"]), &[]).unwrap();
+        assert_eq!(names(&selected), vec!["lead", "drums"]);
+    }
+
+    #[test]
+    fn test_solo_with_mute_is_an_error() {
+        assert!(song().select_tracks(&specs(&["0"]), &specs(&["1"])).is_err());
+    }
+
+    #[test]
+    fn test_unknown_track_lists_available() {
+        let err = song().select_tracks(&specs(&["7"]), &[]).unwrap_err();
+        assert!(err.contains("0: bass, 1: lead, 2: pad, 3: drums"), "{}", err);
+        let err = song().find_track("vocals").unwrap_err();
+        assert!(err.contains("no track named 'vocals'"), "{}", err);
+    }
+
+    #[test]
+    fn test_sequence_line_transpose() {
+        assert_eq!(
+            parse_sequence_line("verse.notes * 2 transpose +5").unwrap(),
+            Some(("verse.notes".to_string(), 2, 5, None))
+        );
+        assert_eq!(
+            parse_sequence_line("verse.notes transpose -12").unwrap(),
+            Some(("verse.notes".to_string(), 1, -12, None))
+        );
+        assert_eq!(
+            parse_sequence_line("verse.notes * 4").unwrap(),
+            Some(("verse.notes".to_string(), 4, 0, None))
+        );
+        assert!(parse_sequence_line("verse.notes * 2 transpose up").is_err());
+    }
+
+    #[test]
+    fn test_inline_and_file_instruments() {
+        let content = "instrument: lead { attack: 0.02, release: 0.4 }\n\
+                       melody.notes * 2\n\
+                       instrument: bass.instr\n\
+                       verse.notes\n\
+                       instrument: pad {}\n\
+                       name: strings\n\
+                       chords.notes\n";
+        let song = parse(content, Path::new("songs")).unwrap();
+        assert_eq!(names(&song), vec!["lead", "bass", "strings"]);
+        match &song.tracks[0].instrument {
+            InstrumentSource::Inline { line, instrument } => {
+                assert_eq!(*line, 1);
+                assert_eq!(instrument.attack, 0.02);
+                assert_eq!(instrument.release, 0.4);
+            }
+            other => panic!("expected inline instrument, got {:?}", other),
+        }
+        assert!(matches!(
+            &song.tracks[1].instrument,
+            InstrumentSource::File(path) if path == Path::new("songs/bass.instr")
+        ));
+        assert_eq!(song.tracks[0].sequence[0].notes_path, Path::new("songs/melody.notes"));
+    }
+
+    #[test]
+    fn test_output_channel_belongs_to_its_track() {
+        let content = "instrument: bass.instr\noutput_channel: 3\nverse.notes\n\
+                       instrument: lead.instr\nmelody.notes\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        let outputs: Vec<Option<u16>> = song.tracks.iter().map(|t| t.output_channel).collect();
+        assert_eq!(outputs, vec![Some(3), None]);
+
+        let err = parse("output_channel: 2\ninstrument: a.instr\nv.notes\n", Path::new("."));
+        assert_eq!(err.unwrap_err(), "line 1: 'output_channel:' before any 'instrument:'");
+        let err = parse("instrument: a.instr\noutput_channel: left\nv.notes\n", Path::new("."));
+        assert!(err.unwrap_err().starts_with("invalid output_channel 'left' at line 2"));
+    }
+
+    #[test]
+    fn test_tracks_join_buses() {
+        let content = "instrument: kick.instr\nbus: drums\nk.notes\n\
+                       instrument: hat.instr\nbus: drums\nh.notes\n\
+                       instrument: lead.instr\nmelody.notes\n\
+                       bus: drums { gain: 0.8 }\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        let buses: Vec<Option<usize>> = song.tracks.iter().map(|t| t.bus).collect();
+        assert_eq!(buses, vec![Some(0), Some(0), None]);
+        assert_eq!((song.buses[0].name.as_str(), song.buses[0].gain), ("drums", 0.8));
+    }
+
+    #[test]
+    fn test_loud_gains() {
+        let content = "master_volume: 3\nbus: drums { gain: 2.5 }\nbus: keys { gain: 2 }\n\
+                       instrument: lead.instr\nmelody.notes\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        assert_eq!(
+            song.loud_gains(false),
+            [
+                "bus 'drums' gain 2.5 is above 2 and will likely clip",
+                "master_volume 3 is above 2 and will likely clip",
+            ]
+        );
+        // --master-volume replaces the song's
+        assert_eq!(song.loud_gains(true).len(), 1);
+    }
+
+    #[test]
+    fn test_track_offsets() {
+        let content = "time_signature: 3/4\ninstrument: bass.instr\nv.notes\n\
+                       instrument: lead.instr\noffset: 6.5\nm.notes\n\
+                       instrument: pad.instr\noffset_bars: 8\np.notes\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        let offsets: Vec<f64> = song.tracks.iter().map(|t| t.offset).collect();
+        assert_eq!(offsets, vec![0.0, 6.5, 24.0]);
+
+        let err = |content: &str| parse(content, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err("instrument: a.instr\noffset: -4\nv.notes\n"),
+            "invalid offset '-4' at line 2 (expected 0 or more beats)"
+        );
+        assert_eq!(err("offset_bars: 2\n"), "line 1: 'offset_bars:' before any 'instrument:'");
+        assert_eq!(
+            err("instrument: a.instr\noffset: 4\nsection s { a: v.notes }\narrangement: s\n"),
+            "line 2: track 'a' can't have an offset in a song with an 'arrangement:'"
+        );
+    }
+
+    #[test]
+    fn test_track_effects() {
+        let content = "instrument: bass.instr\neffects: lowpass(800, 0.2) > pan(-0.5)\nv.notes\n\
+                       instrument: lead.instr\nm.notes\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        let bass = &song.tracks[0].effects;
+        assert_eq!(bass.len(), 2);
+        assert!(matches!(bass[0], Effect::Lowpass { cutoff: 800.0, .. }));
+        assert_eq!(bass[1], Effect::Pan(-0.5));
+        assert!(song.tracks[1].effects.is_empty());
+
+        let err = |content: &str| parse(content, Path::new(".")).unwrap_err();
+        assert_eq!(err("effects: gain(1)\n"), "line 1: 'effects:' before any 'instrument:'");
+        assert_eq!(
+            err("instrument: a.instr\nv.notes\neffects: delay(0.5)\n"),
+            "line 3: delay takes 3 values (time, feedback, mix), got 1"
+        );
+        let unknown = err("instrument: a.instr\neffects: fuzz(2)\n");
+        assert!(unknown.starts_with("line 2: unknown effect 'fuzz'"), "{}", unknown);
+    }
+
+    #[test]
+    fn test_automation_lanes() {
+        let content = "instrument: bass.instr\neffects: lowpass(800, 0.2)\nv.notes\n\
+                       instrument: lead.instr\nm.notes\n\
+                       automate: track=0 param=cutoff from=400 to=4000 start_bar=9 end_bar=17\n\
+                       automate: track=lead param=gain from=1 to=0 start_bar=1 end_bar=3\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        assert_eq!(song.automation.len(), 2);
+        let sweep = &song.automation[0];
+        assert_eq!((sweep.track, sweep.param), (0, Param::Cutoff));
+        assert_eq!((sweep.start_bar, sweep.end_bar), (9, 17));
+        // Cutoff moves evenly in pitch: halfway is the geometric mean
+        assert!((sweep.value_at(0.5) - 400.0 * 10f64.sqrt()).abs() < 1e-9);
+        assert_eq!(song.automation[1].track, 1);
+        assert_eq!(song.automation[1].value_at(0.25), 0.75);
+
+        // Muting a track takes its lanes with it; the rest follow theirs
+        let solo = song.select_tracks(&[], &["bass".to_string()]).unwrap();
+        assert_eq!(solo.automation.len(), 1);
+        assert_eq!((solo.automation[0].track, solo.automation[0].param), (0, Param::Gain));
+    }
+
+    #[test]
+    fn test_automation_errors() {
+        let err = |lane: &str| {
+            let content = format!("instrument: a.instr\nv.notes\nautomate: {}\n", lane);
+            parse(&content, Path::new(".")).unwrap_err()
+        };
+        let lane = "track=0 param=gain from=1 to=0";
+        assert_eq!(
+            err(&format!("{} start_bar=1 end_bar=2 curve=exp", lane)),
+            "line 3: unknown automate setting 'curve' (expected track, param, from, to, \
+             start_bar and end_bar)"
+        );
+        assert_eq!(err(lane), "line 3: automate: is missing start_bar=");
+        assert_eq!(
+            err("track=0 param=volume from=1 to=0 start_bar=1 end_bar=2"),
+            "line 3: unknown param 'volume' (expected gain, pan, cutoff)"
+        );
+        assert_eq!(
+            err("track=0 param=pan from=-2 to=0 start_bar=1 end_bar=2"),
+            "line 3: invalid pan from '-2' (expected -1 to 1)"
+        );
+        assert_eq!(
+            err(&format!("{} start_bar=4 end_bar=4", lane)),
+            "line 3: end_bar must come after start_bar"
+        );
+        assert_eq!(
+            err(&format!("{} start_bar=0 end_bar=4", lane)),
+            "line 3: invalid start_bar '0' (bars count from 1)"
+        );
+        assert_eq!(
+            err("track=1 param=gain from=1 to=0 start_bar=1 end_bar=2"),
+            "line 3: track index 1 out of range; available tracks: 0: a"
+        );
+        assert_eq!(
+            err("track=0 param=cutoff from=400 to=800 start_bar=1 end_bar=2"),
+            "line 3: track 'a' has no lowpass or highpass in its effects: for cutoff to move"
+        );
+        let content = "instrument: a.instr\nv.notes\n\
+                       automate: track=a param=pan from=0 to=1 start_bar=1 end_bar=5\n\
+                       automate: track=a param=pan from=1 to=0 start_bar=4 end_bar=8\n\
+                       automate: track=a param=gain from=1 to=0 start_bar=4 end_bar=8\n";
+        assert_eq!(
+            parse(content, Path::new(".")).unwrap_err(),
+            "line 4: automation of pan on track 'a' overlaps an earlier automate: line"
+        );
+    }
+
+    #[test]
+    fn test_missing_patterns() {
+        assert_eq!(
+            parse_sequence_line("intro.notes * 4 length 16 transpose -2").unwrap(),
+            Some(("intro.notes".to_string(), 4, -2, Some(16.0)))
+        );
+        assert_eq!(
+            parse_sequence_line("intro.notes transpose +3 length 2.5").unwrap(),
+            Some(("intro.notes".to_string(), 1, 3, Some(2.5)))
+        );
+        assert_eq!(
+            parse_sequence_line("intro.notes length 0").unwrap_err(),
+            "invalid length '0' (expected beats above 0)"
+        );
+
+        let dir = temp_dir("missing");
+        fs::write(dir.join("v.notes"), "a s d f\n").unwrap();
+        let content = "missing: skip\ninstrument: a.instr\nv.notes length 2\n\
+                       gap.notes * 2 length 8\ngone.notes\n";
+        let mut song = parse(content, &dir).unwrap();
+        assert_eq!(song.missing, Missing::Skip);
+        let skipped = song.skip_missing();
+        let beats: Vec<f64> = skipped.iter().map(|s| s.beats).collect();
+        assert_eq!(beats, [16.0, 0.0]);
+        assert_eq!(skipped[1].notes_path, dir.join("gone.notes"));
+        // The file that exists keeps its own length; the others play silence
+        let lengths: Vec<_> = song.tracks[0].sequence.iter().map(|s| s.length).collect();
+        assert_eq!(lengths, [Some(2.0), Some(8.0), Some(0.0)]);
+        assert!(skipped[0].to_string().ends_with("16 beats of silence play in its place"));
+
+        let err = parse("missing: maybe\n", &dir).unwrap_err();
+        assert_eq!(err, "invalid missing 'maybe' at line 1 (expected error or skip)");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bus_errors() {
+        let err = |content: &str| parse(content, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err("bus: drums { gain: 0.8 }\ninstrument: a.instr\nbus: perc\nv.notes\n"),
+            "line 3: unknown bus 'perc' (buses: drums)"
+        );
+        assert_eq!(
+            err("instrument: a.instr\nbus: drums\nv.notes\n"),
+            "line 2: unknown bus 'drums' (no buses are declared)"
+        );
+        assert_eq!(err("bus: drums\n"), "line 1: 'bus:' before any 'instrument:'");
+        assert_eq!(
+            err("bus: a { gain: 1 }\nbus: a { gain: 0.5 }\n"),
+            "line 2: bus 'a' is defined twice"
+        );
+        assert!(err("bus: a { gain: loud }\n").starts_with("invalid bus gain 'loud' at line 1"));
+        assert!(err("bus: a { pan: 0.5 }\n").contains("unknown bus setting 'pan'"));
+        assert!(
+            err("bus: d { }\ninstrument: a.instr\noutput_channel: 2\nbus: d\nv.notes\n")
+                .starts_with("line 4: track 'a' has an output_channel")
+        );
+    }
+
+    #[test]
+    fn test_reverb_settings_and_sends() {
+        let content = "reverb_mix: 0.3\nreverb_size: 0.8\n\
+                       instrument: a.instr\nreverb_send: 0.25\nv.notes\n\
+                       instrument: b.instr\nv.notes\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        assert_eq!((song.reverb.mix, song.reverb.size), (0.3, 0.8));
+        assert_eq!(song.reverb.damping, Reverb::default().damping);
+        let sends: Vec<f64> = song.tracks.iter().map(|t| t.reverb_send).collect();
+        assert_eq!(sends, vec![0.25, 1.0]);
+
+        let err = |content| parse(content, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err("reverb_mix: 1.5\ninstrument: a.instr\nv.notes\n"),
+            "invalid reverb_mix '1.5' at line 1 (expected 0 to 1)"
+        );
+        assert_eq!(
+            err("reverb_send: 0.5\ninstrument: a.instr\nv.notes\n"),
+            "line 1: 'reverb_send:' before any 'instrument:'"
+        );
+    }
+
+    #[test]
+    fn test_master_volume() {
+        let song = parse("master_volume: 0.8\ninstrument: a.instr\nv.notes\n", Path::new("."));
+        assert_eq!(song.unwrap().mix().master_volume, 0.8);
+        let song = parse("instrument: a.instr\nv.notes\n", Path::new(".")).unwrap();
+        assert_eq!(song.master_volume, 1.0);
+        let err = parse("master_volume: -1\ninstrument: a.instr\nv.notes\n", Path::new("."));
+        assert_eq!(err.unwrap_err(), "invalid master_volume '-1' at line 1");
+    }
+
+    #[test]
+    fn test_prefer_flats() {
+        let song = parse("prefer_flats: true\ninstrument: a.instr\nv.notes\n", Path::new("."));
+        assert!(song.unwrap().prefer_flats);
+        let song = parse("instrument: a.instr\nv.notes\n", Path::new(".")).unwrap();
+        assert!(!song.prefer_flats);
+        let err = parse("prefer_flats: yes\ninstrument: a.instr\nv.notes\n", Path::new("."));
+        assert_eq!(
+            err.unwrap_err(),
+            "invalid prefer_flats 'yes' at line 1 (expected true or false)"
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let content =
+            "title: Demo\nauthor: KM\ndescription: Two tracks\ninstrument: a.instr\nv.notes\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        assert_eq!(song.metadata.title.as_deref(), Some("Demo"));
+        let entries: Vec<_> = song.metadata.entries().collect();
+        assert_eq!(entries, [("title", "Demo"), ("author", "KM"), ("description", "Two tracks")]);
+        let song = parse("instrument: a.instr\nv.notes\n", Path::new(".")).unwrap();
+        assert_eq!(song.metadata, Metadata::default());
+    }
+
+    #[test]
+    fn test_fractional_tempo() {
+        let song = parse("tempo: 93.5
+instrument: a.instr
+v.notes
+", Path::new("."));
+        assert_eq!(song.unwrap().tempo, 93.5);
+        let song = parse("instrument: a.instr
+v.notes
+", Path::new(".")).unwrap();
+        assert_eq!(song.tempo, 120.0);
+        let err = parse("tempo: 0
+instrument: a.instr
+v.notes
+", Path::new("."));
+        assert_eq!(err.unwrap_err(), "invalid tempo '0' at line 1");
+    }
+
+    #[test]
+    fn test_time_signature_between_sequence_lines() {
+        let content = "time_signature: 4/4\ninstrument: a.instr\nv.notes * 2\n\
+                       time_signature: 7/8\nodd.notes * 2\ntime_signature: 4/4\nv.notes\n\
+                       instrument: b.instr\ntime_signature: 3/4\nw.notes\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        assert_eq!(
+            song.tracks[0].meter_changes,
+            [
+                SegmentMeter {
+                    segment: 1,
+                    signature: (7, 8)
+                },
+                SegmentMeter {
+                    segment: 2,
+                    signature: (4, 4)
+                },
+            ]
+        );
+        // Before a track's first sequence line it sets the song's signature
+        assert!(song.tracks[1].meter_changes.is_empty());
+        assert_eq!(song.time_signature, (3, 4));
+    }
+
+    #[test]
+    fn test_inline_instrument_errors() {
+        let err = parse("tempo: 90\ninstrument: lead { attack: soon }\na.notes\n", Path::new("."))
+            .unwrap_err();
+        assert_eq!(err, "invalid number 'soon' for attack at line 2");
+        let err = parse("instrument: lead { attack: 0.1\n", Path::new(".")).unwrap_err();
+        assert!(err.starts_with("line 1:"), "{}", err);
+        assert!(parse("instrument: { attack: 0.1 }\n", Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn test_sections_expand_in_arrangement_order() {
+        let content = "instrument: bass.instr\n\
+                       instrument: lead { attack: 0.02 }\n\
+                       section verse { bass: verse.notes * 2, lead: melody.notes }\n\
+                       section chorus { bass: chorus.notes transpose +5 }\n\
+                       section_align: error\n\
+                       arrangement: verse chorus verse\n";
+        let song = parse(content, Path::new("songs")).unwrap();
+        let arrangement = song.arrangement.as_ref().unwrap();
+        assert_eq!(arrangement.sections, vec![0, 1, 0]);
+        assert_eq!(arrangement.align, SectionAlign::Error);
+        assert_eq!(song.slot_name(1), "chorus");
+
+        let segments = |track: usize| -> Vec<(String, u32, i8, Option<usize>)> {
+            song.tracks[track]
+                .sequence
+                .iter()
+                .map(|s| (s.notes_path.display().to_string(), s.times, s.transpose, s.slot))
+                .collect()
+        };
+        assert_eq!(
+            segments(0),
+            vec![
+                ("songs/verse.notes".to_string(), 2, 0, Some(0)),
+                ("songs/chorus.notes".to_string(), 1, 5, Some(1)),
+                ("songs/verse.notes".to_string(), 2, 0, Some(2)),
+            ]
+        );
+        // The lead sits out the chorus
+        assert_eq!(segments(1).len(), 2);
+        assert_eq!(segments(1)[1].3, Some(2));
+    }
+
+    #[test]
+    fn test_track_without_a_part_is_dropped() {
+        let content = "align: loop\n\
+                       instrument: a.instr\n\
+                       name: bass\n\
+                       instrument: a.instr\n\
+                       name: lead\n\
+                       section verse { bass: v.notes }\n\
+                       arrangement: verse verse\n";
+        let song = parse(content, Path::new(".")).unwrap();
+        assert_eq!(song.tracks.len(), 1);
+        assert_eq!(song.tracks[0].name, "bass");
+    }
+
+    #[test]
+    fn test_section_errors() {
+        let err = |content: &str| parse(content, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err("instrument: bass.instr\nsection a { bass: a.notes }\narrangement: a b\n"),
+            "line 3: unknown section 'b' in arrangement (sections: a)"
+        );
+        assert_eq!(
+            err("instrument: bass.instr\nsection a { drums: a.notes }\narrangement: a\n"),
+            "line 2: section 'a' names unknown track 'drums'"
+        );
+        assert!(err("instrument: b.instr\nsection a { b: a.notes }\n").contains("never played"));
+        assert!(
+            err("instrument: b.instr\nx.notes\nsection a { b: a.notes }\narrangement: a\n")
+                .starts_with("line 2: sequence lines can't be mixed")
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_track_name() {
+        let mut song = song();
+        song.tracks.push(track("bass"));
+        assert!(song.find_track("bass").unwrap_err().contains("ambiguous"));
+    }
 }
@@ -1,7 +1,285 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc;
+use std::time::Duration;
 
-use crate::note::Event;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{AudioBackend, BufferInfo, CpalBackend, OutputOptions, StreamFormat};
+use crate::beat::Beat;
+use crate::effects::{Effect, EffectChain, Process, Reverb, ReverbLine};
+use crate::error::ClidawError;
+use crate::meter::MeterMap;
+use crate::note::Drum;
+use crate::record::{Recorder, RecordingSummary, Tap};
+use crate::rng::Rng;
+
+/// Shape of an envelope segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// RC-style: moves quickly at first, then eases into the target
+    Exponential,
+}
+
+/// Steepness of the exponential curve; it covers 1 - e^-5 (99.3%) of the
+/// distance before being rescaled to land exactly on the target
+const CURVE_STEEPNESS: f64 = 5.0;
+
+impl Curve {
+    pub fn from_name(name: &str) -> Option<Curve> {
+        match name {
+            "linear" => Some(Curve::Linear),
+            "exponential" | "exp" => Some(Curve::Exponential),
+            _ => None,
+        }
+    }
+
+    /// Fraction of the segment's travel covered at progress `t` (both 0..=1).
+    /// Passes through (0, 0) and (1, 1) and never decreases.
+    fn shape(self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => t,
+            Curve::Exponential => {
+                (1.0 - (-CURVE_STEEPNESS * t).exp()) / (1.0 - (-CURVE_STEEPNESS).exp())
+            }
+        }
+    }
+
+    /// Progress at which `shape` reaches `y` (inverse of `shape`)
+    fn progress_at(self, y: f64) -> f64 {
+        let y = y.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => y,
+            Curve::Exponential => {
+                -(1.0 - y * (1.0 - (-CURVE_STEEPNESS).exp())).ln() / CURVE_STEEPNESS
+            }
+        }
+    }
+}
+
+/// Oscillator shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    /// Random samples instead of a cycle, coloured by the track's
+    /// `NoiseColor`
+    Noise,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "square" => Some(Waveform::Square),
+            "saw" => Some(Waveform::Saw),
+            "triangle" => Some(Waveform::Triangle),
+            "noise" => Some(Waveform::Noise),
+            _ => None,
+        }
+    }
+
+    /// The name `waveform:` takes for this shape
+    pub fn name(self) -> &'static str {
+        match self {
+            Waveform::Sine => "sine",
+            Waveform::Square => "square",
+            Waveform::Saw => "saw",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        }
+    }
+
+    /// The waveform at `phase` (0..1) for an oscillator advancing `inc`
+    /// cycles per sample. With `antialias`, square and saw get PolyBLEP
+    /// corrections at their jumps and the triangle PolyBLAMP ones at its
+    /// corners; without it they are the naive shapes, which alias audibly on
+    /// high notes.
+    fn sample(self, phase: f64, inc: f64, antialias: bool) -> f64 {
+        let tau = 2.0 * std::f64::consts::PI;
+        match self {
+            Waveform::Sine => (phase * tau).sin(),
+            Waveform::Saw => {
+                let naive = 2.0 * phase - 1.0;
+                if antialias { naive - poly_blep(phase, inc) } else { naive }
+            }
+            Waveform::Square => {
+                let naive = if phase < 0.5 { 1.0 } else { -1.0 };
+                if antialias {
+                    naive + poly_blep(phase, inc) - poly_blep((phase + 0.5).fract(), inc)
+                } else {
+                    naive
+                }
+            }
+            Waveform::Triangle => {
+                let naive = 4.0 * ((phase + 0.75).fract() - 0.5).abs() - 1.0;
+                if antialias {
+                    // The slope drops by 8 cycles' worth at the peak and
+                    // climbs back at the trough
+                    let corner = 8.0 * inc;
+                    naive - corner * poly_blamp((phase + 0.75).fract(), inc)
+                        + corner * poly_blamp((phase + 0.25).fract(), inc)
+                } else {
+                    naive
+                }
+            }
+            // Each voice draws its own (see `NoiseSource`)
+            Waveform::Noise => 0.0,
+        }
+    }
+}
+
+/// Spectrum of `waveform: noise`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoiseColor {
+    /// Every frequency equally loud: hiss, hats and shakers
+    #[default]
+    White,
+    /// Falling 3 dB per octave: softer, like wind or breath
+    Pink,
+    /// Band-passed around the note's pitch, so different notes sound tuned
+    Tuned,
+}
+
+/// Pink noise's filter output scaled to about white noise's level
+const PINK_GAIN: f64 = 0.25;
+
+/// Resonance of tuned noise's band-pass: narrow enough to hear a pitch,
+/// wide enough to still sound like noise
+const TUNED_NOISE_Q: f64 = 8.0;
+
+impl NoiseColor {
+    pub fn from_name(name: &str) -> Option<NoiseColor> {
+        match name {
+            "white" => Some(NoiseColor::White),
+            "pink" => Some(NoiseColor::Pink),
+            "tuned" => Some(NoiseColor::Tuned),
+            _ => None,
+        }
+    }
+}
+
+/// What a NoteOn does to a voice still sounding the same key (on a mono
+/// track, to the track's voice)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retrigger {
+    /// Fade the old voice out quickly and start a new one from silence
+    Reset,
+    /// Restart the attack from the current level
+    Continue,
+    /// Keep the envelope where it is; only the pitch and velocity change
+    Legato,
+}
+
+/// Fade under a `Retrigger::Reset` voice's replacement: long enough not
+/// to click, short enough that the new attack sounds clean
+const RESET_FADE_SECS: f64 = 0.005;
+
+impl Retrigger {
+    pub fn from_name(name: &str) -> Option<Retrigger> {
+        match name {
+            "reset" => Some(Retrigger::Reset),
+            "continue" => Some(Retrigger::Continue),
+            "legato" => Some(Retrigger::Legato),
+            _ => None,
+        }
+    }
+}
+
+/// Noise for one voice of a `waveform: noise` track: its own generator,
+/// seeded from the voice's number so renders repeat exactly, and the
+/// filter state its colour needs
+struct NoiseSource {
+    rng: Rng,
+    color: NoiseColor,
+    /// Paul Kellet's three-pole approximation of the pink slope
+    pink: [f64; 3],
+    /// Low-pass and band-pass outputs of the tuned colour's state-variable
+    /// filter
+    low: f64,
+    band: f64,
+}
+
+impl NoiseSource {
+    fn new(seed: u64, color: NoiseColor) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            color,
+            pink: [0.0; 3],
+            low: 0.0,
+            band: 0.0,
+        }
+    }
+
+    /// The next sample of noise for a note at `freq`
+    fn next_sample(&mut self, freq: f64, sample_rate: f64) -> f64 {
+        let white = self.rng.next_signed();
+        match self.color {
+            NoiseColor::White => white,
+            NoiseColor::Pink => {
+                let pink = &mut self.pink;
+                pink[0] = 0.99765 * pink[0] + white * 0.099_046;
+                pink[1] = 0.963 * pink[1] + white * 0.296_516_4;
+                pink[2] = 0.57 * pink[2] + white * 1.052_691_3;
+                (pink[0] + pink[1] + pink[2] + white * 0.1848) * PINK_GAIN
+            }
+            NoiseColor::Tuned => {
+                // Chamberlin's filter is only stable well below Nyquist
+                let center = freq.clamp(1.0, sample_rate / 6.0);
+                let f = 2.0 * (std::f64::consts::PI * center / sample_rate).sin();
+                let damping = 1.0 / TUNED_NOISE_Q;
+                self.low += f * self.band;
+                let high = white - self.low - damping * self.band;
+                self.band += f * high;
+                // Unity gain at the centre passes about sqrt(π fc / (Q fs))
+                // of white noise's level; make that up so pitch doesn't
+                // change loudness
+                let makeup = (TUNED_NOISE_Q * sample_rate / (std::f64::consts::PI * center)).sqrt();
+                self.band * damping * makeup
+            }
+        }
+    }
+}
+
+/// PolyBLEP correction for a jump of 2 at phase 0, non-zero only within one
+/// sample (`inc`) either side of it: added for a rising jump (the square's
+/// leading edge), subtracted for a falling one (the saw's reset)
+fn poly_blep(phase: f64, inc: f64) -> f64 {
+    if inc <= 0.0 {
+        0.0
+    } else if phase < inc {
+        let t = phase / inc;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - inc {
+        let t = (phase - 1.0) / inc;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// PolyBLAMP correction for a corner at phase 0 where the slope rises by
+/// one per sample: `poly_blep`'s integrated, so likewise non-zero only
+/// within one sample either side of it
+fn poly_blamp(phase: f64, inc: f64) -> f64 {
+    let t = if inc <= 0.0 {
+        return 0.0;
+    } else if phase < inc {
+        phase / inc
+    } else if phase > 1.0 - inc {
+        (phase - 1.0) / inc
+    } else {
+        return 0.0;
+    };
+    (1.0 - t.abs()).powi(3) / 6.0
+}
 
 /// ADSR envelope parameters (times in seconds, sustain as level 0.0..=1.0)
 #[derive(Debug, Clone)]
@@ -14,6 +292,9 @@ pub struct Adsr {
     pub sustain: f64,
     /// Time to fall to zero after key release (seconds)
     pub release: f64,
+    pub attack_curve: Curve,
+    pub decay_curve: Curve,
+    pub release_curve: Curve,
 }
 
 impl Default for Adsr {
@@ -23,8 +304,421 @@ impl Default for Adsr {
             decay: 0.1,
             sustain: 0.7,
             release: 0.25,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+        }
+    }
+}
+
+/// How a track's notes respond to velocity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityResponse {
+    /// How far velocity scales amplitude: 1 scales it fully, 0 plays every
+    /// note at full level
+    pub to_amp: f64,
+    /// How much a full-velocity note shortens the attack: 0.5 halves it, 1
+    /// makes it instant (0 = every note has the same attack)
+    pub to_attack: f64,
+}
+
+impl Default for VelocityResponse {
+    fn default() -> Self {
+        Self {
+            to_amp: 1.0,
+            to_attack: 0.0,
+        }
+    }
+}
+
+impl VelocityResponse {
+    /// Amplitude of a note at `velocity` (0.0..=1.0)
+    pub fn amplitude(&self, velocity: f64) -> f64 {
+        1.0 - self.to_amp * (1.0 - velocity)
+    }
+
+    /// Share of the attack time a note at `velocity` takes
+    pub fn attack_scale(&self, velocity: f64) -> f64 {
+        (1.0 - self.to_attack * velocity).clamp(0.0, 1.0)
+    }
+}
+
+/// Per-track synthesis settings: envelope plus oscillator options
+#[derive(Debug, Clone)]
+pub struct Patch {
+    pub adsr: Adsr,
+    /// How velocity shapes each note's level and attack
+    pub velocity: VelocityResponse,
+    /// Number of oscillators per voice (1 = plain single oscillator)
+    pub unison: u32,
+    /// Total detune spread across the unison oscillators (cents)
+    pub detune: f64,
+    /// Drum voices for `type: drum` instruments (None = tonal track)
+    pub kit: Option<DrumKit>,
+    /// Frequency modulation for `type: fm` instruments (None = plain
+    /// oscillators)
+    pub fm: Option<Fm>,
+    /// Chorus on the track's output, before the delay (None = dry)
+    pub chorus: Option<Chorus>,
+    /// Feedback delay on the track's output (None = dry)
+    pub delay: Option<Delay>,
+    /// The song track's `effects:` chain, after the chorus and delay
+    pub effects: Vec<Effect>,
+    /// Semitones a full pitch bend moves the track's notes
+    pub bend_range: f64,
+    /// One voice at a time; the newest held key sounds
+    pub mono: bool,
+    /// Seconds a mono track takes to slide to a new pitch (0 = jump)
+    pub glide: f64,
+    /// What a NoteOn does to a key's sounding voice (None = legato on mono
+    /// tracks, continue on others)
+    pub retrigger: Option<Retrigger>,
+    pub waveform: Waveform,
+    /// Band-limit the square, saw and triangle waveforms
+    pub antialias: bool,
+    /// Spectrum of `Waveform::Noise` (ignored by the other waveforms)
+    pub noise_color: NoiseColor,
+    /// Output channel (from 0) the track plays on by itself, for mixing on
+    /// external hardware (None = the shared mix on channels 0 and 1)
+    pub output_channel: Option<usize>,
+    /// Bus (index into the synth's buses) the track is summed into before
+    /// the master mix (None = straight to the master mix)
+    pub bus: Option<usize>,
+    /// Level the track sends to the master reverb (0 = dry)
+    pub reverb_send: f64,
+    /// Peak amplitude of one voice at full velocity
+    pub gain: f64,
+    /// Turn the track down as more of its voices sound at once, so chords
+    /// don't get louder than single notes
+    pub normalize: bool,
+}
+
+/// Default pitch bend range in semitones
+pub const DEFAULT_BEND_RANGE: f64 = 2.0;
+
+/// Default track gain: a single voice peaks well below full scale, leaving
+/// headroom for chords and several tracks
+pub const DEFAULT_GAIN: f64 = 0.3;
+
+/// Time constant of a normalized track's gain following its voice count
+const NORMALIZE_SECS: f64 = 0.005;
+
+impl Default for Patch {
+    fn default() -> Self {
+        Self {
+            adsr: Adsr::default(),
+            velocity: VelocityResponse::default(),
+            unison: 1,
+            detune: 0.0,
+            kit: None,
+            fm: None,
+            chorus: None,
+            delay: None,
+            effects: Vec::new(),
+            bend_range: DEFAULT_BEND_RANGE,
+            mono: false,
+            glide: 0.0,
+            retrigger: None,
+            waveform: Waveform::Sine,
+            antialias: true,
+            noise_color: NoiseColor::White,
+            output_channel: None,
+            bus: None,
+            reverb_send: 1.0,
+            gain: DEFAULT_GAIN,
+            normalize: false,
+        }
+    }
+}
+
+/// Feedback delay (echo) settings for one track
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delay {
+    /// Time between echoes in seconds
+    pub time: f64,
+    /// Share of each echo fed back into the next (0.0..1.0)
+    pub feedback: f64,
+    /// Level of the echoes mixed in with the dry signal (0.0..=1.0)
+    pub mix: f64,
+}
+
+/// Level below which a delay tail counts as silent (-60 dB)
+const DELAY_SILENCE: f64 = 0.001;
+
+/// Longest delay tail waited for after playback ends
+const MAX_DELAY_TAIL_SECS: f64 = 10.0;
+
+impl Delay {
+    /// Seconds until the echoes have died away
+    pub fn tail_secs(&self) -> f64 {
+        if self.feedback <= 0.0 {
+            return self.time;
+        }
+        let repeats = DELAY_SILENCE.ln() / self.feedback.min(0.999).ln();
+        (self.time * (repeats + 1.0)).min(MAX_DELAY_TAIL_SECS)
+    }
+}
+
+/// Circular buffer holding one delay time of a track's output
+struct DelayLine {
+    buffer: Vec<f64>,
+    pos: usize,
+    feedback: f64,
+    mix: f64,
+}
+
+impl DelayLine {
+    fn new(delay: &Delay, sample_rate: f64) -> Self {
+        let len = ((delay.time * sample_rate).round() as usize).max(1);
+        Self {
+            buffer: vec![0.0; len],
+            pos: 0,
+            feedback: delay.feedback,
+            mix: delay.mix,
+        }
+    }
+
+    /// Feed one dry sample in and return it with the echoes mixed in
+    fn process(&mut self, dry: f64) -> f64 {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = dry + delayed * self.feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        dry + delayed * self.mix
+    }
+}
+
+/// Chorus settings for one track: a copy of the signal delayed by a time
+/// that sweeps between `CHORUS_MIN_DELAY_MS` and that plus `depth`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chorus {
+    /// Sweep of the delay time in milliseconds
+    pub depth: f64,
+    /// Sweeps per second (Hz)
+    pub rate: f64,
+    /// Share of the delayed copy in the output (0.0..=1.0; 0.5 is an even blend)
+    pub mix: f64,
+}
+
+/// Shortest chorus delay; below this the copy just colours the tone
+pub const CHORUS_MIN_DELAY_MS: f64 = 5.0;
+
+/// Longest chorus delay; beyond this the copy is heard as an echo
+pub const CHORUS_MAX_DELAY_MS: f64 = 30.0;
+
+/// Modulated delay line for one track's chorus
+struct ChorusLine {
+    buffer: Vec<f64>,
+    pos: usize,
+    /// LFO position in cycles (0.0..1.0) and its step per sample
+    phase: f64,
+    phase_step: f64,
+    /// Delay range in samples
+    min_delay: f64,
+    sweep: f64,
+    mix: f64,
+}
+
+impl ChorusLine {
+    fn new(chorus: &Chorus, sample_rate: f64) -> Self {
+        let depth = chorus.depth.clamp(0.0, CHORUS_MAX_DELAY_MS - CHORUS_MIN_DELAY_MS);
+        // Room for the longest delay plus the sample after it
+        let len = (CHORUS_MAX_DELAY_MS / 1000.0 * sample_rate).ceil() as usize + 2;
+        Self {
+            buffer: vec![0.0; len],
+            pos: 0,
+            phase: 0.0,
+            phase_step: chorus.rate.max(0.0) / sample_rate,
+            min_delay: CHORUS_MIN_DELAY_MS / 1000.0 * sample_rate,
+            sweep: depth / 1000.0 * sample_rate,
+            mix: chorus.mix,
+        }
+    }
+
+    /// Feed one dry sample in and return it blended with the delayed copy
+    fn process(&mut self, dry: f64) -> f64 {
+        self.buffer[self.pos] = dry;
+        let lfo = 0.5 - 0.5 * (self.phase * 2.0 * std::f64::consts::PI).cos();
+        self.phase = (self.phase + self.phase_step).fract();
+
+        // Read between two samples, interpolating so the sweep doesn't zipper
+        let delay = self.min_delay + self.sweep * lfo;
+        let len = self.buffer.len();
+        let whole = delay.floor() as usize;
+        let frac = delay - whole as f64;
+        let newer = self.buffer[(self.pos + len - whole) % len];
+        let older = self.buffer[(self.pos + len - whole - 1) % len];
+        let wet = newer + (older - newer) * frac;
+
+        self.pos = (self.pos + 1) % len;
+        dry + (wet - dry) * self.mix
+    }
+}
+
+/// A group of tracks summed together before the master mix, so they can be
+/// turned up or down (and later processed) as one
+#[derive(Debug, Clone)]
+pub struct Bus {
+    pub gain: f64,
+    /// Applied in order to the bus sum, before its gain
+    pub effects: Vec<BusEffect>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            effects: Vec::new(),
+        }
+    }
+}
+
+impl Bus {
+    /// Run one sample of the bus sum through the effects and gain
+    fn process(&mut self, input: f64) -> f64 {
+        let wet = self
+            .effects
+            .iter_mut()
+            .fold(input, |sample, effect| effect.process(sample));
+        wet * self.gain
+    }
+}
+
+/// An effect on a bus sum. There are none yet; the reverb sits on the
+/// master mix instead (see `Mix`).
+#[derive(Debug, Clone)]
+pub enum BusEffect {}
+
+impl BusEffect {
+    fn process(&mut self, _input: f64) -> f64 {
+        match *self {}
+    }
+}
+
+/// Everything between the tracks and the output: the buses tracks are
+/// grouped into, the reverb they send to and the master volume
+#[derive(Debug, Clone)]
+pub struct Mix {
+    pub buses: Vec<Bus>,
+    pub reverb: Reverb,
+    /// Gain on the whole mix, under the fades (clicks aren't affected)
+    pub master_volume: f64,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self {
+            buses: Vec::new(),
+            reverb: Reverb::default(),
+            master_volume: 1.0,
+        }
+    }
+}
+
+/// Level the master limiter holds peaks to
+const LIMITER_THRESHOLD: f64 = 0.9;
+
+/// Time for the limiter's gain reduction to recover by a factor of e
+const LIMITER_RELEASE_SECS: f64 = 0.25;
+
+/// Channels the shared mix plays on once some tracks have their own output
+/// channel; without routing it plays on every channel
+const MAIN_CHANNELS: usize = 2;
+
+/// Peak limiter on the master sum: gain drops instantly when a sample would
+/// exceed the threshold and recovers slowly, so loud chords are turned down
+/// instead of hard-clipping. Below the threshold it does nothing.
+struct Limiter {
+    /// Recent peak level (decays toward the current level)
+    envelope: f64,
+    /// Per-sample envelope decay factor
+    release: f64,
+}
+
+impl Limiter {
+    fn new(sample_rate: f64) -> Self {
+        Self {
+            envelope: 0.0,
+            release: (-1.0 / (LIMITER_RELEASE_SECS * sample_rate)).exp(),
         }
     }
+
+    fn process(&mut self, value: f64) -> f64 {
+        let [value, _] = self.process_frame([value, value]);
+        value
+    }
+
+    /// Limit both sides of a stereo frame by the louder one, so the image
+    /// doesn't shift when one side is turned down
+    fn process_frame(&mut self, frame: [f64; 2]) -> [f64; 2] {
+        let peak = frame[0].abs().max(frame[1].abs());
+        self.envelope = peak.max(self.envelope * self.release);
+        if self.envelope > LIMITER_THRESHOLD {
+            frame.map(|value| value * LIMITER_THRESHOLD / self.envelope)
+        } else {
+            frame
+        }
+    }
+}
+
+/// Decay time of each drum voice (seconds until the hit has died away)
+#[derive(Debug, Clone)]
+pub struct DrumKit {
+    pub kick_decay: f64,
+    pub snare_decay: f64,
+    pub hat_decay: f64,
+}
+
+impl Default for DrumKit {
+    fn default() -> Self {
+        Self {
+            kick_decay: 0.4,
+            snare_decay: 0.2,
+            hat_decay: 0.05,
+        }
+    }
+}
+
+impl DrumKit {
+    fn decay(&self, drum: Drum) -> f64 {
+        match drum {
+            Drum::Kick => self.kick_decay,
+            Drum::Snare => self.snare_decay,
+            Drum::Hat => self.hat_decay,
+        }
+    }
+}
+
+/// Two-operator FM: a sine modulator at `ratio` times the note's pitch
+/// bends the phase of the carrier (the track's waveform)
+#[derive(Debug, Clone)]
+pub struct Fm {
+    /// Modulator frequency over the carrier's; whole numbers give
+    /// harmonic tones, others bell-like ones
+    pub ratio: f64,
+    /// Peak phase deviation in radians; higher is brighter
+    pub index: f64,
+    /// Scale the index by the amplitude envelope, so the tone darkens as
+    /// the note fades
+    pub index_env: bool,
+    /// Separate envelope for the index (None = a constant index)
+    pub envelope: Option<Adsr>,
+}
+
+impl Patch {
+    /// Frequency ratio for each unison oscillator, spread evenly across
+    /// `-detune/2 ..= +detune/2` cents. A single oscillator is never detuned.
+    fn unison_ratios(&self) -> Vec<f64> {
+        let n = self.unison.max(1);
+        if n == 1 {
+            return vec![1.0];
+        }
+        (0..n)
+            .map(|i| {
+                let cents = self.detune * (i as f64 / (n - 1) as f64 - 0.5);
+                2.0_f64.powf(cents / 1200.0)
+            })
+            .collect()
+    }
 }
 
 /// Envelope stage for one voice
@@ -35,6 +729,38 @@ enum EnvStage {
     Decay,
     Sustain,
     Release,
+    /// Short linear fade to silence when a voice is cut off (see `FADE_SECS`)
+    Fade,
+}
+
+/// Length of the anti-click fade used when voices are cut off (seconds)
+const FADE_SECS: f64 = 0.001;
+
+/// Length of the fade `LiveCommand::Panic` silences voices with (seconds)
+pub const PANIC_FADE_SECS: f64 = 0.005;
+
+/// Length of the fade `LiveCommand::HardStop` silences voices with (seconds)
+const HARD_STOP_FADE_SECS: f64 = 0.002;
+
+/// Move an envelope on by `dt` seconds, entering the next stage when the
+/// current one is over
+fn advance_envelope(stage: &mut EnvStage, phase: &mut f64, adsr: &Adsr, dt: f64) {
+    let length = match *stage {
+        EnvStage::Idle | EnvStage::Sustain => return,
+        EnvStage::Attack => adsr.attack,
+        EnvStage::Decay => adsr.decay,
+        EnvStage::Release => adsr.release,
+        EnvStage::Fade => FADE_SECS,
+    };
+    *phase += dt;
+    if *phase >= length {
+        *stage = match *stage {
+            EnvStage::Attack => EnvStage::Decay,
+            EnvStage::Decay => EnvStage::Sustain,
+            _ => EnvStage::Idle,
+        };
+        *phase = 0.0;
+    }
 }
 
 /// Compute current envelope level from voice state and ADSR params
@@ -50,14 +776,14 @@ fn envelope_level(
             if adsr.attack <= 0.0 {
                 1.0
             } else {
-                (phase / adsr.attack).min(1.0)
+                adsr.attack_curve.shape(phase / adsr.attack)
             }
         }
         EnvStage::Decay => {
             if adsr.decay <= 0.0 {
                 adsr.sustain
             } else {
-                let t = (phase / adsr.decay).min(1.0);
+                let t = adsr.decay_curve.shape(phase / adsr.decay);
                 1.0 + t * (adsr.sustain - 1.0)
             }
         }
@@ -66,320 +792,3026 @@ fn envelope_level(
             if adsr.release <= 0.0 {
                 0.0
             } else {
-                let t = (phase / adsr.release).min(1.0);
+                let t = adsr.release_curve.shape(phase / adsr.release);
                 release_start * (1.0 - t)
             }
         }
+        EnvStage::Fade => release_start * (1.0 - (phase / FADE_SECS).min(1.0)),
+    }
+}
+
+/// A track setting `automate:` lines in a .song move while it plays
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Param {
+    /// Level on top of the instrument's gain (1 = as written)
+    Gain,
+    /// Balance from -1 (left) to 1 (right), like the `pan` effect
+    Pan,
+    /// Cutoff in Hz of the first lowpass or highpass in the track's `effects:`
+    Cutoff,
+}
+
+impl Param {
+    pub const ALL: [Param; 3] = [Param::Gain, Param::Pan, Param::Cutoff];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Param::Gain => "gain",
+            Param::Pan => "pan",
+            Param::Cutoff => "cutoff",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Param> {
+        Param::ALL.into_iter().find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether `value` is one the setting can take, and what it can take
+    pub fn check(self, value: f64) -> Result<(), &'static str> {
+        let ok = match self {
+            Param::Gain => value >= 0.0,
+            Param::Pan => (-1.0..=1.0).contains(&value),
+            Param::Cutoff => value > 0.0,
+        };
+        match self {
+            _ if ok && value.is_finite() => Ok(()),
+            Param::Gain => Err("0 or more"),
+            Param::Pan => Err("-1 to 1"),
+            Param::Cutoff => Err("Hz above 0"),
+        }
     }
 }
 
+/// How long the engine takes to move an automated setting to a new value,
+/// so a lane's steps join into a smooth line instead of zipper noise
+const PARAM_RAMP_SECS: f64 = 0.02;
+
 /// A command sent to the audio engine
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum LiveCommand {
     /// Start playing a note on a track
     NoteOn {
         track: usize,
         key: char,
         freq: f64,
+        /// Loudness 0.0..=1.0 (scales the voice amplitude)
+        velocity: f64,
     },
     /// Stop a note on a track
     NoteOff { track: usize, key: char },
-    /// Stop all notes (all tracks)
-    AllNotesOff,
-    /// Shut down the engine
+    /// Trigger a drum voice (ignored on tracks without a drum kit)
+    DrumHit {
+        track: usize,
+        drum: Drum,
+        velocity: f64,
+    },
+    /// Metronome click (not affected by the master gain)
+    Click {
+        /// Downbeat: higher pitch
+        accent: bool,
+        /// Loudness 0.0..=1.0
+        volume: f64,
+    },
+    /// Ramp the master gain linearly to `gain` over `ramp_secs` (0 = jump)
+    SetMasterGain { gain: f64, ramp_secs: f64 },
+    /// Move a track's automated setting to `value`, ramping over
+    /// `PARAM_RAMP_SECS` (a cutoff on a track without a filter is ignored)
+    SetParam {
+        track: usize,
+        param: Param,
+        value: f64,
+    },
+    /// Bend every sounding (and new) note: -1.0..=1.0 maps to each track's
+    /// `bend_range` down or up; 0 is no bend
+    PitchBend(f64),
+    /// Turn the master limiter on (the default) or off
+    SetLimiter(bool),
+    /// Release every note held on `track` (None = on every track); they
+    /// ring out with their releases
+    AllNotesOff { track: Option<usize> },
+    /// Stop everything now: every note and drum voice fades out over
+    /// `HARD_STOP_FADE_SECS` instead of its release
+    HardStop,
+    /// Silence every note and drum voice at once, with a `PANIC_FADE_SECS`
+    /// fade instead of their releases (for stuck notes)
+    Panic,
+    /// Release any note held longer than this many seconds without a
+    /// NoteOff (None, the default, never does)
+    SetMaxNoteSecs(Option<f64>),
+    /// Shut down the engine (voices fade out over ~1 ms)
     Shutdown,
 }
 
+impl LiveCommand {
+    /// The track the command is for (None for commands for every track)
+    pub fn track(&self) -> Option<usize> {
+        match *self {
+            LiveCommand::NoteOn { track, .. }
+            | LiveCommand::NoteOff { track, .. }
+            | LiveCommand::DrumHit { track, .. }
+            | LiveCommand::SetParam { track, .. } => Some(track),
+            LiveCommand::AllNotesOff { track } => track,
+            _ => None,
+        }
+    }
+
+    /// The command moved `offset` tracks along (commands for no one track
+    /// are unchanged)
+    pub fn on_track(&self, offset: usize) -> LiveCommand {
+        match *self {
+            LiveCommand::NoteOn {
+                track,
+                key,
+                freq,
+                velocity,
+            } => LiveCommand::NoteOn {
+                track: offset + track,
+                key,
+                freq,
+                velocity,
+            },
+            LiveCommand::NoteOff { track, key } => LiveCommand::NoteOff {
+                track: offset + track,
+                key,
+            },
+            LiveCommand::DrumHit {
+                track,
+                drum,
+                velocity,
+            } => LiveCommand::DrumHit {
+                track: offset + track,
+                drum,
+                velocity,
+            },
+            LiveCommand::SetParam {
+                track,
+                param,
+                value,
+            } => LiveCommand::SetParam {
+                track: offset + track,
+                param,
+                value,
+            },
+            LiveCommand::AllNotesOff { track: Some(track) } => LiveCommand::AllNotesOff {
+                track: Some(offset + track),
+            },
+            ref other => other.clone(),
+        }
+    }
+}
+
 /// A single playing voice with ADSR envelope
 struct Voice {
     track: usize,
     key: char,
     freq: f64,
-    phase: f64,
+    /// Level from the note's velocity, and the share of the attack time it
+    /// takes
+    amplitude: f64,
+    attack_scale: f64,
+    /// One phase accumulator per unison oscillator
+    phases: Vec<f64>,
+    /// The voice's noise on a `waveform: noise` track, played instead of
+    /// the oscillators
+    noise: Option<NoiseSource>,
     env_stage: EnvStage,
     env_phase: f64,
+    /// Level when the current release (or fade) began
     release_start_level: f64,
+    /// Length of the current fade (seconds)
+    fade_secs: f64,
+    /// Samples since the note started, for the stuck-note watchdog
+    held_samples: u64,
+    /// FM modulator phase, and its index envelope's state and last level
+    fm_phase: f64,
+    fm_stage: EnvStage,
+    fm_env_phase: f64,
+    fm_level: f64,
+    fm_release_start: f64,
+    /// Glide toward `glide_target`: per-sample frequency ratio and samples left
+    glide_ratio: f64,
+    glide_left: u64,
+    glide_target: f64,
 }
 
-/// Peak amplitude of the oscillator (envelope scales this)
-const PEAK_AMP: f64 = 0.3;
+impl Voice {
+    fn new(
+        track: usize,
+        key: char,
+        freq: f64,
+        velocity: f64,
+        response: &VelocityResponse,
+        oscillators: usize,
+    ) -> Self {
+        Self {
+            track,
+            key,
+            freq,
+            amplitude: response.amplitude(velocity),
+            attack_scale: response.attack_scale(velocity),
+            phases: vec![0.0; oscillators],
+            noise: None,
+            env_stage: EnvStage::Attack,
+            env_phase: 0.0,
+            release_start_level: 0.0,
+            fade_secs: FADE_SECS,
+            held_samples: 0,
+            fm_phase: 0.0,
+            fm_stage: EnvStage::Attack,
+            fm_env_phase: 0.0,
+            fm_level: 0.0,
+            fm_release_start: 0.0,
+            glide_ratio: 1.0,
+            glide_left: 0,
+            glide_target: freq,
+        }
+    }
 
-/// Audio engine that owns the cpal stream and accepts commands via a channel
-pub struct AudioEngine {
-    cmd_tx: mpsc::Sender<LiveCommand>,
-    // Hold the stream to keep it alive; dropping it stops audio
-    _stream: cpal::Stream,
-}
+    /// Take a new note's `velocity` (the envelope carries on)
+    fn set_velocity(&mut self, velocity: f64, response: &VelocityResponse) {
+        self.amplitude = response.amplitude(velocity);
+        self.attack_scale = response.attack_scale(velocity);
+    }
 
-impl AudioEngine {
-    /// Create a new AudioEngine using the default audio output device and default ADSR (single track)
-    pub fn new() -> Result<Self, String> {
-        Self::with_adsr(Adsr::default())
-    }
-
-    /// Create a new AudioEngine with one custom ADSR (single track, track index 0)
-    pub fn with_adsr(adsr: Adsr) -> Result<Self, String> {
-        Self::with_instruments(vec![adsr])
-    }
-
-    /// Create a new AudioEngine with one ADSR per track (for song playback)
-    pub fn with_instruments(adsrs: Vec<Adsr>) -> Result<Self, String> {
-        if adsrs.is_empty() {
-            return Err("at least one instrument required".to_string());
-        }
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .ok_or("no output audio device available")?;
-
-        let config = device
-            .default_output_config()
-            .map_err(|e| format!("failed to get default output config: {}", e))?;
-
-        let sample_rate = config.sample_rate() as f64;
-        let dt = 1.0 / sample_rate;
-
-        let (cmd_tx, cmd_rx) = mpsc::channel::<LiveCommand>();
-
-        let mut voices: Vec<Voice> = Vec::new();
-        let adsrs = adsrs;
-
-        let stream = device
-            .build_output_stream(
-                &config.into(),
-                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                    while let Ok(cmd) = cmd_rx.try_recv() {
-                        match cmd {
-                            LiveCommand::NoteOn { track, key, freq } => {
-                                if let Some(v) = voices
-                                    .iter_mut()
-                                    .find(|v| v.track == track && v.key == key)
-                                {
-                                    v.freq = freq;
-                                    v.env_stage = EnvStage::Attack;
-                                    v.env_phase = 0.0;
-                                    v.release_start_level = 0.0;
-                                } else {
-                                    voices.push(Voice {
-                                        track,
-                                        key,
-                                        freq,
-                                        phase: 0.0,
-                                        env_stage: EnvStage::Attack,
-                                        env_phase: 0.0,
-                                        release_start_level: 0.0,
-                                    });
-                                }
-                            }
-                            LiveCommand::NoteOff { track, key } => {
-                                for v in voices.iter_mut() {
-                                    if v.track == track
-                                        && v.key == key
-                                        && v.env_stage != EnvStage::Idle
-                                    {
-                                        let adsr = &adsrs[v.track];
-                                        v.release_start_level = envelope_level(
-                                            v.env_stage,
-                                            v.env_phase,
-                                            v.release_start_level,
-                                            adsr,
-                                        );
-                                        v.env_stage = EnvStage::Release;
-                                        v.env_phase = 0.0;
-                                    }
-                                }
-                            }
-                            LiveCommand::AllNotesOff => {
-                                for v in voices.iter_mut() {
-                                    if v.env_stage != EnvStage::Idle {
-                                        let adsr = &adsrs[v.track];
-                                        v.release_start_level = envelope_level(
-                                            v.env_stage,
-                                            v.env_phase,
-                                            v.release_start_level,
-                                            adsr,
-                                        );
-                                        v.env_stage = EnvStage::Release;
-                                        v.env_phase = 0.0;
-                                    }
-                                }
-                            }
-                            LiveCommand::Shutdown => {
-                                voices.clear();
-                                for sample in data.iter_mut() {
-                                    *sample = 0.0;
-                                }
-                                return;
-                            }
-                        }
-                    }
+    /// Whether the key is still down (not releasing or done)
+    fn is_held(&self) -> bool {
+        matches!(self.env_stage, EnvStage::Attack | EnvStage::Decay | EnvStage::Sustain)
+    }
 
-                    for sample in data.iter_mut() {
-                        let mut value = 0.0_f64;
-
-                        for voice in voices.iter_mut() {
-                            let adsr = &adsrs[voice.track];
-                            match voice.env_stage {
-                                EnvStage::Idle => {}
-                                EnvStage::Attack => {
-                                    voice.env_phase += dt;
-                                    if voice.env_phase >= adsr.attack {
-                                        voice.env_stage = EnvStage::Decay;
-                                        voice.env_phase = 0.0;
-                                    }
-                                }
-                                EnvStage::Decay => {
-                                    voice.env_phase += dt;
-                                    if voice.env_phase >= adsr.decay {
-                                        voice.env_stage = EnvStage::Sustain;
-                                        voice.env_phase = 0.0;
-                                    }
-                                }
-                                EnvStage::Sustain => {}
-                                EnvStage::Release => {
-                                    voice.env_phase += dt;
-                                    if voice.env_phase >= adsr.release {
-                                        voice.env_stage = EnvStage::Idle;
-                                    }
-                                }
-                            }
-
-                            let level = envelope_level(
-                                voice.env_stage,
-                                voice.env_phase,
-                                voice.release_start_level,
-                                adsr,
-                            );
-
-                            if level > 0.0001 {
-                                value += (voice.phase * 2.0 * std::f64::consts::PI).sin()
-                                    * PEAK_AMP
-                                    * level;
-                                voice.phase += voice.freq / sample_rate;
-                                if voice.phase >= 1.0 {
-                                    voice.phase -= 1.0;
-                                }
-                            }
-                        }
+    /// Restart the attack from the current level (not zero) so the output
+    /// stays continuous; phases keep running. An FM index envelope
+    /// (`fm_env`) restarts the same way.
+    fn retrigger(&mut self, adsr: &Adsr, fm_env: Option<&Adsr>) {
+        let level = self.level(adsr);
+        self.env_stage = EnvStage::Attack;
+        self.env_phase = adsr.attack_curve.progress_at(level) * adsr.attack.max(0.0);
+        self.release_start_level = 0.0;
+        self.held_samples = 0;
+        if let Some(env) = fm_env {
+            self.fm_stage = EnvStage::Attack;
+            self.fm_env_phase = env.attack_curve.progress_at(self.fm_level) * env.attack.max(0.0);
+            self.fm_release_start = 0.0;
+        }
+    }
 
-                        voices.retain(|v| v.env_stage != EnvStage::Idle);
+    /// Advance the FM modulator by one sample and return the carrier's
+    /// phase offset in cycles. `level` is the amplitude envelope's.
+    fn modulate(&mut self, fm: &Fm, freq: f64, level: f64, dt: f64) -> f64 {
+        let tau = 2.0 * std::f64::consts::PI;
+        let mut index = fm.index;
+        if let Some(env) = &fm.envelope {
+            advance_envelope(&mut self.fm_stage, &mut self.fm_env_phase, env, dt);
+            let (stage, phase) = (self.fm_stage, self.fm_env_phase);
+            self.fm_level = envelope_level(stage, phase, self.fm_release_start, env);
+            index *= self.fm_level;
+        }
+        if fm.index_env {
+            index *= level;
+        }
+        let offset = index * (self.fm_phase * tau).sin() / tau;
+        self.fm_phase = (self.fm_phase + freq * fm.ratio * dt).fract();
+        offset
+    }
 
-                        *sample = value as f32;
-                    }
-                },
-                move |err| {
-                    eprintln!("audio stream error: {}", err);
-                },
-                None,
-            )
-            .map_err(|e| format!("failed to build output stream: {}", e))?;
+    /// Slide to `freq` over `samples` samples, evenly in pitch
+    fn glide_to(&mut self, freq: f64, samples: u64) {
+        self.glide_target = freq;
+        self.glide_left = samples;
+        if samples == 0 {
+            self.freq = freq;
+        } else {
+            self.glide_ratio = (freq / self.freq).powf(1.0 / samples as f64);
+        }
+    }
 
-        stream
-            .play()
-            .map_err(|e| format!("failed to play stream: {}", e))?;
+    /// Advance the glide by one sample
+    fn step_glide(&mut self) {
+        if self.glide_left == 0 {
+            return;
+        }
+        self.glide_left -= 1;
+        self.freq = if self.glide_left == 0 {
+            self.glide_target
+        } else {
+            self.freq * self.glide_ratio
+        };
+    }
 
-        Ok(AudioEngine {
-            cmd_tx,
-            _stream: stream,
-        })
+    fn level(&self, adsr: &Adsr) -> f64 {
+        envelope_level(self.env_stage, self.env_phase, self.release_start_level, adsr)
     }
 
-    /// Send a command to the audio thread
-    pub fn send(&self, cmd: LiveCommand) -> Result<(), String> {
-        self.cmd_tx
-            .send(cmd)
-            .map_err(|_| "audio thread disconnected".to_string())
+    /// Move the envelope on by `dt` seconds. A fade's phase runs in
+    /// `FADE_SECS` units, so a longer fade moves it on more slowly; the
+    /// attack's runs in `adsr.attack` units, moved on faster by a shorter
+    /// velocity-scaled attack.
+    fn advance(&mut self, adsr: &Adsr, dt: f64) {
+        let dt = match self.env_stage {
+            EnvStage::Fade => dt * FADE_SECS / self.fade_secs,
+            EnvStage::Attack if self.attack_scale > 0.0 => dt / self.attack_scale,
+            EnvStage::Attack => f64::INFINITY,
+            _ => dt,
+        };
+        advance_envelope(&mut self.env_stage, &mut self.env_phase, adsr, dt);
     }
-}
 
-/// Play a single pattern through the given audio engine (track 0).
-pub fn play_pattern_with_engine(
-    pattern: &crate::note::Pattern,
-    tempo: u32,
-    engine: &AudioEngine,
-) -> Result<(), String> {
-    let beat_duration = 60.0 / tempo as f64;
-    const TRACK: usize = 0;
-
-    for event in &pattern.events {
-        match event {
-            Event::Note(n) => {
-                let freq = n.note.to_freq(n.octave);
-                println!("  Playing {:?}{} ({:.1} Hz)", n.note, n.octave, freq);
-                engine.send(LiveCommand::NoteOn {
-                    track: TRACK,
-                    key: '\0',
-                    freq,
-                })?;
-                std::thread::sleep(std::time::Duration::from_secs_f64(beat_duration));
-                engine.send(LiveCommand::NoteOff {
-                    track: TRACK,
-                    key: '\0',
-                })?;
-            }
-            Event::Chord(notes) => {
-                let desc: Vec<String> = notes
-                    .iter()
-                    .map(|n| format!("{:?}{}", n.note, n.octave))
-                    .collect();
-                println!("  Playing chord [{}]", desc.join(" "));
-                for (i, n) in notes.iter().enumerate() {
-                    let freq = n.note.to_freq(n.octave);
-                    let key = char::from(b'0' + i as u8);
-                    engine.send(LiveCommand::NoteOn {
-                        track: TRACK,
-                        key,
-                        freq,
-                    })?;
-                }
-                std::thread::sleep(std::time::Duration::from_secs_f64(beat_duration));
-                engine.send(LiveCommand::AllNotesOff)?;
-                std::thread::sleep(std::time::Duration::from_millis(10));
-            }
-            Event::Rest(beats) => {
-                let rest_duration = beat_duration * beats;
-                println!("  Rest ({} beats)", beats);
-                std::thread::sleep(std::time::Duration::from_secs_f64(rest_duration));
-            }
-            Event::BarLine => {}
+    /// Enter the release stage from the current level (no-op if already
+    /// releasing, so duplicate NoteOffs don't restart the release)
+    fn release(&mut self, adsr: &Adsr) {
+        if matches!(self.env_stage, EnvStage::Idle | EnvStage::Release | EnvStage::Fade) {
+            return;
         }
+        self.release_start_level = self.level(adsr);
+        self.env_stage = EnvStage::Release;
+        self.env_phase = 0.0;
+        self.fm_release_start = self.fm_level;
+        self.fm_stage = EnvStage::Release;
+        self.fm_env_phase = 0.0;
     }
 
-    std::thread::sleep(std::time::Duration::from_millis(100));
-    let _ = engine.send(LiveCommand::Shutdown);
-
-    Ok(())
+    /// Cut the voice off with a `secs` fade instead of its release
+    fn fade_out(&mut self, adsr: &Adsr, secs: f64) {
+        if self.env_stage == EnvStage::Idle {
+            return;
+        }
+        self.release_start_level = self.level(adsr);
+        self.env_stage = EnvStage::Fade;
+        self.env_phase = 0.0;
+        self.fade_secs = secs.max(FADE_SECS);
+    }
+}
+
+/// Kick pitch sweeps from `KICK_END_HZ + KICK_SWEEP_HZ` down to `KICK_END_HZ`
+const KICK_END_HZ: f64 = 45.0;
+const KICK_SWEEP_HZ: f64 = 110.0;
+/// Time constant of the kick's pitch drop (seconds)
+const KICK_SWEEP_SECS: f64 = 0.035;
+/// Pitch of the tonal body under the snare's noise
+const SNARE_TONE_HZ: f64 = 185.0;
+
+/// Metronome click pitches and length
+const CLICK_HZ: f64 = 1000.0;
+const CLICK_ACCENT_HZ: f64 = 1500.0;
+const CLICK_SECS: f64 = 0.04;
+/// Peak of a click at full volume
+const CLICK_LEVEL: f64 = 0.3;
+
+/// A sounding metronome click: a sine blip with a fast exponential decay
+struct ClickVoice {
+    freq: f64,
+    volume: f64,
+    age: f64,
 }
 
-/// Play a single pattern with default instrument (convenience for .notes file).
-pub fn play_pattern(pattern: &crate::note::Pattern, tempo: u32) -> Result<(), String> {
-    let engine = AudioEngine::new()?;
-    play_pattern_with_engine(pattern, tempo, &engine)
+impl ClickVoice {
+    fn next_sample(&mut self, dt: f64) -> f64 {
+        let env = (-6.9 * self.age / CLICK_SECS).exp();
+        let value = (self.age * self.freq * 2.0 * std::f64::consts::PI).sin() * env;
+        self.age += dt;
+        value * self.volume * CLICK_LEVEL
+    }
 }
 
-/// Run a pre-sorted schedule of (beat, command); blocks until playback finishes.
+/// A sounding drum hit. Drums have no key or release: each hit decays
+/// exponentially and is dropped once its decay time has passed.
+struct DrumVoice {
+    track: usize,
+    drum: Drum,
+    velocity: f64,
+    decay: f64,
+    /// Seconds since the hit
+    age: f64,
+    /// Oscillator phase (kick sweep, snare body)
+    phase: f64,
+    /// Previous noise sample, for the high-pass filter
+    last_noise: f64,
+    /// Age at which a shutdown or panic fade began, and its length
+    fade_start: Option<(f64, f64)>,
+}
+
+impl DrumVoice {
+    /// Produce one sample and advance by `dt`. `noise` is white noise in -1..1.
+    fn next_sample(&mut self, noise: f64, dt: f64) -> f64 {
+        // exp(-6.9) is about -60 dB: inaudible by the end of the decay time
+        let env = (-6.9 * self.age / self.decay.max(1e-4)).exp();
+        let highpassed = (noise - self.last_noise) * 0.5;
+        self.last_noise = noise;
+
+        let freq;
+        let raw = match self.drum {
+            Drum::Kick => {
+                freq = KICK_END_HZ + KICK_SWEEP_HZ * (-self.age / KICK_SWEEP_SECS).exp();
+                (self.phase * 2.0 * std::f64::consts::PI).sin()
+            }
+            Drum::Snare => {
+                freq = SNARE_TONE_HZ;
+                let body = (self.phase * 2.0 * std::f64::consts::PI).sin()
+                    * (-self.age / (self.decay * 0.3).max(1e-4)).exp();
+                0.6 * noise + 0.4 * body
+            }
+            Drum::Hat => {
+                freq = 0.0;
+                highpassed
+            }
+        };
+        self.phase = (self.phase + freq * dt).fract();
+
+        let fade = match self.fade_start {
+            Some((start, secs)) => 1.0 - ((self.age - start) / secs).min(1.0),
+            None => 1.0,
+        };
+        self.age += dt;
+        raw * env * fade * self.velocity
+    }
+
+    fn finished(&self) -> bool {
+        self.age >= self.decay
+            || self
+                .fade_start
+                .is_some_and(|(start, secs)| self.age - start >= secs)
+    }
+
+    /// Fade the hit out over `secs` (a fade already running keeps going)
+    fn fade_out(&mut self, secs: f64) {
+        self.fade_start.get_or_insert((self.age, secs));
+    }
+}
+
+/// The synthesizer itself: voice allocation, envelopes, and mixing.
+/// The audio callback only feeds it commands and asks it to render, so it can
+/// also run offline (tests, file rendering).
+pub struct Synth {
+    sample_rate: f64,
+    channels: usize,
+    adsrs: Vec<Adsr>,
+    velocity_responses: Vec<VelocityResponse>,
+    /// Waveform per track and whether it is band-limited
+    waveforms: Vec<(Waveform, bool)>,
+    /// Noise colour per track, and the number of noise voices started so
+    /// far, which seeds the next one
+    noise_colors: Vec<NoiseColor>,
+    noise_voices: u64,
+    /// Frequency modulation per track (None for plain oscillators)
+    fms: Vec<Option<Fm>>,
+    /// Unison frequency ratios per track
+    unison: Vec<Vec<f64>>,
+    /// Gain keeping summed unison oscillators at roughly the loudness of one
+    unison_gain: Vec<f64>,
+    /// Per-track gain
+    gains: Vec<f64>,
+    /// Per normalized track: the gain following 1/sqrt(voices), smoothed
+    /// so it doesn't step (None for other tracks)
+    normalized: Vec<Option<f64>>,
+    normalize_coeff: f64,
+    voices: Vec<Voice>,
+    /// Drum kit per track (None for tonal tracks)
+    kits: Vec<Option<DrumKit>>,
+    drums: Vec<DrumVoice>,
+    clicks: Vec<ClickVoice>,
+    /// Noise source for drums; fixed seed so renders are repeatable
+    noise: Rng,
+    /// Per-track chorus and delay lines (None when the track has none)
+    choruses: Vec<Option<ChorusLine>>,
+    delays: Vec<Option<DelayLine>>,
+    /// Per-track effect chain (None when the track has none)
+    chains: Vec<Option<EffectChain>>,
+    /// Per-track automated gain, pan and cutoff
+    params: Vec<TrackParams>,
+    /// Per-track sum of the current sample, before effects
+    track_mix: Vec<f64>,
+    /// Per-track bend range (semitones) and the current bend as a frequency ratio
+    bend_ranges: Vec<f64>,
+    bend_ratios: Vec<f64>,
+    /// Samples a note may be held before the watchdog releases it (None = no limit)
+    max_note_samples: Option<u64>,
+    /// Glide length in samples per mono track (None for polyphonic tracks)
+    mono: Vec<Option<u64>>,
+    /// What a NoteOn does to a sounding voice, per track
+    retriggers: Vec<Retrigger>,
+    /// Keys down on each mono track, oldest first: (key, freq, velocity)
+    held: Vec<Vec<(char, f64, f64)>>,
+    /// Output channel per track (None = the shared mix)
+    outputs: Vec<Option<usize>>,
+    /// Current sample of each output channel's routed tracks (empty when
+    /// no track is routed)
+    channel_mix: Vec<f64>,
+    /// Bus per track (None = the master mix), the buses, and the current
+    /// frame (left, right) of each bus's tracks
+    track_buses: Vec<Option<usize>>,
+    buses: Vec<Bus>,
+    bus_mix: Vec<[f64; 2]>,
+    /// Master reverb (None when its mix is 0) and each track's send to it
+    reverb: Option<ReverbLine>,
+    reverb_sends: Vec<f64>,
+    /// Gain applied to the mix (fades), and the fixed volume under it
+    master_gain: f64,
+    master_volume: f64,
+    /// Per-sample gain change and samples left in the current ramp
+    gain_step: f64,
+    gain_ramp_left: u64,
+    /// Master limiters (None when turned off): one per output channel when
+    /// tracks are routed, else one for the shared mix
+    limiters: Option<Vec<Limiter>>,
+}
+
+impl Synth {
+    /// Create a synth with one patch per track, rendering interleaved frames
+    /// of `channels` samples. The shared mix plays its left side on channel
+    /// 0 and its right on channel 1; other channels (or a lone one) get the
+    /// two mixed, so every channel hears the same until a track is panned.
+    /// A patch with an `output_channel` plays alone on that channel instead
+    /// (one past the last channel is ignored).
+    /// Tracks with a `bus` are summed into that bus before the master mix
+    /// (a bus index past the end of `mix.buses` is ignored), and every
+    /// track on the shared mix sends `reverb_send` of itself to the reverb.
+    pub fn new(patches: &[Patch], mix: &Mix, sample_rate: f64, channels: usize) -> Self {
+        let channels = channels.max(1);
+        let unison: Vec<Vec<f64>> = patches.iter().map(Patch::unison_ratios).collect();
+        let unison_gain = unison
+            .iter()
+            .map(|r| 1.0 / (r.len() as f64).sqrt())
+            .collect();
+        let outputs: Vec<Option<usize>> = patches
+            .iter()
+            .map(|p| p.output_channel.filter(|&c| c < channels))
+            .collect();
+        let routed = outputs.iter().any(Option::is_some);
+        let mut synth = Self {
+            sample_rate,
+            channels,
+            adsrs: patches.iter().map(|p| p.adsr.clone()).collect(),
+            velocity_responses: patches.iter().map(|p| p.velocity).collect(),
+            waveforms: patches.iter().map(|p| (p.waveform, p.antialias)).collect(),
+            noise_colors: patches.iter().map(|p| p.noise_color).collect(),
+            noise_voices: 0,
+            fms: patches.iter().map(|p| p.fm.clone()).collect(),
+            unison,
+            unison_gain,
+            gains: patches.iter().map(|p| p.gain.max(0.0)).collect(),
+            normalized: patches.iter().map(|p| p.normalize.then_some(1.0)).collect(),
+            normalize_coeff: 1.0 - (-1.0 / (NORMALIZE_SECS * sample_rate)).exp(),
+            voices: Vec::new(),
+            kits: patches.iter().map(|p| p.kit.clone()).collect(),
+            drums: Vec::new(),
+            clicks: Vec::new(),
+            noise: Rng::new(0),
+            // A zero mix bypasses an effect entirely: no buffer at all
+            choruses: patches
+                .iter()
+                .map(|p| {
+                    p.chorus
+                        .as_ref()
+                        .filter(|c| c.mix > 0.0)
+                        .map(|c| ChorusLine::new(c, sample_rate))
+                })
+                .collect(),
+            delays: patches
+                .iter()
+                .map(|p| {
+                    p.delay
+                        .as_ref()
+                        .filter(|d| d.mix > 0.0)
+                        .map(|d| DelayLine::new(d, sample_rate))
+                })
+                .collect(),
+            chains: patches.iter().map(|p| EffectChain::new(&p.effects, sample_rate)).collect(),
+            params: vec![TrackParams::default(); patches.len()],
+            track_mix: vec![0.0; patches.len()],
+            bend_ranges: patches.iter().map(|p| p.bend_range).collect(),
+            bend_ratios: vec![1.0; patches.len()],
+            max_note_samples: None,
+            mono: patches
+                .iter()
+                .map(|p| p.mono.then(|| (p.glide.max(0.0) * sample_rate).round() as u64))
+                .collect(),
+            retriggers: patches
+                .iter()
+                .map(|p| {
+                    let default = if p.mono { Retrigger::Legato } else { Retrigger::Continue };
+                    p.retrigger.unwrap_or(default)
+                })
+                .collect(),
+            held: vec![Vec::new(); patches.len()],
+            outputs,
+            channel_mix: if routed { vec![0.0; channels] } else { Vec::new() },
+            track_buses: patches
+                .iter()
+                .map(|p| p.bus.filter(|&b| b < mix.buses.len()))
+                .collect(),
+            buses: mix.buses.clone(),
+            bus_mix: vec![[0.0; 2]; mix.buses.len()],
+            reverb: ReverbLine::new(&mix.reverb, sample_rate),
+            reverb_sends: patches.iter().map(|p| p.reverb_send.max(0.0)).collect(),
+            master_gain: 1.0,
+            master_volume: mix.master_volume.max(0.0),
+            gain_step: 0.0,
+            gain_ramp_left: 0,
+            limiters: None,
+        };
+        synth.limiters = Some(synth.new_limiters());
+        synth
+    }
+
+    /// Fresh limiters for every output the synth limits separately
+    fn new_limiters(&self) -> Vec<Limiter> {
+        let count = self.channel_mix.len().max(1);
+        (0..count).map(|_| Limiter::new(self.sample_rate)).collect()
+    }
+
+    /// Apply one command to the voice state
+    pub fn process_command(&mut self, cmd: LiveCommand) {
+        match cmd {
+            LiveCommand::NoteOn {
+                track,
+                key,
+                freq,
+                velocity,
+            } => {
+                if self.mono[track].is_some() {
+                    let held = &mut self.held[track];
+                    held.retain(|&(k, ..)| k != key);
+                    held.push((key, freq, velocity));
+                    self.play_mono(track, key, freq, velocity);
+                    return;
+                }
+                let adsr = &self.adsrs[track];
+                let response = &self.velocity_responses[track];
+                let fm_env = self.fms[track].as_ref().and_then(|fm| fm.envelope.as_ref());
+                // A key still down retriggers its voice; one already
+                // releasing (or a reused scheduler key) gets a new voice, and
+                // the old one fades out under it instead of jumping to the
+                // new pitch
+                let Some(v) = self
+                    .voices
+                    .iter_mut()
+                    .find(|v| v.track == track && v.key == key && v.is_held())
+                else {
+                    self.start_voice(track, key, freq, velocity);
+                    return;
+                };
+                match self.retriggers[track] {
+                    Retrigger::Reset => {
+                        v.fade_out(adsr, RESET_FADE_SECS);
+                        self.start_voice(track, key, freq, velocity);
+                    }
+                    Retrigger::Continue => {
+                        v.freq = freq;
+                        v.set_velocity(velocity, response);
+                        v.retrigger(adsr, fm_env);
+                    }
+                    Retrigger::Legato => {
+                        v.freq = freq;
+                        v.set_velocity(velocity, response);
+                        v.held_samples = 0;
+                    }
+                }
+            }
+            LiveCommand::NoteOff { track, key } => {
+                if self.mono[track].is_some() {
+                    let held = &mut self.held[track];
+                    let newest = held.last().is_some_and(|&(k, ..)| k == key);
+                    held.retain(|&(k, ..)| k != key);
+                    // Releasing an older key changes nothing; releasing the
+                    // newest returns to the one pressed before it
+                    if !newest {
+                        return;
+                    }
+                    if let Some(&(key, freq, velocity)) = held.last() {
+                        self.play_mono(track, key, freq, velocity);
+                        return;
+                    }
+                }
+                // The newest voice for the key; older ones are releasing already
+                if let Some(v) = self
+                    .voices
+                    .iter_mut()
+                    .rev()
+                    .find(|v| v.track == track && v.key == key && v.is_held())
+                {
+                    v.release(&self.adsrs[track]);
+                }
+            }
+            LiveCommand::DrumHit {
+                track,
+                drum,
+                velocity,
+            } => {
+                if let Some(kit) = &self.kits[track] {
+                    self.drums.push(DrumVoice {
+                        track,
+                        drum,
+                        velocity,
+                        decay: kit.decay(drum),
+                        age: 0.0,
+                        phase: 0.0,
+                        last_noise: 0.0,
+                        fade_start: None,
+                    });
+                }
+            }
+            LiveCommand::Click { accent, volume } => {
+                self.clicks.push(ClickVoice {
+                    freq: if accent { CLICK_ACCENT_HZ } else { CLICK_HZ },
+                    volume,
+                    age: 0.0,
+                });
+            }
+            LiveCommand::SetMasterGain { gain, ramp_secs } => {
+                let samples = (ramp_secs.max(0.0) * self.sample_rate).round() as u64;
+                if samples == 0 {
+                    self.master_gain = gain;
+                    self.gain_ramp_left = 0;
+                } else {
+                    self.gain_step = (gain - self.master_gain) / samples as f64;
+                    self.gain_ramp_left = samples;
+                }
+            }
+            LiveCommand::SetParam {
+                track,
+                param,
+                value,
+            } => {
+                let samples = (PARAM_RAMP_SECS * self.sample_rate).round() as u64;
+                let params = &mut self.params[track];
+                match param {
+                    Param::Gain => params.gain.set(value.max(0.0), samples),
+                    Param::Pan => params.pan.set(value.clamp(-1.0, 1.0), samples),
+                    Param::Cutoff => {
+                        let cutoff = self.chains[track].as_ref().and_then(EffectChain::cutoff);
+                        if let Some(cutoff) = cutoff {
+                            params.cutoff.get_or_insert(Ramp::new(cutoff)).set(value, samples);
+                        }
+                    }
+                }
+            }
+            LiveCommand::PitchBend(amount) => {
+                let amount = amount.clamp(-1.0, 1.0);
+                for (ratio, range) in self.bend_ratios.iter_mut().zip(&self.bend_ranges) {
+                    *ratio = 2.0_f64.powf(amount * range / 12.0);
+                }
+            }
+            LiveCommand::SetLimiter(on) => {
+                if on != self.limiters.is_some() {
+                    self.limiters = on.then(|| self.new_limiters());
+                }
+            }
+            LiveCommand::AllNotesOff { track } => {
+                let on_track = |t: usize| track.is_none_or(|track| t == track);
+                for (t, held) in self.held.iter_mut().enumerate() {
+                    if on_track(t) {
+                        held.clear();
+                    }
+                }
+                for v in self.voices.iter_mut().filter(|v| on_track(v.track)) {
+                    v.release(&self.adsrs[v.track]);
+                }
+            }
+            LiveCommand::HardStop => self.fade_all(HARD_STOP_FADE_SECS),
+            LiveCommand::Panic => self.fade_all(PANIC_FADE_SECS),
+            LiveCommand::SetMaxNoteSecs(secs) => {
+                self.max_note_samples = secs
+                    .filter(|&s| s > 0.0)
+                    .map(|s| (s * self.sample_rate).round() as u64);
+            }
+            LiveCommand::Shutdown => self.fade_all(FADE_SECS),
+        }
+    }
+
+    /// Cut off every note and drum voice with a `secs` fade
+    fn fade_all(&mut self, secs: f64) {
+        self.held.iter_mut().for_each(Vec::clear);
+        for v in self.voices.iter_mut() {
+            v.fade_out(&self.adsrs[v.track], secs);
+        }
+        for d in self.drums.iter_mut() {
+            d.fade_out(secs);
+        }
+    }
+
+    /// Sound `key` on a mono track: its voice takes over the new key and
+    /// glides to the new pitch, keeping its envelope while a key is held
+    /// (`Retrigger::Legato`) or restarting it from the current level
+    /// (`Continue`). `Reset` fades the voice out under a new one instead.
+    fn play_mono(&mut self, track: usize, key: char, freq: f64, velocity: f64) {
+        let glide = self.mono[track].unwrap_or(0);
+        let response = &self.velocity_responses[track];
+        let retrigger = self.retriggers[track];
+        // Voices fading out under a reset's new voice are done with
+        let voice = self
+            .voices
+            .iter_mut()
+            .find(|v| v.track == track && v.env_stage != EnvStage::Fade);
+        match voice {
+            Some(v) if retrigger == Retrigger::Reset => {
+                v.fade_out(&self.adsrs[track], RESET_FADE_SECS);
+                self.start_voice(track, key, freq, velocity);
+            }
+            Some(v) => {
+                if retrigger == Retrigger::Continue || !v.is_held() {
+                    let fm_env = self.fms[track].as_ref().and_then(|fm| fm.envelope.as_ref());
+                    v.retrigger(&self.adsrs[track], fm_env);
+                }
+                v.key = key;
+                v.set_velocity(velocity, response);
+                v.held_samples = 0;
+                v.glide_to(freq, glide);
+            }
+            None => self.start_voice(track, key, freq, velocity),
+        }
+    }
+
+    /// Add a new voice for `key` on `track`, with its own noise source if
+    /// the track plays noise
+    fn start_voice(&mut self, track: usize, key: char, freq: f64, velocity: f64) {
+        let response = &self.velocity_responses[track];
+        let oscillators = self.unison[track].len();
+        let mut voice = Voice::new(track, key, freq, velocity, response, oscillators);
+        if self.waveforms[track].0 == Waveform::Noise {
+            voice.noise = Some(NoiseSource::new(self.noise_voices, self.noise_colors[track]));
+            self.noise_voices += 1;
+        }
+        self.voices.push(voice);
+    }
+
+    /// Sounding note and drum voices (clicks aren't counted)
+    pub fn active_voices(&self) -> usize {
+        self.voices.len() + self.drums.len()
+    }
+
+    /// Render interleaved frames into `out`
+    pub fn render(&mut self, out: &mut [f32]) {
+        if self.channel_mix.is_empty() {
+            for frame in out.chunks_mut(self.channels) {
+                let shared = self.next_frame();
+                let [left, right] = match &mut self.limiters {
+                    Some(limiters) => limiters[0].process_frame(shared),
+                    None => shared,
+                };
+                let both = (left + right) / 2.0;
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    *sample = match (channel, self.channels) {
+                        (0, 2..) => left,
+                        (1, _) => right,
+                        _ => both,
+                    } as f32;
+                }
+            }
+            return;
+        }
+        // Routed tracks play alone on their channel, beside the shared mix
+        for frame in out.chunks_mut(self.channels) {
+            let shared = self.next_frame();
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut value = self.channel_mix[channel];
+                if channel < MAIN_CHANNELS {
+                    value += shared[channel];
+                }
+                if let Some(limiters) = &mut self.limiters {
+                    value = limiters[channel].process(value);
+                }
+                *sample = value as f32;
+            }
+        }
+    }
+
+    /// Advance every voice by one sample and return the shared mix's left
+    /// and right, before the limiter. Routed tracks are left in
+    /// `channel_mix` instead.
+    fn next_frame(&mut self) -> [f64; 2] {
+        let dt = 1.0 / self.sample_rate;
+        self.track_mix.fill(0.0);
+        self.channel_mix.fill(0.0);
+        self.bus_mix.fill([0.0; 2]);
+
+        for voice in self.voices.iter_mut() {
+            let adsr = &self.adsrs[voice.track];
+            if voice.is_held() {
+                voice.held_samples += 1;
+                // A NoteOff that never came: release the note, and forget
+                // the keys a mono track would fall back to
+                if self.max_note_samples.is_some_and(|max| voice.held_samples > max) {
+                    voice.release(adsr);
+                    self.held[voice.track].clear();
+                }
+            }
+            voice.advance(adsr, dt);
+            if voice.env_stage == EnvStage::Idle {
+                continue;
+            }
+
+            let level = voice.level(adsr);
+            // Phases advance even when inaudible so a retrigger resumes
+            // mid-cycle exactly where the waveform is.
+            let audible = level > 0.0001;
+            let mut osc = 0.0_f64;
+            let mut osc_gain = self.unison_gain[voice.track];
+            voice.step_glide();
+            let freq = voice.freq * self.bend_ratios[voice.track];
+            if let Some(noise) = &mut voice.noise {
+                // One source however many unison oscillators the track has
+                osc = noise.next_sample(freq, self.sample_rate);
+                osc_gain = 1.0;
+            } else {
+                let (waveform, antialias) = self.waveforms[voice.track];
+                let offset = match &self.fms[voice.track] {
+                    Some(fm) => voice.modulate(fm, freq, level, dt),
+                    None => 0.0,
+                };
+                for (phase, ratio) in voice.phases.iter_mut().zip(&self.unison[voice.track]) {
+                    let inc = freq * ratio / self.sample_rate;
+                    if audible {
+                        osc += waveform.sample((*phase + offset).rem_euclid(1.0), inc, antialias);
+                    }
+                    *phase += inc;
+                    if *phase >= 1.0 {
+                        *phase -= 1.0;
+                    }
+                }
+            }
+            if audible {
+                self.track_mix[voice.track] += osc * osc_gain * level * voice.amplitude;
+            }
+        }
+
+        self.voices.retain(|v| v.env_stage != EnvStage::Idle);
+
+        if !self.drums.is_empty() {
+            let noise = self.noise.next_signed();
+            for drum in self.drums.iter_mut() {
+                self.track_mix[drum.track] += drum.next_sample(noise, dt);
+            }
+            self.drums.retain(|d| !d.finished());
+        }
+
+        for (track, (sample, gain)) in self.track_mix.iter_mut().zip(&self.gains).enumerate() {
+            *sample *= gain;
+            if let Some(level) = &mut self.normalized[track] {
+                let voices = self.voices.iter().filter(|v| v.track == track).count();
+                let target = 1.0 / (voices.max(1) as f64).sqrt();
+                *level += (target - *level) * self.normalize_coeff;
+                *sample *= *level;
+            }
+        }
+
+        if self.gain_ramp_left > 0 {
+            self.master_gain += self.gain_step;
+            self.gain_ramp_left -= 1;
+        }
+
+        let master = self.master_gain * self.master_volume;
+        let mut value = [0.0_f64; 2];
+        let mut send = 0.0_f64;
+        for (((((((dry, chorus), delay), chain), params), output), bus), level) in self
+            .track_mix
+            .iter()
+            .zip(self.choruses.iter_mut())
+            .zip(self.delays.iter_mut())
+            .zip(self.chains.iter_mut())
+            .zip(self.params.iter_mut())
+            .zip(&self.outputs)
+            .zip(&self.track_buses)
+            .zip(&self.reverb_sends)
+        {
+            let chorused = match chorus {
+                Some(line) => line.process(*dry),
+                None => *dry,
+            };
+            let wet = match delay {
+                Some(line) => line.process(chorused),
+                None => chorused,
+            };
+            let mut frame = [wet; 2];
+            if let Some(chain) = chain {
+                if let Some(cutoff) = &mut params.cutoff
+                    && cutoff.moving()
+                {
+                    chain.set_cutoff(cutoff.next());
+                }
+                chain.process(&mut frame);
+            }
+            // Automated gain and pan come after the track's own effects,
+            // like a mixer channel's fader and pan
+            let (gain, pan) = (params.gain.next(), params.pan.next());
+            frame[0] *= gain * (1.0 - pan).min(1.0);
+            frame[1] *= gain * (1.0 + pan).min(1.0);
+            let mono = (frame[0] + frame[1]) / 2.0;
+            match (output, bus) {
+                (Some(channel), _) => self.channel_mix[*channel] += mono * master,
+                (None, Some(bus)) => add_frame(&mut self.bus_mix[*bus], frame),
+                (None, None) => add_frame(&mut value, frame),
+            }
+            // Sends are taken before the bus, so a bus's gain sets only its
+            // dry level
+            if output.is_none() {
+                send += mono * level;
+            }
+        }
+        for (bus, sum) in self.buses.iter_mut().zip(&self.bus_mix) {
+            add_frame(&mut value, sum.map(|side| bus.process(side)));
+        }
+        if let Some(reverb) = &mut self.reverb {
+            let wet = reverb.process(send);
+            add_frame(&mut value, [wet; 2]);
+        }
+        value = value.map(|side| side * master);
+
+        for click in self.clicks.iter_mut() {
+            let click = click.next_sample(dt);
+            add_frame(&mut value, [click; 2]);
+        }
+        self.clicks.retain(|c| c.age < CLICK_SECS);
+        value
+    }
+}
+
+/// A setting moving in a straight line toward its latest target
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    value: f64,
+    target: f64,
+    step: f64,
+    /// Samples until `target` is reached
+    left: u64,
+}
+
+impl Ramp {
+    fn new(value: f64) -> Self {
+        Self {
+            value,
+            target: value,
+            step: 0.0,
+            left: 0,
+        }
+    }
+
+    /// Head for `target`, reaching it in `samples`
+    fn set(&mut self, target: f64, samples: u64) {
+        self.target = target;
+        self.left = samples.max(1);
+        self.step = (target - self.value) / self.left as f64;
+    }
+
+    fn moving(&self) -> bool {
+        self.left > 0
+    }
+
+    /// The value for the next sample
+    fn next(&mut self) -> f64 {
+        if self.left > 0 {
+            self.left -= 1;
+            self.value = if self.left == 0 { self.target } else { self.value + self.step };
+        }
+        self.value
+    }
+}
+
+/// A track's automated settings (see `Param`)
+#[derive(Debug, Clone)]
+struct TrackParams {
+    gain: Ramp,
+    pan: Ramp,
+    /// Created by the first cutoff change, from the filter's own cutoff
+    cutoff: Option<Ramp>,
+}
+
+impl Default for TrackParams {
+    fn default() -> Self {
+        Self {
+            gain: Ramp::new(1.0),
+            pan: Ramp::new(0.0),
+            cutoff: None,
+        }
+    }
+}
+
+/// Add `frame` to `sum`, side by side
+fn add_frame(sum: &mut [f64; 2], frame: [f64; 2]) {
+    sum[0] += frame[0];
+    sum[1] += frame[1];
+}
+
+/// Audio engine that owns the output stream and accepts commands via a
+/// channel
+pub struct AudioEngine {
+    cmd_tx: mpsc::Sender<LiveCommand>,
+    /// Published by the audio callback after each buffer
+    stats: Arc<StreamStats>,
+    /// Writer for `live --record`, finished by `finish_recording`
+    recorder: Option<Recorder>,
+    /// Where the audio goes; dropping it stops the stream
+    backend: Box<dyn AudioBackend>,
+}
+
+/// The stream's timing as its callbacks see it (see `AudioEngine::latency`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    pub sample_rate: u32,
+    /// Frames the device asks for per callback
+    pub buffer_frames: usize,
+    /// Time from a callback until its first sample plays, as the device
+    /// predicts it (None when it doesn't say)
+    pub output: Option<Duration>,
+}
+
+impl Latency {
+    /// Length of one buffer
+    pub fn buffer(&self) -> Duration {
+        Duration::from_secs_f64(self.buffer_frames as f64 / self.sample_rate.max(1) as f64)
+    }
+
+    /// Longest time from sending a command to hearing it: it waits up to a
+    /// buffer for the next callback, then the device's output latency (one
+    /// more buffer when the device doesn't report it)
+    pub fn total(&self) -> Duration {
+        self.buffer() + self.output.unwrap_or_else(|| self.buffer())
+    }
+}
+
+/// Frames rendered between checks for new commands, so a command sent
+/// while a large buffer is rendering still lands in it
+const COMMAND_FRAMES: usize = 64;
+
+/// What the audio callback publishes for the engine to read
+#[derive(Debug, Default)]
+struct StreamStats {
+    /// Voices sounding as of the last buffer
+    voices: AtomicUsize,
+    /// Frames in the last buffer (0 before the first callback)
+    buffer_frames: AtomicUsize,
+    /// The device's predicted output latency at the last callback, in
+    /// microseconds (0 when it doesn't predict one)
+    output_micros: AtomicU64,
+}
+
+/// The audio callback's state
+struct Callback {
+    synth: Synth,
+    cmd_rx: mpsc::Receiver<LiveCommand>,
+    stats: Arc<StreamStats>,
+    /// The recording's tap arrives once the stream has been opened
+    tap_rx: mpsc::Receiver<Tap>,
+    tap: Option<Tap>,
+}
+
+impl Callback {
+    fn new(synth: Synth, stats: Arc<StreamStats>) -> (Self, StreamInput) {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (tap_tx, tap_rx) = mpsc::channel();
+        let callback = Callback {
+            synth,
+            cmd_rx,
+            stats,
+            tap_rx,
+            tap: None,
+        };
+        (callback, StreamInput { cmd_tx, tap_tx })
+    }
+
+    /// Render into `out`, applying pending commands before every
+    /// `COMMAND_FRAMES` frames, and record it
+    fn render(&mut self, out: &mut [f32]) {
+        if self.tap.is_none() {
+            self.tap = self.tap_rx.try_recv().ok();
+        }
+        for block in out.chunks_mut(COMMAND_FRAMES * self.synth.channels) {
+            while let Ok(cmd) = self.cmd_rx.try_recv() {
+                self.synth.process_command(cmd);
+            }
+            self.synth.render(block);
+        }
+        if let Some(tap) = &self.tap {
+            tap.push(out);
+        }
+        self.stats.voices.store(self.synth.active_voices(), Ordering::Relaxed);
+    }
+
+    /// Note the size and timing of the buffer the backend is filling
+    fn observe(&self, info: &BufferInfo) {
+        self.stats.buffer_frames.store(info.frames, Ordering::Relaxed);
+        let micros = info.output_latency.map_or(0, |d| d.as_micros() as u64);
+        self.stats.output_micros.store(micros, Ordering::Relaxed);
+    }
+}
+
+/// The ends of a stream's callback the engine keeps
+struct StreamInput {
+    cmd_tx: mpsc::Sender<LiveCommand>,
+    tap_tx: mpsc::Sender<Tap>,
+}
+
+impl AudioEngine {
+    /// Create an engine with one patch per track (track index = position)
+    /// and the buses and reverb in `mix`, playing on the device and at the
+    /// settings in `output`. Requested settings the device can't use are
+    /// replaced with a printed warning.
+    pub fn new(
+        patches: Vec<Patch>,
+        mix: &Mix,
+        output: &OutputOptions,
+    ) -> Result<Self, ClidawError> {
+        let backend = Box::new(CpalBackend::new(output.clone()));
+        Self::with_backend(backend, patches, mix, None)
+    }
+
+    /// Like `new`, also writing everything the engine plays to a WAV file
+    /// at `record` in the stream's sample rate and channel count.
+    pub fn recording(
+        patches: Vec<Patch>,
+        mix: &Mix,
+        output: &OutputOptions,
+        record: &Path,
+    ) -> Result<Self, ClidawError> {
+        let backend = Box::new(CpalBackend::new(output.clone()));
+        Self::with_backend(backend, patches, mix, Some(record))
+    }
+
+    /// Create an engine playing through `backend`, recording to `record`
+    /// if given. The synth is timed at whatever rate the backend opens at.
+    pub fn with_backend(
+        mut backend: Box<dyn AudioBackend>,
+        patches: Vec<Patch>,
+        mix: &Mix,
+        record: Option<&Path>,
+    ) -> Result<Self, ClidawError> {
+        if patches.is_empty() {
+            return Err(ClidawError::Audio("at least one instrument required".to_string()));
+        }
+        let routes = patches.iter().enumerate().filter_map(|(t, p)| Some((t, p.output_channel?)));
+        let needed = routes.clone().map(|(_, c)| c + 1).max().unwrap_or(1);
+        let needed = u16::try_from(needed).unwrap_or(u16::MAX);
+
+        let stats = Arc::new(StreamStats::default());
+        let mut input = None;
+        // Envelopes, glides and effect buffers are timed at the rate
+        // actually chosen, so they stay in tune whatever the device runs at
+        let format = backend.open(needed, &mut |format: StreamFormat| {
+            let channels = format.channels as usize;
+            let synth = Synth::new(&patches, mix, format.sample_rate as f64, channels);
+            let (mut callback, stream_input) = Callback::new(synth, Arc::clone(&stats));
+            input = Some(stream_input);
+            Box::new(move |out: &mut [f32], info: &BufferInfo| {
+                callback.observe(info);
+                callback.render(out);
+            })
+        })?;
+        let input = input.expect("an opened backend has built its callback");
+        let available = format.channels as usize;
+        if let Some((track, channel)) = routes.into_iter().find(|&(_, c)| c >= available) {
+            return Err(ClidawError::Audio(format!(
+                "track {} is routed to output channel {}, but the device has {} output \
+                 channel{} (0-{})",
+                track,
+                channel,
+                available,
+                if available == 1 { "" } else { "s" },
+                available - 1
+            )));
+        }
+
+        let recorder = match record {
+            Some(path) => {
+                let (recorder, tap) = Recorder::start(path, format.sample_rate, format.channels)?;
+                let _ = input.tap_tx.send(tap);
+                Some(recorder)
+            }
+            None => None,
+        };
+        backend.start()?;
+
+        Ok(AudioEngine {
+            cmd_tx: input.cmd_tx,
+            stats,
+            recorder,
+            backend,
+        })
+    }
+
+    /// Send a command to the audio thread
+    pub fn send(&self, cmd: LiveCommand) -> Result<(), ClidawError> {
+        self.cmd_tx
+            .send(cmd)
+            .map_err(|_| ClidawError::Audio("audio thread disconnected".to_string()))
+    }
+
+    /// A sender for commands from another thread (e.g. a metronome clock)
+    pub fn sender(&self) -> mpsc::Sender<LiveCommand> {
+        self.cmd_tx.clone()
+    }
+
+    /// Voices sounding as of the last audio buffer
+    pub fn active_voices(&self) -> usize {
+        self.stats.voices.load(Ordering::Relaxed)
+    }
+
+    /// The stream's buffer size and output latency, once its first callback
+    /// has run (None if none has within `wait`)
+    pub fn latency(&self, wait: Duration) -> Option<Latency> {
+        let deadline = std::time::Instant::now() + wait;
+        loop {
+            let buffer_frames = self.stats.buffer_frames.load(Ordering::Relaxed);
+            if buffer_frames > 0 {
+                let micros = self.stats.output_micros.load(Ordering::Relaxed);
+                return Some(Latency {
+                    sample_rate: self.backend.format().map_or(0, |f| f.sample_rate),
+                    buffer_frames,
+                    output: (micros > 0).then(|| Duration::from_micros(micros)),
+                });
+            }
+            if std::time::Instant::now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Whether this engine was opened with `recording`
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Stop the stream and finish the recording file, if there is one
+    pub fn finish_recording(self) -> Result<Option<RecordingSummary>, ClidawError> {
+        let AudioEngine {
+            recorder,
+            mut backend,
+            ..
+        } = self;
+        // The callback owns the tap; stopping lets the writer drain and finish
+        backend.stop();
+        recorder.map(Recorder::finish).transpose()
+    }
+}
+
+/// Whether playback should stop: `stop` was raised or Ctrl-C was pressed
+fn is_stopped(stop: Option<&AtomicBool>) -> bool {
+    stop.is_some_and(|s| s.load(Ordering::Relaxed)) || crate::interrupt::requested()
+}
+
+/// Time for the engine to render the shutdown fade before it is dropped
+const HALT_SECS: f64 = 0.05;
+
+/// Silence everything when playback is stopped early. After Ctrl-C the
+/// released notes get up to `release_secs` (bounded) to fade out first;
+/// otherwise (e.g. a `--watch` restart) they are cut off at once.
+fn halt(
+    clock: &mut impl Clock,
+    release_secs: f64,
+    mut send: impl FnMut(LiveCommand) -> Result<(), ClidawError>,
+) {
+    if crate::interrupt::requested() {
+        let _ = send(LiveCommand::AllNotesOff { track: None });
+        clock.sleep(release_secs.min(crate::interrupt::MAX_RELEASE_WAIT_SECS));
+    } else {
+        let _ = send(LiveCommand::HardStop);
+    }
+    let _ = send(LiveCommand::Shutdown);
+}
+
+/// Time source for schedule playback, so tests can run without waiting
+trait Clock {
+    /// Seconds since playback started
+    fn elapsed(&self) -> f64;
+    fn sleep(&mut self, secs: f64);
+}
+
+/// Wall-clock time
+struct SystemClock {
+    start: std::time::Instant,
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn sleep(&mut self, secs: f64) {
+        std::thread::sleep(std::time::Duration::from_secs_f64(secs));
+    }
+}
+
+/// Longest single sleep during schedule playback, so the status line keeps
+/// updating through long notes and rests
+const TICK_SECS: f64 = 0.1;
+
+/// Sleep until `target_secs`, calling `tick` with the elapsed time at least
+/// every `TICK_SECS`; returns at once if that time has already passed.
+/// Returns false as soon as `tick` does (playback was stopped).
+fn sleep_until(clock: &mut impl Clock, target_secs: f64, tick: &mut impl FnMut(f64) -> bool) -> bool {
+    loop {
+        let now = clock.elapsed();
+        if !tick(now) {
+            return false;
+        }
+        let remaining = target_secs - now;
+        if remaining <= 0.0 {
+            return true;
+        }
+        clock.sleep(remaining.min(TICK_SECS));
+    }
+}
+
+/// Extra time after the longest tail before shutting down, covering the
+/// audio buffer still queued in the device
+const RING_OUT_MARGIN_SECS: f64 = 0.1;
+
+/// How long to keep the engine running after the last scheduled event so
+/// every track's release (or drum decay) finishes, followed by the reverb's
+/// tail.
+pub fn ring_out_secs(patches: &[Patch], mix: &Mix) -> f64 {
+    let longest = patches
+        .iter()
+        .map(|p| {
+            let drums = p
+                .kit
+                .as_ref()
+                .map(|k| k.kick_decay.max(k.snare_decay).max(k.hat_decay))
+                .unwrap_or(0.0);
+            let chorus = p
+                .chorus
+                .as_ref()
+                .filter(|c| c.mix > 0.0)
+                .map_or(0.0, |_| CHORUS_MAX_DELAY_MS / 1000.0);
+            let delay = p.delay.as_ref().filter(|d| d.mix > 0.0).map_or(0.0, Delay::tail_secs);
+            let chain: f64 = p.effects.iter().map(Effect::tail_secs).sum();
+            p.adsr.release.max(drums) + chorus + delay + chain
+        })
+        .fold(0.0, f64::max);
+    longest + mix.reverb.tail_secs() + RING_OUT_MARGIN_SECS
+}
+
+/// What the playback status line shows: position in bars and the song length
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Where bars fall (from the start of a pass when looping)
+    pub meter: MeterMap,
+    /// Beat of the last scheduled event
+    pub total_beats: f64,
+    /// When looping: the beat the first pass starts on and the pass length
+    pub looping: Option<(f64, f64)>,
+}
+
+impl Progress {
+    /// Progress through `stream`
+    pub fn of(stream: &crate::scheduler::SongStream) -> Self {
+        Self {
+            meter: stream.meter.clone(),
+            total_beats: stream.end_beat.map_or(f64::INFINITY, Beat::as_f64),
+            looping: stream.looping.map(|(first, period)| (first.as_f64(), period.as_f64())),
+        }
+    }
+
+    /// Status line such as `bar 3:2  0:05 / 1:30  voices 4`, or
+    /// `loop 2  bar 1:4  0:12  voices 4` when looping
+    fn status_line(&self, elapsed: f64, tempo: f64, voices: usize) -> String {
+        let time = if self.looping.is_some() {
+            format_secs(elapsed)
+        } else {
+            let total = self.total_beats / (tempo / 60.0);
+            format!("{} / {}", format_secs(elapsed.min(total)), format_secs(total))
+        };
+        format!("{}  {}  voices {}", self.position(elapsed, tempo), time, voices)
+    }
+
+    /// Where playback is `elapsed` seconds in: `bar 3:2`, or `loop 2  bar
+    /// 1:4` when looping
+    pub fn position(&self, elapsed: f64, tempo: f64) -> String {
+        let beats = elapsed * tempo / 60.0;
+        if let Some((first, period)) = self.looping {
+            let into = (beats - first).max(0.0);
+            let (bar, beat) = self.bar_and_beat(into.rem_euclid(period));
+            return format!("loop {}  bar {}:{}", (into / period) as u64 + 1, bar, beat);
+        }
+        let (bar, beat) = self.bar_and_beat(beats.min(self.total_beats));
+        format!("bar {}:{}", bar, beat)
+    }
+
+    /// 1-based bar and beat within it of the position `beat`
+    fn bar_and_beat(&self, beat: f64) -> (u32, u32) {
+        let bar = self.meter.bar_at_beat(beat);
+        let into = beat - self.meter.beat_of_bar(bar);
+        (bar + 1, (into + 1e-9) as u32 + 1)
+    }
+}
+
+/// Whole seconds as m:ss
+pub fn format_secs(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Run a sorted stream of (beat, command); blocks until playback finishes.
+/// Events are pulled as they come due, so a lazily built schedule starts
+/// playing immediately. After the last event the engine keeps running for
+/// `ring_out` seconds. With `progress`, a status line on stdout is redrawn
+/// every `TICK_SECS`. Raising `stop` (or Ctrl-C) silences the engine and
+/// returns early.
 pub fn play_schedule(
-    schedule: &[crate::scheduler::ScheduledEvent],
-    tempo: u32,
+    schedule: impl IntoIterator<Item = crate::scheduler::ScheduledEvent>,
+    tempo: f64,
+    ring_out: f64,
     engine: &AudioEngine,
-) -> Result<(), String> {
-    let beat_duration = 60.0 / tempo as f64;
-    let start = std::time::Instant::now();
+    progress: Option<&Progress>,
+    stop: Option<&AtomicBool>,
+) -> Result<(), ClidawError> {
+    use std::io::Write;
+
+    let mut clock = SystemClock {
+        start: std::time::Instant::now(),
+    };
+    let mut next_draw = 0.0;
+    let tick = |elapsed: f64| {
+        if is_stopped(stop) {
+            return false;
+        }
+        if let Some(progress) = progress
+            && elapsed >= next_draw
+        {
+            next_draw = elapsed + TICK_SECS;
+            let line = progress.status_line(elapsed, tempo, engine.active_voices());
+            print!("\r{}\x1b[K", line);
+            let _ = std::io::stdout().flush();
+        }
+        true
+    };
+    let send = |cmd| engine.send(cmd);
+    let result = run_to_silence(schedule, tempo, ring_out, &mut clock, tick, send);
+    if progress.is_some() {
+        println!();
+    }
+    result
+}
+
+/// Play `schedule` from `start` while other input reaches the engine too
+/// (a `live --backing` song), until it ends or `stop` is set. Its end
+/// doesn't shut the engine down; that is left to whoever owns it.
+pub fn play_alongside(
+    schedule: impl IntoIterator<Item = crate::scheduler::ScheduledEvent>,
+    tempo: f64,
+    start: std::time::Instant,
+    stop: &AtomicBool,
+    mut send: impl FnMut(LiveCommand) -> Result<(), ClidawError>,
+) -> Result<(), ClidawError> {
+    let mut clock = SystemClock { start };
+    let tick = |_| !stop.load(Ordering::Relaxed);
+    run_schedule(schedule, tempo, 0.0, &mut clock, tick, |cmd| match cmd {
+        LiveCommand::Shutdown => Ok(()),
+        cmd => send(cmd),
+    })
+}
+
+/// `run_schedule`, then let the shutdown fade play before the caller drops
+/// the engine, whether playback ran out or was stopped, so the last buffer
+/// ends in silence
+fn run_to_silence(
+    schedule: impl IntoIterator<Item = crate::scheduler::ScheduledEvent>,
+    tempo: f64,
+    ring_out: f64,
+    clock: &mut impl Clock,
+    tick: impl FnMut(f64) -> bool,
+    send: impl FnMut(LiveCommand) -> Result<(), ClidawError>,
+) -> Result<(), ClidawError> {
+    let result = run_schedule(schedule, tempo, ring_out, clock, tick, send);
+    clock.sleep(HALT_SECS);
+    result
+}
+
+fn run_schedule(
+    schedule: impl IntoIterator<Item = crate::scheduler::ScheduledEvent>,
+    tempo: f64,
+    ring_out: f64,
+    clock: &mut impl Clock,
+    mut tick: impl FnMut(f64) -> bool,
+    mut send: impl FnMut(LiveCommand) -> Result<(), ClidawError>,
+) -> Result<(), ClidawError> {
+    let mut last_beat = Beat::ZERO;
 
     for ev in schedule {
-        let target_secs = ev.beat * beat_duration;
-        let elapsed = start.elapsed().as_secs_f64();
-        if target_secs > elapsed {
-            std::thread::sleep(std::time::Duration::from_secs_f64(target_secs - elapsed));
+        if !sleep_until(clock, crate::scheduler::beats_to_secs(ev.beat, tempo), &mut tick) {
+            halt(clock, ring_out, send);
+            return Ok(());
         }
-        engine.send(ev.command.clone())?;
+        send(ev.command)?;
+        last_beat = ev.beat;
     }
 
     // Let last notes ring out
-    let last_beat = schedule.last().map(|e| e.beat).unwrap_or(0.0);
-    std::thread::sleep(std::time::Duration::from_secs_f64(
-        last_beat * beat_duration + 0.5 - start.elapsed().as_secs_f64(),
-    ));
-    let _ = engine.send(LiveCommand::Shutdown);
+    let end = crate::scheduler::beats_to_secs(last_beat, tempo) + ring_out;
+    if !sleep_until(clock, end, &mut tick) {
+        halt(clock, ring_out, send);
+        return Ok(());
+    }
+    let _ = send(LiveCommand::Shutdown);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_oscillator_is_not_detuned() {
+        let patch = Patch {
+            detune: 25.0,
+            ..Patch::default()
+        };
+        assert_eq!(patch.unison_ratios(), vec![1.0]);
+    }
+
+    #[test]
+    fn test_unison_spread_is_symmetric() {
+        let patch = Patch {
+            unison: 3,
+            detune: 12.0,
+            ..Patch::default()
+        };
+        let ratios = patch.unison_ratios();
+        assert_eq!(ratios.len(), 3);
+        assert!((ratios[0] - 2.0_f64.powf(-6.0 / 1200.0)).abs() < 1e-12);
+        assert_eq!(ratios[1], 1.0);
+        assert!((ratios[0] * ratios[2] - 1.0).abs() < 1e-12);
+    }
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn render_secs(synth: &mut Synth, secs: f64, out: &mut Vec<f32>) {
+        let mut buf = vec![0.0_f32; (secs * SAMPLE_RATE) as usize];
+        synth.render(&mut buf);
+        out.extend_from_slice(&buf);
+    }
+
+    fn max_jump(samples: &[f32]) -> f32 {
+        samples
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    fn note_on(key: char, freq: f64) -> LiveCommand {
+        LiveCommand::NoteOn {
+            track: 0,
+            key,
+            freq,
+            velocity: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_mono_track_plays_the_newest_held_key() {
+        let patch = Patch {
+            mono: true,
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+        let sounding = |synth: &Synth| {
+            let v: Vec<_> = synth.voices.iter().filter(|v| v.is_held()).collect();
+            assert!(v.len() <= 1, "mono track sounded {} voices", v.len());
+            v.first().map(|v| (v.key, v.freq))
+        };
+        synth.process_command(note_on('a', 220.0));
+        synth.process_command(note_on('s', 330.0));
+        synth.process_command(note_on('d', 440.0));
+        assert_eq!(synth.voices.len(), 1);
+        assert_eq!(sounding(&synth), Some(('d', 440.0)));
+
+        // Releasing an older key changes nothing; the newest returns to the
+        // one still held before it
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 's' });
+        assert_eq!(sounding(&synth), Some(('d', 440.0)));
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'd' });
+        assert_eq!(sounding(&synth), Some(('a', 220.0)));
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+        assert_eq!(sounding(&synth), None);
+        assert_eq!(synth.voices.len(), 1, "the last key releases normally");
+    }
+
+    #[test]
+    fn test_mono_glide_slides_evenly_in_pitch() {
+        let patch = Patch {
+            mono: true,
+            glide: 0.01,
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+        synth.process_command(note_on('a', 220.0));
+        synth.process_command(note_on('s', 880.0));
+        let mut out = Vec::new();
+        // Halfway through the glide is halfway in pitch: one octave up
+        render_secs(&mut synth, 0.005, &mut out);
+        assert!((synth.voices[0].freq - 440.0).abs() < 0.5, "{}", synth.voices[0].freq);
+        render_secs(&mut synth, 0.01, &mut out);
+        assert_eq!(synth.voices[0].freq, 880.0);
+    }
+
+    /// Amplitude of the `freq` component of `samples` (Goertzel)
+    fn goertzel(samples: &[f32], freq: f64) -> f64 {
+        let coeff = 2.0 * (2.0 * std::f64::consts::PI * freq / SAMPLE_RATE).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for &x in samples {
+            let s0 = x as f64 + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        (s1 * s1 + s2 * s2 - coeff * s1 * s2).sqrt() * 2.0 / samples.len() as f64
+    }
+
+    #[test]
+    fn test_antialiased_waveforms_cut_aliases() {
+        // At 48 kHz the 9th harmonic of 5 kHz (45 kHz) folds back to 3 kHz
+        let spectrum = |waveform: Waveform, antialias: bool| {
+            let patch = Patch {
+                waveform,
+                antialias,
+                ..Patch::default()
+            };
+            let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(note_on('a', 5000.0));
+            let mut out = Vec::new();
+            render_secs(&mut synth, 0.7, &mut out);
+            // Past the attack and decay
+            let held = &out[(0.2 * SAMPLE_RATE) as usize..];
+            (goertzel(held, 5000.0), goertzel(held, 3000.0))
+        };
+        for waveform in [Waveform::Saw, Waveform::Square, Waveform::Triangle] {
+            let (naive_fundamental, naive_alias) = spectrum(waveform, false);
+            let (fundamental, alias) = spectrum(waveform, true);
+            assert!(
+                alias < naive_alias / 4.0,
+                "{:?}: alias {} vs {} naive",
+                waveform,
+                alias,
+                naive_alias
+            );
+            // The note itself is barely touched
+            assert!((fundamental / naive_fundamental - 1.0).abs() < 0.2, "{:?}", waveform);
+        }
+    }
+
+    #[test]
+    fn test_fm_adds_sidebands() {
+        // Level of the note and its 2nd and 3rd harmonics, 0.2 s to 0.6 s in
+        let spectrum = |fm: Option<Fm>| {
+            let patch = Patch {
+                fm,
+                ..Patch::default()
+            };
+            let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(note_on('a', 440.0));
+            let mut out = Vec::new();
+            render_secs(&mut synth, 0.6, &mut out);
+            let held = &out[(0.2 * SAMPLE_RATE) as usize..];
+            [440.0, 880.0, 1320.0].map(|freq| goertzel(held, freq))
+        };
+        let plain = spectrum(None);
+        assert!(plain[1] < 1e-3 && plain[2] < 1e-3, "{:?}", plain);
+
+        // At a 1:1 ratio the sidebands land on the harmonics
+        let fm = Fm {
+            ratio: 1.0,
+            index: 2.0,
+            index_env: false,
+            envelope: None,
+        };
+        let bright = spectrum(Some(fm.clone()));
+        assert!(bright[1] > 0.02 && bright[2] > 0.02, "{:?}", bright);
+        let silent_index = spectrum(Some(Fm { index: 0.0, ..fm.clone() }));
+        assert!((silent_index[0] - plain[0]).abs() < 1e-6);
+
+        // An index envelope that has decayed to nothing leaves a plain sine
+        let decayed = Fm {
+            envelope: Some(Adsr {
+                attack: 0.0,
+                decay: 0.1,
+                sustain: 0.0,
+                ..Adsr::default()
+            }),
+            ..fm.clone()
+        };
+        let faded = spectrum(Some(decayed));
+        assert!(faded[1] < 1e-3 && faded[2] < 1e-3, "{:?}", faded);
+
+        // Following the amplitude envelope (sustain 0.7) dims the sidebands
+        let following = spectrum(Some(Fm { index_env: true, ..fm }));
+        assert!(following[2] < bright[2] * 0.8, "{:?} vs {:?}", following, bright);
+    }
+
+    #[test]
+    fn test_reverb_rings_after_sending_tracks() {
+        // Level in the 100 ms after a short note has been released
+        let tail = |reverb_send: f64, mix: f64| {
+            let patch = Patch {
+                reverb_send,
+                ..Patch::default()
+            };
+            let mix = Mix {
+                reverb: Reverb {
+                    mix,
+                    ..Reverb::default()
+                },
+                ..Mix::default()
+            };
+            let mut synth = Synth::new(&[patch], &mix, SAMPLE_RATE, 1);
+            synth.process_command(LiveCommand::NoteOn {
+                track: 0,
+                key: 'a',
+                freq: 440.0,
+                velocity: 1.0,
+            });
+            let mut out = vec![0.0_f32; 4410];
+            synth.render(&mut out);
+            synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+            let mut out = vec![0.0_f32; 22_050];
+            synth.render(&mut out);
+            synth.render(&mut out[..4410]);
+            out[..4410].iter().fold(0.0_f32, |m, s| m.max(s.abs()))
+        };
+        assert!(tail(1.0, 0.5) > 0.001, "{}", tail(1.0, 0.5));
+        assert_eq!(tail(0.0, 0.5), 0.0);
+        assert_eq!(tail(1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_bus_gain_scales_its_tracks_together() {
+        let on_bus = Patch {
+            bus: Some(0),
+            ..Patch::default()
+        };
+        let peak = |buses: Vec<Bus>| {
+            let patches = [on_bus.clone(), on_bus.clone()];
+            let mix = Mix {
+                buses,
+                ..Mix::default()
+            };
+            let mut synth = Synth::new(&patches, &mix, SAMPLE_RATE, 1);
+            synth.process_command(LiveCommand::SetLimiter(false));
+            for track in 0..2 {
+                synth.process_command(LiveCommand::NoteOn {
+                    track,
+                    key: 'a',
+                    freq: 440.0,
+                    velocity: 1.0,
+                });
+            }
+            let mut out = vec![0.0_f32; 2000];
+            synth.render(&mut out);
+            out.iter().fold(0.0_f32, |m, s| m.max(s.abs()))
+        };
+        let full = peak(vec![Bus::default()]);
+        let half = peak(vec![Bus {
+            gain: 0.5,
+            ..Bus::default()
+        }]);
+        assert!(full > 0.1, "{}", full);
+        assert!((half - full * 0.5).abs() < 1e-4, "{} vs {}", half, full);
+        // Without the bus it names, a track goes straight to the master mix
+        assert_eq!(peak(Vec::new()), full);
+    }
+
+    #[test]
+    fn test_track_gain_and_master_volume() {
+        let peak = |patch: Patch, master_volume: f64, notes: &[f64]| {
+            let mix = Mix {
+                master_volume,
+                ..Mix::default()
+            };
+            let mut synth = Synth::new(&[patch], &mix, SAMPLE_RATE, 1);
+            synth.process_command(LiveCommand::SetLimiter(false));
+            for (key, &freq) in ('a'..).zip(notes) {
+                synth.process_command(note_on(key, freq));
+            }
+            // Past the normalized gain's settling time
+            let mut out = Vec::new();
+            render_secs(&mut synth, 0.1, &mut out);
+            out[2400..].iter().fold(0.0_f32, |m, s| m.max(s.abs()))
+        };
+        let loud = Patch {
+            gain: 0.6,
+            ..Patch::default()
+        };
+        let single = peak(Patch::default(), 1.0, &[440.0]);
+        assert!(single > 0.1 && single < DEFAULT_GAIN as f32, "{}", single);
+        assert!((peak(loud.clone(), 1.0, &[440.0]) - single * 2.0).abs() < 1e-3);
+        assert!((peak(loud, 0.5, &[440.0]) - single).abs() < 1e-3);
+
+        // Normalized, four voices in unison sum to twice one voice, not four times
+        let unison = [440.0; 4];
+        assert!((peak(Patch::default(), 1.0, &unison) - single * 4.0).abs() < 0.01);
+        let normalized = Patch {
+            normalize: true,
+            ..Patch::default()
+        };
+        let chord = peak(normalized, 1.0, &unison);
+        assert!((chord - single * 2.0).abs() < 0.01, "{} vs {}", chord, single);
+    }
+
+    #[test]
+    fn test_velocity_scaling_curves() {
+        let full = VelocityResponse::default();
+        assert_eq!(full.amplitude(0.5), 0.5);
+        assert_eq!(full.attack_scale(1.0), 1.0);
+        let response = VelocityResponse {
+            to_amp: 0.6,
+            to_attack: 0.5,
+        };
+        // Softer notes are quieter, but less so than their velocity
+        assert!((response.amplitude(0.5) - 0.7).abs() < 1e-12);
+        assert_eq!(response.amplitude(1.0), 1.0);
+        assert_eq!(response.attack_scale(1.0), 0.5);
+        assert_eq!(response.attack_scale(0.0), 1.0);
+        let instant = VelocityResponse {
+            to_amp: 0.0,
+            to_attack: 1.0,
+        };
+        assert_eq!(instant.amplitude(0.2), 1.0);
+        assert_eq!(instant.attack_scale(1.0), 0.0);
+    }
+
+    #[test]
+    fn test_velocity_shortens_the_attack() {
+        let patch = Patch {
+            adsr: Adsr {
+                attack: 0.1,
+                ..Adsr::default()
+            },
+            velocity: VelocityResponse {
+                to_amp: 1.0,
+                to_attack: 0.5,
+            },
+            ..Patch::default()
+        };
+        let stage_after = |velocity: f64, secs: f64| {
+            let patches = std::slice::from_ref(&patch);
+            let mut synth = Synth::new(patches, &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(LiveCommand::NoteOn {
+                track: 0,
+                key: 'a',
+                freq: 440.0,
+                velocity,
+            });
+            render_secs(&mut synth, secs, &mut Vec::new());
+            (synth.voices[0].env_stage, synth.voices[0].level(&synth.adsrs[0]))
+        };
+        // A full-velocity note peaks in half the attack time
+        assert_eq!(stage_after(1.0, 0.06).0, EnvStage::Decay);
+        let (stage, level) = stage_after(0.5, 0.06);
+        assert_eq!(stage, EnvStage::Attack);
+        assert!((level - 0.8).abs() < 0.01, "{}", level);
+        assert_eq!(stage_after(0.0, 0.09).0, EnvStage::Attack);
+    }
+
+    #[test]
+    fn test_routed_tracks_play_alone_on_their_channel() {
+        let routed = Patch {
+            output_channel: Some(3),
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[Patch::default(), routed], &Mix::default(), SAMPLE_RATE, 4);
+        synth.process_command(LiveCommand::NoteOn {
+            track: 1,
+            key: 'a',
+            freq: 440.0,
+            velocity: 1.0,
+        });
+        let mut out = vec![0.0_f32; 4 * 2000];
+        synth.render(&mut out);
+        let peak = |out: &[f32], channel: usize| {
+            out.iter().skip(channel).step_by(4).fold(0.0_f32, |m, s| m.max(s.abs()))
+        };
+        assert_eq!((peak(&out, 0), peak(&out, 1), peak(&out, 2)), (0.0, 0.0, 0.0));
+        assert!(peak(&out, 3) > 0.1, "{}", peak(&out, 3));
+
+        // The shared mix plays on channels 0 and 1 only
+        synth.process_command(note_on('s', 220.0));
+        synth.render(&mut out);
+        assert!(peak(&out, 0) > 0.1 && peak(&out, 1) > 0.1);
+        assert_eq!(peak(&out, 2), 0.0);
+
+        // Without routing every channel carries the mix, as before
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 4);
+        synth.process_command(note_on('a', 440.0));
+        synth.render(&mut out);
+        assert!(out.chunks(4).all(|f| f.iter().all(|&s| s == f[0])));
+        assert!(peak(&out, 2) > 0.1);
+    }
+
+    #[test]
+    fn test_track_effect_chain_pans_the_shared_mix() {
+        let panned = Patch {
+            effects: crate::effects::parse_chain("lowpass(2000, 0) > pan(-1)").unwrap(),
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[panned, Patch::default()], &Mix::default(), SAMPLE_RATE, 3);
+        synth.process_command(note_on('a', 440.0));
+        let mut out = vec![0.0_f32; 3 * 2000];
+        synth.render(&mut out);
+        let peak = |out: &[f32], channel: usize| {
+            out.iter().skip(channel).step_by(3).fold(0.0_f32, |m, s| m.max(s.abs()))
+        };
+        // Left only; a third channel gets both sides mixed
+        assert!(peak(&out, 0) > 0.1, "{}", peak(&out, 0));
+        assert_eq!(peak(&out, 1), 0.0);
+        assert!(out.chunks(3).all(|f| f[2] == f[0] / 2.0));
+
+        // The unpanned track stays in the middle
+        synth.process_command(LiveCommand::AllNotesOff { track: None });
+        let mut quiet = vec![0.0_f32; 3 * 44_100];
+        synth.render(&mut quiet);
+        synth.process_command(LiveCommand::NoteOn {
+            track: 1,
+            key: 'a',
+            freq: 440.0,
+            velocity: 1.0,
+        });
+        synth.render(&mut out);
+        assert!(out.chunks(3).all(|f| f[0] == f[1] && f[1] == f[2]));
+        assert!(peak(&out, 1) > 0.1);
+        // Mono output hears the panned track at half level
+        let panned = Patch {
+            effects: crate::effects::parse_chain("pan(-1)").unwrap(),
+            ..Patch::default()
+        };
+        let mut mono = Synth::new(&[panned, Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        let mut centred = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        mono.process_command(note_on('a', 440.0));
+        centred.process_command(note_on('a', 440.0));
+        let (mut a, mut b) = (vec![0.0_f32; 500], vec![0.0_f32; 500]);
+        mono.render(&mut a);
+        centred.render(&mut b);
+        assert!(a.iter().zip(&b).all(|(a, b)| (a - b / 2.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_automated_params_ramp_to_their_values() {
+        let filtered = Patch {
+            effects: crate::effects::parse_chain("lowpass(2000, 0)").unwrap(),
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[filtered, Patch::default()], &Mix::default(), SAMPLE_RATE, 2);
+        let set = |track, param, value| LiveCommand::SetParam {
+            track,
+            param,
+            value,
+        };
+        synth.process_command(note_on('a', 440.0));
+        let mut out = vec![0.0_f32; 2 * 4800];
+        synth.render(&mut out);
+        synth.process_command(set(0, Param::Gain, 0.0));
+        synth.process_command(set(0, Param::Cutoff, 500.0));
+        // A track without a filter has no cutoff to move
+        synth.process_command(set(1, Param::Cutoff, 500.0));
+        assert!(synth.params[1].cutoff.is_none());
+
+        let ramp = (PARAM_RAMP_SECS * SAMPLE_RATE) as usize;
+        let mut fading = vec![0.0_f32; 2 * ramp];
+        synth.render(&mut fading);
+        // Down over the ramp without a click, then silent
+        assert!(max_jump(&fading) < 0.05, "{}", max_jump(&fading));
+        assert!(fading[..200].iter().any(|s| s.abs() > 0.05));
+        synth.render(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+        let cutoff = synth.chains[0].as_ref().and_then(EffectChain::cutoff);
+        assert_eq!(cutoff, Some(500.0));
+
+        // Panned hard left, the track leaves the right channel
+        synth.process_command(set(0, Param::Gain, 1.0));
+        synth.process_command(set(0, Param::Pan, -1.0));
+        synth.render(&mut fading);
+        synth.render(&mut out);
+        assert!(out.iter().step_by(2).any(|s| s.abs() > 0.05));
+        assert!(out.iter().skip(1).step_by(2).all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_no_clicks_at_note_boundaries() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+
+        synth.process_command(note_on('a', 440.0));
+        render_secs(&mut synth, 0.1, &mut out);
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+        render_secs(&mut synth, 0.05, &mut out);
+        // Retrigger mid-release at a new pitch
+        synth.process_command(note_on('a', 660.0));
+        render_secs(&mut synth, 0.1, &mut out);
+        // Cut off while sustaining
+        synth.process_command(LiveCommand::Shutdown);
+        render_secs(&mut synth, 0.01, &mut out);
+
+        // A full-scale 660 Hz sine moves at most ~0.026 per sample
+        assert!(max_jump(&out) < 0.05, "jump of {}", max_jump(&out));
+        assert_eq!(*out.last().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_limiter_keeps_chord_in_range() {
+        let chord = |limiter: bool| {
+            let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(LiveCommand::SetLimiter(limiter));
+            // Twelve harmonics of one root, so their peaks line up
+            for (i, key) in "asdfghjkl;'w".chars().enumerate() {
+                synth.process_command(note_on(key, 110.0 * (i + 1) as f64));
+            }
+            let mut out = Vec::new();
+            render_secs(&mut synth, 0.5, &mut out);
+            out.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()))
+        };
+        assert!(chord(false) > 1.0);
+        let peak = chord(true);
+        assert!(peak <= LIMITER_THRESHOLD as f32, "peak {}", peak);
+    }
+
+    #[test]
+    fn test_envelope_stage_transitions() {
+        let patch = Patch {
+            adsr: Adsr {
+                attack: 0.01,
+                decay: 0.02,
+                sustain: 0.5,
+                release: 0.05,
+                ..Adsr::default()
+            },
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        let stage = |synth: &Synth| synth.voices[0].env_stage;
+        let level = |synth: &Synth| synth.voices[0].level(&synth.adsrs[0]);
+
+        synth.process_command(note_on('a', 440.0));
+        render_secs(&mut synth, 0.005, &mut out);
+        assert_eq!(stage(&synth), EnvStage::Attack);
+        assert!((level(&synth) - 0.5).abs() < 0.01);
+        render_secs(&mut synth, 0.01, &mut out);
+        assert_eq!(stage(&synth), EnvStage::Decay);
+        render_secs(&mut synth, 0.02, &mut out);
+        assert_eq!(stage(&synth), EnvStage::Sustain);
+        assert_eq!(level(&synth), 0.5);
+
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+        assert_eq!(stage(&synth), EnvStage::Release);
+        render_secs(&mut synth, 0.025, &mut out);
+        assert!((level(&synth) - 0.25).abs() < 0.01);
+        render_secs(&mut synth, 0.03, &mut out);
+        assert_eq!(synth.active_voices(), 0);
+
+        let mut tail = Vec::new();
+        render_secs(&mut synth, 0.01, &mut tail);
+        assert!(tail.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_voice_lifecycle() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        for key in ['a', 's', 'd'] {
+            synth.process_command(note_on(key, 440.0));
+        }
+        render_secs(&mut synth, 0.05, &mut out);
+        assert_eq!(synth.active_voices(), 3);
+
+        // A NoteOff releases only its own key; an unknown key does nothing
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 's' });
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'z' });
+        render_secs(&mut synth, Adsr::default().release + 0.01, &mut out);
+        let keys: Vec<char> = synth.voices.iter().map(|v| v.key).collect();
+        assert_eq!(keys, vec!['a', 'd']);
+
+        synth.process_command(LiveCommand::AllNotesOff { track: None });
+        assert!(synth.voices.iter().all(|v| v.env_stage == EnvStage::Release));
+        synth.process_command(LiveCommand::Shutdown);
+        assert!(synth.voices.iter().all(|v| v.env_stage == EnvStage::Fade));
+        render_secs(&mut synth, FADE_SECS + 0.001, &mut out);
+        assert_eq!(synth.active_voices(), 0);
+    }
+
+    #[test]
+    fn test_tracks_mix_by_summing() {
+        let patches = [
+            Patch::default(),
+            Patch {
+                adsr: Adsr {
+                    attack: 0.05,
+                    release: 1.0,
+                    ..Adsr::default()
+                },
+                ..Patch::default()
+            },
+        ];
+        let play = |tracks: &[usize]| {
+            let mut synth = Synth::new(&patches, &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(LiveCommand::SetLimiter(false));
+            for &track in tracks {
+                // The same key on each track is a separate voice
+                synth.process_command(LiveCommand::NoteOn {
+                    track,
+                    key: 'a',
+                    freq: 220.0 * (track + 1) as f64,
+                    velocity: 1.0,
+                });
+            }
+            let mut out = Vec::new();
+            render_secs(&mut synth, 0.1, &mut out);
+            synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+            render_secs(&mut synth, 0.3, &mut out);
+            (out, synth.active_voices())
+        };
+
+        let (first, _) = play(&[0]);
+        let (second, _) = play(&[1]);
+        let (both, voices) = play(&[0, 1]);
+        // Track 0's voice has finished its release; track 1's is still held
+        assert_eq!(voices, 1);
+        for ((a, b), mixed) in first.iter().zip(&second).zip(&both) {
+            assert!((a + b - mixed).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_retrigger_starts_from_current_level() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        synth.process_command(note_on('a', 440.0));
+        render_secs(&mut synth, 0.2, &mut out);
+        let level = synth.voices[0].level(&synth.adsrs[0]);
+        synth.process_command(note_on('a', 440.0));
+        let after = synth.voices[0].level(&synth.adsrs[0]);
+        assert!((level - after).abs() < 1e-9);
+    }
+
+    /// Hold a note into its sustain, play `second` on the same track, and
+    /// return the level just before and each voice's level at every sample
+    /// of the next 20 ms
+    fn retrigger_trajectory(
+        retrigger: Retrigger,
+        mono: bool,
+        second: char,
+    ) -> (f64, Vec<Vec<f64>>) {
+        let patch = Patch {
+            retrigger: Some(retrigger),
+            mono,
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        synth.process_command(note_on('a', 440.0));
+        render_secs(&mut synth, 0.2, &mut out);
+        let before = synth.voices[0].level(&synth.adsrs[0]);
+        synth.process_command(note_on(second, 330.0));
+        let mut levels = Vec::new();
+        for _ in 0..(0.02 * SAMPLE_RATE) as usize {
+            levels.push(synth.voices.iter().map(|v| v.level(&synth.adsrs[0])).collect());
+            synth.render(&mut [0.0]);
+        }
+        (before, levels)
+    }
+
+    #[test]
+    fn test_retrigger_modes() {
+        let adsr = Adsr::default();
+        // The most the attack climbs in one sample
+        let attack_step = 1.0 / (adsr.attack * SAMPLE_RATE) + 1e-9;
+        for mono in [false, true] {
+            let key = if mono { 's' } else { 'a' };
+
+            // Continue: from the held level back up to the peak, never jumping
+            let (before, levels) = retrigger_trajectory(Retrigger::Continue, mono, key);
+            let voice: Vec<f64> = levels.iter().map(|l| l[0]).collect();
+            assert!((voice[0] - before).abs() < 1e-9, "{} vs {}", voice[0], before);
+            assert!(voice.windows(2).all(|w| w[1] - w[0] <= attack_step));
+            assert!(voice.iter().any(|&l| l > 0.99));
+
+            // Reset: the old voice fades out within RESET_FADE_SECS while a
+            // new one attacks from silence
+            let (before, levels) = retrigger_trajectory(Retrigger::Reset, mono, key);
+            assert_eq!(levels[0].len(), 2);
+            assert!((levels[0][0] - before).abs() < 1e-9);
+            assert_eq!(levels[0][1], 0.0);
+            let faded = (RESET_FADE_SECS * SAMPLE_RATE) as usize + 2;
+            assert_eq!(levels[faded].len(), 1, "{:?}", &levels[faded]);
+            let new: Vec<f64> = levels[faded..].iter().map(|l| l[0]).collect();
+            assert!(new.windows(2).all(|w| (w[1] - w[0]).abs() <= attack_step));
+
+            // Legato: the envelope carries on at the sustain level
+            let (before, levels) = retrigger_trajectory(Retrigger::Legato, mono, key);
+            assert_eq!(before, adsr.sustain);
+            assert!(levels.iter().all(|l| l == &[adsr.sustain]));
+        }
+
+        // Each mode sounds without a click
+        for retrigger in [Retrigger::Reset, Retrigger::Continue, Retrigger::Legato] {
+            let patch = Patch {
+                retrigger: Some(retrigger),
+                ..Patch::default()
+            };
+            let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+            let mut out = Vec::new();
+            synth.process_command(note_on('a', 440.0));
+            render_secs(&mut synth, 0.2, &mut out);
+            synth.process_command(note_on('a', 440.0));
+            render_secs(&mut synth, 0.02, &mut out);
+            assert!(max_jump(&out) < 0.05, "{:?}: {}", retrigger, max_jump(&out));
+        }
+    }
+
+    #[test]
+    fn test_releasing_key_gets_a_new_voice() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        synth.process_command(note_on('a', 440.0));
+        render_secs(&mut synth, 0.1, &mut out);
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+        render_secs(&mut synth, 0.01, &mut out);
+        synth.process_command(note_on('a', 330.0));
+        // The old note keeps its pitch and release under the new one
+        assert_eq!(synth.voices.len(), 2);
+        assert_eq!(synth.voices[0].freq, 440.0);
+        assert_eq!(synth.voices[0].env_stage, EnvStage::Release);
+        render_secs(&mut synth, 0.01, &mut out);
+        assert!(max_jump(&out) < 0.05, "{}", max_jump(&out));
+
+        // Its note-off is the new voice's
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+        assert_eq!(synth.voices[1].env_stage, EnvStage::Release);
+    }
+
+    #[test]
+    fn test_drum_hits_decay_to_silence() {
+        let kit_patch = Patch {
+            kit: Some(DrumKit::default()),
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[kit_patch, Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        for drum in Drum::ALL {
+            synth.process_command(LiveCommand::DrumHit {
+                track: 0,
+                drum,
+                velocity: 1.0,
+            });
+        }
+        // Tonal tracks have no kit and ignore hits
+        synth.process_command(LiveCommand::DrumHit {
+            track: 1,
+            drum: Drum::Kick,
+            velocity: 1.0,
+        });
+        assert_eq!(synth.drums.len(), 3);
+
+        let mut out = Vec::new();
+        render_secs(&mut synth, 0.1, &mut out);
+        assert!(out.iter().any(|s| s.abs() > 0.1));
+        // Hat (0.05 s) is gone; kick (0.4 s) is still sounding
+        assert_eq!(synth.drums.len(), 2);
+
+        render_secs(&mut synth, 0.4, &mut out);
+        assert!(synth.drums.is_empty());
+        assert_eq!(*out.last().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_kick_pitch_drops() {
+        let kit_patch = Patch {
+            kit: Some(DrumKit::default()),
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[kit_patch], &Mix::default(), SAMPLE_RATE, 1);
+        synth.process_command(LiveCommand::DrumHit {
+            track: 0,
+            drum: Drum::Kick,
+            velocity: 1.0,
+        });
+        let mut out = Vec::new();
+        render_secs(&mut synth, 0.3, &mut out);
+
+        let crossings = |s: &[f32]| s.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        let window = (0.05 * SAMPLE_RATE) as usize;
+        let early = crossings(&out[..window]);
+        let late = crossings(&out[out.len() - window..]);
+        assert!(early > late, "early {} late {}", early, late);
+    }
+
+    /// Clock whose time only moves when told to, recording every sleep
+    struct MockClock {
+        now: f64,
+        sleeps: Vec<f64>,
+    }
+
+    impl Clock for MockClock {
+        fn elapsed(&self) -> f64 {
+            self.now
+        }
+
+        fn sleep(&mut self, secs: f64) {
+            self.sleeps.push(secs);
+            self.now += secs;
+        }
+    }
+
+    #[test]
+    fn test_schedule_running_late_does_not_sleep_negative() {
+        use crate::scheduler::ScheduledEvent;
+        let schedule = vec![
+            ScheduledEvent {
+                beat: Beat::ZERO,
+                command: note_on('a', 440.0),
+            },
+            ScheduledEvent {
+                beat: Beat::ONE,
+                command: LiveCommand::NoteOff { track: 0, key: 'a' },
+            },
+        ];
+        // Playback is already 10 s in: every event and the ring-out are overdue
+        let mut clock = MockClock {
+            now: 10.0,
+            sleeps: Vec::new(),
+        };
+        let mut sent = Vec::new();
+        run_schedule(schedule, 120.0, 0.6, &mut clock, |_| true, |cmd| {
+            sent.push(cmd);
+            Ok(())
+        })
+        .unwrap();
+
+        assert!(clock.sleeps.is_empty(), "{:?}", clock.sleeps);
+        assert_eq!(sent.len(), 3);
+        assert!(matches!(sent[2], LiveCommand::Shutdown));
+    }
+
+    #[test]
+    fn test_ring_out_waits_for_longest_release() {
+        let pad = Patch {
+            adsr: Adsr {
+                release: 3.0,
+                ..Adsr::default()
+            },
+            ..Patch::default()
+        };
+        let ring_out = ring_out_secs(&[Patch::default(), pad], &Mix::default());
+        assert_eq!(ring_out, 3.0 + RING_OUT_MARGIN_SECS);
+
+        let schedule = vec![crate::scheduler::ScheduledEvent {
+            beat: Beat::whole(2),
+            command: LiveCommand::NoteOff { track: 1, key: 'a' },
+        }];
+        let mut clock = MockClock {
+            now: 0.0,
+            sleeps: Vec::new(),
+        };
+        run_schedule(schedule, 120.0, ring_out, &mut clock, |_| true, |_| Ok(())).unwrap();
+        // Beat 2 at 120 BPM is 1 s; shutdown comes after the 3 s release
+        assert!((clock.now - (1.0 + ring_out)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_playback_waits_for_the_shutdown_fade() {
+        let schedule = vec![crate::scheduler::ScheduledEvent {
+            beat: Beat::whole(2),
+            command: LiveCommand::NoteOff { track: 0, key: 'a' },
+        }];
+        let mut clock = MockClock {
+            now: 0.0,
+            sleeps: Vec::new(),
+        };
+        let mut sent = Vec::new();
+        run_to_silence(schedule, 120.0, 0.5, &mut clock, |_| true, |cmd| {
+            sent.push(cmd);
+            Ok(())
+        })
+        .unwrap();
+        // The fade gets its own wait after the ring-out and the shutdown
+        assert!(matches!(sent.last(), Some(LiveCommand::Shutdown)));
+        assert_eq!(clock.sleeps.last(), Some(&HALT_SECS));
+        assert!((clock.now - (1.0 + 0.5 + HALT_SECS)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_long_waits_tick_the_status_line() {
+        let schedule = vec![crate::scheduler::ScheduledEvent {
+            beat: Beat::whole(2),
+            command: note_on('a', 440.0),
+        }];
+        let mut clock = MockClock {
+            now: 0.0,
+            sleeps: Vec::new(),
+        };
+        let mut ticks = Vec::new();
+        run_schedule(
+            schedule,
+            120.0,
+            0.0,
+            &mut clock,
+            |t| {
+                ticks.push(t);
+                true
+            },
+            |_| Ok(()),
+        )
+        .unwrap();
+        // Beat 2 at 120 BPM is 1 s away: slept in TICK_SECS steps
+        assert!(clock.sleeps.iter().all(|&s| s <= TICK_SECS + 1e-12));
+        assert!(ticks.len() >= 10, "{:?}", ticks);
+    }
+
+    #[test]
+    fn test_stop_silences_and_skips_the_rest() {
+        use crate::scheduler::ScheduledEvent;
+        let schedule: Vec<ScheduledEvent> = (0..8)
+            .map(|i| ScheduledEvent {
+                beat: Beat::whole(i),
+                command: note_on('a', 440.0),
+            })
+            .collect();
+        let mut clock = MockClock {
+            now: 0.0,
+            sleeps: Vec::new(),
+        };
+        let mut sent = Vec::new();
+        // Stop 1.2 s in: beats 0, 1 and 2 (at 120 BPM) have been sent
+        run_schedule(schedule, 120.0, 5.0, &mut clock, |t| t < 1.2, |cmd| {
+            sent.push(cmd);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent.len(), 5, "{:?}", sent);
+        assert!(matches!(sent[3], LiveCommand::HardStop));
+        assert!(matches!(sent[4], LiveCommand::Shutdown));
+        assert!(clock.now < 1.3);
+    }
+
+    #[test]
+    fn test_status_line() {
+        let progress = Progress {
+            meter: MeterMap::new((3, 4)),
+            total_beats: 180.0,
+            looping: None,
+        };
+        // 120 BPM: 2 beats per second, so 2.6 s is beat 5 (bar 2, beat 3)
+        assert_eq!(progress.status_line(2.6, 120.0, 4), "bar 2:3  0:02 / 1:30  voices 4");
+        // Ring-out past the end holds at the total
+        assert_eq!(progress.status_line(95.0, 120.0, 0), "bar 61:1  1:30 / 1:30  voices 0");
+
+        // Looping a 6-beat pass after a one-bar count-in: beat 17 is pass 3
+        let progress = Progress {
+            looping: Some((3.0, 6.0)),
+            ..progress
+        };
+        assert_eq!(progress.status_line(8.5, 120.0, 2), "loop 3  bar 1:3  0:08  voices 2");
+        assert_eq!(progress.position(8.5, 120.0), "loop 3  bar 1:3");
+    }
+
+    #[test]
+    fn test_on_track_moves_notes_and_hits() {
+        let hit = LiveCommand::DrumHit {
+            track: 1,
+            drum: Drum::Kick,
+            velocity: 1.0,
+        };
+        assert!(matches!(hit.on_track(8), LiveCommand::DrumHit { track: 9, .. }));
+        let off = LiveCommand::NoteOff { track: 0, key: 'a' };
+        assert_eq!(off.on_track(3), LiveCommand::NoteOff { track: 3, key: 'a' });
+        let all = LiveCommand::AllNotesOff { track: None };
+        assert_eq!(all.on_track(3), all);
+        let one = LiveCommand::AllNotesOff { track: Some(1) };
+        assert_eq!(one.on_track(3), LiveCommand::AllNotesOff { track: Some(4) });
+    }
+
+    #[test]
+    fn test_delay_repeats_a_hit() {
+        let kit = |delay: Option<Delay>| Patch {
+            kit: Some(DrumKit::default()),
+            delay,
+            ..Patch::default()
+        };
+        let hit = LiveCommand::DrumHit {
+            track: 0,
+            drum: Drum::Hat,
+            velocity: 1.0,
+        };
+        let peak_after = |patch: Patch| {
+            let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(hit.clone());
+            let mut buf = vec![0.0_f32; (0.5 * SAMPLE_RATE) as usize];
+            synth.render(&mut buf);
+            // The hat has died away after 0.2 s; only an echo remains
+            let tail = &buf[(0.2 * SAMPLE_RATE) as usize..];
+            tail.iter().fold(0.0_f32, |m, s| m.max(s.abs()))
+        };
+        let echo = Delay {
+            time: 0.25,
+            feedback: 0.4,
+            mix: 0.5,
+        };
+        assert!(peak_after(kit(Some(echo.clone()))) > 0.01);
+        assert_eq!(peak_after(kit(None)), 0.0);
+        let muted = Delay { mix: 0.0, ..echo };
+        let synth = Synth::new(&[kit(Some(muted))], &Mix::default(), SAMPLE_RATE, 1);
+        assert!(synth.delays[0].is_none());
+    }
+
+    #[test]
+    fn test_chorus_changes_the_sound_but_stays_bounded() {
+        let render = |mix: f64| {
+            let patch = Patch {
+                chorus: Some(Chorus {
+                    depth: 8.0,
+                    rate: 1.5,
+                    mix,
+                }),
+                ..Patch::default()
+            };
+            let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(note_on('a', 220.0));
+            synth.process_command(note_on('s', 330.0));
+            let mut buf = vec![0.0_f32; SAMPLE_RATE as usize];
+            synth.render(&mut buf);
+            buf
+        };
+        let dry = render(0.0);
+        let wet = render(0.5);
+        assert_ne!(dry, wet);
+        let peak = |buf: &[f32]| buf.iter().fold(0.0_f32, |m, s| m.max(s.abs()));
+        assert!(peak(&wet) > 0.01);
+        assert!(peak(&wet) <= peak(&dry) * 1.01, "{} vs {}", peak(&wet), peak(&dry));
+        // The dry signal passes straight through before the first delay
+        let first_delay = (CHORUS_MIN_DELAY_MS / 1000.0 * SAMPLE_RATE) as usize;
+        assert!(
+            dry[..first_delay]
+                .iter()
+                .zip(&wet)
+                .all(|(d, w)| (d - w * 2.0).abs() < 1e-6)
+        );
+    }
+
+    #[test]
+    fn test_chorus_interpolates_between_samples() {
+        let chorus = Chorus {
+            depth: 0.0,
+            rate: 0.0,
+            mix: 1.0,
+        };
+        // At 1 kHz the 5 ms minimum delay is exactly 5 samples
+        let mut line = ChorusLine::new(&chorus, 1000.0);
+        let out: Vec<f64> = (0..8).map(|i| line.process(i as f64)).collect();
+        assert_eq!(out, [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0]);
+
+        line.min_delay = 5.5;
+        assert_eq!(line.process(8.0), 2.5);
+    }
+
+    #[test]
+    fn test_delay_tail_extends_ring_out() {
+        let delay = Delay {
+            time: 0.5,
+            feedback: 0.1,
+            mix: 0.3,
+        };
+        // 0.1^3 = -60 dB: three feedback passes plus the first echo
+        assert!((delay.tail_secs() - 2.0).abs() < 1e-9);
+        let patch = Patch {
+            delay: Some(delay),
+            ..Patch::default()
+        };
+        let expected = Adsr::default().release + 2.0 + RING_OUT_MARGIN_SECS;
+        assert!((ring_out_secs(&[patch], &Mix::default()) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_pitch_bend_uses_track_range() {
+        let crossings = |bend: f64| {
+            let patch = Patch {
+                bend_range: 12.0,
+                ..Patch::default()
+            };
+            let mut synth = Synth::new(&[patch], &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(note_on('a', 220.0));
+            synth.process_command(LiveCommand::PitchBend(bend));
+            let mut buf = vec![0.0_f32; SAMPLE_RATE as usize];
+            synth.render(&mut buf);
+            buf.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count() as f64
+        };
+        // A full bend up is one octave with a 12 semitone range
+        assert!((crossings(1.0) - 440.0).abs() <= 2.0);
+        assert!((crossings(-1.0) - 110.0).abs() <= 2.0);
+        assert!((crossings(0.0) - 220.0).abs() <= 2.0);
+    }
+
+    #[test]
+    fn test_duplicate_note_off_is_ignored() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        synth.process_command(note_on('a', 440.0));
+        render_secs(&mut synth, 0.1, &mut out);
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+        render_secs(&mut synth, 0.1, &mut out);
+        let phase = synth.voices[0].env_phase;
+        synth.process_command(LiveCommand::NoteOff { track: 0, key: 'a' });
+        assert_eq!(synth.voices[0].env_phase, phase);
+    }
+
+    #[test]
+    fn test_panic_silences_everything_with_a_short_fade() {
+        let kit_patch = Patch {
+            kit: Some(DrumKit::default()),
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[Patch::default(), kit_patch], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        synth.process_command(note_on('a', 440.0));
+        synth.process_command(note_on('s', 550.0));
+        synth.process_command(LiveCommand::DrumHit {
+            track: 1,
+            drum: Drum::Kick,
+            velocity: 1.0,
+        });
+        render_secs(&mut synth, 0.1, &mut out);
+        synth.process_command(LiveCommand::Panic);
+        let mut fade = Vec::new();
+        render_secs(&mut synth, PANIC_FADE_SECS * 0.5, &mut fade);
+        // Still fading halfway through, without a click
+        assert_eq!(synth.active_voices(), 3);
+        assert!(max_jump(&fade) < 0.1, "jump {}", max_jump(&fade));
+        render_secs(&mut synth, PANIC_FADE_SECS * 0.5 + 0.001, &mut fade);
+        assert_eq!(synth.active_voices(), 0);
+        let mut tail = Vec::new();
+        render_secs(&mut synth, 0.01, &mut tail);
+        assert!(tail.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_all_notes_off_on_one_track() {
+        let mono = Patch {
+            mono: true,
+            ..Patch::default()
+        };
+        let patches = [Patch::default(), mono, Patch::default()];
+        let mut synth = Synth::new(&patches, &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        for track in 0..3 {
+            synth.process_command(note_on('a', 440.0).on_track(track));
+        }
+        render_secs(&mut synth, 0.2, &mut out);
+        synth.process_command(LiveCommand::AllNotesOff { track: Some(1) });
+        let stages: Vec<EnvStage> = synth.voices.iter().map(|v| v.env_stage).collect();
+        assert_eq!(stages, [EnvStage::Sustain, EnvStage::Release, EnvStage::Sustain]);
+        // The mono track forgot its held keys, so their NoteOffs bring nothing back
+        assert!(synth.held[1].is_empty());
+
+        synth.process_command(LiveCommand::AllNotesOff { track: None });
+        assert!(synth.voices.iter().all(|v| v.env_stage == EnvStage::Release));
+    }
+
+    #[test]
+    fn test_hard_stop_cuts_notes_without_their_releases() {
+        let pad = Patch {
+            adsr: Adsr {
+                release: 3.0,
+                ..Adsr::default()
+            },
+            ..Patch::default()
+        };
+        let mut synth = Synth::new(&[pad], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        synth.process_command(note_on('a', 440.0));
+        synth.process_command(note_on('s', 550.0));
+        render_secs(&mut synth, 0.1, &mut out);
+        synth.process_command(LiveCommand::HardStop);
+        let mut fade = Vec::new();
+        render_secs(&mut synth, HARD_STOP_FADE_SECS * 0.5, &mut fade);
+        assert_eq!(synth.active_voices(), 2);
+        assert!(max_jump(&fade) < 0.1, "jump {}", max_jump(&fade));
+        render_secs(&mut synth, HARD_STOP_FADE_SECS * 0.5 + 0.001, &mut fade);
+        assert_eq!(synth.active_voices(), 0);
+    }
+
+    #[test]
+    fn test_watchdog_releases_stuck_notes() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        let mut out = Vec::new();
+        synth.process_command(note_on('a', 440.0));
+        // Off by default: a note without a NoteOff sounds on
+        render_secs(&mut synth, 0.5, &mut out);
+        assert!(synth.voices[0].is_held());
+
+        // The old note goes at once, the new one once it's been held too long
+        synth.process_command(LiveCommand::SetMaxNoteSecs(Some(0.2)));
+        synth.process_command(note_on('s', 550.0));
+        render_secs(&mut synth, 0.1, &mut out);
+        let held: Vec<(char, bool)> = synth.voices.iter().map(|v| (v.key, v.is_held())).collect();
+        assert_eq!(held, vec![('a', false), ('s', true)]);
+        // Retriggering restarts the count
+        synth.process_command(note_on('s', 550.0));
+        render_secs(&mut synth, 0.15, &mut out);
+        assert!(synth.voices.iter().any(|v| v.key == 's' && v.is_held()));
+        render_secs(&mut synth, 0.1 + Adsr::default().release, &mut out);
+        assert_eq!(synth.active_voices(), 0);
+    }
+
+    #[test]
+    fn test_master_gain_ramps_per_sample() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        synth.process_command(LiveCommand::SetMasterGain {
+            gain: 0.0,
+            ramp_secs: 0.0,
+        });
+        assert_eq!(synth.master_gain, 0.0);
+        synth.process_command(LiveCommand::SetMasterGain {
+            gain: 1.0,
+            ramp_secs: 0.5,
+        });
+        let mut out = Vec::new();
+        render_secs(&mut synth, 0.25, &mut out);
+        assert!((synth.master_gain - 0.5).abs() < 1e-9, "{}", synth.master_gain);
+        render_secs(&mut synth, 0.5, &mut out);
+        assert!((synth.master_gain - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_engine_plays_through_a_null_backend() {
+        use crate::backend::NullBackend;
+        let (backend, pull) = NullBackend::new(48_000, 2);
+        let path = std::env::temp_dir().join(format!("clidaw-engine-{}.wav", std::process::id()));
+        let patches = vec![Patch::default()];
+        let engine =
+            AudioEngine::with_backend(Box::new(backend), patches, &Mix::default(), Some(&path))
+                .unwrap();
+        assert_eq!(engine.latency(Duration::ZERO), None);
+        engine.send(note_on('a', 440.0)).unwrap();
+        let mut out = vec![0.0_f32; 2 * 4800];
+        pull.render(&mut out);
+        assert!(out.iter().any(|s| s.abs() > 0.1));
+        assert_eq!(engine.active_voices(), 1);
+        let latency = engine.latency(Duration::ZERO).unwrap();
+        assert_eq!((latency.sample_rate, latency.buffer_frames), (48_000, 4800));
+
+        // The recording has what was pulled
+        let summary = engine.finish_recording().unwrap().unwrap();
+        assert_eq!(summary.frames, 4800);
+        let _ = std::fs::remove_file(&path);
+
+        // A track routed past the backend's channels can't play
+        let (backend, _pull) = NullBackend::new(48_000, 2);
+        let routed = Patch {
+            output_channel: Some(3),
+            ..Patch::default()
+        };
+        let err = AudioEngine::with_backend(Box::new(backend), vec![routed], &Mix::default(), None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("routed to output channel 3"), "{}", err);
+    }
+
+    fn curved_adsr() -> Adsr {
+        Adsr {
+            attack: 0.1,
+            decay: 0.2,
+            sustain: 0.5,
+            release: 0.4,
+            attack_curve: Curve::Exponential,
+            decay_curve: Curve::Exponential,
+            release_curve: Curve::Exponential,
+        }
+    }
+
+    /// Sample a stage's level over its whole length
+    fn stage_levels(stage: EnvStage, length: f64, release_start: f64, adsr: &Adsr) -> Vec<f64> {
+        (0..=100)
+            .map(|i| envelope_level(stage, length * i as f64 / 100.0, release_start, adsr))
+            .collect()
+    }
+
+    #[test]
+    fn test_curved_stages_hit_endpoints() {
+        let adsr = curved_adsr();
+        let attack = stage_levels(EnvStage::Attack, adsr.attack, 0.0, &adsr);
+        assert_eq!((attack[0], attack[100]), (0.0, 1.0));
+        let decay = stage_levels(EnvStage::Decay, adsr.decay, 0.0, &adsr);
+        assert_eq!(decay[0], 1.0);
+        assert!((decay[100] - adsr.sustain).abs() < 1e-12);
+        // Release from a level reached mid-attack
+        let release = stage_levels(EnvStage::Release, adsr.release, 0.3, &adsr);
+        assert_eq!((release[0], release[100]), (0.3, 0.0));
+    }
+
+    #[test]
+    fn test_curved_stages_are_monotonic() {
+        let adsr = curved_adsr();
+        let rising = stage_levels(EnvStage::Attack, adsr.attack, 0.0, &adsr);
+        assert!(rising.windows(2).all(|w| w[1] >= w[0]));
+        for falling in [
+            stage_levels(EnvStage::Decay, adsr.decay, 0.0, &adsr),
+            stage_levels(EnvStage::Release, adsr.release, 0.8, &adsr),
+        ] {
+            assert!(falling.windows(2).all(|w| w[1] <= w[0]));
+        }
+        // Exponential moves faster than linear early in the segment
+        assert!(rising[20] > 0.2);
+    }
+
+    #[test]
+    fn test_curve_inverse_round_trips() {
+        for y in [0.0, 0.1, 0.5, 0.9, 1.0] {
+            let t = Curve::Exponential.progress_at(y);
+            assert!((Curve::Exponential.shape(t) - y).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_click_is_short_and_ignores_master_gain() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        synth.process_command(LiveCommand::SetMasterGain {
+            gain: 0.0,
+            ramp_secs: 0.0,
+        });
+        synth.process_command(LiveCommand::Click {
+            accent: true,
+            volume: 1.0,
+        });
+        let mut out = Vec::new();
+        render_secs(&mut synth, 0.01, &mut out);
+        assert!(out.iter().any(|s| s.abs() > 0.1));
+        render_secs(&mut synth, 0.05, &mut out);
+        assert!(synth.clicks.is_empty());
+    }
+
+    #[test]
+    fn test_frames_are_interleaved() {
+        let mut synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 2);
+        synth.process_command(note_on('a', 440.0));
+        let mut stereo = vec![0.0_f32; 200];
+        synth.render(&mut stereo);
+
+        let mut mono_synth = Synth::new(&[Patch::default()], &Mix::default(), SAMPLE_RATE, 1);
+        mono_synth.process_command(note_on('a', 440.0));
+        let mut mono = vec![0.0_f32; 100];
+        mono_synth.render(&mut mono);
+
+        for (frame, sample) in stereo.chunks(2).zip(&mono) {
+            assert_eq!(frame, [*sample, *sample]);
+        }
+    }
+
+    /// Root mean square of `samples`
+    fn rms(samples: &[f64]) -> f64 {
+        (samples.iter().map(|s| s * s).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_noise_colors() {
+        let second = |color, freq| -> Vec<f64> {
+            let mut noise = NoiseSource::new(7, color);
+            (0..SAMPLE_RATE as usize).map(|_| noise.next_sample(freq, SAMPLE_RATE)).collect()
+        };
+        // Size of the sample-to-sample steps against the level: the share
+        // of high frequencies
+        let roughness = |s: &[f64]| {
+            let steps: Vec<f64> = s.windows(2).map(|w| w[1] - w[0]).collect();
+            rms(&steps) / rms(s)
+        };
+        let white = second(NoiseColor::White, 440.0);
+        let pink = second(NoiseColor::Pink, 440.0);
+        assert!(roughness(&pink) < roughness(&white) * 0.5);
+        for (freq, color) in [(440.0, NoiseColor::Pink), (110.0, NoiseColor::Tuned)] {
+            let level = rms(&second(color, freq)) / rms(&white);
+            assert!((0.5..2.0).contains(&level), "{:?} at {}: {}", color, freq, level);
+        }
+        // Tuned noise is loudest around the note's pitch, at about the
+        // same level whatever the pitch
+        for freq in [110.0, 880.0, 3520.0] {
+            let tuned = second(NoiseColor::Tuned, freq);
+            let samples: Vec<f32> = tuned.iter().map(|&s| s as f32).collect();
+            let band = |center: f64| -> f64 {
+                (0..10).map(|i| goertzel(&samples, center * (0.95 + 0.01 * i as f64))).sum()
+            };
+            let (at, above, below) = (band(freq), band(freq * 3.0), band(freq / 3.0));
+            let (ratio_above, ratio_below) = (at / above, at / below);
+            assert!(ratio_above > 4.0 && ratio_below > 4.0, "{} Hz", freq);
+            let level = rms(&tuned) / rms(&white);
+            assert!((0.5..2.0).contains(&level), "{} Hz: {}", freq, level);
+        }
+    }
+
+    #[test]
+    fn test_noise_voices_repeat_between_renders() {
+        let patch = Patch {
+            waveform: Waveform::Noise,
+            ..Patch::default()
+        };
+        let render = || {
+            let patches = std::slice::from_ref(&patch);
+            let mut synth = Synth::new(patches, &Mix::default(), SAMPLE_RATE, 1);
+            synth.process_command(note_on('a', 220.0));
+            synth.process_command(note_on('s', 220.0));
+            let mut out = Vec::new();
+            render_secs(&mut synth, 0.05, &mut out);
+            let sources: Vec<f64> = synth
+                .voices
+                .iter_mut()
+                .map(|v| v.noise.as_mut().unwrap().next_sample(220.0, SAMPLE_RATE))
+                .collect();
+            (out, sources)
+        };
+        let (first, sources) = render();
+        assert!(first.iter().any(|&s| s.abs() > 0.01));
+        assert_eq!(render().0, first);
+        // Two notes at once aren't the same noise twice
+        assert_ne!(sources[0], sources[1]);
+    }
+}
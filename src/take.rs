@@ -0,0 +1,241 @@
+//! Recording what's played in live mode to a `.notes` file, for the
+//! palette's `:record`.
+//!
+//! A take keeps the time of every key's NoteOn and NoteOff. When it is
+//! written, times snap to a quarter-beat grid at the tempo the take started
+//! at, and the silence before the first note is dropped. A `.notes` line
+//! plays one note or chord at a time: notes that start on the same step
+//! join a chord (lasting as long as the longest of them), and a note cut
+//! into by the next one ends where it starts. Notes are split into bars of
+//! 4/4 with ties, and the last bar is filled out with rest.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::beat::Beat;
+use crate::error::ClidawError;
+use crate::note::{self, Composition, Event, NoteEvent, NoteName, Spelling, Track};
+use crate::synth::LiveCommand;
+use crate::writer;
+
+/// Grid steps per beat
+const STEPS_PER_BEAT: u64 = 4;
+
+/// Steps per bar (4/4)
+const BAR_STEPS: u64 = 4 * STEPS_PER_BEAT;
+
+/// One key press: seconds from the start of the take, and the MIDI note
+#[derive(Debug, Clone)]
+struct TakeNote {
+    key: char,
+    midi: u8,
+    velocity: f64,
+    start: f64,
+    /// None while the key is down
+    end: Option<f64>,
+}
+
+/// Notes played since `:record` was given
+#[derive(Debug, Clone)]
+pub struct Take {
+    path: PathBuf,
+    tempo: f64,
+    start: Instant,
+    notes: Vec<TakeNote>,
+}
+
+impl Take {
+    /// A take written to `path`, on a grid of `tempo` BPM
+    pub fn new(path: PathBuf, tempo: f64, start: Instant) -> Self {
+        Self {
+            path,
+            tempo,
+            start,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keep a keyboard NoteOn or NoteOff sent `at` (other commands are
+    /// ignored)
+    pub fn record(&mut self, command: &LiveCommand, at: Instant) {
+        let secs = at.saturating_duration_since(self.start).as_secs_f64();
+        match *command {
+            LiveCommand::NoteOn {
+                key,
+                freq,
+                velocity,
+                ..
+            } => self.notes.push(TakeNote {
+                key,
+                midi: note::freq_to_midi(freq).round().clamp(12.0, 119.0) as u8,
+                velocity,
+                start: secs,
+                end: None,
+            }),
+            LiveCommand::NoteOff { key, .. } => {
+                let held = self.notes.iter_mut().rev().find(|n| n.key == key && n.end.is_none());
+                if let Some(note) = held {
+                    note.end = Some(secs);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The take as one track, notes still held ending at `end`
+    pub fn composition(&self, end: Instant) -> Composition {
+        let end = end.saturating_duration_since(self.start).as_secs_f64();
+        let step = |secs: f64| (secs * self.tempo / 60.0 * STEPS_PER_BEAT as f64).round() as u64;
+        let mut notes: Vec<(u64, u64, &TakeNote)> = self
+            .notes
+            .iter()
+            .map(|n| (step(n.start), step(n.end.unwrap_or(end)), n))
+            .collect();
+        notes.sort_by_key(|&(start, ..)| start);
+        let first = notes.first().map_or(0, |&(start, ..)| start);
+
+        // (steps, notes sounding) from the first note on; no notes is a rest
+        let mut spans: Vec<(u64, Vec<&TakeNote>)> = Vec::new();
+        let mut at = first;
+        let mut idx = 0;
+        while idx < notes.len() {
+            let start = notes[idx].0;
+            let chord: Vec<_> = notes[idx..].iter().take_while(|n| n.0 == start).collect();
+            idx += chord.len();
+            let next = notes.get(idx).map(|n| n.0);
+            let mut stop = chord.iter().map(|n| n.1).max().unwrap_or(start).max(start + 1);
+            if let Some(next) = next {
+                stop = stop.min(next);
+            }
+            if start > at {
+                spans.push((start - at, Vec::new()));
+            }
+            let mut members: Vec<&TakeNote> = chord.iter().map(|n| n.2).collect();
+            members.sort_by_key(|n| n.midi);
+            members.dedup_by_key(|n| n.midi);
+            spans.push((stop - start, members));
+            at = stop;
+        }
+
+        let mut comp = Composition::new();
+        comp.tempo = self.tempo;
+        comp.tracks.push(Track {
+            name: "take".to_string(),
+            patch: None,
+            octave: comp.default_octave,
+            events: bar_events(&spans),
+            includes: Vec::new(),
+            meter_changes: Vec::new(),
+        });
+        comp
+    }
+
+    /// Write the take, as it stands at `end`, to its file. Returns how many
+    /// notes it has; with none, nothing is written.
+    pub fn write(&self, end: Instant) -> Result<usize, ClidawError> {
+        if self.notes.is_empty() {
+            return Ok(0);
+        }
+        let text = writer::composition_text(&self.composition(end));
+        fs::write(&self.path, text).map_err(|e| ClidawError::io(&self.path, e))?;
+        Ok(self.notes.len())
+    }
+}
+
+/// `spans` as events, split at every bar line with ties (or more rest)
+/// carrying the rest of a span over, and the last bar filled out
+fn bar_events(spans: &[(u64, Vec<&TakeNote>)]) -> Vec<Event> {
+    let beats = |steps: u64| Beat::ratio(steps as i64, STEPS_PER_BEAT as i64);
+    let mut events = Vec::new();
+    let mut at = 0;
+    for (len, members) in spans {
+        let mut left = *len;
+        let mut first = true;
+        while left > 0 {
+            let piece = left.min(BAR_STEPS - at % BAR_STEPS);
+            let length = beats(piece);
+            events.push(match members.as_slice() {
+                [] => Event::Rest(length),
+                _ if !first => Event::Tie(length),
+                [note] => Event::Note(note_event(note), length),
+                _ => Event::Chord(members.iter().map(|n| note_event(n)).collect(), length),
+            });
+            first = false;
+            left -= piece;
+            at += piece;
+            if at.is_multiple_of(BAR_STEPS) {
+                events.push(Event::BarLine);
+            }
+        }
+    }
+    if !at.is_multiple_of(BAR_STEPS) {
+        events.push(Event::Rest(beats(BAR_STEPS - at % BAR_STEPS)));
+        events.push(Event::BarLine);
+    }
+    events
+}
+
+fn note_event(note: &TakeNote) -> NoteEvent {
+    NoteEvent {
+        note: NoteName::ALL[(note.midi % 12) as usize],
+        octave: note.midi / 12 - 1,
+        degree: None,
+        velocity: note.velocity,
+        spelling: Spelling::Sharp,
+        chance: 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_take_snaps_to_the_grid_in_bars() {
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+        let mut take = Take::new(PathBuf::from("take.notes"), 120.0, start);
+        let on = |key, freq| LiveCommand::NoteOn {
+            track: 0,
+            key,
+            freq,
+            velocity: 1.0,
+        };
+        let off = |key| LiveCommand::NoteOff { track: 0, key };
+        // At 120 BPM a beat is half a second; the first note comes a bar in
+        let plan = [
+            (2.0, on('a', 261.63)),
+            (2.49, off('a')),
+            (3.0, on('d', 329.63)),
+            (3.01, on('g', 392.0)),
+            (4.0, off('d')),
+            (4.0, off('g')),
+            // Held across the bar line, and cut short by the next note
+            (5.5, on('s', 293.66)),
+            (6.6, on('s', 293.66)),
+            (7.0, off('s')),
+        ];
+        for (secs, command) in &plan {
+            take.record(command, at(*secs));
+        }
+        let text = writer::composition_text(&take.composition(at(8.0)));
+        assert_eq!(
+            text,
+            "octave: 4\n\na - [dg]:2 |\n--- s |\n_:1.25 s:0.75 -- |\n"
+        );
+        // Notes still held at the end last until then
+        take.record(&on('j', 493.88), at(8.0));
+        let comp = take.composition(at(9.0));
+        let events = &comp.tracks[0].events;
+        assert!(events.contains(&Event::Note(note_event(&take.notes[5]), Beat::whole(2))));
+        assert!(parse(&writer::composition_text(&comp)).is_ok());
+    }
+}
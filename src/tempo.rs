@@ -0,0 +1,138 @@
+//! Tempos in BPM: reading them from files and the command line, and
+//! `clidaw tap`, which works one out from keys hit on the beat.
+//!
+//! A tempo is any positive number of beats per minute, so `93.5` works as
+//! well as `120`. Tapping keeps the gaps between taps, drops the ones far
+//! from the median (a missed beat, a double hit) and averages the rest.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+
+use crate::error::ClidawError;
+
+/// A pause this long between taps starts the count again
+const TAP_RESET: Duration = Duration::from_secs(2);
+
+/// Gaps further than this fraction from the median are stray taps
+const OUTLIER: f64 = 0.25;
+
+/// A tempo as written in a file or on the command line (None unless it's a
+/// finite number above 0)
+pub fn parse(text: &str) -> Option<f64> {
+    let bpm: f64 = text.trim().parse().ok()?;
+    (bpm.is_finite() && bpm > 0.0).then_some(bpm)
+}
+
+/// `--tempo`, for clap
+pub fn parse_arg(text: &str) -> Result<f64, String> {
+    parse(text).ok_or_else(|| "expected beats per minute above 0, e.g. 120 or 93.5".to_string())
+}
+
+/// The tempo of taps at `times` (seconds, in order): the mean gap between
+/// them once gaps far from the median are left out. None for fewer than
+/// two taps.
+pub fn from_taps(times: &[f64]) -> Option<f64> {
+    let mut gaps: Vec<f64> = times.windows(2).map(|w| w[1] - w[0]).filter(|&g| g > 0.0).collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort_by(f64::total_cmp);
+    let mid = gaps.len() / 2;
+    let median = if gaps.len().is_multiple_of(2) {
+        (gaps[mid - 1] + gaps[mid]) / 2.0
+    } else {
+        gaps[mid]
+    };
+    let kept: Vec<f64> = gaps
+        .iter()
+        .copied()
+        .filter(|g| (g - median).abs() <= median * OUTLIER)
+        .collect();
+    let gap = if kept.is_empty() {
+        median
+    } else {
+        kept.iter().sum::<f64>() / kept.len() as f64
+    };
+    Some(60.0 / gap)
+}
+
+/// `clidaw tap`: time keys hit on the beat until Enter or Esc, showing the
+/// tempo so far, then print it
+pub fn tap() -> Result<(), ClidawError> {
+    terminal::enable_raw_mode()
+        .map_err(|e| ClidawError::Terminal(format!("failed to enable raw mode: {}", e)))?;
+    let result = tap_loop(&mut io::stdout());
+    let _ = terminal::disable_raw_mode();
+    match result? {
+        Some(bpm) => println!("{:.1} BPM", bpm),
+        None => println!("Not enough taps for a tempo (tap at least twice)"),
+    }
+    Ok(())
+}
+
+/// Read taps until Enter, Esc or Ctrl-C; the tempo of the last run of taps
+fn tap_loop(out: &mut impl Write) -> Result<Option<f64>, ClidawError> {
+    let _ = write!(out, "Tap any key on the beat; Enter or Esc when done\r\n");
+    let _ = out.flush();
+    let start = Instant::now();
+    let mut taps: Vec<f64> = Vec::new();
+    loop {
+        let ev = event::read()
+            .map_err(|e| ClidawError::Terminal(format!("event read error: {}", e)))?;
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = ev
+        else {
+            continue;
+        };
+        let ctrl_c = code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL);
+        if ctrl_c || matches!(code, KeyCode::Enter | KeyCode::Esc) {
+            break;
+        }
+        let at = start.elapsed().as_secs_f64();
+        if taps.last().is_some_and(|&last| at - last > TAP_RESET.as_secs_f64()) {
+            taps.clear();
+        }
+        taps.push(at);
+        let bpm = from_taps(&taps).map_or("-".to_string(), |bpm| format!("{:.1}", bpm));
+        let _ = write!(out, "\r\x1b[2K  Taps: {}  BPM: {}", taps.len(), bpm);
+        let _ = out.flush();
+    }
+    let _ = write!(out, "\r\n");
+    Ok(from_taps(&taps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_accepts_fractions_and_rejects_nonsense() {
+        assert_eq!(parse("120"), Some(120.0));
+        assert_eq!(parse(" 93.5 "), Some(93.5));
+        for text in ["0", "-90", "fast", "inf", "NaN", ""] {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+        assert!(parse_arg("0").is_err());
+    }
+
+    #[test]
+    fn test_taps_average_and_skip_stray_gaps() {
+        assert_eq!(from_taps(&[]), None);
+        assert_eq!(from_taps(&[1.0]), None);
+        // Half a second apart is 120 BPM; a slow start and a missed beat
+        // don't count
+        let taps = [0.0, 1.5, 2.0, 2.5, 3.5, 4.0, 4.5];
+        let bpm = from_taps(&taps).unwrap();
+        assert!((bpm - 120.0).abs() < 1e-9, "{}", bpm);
+        // Slightly uneven taps average out
+        let bpm = from_taps(&[0.0, 0.62, 1.28, 1.92]).unwrap();
+        assert!((bpm - 93.75).abs() < 1e-9, "{}", bpm);
+    }
+}
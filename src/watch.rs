@@ -0,0 +1,96 @@
+//! `play --watch`: replay a file from the top whenever it, or any file it
+//! references, changes on disk.
+//!
+//! Files are polled for modification times, so this works the same on every
+//! platform without a file notification service.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::error::ClidawError;
+use crate::interrupt;
+
+/// How often watched files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Modification time of each file (None if it can't be read, e.g. mid-save)
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Load with `load` and play with `play`, reloading whenever a watched file
+/// changes. `load` returns the playable value and the files to watch.
+///
+/// Reloads happen on a watcher thread while the current version keeps
+/// playing: a file that fails to load is reported and ignored, and a good
+/// one raises the stop flag passed to `play` so playback restarts with it.
+/// Only an error from the first load or from `play` itself ends the loop.
+pub fn run<T, L, P>(load: L, mut play: P) -> Result<(), ClidawError>
+where
+    T: Send + 'static,
+    L: Fn() -> Result<(T, Vec<PathBuf>), ClidawError> + Send + 'static,
+    P: FnMut(&T, &AtomicBool) -> Result<(), ClidawError>,
+{
+    let (mut current, files) = load()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel::<T>();
+
+    let watcher_stop = Arc::clone(&stop);
+    thread::spawn(move || {
+        let mut files = files;
+        let mut stamps = modified_times(&files);
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let now = modified_times(&files);
+            if now == stamps {
+                continue;
+            }
+            stamps = now;
+            match load() {
+                Ok((next, next_files)) => {
+                    println!("\nChange detected; restarting");
+                    if next_files != files {
+                        files = next_files;
+                        stamps = modified_times(&files);
+                    }
+                    if tx.send(next).is_err() {
+                        return;
+                    }
+                    watcher_stop.store(true, Ordering::Relaxed);
+                }
+                Err(e) => eprintln!("\n{}\n(keeping the previous version)", e),
+            }
+        }
+    });
+
+    loop {
+        stop.store(false, Ordering::Relaxed);
+        while let Ok(next) = rx.try_recv() {
+            current = next;
+        }
+        play(&current, &stop)?;
+        if interrupt::requested() {
+            return Ok(());
+        }
+        if stop.load(Ordering::Relaxed) {
+            continue;
+        }
+        println!("Finished; waiting for changes (Ctrl-C to quit)");
+        loop {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(next) => {
+                    current = next;
+                    break;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) if !interrupt::requested() => {}
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+}
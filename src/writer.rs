@@ -0,0 +1,413 @@
+//! Writing a `Composition` back out as `.notes` text, for `clidaw transform`
+//! and `clidaw import`.
+//!
+//! The text parses back to the same events: the header directives, then
+//! each track's notes a bar to a line in the home-row letters, with `<`/`>`
+//! octave shifts from the file's `octave:` and a `:length` wherever an event
+//! isn't one beat long. Drum steps become drum lines, after a `drum_step:`
+//! when they aren't a beat long. What the events don't
+//! record is lost: comments, `include:` lines (the included notes are
+//! written in their place), scale degrees (written as the notes they play)
+//! and crescendos (each note gets the dynamics mark nearest its velocity).
+//! Chances and `alt{...}` groups are written as they were parsed.
+//! Sharps and flats keep their names only file-wide: `prefer_flats: true`
+//! is written when every black key is a flat the file's key wouldn't give.
+
+use std::fmt::Write;
+
+use crate::beat::Beat;
+use crate::note::{
+    Alternatives, Composition, Drum, Event, MeterChange, NoteEvent, NoteName, Spelling,
+};
+use crate::parser::DYNAMICS;
+
+/// Whether the file needs `prefer_flats: true` to name its notes as they
+/// are: it said so, or its black keys are all flats its key doesn't give
+fn prefers_flats(comp: &Composition) -> bool {
+    if comp.prefer_flats {
+        return true;
+    }
+    if comp.key.is_some_and(|key| key.spelling == Spelling::Flat) {
+        return false;
+    }
+    let mut black = comp
+        .tracks
+        .iter()
+        .flat_map(|t| &t.events)
+        .flat_map(Event::notes)
+        .filter(|n| n.note.name() != n.note.flat_name())
+        .peekable();
+    black.peek().is_some() && black.all(|n| n.spelling == Spelling::Flat)
+}
+
+/// Note character for a pitch class, in the home-row octave
+fn note_char(note: NoteName) -> char {
+    match note {
+        NoteName::C => 'a',
+        NoteName::CSharp => 'w',
+        NoteName::D => 's',
+        NoteName::DSharp => 'e',
+        NoteName::E => 'd',
+        NoteName::F => 'f',
+        NoteName::FSharp => 't',
+        NoteName::G => 'g',
+        NoteName::GSharp => 'y',
+        NoteName::A => 'h',
+        NoteName::ASharp => 'u',
+        NoteName::B => 'j',
+    }
+}
+
+/// Dynamics mark nearest a velocity (0.0..=1.0)
+pub fn dynamic_mark(velocity: f64) -> &'static str {
+    DYNAMICS
+        .iter()
+        .min_by(|a, b| (a.1 - velocity).abs().total_cmp(&(b.1 - velocity).abs()))
+        .map_or("ff", |(name, _)| name)
+}
+
+/// MIDI note `key` written with the `<`/`>` shifts it needs from `octave`,
+/// which follows the shifts
+pub fn write_key(out: &mut String, key: u8, octave: &mut u8) {
+    let target = key / 12 - 1;
+    while *octave < target {
+        out.push('>');
+        *octave += 1;
+    }
+    while *octave > target {
+        out.push('<');
+        *octave -= 1;
+    }
+    out.push(note_char(NoteName::ALL[(key % 12) as usize]));
+}
+
+/// A length in beats as written after `:` or in `beats:`. Nine decimals
+/// are within half a tick, so the text parses back to the same length.
+pub fn beats_text(beats: Beat) -> String {
+    let text = format!("{:.9}", beats.as_f64());
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// `:length` for an event that isn't one beat long
+pub fn length_suffix(beats: Beat) -> String {
+    if beats == Beat::ONE {
+        String::new()
+    } else {
+        format!(":{}", beats_text(beats))
+    }
+}
+
+/// A rest: dashes for up to eight whole beats, else `-:length`
+pub fn rest_token(beats: Beat) -> String {
+    let whole = beats % Beat::ONE == Beat::ZERO && beats > Beat::ZERO;
+    let count = beats.as_f64().round() as usize;
+    if whole && count <= 8 {
+        "-".repeat(count)
+    } else {
+        format!("-{}", length_suffix(beats))
+    }
+}
+
+/// `?chance` for a note that doesn't always play (`?` alone is 0.5)
+fn chance_suffix(chance: f64) -> String {
+    if chance >= 1.0 {
+        String::new()
+    } else if chance == 0.5 {
+        "?".to_string()
+    } else {
+        let text = format!("{:.6}", chance);
+        format!("?{}", text.trim_end_matches('0').trim_end_matches('.'))
+    }
+}
+
+/// The whole file: header directives, then each track
+pub fn composition_text(comp: &Composition) -> String {
+    let mut text = String::new();
+    for (key, value) in comp.metadata.entries() {
+        for line in value.lines() {
+            let _ = writeln!(text, "{}: {}", key, line);
+        }
+    }
+    if comp.tempo != 120.0 {
+        let _ = writeln!(text, "tempo: {}", comp.tempo);
+    }
+    if comp.time_signature != (4, 4) {
+        let (num, den) = comp.time_signature;
+        let _ = writeln!(text, "time_signature: {}/{}", num, den);
+    }
+    if let Some(key) = comp.key {
+        let _ = writeln!(text, "key: {}", key);
+    }
+    if prefers_flats(comp) {
+        text.push_str("prefer_flats: true\n");
+    }
+    if comp.beats > Beat::ZERO {
+        let _ = writeln!(text, "beats: {}", beats_text(comp.beats));
+    }
+    if comp.loop_pattern {
+        text.push_str("loop: true\n");
+    }
+    let _ = writeln!(text, "octave: {}", comp.default_octave);
+    if let Some(patch) = &comp.default_patch {
+        let _ = writeln!(text, "patch: {}", patch);
+    }
+    let sections = comp.has_tracks();
+    for track in &comp.tracks {
+        text.push('\n');
+        if sections {
+            let _ = writeln!(text, "[track: {}]", track.name);
+            if let Some(patch) = &track.patch {
+                let _ = writeln!(text, "patch: {}", patch);
+            }
+        }
+        let mut writer = TrackWriter::new(comp.default_octave);
+        for (idx, event) in track.events.iter().enumerate() {
+            writer.signature_changes(&mut text, &track.meter_changes, idx);
+            writer.event(&mut text, event);
+        }
+        writer.signature_changes(&mut text, &track.meter_changes, track.events.len());
+        writer.finish(&mut text);
+    }
+    text
+}
+
+/// One track's events on their way to text: the note line and drum block
+/// being built
+struct TrackWriter {
+    /// Octave every note line starts in
+    base_octave: u8,
+    /// Octave after the line's shifts so far
+    octave: u8,
+    line: Vec<String>,
+    /// Dynamics mark in effect (notes start at full velocity)
+    mark: &'static str,
+    /// Steps of the drum block (empty for a step of rest)
+    drums: Vec<Vec<Drum>>,
+    /// Length of the drum block's steps, and the `drum_step:` in effect
+    drum_step: Beat,
+    written_step: Beat,
+}
+
+impl TrackWriter {
+    fn new(octave: u8) -> Self {
+        Self {
+            base_octave: octave,
+            octave,
+            line: Vec::new(),
+            mark: "ff",
+            drums: Vec::new(),
+            drum_step: Beat::ONE,
+            written_step: Beat::ONE,
+        }
+    }
+
+    /// A `time_signature:` line for each change at event `idx`
+    fn signature_changes(&mut self, text: &mut String, changes: &[MeterChange], idx: usize) {
+        for change in changes.iter().filter(|c| c.event == idx) {
+            self.finish(text);
+            let (num, den) = change.signature;
+            let _ = writeln!(text, "time_signature: {}/{}", num, den);
+        }
+    }
+
+    fn event(&mut self, text: &mut String, event: &Event) {
+        match event {
+            Event::Drums(drums, beats) => {
+                self.end_line(text);
+                if *beats != self.drum_step {
+                    self.end_drums(text);
+                    self.drum_step = *beats;
+                }
+                self.drums.push(drums.clone());
+                return;
+            }
+            // A step's rest inside a drum block is a step without hits
+            Event::Rest(beats) if *beats == self.drum_step && !self.drums.is_empty() => {
+                self.drums.push(Vec::new());
+                return;
+            }
+            _ => self.end_drums(text),
+        }
+        match event {
+            Event::Note(note, beats) => {
+                let mut token = self.dynamics(note);
+                write_key(&mut token, note.note.to_midi(note.octave), &mut self.octave);
+                token.push_str(&length_suffix(*beats));
+                token.push_str(&chance_suffix(note.chance));
+                self.line.push(token);
+            }
+            Event::Chord(notes, beats) => {
+                let mut token = notes.first().map_or_else(String::new, |n| self.dynamics(n));
+                token.push('[');
+                for note in notes {
+                    write_key(&mut token, note.note.to_midi(note.octave), &mut self.octave);
+                }
+                token.push(']');
+                token.push_str(&length_suffix(*beats));
+                token.push_str(&chance_suffix(notes.first().map_or(1.0, |n| n.chance)));
+                self.line.push(token);
+            }
+            Event::Rest(beats) => self.line.push(rest_token(*beats)),
+            Event::Tie(beats) => self.line.push(format!("_{}", length_suffix(*beats))),
+            Event::BarLine => {
+                self.line.push("|".to_string());
+                self.end_line(text);
+            }
+            Event::Alt(alt) => {
+                let token = self.alternatives(alt);
+                self.line.push(token);
+            }
+            Event::Drums(..) => {}
+        }
+    }
+
+    /// `alt{1: ... | 2: ...}`, or `alt?{... | ...}` when picked at random.
+    /// Each choice starts from the octave and dynamics the line is in.
+    fn alternatives(&self, alt: &Alternatives) -> String {
+        let choices: Vec<String> = alt
+            .choices
+            .iter()
+            .enumerate()
+            .map(|(idx, choice)| {
+                let mut inner = TrackWriter::new(self.octave);
+                inner.mark = self.mark;
+                // Choices hold no drum steps or bar lines, so nothing is
+                // written out before the tokens are taken
+                let mut unused = String::new();
+                for event in choice {
+                    inner.event(&mut unused, event);
+                }
+                let tokens = inner.line.join(" ");
+                match (alt.random, tokens.is_empty()) {
+                    (true, _) => tokens,
+                    (false, true) => format!("{}:", idx + 1),
+                    (false, false) => format!("{}: {}", idx + 1, tokens),
+                }
+            })
+            .collect();
+        let open = if alt.random { "alt?{" } else { "alt{" };
+        format!("{}{}}}", open, choices.join(" | "))
+    }
+
+    /// A `!mark! ` before `note` if its velocity calls for another mark
+    fn dynamics(&mut self, note: &NoteEvent) -> String {
+        let wanted = dynamic_mark(note.velocity);
+        if wanted == self.mark {
+            return String::new();
+        }
+        self.mark = wanted;
+        format!("!{}! ", wanted)
+    }
+
+    /// Write out the note line so far
+    fn end_line(&mut self, text: &mut String) {
+        if !self.line.is_empty() {
+            let _ = writeln!(text, "{}", self.line.join(" "));
+            self.line.clear();
+        }
+        self.octave = self.base_octave;
+    }
+
+    /// Write out the drum block so far: a line per drum it hits, and a
+    /// blank line to close it
+    fn end_drums(&mut self, text: &mut String) {
+        if self.drums.is_empty() {
+            return;
+        }
+        if self.drum_step != self.written_step {
+            let _ = writeln!(text, "drum_step: {}", beats_text(self.drum_step));
+            self.written_step = self.drum_step;
+        }
+        for drum in Drum::ALL {
+            if !self.drums.iter().any(|step| step.contains(&drum)) {
+                continue;
+            }
+            let label = format!("{}:", drum.name());
+            let steps: Vec<&str> = self
+                .drums
+                .iter()
+                .map(|step| if step.contains(&drum) { "x" } else { "-" })
+                .collect();
+            let _ = writeln!(text, "{:<7}{}", label, steps.join(" "));
+        }
+        text.push('\n');
+        self.drums.clear();
+    }
+
+    fn finish(&mut self, text: &mut String) {
+        self.end_line(text);
+        self.end_drums(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// `input` parsed, written out and parsed again gives the same tracks
+    fn assert_round_trip(input: &str) -> String {
+        let comp = parse(input).unwrap();
+        let text = composition_text(&comp);
+        let again = parse(&text).unwrap();
+        assert_eq!(again.tracks.len(), comp.tracks.len(), "{}", text);
+        for (a, b) in comp.tracks.iter().zip(&again.tracks) {
+            assert_eq!(a.name, b.name, "{}", text);
+            assert_eq!(a.patch, b.patch, "{}", text);
+            assert_eq!(a.meter_changes, b.meter_changes, "{}", text);
+            // Degrees are written as the notes they play
+            let mut events = a.events.clone();
+            for note in events.iter_mut().flat_map(Event::notes_mut) {
+                note.degree = None;
+            }
+            assert_eq!(events, b.events, "{}", text);
+        }
+        assert_eq!(again.tempo, comp.tempo);
+        assert_eq!(again.time_signature, comp.time_signature);
+        assert_eq!(again.key, comp.key);
+        assert_eq!(again.beats, comp.beats);
+        assert_eq!(again.metadata, comp.metadata);
+        text
+    }
+
+    #[test]
+    fn test_notes_round_trip() {
+        let text = assert_round_trip("octave: 3\na s:0.5 -:0.5 [adg]:2 | > k _ < a -- |\n");
+        assert_eq!(text, "octave: 3\n\na s:0.5 -:0.5 [adg]:2 |\n>>a _ <<a -- |\n");
+        assert_round_trip("key: D minor\n1 2 3 !p! 5 (a s d)/3 _:0.25 |\n");
+        assert_round_trip("key: Bb major\n4 u y |\n");
+        let text = assert_round_trip("octave: 3\nBb7 Ebmaj\n");
+        assert!(text.starts_with("prefer_flats: true\n"), "{}", text);
+        // Mixed sharps and flats are written with the key's
+        let comp = parse("w Bb\n").unwrap();
+        assert!(!composition_text(&comp).contains("prefer_flats"));
+        assert_round_trip(
+            "title: Riff\ndescription: one\ndescription: two\ntempo: 93.5\nbeats: 8\n\
+             time_signature: 3/4\na s d |\ntime_signature: 5/8\nf g h j k |\n",
+        );
+    }
+
+    #[test]
+    fn test_chances_and_alternatives_round_trip() {
+        let text =
+            assert_round_trip("a? s?0.25 [dg]:2?0.8 | alt{1: a s | 2: > d} !p! alt?{f? | -} |\n");
+        assert_eq!(
+            text,
+            "octave: 4\n\na? s?0.25 [dg]:2?0.8 |\nalt{1: a s | 2: >d} alt?{!p! f? | -} |\n"
+        );
+    }
+
+    #[test]
+    fn test_tracks_and_drums_round_trip() {
+        let text = assert_round_trip(
+            "patch: keys\n[track: lead]\npatch: lead.instr\noctave: 5\na s |\n\
+             [track: drums]\nkick:  x - x -\nhat:   x x x x\n\nsnare: - x\n- |\n",
+        );
+        // Both blocks and the rest after them make one block
+        let drums = "kick:  x - x - - - -\nsnare: - - - - - x -\nhat:   x x x x - - -\n\n|\n";
+        assert!(text.ends_with(drums), "{}", text);
+
+        let text = assert_round_trip("kick: x x\n\ndrum_step: 0.5\nkick: x -\n\n[track: b]\nhat: x\n");
+        assert!(text.contains("drum_step: 0.5\nkick:  x -\n"), "{}", text);
+    }
+}